@@ -0,0 +1,138 @@
+// DHT scrapes (BEP 33): nodes summarize the peers they store for a torrent in two bloom
+// filters, one for seeds and one for downloaders, which can be merged to estimate the
+// swarm size without fetching all the peers.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use sha1w::{ISha1, Sha1};
+
+const BLOOM_FILTER_BYTES: usize = 256;
+const BLOOM_FILTER_BITS: usize = BLOOM_FILTER_BYTES * 8;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScrapeBloomFilter([u8; BLOOM_FILTER_BYTES]);
+
+impl Default for ScrapeBloomFilter {
+    fn default() -> Self {
+        Self([0u8; BLOOM_FILTER_BYTES])
+    }
+}
+
+impl ScrapeBloomFilter {
+    pub fn insert(&mut self, ip: IpAddr) {
+        let mut hash = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hash.update(&ip.octets()),
+            IpAddr::V6(ip) => hash.update(&ip.octets()),
+        }
+        let hash = hash.finish();
+        for index in [
+            hash[0] as usize | (hash[1] as usize) << 8,
+            hash[2] as usize | (hash[3] as usize) << 8,
+        ] {
+            let index = index % BLOOM_FILTER_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (b, o) in self.0.iter_mut().zip(other.0.iter()) {
+            *b |= o;
+        }
+    }
+
+    /// Estimated number of distinct IPs inserted.
+    pub fn estimate(&self) -> usize {
+        let m = BLOOM_FILTER_BITS as f64;
+        let zeros = self.0.iter().map(|b| b.count_zeros()).sum::<u32>() as f64;
+        if zeros == m {
+            return 0;
+        }
+        let zeros = zeros.min(m - 1.);
+        ((zeros / m).ln() / (2. * (1. - 1. / m).ln())).round() as usize
+    }
+}
+
+impl core::fmt::Debug for ScrapeBloomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "~{} IPs", self.estimate())
+    }
+}
+
+impl Serialize for ScrapeBloomFilter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ScrapeBloomFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ScrapeBloomFilter;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(formatter, "{BLOOM_FILTER_BYTES} bytes of bloom filter")
+            }
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                let bits = v
+                    .try_into()
+                    .map_err(|_| E::invalid_length(v.len(), &self))?;
+                Ok(ScrapeBloomFilter(bits))
+            }
+        }
+        deserializer.deserialize_bytes(Visitor {})
+    }
+}
+
+/// How many seeds and downloaders the DHT knows of for a torrent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DhtScrape {
+    pub seeds: usize,
+    pub peers: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::ScrapeBloomFilter;
+
+    #[test]
+    fn test_bep33_estimate() {
+        // The example from BEP 33: 192.0.2.0-255 and 2001:DB8::0-3E7 estimate to 1224.93.
+        let mut filter = ScrapeBloomFilter::default();
+        for i in 0..=255u8 {
+            filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        for i in 0..1000u16 {
+            filter.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        assert_eq!(filter.estimate(), 1225);
+    }
+
+    #[test]
+    fn test_merge() {
+        let ip1 = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+        let mut a = ScrapeBloomFilter::default();
+        a.insert(ip1);
+        let mut b = ScrapeBloomFilter::default();
+        b.insert(ip1);
+        b.insert(ip2);
+        a.merge(&b);
+        assert_eq!(a, b);
+        assert_eq!(a.estimate(), 2);
+        assert_eq!(ScrapeBloomFilter::default().estimate(), 0);
+    }
+}
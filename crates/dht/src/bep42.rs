@@ -0,0 +1,178 @@
+// DHT node ID security (BEP 42): node IDs are derived from the node's external IP, so that
+// one host can't pick IDs all over the keyspace.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+};
+
+use librqbit_core::hash_id::Id20;
+use rand::RngCore;
+
+// How many distinct nodes need to agree on our external IP before we trust it.
+const MIN_EXTERNAL_IP_VOTES: usize = 10;
+// Don't track more candidate IPs than this, so nodes can't make us remember arbitrary IPs.
+const MAX_EXTERNAL_IP_CANDIDATES: usize = 16;
+
+// CRC32-C (Castagnoli), which BEP 42 uses.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn id_prefix_crc(ip: IpAddr, r: u8) -> u32 {
+    const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+    const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+    let mut buf = [0u8; 8];
+    let len = match ip {
+        IpAddr::V4(ip) => {
+            for (b, (o, m)) in buf.iter_mut().zip(ip.octets().iter().zip(V4_MASK)) {
+                *b = o & m;
+            }
+            4
+        }
+        IpAddr::V6(ip) => {
+            for (b, (o, m)) in buf.iter_mut().zip(ip.octets().iter().zip(V6_MASK)) {
+                *b = o & m;
+            }
+            8
+        }
+    };
+    buf[0] |= (r & 0x7) << 5;
+    crc32c(&buf[..len])
+}
+
+fn node_id_from_parts(ip: IpAddr, rand: u8, mut rest: [u8; 20]) -> Id20 {
+    let crc = id_prefix_crc(ip, rand);
+    rest[0] = (crc >> 24) as u8;
+    rest[1] = (crc >> 16) as u8;
+    rest[2] = ((crc >> 8) as u8 & 0xf8) | (rest[2] & 0x7);
+    rest[19] = rand;
+    Id20::new(rest)
+}
+
+// Addresses BEP 42 doesn't apply to, as many nodes share them.
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
+
+/// Generate a random node ID that is valid for the given external IP.
+pub fn generate_node_id(ip: IpAddr) -> Id20 {
+    let mut rest = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut rest);
+    node_id_from_parts(ip, rest[19], rest)
+}
+
+/// Whether a node reachable at this IP is allowed to use this ID.
+pub fn is_node_id_valid(id: &Id20, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = id_prefix_crc(ip, id.0[19]);
+    id.0[0] == (crc >> 24) as u8
+        && id.0[1] == (crc >> 16) as u8
+        && id.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+// Nodes tell us what IP they see us at in the "ip" field of their responses.
+#[derive(Default)]
+pub(crate) struct ExternalIpVotes {
+    votes: HashMap<Ipv4Addr, HashSet<IpAddr>>,
+}
+
+impl ExternalIpVotes {
+    // Returns true if this vote changed the external IP.
+    pub fn add_vote(&mut self, ip: Ipv4Addr, voter: IpAddr) -> bool {
+        let before = self.external_ip();
+        if !self.votes.contains_key(&ip) && self.votes.len() >= MAX_EXTERNAL_IP_CANDIDATES {
+            return false;
+        }
+        self.votes.entry(ip).or_default().insert(voter);
+        self.external_ip() != before
+    }
+
+    pub fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.votes
+            .iter()
+            .filter(|(_, voters)| voters.len() >= MIN_EXTERNAL_IP_VOTES)
+            .max_by_key(|(ip, voters)| (voters.len(), **ip))
+            .map(|(ip, _)| *ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{
+        generate_node_id, is_node_id_valid, node_id_from_parts, ExternalIpVotes,
+        MIN_EXTERNAL_IP_VOTES,
+    };
+
+    // Test vectors from BEP 42.
+    const VECTORS: &[(&str, u8, &str)] = &[
+        ("124.31.75.21", 1, "5fbfbf"),
+        ("21.75.31.124", 86, "5a3ce9"),
+        ("65.23.51.170", 22, "a5d432"),
+        ("84.124.73.14", 65, "1b0321"),
+        ("43.213.53.83", 90, "e56f6c"),
+    ];
+
+    #[test]
+    fn test_bep42_vectors() {
+        for (ip, rand, prefix) in VECTORS {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = node_id_from_parts(ip, *rand, [0u8; 20]);
+            let expected = u32::from_str_radix(prefix, 16).unwrap();
+            assert_eq!(id.0[0], (expected >> 16) as u8, "{ip}");
+            assert_eq!(id.0[1], (expected >> 8) as u8, "{ip}");
+            assert_eq!(id.0[2] & 0xf8, expected as u8 & 0xf8, "{ip}");
+            assert_eq!(id.0[19], *rand);
+            assert!(is_node_id_valid(&id, ip));
+        }
+    }
+
+    #[test]
+    fn test_validate_node_id() {
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        let id = generate_node_id(ip);
+        assert!(is_node_id_valid(&id, ip));
+        assert!(!is_node_id_valid(&id, "21.75.31.124".parse().unwrap()));
+        assert!(!is_node_id_valid(&Id20::new([0u8; 20]), ip));
+
+        // Local addresses are exempt.
+        assert!(is_node_id_valid(
+            &Id20::new([0u8; 20]),
+            "192.168.1.1".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_external_ip_votes() {
+        let mut votes = ExternalIpVotes::default();
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        for i in 0..MIN_EXTERNAL_IP_VOTES as u8 - 1 {
+            assert!(!votes.add_vote(ip, Ipv4Addr::new(5, 5, 5, i).into()));
+        }
+        // The same node voting again doesn't count.
+        assert!(!votes.add_vote(ip, Ipv4Addr::new(5, 5, 5, 0).into()));
+        assert_eq!(votes.external_ip(), None);
+        assert!(votes.add_vote(ip, Ipv4Addr::new(5, 5, 5, 100).into()));
+        assert_eq!(votes.external_ip(), Some(ip));
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     io::Write,
     marker::PhantomData,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
 use bencode::{ByteBuf, ByteBufOwned};
@@ -12,6 +12,8 @@ use serde::{
     Deserialize, Deserializer, Serialize,
 };
 
+use crate::bep33::ScrapeBloomFilter;
+
 #[derive(Debug)]
 enum MessageType {
     Request,
@@ -161,87 +163,128 @@ struct RawMessage<BufT, Args = IgnoredAny, Resp = IgnoredAny> {
     version: Option<BufT>,
     #[serde(rename = "ip", skip_serializing_if = "Option::is_none")]
     ip: Option<CompactPeerInfo>,
+    // BEP 43: the sender doesn't respond to queries.
+    #[serde(rename = "ro", skip_serializing_if = "Option::is_none")]
+    read_only: Option<u8>,
+}
+
+/// An address in compact form: the IP followed by the port, both big endian.
+pub trait CompactAddr: Sized + Copy + core::fmt::Display {
+    const LEN: usize;
+    fn write_compact(&self, buf: &mut Vec<u8>);
+    // b.len() is always LEN.
+    fn read_compact(b: &[u8]) -> Self;
 }
 
-pub struct Node {
+impl CompactAddr for SocketAddrV4 {
+    const LEN: usize = 6;
+
+    fn write_compact(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.ip().octets());
+        buf.extend_from_slice(&self.port().to_be_bytes());
+    }
+
+    fn read_compact(b: &[u8]) -> Self {
+        let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+        let port = ((b[4] as u16) << 8) + b[5] as u16;
+        SocketAddrV4::new(ip, port)
+    }
+}
+
+impl CompactAddr for SocketAddrV6 {
+    const LEN: usize = 18;
+
+    fn write_compact(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.ip().octets());
+        buf.extend_from_slice(&self.port().to_be_bytes());
+    }
+
+    fn read_compact(b: &[u8]) -> Self {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&b[..16]);
+        let port = ((b[16] as u16) << 8) + b[17] as u16;
+        SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0)
+    }
+}
+
+pub struct Node<A = SocketAddrV4> {
     pub id: Id20,
-    pub addr: SocketAddrV4,
+    pub addr: A,
 }
 
-impl core::fmt::Debug for Node {
+impl<A: CompactAddr> core::fmt::Debug for Node<A> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}={:?}", self.addr, self.id)
     }
 }
 
-pub struct CompactNodeInfo {
-    pub nodes: Vec<Node>,
+// "nodes" has IPv4 nodes, "nodes6" IPv6 ones (BEP 32).
+pub struct CompactNodeInfo<A = SocketAddrV4> {
+    pub nodes: Vec<Node<A>>,
 }
 
-impl core::fmt::Debug for CompactNodeInfo {
+impl<A: CompactAddr> core::fmt::Debug for CompactNodeInfo<A> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self.nodes)
     }
 }
 
-impl Serialize for CompactNodeInfo {
+impl<A: CompactAddr> Serialize for CompactNodeInfo<A> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut buf = Vec::<u8>::with_capacity(self.nodes.len() * 26);
+        let mut buf = Vec::<u8>::with_capacity(self.nodes.len() * (20 + A::LEN));
         for node in self.nodes.iter() {
             buf.extend_from_slice(&node.id.0);
-            let ip_octets = node.addr.ip().octets();
-            let port = node.addr.port();
-            buf.extend_from_slice(&ip_octets);
-            // BE encoding for port.
-            buf.push((port >> 8) as u8);
-            buf.push((port & 0xff) as u8);
+            node.addr.write_compact(&mut buf);
         }
         serializer.serialize_bytes(&buf)
     }
 }
 
-impl<'de> Deserialize<'de> for CompactNodeInfo {
+impl<'de, A: CompactAddr> Deserialize<'de> for CompactNodeInfo<A> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        struct Visitor;
-        impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = CompactNodeInfo;
+        struct Visitor<A>(PhantomData<A>);
+        impl<'de, A: CompactAddr> serde::de::Visitor<'de> for Visitor<A> {
+            type Value = CompactNodeInfo<A>;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "compact node info with length multiple of 26")
+                write!(
+                    formatter,
+                    "compact node info with length multiple of {}",
+                    20 + A::LEN
+                )
             }
             fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
             where
                 E: serde::de::Error,
             {
-                if v.len() % 26 != 0 {
+                let len = 20 + A::LEN;
+                if v.len() % len != 0 {
                     return Err(E::invalid_length(v.len(), &self));
                 }
-                let mut buf = Vec::<Node>::with_capacity(v.len() / 26);
-                for chunk in v.chunks_exact(26) {
+                let mut buf = Vec::<Node<A>>::with_capacity(v.len() / len);
+                for chunk in v.chunks_exact(len) {
                     let mut node_id = [0u8; 20];
                     node_id.copy_from_slice(&chunk[..20]);
-                    let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
-                    let port = ((chunk[24] as u16) << 8) + chunk[25] as u16;
                     buf.push(Node {
                         id: Id20::new(node_id),
-                        addr: SocketAddrV4::new(ip, port),
+                        addr: A::read_compact(&chunk[20..]),
                     })
                 }
                 Ok(CompactNodeInfo { nodes: buf })
             }
         }
-        deserializer.deserialize_bytes(Visitor)
+        deserializer.deserialize_bytes(Visitor(PhantomData))
     }
 }
 
 pub struct CompactPeerInfo {
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 }
 
 impl core::fmt::Debug for CompactPeerInfo {
@@ -255,16 +298,11 @@ impl Serialize for CompactPeerInfo {
     where
         S: serde::Serializer,
     {
-        let octets = self.addr.ip().octets();
-        let port = self.addr.port();
-        let buf = [
-            octets[0],
-            octets[1],
-            octets[2],
-            octets[3],
-            (port >> 8) as u8,
-            (port & 0xff) as u8,
-        ];
+        let mut buf = Vec::with_capacity(SocketAddrV6::LEN);
+        match self.addr {
+            SocketAddr::V4(addr) => addr.write_compact(&mut buf),
+            SocketAddr::V6(addr) => addr.write_compact(&mut buf),
+        }
         serializer.serialize_bytes(&buf)
     }
 }
@@ -279,20 +317,18 @@ impl<'de> Deserialize<'de> for CompactPeerInfo {
             type Value = CompactPeerInfo;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "6 bytes of peer info")
+                write!(formatter, "6 or 18 bytes of peer info")
             }
             fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: serde::de::Error,
             {
-                if v.len() != 6 {
-                    return Err(E::invalid_length(6, &self));
-                }
-                let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
-                let port = ((v[4] as u16) << 8) + v[5] as u16;
-                Ok(CompactPeerInfo {
-                    addr: SocketAddrV4::new(ip, port),
-                })
+                let addr = match v.len() {
+                    len if len == SocketAddrV4::LEN => SocketAddrV4::read_compact(v).into(),
+                    len if len == SocketAddrV6::LEN => SocketAddrV6::read_compact(v).into(),
+                    len => return Err(E::invalid_length(len, &self)),
+                };
+                Ok(CompactPeerInfo { addr })
             }
         }
         deserializer.deserialize_bytes(Visitor {})
@@ -313,13 +349,23 @@ pub struct Response<BufT> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nodes: Option<CompactNodeInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<CompactNodeInfo<SocketAddrV6>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token: Option<BufT>,
+    // BEP 33 scrape: bloom filters of the seeds and downloaders the node stores.
+    #[serde(rename = "BFsd", skip_serializing_if = "Option::is_none")]
+    pub bf_seeds: Option<ScrapeBloomFilter>,
+    #[serde(rename = "BFpe", skip_serializing_if = "Option::is_none")]
+    pub bf_peers: Option<ScrapeBloomFilter>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetPeersRequest {
     pub id: Id20,
     pub info_hash: Id20,
+    // BEP 33: ask for bloom filters of the stored peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -334,6 +380,9 @@ pub struct AnnouncePeer<BufT> {
     pub info_hash: Id20,
     pub port: u16,
     pub token: BufT,
+    // BEP 33: the announcing peer is a seed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -353,7 +402,8 @@ pub struct Message<BufT> {
     pub kind: MessageKind<BufT>,
     pub transaction_id: BufT,
     pub version: Option<BufT>,
-    pub ip: Option<SocketAddrV4>,
+    pub ip: Option<SocketAddr>,
+    pub read_only: bool,
 }
 
 impl Message<ByteBufOwned> {
@@ -371,7 +421,7 @@ pub enum MessageKind<BufT> {
     Error(ErrorDescription<BufT>),
     GetPeersRequest(GetPeersRequest),
     FindNodeRequest(FindNodeRequest),
-    Response(Response<BufT>),
+    Response(Box<Response<BufT>>),
     PingRequest(PingRequest),
     AnnouncePeer(AnnouncePeer<BufT>),
 }
@@ -393,10 +443,12 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
     writer: &mut W,
     transaction_id: BufT,
     version: Option<BufT>,
-    ip: Option<SocketAddrV4>,
+    ip: Option<SocketAddr>,
+    read_only: bool,
     kind: MessageKind<BufT>,
 ) -> anyhow::Result<()> {
     let ip = ip.map(|ip| CompactPeerInfo { addr: ip });
+    let read_only = read_only.then_some(1);
     match kind {
         MessageKind::Error(e) => {
             let msg: RawMessage<BufT, (), ()> = RawMessage {
@@ -407,6 +459,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 method_name: None,
                 version,
                 ip,
+                read_only,
                 arguments: None,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -420,6 +473,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 method_name: Some(BufT::from(b"get_peers")),
                 arguments: Some(req),
                 ip,
+                read_only,
                 version,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -433,6 +487,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 method_name: Some(BufT::from(b"find_node")),
                 arguments: Some(req),
                 ip,
+                read_only,
                 version,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -442,10 +497,11 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 message_type: MessageType::Response,
                 transaction_id,
                 error: None,
-                response: Some(resp),
+                response: Some(*resp),
                 method_name: None,
                 arguments: None,
                 ip,
+                read_only,
                 version,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -459,6 +515,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 method_name: Some(BufT::from(b"ping")),
                 arguments: Some(ping),
                 ip,
+                read_only,
                 version,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -472,6 +529,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
                 method_name: Some(BufT::from(b"announce_peer")),
                 arguments: Some(announce),
                 ip,
+                read_only,
                 version,
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
@@ -493,6 +551,7 @@ where
                         transaction_id: de.transaction_id,
                         version: de.version,
                         ip: de.ip.map(|c| c.addr),
+                        read_only: de.read_only.unwrap_or(0) != 0,
                         kind: MessageKind::FindNodeRequest(de.arguments.unwrap()),
                     })
                 }
@@ -502,6 +561,7 @@ where
                         transaction_id: de.transaction_id,
                         version: de.version,
                         ip: de.ip.map(|c| c.addr),
+                        read_only: de.read_only.unwrap_or(0) != 0,
                         kind: MessageKind::GetPeersRequest(de.arguments.unwrap()),
                     })
                 }
@@ -511,6 +571,7 @@ where
                         transaction_id: de.transaction_id,
                         version: de.version,
                         ip: de.ip.map(|c| c.addr),
+                        read_only: de.read_only.unwrap_or(0) != 0,
                         kind: MessageKind::PingRequest(de.arguments.unwrap()),
                     })
                 }
@@ -520,6 +581,7 @@ where
                         transaction_id: de.transaction_id,
                         version: de.version,
                         ip: de.ip.map(|c| c.addr),
+                        read_only: de.read_only.unwrap_or(0) != 0,
                         kind: MessageKind::AnnouncePeer(de.arguments.unwrap())
                     })
                 }
@@ -537,7 +599,8 @@ where
                     transaction_id: de.transaction_id,
                     version: de.version,
                     ip: de.ip.map(|c| c.addr),
-                    kind: MessageKind::Response(de.response.unwrap()),
+                    read_only: de.read_only.unwrap_or(0) != 0,
+                    kind: MessageKind::Response(Box::new(de.response.unwrap())),
                 })
             }
             _ => anyhow::bail!(
@@ -552,6 +615,7 @@ where
                     transaction_id: de.transaction_id,
                     version: de.version,
                     ip: de.ip.map(|c| c.addr),
+                    read_only: de.read_only.unwrap_or(0) != 0,
                     kind: MessageKind::Error(de.error.unwrap()),
                 })
             }
@@ -609,9 +673,11 @@ mod tests {
             transaction_id,
             version,
             ip,
+            read_only,
         } = dbg!(bprotocol::deserialize_message::<ByteBuf>(data).unwrap());
         let mut buf = Vec::new();
-        bprotocol::serialize_message(&mut buf, transaction_id, version, ip, kind).unwrap();
+        bprotocol::serialize_message(&mut buf, transaction_id, version, ip, read_only, kind)
+            .unwrap();
 
         if buf.as_slice() != data {
             write(&format!("{name}-serialized"), buf.as_slice());
@@ -632,6 +698,7 @@ mod tests {
             transaction_id,
             None,
             None,
+            false,
             bprotocol::MessageKind::Error(bprotocol::ErrorDescription {
                 code: 201,
                 description: ByteBuf(b"Some error"),
@@ -646,7 +713,7 @@ mod tests {
         } = bprotocol::deserialize_message::<ByteBuf>(&buf).unwrap();
 
         let mut buf2 = Vec::new();
-        bprotocol::serialize_message(&mut buf2, transaction_id, None, None, kind).unwrap();
+        bprotocol::serialize_message(&mut buf2, transaction_id, None, None, false, kind).unwrap();
 
         if buf.as_slice() != buf2.as_slice() {
             write("error-serialized", buf.as_slice());
@@ -696,11 +763,79 @@ mod tests {
             _ => panic!("wrong kind"),
         }
         let mut buf = Vec::new();
-        bprotocol::serialize_message(&mut buf, msg.transaction_id, msg.version, msg.ip, msg.kind)
-            .unwrap();
+        bprotocol::serialize_message(
+            &mut buf,
+            msg.transaction_id,
+            msg.version,
+            msg.ip,
+            msg.read_only,
+            msg.kind,
+        )
+        .unwrap();
         assert_eq!(ann[..], buf[..]);
     }
 
+    #[test]
+    fn test_read_only() {
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe";
+        let msg = bprotocol::deserialize_message::<ByteBuf>(ping).unwrap();
+        assert!(msg.read_only);
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(
+            &mut buf,
+            msg.transaction_id,
+            msg.version,
+            msg.ip,
+            msg.read_only,
+            msg.kind,
+        )
+        .unwrap();
+        assert_eq!(ping[..], buf[..]);
+    }
+
+    #[test]
+    fn test_response_nodes6() {
+        let id = librqbit_core::hash_id::Id20::new([1u8; 20]);
+        let v4: std::net::SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let v6: std::net::SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let node6 = match v6 {
+            std::net::SocketAddr::V6(addr) => bprotocol::Node { id, addr },
+            _ => unreachable!(),
+        };
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(
+            &mut buf,
+            ByteBuf(b"aa"),
+            None,
+            Some(v6),
+            false,
+            bprotocol::MessageKind::Response(Box::new(bprotocol::Response {
+                id,
+                values: Some(vec![
+                    bprotocol::CompactPeerInfo { addr: v4 },
+                    bprotocol::CompactPeerInfo { addr: v6 },
+                ]),
+                nodes6: Some(bprotocol::CompactNodeInfo { nodes: vec![node6] }),
+                ..Default::default()
+            })),
+        )
+        .unwrap();
+
+        let msg = bprotocol::deserialize_message::<ByteBuf>(&buf).unwrap();
+        assert_eq!(msg.ip, Some(v6));
+        let resp = match msg.kind {
+            bprotocol::MessageKind::Response(resp) => resp,
+            _ => panic!("wrong kind"),
+        };
+        assert!(resp.nodes.is_none());
+        let nodes6 = resp.nodes6.unwrap().nodes;
+        assert_eq!(nodes6.len(), 1);
+        assert_eq!(std::net::SocketAddr::V6(nodes6[0].addr), v6);
+        let values = resp.values.unwrap();
+        assert_eq!(values[0].addr, v4);
+        assert_eq!(values[1].addr, v6);
+    }
+
     #[test]
     fn deserialize_bencode_packets_captured_from_wireshark() {
         debug_hex_bencode("req: find_node", FIND_NODE_REQUEST);
@@ -1,7 +1,6 @@
 use std::{
     cmp::Reverse,
-    net::SocketAddr,
-    str::FromStr,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::{
         atomic::{AtomicU16, Ordering},
         Arc,
@@ -11,13 +10,17 @@ use std::{
 };
 
 use crate::{
+    bep33::{DhtScrape, ScrapeBloomFilter},
+    bep42::{generate_node_id, is_node_id_valid, ExternalIpVotes},
     bprotocol::{
         self, AnnouncePeer, CompactNodeInfo, ErrorDescription, FindNodeRequest, GetPeersRequest,
         Message, MessageKind, Node, PingRequest, Response,
     },
     peer_store::PeerStore,
+    rate_limit::{DhtRateLimiter, DhtRateLimits},
     routing_table::{InsertResult, NodeStatus, RoutingTable},
-    INACTIVITY_TIMEOUT, REQUERY_INTERVAL, RESPONSE_TIMEOUT,
+    ANNOUNCE_INITIAL_DELAY, ANNOUNCE_INTERVAL, INACTIVITY_TIMEOUT, REQUERY_INTERVAL,
+    RESPONSE_TIMEOUT,
 };
 use anyhow::{bail, Context};
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
@@ -27,10 +30,10 @@ use futures::{
     future::BoxFuture, stream::FuturesUnordered, FutureExt, Stream, StreamExt, TryFutureExt,
 };
 
-use leaky_bucket::RateLimiter;
 use librqbit_core::{
     hash_id::Id20,
     peer_id::generate_peer_id,
+    socket_binding::SocketBinding,
     spawn_utils::{spawn, spawn_with_cancel},
 };
 use parking_lot::RwLock;
@@ -38,7 +41,10 @@ use parking_lot::RwLock;
 use serde::Serialize;
 use tokio::{
     net::UdpSocket,
-    sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
 };
 
 use tokio_util::sync::CancellationToken;
@@ -50,6 +56,7 @@ pub struct DhtStats {
     pub id: Id20,
     pub outstanding_requests: usize,
     pub routing_table_size: usize,
+    pub routing_table_size_v6: usize,
 }
 
 struct OutstandingRequest {
@@ -73,23 +80,6 @@ struct MaybeUsefulNode {
     returned_peers: bool,
 }
 
-fn make_rate_limiter() -> RateLimiter {
-    // TODO: move to configuration, i'm lazy.
-    let dht_queries_per_second = std::env::var("DHT_QUERIES_PER_SECOND")
-        .map(|v| v.parse().expect("couldn't parse DHT_QUERIES_PER_SECOND"))
-        .unwrap_or(250usize);
-
-    let per_100_ms = dht_queries_per_second / 10;
-
-    RateLimiter::builder()
-        .initial(per_100_ms)
-        .max(dht_queries_per_second)
-        .interval(Duration::from_millis(100))
-        .fair(false)
-        .refill(per_100_ms)
-        .build()
-}
-
 trait RecursiveRequestCallbacks: Sized + Send + Sync + 'static {
     fn on_request_start(&self, req: &RecursiveRequest<Self>, target_node: Id20, addr: SocketAddr);
     fn on_request_end(
@@ -101,10 +91,29 @@ trait RecursiveRequestCallbacks: Sized + Send + Sync + 'static {
     );
 }
 
+// How many of the closest nodes we announce to (K in BEP 5).
+const ANNOUNCE_TARGETS: usize = 8;
+
+// A node that gave us a write token in its get_peers response.
+#[derive(Debug, Clone)]
+struct AnnounceTarget {
+    id: Id20,
+    addr: SocketAddr,
+    token: ByteBufOwned,
+}
+
+// Keep the closest nodes to info_hash, with the latest token each of them gave us.
+fn add_announce_target(targets: &mut Vec<AnnounceTarget>, info_hash: Id20, target: AnnounceTarget) {
+    targets.retain(|t| t.id != target.id);
+    targets.push(target);
+    targets.sort_by_key(|t| t.id.distance(&info_hash));
+    targets.truncate(ANNOUNCE_TARGETS);
+}
+
+#[derive(Default)]
 struct RecursiveRequestCallbacksGetPeers {
-    // Id20::from_str("00000fffffffffffffffffffffffffffffffffff").unwrap()
-    min_distance_to_announce: Id20,
-    announce_port: Option<u16>,
+    // Sorted by distance to the info hash.
+    announce_targets: RwLock<Vec<AnnounceTarget>>,
 }
 
 impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGetPeers {
@@ -117,44 +126,60 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGetPeers {
         addr: SocketAddr,
         resp: &anyhow::Result<ResponseOrError>,
     ) {
-        let announce_port = match self.announce_port {
-            Some(a) => a,
-            None => return,
-        };
-        let resp = match resp {
-            Ok(ResponseOrError::Response(resp)) => resp,
+        let token = match resp {
+            Ok(ResponseOrError::Response(r)) => match &r.token {
+                Some(token) => token,
+                None => return,
+            },
             _ => return,
         };
-        let token = match &resp.token {
-            Some(token) => token,
-            None => return,
-        };
-        if req.info_hash.distance(&target_node) > self.min_distance_to_announce {
-            trace!(
-                "not announcing, {:?} is too far from {:?}",
-                target_node,
-                req.info_hash
-            );
-            return;
-        }
-        let (tid, message) = req.dht.create_request(Request::Announce {
-            info_hash: req.info_hash,
-            token: token.clone(),
-            port: announce_port,
-        });
+        add_announce_target(
+            &mut self.announce_targets.write(),
+            req.info_hash,
+            AnnounceTarget {
+                id: target_node,
+                addr,
+                token: token.clone(),
+            },
+        );
+    }
+}
 
-        let _ = req.dht.worker_sender.send(WorkerSendRequest {
-            our_tid: Some(tid),
-            message,
-            addr,
-        });
+// The filters of the closest nodes that answered a scrape, sorted by distance to the info hash.
+#[derive(Default)]
+struct RecursiveRequestCallbacksScrape {
+    filters: RwLock<Vec<(Id20, ScrapeBloomFilter, ScrapeBloomFilter)>>,
+}
+
+impl RecursiveRequestCallbacks for RecursiveRequestCallbacksScrape {
+    fn on_request_start(&self, _: &RecursiveRequest<Self>, _: Id20, _: SocketAddr) {}
+
+    fn on_request_end(
+        &self,
+        req: &RecursiveRequest<Self>,
+        target_node: Id20,
+        _addr: SocketAddr,
+        resp: &anyhow::Result<ResponseOrError>,
+    ) {
+        let (seeds, peers) = match resp {
+            Ok(ResponseOrError::Response(r)) => match (&r.bf_seeds, &r.bf_peers) {
+                (Some(seeds), Some(peers)) => (seeds, peers),
+                _ => return,
+            },
+            _ => return,
+        };
+        let mut filters = self.filters.write();
+        filters.retain(|(id, _, _)| *id != target_node);
+        filters.push((target_node, seeds.clone(), peers.clone()));
+        filters.sort_by_key(|(id, _, _)| id.distance(&req.info_hash));
+        filters.truncate(ANNOUNCE_TARGETS);
     }
 }
 
 struct RecursiveRequestCallbacksFindNodes {}
 impl RecursiveRequestCallbacks for RecursiveRequestCallbacksFindNodes {
     fn on_request_start(&self, req: &RecursiveRequest<Self>, target_node: Id20, addr: SocketAddr) {
-        let mut rt = req.dht.routing_table.write();
+        let mut rt = req.dht.routing_table_for(&addr).write();
         match rt.add_node(target_node, addr) {
             InsertResult::WasExisting | InsertResult::ReplacedBad(_) | InsertResult::Added => {
                 rt.mark_outgoing_request(&target_node);
@@ -167,10 +192,10 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksFindNodes {
         &self,
         req: &RecursiveRequest<Self>,
         target_node: Id20,
-        _addr: SocketAddr,
+        addr: SocketAddr,
         resp: &anyhow::Result<ResponseOrError>,
     ) {
-        let mut table = req.dht.routing_table.write();
+        let mut table = req.dht.routing_table_for(&addr).write();
         if resp.is_ok() {
             table.mark_response(&target_node);
         } else {
@@ -193,6 +218,7 @@ struct RecursiveRequest<C: RecursiveRequestCallbacks> {
 
 pub struct RequestPeersStream {
     rx: tokio::sync::mpsc::UnboundedReceiver<SocketAddr>,
+    announce_port: watch::Sender<Option<u16>>,
     cancel_join_handle: tokio::task::JoinHandle<()>,
 }
 
@@ -209,20 +235,26 @@ impl RequestPeersStream {
             useful_nodes: RwLock::new(Vec::new()),
             peer_tx,
             node_tx,
-            callbacks: RecursiveRequestCallbacksGetPeers {
-                min_distance_to_announce: Id20::from_str(
-                    "0000ffffffffffffffffffffffffffffffffffff",
-                )
-                .unwrap(),
-                announce_port,
-            },
+            callbacks: Default::default(),
         });
-        let join_handle = rp.request_peers_forever(node_rx);
+        let (announce_port, announce_port_rx) = watch::channel(announce_port);
+        let join_handle = rp.request_peers_forever(node_rx, announce_port_rx);
         Self {
             rx: peer_rx,
+            announce_port,
             cancel_join_handle: join_handle,
         }
     }
+
+    /// Change the port we announce to the DHT, re-announcing with it right away.
+    /// None stops announcing.
+    pub fn set_announce_port(&self, port: Option<u16>) {
+        self.announce_port.send_if_modified(|p| {
+            let changed = *p != port;
+            *p = port;
+            changed
+        });
+    }
 }
 
 impl Drop for RequestPeersStream {
@@ -320,10 +352,80 @@ impl RecursiveRequest<RecursiveRequestCallbacksFindNodes> {
     }
 }
 
+impl RecursiveRequest<RecursiveRequestCallbacksScrape> {
+    async fn scrape(dht: Arc<DhtState>, info_hash: Id20) -> anyhow::Result<DhtScrape> {
+        let (node_tx, mut node_rx) = unbounded_channel();
+        // Nodes send peers along with the filters, we don't need them.
+        let (peer_tx, _peer_rx) = unbounded_channel();
+        let req = RecursiveRequest {
+            max_depth: 4,
+            info_hash,
+            request: Request::Scrape(info_hash),
+            dht,
+            useful_nodes_limit: 32,
+            useful_nodes: RwLock::new(Vec::new()),
+            peer_tx,
+            node_tx,
+            callbacks: RecursiveRequestCallbacksScrape::default(),
+        };
+
+        let request_one = |id, addr, depth| {
+            req.request_one(id, addr, depth)
+                .map_err(|e| debug!("error: {e:?}"))
+                .instrument(error_span!("scrape", addr = addr.to_string()))
+        };
+
+        let mut futs = FuturesUnordered::new();
+        for table in req.dht.routing_tables() {
+            for node in table
+                .read()
+                .sorted_by_distance_from(info_hash)
+                .iter()
+                .take(ANNOUNCE_TARGETS)
+            {
+                futs.push(request_one(Some(node.id()), node.addr(), 0));
+            }
+        }
+
+        loop {
+            tokio::select! {
+                biased;
+
+                r = node_rx.recv() => {
+                    let (id, addr, depth) = r.unwrap();
+                    futs.push(request_one(id, addr, depth))
+                },
+                f = futs.next() => {
+                    if f.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let filters = req.callbacks.filters.read();
+        if filters.is_empty() {
+            bail!("no nodes answered the scrape");
+        }
+        let mut seeds = ScrapeBloomFilter::default();
+        let mut peers = ScrapeBloomFilter::default();
+        for (_, s, p) in filters.iter() {
+            seeds.merge(s);
+            peers.merge(p);
+        }
+        debug!(?info_hash, "scraped {} nodes", filters.len());
+        Ok(DhtScrape {
+            seeds: seeds.estimate(),
+            peers: peers.estimate(),
+        })
+    }
+}
+
 impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
     fn request_peers_forever(
         self: &Arc<Self>,
         mut node_rx: tokio::sync::mpsc::UnboundedReceiver<(Option<Id20>, SocketAddr, usize)>,
+        mut announce_port_rx: watch::Receiver<Option<u16>>,
     ) -> tokio::task::JoinHandle<()> {
         let this = self.clone();
         spawn(
@@ -352,6 +454,27 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
                 };
                 tokio::pin!(looper);
 
+                // Announcer re-announces on a schedule, and right away when the port changes.
+                let announcer = async move {
+                    let mut sleep = ANNOUNCE_INITIAL_DELAY;
+                    loop {
+                        tokio::select! {
+                            _ = tokio::time::sleep(sleep) => {},
+                            r = announce_port_rx.changed() => {
+                                if r.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                        let port = *announce_port_rx.borrow_and_update();
+                        sleep = match port {
+                            Some(port) if !this.announce(port).await => ANNOUNCE_INITIAL_DELAY,
+                            _ => ANNOUNCE_INTERVAL,
+                        };
+                    }
+                };
+                tokio::pin!(announcer);
+
                 let mut futs = FuturesUnordered::new();
                 loop {
                     tokio::select! {
@@ -367,25 +490,54 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
                         r = &mut looper => {
                             return r
                         }
+                        _ = &mut announcer => {
+                            return Ok(())
+                        }
                     }
                 }
             },
         )
     }
 
+    // Returns false if there was no one to announce to yet.
+    async fn announce(&self, port: u16) -> bool {
+        let targets = self.callbacks.announce_targets.read().clone();
+        if targets.is_empty() {
+            debug!("no nodes to announce to yet");
+            return false;
+        }
+        let responses = futures::future::join_all(targets.iter().map(|t| {
+            self.dht.request(
+                Request::Announce {
+                    info_hash: self.info_hash,
+                    token: t.token.clone(),
+                    port,
+                },
+                t.addr,
+            )
+        }))
+        .await;
+        let succeeded = responses
+            .iter()
+            .filter(|r| matches!(r, Ok(ResponseOrError::Response(_))))
+            .count();
+        debug!(port, "announced to {succeeded}/{} nodes", targets.len());
+        true
+    }
+
     fn get_peers_root(&self) -> anyhow::Result<usize> {
         let mut count = 0;
-        for (id, addr) in self
-            .dht
-            .routing_table
-            .read()
-            .sorted_by_distance_from(self.info_hash)
-            .iter()
-            .map(|n| (n.id(), n.addr()))
-            .take(8)
-        {
-            count += 1;
-            self.node_tx.send((Some(id), addr, 0))?;
+        for table in self.dht.routing_tables() {
+            for (id, addr) in table
+                .read()
+                .sorted_by_distance_from(self.info_hash)
+                .iter()
+                .map(|n| (n.id(), n.addr()))
+                .take(8)
+            {
+                count += 1;
+                self.node_tx.send((Some(id), addr, 0))?;
+            }
         }
         Ok(count)
     }
@@ -402,44 +554,49 @@ impl<C: RecursiveRequestCallbacks> RecursiveRequest<C> {
             self.callbacks.on_request_start(self, id, addr);
         }
 
-        let response = self.dht.request(self.request.clone(), addr).await.map(|r| {
-            self.mark_node_responded(addr, &r);
-            r
-        });
+        let response = self.dht.request(self.request.clone(), addr).await;
+        match &response {
+            Ok(r) => self.mark_node_responded(addr, r),
+            Err(_) => self.mark_node_error(addr),
+        };
         if let Some(id) = id {
             self.callbacks.on_request_end(self, id, addr, &response);
         }
 
-        let response = match self.dht.request(self.request.clone(), addr).await {
-            Ok(ResponseOrError::Response(r)) => r,
+        let response = match response {
+            Ok(ResponseOrError::Response(r)) => *r,
             Ok(ResponseOrError::Error(e)) => bail!("error response: {:?}", e),
-            Err(e) => {
-                self.mark_node_error(addr);
-                return Err(e);
-            }
+            Err(e) => return Err(e),
         };
 
         if let Some(peers) = response.values {
             for peer in peers {
-                self.peer_tx.send(SocketAddr::V4(peer.addr))?;
+                self.peer_tx.send(peer.addr)?;
             }
         }
 
-        if let Some(nodes) = response.nodes {
-            for node in nodes.nodes {
-                let addr = SocketAddr::V4(node.addr);
-                let should_request = self.should_request_node(node.id, addr, depth);
-                trace!(
-                    "should_request={}, id={:?}, addr={}, depth={}/{}",
-                    should_request,
-                    node.id,
-                    addr,
-                    depth,
-                    self.max_depth
-                );
-                if should_request {
-                    self.node_tx.send((Some(node.id), addr, depth + 1))?;
-                }
+        let nodes = response
+            .nodes
+            .into_iter()
+            .flat_map(|n| n.nodes)
+            .map(|n| (n.id, SocketAddr::V4(n.addr)));
+        let nodes6 = response
+            .nodes6
+            .into_iter()
+            .flat_map(|n| n.nodes)
+            .map(|n| (n.id, SocketAddr::V6(n.addr)));
+        for (id, addr) in nodes.chain(nodes6) {
+            let should_request = self.should_request_node(id, addr, depth);
+            trace!(
+                "should_request={}, id={:?}, addr={}, depth={}/{}",
+                should_request,
+                id,
+                addr,
+                depth,
+                self.max_depth
+            );
+            if should_request {
+                self.node_tx.send((Some(id), addr, depth + 1))?;
             }
         }
         Ok(())
@@ -536,43 +693,73 @@ pub struct DhtState {
     inflight_by_transaction_id: DashMap<(u16, SocketAddr), OutstandingRequest>,
 
     routing_table: RwLock<RoutingTable>,
+    // IPv6 nodes are kept separately (BEP 32).
+    routing_table_v6: RwLock<RoutingTable>,
     listen_addr: SocketAddr,
 
     // Sending requests to the worker.
-    rate_limiter: RateLimiter,
+    rate_limiter: DhtRateLimiter,
     // This is to send raw messages
     worker_sender: UnboundedSender<WorkerSendRequest>,
 
     cancellation_token: CancellationToken,
 
     pub(crate) peer_store: PeerStore,
+
+    // What other nodes tell us our IP is.
+    external_ip_votes: RwLock<ExternalIpVotes>,
+    // What we believed our IP was at startup, until enough nodes tell us.
+    configured_external_ip: Option<IpAddr>,
+
+    // BEP 43: we only query, and tell other nodes not to query us.
+    read_only: bool,
 }
 
 impl DhtState {
+    // The rest of the settings come from "config", with the node id already validated.
     fn new_internal(
         id: Id20,
         sender: UnboundedSender<WorkerSendRequest>,
-        routing_table: Option<RoutingTable>,
         listen_addr: SocketAddr,
-        peer_store: PeerStore,
-        cancellation_token: CancellationToken,
+        config: DhtConfig,
     ) -> Self {
-        let routing_table = routing_table.unwrap_or_else(|| RoutingTable::new(id, None));
+        let routing_table = config
+            .routing_table
+            .unwrap_or_else(|| RoutingTable::new(id, None));
+        let routing_table_v6 = config
+            .routing_table_v6
+            .unwrap_or_else(|| RoutingTable::new(id, None));
         Self {
             id,
             next_transaction_id: AtomicU16::new(0),
             inflight_by_transaction_id: Default::default(),
             routing_table: RwLock::new(routing_table),
+            routing_table_v6: RwLock::new(routing_table_v6),
             worker_sender: sender,
             listen_addr,
-            rate_limiter: make_rate_limiter(),
-            peer_store,
-            cancellation_token,
+            rate_limiter: DhtRateLimiter::new(config.rate_limits),
+            peer_store: config.peer_store.unwrap_or_else(|| PeerStore::new(id)),
+            cancellation_token: config.cancellation_token.unwrap_or_default(),
+            external_ip_votes: Default::default(),
+            configured_external_ip: config.external_ip,
+            read_only: config.read_only,
         }
     }
 
+    // The routing table for nodes of this address family.
+    fn routing_table_for(&self, addr: &SocketAddr) -> &RwLock<RoutingTable> {
+        match addr {
+            SocketAddr::V4(_) => &self.routing_table,
+            SocketAddr::V6(_) => &self.routing_table_v6,
+        }
+    }
+
+    fn routing_tables(&self) -> impl Iterator<Item = &RwLock<RoutingTable>> {
+        [&self.routing_table, &self.routing_table_v6].into_iter()
+    }
+
     async fn request(&self, request: Request, addr: SocketAddr) -> anyhow::Result<ResponseOrError> {
-        self.rate_limiter.acquire_one().await;
+        self.rate_limiter.acquire_query().await;
         let (tid, message) = self.create_request(request);
         let key = (tid, addr);
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -616,15 +803,29 @@ impl DhtState {
                 transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
                 version: None,
                 ip: None,
+                read_only: self.read_only,
+                kind: MessageKind::GetPeersRequest(GetPeersRequest {
+                    id: self.id,
+                    info_hash,
+                    scrape: None,
+                }),
+            },
+            Request::Scrape(info_hash) => Message {
+                transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
+                version: None,
+                ip: None,
+                read_only: self.read_only,
                 kind: MessageKind::GetPeersRequest(GetPeersRequest {
                     id: self.id,
                     info_hash,
+                    scrape: Some(1),
                 }),
             },
             Request::FindNode(target) => Message {
                 transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
                 version: None,
                 ip: None,
+                read_only: self.read_only,
                 kind: MessageKind::FindNodeRequest(FindNodeRequest {
                     id: self.id,
                     target,
@@ -634,6 +835,7 @@ impl DhtState {
                 transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
                 version: None,
                 ip: None,
+                read_only: self.read_only,
                 kind: MessageKind::PingRequest(PingRequest { id: self.id }),
             },
             Request::Announce {
@@ -647,10 +849,12 @@ impl DhtState {
                     info_hash,
                     port,
                     token,
+                    seed: None,
                 }),
                 transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
                 version: None,
                 ip: None,
+                read_only: self.read_only,
             },
         };
         (transaction_id, message)
@@ -661,24 +865,34 @@ impl DhtState {
         msg: Message<ByteBufOwned>,
         addr: SocketAddr,
     ) -> anyhow::Result<()> {
+        // Nodes of the requester's address family, in "nodes" for IPv4 and "nodes6" for IPv6.
         let generate_compact_nodes = |target| {
-            let nodes = self
-                .routing_table
-                .read()
-                .sorted_by_distance_from(target)
-                .into_iter()
-                .filter_map(|r| {
-                    Some(Node {
-                        id: r.id(),
-                        addr: match r.addr() {
-                            SocketAddr::V4(v4) => v4,
-                            SocketAddr::V6(_) => return None,
-                        },
-                    })
-                })
-                .take(8)
-                .collect::<Vec<_>>();
-            CompactNodeInfo { nodes }
+            let table = self.routing_table_for(&addr).read();
+            let closest = table.sorted_by_distance_from(target);
+            match addr {
+                SocketAddr::V4(_) => {
+                    let nodes = closest
+                        .into_iter()
+                        .filter_map(|r| match r.addr() {
+                            SocketAddr::V4(addr) => Some(Node { id: r.id(), addr }),
+                            SocketAddr::V6(_) => None,
+                        })
+                        .take(8)
+                        .collect();
+                    (Some(CompactNodeInfo { nodes }), None)
+                }
+                SocketAddr::V6(_) => {
+                    let nodes = closest
+                        .into_iter()
+                        .filter_map(|r| match r.addr() {
+                            SocketAddr::V6(addr) => Some(Node { id: r.id(), addr }),
+                            SocketAddr::V4(_) => None,
+                        })
+                        .take(8)
+                        .collect();
+                    (None, Some(CompactNodeInfo { nodes }))
+                }
+            }
         };
 
         match &msg.kind {
@@ -697,6 +911,10 @@ impl DhtState {
                     }
                 };
 
+                if let (Some(SocketAddr::V4(ip)), MessageKind::Response(_)) = (msg.ip, &msg.kind) {
+                    self.on_external_ip_vote(*ip.ip(), addr);
+                }
+
                 let response_or_error = match msg.kind {
                     MessageKind::Error(e) => ResponseOrError::Error(e),
                     MessageKind::Response(r) => ResponseOrError::Response(r),
@@ -718,19 +936,35 @@ impl DhtState {
 
         trace!("received query from {addr}: {msg:?}");
 
+        if self.read_only {
+            trace!("read-only, not responding to {addr}");
+            return Ok(());
+        }
+
+        if !self.rate_limiter.try_acquire_response() {
+            trace!("out of DHT traffic budget, not responding to {addr}");
+            return Ok(());
+        }
+
+        // Tell the node which IP we see it at, so it can pick a node id for it (BEP 42).
+        let requester_ip = Some(addr);
+
         match &msg.kind {
             // Otherwise, respond to a query.
             MessageKind::PingRequest(req) => {
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
-                    ip: None,
-                    kind: MessageKind::Response(bprotocol::Response {
+                    ip: requester_ip,
+                    read_only: false,
+                    kind: MessageKind::Response(Box::new(bprotocol::Response {
                         id: self.id,
                         ..Default::default()
-                    }),
+                    })),
                 };
-                self.routing_table.write().mark_last_query(&req.id);
+                self.routing_table_for(&addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 self.worker_sender.send(WorkerSendRequest {
                     our_tid: None,
                     message,
@@ -739,17 +973,20 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::AnnouncePeer(ann) => {
-                self.routing_table.write().mark_last_query(&ann.id);
+                self.routing_table_for(&addr)
+                    .write()
+                    .mark_last_query(&ann.id);
                 let added = self.peer_store.store_peer(ann, addr);
                 trace!("{addr}: added_peer={added}, announce={ann:?}");
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
-                    ip: None,
-                    kind: MessageKind::Response(bprotocol::Response {
+                    ip: requester_ip,
+                    read_only: false,
+                    kind: MessageKind::Response(Box::new(bprotocol::Response {
                         id: self.id,
                         ..Default::default()
-                    }),
+                    })),
                 };
                 self.worker_sender.send(WorkerSendRequest {
                     our_tid: None,
@@ -759,21 +996,36 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::GetPeersRequest(req) => {
-                let compact_node_info = generate_compact_nodes(req.info_hash);
-                let compact_peer_info = self.peer_store.get_for_info_hash(req.info_hash);
-                self.routing_table.write().mark_last_query(&req.id);
+                let (nodes, nodes6) = generate_compact_nodes(req.info_hash);
+                let compact_peer_info = self
+                    .peer_store
+                    .get_for_info_hash(req.info_hash, addr.is_ipv6());
+                let (bf_seeds, bf_peers) = match req.scrape {
+                    Some(1) => {
+                        let (seeds, peers) = self.peer_store.scrape(req.info_hash);
+                        (Some(seeds), Some(peers))
+                    }
+                    _ => (None, None),
+                };
+                self.routing_table_for(&addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
-                    ip: None,
-                    kind: MessageKind::Response(bprotocol::Response {
+                    ip: requester_ip,
+                    read_only: false,
+                    kind: MessageKind::Response(Box::new(bprotocol::Response {
                         id: self.id,
-                        nodes: Some(compact_node_info),
+                        nodes,
+                        nodes6,
                         values: Some(compact_peer_info),
                         token: Some(ByteBufOwned::from(
                             &self.peer_store.gen_token_for(req.id, addr)[..],
                         )),
-                    }),
+                        bf_seeds,
+                        bf_peers,
+                    })),
                 };
                 self.worker_sender.send(WorkerSendRequest {
                     our_tid: None,
@@ -783,17 +1035,21 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::FindNodeRequest(req) => {
-                let compact_node_info = generate_compact_nodes(req.target);
-                self.routing_table.write().mark_last_query(&req.id);
+                let (nodes, nodes6) = generate_compact_nodes(req.target);
+                self.routing_table_for(&addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
-                    ip: None,
-                    kind: MessageKind::Response(bprotocol::Response {
+                    ip: requester_ip,
+                    read_only: false,
+                    kind: MessageKind::Response(Box::new(bprotocol::Response {
                         id: self.id,
-                        nodes: Some(compact_node_info),
+                        nodes,
+                        nodes6,
                         ..Default::default()
-                    }),
+                    })),
                 };
                 self.worker_sender.send(WorkerSendRequest {
                     our_tid: None,
@@ -806,11 +1062,25 @@ impl DhtState {
         }
     }
 
+    fn on_external_ip_vote(&self, ip: Ipv4Addr, voter: SocketAddr) {
+        if !self.external_ip_votes.write().add_vote(ip, voter.ip()) {
+            return;
+        }
+        info!(%ip, "DHT external IP changed");
+        if !is_node_id_valid(&self.id, ip.into()) {
+            warn!(
+                "DHT node id {:?} is not derived from our external IP {} (BEP 42)",
+                self.id, ip
+            );
+        }
+    }
+
     pub fn get_stats(&self) -> DhtStats {
         DhtStats {
             id: self.id,
             outstanding_requests: self.inflight_by_transaction_id.len(),
             routing_table_size: self.routing_table.read().len(),
+            routing_table_size_v6: self.routing_table_v6.read().len(),
         }
     }
 }
@@ -818,6 +1088,7 @@ impl DhtState {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Request {
     GetPeers(Id20),
+    Scrape(Id20),
     FindNode(Id20),
     Announce {
         info_hash: Id20,
@@ -828,7 +1099,7 @@ enum Request {
 }
 
 enum ResponseOrError {
-    Response(Response<ByteBufOwned>),
+    Response(Box<Response<ByteBufOwned>>),
     Error(ErrorDescription<ByteBufOwned>),
 }
 
@@ -842,7 +1113,8 @@ impl core::fmt::Debug for ResponseOrError {
 }
 
 struct DhtWorker {
-    socket: UdpSocket,
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
     dht: Arc<DhtState>,
 }
 
@@ -891,6 +1163,11 @@ impl DhtWorker {
     }
 
     async fn bootstrap(&self, bootstrap_addrs: &[String]) -> anyhow::Result<()> {
+        if bootstrap_addrs.is_empty() {
+            // E.g. an isolated network, where nodes are added by hand or from peers.
+            info!("no DHT bootstrap nodes configured");
+            return Ok(());
+        }
         let mut futs = FuturesUnordered::new();
 
         for addr in bootstrap_addrs.iter() {
@@ -918,14 +1195,27 @@ impl DhtWorker {
             let mut iteration = 0;
             loop {
                 interval.tick().await;
+                if self.dht.rate_limiter.is_saturated() {
+                    debug!("out of DHT traffic budget, skipping bucket refresh");
+                    continue;
+                }
                 let mut found = 0;
-                for bucket in self.dht.routing_table.read().iter_buckets() {
-                    if bucket.leaf.last_refreshed.elapsed() < INACTIVITY_TIMEOUT {
-                        continue;
+                for table in self.dht.routing_tables() {
+                    let table = table.read();
+                    for bucket in table.iter_buckets() {
+                        if bucket.leaf.last_refreshed.elapsed() < INACTIVITY_TIMEOUT {
+                            continue;
+                        }
+                        found += 1;
+                        let random_id = bucket.random_within();
+                        let addrs = table
+                            .sorted_by_distance_from(random_id)
+                            .iter()
+                            .map(|n| n.addr())
+                            .take(8)
+                            .collect::<Vec<_>>();
+                        tx.send((random_id, addrs)).unwrap();
                     }
-                    found += 1;
-                    let random_id = bucket.random_within();
-                    tx.send(random_id).unwrap();
                 }
                 trace!("iteration {}, refreshing {} buckets", iteration, found);
                 iteration += 1;
@@ -937,16 +1227,8 @@ impl DhtWorker {
         loop {
             tokio::select! {
                 _ = &mut filler => {},
-                random_id = rx.recv() => {
-                    let random_id = random_id.unwrap();
-                    let addrs = self
-                        .dht
-                        .routing_table
-                        .read()
-                        .sorted_by_distance_from(random_id)
-                        .iter()
-                        .map(|n| n.addr())
-                        .take(8).collect::<Vec<_>>();
+                r = rx.recv() => {
+                    let (random_id, addrs) = r.unwrap();
                     futs.push(
                         RecursiveRequest::find_node_for_routing_table(
                             self.dht.clone(), random_id, addrs.into_iter()
@@ -966,14 +1248,20 @@ impl DhtWorker {
             let mut iteration = 0;
             loop {
                 interval.tick().await;
+                if self.dht.rate_limiter.is_saturated() {
+                    debug!("out of DHT traffic budget, skipping pinging nodes");
+                    continue;
+                }
                 let mut found = 0;
-                for node in self.dht.routing_table.read().iter() {
-                    if matches!(
-                        node.status(),
-                        NodeStatus::Questionable | NodeStatus::Unknown
-                    ) {
-                        found += 1;
-                        tx.send((node.id(), node.addr())).unwrap();
+                for table in self.dht.routing_tables() {
+                    for node in table.read().iter() {
+                        if matches!(
+                            node.status(),
+                            NodeStatus::Questionable | NodeStatus::Unknown
+                        ) {
+                            found += 1;
+                            tx.send((node.id(), node.addr())).unwrap();
+                        }
                     }
                 }
                 trace!("iteration {}, pinging {} nodes", iteration, found);
@@ -989,13 +1277,14 @@ impl DhtWorker {
                 r = rx.recv() => {
                     let (id, addr) = r.unwrap();
                     futs.push(async move {
-                        self.dht.routing_table.write().mark_outgoing_request(&id);
+                        let table = self.dht.routing_table_for(&addr);
+                        table.write().mark_outgoing_request(&id);
                         match self.dht.request(Request::Ping, addr).await {
                             Ok(_) => {
-                                self.dht.routing_table.write().mark_response(&id);
+                                table.write().mark_response(&id);
                             },
                             Err(e) => {
-                                self.dht.routing_table.write().mark_error(&id);
+                                table.write().mark_error(&id);
                                 debug!("error: {e:?}");
                             }
                         }
@@ -1006,9 +1295,35 @@ impl DhtWorker {
         }
     }
 
+    async fn read_socket(
+        socket: Option<&UdpSocket>,
+        output_tx: &Sender<(Message<ByteBufOwned>, SocketAddr)>,
+    ) -> anyhow::Result<()> {
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return std::future::pending().await,
+        };
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let (size, addr) = socket
+                .recv_from(&mut buf)
+                .await
+                .context("error reading from UDP socket")?;
+            match bprotocol::deserialize_message::<ByteBufOwned>(&buf[..size]) {
+                Ok(msg) => match output_tx.send((msg, addr)).await {
+                    Ok(_) => {}
+                    Err(_) => break,
+                },
+                Err(e) => debug!("{}: error deserializing incoming message: {}", addr, e),
+            }
+        }
+        Err::<(), _>(anyhow::anyhow!(
+            "DHT UDP socket reader over, nowhere to send responses to"
+        ))
+    }
+
     async fn framer(
         &self,
-        socket: &UdpSocket,
         mut input_rx: UnboundedReceiver<WorkerSendRequest>,
         output_tx: Sender<(Message<ByteBufOwned>, SocketAddr)>,
     ) -> anyhow::Result<()> {
@@ -1029,9 +1344,29 @@ impl DhtWorker {
                     message.transaction_id,
                     message.version,
                     message.ip,
+                    message.read_only,
                     message.kind,
                 )
                 .unwrap();
+                let socket = match addr {
+                    SocketAddr::V4(_) => self.socket_v4.as_ref(),
+                    SocketAddr::V6(_) => self.socket_v6.as_ref(),
+                };
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => {
+                        debug!("no socket to send to {addr}");
+                        if let Some(tid) = our_tid {
+                            self.on_send_error(
+                                tid,
+                                addr,
+                                anyhow::anyhow!("no socket for this address family"),
+                            );
+                        }
+                        continue;
+                    }
+                };
+                self.dht.rate_limiter.acquire_bytes(buf.len()).await;
                 if let Err(e) = socket.send_to(&buf, addr).await {
                     debug!("error sending to {addr}: {e:?}");
                     if let Some(tid) = our_tid {
@@ -1043,28 +1378,10 @@ impl DhtWorker {
                 "DHT UDP socket writer over, nowhere to read messages from"
             ))
         };
-        let reader = async {
-            let mut buf = vec![0u8; 16384];
-            loop {
-                let (size, addr) = socket
-                    .recv_from(&mut buf)
-                    .await
-                    .context("error reading from UDP socket")?;
-                match bprotocol::deserialize_message::<ByteBufOwned>(&buf[..size]) {
-                    Ok(msg) => match output_tx.send((msg, addr)).await {
-                        Ok(_) => {}
-                        Err(_) => break,
-                    },
-                    Err(e) => debug!("{}: error deserializing incoming message: {}", addr, e),
-                }
-            }
-            Err::<(), _>(anyhow::anyhow!(
-                "DHT UDP socket reader over, nowhere to send responses to"
-            ))
-        };
         let result = tokio::select! {
             err = writer => err,
-            err = reader => err,
+            err = Self::read_socket(self.socket_v4.as_ref(), &output_tx) => err,
+            err = Self::read_socket(self.socket_v6.as_ref(), &output_tx) => err,
         };
         result.context("DHT UDP framer closed")
     }
@@ -1076,7 +1393,7 @@ impl DhtWorker {
     ) -> anyhow::Result<()> {
         let (out_tx, mut out_rx) = channel(1);
         let framer = self
-            .framer(&self.socket, in_rx, out_tx)
+            .framer(in_rx, out_tx)
             .instrument(debug_span!("dht_framer"));
 
         let bootstrap = self.bootstrap(bootstrap_addrs);
@@ -1131,12 +1448,23 @@ impl DhtWorker {
 
 #[derive(Default)]
 pub struct DhtConfig {
+    /// Our node id. Random if not set, and regenerated if it's not valid for `external_ip`.
     pub peer_id: Option<Id20>,
+    /// "host:port" of the nodes to bootstrap from. Defaults to [crate::DHT_BOOTSTRAP], empty
+    /// disables bootstrapping.
     pub bootstrap_addrs: Option<Vec<String>>,
     pub routing_table: Option<RoutingTable>,
+    pub routing_table_v6: Option<RoutingTable>,
     pub listen_addr: Option<SocketAddr>,
     pub peer_store: Option<PeerStore>,
     pub cancellation_token: Option<CancellationToken>,
+    /// Bind the socket to this local address and/or interface.
+    pub socket_binding: Option<SocketBinding>,
+    /// Our external IP, if known. The node id is derived from it (BEP 42).
+    pub external_ip: Option<IpAddr>,
+    pub rate_limits: DhtRateLimits,
+    /// Query the DHT without answering other nodes' queries or storing peers for them (BEP 43).
+    pub read_only: bool,
 }
 
 impl DhtState {
@@ -1150,42 +1478,79 @@ impl DhtState {
     #[inline(never)]
     pub fn with_config(mut config: DhtConfig) -> BoxFuture<'static, anyhow::Result<Arc<Self>>> {
         async move {
-            let socket = match config.listen_addr {
-                Some(addr) => UdpSocket::bind(addr)
-                    .await
-                    .with_context(|| format!("error binding socket, address {addr}")),
-                None => UdpSocket::bind("0.0.0.0:0")
-                    .await
-                    .context("error binding socket, address 0.0.0.0:0"),
-            }?;
+            let binding = config.socket_binding.take().unwrap_or_default();
+            let socket = binding
+                .bind_udp(
+                    config
+                        .listen_addr
+                        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))),
+                )
+                .await?;
 
             let listen_addr = socket
                 .local_addr()
                 .context("cannot determine UDP listen addr")?;
             info!("DHT listening on {:?}", listen_addr);
 
-            let peer_id = config.peer_id.unwrap_or_else(generate_peer_id);
+            // Run an IPv6 DHT on the same port next to the IPv4 one (BEP 32), unless we were
+            // asked to bind to a specific address.
+            let (socket_v4, socket_v6) = match listen_addr {
+                SocketAddr::V6(_) => (None, Some(socket)),
+                SocketAddr::V4(_) if binding.local_addr.is_some() => (Some(socket), None),
+                SocketAddr::V4(addr) => match binding.bind_udp_v6_only(addr.port()) {
+                    Ok(socket_v6) => {
+                        info!("DHT listening on [::]:{}", addr.port());
+                        (Some(socket), Some(socket_v6))
+                    }
+                    Err(e) => {
+                        info!("IPv6 DHT disabled: {e:#}");
+                        (Some(socket), None)
+                    }
+                },
+            };
+
+            let mut peer_id = config.peer_id.unwrap_or_else(generate_peer_id);
+            if let Some(ip) = config.external_ip {
+                if !is_node_id_valid(&peer_id, ip) {
+                    peer_id = generate_node_id(ip);
+                    info!(%ip, "generated a new DHT peer id from external IP (BEP 42)");
+                }
+            }
+            if [&config.routing_table, &config.routing_table_v6]
+                .into_iter()
+                .flatten()
+                .any(|rt| rt.id() != peer_id)
+            {
+                // Re-insert the nodes we knew, as the table is laid out around our id.
+                let rebuild = |old: RoutingTable| {
+                    let mut rt = RoutingTable::new(peer_id, None);
+                    for node in old.iter() {
+                        rt.add_node(node.id(), node.addr());
+                    }
+                    rt
+                };
+                config.routing_table = config.routing_table.take().map(rebuild);
+                config.routing_table_v6 = config.routing_table_v6.take().map(rebuild);
+                // Stored peers were picked by distance to the old id.
+                config.peer_store = None;
+            }
             info!("starting up DHT with peer id {:?}", peer_id);
             let bootstrap_addrs = config
                 .bootstrap_addrs
+                .take()
                 .unwrap_or_else(|| crate::DHT_BOOTSTRAP.iter().map(|v| v.to_string()).collect());
 
-            let token = config.cancellation_token.take().unwrap_or_default();
-
             let (in_tx, in_rx) = unbounded_channel();
-            let state = Arc::new(Self::new_internal(
-                peer_id,
-                in_tx,
-                config.routing_table,
-                listen_addr,
-                config.peer_store.unwrap_or_else(|| PeerStore::new(peer_id)),
-                token,
-            ));
+            let state = Arc::new(Self::new_internal(peer_id, in_tx, listen_addr, config));
 
             spawn_with_cancel(error_span!("dht"), state.cancellation_token.clone(), {
                 let state = state.clone();
                 async move {
-                    let worker = DhtWorker { socket, dht: state };
+                    let worker = DhtWorker {
+                        socket_v4,
+                        socket_v6,
+                        dht: state,
+                    };
                     worker.start(in_rx, &bootstrap_addrs).await
                 }
             });
@@ -1207,10 +1572,30 @@ impl DhtState {
         ))
     }
 
+    /// Estimate how many seeds and downloaders a torrent has, from the bloom filters of the
+    /// nodes closest to it (BEP 33).
+    pub async fn scrape(self: &Arc<Self>, info_hash: Id20) -> anyhow::Result<DhtScrape> {
+        RecursiveRequest::scrape(self.clone(), info_hash).await
+    }
+
     pub fn listen_addr(&self) -> SocketAddr {
         self.listen_addr
     }
 
+    /// Whether we only query other nodes and don't answer them (BEP 43).
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Our external IP, once enough nodes agree on it.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip_votes
+            .read()
+            .external_ip()
+            .map(IpAddr::V4)
+            .or(self.configured_external_ip)
+    }
+
     pub fn stats(&self) -> DhtStats {
         self.get_stats()
     }
@@ -1222,4 +1607,66 @@ impl DhtState {
     pub fn clone_routing_table(&self) -> RoutingTable {
         self.routing_table.read().clone()
     }
+
+    pub fn with_routing_table_v6<R, F: FnOnce(&RoutingTable) -> R>(&self, f: F) -> R {
+        f(&self.routing_table_v6.read())
+    }
+
+    /// Ping a node we learned about outside of the DHT (e.g. from a peer's PORT message),
+    /// and add it to the routing table if it responds.
+    pub async fn ping_and_add_node(&self, addr: SocketAddr) -> anyhow::Result<Id20> {
+        let id = match self.request(Request::Ping, addr).await? {
+            ResponseOrError::Response(r) => r.id,
+            ResponseOrError::Error(e) => bail!("error response to ping: {e:?}"),
+        };
+        let mut rt = self.routing_table_for(&addr).write();
+        match rt.add_node(id, addr) {
+            InsertResult::WasExisting | InsertResult::ReplacedBad(_) | InsertResult::Added => {
+                rt.mark_response(&id);
+            }
+            InsertResult::Ignored => {}
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bencode::ByteBufOwned;
+    use librqbit_core::hash_id::Id20;
+
+    use super::{add_announce_target, AnnounceTarget, ANNOUNCE_TARGETS};
+
+    fn target(id: u8, token: &[u8]) -> AnnounceTarget {
+        let mut id_bytes = [0u8; 20];
+        id_bytes[0] = id;
+        AnnounceTarget {
+            id: Id20::new(id_bytes),
+            addr: SocketAddr::from(([127, 0, 0, 1], 6881 + id as u16)),
+            token: ByteBufOwned::from(token),
+        }
+    }
+
+    #[test]
+    fn test_add_announce_target() {
+        let info_hash = Id20::new([0u8; 20]);
+        let mut targets = Vec::new();
+        for id in (0..ANNOUNCE_TARGETS as u8 + 4).rev() {
+            add_announce_target(&mut targets, info_hash, target(id, b"old"));
+        }
+        assert_eq!(targets.len(), ANNOUNCE_TARGETS);
+        assert_eq!(targets[0].id, target(0, b"").id);
+        assert_eq!(targets[ANNOUNCE_TARGETS - 1].id, target(7, b"").id);
+
+        // A fresh token from a node we already know replaces the old one.
+        add_announce_target(&mut targets, info_hash, target(3, b"new"));
+        assert_eq!(targets.len(), ANNOUNCE_TARGETS);
+        assert_eq!(&targets[3].token[..], b"new");
+
+        // Nodes further than all the ones we have are dropped.
+        add_announce_target(&mut targets, info_hash, target(200, b"far"));
+        assert!(targets.iter().all(|t| t.id != target(200, b"").id));
+    }
 }
@@ -1,17 +1,22 @@
+mod bep33;
+mod bep42;
 mod bprotocol;
 mod dht;
 mod peer_store;
 mod persistence;
+mod rate_limit;
 mod routing_table;
 mod utils;
 
 use std::sync::Arc;
 use std::time::Duration;
 
+pub use crate::bep33::DhtScrape;
 pub use crate::dht::DhtStats;
 pub use crate::dht::{DhtConfig, DhtState, RequestPeersStream};
 pub use librqbit_core::hash_id::Id20;
 pub use persistence::{PersistentDht, PersistentDhtConfig};
+pub use rate_limit::DhtRateLimits;
 
 pub type Dht = Arc<DhtState>;
 
@@ -19,6 +24,10 @@ pub type Dht = Arc<DhtState>;
 pub(crate) const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
 // TODO: Not sure if we should re-query tbh.
 pub(crate) const REQUERY_INTERVAL: Duration = Duration::from_secs(60);
+// How often we announce ourselves to the closest nodes of a torrent.
+pub(crate) const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// How long we wait for the first lookup to find nodes before announcing.
+pub(crate) const ANNOUNCE_INITIAL_DELAY: Duration = Duration::from_secs(10);
 // After how long we consider a routing table node questionable.
 pub(crate) const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(15 * 60);
 
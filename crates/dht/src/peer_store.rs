@@ -1,9 +1,4 @@
-use std::{
-    collections::VecDeque,
-    net::{SocketAddr, SocketAddrV4},
-    str::FromStr,
-    sync::atomic::AtomicU32,
-};
+use std::{collections::VecDeque, net::SocketAddr, str::FromStr, sync::atomic::AtomicU32};
 
 use bencode::ByteBufOwned;
 use chrono::{DateTime, Utc};
@@ -16,7 +11,10 @@ use serde::{
 };
 use tracing::trace;
 
-use crate::bprotocol::{AnnouncePeer, CompactPeerInfo};
+use crate::{
+    bep33::ScrapeBloomFilter,
+    bprotocol::{AnnouncePeer, CompactPeerInfo},
+};
 
 #[derive(Serialize, Deserialize)]
 struct StoredToken {
@@ -28,8 +26,10 @@ struct StoredToken {
 
 #[derive(Serialize, Deserialize)]
 struct StoredPeer {
-    addr: SocketAddrV4,
+    addr: SocketAddr,
     time: DateTime<Utc>,
+    #[serde(default)]
+    seed: bool,
 }
 
 pub struct PeerStore {
@@ -134,27 +134,17 @@ impl PeerStore {
         token
     }
 
-    pub fn store_peer(&self, announce: &AnnouncePeer<ByteBufOwned>, addr: SocketAddr) -> bool {
+    pub fn store_peer(&self, announce: &AnnouncePeer<ByteBufOwned>, mut addr: SocketAddr) -> bool {
         // If the info_hash in announce is too far away from us, don't store it.
         // If the token doesn't match, don't store it.
         // If we are out of capacity, don't store it.
         // Otherwise, store it.
-        let mut addr = match addr {
-            SocketAddr::V4(addr) => addr,
-            SocketAddr::V6(_) => {
-                trace!("peer store: IPv6 not supported");
-                return false;
-            }
-        };
-
         if announce.info_hash.distance(&self.self_id) > self.max_distance {
             trace!("peer store: info_hash too far to store");
             return false;
         }
         if !self.tokens.read().iter().any(|t| {
-            t.token[..] == announce.token[..]
-                && t.addr == std::net::SocketAddr::V4(addr)
-                && t.node_id == announce.id
+            t.token[..] == announce.token[..] && t.addr == addr && t.node_id == announce.id
         }) {
             trace!("peer store: can't find this token / addr combination");
             return false;
@@ -164,6 +154,7 @@ impl PeerStore {
             addr.set_port(announce.port);
         }
 
+        let seed = announce.seed == Some(1);
         use dashmap::mapref::entry::Entry;
         let peers_entry = self.peers.entry(announce.info_hash);
         let peers_len = self.peers_len.load(std::sync::atomic::Ordering::SeqCst);
@@ -171,6 +162,7 @@ impl PeerStore {
             Entry::Occupied(mut occ) => {
                 if let Some(s) = occ.get_mut().iter_mut().find(|s| s.addr == addr) {
                     s.time = Utc::now();
+                    s.seed = seed;
                     return true;
                 }
                 if peers_len >= self.max_remembered_peers {
@@ -180,6 +172,7 @@ impl PeerStore {
                 occ.get_mut().push(StoredPeer {
                     addr,
                     time: Utc::now(),
+                    seed,
                 });
             }
             Entry::Vacant(vac) => {
@@ -190,6 +183,7 @@ impl PeerStore {
                 vac.insert(vec![StoredPeer {
                     addr,
                     time: Utc::now(),
+                    seed,
                 }]);
             }
         }
@@ -199,16 +193,34 @@ impl PeerStore {
         true
     }
 
-    pub fn get_for_info_hash(&self, info_hash: Id20) -> Vec<CompactPeerInfo> {
+    // Only peers of the same address family as the requesting node (BEP 32).
+    pub fn get_for_info_hash(&self, info_hash: Id20, ipv6: bool) -> Vec<CompactPeerInfo> {
         if let Some(stored_peers) = self.peers.get(&info_hash) {
             return stored_peers
                 .iter()
+                .filter(|p| p.addr.is_ipv6() == ipv6)
                 .map(|p| CompactPeerInfo { addr: p.addr })
                 .collect();
         }
         Vec::new()
     }
 
+    // Bloom filters of the seeds and downloaders we store for info_hash (BEP 33).
+    pub fn scrape(&self, info_hash: Id20) -> (ScrapeBloomFilter, ScrapeBloomFilter) {
+        let mut seeds = ScrapeBloomFilter::default();
+        let mut peers = ScrapeBloomFilter::default();
+        if let Some(stored_peers) = self.peers.get(&info_hash) {
+            for p in stored_peers.iter() {
+                if p.seed {
+                    seeds.insert(p.addr.ip());
+                } else {
+                    peers.insert(p.addr.ip());
+                }
+            }
+        }
+        (seeds, peers)
+    }
+
     #[allow(dead_code)]
     pub fn garbage_collect_peers(&self) {
         todo!()
@@ -3,11 +3,13 @@
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use librqbit_core::directories::get_configuration_directory;
+use librqbit_core::hash_id::Id20;
+use librqbit_core::socket_binding::SocketBinding;
 use librqbit_core::spawn_utils::spawn_with_cancel;
 use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
 use std::io::{BufReader, BufWriter};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio_util::sync::CancellationToken;
@@ -17,12 +19,23 @@ use tracing::{error, error_span, info, trace, warn};
 
 use crate::peer_store::PeerStore;
 use crate::routing_table::RoutingTable;
-use crate::{Dht, DhtConfig, DhtState};
+use crate::{Dht, DhtConfig, DhtRateLimits, DhtState};
 
 #[derive(Default)]
 pub struct PersistentDhtConfig {
     pub dump_interval: Option<Duration>,
     pub config_filename: Option<PathBuf>,
+    /// Bind the DHT socket to this local address and/or interface.
+    pub socket_binding: Option<SocketBinding>,
+    pub rate_limits: DhtRateLimits,
+    /// Query the DHT without answering other nodes' queries or storing peers for them (BEP 43).
+    pub read_only: bool,
+    /// "host:port" of the nodes to bootstrap from instead of the built-in ones. Empty
+    /// disables bootstrapping, e.g. for isolated networks.
+    pub bootstrap_addrs: Option<Vec<String>>,
+    /// Use this node id instead of the persisted one. It's kept even if it's not valid for
+    /// our external IP (BEP 42).
+    pub peer_id: Option<Id20>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,6 +43,12 @@ struct DhtSerialize<Table, PeerStore> {
     addr: SocketAddr,
     table: Table,
     peer_store: Option<PeerStore>,
+    #[serde(default)]
+    external_ip: Option<IpAddr>,
+    // Missing in files written before the IPv6 DHT. A plain #[serde(default)] would require
+    // Table: Default.
+    #[serde(default = "Option::default")]
+    table_v6: Option<Table>,
 }
 
 pub struct PersistentDht {
@@ -47,14 +66,18 @@ fn dump_dht(dht: &Dht, filename: &Path, tempfile_name: &Path) -> anyhow::Result<
 
     let addr = dht.listen_addr();
     match dht.with_routing_table(|r| {
-        serde_json::to_writer(
-            &mut file,
-            &DhtSerialize {
-                addr,
-                table: r,
-                peer_store: Some(&dht.peer_store),
-            },
-        )
+        dht.with_routing_table_v6(|r6| {
+            serde_json::to_writer(
+                &mut file,
+                &DhtSerialize {
+                    addr,
+                    table: r,
+                    peer_store: Some(&dht.peer_store),
+                    external_ip: dht.external_ip(),
+                    table_v6: Some(r6),
+                },
+            )
+        })
     }) {
         Ok(_) => {
             trace!("dumped DHT to {:?}", &tempfile_name);
@@ -126,18 +149,34 @@ impl PersistentDht {
                     }
                 },
             };
-            let (listen_addr, routing_table, peer_store) = de
-                .map(|de| (Some(de.addr), Some(de.table), de.peer_store))
-                .unwrap_or((None, None, None));
-            let peer_id = routing_table.as_ref().map(|r| r.id());
+            let (listen_addr, routing_table, routing_table_v6, peer_store, external_ip) = de
+                .map(|de| {
+                    (
+                        Some(de.addr),
+                        Some(de.table),
+                        de.table_v6,
+                        de.peer_store,
+                        de.external_ip,
+                    )
+                })
+                .unwrap_or((None, None, None, None, None));
+            let (peer_id, external_ip) = match config.peer_id {
+                Some(peer_id) => (Some(peer_id), None),
+                None => (routing_table.as_ref().map(|r| r.id()), external_ip),
+            };
 
             let dht_config = DhtConfig {
                 peer_id,
                 routing_table,
+                routing_table_v6,
                 listen_addr,
                 peer_store,
                 cancellation_token,
-                ..Default::default()
+                socket_binding: config.socket_binding.take(),
+                external_ip,
+                rate_limits: config.rate_limits,
+                read_only: config.read_only,
+                bootstrap_addrs: config.bootstrap_addrs.take(),
             };
             let dht = DhtState::with_config(dht_config).await?;
             spawn_with_cancel(
@@ -176,3 +215,16 @@ impl PersistentDht {
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DhtSerialize;
+
+    #[test]
+    fn test_load_without_table_v6() {
+        let de: DhtSerialize<serde_json::Value, serde_json::Value> =
+            serde_json::from_str(r#"{"addr":"0.0.0.0:4240","table":{},"peer_store":null}"#)
+                .unwrap();
+        assert!(de.table_v6.is_none());
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    num::NonZeroU32,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use leaky_bucket::RateLimiter;
+
+/// Limits on outgoing DHT traffic. Many home routers struggle with the number of UDP
+/// "connections" the DHT creates, so these are worth lowering on them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DhtRateLimits {
+    /// Outgoing packets per second. Defaults to the DHT_QUERIES_PER_SECOND environment
+    /// variable, or 250.
+    pub packets_per_second: Option<NonZeroU32>,
+    /// Outgoing bytes per second. Unlimited by default.
+    pub bytes_per_second: Option<NonZeroU32>,
+}
+
+fn make_rate_limiter(per_second: usize) -> RateLimiter {
+    let per_100_ms = (per_second / 10).max(1);
+
+    RateLimiter::builder()
+        .initial(per_100_ms)
+        .refill(per_100_ms)
+        .max(per_second)
+        .interval(Duration::from_millis(100))
+        .fair(false)
+        .build()
+}
+
+pub(crate) struct DhtRateLimiter {
+    packets: RateLimiter,
+    bytes: Option<RateLimiter>,
+    max_bytes: usize,
+    // Queries waiting for their turn.
+    waiting: AtomicUsize,
+}
+
+impl DhtRateLimiter {
+    pub fn new(limits: DhtRateLimits) -> Self {
+        let packets_per_second = match limits.packets_per_second {
+            Some(p) => p.get() as usize,
+            None => std::env::var("DHT_QUERIES_PER_SECOND")
+                .map(|v| v.parse().expect("couldn't parse DHT_QUERIES_PER_SECOND"))
+                .unwrap_or(250usize),
+        };
+        let max_bytes = limits
+            .bytes_per_second
+            .map(|b| b.get() as usize)
+            .unwrap_or(usize::MAX);
+        Self {
+            packets: make_rate_limiter(packets_per_second),
+            bytes: limits
+                .bytes_per_second
+                .map(|b| make_rate_limiter(b.get() as usize)),
+            max_bytes,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    // Wait for our turn to send a query.
+    pub async fn acquire_query(&self) {
+        struct Waiting<'a>(&'a AtomicUsize);
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        self.packets.acquire_one().await;
+    }
+
+    // Responses to other nodes' queries don't wait, they are dropped when we're out of budget.
+    pub fn try_acquire_response(&self) -> bool {
+        self.packets.try_acquire(1)
+    }
+
+    // Whether queries are waiting for budget, so background maintenance should hold off.
+    pub fn is_saturated(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) > 0
+    }
+
+    pub async fn acquire_bytes(&self, len: usize) {
+        if let Some(bytes) = self.bytes.as_ref() {
+            bytes.acquire(len.min(self.max_bytes)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use super::{DhtRateLimiter, DhtRateLimits};
+
+    #[tokio::test]
+    async fn test_responses_dropped_when_out_of_budget() {
+        let limiter = DhtRateLimiter::new(DhtRateLimits {
+            packets_per_second: NonZeroU32::new(10),
+            bytes_per_second: None,
+        });
+        // The bucket starts with a tenth of a second's worth.
+        assert!(limiter.try_acquire_response());
+        assert!(!limiter.try_acquire_response());
+        assert!(!limiter.is_saturated());
+
+        tokio::time::timeout(Duration::from_secs(1), limiter.acquire_query())
+            .await
+            .unwrap();
+        assert!(!limiter.is_saturated());
+    }
+}
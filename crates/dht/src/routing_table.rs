@@ -5,7 +5,7 @@ use rand::RngCore;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use tracing::{debug, trace};
 
-use crate::INACTIVITY_TIMEOUT;
+use crate::{bep42::is_node_id_valid, INACTIVITY_TIMEOUT};
 
 #[derive(Clone, Debug)]
 pub struct LeafBucket {
@@ -298,6 +298,23 @@ impl BucketTree {
                 return InsertResult::ReplacedBad(new_node);
             }
 
+            // If there's no room, prefer nodes whose id matches their IP (BEP 42).
+            let can_split = *self_id >= leaf.start && *self_id <= leaf.end_inclusive;
+            let full = self.size == self.max_size || (nodes.nodes.len() >= 8 && !can_split);
+            if full && is_node_id_valid(&id, addr.ip()) {
+                if let Some(invalid_node) = nodes
+                    .nodes
+                    .iter_mut()
+                    .find(|r| !is_node_id_valid(&r.id, r.addr.ip()))
+                {
+                    std::mem::swap(invalid_node, &mut new_node);
+                    nodes.nodes.sort_by_key(|n| n.id);
+                    debug!("replaced node with an invalid id {:?}", new_node);
+                    nodes.last_refreshed = Instant::now();
+                    return InsertResult::ReplacedBad(new_node);
+                }
+            }
+
             // if max size reached, don't bother
             if self.size == self.max_size {
                 trace!(
@@ -529,7 +546,7 @@ impl RoutingTable {
         let res = self.buckets.add_node(&self.id, id, addr);
         let replaced = match &res {
             InsertResult::WasExisting => false,
-            InsertResult::ReplacedBad(..) => true,
+            InsertResult::ReplacedBad(..) => false,
             InsertResult::Added => true,
             InsertResult::Ignored => false,
         };
@@ -586,9 +603,9 @@ mod tests {
     use librqbit_core::hash_id::Id20;
     use rand::Rng;
 
-    use crate::routing_table::compute_split_start_end;
+    use crate::{bep42::generate_node_id, routing_table::compute_split_start_end};
 
-    use super::{generate_random_id, RoutingTable};
+    use super::{generate_random_id, InsertResult, RoutingTable};
 
     #[test]
     fn compute_split_start_end_root() {
@@ -707,4 +724,41 @@ mod tests {
             assert!(id >= start && id <= end, "{:?}", id);
         }
     }
+
+    #[test]
+    fn test_prefer_valid_node_ids() {
+        let mut table = RoutingTable::new(Id20::new([0u8; 20]), None);
+        let public_addr = |i: u8| SocketAddr::from(([8, 8, 8, i], 6881));
+        // Fill the far half of the keyspace with nodes whose ids don't match their IPs.
+        for i in 0..8u8 {
+            let mut id = [0xffu8; 20];
+            id[19] = i;
+            assert!(matches!(
+                table.add_node(Id20::new(id), public_addr(i)),
+                InsertResult::Added
+            ));
+        }
+        // Split the root bucket so the far one can't split anymore.
+        table.add_node(Id20::new([1u8; 20]), public_addr(100));
+
+        let mut invalid = [0xfeu8; 20];
+        invalid[19] = 100;
+        assert!(matches!(
+            table.add_node(Id20::new(invalid), public_addr(101)),
+            InsertResult::Ignored
+        ));
+
+        let (ip, valid) = (0..=255u8)
+            .map(|i| {
+                let ip = Ipv4Addr::new(9, 9, 9, i);
+                (ip, generate_node_id(ip.into()))
+            })
+            .find(|(_, id)| id.0[0] >= 0x80)
+            .unwrap();
+        assert!(matches!(
+            table.add_node(valid, SocketAddr::from((ip, 6881))),
+            InsertResult::ReplacedBad(_)
+        ));
+        assert_eq!(table.len(), 9);
+    }
 }
@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/rqbit.proto").expect("error compiling proto/rqbit.proto");
+}
@@ -1,32 +1,110 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::Context;
 use buffers::ByteBufOwned;
-use dht::{DhtStats, Id20};
+use dht::{DhtScrape, DhtStats, Id20};
 use futures::Stream;
 use http::StatusCode;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
-use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::warn;
 
 use crate::{
     api_error::{ApiError, ApiErrorExt},
+    bandwidth_history::BandwidthHistorySnapshot,
+    chunk_tracker::FilePriority,
+    events::Event,
+    feeds::{FeedId, FeedSubscription},
     session::{
         AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, TorrentId,
+        TrackerAnnounceStatus,
     },
-    torrent_state::{
-        peer::stats::snapshot::{PeerStatsFilter, PeerStatsSnapshot},
-        ManagedTorrentHandle,
-    },
-    tracing_subscriber_config_utils::LineBroadcast,
+    session_stats::SessionStatsSnapshot,
+    torrent_state::ManagedTorrentHandle,
+    tracing_subscriber_config_utils::{LineBroadcast, LogStreamFilter},
+    tracker_stats::TrackerStats,
 };
 
+pub use crate::torrent_state::peer::stats::snapshot::{PeerStatsFilter, PeerStatsSnapshot};
+pub use crate::torrent_state::peer::PeerOrigin;
 pub use crate::torrent_state::stats::{LiveStats, TorrentStats};
 
 pub type Result<T> = std::result::Result<T, ApiError>;
 
+/// A torrent, by its id in the session or by its info hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentIdOrHash {
+    Id(TorrentId),
+    Hash(Id20),
+}
+
+impl From<TorrentId> for TorrentIdOrHash {
+    fn from(id: TorrentId) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl From<Id20> for TorrentIdOrHash {
+    fn from(info_hash: Id20) -> Self {
+        Self::Hash(info_hash)
+    }
+}
+
+impl std::fmt::Display for TorrentIdOrHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Hash(info_hash) => write!(f, "{}", info_hash.as_string()),
+        }
+    }
+}
+
+impl FromStr for TorrentIdOrHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // Info hashes are 40 hex characters, far longer than any id.
+        if s.len() == 40 {
+            return Ok(Self::Hash(Id20::from_str(s)?));
+        }
+        let id = s
+            .parse()
+            .with_context(|| format!("expected a torrent id or an info hash, got {s:?}"))?;
+        Ok(Self::Id(id))
+    }
+}
+
+impl Serialize for TorrentIdOrHash {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Id(id) => id.serialize(serializer),
+            Self::Hash(info_hash) => info_hash.as_string().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TorrentIdOrHash {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 /// Library API for use in different web frameworks.
 /// Contains all methods you might want to expose with (de)serializable inputs/outputs.
 #[derive(Clone)]
@@ -53,34 +131,88 @@ impl Api {
         &self.session
     }
 
-    pub fn mgr_handle(&self, idx: TorrentId) -> Result<ManagedTorrentHandle> {
-        self.session
-            .get(idx)
-            .ok_or(ApiError::torrent_not_found(idx))
+    pub fn mgr_handle(&self, idx: TorrentIdOrHash) -> Result<ManagedTorrentHandle> {
+        self.resolve(idx).map(|(_, handle)| handle)
+    }
+
+    fn resolve(&self, idx: TorrentIdOrHash) -> Result<(TorrentId, ManagedTorrentHandle)> {
+        match idx {
+            TorrentIdOrHash::Id(id) => self.session.get(id).map(|handle| (id, handle)),
+            TorrentIdOrHash::Hash(info_hash) => self.session.get_by_info_hash(info_hash),
+        }
+        .ok_or(ApiError::torrent_not_found(idx))
     }
 
     pub fn api_torrent_list(&self) -> TorrentListResponse {
-        let items = self.session.with_torrents(|torrents| {
-            torrents
-                .map(|(id, mgr)| TorrentListResponseItem {
-                    id,
-                    info_hash: mgr.info().info_hash.as_string(),
-                })
-                .collect()
-        });
-        TorrentListResponse { torrents: items }
+        self.api_torrent_list_ext(TorrentListQuery::default())
     }
 
-    pub fn api_torrent_details(&self, idx: TorrentId) -> Result<TorrentDetailsResponse> {
+    pub fn api_torrent_list_ext(&self, query: TorrentListQuery) -> TorrentListResponse {
+        // Torrent ids are handed out in the order torrents were added, so they are sorted by
+        // added time.
+        let mut torrents = self.session.torrents();
+        match query.sort_by {
+            TorrentListSortBy::Added => {}
+            TorrentListSortBy::Name => torrents.sort_by_cached_key(|(_, mgr)| {
+                mgr.info().info.name.as_ref().map(|n| n.to_string())
+            }),
+            TorrentListSortBy::Progress => sort_by_f64_key(&mut torrents, |mgr| {
+                let stats = mgr.stats();
+                if stats.total_bytes == 0 {
+                    return 0f64;
+                }
+                stats.progress_bytes as f64 / stats.total_bytes as f64
+            }),
+            TorrentListSortBy::DownloadRate => sort_by_f64_key(&mut torrents, |mgr| {
+                mgr.live()
+                    .map(|l| l.down_speed_estimator().mbps())
+                    .unwrap_or_default()
+            }),
+            TorrentListSortBy::UploadRate => sort_by_f64_key(&mut torrents, |mgr| {
+                mgr.live()
+                    .map(|l| l.up_speed_estimator().mbps())
+                    .unwrap_or_default()
+            }),
+        }
+        if query.desc {
+            torrents.reverse();
+        }
+
+        let total = torrents.len();
+        let items = torrents
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(id, mgr)| TorrentListResponseItem {
+                id,
+                info_hash: mgr.info().info_hash.as_string(),
+            })
+            .collect();
+        TorrentListResponse {
+            torrents: items,
+            total,
+        }
+    }
+
+    pub fn api_torrent_details(&self, idx: TorrentIdOrHash) -> Result<TorrentDetailsResponse> {
         let handle = self.mgr_handle(idx)?;
         let info_hash = handle.info().info_hash;
         let only_files = handle.only_files();
-        make_torrent_details(&info_hash, &handle.info().info, only_files.as_deref())
+        let mut details =
+            make_torrent_details(&info_hash, &handle.info().info, only_files.as_deref())?;
+        apply_renamed_files(&mut details, &handle.info().renamed_files());
+        if let Ok(progress) = handle.file_progress() {
+            for (file, have) in details.files.iter_mut().zip(progress) {
+                file.have_bytes = Some(have);
+            }
+        }
+        details.category = handle.info().category.clone();
+        Ok(details)
     }
 
     pub fn api_peer_stats(
         &self,
-        idx: TorrentId,
+        idx: TorrentIdOrHash,
         filter: PeerStatsFilter,
     ) -> Result<PeerStatsSnapshot> {
         let handle = self.mgr_handle(idx)?;
@@ -90,16 +222,31 @@ impl Api {
             .per_peer_stats_snapshot(filter))
     }
 
-    pub fn api_torrent_action_pause(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
+    pub fn api_peer_set_rate_limits(
+        &self,
+        idx: TorrentIdOrHash,
+        addr: SocketAddr,
+        limits: PeerRateLimitsRequest,
+    ) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
         handle
-            .pause()
+            .live()
+            .context("not live")?
+            .set_peer_rate_limits(addr, limits.upload_bps, limits.download_bps)
+            .with_error_status_code(StatusCode::NOT_FOUND)?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_pause(&self, idx: TorrentIdOrHash) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        self.session
+            .pause(&handle)
             .context("error pausing torrent")
             .with_error_status_code(StatusCode::BAD_REQUEST)?;
         Ok(Default::default())
     }
 
-    pub fn api_torrent_action_start(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
+    pub fn api_torrent_action_start(&self, idx: TorrentIdOrHash) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
         self.session
             .unpause(&handle)
@@ -108,23 +255,34 @@ impl Api {
         Ok(Default::default())
     }
 
-    pub fn api_torrent_action_forget(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
+    pub fn api_torrent_action_forget(&self, idx: TorrentIdOrHash) -> Result<EmptyJsonResponse> {
+        let (id, _) = self.resolve(idx)?;
         self.session
-            .delete(idx, false)
+            .delete(id, false)
             .context("error forgetting torrent")?;
         Ok(Default::default())
     }
 
-    pub fn api_torrent_action_delete(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
+    pub fn api_torrent_action_delete(&self, idx: TorrentIdOrHash) -> Result<EmptyJsonResponse> {
+        let (id, _) = self.resolve(idx)?;
         self.session
-            .delete(idx, true)
+            .delete(id, true)
             .context("error deleting torrent with files")?;
         Ok(Default::default())
     }
 
+    pub fn api_torrent_action_recheck(&self, idx: TorrentIdOrHash) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        self.session
+            .recheck(&handle)
+            .context("error rechecking torrent")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_update_only_files(
         &self,
-        idx: TorrentId,
+        idx: TorrentIdOrHash,
         only_files: &HashSet<usize>,
     ) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
@@ -134,6 +292,149 @@ impl Api {
         Ok(Default::default())
     }
 
+    pub fn api_torrent_action_set_sequential(
+        &self,
+        idx: TorrentIdOrHash,
+        sequential: bool,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_sequential(sequential)
+            .context("error setting sequential mode")?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_set_file_priorities(
+        &self,
+        idx: TorrentIdOrHash,
+        priorities: Vec<FilePriority>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_file_priorities(priorities)
+            .context("error setting file priorities")?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_set_head_tail_files(
+        &self,
+        idx: TorrentIdOrHash,
+        files: Vec<usize>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_head_tail_files(files)
+            .context("error setting head and tail first files")?;
+        Ok(Default::default())
+    }
+
+    pub async fn api_torrent_action_move_storage(
+        &self,
+        idx: TorrentIdOrHash,
+        new_dir: PathBuf,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        // Copying files between filesystems can take long, so don't block the runtime.
+        tokio::task::spawn_blocking(move || handle.move_storage(&new_dir))
+            .await
+            .context("move_storage task panicked")?
+            .context("error moving storage")?;
+        Ok(Default::default())
+    }
+
+    pub async fn api_torrent_action_rename_file(
+        &self,
+        idx: TorrentIdOrHash,
+        file_id: usize,
+        new_path: PathBuf,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        tokio::task::spawn_blocking(move || handle.rename_file(file_id, &new_path))
+            .await
+            .context("rename_file task panicked")?
+            .context("error renaming file")?;
+        Ok(Default::default())
+    }
+
+    pub async fn api_torrent_action_rename_root_folder(
+        &self,
+        idx: TorrentIdOrHash,
+        new_name: String,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        tokio::task::spawn_blocking(move || handle.rename_root_folder(&new_name))
+            .await
+            .context("rename_root_folder task panicked")?
+            .context("error renaming root folder")?;
+        Ok(Default::default())
+    }
+
+    pub async fn api_torrent_action_verify_piece(
+        &self,
+        idx: TorrentIdOrHash,
+        piece: u32,
+    ) -> Result<VerifyPieceResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .info()
+            .lengths
+            .validate_piece_index(piece)
+            .with_context(|| format!("invalid piece {piece}"))
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        let live = handle
+            .live()
+            .context("not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        let valid = live
+            .verify_piece(piece)
+            .await
+            .context("error verifying piece")?;
+        Ok(VerifyPieceResponse { valid })
+    }
+
+    pub fn api_torrent_trackers(&self, idx: TorrentIdOrHash) -> Result<Vec<TrackerStats>> {
+        Ok(self.mgr_handle(idx)?.tracker_stats())
+    }
+
+    pub async fn api_torrent_action_reannounce(
+        &self,
+        idx: TorrentIdOrHash,
+    ) -> Result<Vec<TrackerAnnounceStatus>> {
+        let handle = self.mgr_handle(idx)?;
+        Ok(self
+            .session
+            .reannounce(&handle)
+            .await
+            .context("error reannouncing")?)
+    }
+
+    pub fn api_feed_list(&self) -> FeedListResponse {
+        FeedListResponse {
+            feeds: self
+                .session
+                .feeds()
+                .into_iter()
+                .map(|(id, subscription)| FeedListResponseItem { id, subscription })
+                .collect(),
+        }
+    }
+
+    pub fn api_feed_add(&self, subscription: FeedSubscription) -> Result<FeedAddResponse> {
+        let id = self
+            .session
+            .add_feed(subscription)
+            .context("error adding feed")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(FeedAddResponse { id })
+    }
+
+    pub fn api_feed_delete(&self, id: FeedId) -> Result<EmptyJsonResponse> {
+        self.session
+            .remove_feed(id)
+            .with_error_status_code(StatusCode::NOT_FOUND)?;
+        Ok(Default::default())
+    }
+
     pub fn api_set_rust_log(&self, new_value: String) -> Result<EmptyJsonResponse> {
         let tx = self
             .rust_log_reload_tx
@@ -146,6 +447,7 @@ impl Api {
 
     pub fn api_log_lines_stream(
         &self,
+        filter: LogStreamFilter,
     ) -> Result<
         impl Stream<Item = std::result::Result<bytes::Bytes, BroadcastStreamRecvError>>
             + Send
@@ -155,10 +457,22 @@ impl Api {
         Ok(self
             .line_broadcast
             .as_ref()
-            .map(|sender| BroadcastStream::new(sender.subscribe()))
+            .map(|lines| lines.subscribe(filter))
             .context("line_rx wasn't set")?)
     }
 
+    /// Session metrics in the Prometheus text format.
+    pub fn api_metrics(&self) -> String {
+        crate::metrics::render(&self.session)
+    }
+
+    pub fn api_events_stream(
+        &self,
+    ) -> impl Stream<Item = std::result::Result<Event, BroadcastStreamRecvError>> + Send + 'static
+    {
+        self.session.subscribe()
+    }
+
     pub async fn api_add_torrent(
         &self,
         add: AddTorrent<'_>,
@@ -176,7 +490,7 @@ impl Api {
                     "{:?} is already managed, id={}, downloaded to {:?}",
                     managed.info_hash(),
                     id,
-                    &managed.info().out_dir
+                    &managed.info().out_dir()
                 ))
                 .with_error_status_code(StatusCode::CONFLICT);
             }
@@ -203,7 +517,7 @@ impl Api {
                 ApiAddTorrentResponse {
                     id: Some(id),
                     details,
-                    output_folder: handle.info().out_dir.to_string_lossy().into_owned(),
+                    output_folder: handle.info().out_dir().to_string_lossy().into_owned(),
                     seen_peers: None,
                 }
             }
@@ -219,28 +533,108 @@ impl Api {
             .ok_or(ApiError::dht_disabled())
     }
 
+    pub async fn api_dht_scrape(&self, info_hash: Id20) -> Result<DhtScrape> {
+        let dht = self.session.get_dht().ok_or(ApiError::dht_disabled())?;
+        Ok(dht.scrape(info_hash).await?)
+    }
+
     pub fn api_dht_table(&self) -> Result<impl Serialize> {
         let dht = self.session.get_dht().ok_or(ApiError::dht_disabled())?;
         Ok(dht.with_routing_table(|r| r.clone()))
     }
 
-    pub fn api_stats_v0(&self, idx: TorrentId) -> Result<LiveStats> {
+    pub fn api_session_stats(&self) -> SessionStatsSnapshot {
+        self.session.stats_snapshot()
+    }
+
+    pub fn api_session_bandwidth_history(&self) -> BandwidthHistorySnapshot {
+        self.session.bandwidth_history()
+    }
+
+    pub fn api_torrent_bandwidth_history(
+        &self,
+        idx: TorrentIdOrHash,
+    ) -> Result<BandwidthHistorySnapshot> {
+        let handle = self.mgr_handle(idx)?;
+        Ok(handle.live().context("not live")?.bandwidth_history())
+    }
+
+    pub fn api_stats_v0(&self, idx: TorrentIdOrHash) -> Result<LiveStats> {
         let mgr = self.mgr_handle(idx)?;
         let live = mgr.live().context("torrent not live")?;
         Ok(LiveStats::from(&*live))
     }
 
-    pub fn api_stats_v1(&self, idx: TorrentId) -> Result<TorrentStats> {
+    pub fn api_stats_v1(&self, idx: TorrentIdOrHash) -> Result<TorrentStats> {
         let mgr = self.mgr_handle(idx)?;
         Ok(mgr.stats())
     }
 
-    pub fn api_dump_haves(&self, idx: usize) -> Result<String> {
+    pub fn api_piece_map(
+        &self,
+        idx: TorrentIdOrHash,
+        query: PieceMapQuery,
+    ) -> Result<PieceMapResponse> {
+        use base64::{engine::general_purpose, Engine as _};
+        let mgr = self.mgr_handle(idx)?;
+        let mut response = PieceMapResponse {
+            total_pieces: mgr.info().lengths.total_pieces(),
+            bits_per_piece: 2,
+            states: None,
+            runs: None,
+        };
+        match query.encoding {
+            PieceMapEncoding::Base64 => {
+                let map = mgr.with_chunk_tracker(|chunks| chunks.get_piece_map())?;
+                response.states = Some(general_purpose::STANDARD.encode(map));
+            }
+            PieceMapEncoding::Rle => {
+                response.runs = Some(mgr.with_chunk_tracker(|chunks| chunks.get_piece_runs())?);
+            }
+        }
+        Ok(response)
+    }
+
+    pub fn api_dump_haves(&self, idx: TorrentIdOrHash) -> Result<String> {
         let mgr = self.mgr_handle(idx)?;
         Ok(mgr.with_chunk_tracker(|chunks| format!("{:?}", chunks.get_have_pieces()))?)
     }
 }
 
+// Stable sort by a float key. Ties keep the existing (added) order.
+fn sort_by_f64_key(
+    torrents: &mut Vec<(TorrentId, ManagedTorrentHandle)>,
+    key: impl Fn(&ManagedTorrentHandle) -> f64,
+) {
+    let mut keyed = torrents
+        .drain(..)
+        .map(|(id, mgr)| (key(&mgr), id, mgr))
+        .collect::<Vec<_>>();
+    keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+    torrents.extend(keyed.into_iter().map(|(_, id, mgr)| (id, mgr)));
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentListSortBy {
+    #[default]
+    Added,
+    Name,
+    Progress,
+    DownloadRate,
+    UploadRate,
+}
+
+#[derive(Default, Deserialize)]
+pub struct TorrentListQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub sort_by: TorrentListSortBy,
+    #[serde(default)]
+    pub desc: bool,
+}
+
 #[derive(Serialize)]
 pub struct TorrentListResponseItem {
     pub id: usize,
@@ -250,6 +644,43 @@ pub struct TorrentListResponseItem {
 #[derive(Serialize)]
 pub struct TorrentListResponse {
     pub torrents: Vec<TorrentListResponseItem>,
+    /// The number of torrents in the session, before applying limit and offset.
+    pub total: usize,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceMapEncoding {
+    #[default]
+    Base64,
+    Rle,
+}
+
+#[derive(Default, Deserialize)]
+pub struct PieceMapQuery {
+    #[serde(default)]
+    pub encoding: PieceMapEncoding,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyPieceResponse {
+    /// Whether the piece on disk matches its hash. If it doesn't, it's downloaded again.
+    pub valid: bool,
+}
+
+/// Per-piece state. Values: 0 - missing, 1 - downloading, 2 - have, 3 - failed hash check.
+#[derive(Serialize, Deserialize)]
+pub struct PieceMapResponse {
+    pub total_pieces: u32,
+    pub bits_per_piece: u8,
+    /// Packed 4 pieces per byte (first piece in the most significant bits) and
+    /// base64-encoded. Set with the base64 encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub states: Option<String>,
+    /// Runs of pieces in the same state, as [state, count] pairs in piece order. Set with
+    /// the rle encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runs: Option<Vec<(u8, u32)>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -258,16 +689,45 @@ pub struct TorrentDetailsResponseFile {
     pub components: Vec<String>,
     pub length: u64,
     pub included: bool,
+    /// Bytes downloaded and verified. Missing if the torrent isn't paused or live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub have_bytes: Option<u64>,
 }
 
 #[derive(Default, Serialize)]
 pub struct EmptyJsonResponse {}
 
+#[derive(Serialize)]
+pub struct FeedListResponseItem {
+    pub id: FeedId,
+    #[serde(flatten)]
+    pub subscription: FeedSubscription,
+}
+
+#[derive(Serialize)]
+pub struct FeedListResponse {
+    pub feeds: Vec<FeedListResponseItem>,
+}
+
+#[derive(Serialize)]
+pub struct FeedAddResponse {
+    pub id: FeedId,
+}
+
+/// Per-peer rate limits in bytes per second. A missing value removes the limit.
+#[derive(Default, Deserialize)]
+pub struct PeerRateLimitsRequest {
+    pub upload_bps: Option<NonZeroU32>,
+    pub download_bps: Option<NonZeroU32>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TorrentDetailsResponse {
     pub info_hash: String,
     pub name: Option<String>,
     pub files: Vec<TorrentDetailsResponseFile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -278,6 +738,18 @@ pub struct ApiAddTorrentResponse {
     pub seen_peers: Option<Vec<SocketAddr>>,
 }
 
+fn apply_renamed_files(details: &mut TorrentDetailsResponse, renamed: &HashMap<usize, PathBuf>) {
+    for (idx, path) in renamed {
+        if let Some(file) = details.files.get_mut(*idx) {
+            file.components = path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            file.name = file.components.join("/");
+        }
+    }
+}
+
 fn make_torrent_details(
     info_hash: &Id20,
     info: &TorrentMetaV1Info<ByteBufOwned>,
@@ -302,6 +774,7 @@ fn make_torrent_details(
                 components,
                 length,
                 included,
+                have_bytes: None,
             }
         })
         .collect();
@@ -309,5 +782,6 @@ fn make_torrent_details(
         info_hash: info_hash.as_string(),
         name: info.name.as_ref().map(|b| b.to_string()),
         files,
+        category: None,
     })
 }
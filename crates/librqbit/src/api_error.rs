@@ -2,6 +2,8 @@ use axum::response::{IntoResponse, Response};
 use http::StatusCode;
 use serde::{Serialize, Serializer};
 
+use crate::api::TorrentIdOrHash;
+
 // Convenience error type.
 #[derive(Debug)]
 pub struct ApiError {
@@ -19,7 +21,7 @@ impl ApiError {
         }
     }
 
-    pub const fn torrent_not_found(torrent_id: usize) -> Self {
+    pub const fn torrent_not_found(torrent_id: TorrentIdOrHash) -> Self {
         Self {
             status: Some(StatusCode::NOT_FOUND),
             kind: ApiErrorKind::TorrentNotFound(torrent_id),
@@ -75,7 +77,7 @@ impl ApiError {
 
 #[derive(Debug)]
 enum ApiErrorKind {
-    TorrentNotFound(usize),
+    TorrentNotFound(TorrentIdOrHash),
     DhtDisabled,
     Text(&'static str),
     Other(anyhow::Error),
@@ -93,13 +95,16 @@ impl Serialize for ApiError {
             status: u16,
             status_text: String,
             #[serde(skip_serializing_if = "Option::is_none")]
-            id: Option<usize>,
+            id: Option<TorrentIdOrHash>,
         }
         let mut serr: SerializedError = SerializedError {
-            error_kind: match self.kind {
+            error_kind: match &self.kind {
                 ApiErrorKind::TorrentNotFound(_) => "torrent_not_found",
                 ApiErrorKind::DhtDisabled => "dht_disabled",
-                ApiErrorKind::Other(_) => "internal_error",
+                ApiErrorKind::Other(e) => e
+                    .downcast_ref::<crate::Error>()
+                    .map(|e| e.kind_name())
+                    .unwrap_or("internal_error"),
                 ApiErrorKind::Text(_) => "internal_error",
             },
             human_readable: format!("{self}"),
@@ -114,9 +119,32 @@ impl Serialize for ApiError {
     }
 }
 
+fn status_of(e: &crate::Error) -> Option<StatusCode> {
+    match e {
+        crate::Error::InvalidTorrent(_) => Some(StatusCode::BAD_REQUEST),
+        crate::Error::TorrentNotFound(_) => Some(StatusCode::NOT_FOUND),
+        crate::Error::ShuttingDown => Some(StatusCode::SERVICE_UNAVAILABLE),
+        crate::Error::Tracker(_) => Some(StatusCode::BAD_GATEWAY),
+        _ => None,
+    }
+}
+
+impl From<crate::Error> for ApiError {
+    fn from(value: crate::Error) -> Self {
+        Self {
+            status: status_of(&value),
+            kind: ApiErrorKind::Other(value.into()),
+            plaintext: false,
+        }
+    }
+}
+
 impl From<anyhow::Error> for ApiError {
     fn from(value: anyhow::Error) -> Self {
-        let status = value.downcast_ref::<ApiError>().and_then(|e| e.status);
+        let status = value
+            .downcast_ref::<ApiError>()
+            .and_then(|e| e.status)
+            .or_else(|| value.downcast_ref::<crate::Error>().and_then(status_of));
         Self {
             status,
             kind: ApiErrorKind::Other(value),
@@ -0,0 +1,117 @@
+// Rolling history of per-second download and upload rates, so that UIs can draw speed graphs
+// without polling and diffing stats themselves.
+
+use std::{collections::VecDeque, time::Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+// 10 minutes of per-second samples.
+pub(crate) const BANDWIDTH_HISTORY_SECONDS: usize = 600;
+
+/// Rates in bytes per second.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BandwidthSample {
+    pub download_bps: u64,
+    pub upload_bps: u64,
+}
+
+/// Per-second samples, oldest first.
+#[derive(Debug, Serialize)]
+pub struct BandwidthHistorySnapshot {
+    pub interval_secs: u64,
+    pub samples: Vec<BandwidthSample>,
+}
+
+#[derive(Default)]
+struct BandwidthHistoryLocked {
+    samples: VecDeque<BandwidthSample>,
+    // Download and upload totals at the last call to add_totals().
+    last_totals: Option<(u64, u64, Instant)>,
+}
+
+#[derive(Default)]
+pub(crate) struct BandwidthHistory {
+    locked: Mutex<BandwidthHistoryLocked>,
+}
+
+impl BandwidthHistory {
+    pub fn add_sample(&self, sample: BandwidthSample) {
+        let mut g = self.locked.lock();
+        if g.samples.len() == BANDWIDTH_HISTORY_SECONDS {
+            g.samples.pop_front();
+        }
+        g.samples.push_back(sample);
+    }
+
+    // Add a sample computed from the total downloaded and uploaded bytes. The first call only
+    // remembers the totals.
+    pub fn add_totals(&self, downloaded: u64, uploaded: u64, now: Instant) {
+        let prev = self
+            .locked
+            .lock()
+            .last_totals
+            .replace((downloaded, uploaded, now));
+        let (prev_downloaded, prev_uploaded, prev_now) = match prev {
+            Some(prev) => prev,
+            None => return,
+        };
+        let elapsed = now.duration_since(prev_now).as_secs_f64();
+        if elapsed <= 0. {
+            return;
+        }
+        let rate = |cur: u64, prev: u64| (cur.saturating_sub(prev) as f64 / elapsed) as u64;
+        self.add_sample(BandwidthSample {
+            download_bps: rate(downloaded, prev_downloaded),
+            upload_bps: rate(uploaded, prev_uploaded),
+        });
+    }
+
+    pub fn last(&self) -> Option<BandwidthSample> {
+        self.locked.lock().samples.back().copied()
+    }
+
+    pub fn snapshot(&self) -> BandwidthHistorySnapshot {
+        BandwidthHistorySnapshot {
+            interval_secs: 1,
+            samples: self.locked.lock().samples.iter().copied().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BandwidthHistory, BandwidthSample, BANDWIDTH_HISTORY_SECONDS};
+
+    #[test]
+    fn test_bandwidth_history() {
+        let h = BandwidthHistory::default();
+        let start = Instant::now();
+        h.add_totals(100, 0, start);
+        assert!(h.snapshot().samples.is_empty());
+
+        h.add_totals(1100, 50, start + Duration::from_secs(1));
+        h.add_totals(2100, 150, start + Duration::from_secs(3));
+        let expected = vec![
+            BandwidthSample {
+                download_bps: 1000,
+                upload_bps: 50,
+            },
+            BandwidthSample {
+                download_bps: 500,
+                upload_bps: 50,
+            },
+        ];
+        assert_eq!(h.snapshot().samples, expected);
+        assert_eq!(h.last(), Some(expected[1]));
+
+        for _ in 0..BANDWIDTH_HISTORY_SECONDS {
+            h.add_sample(BandwidthSample::default());
+        }
+        let samples = h.snapshot().samples;
+        assert_eq!(samples.len(), BANDWIDTH_HISTORY_SECONDS);
+        assert!(samples.iter().all(|s| *s == BandwidthSample::default()));
+    }
+}
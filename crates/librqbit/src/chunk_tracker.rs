@@ -1,12 +1,39 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::Context;
-use librqbit_core::lengths::{ChunkInfo, Lengths, ValidPieceIndex};
+use bitvec::{order::Msb0, slice::BitSlice};
+use librqbit_core::{
+    compact_bitfield::CompactBitfield,
+    lengths::{ChunkInfo, Lengths, ValidPieceIndex},
+};
+use parking_lot::RwLock;
 use peer_binary_protocol::Piece;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use crate::type_aliases::BF;
-
+use crate::{
+    piece_picker::{
+        LastPieceFirstPicker, PickerContext, PieceAvailability, PiecePicker, StreamingPicker,
+        DEFAULT_STREAMING_LOOKAHEAD_PIECES,
+    },
+    type_aliases::BF,
+};
+
+// Used instead of the torrent's picker in sequential mode.
+static SEQUENTIAL_PICKER: StreamingPicker = StreamingPicker {
+    lookahead: DEFAULT_STREAMING_LOOKAHEAD_PIECES,
+};
+
+// Unlike the peers' bitfields (CompactBitfield), the ones here are plain dense BFs. There's one
+// tracker per torrent, so they don't add up with the number of peers, and they are combined
+// with bitwise operations on whole words, e.g. queued = selected & !have, and sliced by piece
+// ranges, neither of which the compact representation is good at.
 pub struct ChunkTracker {
     // This forms the basis of a "queue" to pull from.
     // It's set to 1 if we need a piece, but the moment we start requesting a peer,
@@ -25,18 +52,80 @@ pub struct ChunkTracker {
     // These are the pieces that we actually have, fully checked and downloaded.
     have: BF,
 
+    // Pieces that failed the hash check and were not successfully re-downloaded since.
+    hash_failed: BF,
+
     // The pieces that the user selected. This doesn't change unless update_only_files
     // was called.
     selected: BF,
 
     lengths: Lengths,
 
-    // What pieces to download first.
-    priority_piece_ids: Vec<usize>,
+    // The order to download pieces in.
+    picker: Arc<dyn PiecePicker>,
+
+    // Download pieces in order, e.g. to watch a video while it's downloading.
+    sequential: bool,
+
+    // The highest priority of the files each piece belongs to.
+    piece_priorities: Vec<FilePriority>,
+
+    // "queue_pieces" split by piece priority, indexed by FilePriority, so that the picker
+    // orders one priority at a time from the highest, and doesn't need to sort.
+    queued_by_priority: Vec<BF>,
+
+    // The first and last pieces of files that players need early to probe the container. They
+    // go before the other pieces of the same priority.
+    head_tail_pieces: BF,
+
+    // A copy of "have" and quick to retrieve stats, that MUST be in sync with the BFs
+    // above (have/selected). Can be read without locking the chunk tracker.
+    shared: Arc<ChunkTrackerShared>,
+}
 
-    // Quick to retrieve stats, that MUST be in sync with the BFs
-    // above (have/selected).
-    hns: HaveNeededSelected,
+// What serving uploads and reporting stats need, kept up to date by the chunk tracker so that
+// they can be read without locking it. Picking pieces and marking chunks downloaded still go
+// through the lock.
+#[derive(Default)]
+pub struct ChunkTrackerShared {
+    have: Box<[AtomicU8]>,
+    hns: RwLock<HaveNeededSelected>,
+}
+
+impl ChunkTrackerShared {
+    fn new(have: &BF, hns: HaveNeededSelected) -> Self {
+        let shared = Self {
+            have: (0..have.len().div_ceil(8))
+                .map(|_| AtomicU8::new(0))
+                .collect(),
+            hns: RwLock::new(hns),
+        };
+        for id in have.iter_ones() {
+            shared.set_have(id, true);
+        }
+        shared
+    }
+
+    fn set_have(&self, id: usize, value: bool) {
+        let bit = 1 << (id % 8);
+        if value {
+            self.have[id / 8].fetch_or(bit, Ordering::Release);
+        } else {
+            self.have[id / 8].fetch_and(!bit, Ordering::Release);
+        }
+    }
+
+    pub fn have_piece(&self, index: ValidPieceIndex) -> bool {
+        let id = index.get() as usize;
+        self.have
+            .get(id / 8)
+            .map(|b| b.load(Ordering::Acquire) & (1 << (id % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    pub fn hns(&self) -> HaveNeededSelected {
+        *self.hns.read()
+    }
 }
 
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
@@ -80,9 +169,7 @@ fn compute_chunk_have_status(lengths: &Lengths, have_pieces: &BF) -> anyhow::Res
     let mut chunk_bf = BF::from_boxed_slice(vec.into_boxed_slice());
 
     for piece in lengths.iter_piece_infos() {
-        let chunks = lengths.chunks_per_piece(piece.piece_index) as usize;
-        let offset = (lengths.default_chunks_per_piece() * piece.piece_index.get()) as usize;
-        let range = offset..(offset + chunks);
+        let range = lengths.chunk_range(piece.piece_index);
         if have_pieces[piece.piece_index.get() as usize] {
             chunk_bf
                 .get_mut(range.clone())
@@ -118,6 +205,42 @@ fn compute_queued_pieces(have_pieces: &BF, selected_pieces: &BF) -> anyhow::Resu
     ))
 }
 
+/// How soon the pieces of a file should be downloaded. Pieces of "skip" files are not downloaded
+/// at all, unless they are shared with other files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    const HIGHEST_FIRST: [FilePriority; 4] = [
+        FilePriority::High,
+        FilePriority::Normal,
+        FilePriority::Low,
+        FilePriority::Skip,
+    ];
+}
+
+// The state of one piece, as shown in piece maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PieceState {
+    Missing = 0,
+    Downloading = 1,
+    Have = 2,
+    Failed = 3,
+}
+
+/// The chunks of unfinished pieces that are on disk, by piece index, to resume these pieces
+/// after a restart. Each piece's chunks are packed into bytes, the first chunk in the most
+/// significant bit.
+pub type PartialPieces = BTreeMap<u32, Vec<u8>>;
+
 pub enum ChunkMarkingResult {
     PreviouslyCompleted,
     NotCompleted,
@@ -135,32 +258,27 @@ impl ChunkTracker {
         let needed_pieces = compute_queued_pieces(&have_pieces, &selected_pieces)
             .context("error computing needed pieces")?;
 
-        // TODO: ideally this needs to be a list based on needed files, e.g.
-        // last needed piece for each file. But let's keep simple for now.
-
-        // TODO: bitvec is bugged, the short version panics.
-        // let last_needed_piece_id = needed_pieces.iter_ones().next_back();
-        let last_needed_piece_id = needed_pieces
-            .iter()
-            .enumerate()
-            .filter_map(|(id, b)| if *b { Some(id) } else { None })
-            .last();
-
-        // The last pieces first. Often important information is stored in the last piece.
-        // E.g. if it's a video file, than the last piece often contains some index, or just
-        // players look into it, and it's better be there.
-        let priority_piece_ids = last_needed_piece_id.into_iter().collect();
+        let mut hash_failed = have_pieces.clone();
+        hash_failed.fill(false);
+        let head_tail_pieces = hash_failed.clone();
+        let mut queued_by_priority = vec![hash_failed.clone(); FilePriority::HIGHEST_FIRST.len()];
+        queued_by_priority[FilePriority::Normal as usize] = needed_pieces.clone();
         let mut ct = Self {
+            hash_failed,
             chunk_status: compute_chunk_have_status(&lengths, &have_pieces)
                 .context("error computing chunk status")?,
             queue_pieces: needed_pieces,
             selected: selected_pieces,
             lengths,
             have: have_pieces,
-            priority_piece_ids,
-            hns: HaveNeededSelected::default(),
+            picker: Arc::new(LastPieceFirstPicker),
+            sequential: false,
+            piece_priorities: vec![FilePriority::Normal; lengths.total_pieces() as usize],
+            queued_by_priority,
+            head_tail_pieces,
+            shared: Default::default(),
         };
-        ct.hns = ct.calc_hns();
+        ct.shared = Arc::new(ChunkTrackerShared::new(&ct.have, ct.calc_hns()));
         Ok(ct)
     }
 
@@ -175,12 +293,70 @@ impl ChunkTracker {
     pub fn get_selected_pieces(&self) -> &BF {
         &self.selected
     }
+
+    pub fn get_piece_state(&self, index: ValidPieceIndex) -> PieceState {
+        let id = index.get() as usize;
+        if self.have[id] {
+            return PieceState::Have;
+        }
+        if self.hash_failed[id] {
+            return PieceState::Failed;
+        }
+        // Selected pieces leave the queue the moment they get requested from a peer.
+        let reserved = self.selected[id] && !self.queue_pieces[id];
+        let partial = self
+            .chunk_status
+            .get(self.lengths.chunk_range(index))
+            .map(|s| s.any())
+            .unwrap_or_default();
+        if reserved || partial {
+            return PieceState::Downloading;
+        }
+        PieceState::Missing
+    }
+
+    // Pack the states of all pieces, 2 bits per piece (see PieceState for values),
+    // 4 pieces per byte, the first piece in the most significant bits.
+    pub fn get_piece_map(&self) -> Vec<u8> {
+        let mut map = vec![0u8; (self.lengths.total_pieces() as usize).div_ceil(4)];
+        for piece in self.lengths.iter_piece_infos() {
+            let id = piece.piece_index.get() as usize;
+            let state = self.get_piece_state(piece.piece_index) as u8;
+            map[id / 4] |= state << (6 - (id % 4) * 2);
+        }
+        map
+    }
+
+    // The states of all pieces as runs of (state, count), for compact piece bars of large
+    // torrents where most pieces share a state.
+    pub fn get_piece_runs(&self) -> Vec<(u8, u32)> {
+        let mut runs: Vec<(u8, u32)> = Vec::new();
+        for piece in self.lengths.iter_piece_infos() {
+            let state = self.get_piece_state(piece.piece_index) as u8;
+            match runs.last_mut() {
+                Some((s, count)) if *s == state => *count += 1,
+                _ => runs.push((state, 1)),
+            }
+        }
+        runs
+    }
+
     pub fn reserve_needed_piece(&mut self, index: ValidPieceIndex) {
-        self.queue_pieces.set(index.get() as usize, false)
+        self.set_queued(index.get() as usize, false)
     }
 
-    pub fn get_hns(&self) -> &HaveNeededSelected {
-        &self.hns
+    fn set_queued(&mut self, id: usize, queued: bool) {
+        self.queue_pieces.set(id, queued);
+        let priority = self.piece_priorities[id] as usize;
+        self.queued_by_priority[priority].set(id, queued);
+    }
+
+    pub fn get_hns(&self) -> HaveNeededSelected {
+        self.shared.hns()
+    }
+
+    pub fn shared(&self) -> &Arc<ChunkTrackerShared> {
+        &self.shared
     }
 
     fn calc_hns(&self) -> HaveNeededSelected {
@@ -198,16 +374,135 @@ impl ChunkTracker {
         hns
     }
 
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn set_piece_picker(&mut self, picker: Arc<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    fn active_picker(&self) -> &dyn PiecePicker {
+        if self.sequential {
+            &SEQUENTIAL_PICKER
+        } else {
+            self.picker.as_ref()
+        }
+    }
+
+    pub fn is_piece_queued(&self, index: usize) -> bool {
+        self.queue_pieces.get(index).map(|b| *b).unwrap_or_default()
+    }
+
+    // Each piece gets the highest priority of the files it intersects.
+    pub fn set_file_priorities(
+        &mut self,
+        file_lengths_iterator: impl IntoIterator<Item = u64>,
+        priorities: &[FilePriority],
+    ) {
+        let piece_length = self.lengths.default_piece_length() as u64;
+        let mut piece_priorities = vec![FilePriority::Skip; self.lengths.total_pieces() as usize];
+        let mut offset = 0u64;
+        for (idx, len) in file_lengths_iterator.into_iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let priority = priorities.get(idx).copied().unwrap_or_default();
+            let first_piece = (offset / piece_length) as usize;
+            let last_piece = ((offset + len - 1) / piece_length) as usize;
+            for p in piece_priorities
+                .iter_mut()
+                .take(last_piece + 1)
+                .skip(first_piece)
+            {
+                *p = (*p).max(priority);
+            }
+            offset += len;
+        }
+
+        self.piece_priorities = piece_priorities;
+        for queued in self.queued_by_priority.iter_mut() {
+            queued.fill(false);
+        }
+        for (id, priority) in self.piece_priorities.iter().enumerate() {
+            if self.queue_pieces[id] {
+                self.queued_by_priority[*priority as usize].set(id, true);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get_piece_priority(&self, index: ValidPieceIndex) -> FilePriority {
+        self.piece_priorities[index.get() as usize]
+    }
+
+    // Download the first and last pieces of these files before the rest.
+    pub fn set_head_tail_files(
+        &mut self,
+        file_lengths_iterator: impl IntoIterator<Item = u64>,
+        files: &[usize],
+    ) {
+        let piece_length = self.lengths.default_piece_length() as u64;
+        self.head_tail_pieces.fill(false);
+        let mut offset = 0u64;
+        for (idx, len) in file_lengths_iterator.into_iter().enumerate() {
+            if len > 0 && files.contains(&idx) {
+                let first_piece = (offset / piece_length) as usize;
+                let last_piece = ((offset + len - 1) / piece_length) as usize;
+                self.head_tail_pieces.set(first_piece, true);
+                self.head_tail_pieces.set(last_piece, true);
+            }
+            offset += len;
+        }
+    }
+
+    pub fn is_head_tail_piece(&self, index: usize) -> bool {
+        self.head_tail_pieces
+            .get(index)
+            .map(|b| *b)
+            .unwrap_or_default()
+    }
+
+    // Whether it doesn't matter to the user which of the two queued pieces is downloaded first,
+    // so that they can be picked by how rare they are instead.
+    pub(crate) fn are_equally_needed(&self, a: usize, b: usize) -> bool {
+        !self.sequential
+            && self.piece_priorities.get(a) == self.piece_priorities.get(b)
+            && !self.is_head_tail_piece(a)
+            && !self.is_head_tail_piece(b)
+    }
+
     pub fn iter_queued_pieces(&self) -> impl Iterator<Item = usize> + '_ {
-        self.priority_piece_ids
+        self.iter_queued_pieces_with_availability(None)
+    }
+
+    // In the order of the piece picker. Pieces of higher priority files go first, and within
+    // the same priority, head and tail pieces go before the others. The picker only runs for
+    // a priority once the ones before it are exhausted.
+    pub(crate) fn iter_queued_pieces_with_availability<'a>(
+        &'a self,
+        availability: Option<&'a PieceAvailability>,
+    ) -> impl Iterator<Item = usize> + 'a {
+        FilePriority::HIGHEST_FIRST
             .iter()
-            .copied()
-            .filter(move |piece_id| self.queue_pieces[*piece_id])
-            .chain(
-                self.queue_pieces
-                    .iter_ones()
-                    .filter(move |id| !self.priority_piece_ids.contains(id)),
-            )
+            .map(|p| &self.queued_by_priority[*p as usize])
+            .filter(|queued| queued.any())
+            .flat_map(move |queued| {
+                let is_queued = |id: &usize| queued.get(*id).map(|b| *b).unwrap_or_default();
+                let ctx = PickerContext::new(
+                    self.lengths.total_pieces() as usize,
+                    queued,
+                    &self.have,
+                    &self.selected,
+                    availability,
+                );
+                let head_tail = self.head_tail_pieces.iter_ones().filter(is_queued);
+                let rest = self
+                    .active_picker()
+                    .pick(ctx)
+                    .filter(move |id| is_queued(id) && !self.is_head_tail_piece(*id));
+                head_tail.chain(rest)
+            })
     }
 
     // None if wrong chunk
@@ -224,7 +519,7 @@ impl ChunkTracker {
         // This will trigger the requesters to re-check each chunk in this piece.
         let chunk_range = self.lengths.chunk_range(index);
         if !self.chunk_status.get(chunk_range)?.all() {
-            self.queue_pieces.set(index.get() as usize, true);
+            self.set_queued(index.get() as usize, true);
         }
         Some(true)
     }
@@ -239,33 +534,166 @@ impl ChunkTracker {
             return;
         }
         debug!("remarking piece={} as broken", index);
-        self.queue_pieces.set(index.get() as usize, true);
+        // Only downloaded again if it's still needed.
+        let selected = self.selected[index.get() as usize];
+        self.set_queued(index.get() as usize, selected);
+        self.mark_piece_chunks_not_downloaded(index);
+    }
+
+    // Forget the chunks downloaded so far without putting the piece back in the queue, for
+    // when whoever reserved it starts over.
+    pub fn mark_piece_chunks_not_downloaded(&mut self, index: ValidPieceIndex) {
         if let Some(s) = self.chunk_status.get_mut(self.lengths.chunk_range(index)) {
             s.fill(false);
         }
     }
 
+    pub fn mark_piece_hash_failed(&mut self, index: ValidPieceIndex) {
+        if let Some(mut failed) = self.hash_failed.get_mut(index.get() as usize) {
+            *failed = true;
+        }
+        self.mark_piece_broken_if_not_have(index);
+    }
+
     pub fn mark_piece_downloaded(&mut self, idx: ValidPieceIndex) {
         let id = idx.get() as usize;
+        self.hash_failed.set(id, false);
         if !self.have[id] {
             self.have.set(id, true);
             let len = self.lengths.piece_length(idx) as u64;
-            self.hns.have_bytes += len;
+            let mut hns = self.shared.hns.write();
+            hns.have_bytes += len;
             if self.selected[id] {
-                self.hns.needed_bytes -= len;
+                hns.needed_bytes -= len;
             }
+            self.shared.set_have(id, true);
         }
     }
 
-    pub fn is_chunk_ready_to_upload(&self, chunk: &ChunkInfo) -> bool {
-        self.have
-            .get(chunk.piece_index.get() as usize)
+    // Forget a piece we had, e.g. because it no longer verifies on disk, so that it gets
+    // downloaded again. Returns false if we didn't have it.
+    pub fn mark_piece_lost(&mut self, idx: ValidPieceIndex) -> bool {
+        let id = idx.get() as usize;
+        if !self.have.get(id).map(|b| *b).unwrap_or(false) {
+            return false;
+        }
+        self.have.set(id, false);
+        let len = self.lengths.piece_length(idx) as u64;
+        {
+            let mut hns = self.shared.hns.write();
+            hns.have_bytes -= len;
+            if self.selected[id] {
+                hns.needed_bytes += len;
+            }
+        }
+        self.shared.set_have(id, false);
+        self.mark_piece_broken_if_not_have(idx);
+        true
+    }
+
+    // Whether a peer with this bitfield has any piece we still need.
+    pub fn is_interested_in(&self, bitfield: &CompactBitfield) -> bool {
+        if self.get_hns().finished() {
+            return false;
+        }
+        bitfield.iter_ones().any(|id| {
+            let id = id as usize;
+            self.selected.get(id).map(|b| *b).unwrap_or(false) && !self.have[id]
+        })
+    }
+
+    // Bytes of each file covered by pieces we have, given the lengths of all the files.
+    pub fn file_progress(&self, file_lengths: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let piece_length = self.lengths.default_piece_length() as u64;
+        let mut offset = 0u64;
+        file_lengths
+            .into_iter()
+            .map(|len| {
+                let file_offset = offset;
+                offset += len;
+                if len == 0 {
+                    return 0;
+                }
+                let first = (file_offset / piece_length) as u32;
+                let last = ((file_offset + len - 1) / piece_length) as u32;
+                (first..=last)
+                    .filter(|id| self.have.get(*id as usize).map(|b| *b).unwrap_or(false))
+                    .map(|id| self.lengths.size_of_piece_in_file(id, file_offset, len))
+                    .sum()
+            })
+            .collect()
+    }
+
+    pub fn is_chunk_downloaded(&self, chunk: &ChunkInfo) -> bool {
+        self.chunk_status
+            .get(chunk.absolute_index as usize)
             .map(|b| *b)
             .unwrap_or(false)
     }
 
-    pub fn get_remaining_bytes(&self) -> u64 {
-        self.hns.needed_bytes
+    // How much of the piece was downloaded, from 0 to 1.
+    pub fn get_piece_progress(&self, index: ValidPieceIndex) -> f64 {
+        match self.chunk_status.get(self.lengths.chunk_range(index)) {
+            Some(s) if !s.is_empty() => s.count_ones() as f64 / s.len() as f64,
+            _ => 0.,
+        }
+    }
+
+    pub fn has_downloaded_chunks(&self, index: ValidPieceIndex) -> bool {
+        self.chunk_status
+            .get(self.lengths.chunk_range(index))
+            .map(|s| s.any())
+            .unwrap_or(false)
+    }
+
+    // Pieces that have some, but not all of their chunks. Complete pieces are either being
+    // hashed, or will be downloaded again.
+    pub fn get_partial_pieces(&self) -> PartialPieces {
+        let mut partial = PartialPieces::new();
+        for piece in self.lengths.iter_piece_infos() {
+            let id = piece.piece_index.get();
+            if self.have[id as usize] {
+                continue;
+            }
+            let chunks = match self
+                .chunk_status
+                .get(self.lengths.chunk_range(piece.piece_index))
+            {
+                Some(chunks) if chunks.any() && !chunks.all() => chunks,
+                _ => continue,
+            };
+            let mut packed = BF::from_boxed_slice(vec![0u8; chunks.len().div_ceil(8)].into());
+            packed[..chunks.len()].copy_from_bitslice(chunks);
+            partial.insert(id, packed.into_boxed_slice().into_vec());
+        }
+        partial
+    }
+
+    // Mark the chunks of unfinished pieces as downloaded, so that only the rest of each piece
+    // is requested. If the data on disk turns out to be wrong, the piece fails the hash check
+    // and is downloaded again in full.
+    pub fn restore_partial_pieces(&mut self, partial: &PartialPieces) {
+        for (id, packed) in partial.iter() {
+            let index = match self.lengths.validate_piece_index(*id) {
+                Some(index) => index,
+                None => continue,
+            };
+            if self.have[*id as usize] {
+                continue;
+            }
+            let range = self.lengths.chunk_range(index);
+            let saved = match BitSlice::<u8, Msb0>::from_slice(packed).get(..range.len()) {
+                Some(saved) if !saved.all() => saved,
+                _ => continue,
+            };
+            if let Some(chunks) = self.chunk_status.get_mut(range) {
+                chunks.copy_from_bitslice(saved);
+            }
+        }
+    }
+
+    pub fn has_queued_pieces(&self) -> bool {
+        self.queue_pieces.any()
     }
 
     // return true if the whole piece is marked downloaded
@@ -356,8 +784,7 @@ impl ChunkTracker {
                         (false, true) => {}
                         (false, false) => {
                             // don't need the piece, and don't have it - cancel downloading it
-                            self.queue_pieces
-                                .set(current_piece.piece_index.get() as usize, false);
+                            self.set_queued(current_piece.piece_index.get() as usize, false);
                         }
                     }
 
@@ -377,20 +804,36 @@ impl ChunkTracker {
             needed_bytes,
             selected_bytes,
         };
-        self.hns = res;
+        *self.shared.hns.write() = res;
         Ok(res)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
-
-    use librqbit_core::{constants::CHUNK_SIZE, lengths::Lengths};
-
-    use crate::{chunk_tracker::HaveNeededSelected, type_aliases::BF};
-
-    use super::{compute_chunk_have_status, ChunkTracker};
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use librqbit_core::{
+        compact_bitfield::CompactBitfield, constants::CHUNK_SIZE, lengths::Lengths,
+    };
+    use peer_binary_protocol::Piece;
+
+    use crate::{
+        chunk_tracker::HaveNeededSelected,
+        piece_picker::{PickerContext, PiecePicker},
+        type_aliases::BF,
+    };
+
+    use super::{
+        compute_chunk_have_status, ChunkMarkingResult, ChunkTracker, FilePriority, PieceState,
+    };
+    use crate::piece_picker::DEFAULT_STREAMING_LOOKAHEAD_PIECES as SEQUENTIAL_LOOKAHEAD_PIECES;
 
     #[test]
     fn test_compute_chunk_status() {
@@ -610,4 +1053,399 @@ mod tests {
         assert_eq!(ct.queue_pieces[1], true);
         assert_eq!(ct.queue_pieces[2], true);
     }
+
+    #[test]
+    fn test_mark_chunks_odd_piece_lengths() {
+        // Piece lengths that are neither a power of two nor a multiple of CHUNK_SIZE.
+        for (total_len, piece_len) in [
+            (100_000u64, 3u32),
+            (100_000, 12_345),
+            (1_000_000, CHUNK_SIZE * 3 + 7),
+            (1_000_000, 1_000_000 - 1),
+            (1_000_000, 1_000_000 + 1),
+        ] {
+            let l = Lengths::new(total_len, piece_len).unwrap();
+            let bf_len = l.piece_bitfield_bytes();
+            let have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+            let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+            let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+            assert_eq!(ct.get_hns().needed_bytes, total_len);
+
+            for piece in l.iter_piece_infos() {
+                let chunks = l.iter_chunk_infos(piece.piece_index).collect::<Vec<_>>();
+                for (idx, chunk) in chunks.iter().enumerate() {
+                    let block = vec![0u8; chunk.size as usize];
+                    let res = ct
+                        .mark_chunk_downloaded(&Piece {
+                            index: piece.piece_index.get(),
+                            begin: chunk.offset,
+                            block: &block[..],
+                        })
+                        .unwrap();
+                    match res {
+                        ChunkMarkingResult::Completed => assert_eq!(idx, chunks.len() - 1),
+                        ChunkMarkingResult::NotCompleted => assert!(idx < chunks.len() - 1),
+                        ChunkMarkingResult::PreviouslyCompleted => {
+                            panic!("piece {} completed twice", piece.piece_index)
+                        }
+                    }
+                }
+                ct.mark_piece_downloaded(piece.piece_index);
+            }
+
+            assert!(ct.chunk_status[..l.total_chunks() as usize].all());
+            assert!(ct.get_hns().finished());
+            assert_eq!(ct.get_hns().have_bytes, total_len);
+        }
+    }
+
+    #[test]
+    fn test_piece_map() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 5, CHUNK_SIZE).unwrap();
+        assert_eq!(l.total_pieces(), 5);
+        let piece = |i| l.validate_piece_index(i).unwrap();
+
+        let bf_len = l.piece_bitfield_bytes();
+        let mut have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        have.set(0, true);
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+
+        ct.reserve_needed_piece(piece(1));
+        ct.reserve_needed_piece(piece(2));
+        ct.mark_piece_hash_failed(piece(2));
+
+        assert_eq!(ct.get_piece_state(piece(0)), PieceState::Have);
+        assert_eq!(ct.get_piece_state(piece(1)), PieceState::Downloading);
+        assert_eq!(ct.get_piece_state(piece(2)), PieceState::Failed);
+        assert_eq!(ct.get_piece_state(piece(3)), PieceState::Missing);
+        assert_eq!(ct.get_piece_map(), vec![0b10_01_11_00, 0]);
+        assert_eq!(ct.get_piece_runs(), vec![(2, 1), (1, 1), (3, 1), (0, 2)]);
+
+        // A successful re-download clears the failure.
+        ct.mark_piece_downloaded(piece(2));
+        assert_eq!(ct.get_piece_state(piece(2)), PieceState::Have);
+        assert_eq!(ct.get_piece_map(), vec![0b10_01_10_00, 0]);
+        assert_eq!(ct.get_piece_runs(), vec![(2, 1), (1, 1), (2, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn test_mark_piece_lost() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 4, CHUNK_SIZE).unwrap();
+        let piece = |i| l.validate_piece_index(i).unwrap();
+
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+        let shared = ct.shared().clone();
+        assert!(ct.get_hns().finished());
+        assert!(shared.have_piece(piece(1)));
+
+        assert!(ct.mark_piece_lost(piece(1)));
+        assert!(!ct.mark_piece_lost(piece(1)));
+        assert_eq!(ct.get_piece_state(piece(1)), PieceState::Missing);
+        assert_eq!(ct.get_hns().have_bytes, CHUNK_SIZE as u64 * 3);
+        assert_eq!(ct.get_hns().needed_bytes, CHUNK_SIZE as u64);
+        assert_eq!(ct.iter_queued_pieces().collect::<Vec<_>>(), vec![1]);
+        assert!(!shared.have_piece(piece(1)));
+        assert!(shared.have_piece(piece(2)));
+        assert_eq!(shared.hns(), ct.get_hns());
+
+        ct.mark_piece_downloaded(piece(1));
+        assert!(ct.get_hns().finished());
+        assert!(shared.have_piece(piece(1)));
+        assert!(shared.hns().finished());
+    }
+
+    #[test]
+    fn test_mark_piece_broken() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 4, CHUNK_SIZE).unwrap();
+        let piece = |i| l.validate_piece_index(i).unwrap();
+
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0; bf_len].into_boxed_slice());
+        // Only the first 2 pieces are selected.
+        let selected = BF::from_boxed_slice(vec![0b1100_0000; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+        ct.reserve_needed_piece(piece(0));
+        assert_eq!(ct.iter_queued_pieces().collect::<Vec<_>>(), vec![1]);
+
+        ct.mark_piece_broken_if_not_have(piece(0));
+        ct.mark_piece_broken_if_not_have(piece(2));
+        let mut queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        queued.sort();
+        assert_eq!(queued, vec![0, 1]);
+
+        // Starting a reserved piece over doesn't queue it.
+        ct.reserve_needed_piece(piece(0));
+        let chunk = l.iter_chunk_infos(piece(0)).next().unwrap();
+        ct.mark_chunk_downloaded(&Piece {
+            index: 0,
+            begin: 0,
+            block: &[0u8; CHUNK_SIZE as usize][..],
+        })
+        .unwrap();
+        ct.mark_piece_chunks_not_downloaded(piece(0));
+        assert!(!ct.is_chunk_downloaded(&chunk));
+        assert_eq!(ct.iter_queued_pieces().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_is_interested_in() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 4, CHUNK_SIZE).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let mut have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        have.set(0, true);
+        let mut selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        selected.set(1, false);
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+
+        let mut peer = CompactBitfield::have_none(4);
+        assert!(!ct.is_interested_in(&peer));
+        // We have piece 0 and don't want piece 1.
+        peer.set(0, true);
+        peer.set(1, true);
+        assert!(!ct.is_interested_in(&peer));
+        peer.set(2, true);
+        assert!(ct.is_interested_in(&peer));
+
+        ct.mark_piece_downloaded(l.validate_piece_index(2).unwrap());
+        assert!(!ct.is_interested_in(&peer));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let total = SEQUENTIAL_LOOKAHEAD_PIECES as u32 * 2;
+        let l = Lengths::new(CHUNK_SIZE as u64 * total as u64, CHUNK_SIZE).unwrap();
+        let piece = |i| l.validate_piece_index(i).unwrap();
+
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+
+        // The last piece goes first normally.
+        assert_eq!(ct.iter_queued_pieces().next(), Some(total as usize - 1));
+
+        ct.set_sequential(true);
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, (0..SEQUENTIAL_LOOKAHEAD_PIECES).collect::<Vec<_>>());
+
+        // The window doesn't move until the first piece is downloaded.
+        ct.reserve_needed_piece(piece(0));
+        assert_eq!(
+            ct.iter_queued_pieces().last(),
+            Some(SEQUENTIAL_LOOKAHEAD_PIECES - 1)
+        );
+        ct.mark_piece_downloaded(piece(0));
+        assert_eq!(
+            ct.iter_queued_pieces().last(),
+            Some(SEQUENTIAL_LOOKAHEAD_PIECES)
+        );
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 8, CHUNK_SIZE).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+
+        // Pieces 0..3 are low, piece 3 is shared between a low and a high file, 3..6 high and
+        // 6..8 normal.
+        let chunk = CHUNK_SIZE as u64;
+        ct.set_file_priorities(
+            [chunk * 3 + 1, 0, chunk * 3 - 1, chunk * 2],
+            &[
+                FilePriority::Low,
+                FilePriority::Skip,
+                FilePriority::High,
+                FilePriority::Normal,
+            ],
+        );
+        assert_eq!(
+            ct.get_piece_priority(l.validate_piece_index(3).unwrap()),
+            FilePriority::High
+        );
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![3, 4, 5, 7, 6, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_picker_orders_each_piece_once() {
+        // Counts how many pieces it was asked to order.
+        #[derive(Default)]
+        struct CountingPicker(AtomicUsize);
+
+        impl PiecePicker for CountingPicker {
+            fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+                self.0
+                    .fetch_add(ctx.iter_queued().count(), Ordering::Relaxed);
+                Box::new(ctx.iter_queued())
+            }
+        }
+
+        let l = Lengths::new(CHUNK_SIZE as u64 * 4, CHUNK_SIZE).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+        let picker = Arc::new(CountingPicker::default());
+        ct.set_piece_picker(picker.clone());
+
+        // One file per piece, with 3 different priorities.
+        let chunk = CHUNK_SIZE as u64;
+        ct.set_file_priorities(
+            [chunk; 4],
+            &[
+                FilePriority::Low,
+                FilePriority::High,
+                FilePriority::Normal,
+                FilePriority::High,
+            ],
+        );
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![1, 3, 2, 0]);
+        assert_eq!(picker.0.load(Ordering::Relaxed), 4);
+
+        // Only the highest priority is ordered when that's where the pick comes from.
+        picker.0.store(0, Ordering::Relaxed);
+        assert_eq!(ct.iter_queued_pieces().next(), Some(1));
+        assert_eq!(picker.0.load(Ordering::Relaxed), 2);
+
+        // Pieces going back to the queue keep their priority.
+        ct.reserve_needed_piece(l.validate_piece_index(1).unwrap());
+        ct.reserve_needed_piece(l.validate_piece_index(2).unwrap());
+        ct.mark_piece_broken_if_not_have(l.validate_piece_index(2).unwrap());
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![3, 2, 0]);
+    }
+
+    #[test]
+    fn test_head_tail_files() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 10, CHUNK_SIZE).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let mut ct = ChunkTracker::new(have, selected, l).unwrap();
+        ct.set_sequential(true);
+
+        // Pieces 0..2 are the first file, 2..8 the second one, 8..10 the third.
+        let chunk = CHUNK_SIZE as u64;
+        let files = [chunk * 2, chunk * 6, chunk * 2];
+        ct.set_head_tail_files(files, &[1]);
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![2, 7, 0, 1, 3, 4, 5, 6, 8, 9]);
+
+        // Priorities still go first.
+        ct.set_file_priorities(
+            files,
+            &[FilePriority::Normal, FilePriority::Low, FilePriority::High],
+        );
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![8, 9, 0, 1, 2, 7, 3, 4, 5, 6]);
+
+        // Once they're in flight, normal picking resumes.
+        ct.reserve_needed_piece(l.validate_piece_index(2).unwrap());
+        ct.reserve_needed_piece(l.validate_piece_index(7).unwrap());
+        let queued = ct.iter_queued_pieces().collect::<Vec<_>>();
+        assert_eq!(queued, vec![8, 9, 0, 1, 3, 4, 5, 6]);
+
+        ct.set_head_tail_files(files, &[]);
+        assert!(!ct.is_head_tail_piece(7));
+    }
+
+    #[test]
+    fn test_file_progress() {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 4, CHUNK_SIZE).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let mut have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        have.set(1, true);
+        have.set(3, true);
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let ct = ChunkTracker::new(have, selected, l).unwrap();
+
+        // Files: half of piece 0, an empty one, the rest of piece 0 and half of piece 1, then
+        // the rest.
+        let chunk = CHUNK_SIZE as u64;
+        let half = chunk / 2;
+        assert_eq!(
+            ct.file_progress([half, 0, chunk, chunk * 2 + half]),
+            vec![0, 0, half, half + chunk]
+        );
+    }
+
+    #[test]
+    fn test_partial_pieces() {
+        // 3 pieces of 10 chunks, the last one has 5.
+        let l = Lengths::new(CHUNK_SIZE as u64 * 25, CHUNK_SIZE * 10).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let mut have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
+        have.set(1, true);
+        let selected = BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice());
+        let new_tracker = || ChunkTracker::new(have.clone(), selected.clone(), l).unwrap();
+        let block = vec![0u8; CHUNK_SIZE as usize];
+        let mark = |ct: &mut ChunkTracker, index: u32, chunk: u32| {
+            ct.mark_chunk_downloaded(&Piece {
+                index,
+                begin: chunk * CHUNK_SIZE,
+                block: &block[..],
+            })
+            .unwrap();
+        };
+
+        let mut ct = new_tracker();
+        mark(&mut ct, 0, 0);
+        mark(&mut ct, 0, 9);
+        for chunk in 0..5 {
+            mark(&mut ct, 2, chunk);
+        }
+        assert_eq!(
+            ct.get_piece_progress(l.validate_piece_index(0).unwrap()),
+            0.2
+        );
+        assert_eq!(
+            ct.get_piece_progress(l.validate_piece_index(1).unwrap()),
+            1.
+        );
+
+        // The last piece is complete, only the first one is partial.
+        let partial = ct.get_partial_pieces();
+        assert_eq!(
+            partial.into_iter().collect::<Vec<_>>(),
+            vec![(0, vec![0b1000_0000, 0b0100_0000])]
+        );
+
+        let mut ct = new_tracker();
+        mark(&mut ct, 2, 1);
+        let partial = ct.get_partial_pieces();
+        assert_eq!(partial.get(&2), Some(&vec![0b0100_0000]));
+
+        let mut restored = new_tracker();
+        restored.restore_partial_pieces(&partial);
+        let chunk = |piece: u32, chunk: u32| {
+            l.iter_chunk_infos(l.validate_piece_index(piece).unwrap())
+                .nth(chunk as usize)
+                .unwrap()
+        };
+        assert!(restored.is_chunk_downloaded(&chunk(2, 1)));
+        assert!(!restored.is_chunk_downloaded(&chunk(2, 0)));
+        assert_eq!(restored.get_partial_pieces(), partial);
+
+        // Pieces we have, complete pieces and unknown ones are ignored.
+        let mut restored = new_tracker();
+        restored.restore_partial_pieces(
+            &[
+                (1, vec![0b1000_0000, 0]),
+                (2, vec![0b1111_1000]),
+                (3, vec![0b1000_0000]),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert!(restored.get_partial_pieces().is_empty());
+        assert!(!restored.is_chunk_downloaded(&chunk(2, 0)));
+    }
 }
@@ -168,6 +168,9 @@ async fn create_torrent_raw<'a>(
         } else {
             Some(output_files)
         },
+        private: None,
+        meta_version: None,
+        file_tree: None,
     })
 }
 
@@ -209,7 +212,10 @@ pub async fn create_torrent<'a>(
             publisher: None,
             publisher_url: None,
             creation_date: None,
+            url_list: Vec::new(),
+            piece_layers: None,
             info_hash,
+            info_hash_v2: None,
         },
     })
 }
@@ -1,4 +1,7 @@
-use std::{collections::HashSet, net::SocketAddr};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+};
 
 use anyhow::Context;
 use buffers::ByteBufOwned;
@@ -8,6 +11,7 @@ use tracing::debug;
 
 use crate::{
     peer_connection::PeerConnectionOptions, peer_info_reader, spawn_utils::BlockingSpawner,
+    torrent_state::peer::PeerOrigin,
 };
 use librqbit_core::hash_id::Id20;
 
@@ -16,21 +20,23 @@ pub enum ReadMetainfoResult<Rx> {
     Found {
         info: TorrentMetaV1Info<ByteBufOwned>,
         rx: Rx,
-        seen: HashSet<SocketAddr>,
+        seen: HashMap<SocketAddr, PeerOrigin>,
     },
     ChannelClosed {
-        seen: HashSet<SocketAddr>,
+        seen: HashMap<SocketAddr, PeerOrigin>,
     },
 }
 
-pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unpin>(
+pub async fn read_metainfo_from_peer_receiver<
+    A: Stream<Item = (SocketAddr, PeerOrigin)> + Unpin,
+>(
     peer_id: Id20,
     info_hash: Id20,
     initial_addrs: Vec<SocketAddr>,
     addrs_stream: A,
     peer_connection_options: Option<PeerConnectionOptions>,
 ) -> ReadMetainfoResult<A> {
-    let mut seen = HashSet::<SocketAddr>::new();
+    let mut seen = HashMap::<SocketAddr, PeerOrigin>::new();
     let mut addrs = addrs_stream;
 
     let semaphore = tokio::sync::Semaphore::new(128);
@@ -56,7 +62,7 @@ pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unp
     let mut unordered = FuturesUnordered::new();
 
     for a in initial_addrs {
-        seen.insert(a);
+        seen.insert(a, PeerOrigin::Manual);
         unordered.push(read_info_guarded(a));
     }
 
@@ -64,8 +70,9 @@ pub async fn read_metainfo_from_peer_receiver<A: Stream<Item = SocketAddr> + Unp
         tokio::select! {
             next_addr = addrs.next() => {
                 match next_addr {
-                    Some(addr) => {
-                        if seen.insert(addr) {
+                    Some((addr, origin)) => {
+                        if let Entry::Vacant(vac) = seen.entry(addr) {
+                            vac.insert(origin);
                             unordered.push(read_info_guarded(addr));
                         }
                     },
@@ -110,7 +117,10 @@ mod tests {
         let info_hash = Id20::from_str("cab507494d02ebb1178b38f2e9d7be299c86b862").unwrap();
         let dht = DhtBuilder::new().await.unwrap();
 
-        let peer_rx = dht.get_peers(info_hash, None).unwrap();
+        let peer_rx = dht
+            .get_peers(info_hash, None)
+            .unwrap()
+            .map(|addr| (addr, PeerOrigin::Dht));
         let peer_id = generate_peer_id();
         match read_metainfo_from_peer_receiver(peer_id, info_hash, Vec::new(), peer_rx, None).await
         {
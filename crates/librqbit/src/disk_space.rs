@@ -0,0 +1,92 @@
+// Checking that there's enough free disk space for a torrent, and telling "disk full" errors
+// apart from other write errors.
+//
+// A torrent that runs out of space mid-download has to be paused anyway, so it's better to
+// refuse starting it in the first place.
+
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+
+// Free space available to this user on the filesystem containing "path". None if unknown
+// on this platform.
+#[cfg(target_os = "linux")]
+pub(crate) fn available_space(path: &Path) -> anyhow::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("invalid path {path:?}"))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("error getting free space of {path:?}"));
+    }
+    // The field types differ between architectures.
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(Some(available))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn available_space(_path: &Path) -> anyhow::Result<Option<u64>> {
+    Ok(None)
+}
+
+// How many bytes the file already takes on disk. Sparse files take less than their length.
+#[cfg(unix)]
+pub(crate) fn allocated_bytes(file: &File) -> anyhow::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = file.metadata().context("error getting file metadata")?;
+    // st_blocks is always in 512-byte units.
+    Ok(std::cmp::min(meta.blocks() * 512, meta.len()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn allocated_bytes(file: &File) -> anyhow::Result<u64> {
+    Ok(file
+        .metadata()
+        .context("error getting file metadata")?
+        .len())
+}
+
+pub(crate) fn is_out_of_space(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == std::io::ErrorKind::StorageFull)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::{allocated_bytes, is_out_of_space};
+
+    #[test]
+    fn test_is_out_of_space() {
+        let full: std::io::Result<()> = Err(std::io::Error::from(std::io::ErrorKind::StorageFull));
+        let full = full.context("error writing chunk").unwrap_err();
+        assert!(is_out_of_space(&full));
+
+        let other: std::io::Result<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(!is_out_of_space(
+            &other.context("error writing chunk").unwrap_err()
+        ));
+        assert!(!is_out_of_space(&anyhow::anyhow!("no space")));
+    }
+
+    #[test]
+    fn test_allocated_bytes() {
+        let f = tempfile::tempfile().unwrap();
+        f.set_len(10 * 1024 * 1024).unwrap();
+        assert!(allocated_bytes(&f).unwrap() <= 10 * 1024 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_available_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(super::available_space(dir.path()).unwrap().is_some());
+    }
+}
@@ -0,0 +1,156 @@
+// The error type of the public API.
+//
+// Internal code uses anyhow, and marks errors of a known kind with ErrorKind as context, e.g.
+// ".context(ErrorKind::Disk)". Converting to Error at the API boundary finds the marker anywhere
+// in the context chain, and picks the variant from it.
+//
+// Result isn't re-exported at the crate root, so that glob imports of librqbit don't shadow
+// std's Result.
+
+use crate::session::TorrentId;
+
+/// What went wrong in a librqbit operation.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The torrent file, magnet link or metadata is invalid.
+    InvalidTorrent(anyhow::Error),
+    /// Reading or writing the files of a torrent failed.
+    Disk(anyhow::Error),
+    /// Communicating with peers or the DHT failed, e.g. while resolving a magnet link.
+    Protocol(anyhow::Error),
+    /// Announcing to the torrent's trackers failed.
+    Tracker(anyhow::Error),
+    /// There's no torrent with this id in the session.
+    TorrentNotFound(TorrentId),
+    /// The session is shutting down, and doesn't start anything new.
+    ShuttingDown,
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ErrorKind {
+    InvalidTorrent,
+    Disk,
+    Protocol,
+    Tracker,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::InvalidTorrent => f.write_str("invalid torrent"),
+            ErrorKind::Disk => f.write_str("disk error"),
+            ErrorKind::Protocol => f.write_str("protocol error"),
+            ErrorKind::Tracker => f.write_str("tracker error"),
+        }
+    }
+}
+
+impl Error {
+    /// A short snake_case name of the variant, e.g. for API responses.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Error::InvalidTorrent(_) => "invalid_torrent",
+            Error::Disk(_) => "disk_error",
+            Error::Protocol(_) => "protocol_error",
+            Error::Tracker(_) => "tracker_error",
+            Error::TorrentNotFound(_) => "torrent_not_found",
+            Error::ShuttingDown => "shutting_down",
+            Error::Other(_) => "internal_error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        // An Error that went through anyhow, e.g. to get more context. Keep the whole chain,
+        // TorrentNotFound and ShuttingDown don't need any.
+        let kind = match e.downcast_ref::<Error>() {
+            Some(Error::TorrentNotFound(id)) => return Error::TorrentNotFound(*id),
+            Some(Error::ShuttingDown) => return Error::ShuttingDown,
+            Some(Error::InvalidTorrent(_)) => Some(ErrorKind::InvalidTorrent),
+            Some(Error::Disk(_)) => Some(ErrorKind::Disk),
+            Some(Error::Protocol(_)) => Some(ErrorKind::Protocol),
+            Some(Error::Tracker(_)) => Some(ErrorKind::Tracker),
+            Some(Error::Other(_)) => None,
+            None => e.downcast_ref::<ErrorKind>().copied(),
+        };
+        match kind {
+            Some(ErrorKind::InvalidTorrent) => Error::InvalidTorrent(e),
+            Some(ErrorKind::Disk) => Error::Disk(e),
+            Some(ErrorKind::Protocol) => Error::Protocol(e),
+            Some(ErrorKind::Tracker) => Error::Tracker(e),
+            None => Error::Other(e),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidTorrent(e)
+            | Error::Disk(e)
+            | Error::Protocol(e)
+            | Error::Tracker(e)
+            | Error::Other(e) => std::fmt::Display::fmt(e, f),
+            Error::TorrentNotFound(id) => write!(f, "torrent with id {id} did not exist"),
+            Error::ShuttingDown => f.write_str("session is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidTorrent(e)
+            | Error::Disk(e)
+            | Error::Protocol(e)
+            | Error::Tracker(e)
+            | Error::Other(e) => e.source(),
+            Error::TorrentNotFound(_) | Error::ShuttingDown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::{Error, ErrorKind};
+
+    #[test]
+    fn test_kind_from_context() {
+        let e = Err::<(), _>(anyhow::anyhow!("permission denied"))
+            .context(ErrorKind::Disk)
+            .context("error moving storage")
+            .unwrap_err();
+        let e = Error::from(e);
+        assert!(matches!(e, Error::Disk(_)));
+        assert_eq!(
+            format!("{e:#}"),
+            "error moving storage: disk error: permission denied"
+        );
+
+        let e = Error::from(anyhow::Error::from(Error::ShuttingDown).context("error adding"));
+        assert!(matches!(e, Error::ShuttingDown));
+
+        assert!(matches!(
+            Error::from(anyhow::anyhow!("something")),
+            Error::Other(_)
+        ));
+    }
+
+    #[test]
+    fn test_typed_error_keeps_context() {
+        let disk = Error::from(anyhow::anyhow!("permission denied").context(ErrorKind::Disk));
+        let e = Error::from(anyhow::Error::from(disk).context("error moving storage"));
+        assert!(matches!(e, Error::Disk(_)));
+        assert_eq!(
+            format!("{e:#}"),
+            "error moving storage: disk error: permission denied"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// Notifications about what's happening in the session, so that clients don't need to poll stats.
+//
+// Events are broadcast to all subscribers. A subscriber that falls behind by more than
+// EVENTS_CHANNEL_CAPACITY events misses the oldest ones.
+
+use std::net::SocketAddr;
+
+use librqbit_core::hash_id::Id20;
+use serde::{Serialize, Serializer};
+
+pub(crate) const EVENTS_CHANNEL_CAPACITY: usize = 1024;
+
+pub(crate) type EventSender = tokio::sync::broadcast::Sender<Event>;
+
+fn serialize_info_hash<S: Serializer>(info_hash: &Id20, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&info_hash.as_string())
+}
+
+/// Something that happened to a torrent of the session.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TorrentAdded {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        name: Option<String>,
+    },
+    /// A piece was downloaded and passed the hash check.
+    PieceCompleted {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        piece: u32,
+    },
+    /// All the selected files were downloaded.
+    TorrentFinished {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+    },
+    PeerConnected {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        addr: SocketAddr,
+    },
+    /// The peer sent too many pieces that failed the hash check, and won't be connected to again.
+    PeerBanned {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        addr: SocketAddr,
+    },
+    TrackerError {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        tracker: String,
+        error: String,
+    },
+    /// Writing to the files of the torrent failed. The torrent is paused with the error, and can be resumed once
+    /// the disk issue is fixed.
+    DiskError {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        error: String,
+    },
+    /// The torrent couldn't be started, e.g. because its files are missing.
+    TorrentError {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        error: String,
+    },
+    /// Nothing was downloaded for a while, although the torrent isn't finished. Sent again only
+    /// after the download resumes and stalls again.
+    TorrentStalled {
+        #[serde(serialize_with = "serialize_info_hash")]
+        info_hash: Id20,
+        stalled_for_secs: u64,
+    },
+}
+
+impl Event {
+    pub fn info_hash(&self) -> Id20 {
+        match self {
+            Event::TorrentAdded { info_hash, .. }
+            | Event::PieceCompleted { info_hash, .. }
+            | Event::TorrentFinished { info_hash }
+            | Event::PeerConnected { info_hash, .. }
+            | Event::PeerBanned { info_hash, .. }
+            | Event::TrackerError { info_hash, .. }
+            | Event::DiskError { info_hash, .. }
+            | Event::TorrentError { info_hash, .. }
+            | Event::TorrentStalled { info_hash, .. } => *info_hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::hash_id::Id20;
+
+    use super::Event;
+
+    #[test]
+    fn test_serialize() {
+        let info_hash = Id20::new([1; 20]);
+        assert_eq!(
+            serde_json::to_value(Event::PieceCompleted {
+                info_hash,
+                piece: 5
+            })
+            .unwrap(),
+            serde_json::json!({
+                "type": "piece_completed",
+                "info_hash": "0101010101010101010101010101010101010101",
+                "piece": 5,
+            })
+        );
+    }
+}
@@ -0,0 +1,176 @@
+// Custom extension protocol (BEP 10) messages, registered by library users.
+//
+// Each registered extension is advertised in our extended handshake under its name, with a
+// message id from MY_EXTENDED_FIRST_CUSTOM up. Messages peers send with these ids are passed
+// to the extension's handler as raw payloads.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, Context};
+use buffers::ByteBufOwned;
+use librqbit_core::hash_id::Id20;
+use peer_binary_protocol::{extended::ExtendedMessage, Message, MY_EXTENDED_FIRST_CUSTOM};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::peer_connection::WriterRequest;
+
+// Names handled by rqbit itself.
+const BUILTIN_EXTENSIONS: &[&str] = &["ut_metadata", "ut_pex", "ut_holepunch", "lt_donthave"];
+
+/// Handles the messages of a custom extension protocol.
+pub trait ExtensionHandler: Send + Sync {
+    /// The name advertised in the "m" dictionary of the extended handshake, e.g. "lt_tex".
+    fn name(&self) -> &'static str;
+
+    /// Called when a peer advertises this extension in its extended handshake.
+    fn on_peer_handshake(&self, _peer: &ExtensionPeer) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called for every message of this extension the peer sends. Returning an error
+    /// disconnects the peer.
+    fn on_message(&self, peer: &ExtensionPeer, payload: &[u8]) -> anyhow::Result<()>;
+}
+
+/// A connected peer, as seen by an [ExtensionHandler].
+#[derive(Clone)]
+pub struct ExtensionPeer {
+    addr: SocketAddr,
+    info_hash: Id20,
+    // The id the peer wants this extension's messages sent with.
+    msg_id: Option<u8>,
+    tx: Sender<WriterRequest>,
+}
+
+impl ExtensionPeer {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        info_hash: Id20,
+        msg_id: Option<u8>,
+        tx: Sender<WriterRequest>,
+    ) -> Self {
+        Self {
+            addr,
+            info_hash,
+            msg_id,
+            tx,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn info_hash(&self) -> Id20 {
+        self.info_hash
+    }
+
+    /// Whether the peer advertised this extension, i.e. if it can be sent messages.
+    pub fn supports_extension(&self) -> bool {
+        self.msg_id.is_some()
+    }
+
+    /// Send a message of this extension to the peer. Fails if the peer doesn't read what we send
+    /// fast enough and its write queue is full.
+    pub fn send(&self, payload: Vec<u8>) -> anyhow::Result<()> {
+        let msg_id = self.msg_id.context("peer doesn't support this extension")?;
+        let msg = WriterRequest::Message(Message::Extended(ExtendedMessage::Raw(
+            msg_id,
+            ByteBufOwned::from(payload),
+        )));
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!("peer's write queue is full, try again later"),
+            Err(TrySendError::Closed(_)) => bail!("peer disconnected"),
+        }
+    }
+}
+
+/// The custom extensions the session negotiates with peers.
+#[derive(Default, Clone)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Arc<dyn ExtensionHandler>>,
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, handler: Arc<dyn ExtensionHandler>) -> anyhow::Result<()> {
+        let name = handler.name();
+        if BUILTIN_EXTENSIONS.contains(&name) {
+            bail!("extension {name:?} is handled by rqbit");
+        }
+        if self.handlers.iter().any(|h| h.name() == name) {
+            bail!("extension {name:?} is already registered");
+        }
+        if self.handlers.len() >= (u8::MAX - MY_EXTENDED_FIRST_CUSTOM) as usize {
+            bail!("too many extensions registered");
+        }
+        self.handlers.push(handler);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    // The handlers with the message ids we advertise for them.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u8, &Arc<dyn ExtensionHandler>)> {
+        self.handlers
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (MY_EXTENDED_FIRST_CUSTOM + i as u8, h))
+    }
+
+    pub(crate) fn get(&self, msg_id: u8) -> Option<&Arc<dyn ExtensionHandler>> {
+        self.handlers
+            .get(msg_id.checked_sub(MY_EXTENDED_FIRST_CUSTOM)? as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use peer_binary_protocol::MY_EXTENDED_FIRST_CUSTOM;
+
+    use super::{ExtensionHandler, ExtensionPeer, ExtensionRegistry};
+
+    struct Named(&'static str);
+
+    impl ExtensionHandler for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn on_message(&self, _: &ExtensionPeer, _: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = ExtensionRegistry::default();
+        registry.register(Arc::new(Named("lt_tex"))).unwrap();
+        registry.register(Arc::new(Named("my_ext"))).unwrap();
+        assert!(registry.register(Arc::new(Named("my_ext"))).is_err());
+        assert!(registry.register(Arc::new(Named("ut_pex"))).is_err());
+        assert!(registry.register(Arc::new(Named("lt_donthave"))).is_err());
+
+        let ids = registry
+            .iter()
+            .map(|(id, h)| (id, h.name()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                (MY_EXTENDED_FIRST_CUSTOM, "lt_tex"),
+                (MY_EXTENDED_FIRST_CUSTOM + 1, "my_ext")
+            ]
+        );
+        assert_eq!(
+            registry.get(MY_EXTENDED_FIRST_CUSTOM + 1).unwrap().name(),
+            "my_ext"
+        );
+        assert!(registry.get(MY_EXTENDED_FIRST_CUSTOM + 2).is_none());
+        assert!(registry.get(1).is_none());
+    }
+}
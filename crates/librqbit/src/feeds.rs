@@ -0,0 +1,311 @@
+// RSS and Atom feed subscriptions.
+//
+// The session polls every subscribed feed, and adds the entries whose titles match the
+// subscription's filters as torrents. Entries are remembered by their id (guid), so that each
+// one is only added once.
+
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Context;
+use quick_xml::events::{BytesStart, Event};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::DropGuard;
+
+pub type FeedId = usize;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A feed to check periodically for new torrents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeedSubscription {
+    /// The URL of the RSS or Atom feed.
+    pub url: String,
+    /// Only add entries with titles matching this regex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<String>,
+    /// Never add entries with titles matching this regex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<String>,
+    /// How often to check the feed. Defaults to 15 minutes, and can't be less than a minute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval_secs: Option<u64>,
+    /// The category to give the added torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// Where to download the added torrents. Defaults to the session's output folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_folder: Option<String>,
+}
+
+impl FeedSubscription {
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+            .max(MIN_POLL_INTERVAL)
+    }
+}
+
+pub(crate) struct Feed {
+    pub subscription: FeedSubscription,
+    // Ids of the entries that were added already.
+    pub seen: HashSet<String>,
+    // Stops polling when the feed is removed.
+    pub _cancel: DropGuard,
+}
+
+pub(crate) struct FeedFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl FeedFilter {
+    pub fn new(subscription: &FeedSubscription) -> anyhow::Result<Self> {
+        let compile = |re: &Option<String>| {
+            re.as_deref()
+                .map(|re| Regex::new(re).with_context(|| format!("invalid regex {re:?}")))
+                .transpose()
+        };
+        Ok(Self {
+            include: compile(&subscription.include)?,
+            exclude: compile(&subscription.exclude)?,
+        })
+    }
+
+    pub fn matches(&self, title: &str) -> bool {
+        self.include.as_ref().is_none_or(|re| re.is_match(title))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(title))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FeedEntry {
+    pub id: String,
+    pub title: String,
+    // A link to the .torrent file or a magnet link.
+    pub url: String,
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Title,
+    Id,
+    Link,
+    Magnet,
+}
+
+#[derive(Default)]
+struct PartialEntry {
+    id: Option<String>,
+    title: Option<String>,
+    link: Option<String>,
+    magnet: Option<String>,
+    enclosure: Option<String>,
+}
+
+impl PartialEntry {
+    // Read the links that are in attributes: RSS <enclosure url="..."> and Atom <link href="...">.
+    fn on_element(&mut self, e: &BytesStart<'_>) -> anyhow::Result<()> {
+        let attr = |name: &str| -> anyhow::Result<Option<String>> {
+            match e
+                .try_get_attribute(name)
+                .context("error reading attribute")?
+            {
+                Some(a) => Ok(Some(
+                    a.unescape_value()
+                        .context("error unescaping attribute")?
+                        .into_owned(),
+                )),
+                None => Ok(None),
+            }
+        };
+        match e.local_name().as_ref() {
+            b"enclosure" => {
+                if let Some(url) = attr("url")? {
+                    self.enclosure = Some(url);
+                }
+            }
+            b"link" => {
+                if let Some(href) = attr("href")? {
+                    if attr("rel")?.as_deref() == Some("enclosure") {
+                        self.enclosure = Some(href);
+                    } else if self.link.is_none() {
+                        self.link = Some(href);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, field: Field, text: String) {
+        let dst = match field {
+            Field::Title => &mut self.title,
+            Field::Id => &mut self.id,
+            Field::Link => &mut self.link,
+            Field::Magnet => &mut self.magnet,
+        };
+        *dst = Some(text);
+    }
+
+    fn finish(self) -> Option<FeedEntry> {
+        let url = self.enclosure.or(self.magnet).or(self.link)?;
+        Some(FeedEntry {
+            id: self.id.unwrap_or_else(|| url.clone()),
+            title: self.title.unwrap_or_default(),
+            url,
+        })
+    }
+}
+
+// Parse the entries of an RSS 2.0 or Atom feed. Entries without any link are skipped.
+pub(crate) fn parse_feed(data: &[u8]) -> anyhow::Result<Vec<FeedEntry>> {
+    let mut reader = quick_xml::Reader::from_reader(data);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut current: Option<PartialEntry> = None;
+    // The element of the current entry whose text is being read.
+    let mut field: Option<Field> = None;
+
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .context("error parsing feed")?
+        {
+            Event::Start(e) => {
+                field = match e.local_name().as_ref() {
+                    b"item" | b"entry" => {
+                        current = Some(Default::default());
+                        None
+                    }
+                    b"title" => Some(Field::Title),
+                    b"guid" | b"id" => Some(Field::Id),
+                    b"link" => Some(Field::Link),
+                    // Torznab and similar extensions.
+                    b"magnetURI" => Some(Field::Magnet),
+                    _ => None,
+                };
+                if let Some(entry) = current.as_mut() {
+                    entry.on_element(&e)?;
+                }
+            }
+            Event::Empty(e) => {
+                if let Some(entry) = current.as_mut() {
+                    entry.on_element(&e)?;
+                }
+            }
+            Event::Text(t) => {
+                if let (Some(entry), Some(field)) = (current.as_mut(), field) {
+                    let text = t.unescape().context("error unescaping text")?;
+                    entry.set(field, text.into_owned());
+                }
+            }
+            Event::CData(t) => {
+                if let (Some(entry), Some(field)) = (current.as_mut(), field) {
+                    entry.set(field, String::from_utf8_lossy(&t.into_inner()).into_owned());
+                }
+            }
+            Event::End(e) => {
+                field = None;
+                if matches!(e.local_name().as_ref(), b"item" | b"entry") {
+                    if let Some(entry) = current.take().and_then(PartialEntry::finish) {
+                        entries.push(entry);
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_feed, FeedEntry, FeedFilter, FeedSubscription};
+
+    #[test]
+    fn test_parse_rss() {
+        let rss = br#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Some tracker</title>
+    <item>
+      <title>Ubuntu 24.04 &amp; friends</title>
+      <guid>https://example.com/t/1</guid>
+      <link>https://example.com/t/1</link>
+      <enclosure url="https://example.com/dl/1.torrent" type="application/x-bittorrent"/>
+    </item>
+    <item>
+      <title><![CDATA[Debian 12]]></title>
+      <link>magnet:?xt=urn:btih:cab507494d02ebb1178b38f2e9d7be299c86b862</link>
+    </item>
+    <item>
+      <title>No link</title>
+    </item>
+  </channel>
+</rss>"#;
+        assert_eq!(
+            parse_feed(rss).unwrap(),
+            vec![
+                FeedEntry {
+                    id: "https://example.com/t/1".into(),
+                    title: "Ubuntu 24.04 & friends".into(),
+                    url: "https://example.com/dl/1.torrent".into(),
+                },
+                FeedEntry {
+                    id: "magnet:?xt=urn:btih:cab507494d02ebb1178b38f2e9d7be299c86b862".into(),
+                    title: "Debian 12".into(),
+                    url: "magnet:?xt=urn:btih:cab507494d02ebb1178b38f2e9d7be299c86b862".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_atom() {
+        let atom = br#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Some tracker</title>
+  <entry>
+    <title>Fedora 40</title>
+    <id>urn:uuid:1</id>
+    <link href="https://example.com/t/2"/>
+    <link rel="enclosure" href="https://example.com/dl/2.torrent"/>
+  </entry>
+</feed>"#;
+        assert_eq!(
+            parse_feed(atom).unwrap(),
+            vec![FeedEntry {
+                id: "urn:uuid:1".into(),
+                title: "Fedora 40".into(),
+                url: "https://example.com/dl/2.torrent".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = FeedFilter::new(&FeedSubscription {
+            include: Some("(?i)ubuntu".into()),
+            exclude: Some("beta".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(filter.matches("Ubuntu 24.04"));
+        assert!(!filter.matches("ubuntu 24.10 beta"));
+        assert!(!filter.matches("Debian 12"));
+
+        assert!(FeedFilter::new(&FeedSubscription {
+            include: Some("(".into()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -1,7 +1,5 @@
 use std::{
     fs::File,
-    io::{Read, Seek, SeekFrom, Write},
-    marker::PhantomData,
     sync::atomic::{AtomicU64, Ordering},
 };
 
@@ -9,6 +7,7 @@ use anyhow::Context;
 use buffers::ByteBufOwned;
 use librqbit_core::{
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
+    merkle::{PieceHashV2, PieceHasherV2},
     torrent_metainfo::TorrentMetaV1Info,
 };
 use peer_binary_protocol::Piece;
@@ -16,6 +15,8 @@ use sha1w::{ISha1, Sha1};
 use tracing::{debug, trace, warn};
 
 use crate::{
+    direct_io,
+    file_io::{read_exact_at, write_all_at},
     opened_file::OpenedFile,
     type_aliases::{OpenedFiles, PeerHandle, BF},
 };
@@ -43,16 +44,32 @@ pub(crate) struct InitialCheckResults {
     pub selected_bytes: u64,
 }
 
-pub fn update_hash_from_file<Sha1: ISha1>(
-    file: &mut File,
-    hash: &mut Sha1,
+// v1 pieces are hashed with SHA-1, v2 pieces are roots of per-file merkle trees.
+pub(crate) enum PieceHasher {
+    V1(Sha1),
+    V2(PieceHasherV2),
+}
+
+impl PieceHasher {
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            PieceHasher::V1(h) => h.update(buf),
+            PieceHasher::V2(h) => h.update(buf),
+        }
+    }
+}
+
+pub(crate) fn update_hash_from_file(
+    file: &File,
+    offset: u64,
+    hash: &mut PieceHasher,
     buf: &mut [u8],
     mut bytes_to_read: usize,
 ) -> anyhow::Result<()> {
     let mut read = 0;
     while bytes_to_read > 0 {
         let chunk = std::cmp::min(buf.len(), bytes_to_read);
-        file.read_exact(&mut buf[..chunk])
+        read_exact_at(file, &mut buf[..chunk], offset + read as u64)
             .with_context(|| format!("failed reading chunk of size {chunk}, read so far {read}"))?;
         bytes_to_read -= chunk;
         read += chunk;
@@ -63,22 +80,42 @@ pub fn update_hash_from_file<Sha1: ISha1>(
 
 pub(crate) struct FileOps<'a> {
     torrent: &'a TorrentMetaV1Info<ByteBufOwned>,
+    v2_piece_hashes: Option<&'a [PieceHashV2]>,
     files: &'a OpenedFiles,
     lengths: &'a Lengths,
-    phantom_data: PhantomData<Sha1>,
 }
 
 impl<'a> FileOps<'a> {
     pub fn new(
         torrent: &'a TorrentMetaV1Info<ByteBufOwned>,
+        v2_piece_hashes: Option<&'a [PieceHashV2]>,
         files: &'a OpenedFiles,
         lengths: &'a Lengths,
     ) -> Self {
         Self {
             torrent,
+            v2_piece_hashes,
             files,
             lengths,
-            phantom_data: PhantomData,
+        }
+    }
+
+    fn new_hasher(&self) -> PieceHasher {
+        if self.v2_piece_hashes.is_some() {
+            PieceHasher::V2(PieceHasherV2::new())
+        } else {
+            PieceHasher::V1(Sha1::new())
+        }
+    }
+
+    fn compare_hash(&self, piece: u32, hasher: PieceHasher) -> Option<bool> {
+        match (hasher, self.v2_piece_hashes) {
+            (PieceHasher::V1(h), _) => self.torrent.compare_hash(piece, h.finish()),
+            (PieceHasher::V2(h), Some(hashes)) => {
+                let expected = hashes.get(piece as usize)?;
+                Some(h.finish(expected.leaves) == expected.hash)
+            }
+            (PieceHasher::V2(_), None) => None,
         }
     }
 
@@ -138,7 +175,7 @@ impl<'a> FileOps<'a> {
 
         for piece_info in self.lengths.iter_piece_infos() {
             piece_files.clear();
-            let mut computed_hash = Sha1::new();
+            let mut computed_hash = self.new_hasher();
             let mut piece_remaining = piece_info.len as usize;
             let mut some_files_broken = false;
             let mut piece_selected = current_file.full_file_required;
@@ -171,19 +208,21 @@ impl<'a> FileOps<'a> {
                     continue;
                 }
 
-                let mut fd = current_file.fd.file.lock();
+                let fd = current_file.fd.lock_file()?;
 
-                fd.seek(SeekFrom::Start(pos))
-                    .context("bug? error seeking")?;
                 if let Err(err) = update_hash_from_file(
-                    &mut fd,
+                    &fd,
+                    pos,
                     &mut computed_hash,
                     &mut read_buffer,
                     to_read_in_file,
                 ) {
                     debug!(
                         "error reading from file {} ({:?}) at {}: {:#}",
-                        current_file.index, current_file.fd.filename, pos, &err
+                        current_file.index,
+                        current_file.fd.filename(),
+                        pos,
+                        &err
                     );
                     current_file.is_broken = true;
                     some_files_broken = true;
@@ -206,8 +245,7 @@ impl<'a> FileOps<'a> {
             }
 
             if self
-                .torrent
-                .compare_hash(piece_info.piece_index.get(), computed_hash.finish())
+                .compare_hash(piece_info.piece_index.get(), computed_hash)
                 .context("bug: either torrent info broken or we have a bug - piece index invalid")?
             {
                 trace!(
@@ -246,11 +284,11 @@ impl<'a> FileOps<'a> {
 
     pub fn check_piece(
         &self,
-        who_sent: PeerHandle,
+        who_sent: impl std::fmt::Display,
         piece_index: ValidPieceIndex,
         last_received_chunk: &ChunkInfo,
     ) -> anyhow::Result<bool> {
-        let mut h = Sha1::new();
+        let mut h = self.new_hasher();
         let piece_length = self.lengths.piece_length(piece_index);
         let mut absolute_offset = self.lengths.piece_offset(piece_index);
         let mut buf = vec![0u8; std::cmp::min(65536, piece_length as usize)];
@@ -266,27 +304,30 @@ impl<'a> FileOps<'a> {
 
             let to_read_in_file =
                 std::cmp::min(file_remaining_len, piece_remaining_bytes as u64) as usize;
-            let mut file_g = self.files[file_idx].file.lock();
+            let file_g = self.files[file_idx].lock_file()?;
             trace!(
-                "piece={}, handle={}, file_idx={}, seeking to {}. Last received chunk: {:?}",
+                "piece={}, handle={}, file_idx={}, reading at {}. Last received chunk: {:?}",
                 piece_index,
                 who_sent,
                 file_idx,
                 absolute_offset,
                 &last_received_chunk
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
-            update_hash_from_file(&mut file_g, &mut h, &mut buf, to_read_in_file).with_context(
-                || {
-                    format!(
-                        "error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{name:?}\")"
-                    )
-                },
-            )?;
+            if let Some(map) = self.files[file_idx].mmap().as_ref() {
+                h.update(
+                    map.slice_at(absolute_offset, to_read_in_file)
+                        .with_context(|| {
+                            format!("error reading file {file_idx} (\"{name:?}\") from memory map")
+                        })?,
+                );
+            } else {
+                update_hash_from_file(&file_g, absolute_offset, &mut h, &mut buf, to_read_in_file)
+                    .with_context(|| {
+                        format!(
+                            "error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{name:?}\")"
+                        )
+                    })?;
+            }
 
             piece_remaining_bytes -= to_read_in_file;
 
@@ -297,7 +338,22 @@ impl<'a> FileOps<'a> {
             absolute_offset = 0;
         }
 
-        match self.torrent.compare_hash(piece_index.get(), h.finish()) {
+        self.verify_hash(piece_index, h)
+    }
+
+    // Check a piece from memory without reading it from disk, e.g. before writing it.
+    pub fn check_piece_data(
+        &self,
+        piece_index: ValidPieceIndex,
+        data: &[u8],
+    ) -> anyhow::Result<bool> {
+        let mut h = self.new_hasher();
+        h.update(data);
+        self.verify_hash(piece_index, h)
+    }
+
+    fn verify_hash(&self, piece_index: ValidPieceIndex, h: PieceHasher) -> anyhow::Result<bool> {
+        match self.compare_hash(piece_index.get(), h) {
             Some(true) => {
                 trace!("piece={} hash matches", piece_index);
                 Ok(true)
@@ -334,25 +390,27 @@ impl<'a> FileOps<'a> {
             let file_remaining_len = file_len - absolute_offset;
             let to_read_in_file = std::cmp::min(file_remaining_len, buf.len() as u64) as usize;
 
-            let mut file_g = self.files[file_idx].file.lock();
+            let file_g = self.files[file_idx].lock_file()?;
             trace!(
-                "piece={}, handle={}, file_idx={}, seeking to {}. To read chunk: {:?}",
+                "piece={}, handle={}, file_idx={}, reading at {}. To read chunk: {:?}",
                 chunk_info.piece_index,
                 who_sent,
                 file_idx,
                 absolute_offset,
                 &chunk_info
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
-            file_g
-                .read_exact(&mut buf[..to_read_in_file])
-                .with_context(|| {
-                    format!("error reading {file_idx} bytes, file_id: {to_read_in_file}")
-                })?;
+            if let Some(map) = self.files[file_idx].mmap().as_ref() {
+                map.read_at(absolute_offset, &mut buf[..to_read_in_file])
+                    .with_context(|| format!("error reading file {file_idx} from memory map"))?;
+            } else {
+                read_exact_at(&file_g, &mut buf[..to_read_in_file], absolute_offset).with_context(
+                    || {
+                        format!(
+                            "error reading {to_read_in_file} bytes at {absolute_offset}, file_id: {file_idx}"
+                        )
+                    },
+                )?;
+            }
 
             buf = &mut buf[to_read_in_file..];
 
@@ -368,16 +426,40 @@ impl<'a> FileOps<'a> {
 
     pub fn write_chunk<ByteBuf>(
         &self,
-        who_sent: PeerHandle,
+        who_sent: impl std::fmt::Display,
         data: &Piece<ByteBuf>,
         chunk_info: &ChunkInfo,
     ) -> anyhow::Result<()>
     where
         ByteBuf: AsRef<[u8]>,
     {
-        let mut buf = data.block.as_ref();
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        trace!(
+            "piece={}, chunk={:?}, handle={}, begin={}",
+            chunk_info.piece_index,
+            chunk_info,
+            who_sent,
+            chunk_info.offset,
+        );
+        self.write_at(
+            self.lengths.chunk_absolute_offset(chunk_info),
+            data.block.as_ref(),
+        )
+    }
+
+    // Write a whole piece at once, e.g. one that was buffered in memory.
+    pub fn write_piece(&self, piece_index: ValidPieceIndex, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() != self.lengths.piece_length(piece_index) as usize {
+            anyhow::bail!(
+                "bug: piece={piece_index} data is {} bytes, expected {}",
+                data.len(),
+                self.lengths.piece_length(piece_index)
+            );
+        }
+        trace!("piece={}, writing {} bytes", piece_index, data.len());
+        self.write_at(self.lengths.piece_offset(piece_index), data)
+    }
 
+    fn write_at(&self, mut absolute_offset: u64, mut buf: &[u8]) -> anyhow::Result<()> {
         for (file_idx, (name, file_len)) in self.torrent.iter_filenames_and_lengths()?.enumerate() {
             if absolute_offset > file_len {
                 absolute_offset -= file_len;
@@ -387,25 +469,29 @@ impl<'a> FileOps<'a> {
             let remaining_len = file_len - absolute_offset;
             let to_write = std::cmp::min(buf.len(), remaining_len as usize);
 
-            let mut file_g = self.files[file_idx].file.lock();
+            let opened_file = &self.files[file_idx];
+            let file_g = opened_file.lock_file()?;
             trace!(
-                "piece={}, chunk={:?}, handle={}, begin={}, file={}, writing {} bytes at {}",
-                chunk_info.piece_index,
-                chunk_info,
-                who_sent,
-                chunk_info.offset,
+                "file={}, writing {} bytes at {}",
                 file_idx,
                 to_write,
                 absolute_offset
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset} in file {file_idx} (\"{name:?}\")")
+            if let Some(map) = opened_file.mmap().as_mut() {
+                map.write_at(absolute_offset, &buf[..to_write])
+                    .with_context(|| {
+                        format!("error writing to file {file_idx} (\"{name:?}\") memory map")
+                    })?;
+            } else if let Some(direct) = opened_file.direct().as_ref() {
+                direct_io::write_all_at(direct, file_len, absolute_offset, &buf[..to_write])
+                    .with_context(|| {
+                        format!("error writing to file {file_idx} (\"{name:?}\") with direct I/O")
+                    })?;
+            } else {
+                write_all_at(&file_g, &buf[..to_write], absolute_offset).with_context(|| {
+                    format!("error writing at {absolute_offset} to file {file_idx} (\"{name:?}\")")
                 })?;
-            file_g
-                .write_all(&buf[..to_write])
-                .with_context(|| format!("error writing to file {file_idx} (\"{name:?}\")"))?;
+            }
             buf = &buf[to_write..];
             if buf.is_empty() {
                 break;
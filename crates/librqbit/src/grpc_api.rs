@@ -0,0 +1,463 @@
+// The gRPC counterpart of the HTTP API, defined in proto/rqbit.proto. Like the HTTP API, it's a
+// thin layer over Api.
+
+use std::{borrow::Cow, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use http::StatusCode;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::info;
+
+use crate::{
+    api::{Api, TorrentDetailsResponse, TorrentIdOrHash, TorrentListQuery},
+    http_api::HttpApiAuth,
+    session::{AddTorrent, AddTorrentOptions},
+    torrent_state::TorrentStats,
+    ApiError, Event,
+};
+
+pub mod proto {
+    tonic::include_proto!("rqbit.v1");
+}
+
+use proto::rqbit_server::{Rqbit, RqbitServer};
+
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+pub struct GrpcApiOptions {
+    pub read_only: bool,
+    /// The credentials, sent in the "authorization" metadata the same way as the HTTP
+    /// "Authorization" header.
+    pub auth: HttpApiAuth,
+}
+
+pub struct GrpcApi {
+    api: Api,
+    opts: GrpcApiOptions,
+}
+
+impl GrpcApi {
+    pub fn new(api: Api, opts: Option<GrpcApiOptions>) -> Self {
+        Self {
+            api,
+            opts: opts.unwrap_or_default(),
+        }
+    }
+
+    /// Run the gRPC server forever on the given address.
+    #[inline(never)]
+    pub fn run(self, addr: SocketAddr) -> BoxFuture<'static, anyhow::Result<()>> {
+        let authorization_values = Arc::new(
+            self.opts
+                .auth
+                .authorization_values()
+                .into_iter()
+                .map(|v| v.into_bytes())
+                .collect::<Vec<_>>(),
+        );
+        let service = Service {
+            api: self.api,
+            read_only: self.opts.read_only,
+        };
+        let service = RqbitServer::with_interceptor(service, move |request: Request<()>| {
+            check_auth(&authorization_values, request)
+        });
+
+        info!(%addr, "starting gRPC server");
+        async move {
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(addr)
+                .await
+                .context("error running gRPC server")
+        }
+        .boxed()
+    }
+}
+
+fn check_auth(
+    authorization_values: &[Vec<u8>],
+    request: Request<()>,
+) -> Result<Request<()>, Status> {
+    if authorization_values.is_empty() {
+        return Ok(request);
+    }
+    let authorized = request.metadata().get("authorization").is_some_and(|v| {
+        authorization_values
+            .iter()
+            .any(|expected| crate::http_api::constant_time_eq(v.as_bytes(), expected))
+    });
+    if authorized {
+        Ok(request)
+    } else {
+        Err(Status::unauthenticated("missing or invalid credentials"))
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match e.status() {
+            StatusCode::BAD_REQUEST => tonic::Code::InvalidArgument,
+            StatusCode::NOT_FOUND => tonic::Code::NotFound,
+            StatusCode::CONFLICT => tonic::Code::AlreadyExists,
+            StatusCode::SERVICE_UNAVAILABLE => tonic::Code::Unavailable,
+            _ => tonic::Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+fn parse_torrent(s: &str) -> Result<TorrentIdOrHash, Status> {
+    s.parse()
+        .map_err(|e: anyhow::Error| Status::invalid_argument(format!("{e:#}")))
+}
+
+fn parse_torrents(torrents: &[String]) -> Result<Vec<TorrentIdOrHash>, Status> {
+    torrents.iter().map(|t| parse_torrent(t)).collect()
+}
+
+#[derive(Clone)]
+struct Service {
+    api: Api,
+    read_only: bool,
+}
+
+impl Service {
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.read_only {
+            return Err(Status::permission_denied("the API is read-only"));
+        }
+        Ok(())
+    }
+
+    // The stats of the given torrents, or all of them if empty. Torrents that don't exist
+    // (anymore) are skipped.
+    fn stats_updates(&self, torrents: &[TorrentIdOrHash]) -> Vec<proto::TorrentStatsUpdate> {
+        let session = self.api.session();
+        let handles = if torrents.is_empty() {
+            session.torrents()
+        } else {
+            torrents
+                .iter()
+                .filter_map(|t| match *t {
+                    TorrentIdOrHash::Id(id) => Some((id, session.get(id)?)),
+                    TorrentIdOrHash::Hash(info_hash) => session.get_by_info_hash(info_hash),
+                })
+                .collect()
+        };
+        handles
+            .into_iter()
+            .map(|(id, handle)| proto::TorrentStatsUpdate {
+                id: id as u64,
+                info_hash: handle.info_hash().as_string(),
+                stats: Some(handle.stats().into()),
+            })
+            .collect()
+    }
+}
+
+type GrpcStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
+
+#[tonic::async_trait]
+impl Rqbit for Service {
+    type WatchStatsStream = GrpcStream<proto::TorrentStatsUpdate>;
+    type EventsStream = GrpcStream<proto::Event>;
+
+    async fn list_torrents(
+        &self,
+        request: Request<proto::ListTorrentsRequest>,
+    ) -> Result<Response<proto::ListTorrentsResponse>, Status> {
+        let request = request.into_inner();
+        let list = self.api.api_torrent_list_ext(TorrentListQuery {
+            limit: request.limit.map(|l| l as usize),
+            offset: request.offset.map(|o| o as usize),
+            ..Default::default()
+        });
+        Ok(Response::new(proto::ListTorrentsResponse {
+            torrents: list
+                .torrents
+                .into_iter()
+                .map(|t| proto::TorrentListItem {
+                    id: t.id as u64,
+                    info_hash: t.info_hash,
+                })
+                .collect(),
+            total: list.total as u64,
+        }))
+    }
+
+    async fn get_torrent(
+        &self,
+        request: Request<proto::TorrentRef>,
+    ) -> Result<Response<proto::TorrentDetails>, Status> {
+        let idx = parse_torrent(&request.get_ref().torrent)?;
+        let details = self.api.api_torrent_details(idx)?;
+        Ok(Response::new(details.into()))
+    }
+
+    async fn add_torrent(
+        &self,
+        request: Request<proto::AddTorrentRequest>,
+    ) -> Result<Response<proto::AddTorrentResponse>, Status> {
+        self.check_writable()?;
+        let request = request.into_inner();
+        let add = match request.source {
+            Some(proto::add_torrent_request::Source::Url(url)) => AddTorrent::from_url(url),
+            Some(proto::add_torrent_request::Source::TorrentFile(bytes)) => {
+                AddTorrent::TorrentFileBytes(Cow::Owned(bytes))
+            }
+            None => return Err(Status::invalid_argument("url or torrent_file is required")),
+        };
+        let opts = request.options.map(add_torrent_options).transpose()?;
+        let response = self.api.api_add_torrent(add, opts).await?;
+        Ok(Response::new(proto::AddTorrentResponse {
+            id: response.id.map(|id| id as u64),
+            details: Some(response.details.into()),
+            output_folder: response.output_folder,
+            seen_peers: response
+                .seen_peers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect(),
+        }))
+    }
+
+    async fn remove_torrent(
+        &self,
+        request: Request<proto::RemoveTorrentRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.check_writable()?;
+        let request = request.into_inner();
+        let idx = parse_torrent(&request.torrent)?;
+        if request.delete_files {
+            self.api.api_torrent_action_delete(idx)?;
+        } else {
+            self.api.api_torrent_action_forget(idx)?;
+        }
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn pause_torrent(
+        &self,
+        request: Request<proto::TorrentRef>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.check_writable()?;
+        let idx = parse_torrent(&request.get_ref().torrent)?;
+        self.api.api_torrent_action_pause(idx)?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn start_torrent(
+        &self,
+        request: Request<proto::TorrentRef>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.check_writable()?;
+        let idx = parse_torrent(&request.get_ref().torrent)?;
+        self.api.api_torrent_action_start(idx)?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<proto::TorrentRef>,
+    ) -> Result<Response<proto::TorrentStats>, Status> {
+        let idx = parse_torrent(&request.get_ref().torrent)?;
+        let stats = self.api.api_stats_v1(idx)?;
+        Ok(Response::new(stats.into()))
+    }
+
+    async fn watch_stats(
+        &self,
+        request: Request<Streaming<proto::WatchStatsRequest>>,
+    ) -> Result<Response<Self::WatchStatsStream>, Status> {
+        enum Wakeup {
+            Request(Option<proto::WatchStatsRequest>),
+            Tick,
+        }
+
+        let mut requests = request.into_inner();
+        let service = self.clone();
+        let stream = async_stream::try_stream! {
+            let mut torrents = Vec::new();
+            let mut interval = tokio::time::interval(DEFAULT_WATCH_INTERVAL);
+            loop {
+                let wakeup = tokio::select! {
+                    r = requests.message() => Wakeup::Request(r?),
+                    _ = interval.tick() => Wakeup::Tick,
+                };
+                match wakeup {
+                    Wakeup::Request(Some(r)) => {
+                        torrents = parse_torrents(&r.torrents)?;
+                        let period = r
+                            .interval_ms
+                            .map(|ms| Duration::from_millis(ms.into()))
+                            .unwrap_or(DEFAULT_WATCH_INTERVAL)
+                            .max(MIN_WATCH_INTERVAL);
+                        // The first tick is immediate, so the new selection is sent right away.
+                        interval = tokio::time::interval(period);
+                    }
+                    Wakeup::Request(None) => break,
+                    Wakeup::Tick => {
+                        for update in service.stats_updates(&torrents) {
+                            yield update;
+                        }
+                    }
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn events(
+        &self,
+        request: Request<proto::EventsRequest>,
+    ) -> Result<Response<Self::EventsStream>, Status> {
+        let filter = parse_torrents(&request.get_ref().torrents)?
+            .into_iter()
+            .map(|t| {
+                self.api
+                    .mgr_handle(t)
+                    .map(|h| h.info_hash())
+                    .map_err(Status::from)
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        let stream = self.api.api_events_stream().filter_map(move |e| {
+            let e = match e {
+                Ok(e) if filter.is_empty() || filter.contains(&e.info_hash()) => Some(Ok(e.into())),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => Some(Ok(proto::Event {
+                    info_hash: String::new(),
+                    event: Some(proto::event::Event::Lagged(proto::event::Lagged {
+                        skipped,
+                    })),
+                })),
+            };
+            futures::future::ready(e)
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn add_torrent_options(o: proto::AddTorrentOptions) -> Result<AddTorrentOptions, Status> {
+    let initial_peers = o
+        .initial_peers
+        .iter()
+        .map(|p| {
+            p.parse()
+                .map_err(|_| Status::invalid_argument(format!("invalid peer address {p:?}")))
+        })
+        .collect::<Result<Vec<SocketAddr>, Status>>()?;
+    Ok(AddTorrentOptions {
+        paused: o.paused,
+        overwrite: o.overwrite,
+        list_only: o.list_only,
+        sequential: o.sequential,
+        output_folder: o.output_folder,
+        sub_folder: o.sub_folder,
+        only_files: (!o.only_files.is_empty())
+            .then(|| o.only_files.into_iter().map(|i| i as usize).collect()),
+        only_files_regex: o.only_files_regex,
+        category: o.category,
+        initial_peers: (!initial_peers.is_empty()).then_some(initial_peers),
+        ..Default::default()
+    })
+}
+
+impl From<TorrentDetailsResponse> for proto::TorrentDetails {
+    fn from(d: TorrentDetailsResponse) -> Self {
+        Self {
+            info_hash: d.info_hash,
+            name: d.name,
+            files: d
+                .files
+                .into_iter()
+                .map(|f| proto::TorrentFile {
+                    name: f.name,
+                    components: f.components,
+                    length: f.length,
+                    included: f.included,
+                    have_bytes: f.have_bytes,
+                })
+                .collect(),
+            category: d.category,
+        }
+    }
+}
+
+impl From<TorrentStats> for proto::TorrentStats {
+    fn from(s: TorrentStats) -> Self {
+        Self {
+            state: s.state.to_string(),
+            error: s.error,
+            progress_bytes: s.progress_bytes,
+            uploaded_bytes: s.uploaded_bytes,
+            total_bytes: s.total_bytes,
+            finished: s.finished,
+            file_progress: s.file_progress,
+            live: s.live.map(|l| {
+                let p = &l.snapshot.peer_stats;
+                proto::LiveStats {
+                    download_speed_mbps: l.download_speed.mbps,
+                    upload_speed_mbps: l.upload_speed.mbps,
+                    time_remaining_secs: l.time_remaining.map(|t| t.as_secs()),
+                    fetched_bytes: l.snapshot.fetched_bytes,
+                    wasted_bytes: l.snapshot.wasted_bytes,
+                    downloaded_and_checked_pieces: l.snapshot.downloaded_and_checked_pieces,
+                    hash_failed_pieces: l.snapshot.hash_failed_pieces,
+                    min_piece_availability: l.snapshot.min_piece_availability,
+                    distributed_copies: l.snapshot.distributed_copies,
+                    download_bps_smoothed: l.snapshot.download_bps_smoothed,
+                    upload_bps_smoothed: l.snapshot.upload_bps_smoothed,
+                    eta_secs: l.snapshot.eta_secs,
+                    peers: Some(proto::PeerStats {
+                        queued: p.queued as u64,
+                        connecting: p.connecting as u64,
+                        live: p.live as u64,
+                        seen: p.seen as u64,
+                        dead: p.dead as u64,
+                        not_needed: p.not_needed as u64,
+                        banned: p.banned as u64,
+                    }),
+                }
+            }),
+        }
+    }
+}
+
+impl From<Event> for proto::Event {
+    fn from(e: Event) -> Self {
+        use proto::event::{self, Event as E};
+
+        let info_hash = e.info_hash().as_string();
+        let event = match e {
+            Event::TorrentAdded { name, .. } => E::TorrentAdded(event::TorrentAdded { name }),
+            Event::PieceCompleted { piece, .. } => {
+                E::PieceCompleted(event::PieceCompleted { piece })
+            }
+            Event::TorrentFinished { .. } => E::TorrentFinished(event::TorrentFinished {}),
+            Event::PeerConnected { addr, .. } => E::PeerConnected(event::PeerConnected {
+                addr: addr.to_string(),
+            }),
+            Event::PeerBanned { addr, .. } => E::PeerBanned(event::PeerBanned {
+                addr: addr.to_string(),
+            }),
+            Event::TrackerError { tracker, error, .. } => {
+                E::TrackerError(event::TrackerError { tracker, error })
+            }
+            Event::DiskError { error, .. } => E::DiskError(event::DiskError { error }),
+            Event::TorrentError { error, .. } => E::TorrentError(event::TorrentError { error }),
+            Event::TorrentStalled {
+                stalled_for_secs, ..
+            } => E::TorrentStalled(event::TorrentStalled { stalled_for_secs }),
+        };
+        Self {
+            info_hash,
+            event: Some(event),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Running the user's commands when something happens to a torrent, e.g. to move or unpack it
+// once it's finished.
+//
+// Commands run in a shell on the blocking thread pool, so that a slow one doesn't hold up the
+// runtime, and what they print is logged. They get the details of the torrent in environment
+// variables: RQBIT_EVENT, RQBIT_INFO_HASH, RQBIT_TORRENT_NAME, RQBIT_TORRENT_PATH, and
+// RQBIT_ERROR for errors.
+
+use std::{path::PathBuf, process::Output};
+
+use librqbit_core::hash_id::Id20;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Commands to run when something happens to a torrent. The session's hooks apply to all
+/// torrents, and the ones set for a torrent take precedence over them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentHooks {
+    /// When the torrent is added. Not run again when the session is restored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_added: Option<String>,
+    /// When all the selected files were downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_finished: Option<String>,
+    /// When the torrent stops with an error, e.g. it failed to initialize or to write to disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<String>,
+}
+
+impl TorrentHooks {
+    pub fn is_empty(&self) -> bool {
+        self.on_added.is_none() && self.on_finished.is_none() && self.on_error.is_none()
+    }
+
+    pub(crate) fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Added => self.on_added.as_deref(),
+            HookEvent::Finished => self.on_finished.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HookEvent {
+    Added,
+    Finished,
+    Error,
+}
+
+impl HookEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::Added => "added",
+            HookEvent::Finished => "finished",
+            HookEvent::Error => "error",
+        }
+    }
+}
+
+pub(crate) struct HookEnv {
+    pub info_hash: Id20,
+    pub name: Option<String>,
+    pub path: PathBuf,
+    pub error: Option<String>,
+}
+
+fn run_command(command: &str, event: HookEvent, env: &HookEnv) -> std::io::Result<Output> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.stdin(std::process::Stdio::null())
+        .env("RQBIT_EVENT", event.as_str())
+        .env("RQBIT_INFO_HASH", env.info_hash.as_string())
+        .env(
+            "RQBIT_TORRENT_NAME",
+            env.name.as_deref().unwrap_or_default(),
+        )
+        .env("RQBIT_TORRENT_PATH", &env.path)
+        .env("RQBIT_ERROR", env.error.as_deref().unwrap_or_default())
+        .output()
+}
+
+// Run the command in the background, logging its output in the current span.
+pub(crate) fn spawn_hook(command: String, event: HookEvent, env: HookEnv) {
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _guard = span.enter();
+        info!(event = event.as_str(), command, "running hook");
+        let output = match run_command(&command, event, &env) {
+            Ok(output) => output,
+            Err(e) => {
+                warn!(event = event.as_str(), command, "error running hook: {e:#}");
+                return;
+            }
+        };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            info!(event = event.as_str(), "hook: {line}");
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            warn!(event = event.as_str(), "hook: {line}");
+        }
+        if !output.status.success() {
+            warn!(
+                event = event.as_str(),
+                command, "hook failed: {}", output.status
+            );
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use librqbit_core::hash_id::Id20;
+
+    use super::{run_command, HookEnv, HookEvent};
+
+    #[test]
+    fn test_run_command() {
+        let env = HookEnv {
+            info_hash: Id20::new([1; 20]),
+            name: Some("name".into()),
+            path: "/tmp/out".into(),
+            error: None,
+        };
+        let output = run_command(
+            "echo \"$RQBIT_EVENT $RQBIT_INFO_HASH $RQBIT_TORRENT_NAME $RQBIT_TORRENT_PATH [$RQBIT_ERROR]\"",
+            HookEvent::Finished,
+            &env,
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "finished 0101010101010101010101010101010101010101 name /tmp/out []\n"
+        );
+
+        let output = run_command("echo oops >&2; exit 3", HookEvent::Error, &env).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stderr, b"oops\n");
+    }
+}
@@ -1,24 +1,32 @@
 use anyhow::Context;
 use axum::body::Bytes;
 use axum::extract::{Path, Query, State};
+use axum::response::sse::{self, KeepAlive, Sse};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
+use dht::Id20;
 use futures::future::BoxFuture;
-use futures::{FutureExt, TryStreamExt};
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use itertools::Itertools;
 
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::{debug, info};
 
 use axum::Router;
 
-use crate::api::Api;
-use crate::peer_connection::PeerConnectionOptions;
+use crate::api::{Api, PeerRateLimitsRequest, PieceMapQuery, TorrentIdOrHash, TorrentListQuery};
+use crate::chunk_tracker::FilePriority;
+use crate::feeds::{FeedId, FeedSubscription};
+use crate::peer_connection::{PeerConnectionOptions, PeerSocketBinding};
 use crate::session::{AddTorrent, AddTorrentOptions, SUPPORTED_SCHEMES};
 use crate::torrent_state::peer::stats::snapshot::PeerStatsFilter;
+use crate::tracing_subscriber_config_utils::LogStreamFilter;
 
 type ApiState = Api;
 
@@ -33,6 +41,71 @@ pub struct HttpApi {
 #[derive(Debug, Default)]
 pub struct HttpApiOptions {
     pub read_only: bool,
+    pub auth: HttpApiAuth,
+    /// Origins, besides the ones of the bundled UIs, allowed to call the API from a browser,
+    /// e.g. "https://example.com". "*" allows all origins.
+    pub cors_allowed_origins: Vec<String>,
+}
+
+/// The credentials the HTTP API requires. When both are set, either of them is accepted.
+#[derive(Debug, Default, Clone)]
+pub struct HttpApiAuth {
+    /// Username and password for HTTP basic auth.
+    pub basic: Option<(String, String)>,
+    /// A token sent as "Authorization: Bearer <token>".
+    pub bearer_token: Option<String>,
+}
+
+impl HttpApiAuth {
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || self.bearer_token.is_some()
+    }
+
+    // The accepted values of the "Authorization" header.
+    pub(crate) fn authorization_values(&self) -> Vec<String> {
+        use base64::{engine::general_purpose, Engine as _};
+
+        let basic = self.basic.as_ref().map(|(user, pass)| {
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD.encode(format!("{user}:{pass}"))
+            )
+        });
+        let bearer = self.bearer_token.as_ref().map(|t| format!("Bearer {t}"));
+        basic.into_iter().chain(bearer).collect()
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn check_auth(
+    State(auth): State<Arc<HttpApiAuth>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let authorized = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .map(|v| {
+            auth.authorization_values()
+                .iter()
+                .any(|expected| constant_time_eq(v.as_bytes(), expected.as_bytes()))
+        })
+        .unwrap_or(false);
+    if authorized {
+        return next.run(request).await;
+    }
+    let mut response = (http::StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    if auth.basic.is_some() {
+        // Makes browsers ask for the username and password, e.g. for the web UI.
+        response.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_static("Basic realm=\"rqbit\""),
+        );
+    }
+    response
 }
 
 impl HttpApi {
@@ -53,20 +126,42 @@ impl HttpApi {
             axum::Json(serde_json::json!({
                 "apis": {
                     "GET /": "list all available APIs",
+                    "GET /events": "Server-sent events with JSON data: torrent_added, piece_completed, torrent_finished, peer_connected, peer_banned, tracker_error, disk_error, torrent_error and torrent_stalled. A \"lagged\" event means some events were dropped",
+                    "GET /metrics": "Prometheus metrics",
+                    "GET /stats": "Session-wide stats of all torrents: rates, peers, totals, DHT and UPnP port mappings",
+                    "GET /stats/bandwidth": "Per-second download and upload rates of the session over the last 10 minutes",
                     "GET /dht/stats": "DHT stats",
                     "GET /dht/table": "DHT routing table",
-                    "GET /torrents": "List torrents (default torrent is 0)",
-                    "GET /torrents/{index}": "Torrent details",
+                    "GET /dht/scrape/{info_hash}": "Estimate a torrent's seeds and peers from the DHT (BEP 33)",
+                    "GET /torrents": "List torrents (default torrent is 0). Supports ?limit=, ?offset=, ?sort_by=added|name|progress|download_rate|upload_rate and ?desc=true",
+                    "GET /torrents/{index}": "Torrent details. In all /torrents/{index} endpoints, {index} is the torrent id or its info hash",
                     "GET /torrents/{index}/haves": "The bitfield of have pieces",
+                    "GET /torrents/{index}/piece_map": "Per-piece state (0 missing, 1 downloading, 2 have, 3 failed), 2 bits per piece, base64. Pass ?encoding=rle for [state, count] runs instead",
                     "GET /torrents/{index}/stats/v1": "Torrent stats",
+                    "GET /torrents/{index}/stats/bandwidth": "Per-second download and upload rates of a live torrent over the last 10 minutes",
                     "GET /torrents/{index}/peer_stats": "Per peer stats",
+                    "POST /torrents/{index}/peers/{addr}/rate_limits": "Limit a peer's rates. POST json of the form {\"upload_bps\": 1024, \"download_bps\": null}",
                     "POST /torrents/{index}/pause": "Pause torrent",
                     "POST /torrents/{index}/start": "Resume torrent",
+                    "POST /torrents/{index}/recheck": "Check all the files of the torrent again",
+                    "POST /torrents/{index}/pieces/{piece}/verify": "Hash one piece on disk again, and download it again if it doesn't match. Returns {\"valid\": bool}",
                     "POST /torrents/{index}/forget": "Forget about the torrent, keep the files",
                     "POST /torrents/{index}/delete": "Forget about the torrent, remove the files",
                     "POST /torrents/{index}/update_only_files": "Change the selection of files to download. You need to POST json of the following form {\"only_files\": [0, 1, 2]}",
+                    "POST /torrents/{index}/set_sequential": "Download pieces in order. You need to POST json of the following form {\"sequential\": true}",
+                    "POST /torrents/{index}/file_priorities": "Set the priority of every file (skip, low, normal or high). You need to POST json of the following form {\"priorities\": [\"high\", \"skip\"]}",
+                    "POST /torrents/{index}/head_tail_files": "Download the first and last pieces of these files first, e.g. for media players to probe them. You need to POST json of the following form {\"files\": [0, 2]}",
+                    "POST /torrents/{index}/move_storage": "Move the files to another folder, also while downloading. You need to POST json of the following form {\"output_folder\": \"/new/folder\"}",
+                    "POST /torrents/{index}/files/{file_index}/rename": "Rename a file, the path is relative to the torrent's folder. You need to POST json of the following form {\"path\": \"dir/new_name.mkv\"}",
+                    "POST /torrents/{index}/rename": "Rename the folder of the torrent. You need to POST json of the following form {\"name\": \"new name\"}",
+                    "GET /torrents/{index}/trackers": "Announce status of each tracker: last and next announce, last error, seeders and leechers, peers received",
+                    "POST /torrents/{index}/reannounce": "Announce to the trackers and the DHT now. Returns the result of each tracker announce",
                     "POST /torrents": "Add a torrent here. magnet: or http:// or a local file.",
+                    "GET /feeds": "List RSS/Atom feed subscriptions",
+                    "POST /feeds": "Subscribe to a feed. POST json of the form {\"url\": \"https://...\", \"include\": \"regex\", \"exclude\": \"regex\", \"poll_interval_secs\": 900, \"category\": \"tv\", \"output_folder\": \"/downloads/tv\"}",
+                    "POST /feeds/{id}/delete": "Unsubscribe from a feed",
                     "POST /rust_log": "Set RUST_LOG to this post launch (for debugging)",
+                    "GET /stream_logs": "Stream logs as newline-delimited JSON. Filter with ?level=trace&target=librqbit::peer_connection",
                     "GET /web/": "Web UI",
                 },
                 "server": "rqbit",
@@ -74,6 +169,14 @@ impl HttpApi {
             }))
         }
 
+        async fn session_stats(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_session_stats())
+        }
+
+        async fn session_bandwidth_history(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_session_bandwidth_history())
+        }
+
         async fn dht_stats(State(state): State<ApiState>) -> Result<impl IntoResponse> {
             state.api_dht_stats().map(axum::Json)
         }
@@ -82,8 +185,18 @@ impl HttpApi {
             state.api_dht_table().map(axum::Json)
         }
 
-        async fn torrents_list(State(state): State<ApiState>) -> impl IntoResponse {
-            axum::Json(state.api_torrent_list())
+        async fn dht_scrape(
+            State(state): State<ApiState>,
+            Path(info_hash): Path<Id20>,
+        ) -> Result<impl IntoResponse> {
+            state.api_dht_scrape(info_hash).await.map(axum::Json)
+        }
+
+        async fn torrents_list(
+            State(state): State<ApiState>,
+            Query(query): Query<TorrentListQuery>,
+        ) -> impl IntoResponse {
+            axum::Json(state.api_torrent_list_ext(query))
         }
 
         async fn torrents_post(
@@ -120,64 +233,106 @@ impl HttpApi {
 
         async fn torrent_details(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_torrent_details(idx).map(axum::Json)
         }
 
         async fn torrent_haves(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_dump_haves(idx)
         }
 
+        async fn torrent_piece_map(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            Query(query): Query<PieceMapQuery>,
+        ) -> Result<impl IntoResponse> {
+            state.api_piece_map(idx, query).map(axum::Json)
+        }
+
         async fn torrent_stats_v0(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_stats_v0(idx).map(axum::Json)
         }
 
         async fn torrent_stats_v1(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_stats_v1(idx).map(axum::Json)
         }
 
+        async fn torrent_bandwidth_history(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_bandwidth_history(idx).map(axum::Json)
+        }
+
         async fn peer_stats(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
             Query(filter): Query<PeerStatsFilter>,
         ) -> Result<impl IntoResponse> {
             state.api_peer_stats(idx, filter).map(axum::Json)
         }
 
+        async fn peer_set_rate_limits(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(TorrentIdOrHash, SocketAddr)>,
+            axum::Json(req): axum::Json<PeerRateLimitsRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_peer_set_rate_limits(idx, addr, req)
+                .map(axum::Json)
+        }
+
         async fn torrent_action_pause(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_torrent_action_pause(idx).map(axum::Json)
         }
 
+        async fn torrent_action_recheck(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_action_recheck(idx).map(axum::Json)
+        }
+
+        async fn torrent_action_verify_piece(
+            State(state): State<ApiState>,
+            Path((idx, piece)): Path<(TorrentIdOrHash, u32)>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_verify_piece(idx, piece)
+                .await
+                .map(axum::Json)
+        }
+
         async fn torrent_action_start(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_torrent_action_start(idx).map(axum::Json)
         }
 
         async fn torrent_action_forget(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_torrent_action_forget(idx).map(axum::Json)
         }
 
         async fn torrent_action_delete(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
         ) -> Result<impl IntoResponse> {
             state.api_torrent_action_delete(idx).map(axum::Json)
         }
@@ -189,7 +344,7 @@ impl HttpApi {
 
         async fn torrent_action_update_only_files(
             State(state): State<ApiState>,
-            Path(idx): Path<usize>,
+            Path(idx): Path<TorrentIdOrHash>,
             axum::Json(req): axum::Json<UpdateOnlyFilesRequest>,
         ) -> Result<impl IntoResponse> {
             state
@@ -197,6 +352,134 @@ impl HttpApi {
                 .map(axum::Json)
         }
 
+        #[derive(Deserialize)]
+        struct SetSequentialRequest {
+            sequential: bool,
+        }
+
+        async fn torrent_action_set_sequential(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            axum::Json(req): axum::Json<SetSequentialRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_set_sequential(idx, req.sequential)
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct SetFilePrioritiesRequest {
+            priorities: Vec<FilePriority>,
+        }
+
+        async fn torrent_action_set_file_priorities(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            axum::Json(req): axum::Json<SetFilePrioritiesRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_set_file_priorities(idx, req.priorities)
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct SetHeadTailFilesRequest {
+            files: Vec<usize>,
+        }
+
+        async fn torrent_action_set_head_tail_files(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            axum::Json(req): axum::Json<SetHeadTailFilesRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_set_head_tail_files(idx, req.files)
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct MoveStorageRequest {
+            output_folder: String,
+        }
+
+        async fn torrent_action_move_storage(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            axum::Json(req): axum::Json<MoveStorageRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_move_storage(idx, req.output_folder.into())
+                .await
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct RenameFileRequest {
+            path: String,
+        }
+
+        async fn torrent_action_rename_file(
+            State(state): State<ApiState>,
+            Path((idx, file_id)): Path<(TorrentIdOrHash, usize)>,
+            axum::Json(req): axum::Json<RenameFileRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_rename_file(idx, file_id, req.path.into())
+                .await
+                .map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct RenameRootFolderRequest {
+            name: String,
+        }
+
+        async fn torrent_action_rename_root_folder(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+            axum::Json(req): axum::Json<RenameRootFolderRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_rename_root_folder(idx, req.name)
+                .await
+                .map(axum::Json)
+        }
+
+        async fn torrent_trackers(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_trackers(idx).map(axum::Json)
+        }
+
+        async fn torrent_action_reannounce(
+            State(state): State<ApiState>,
+            Path(idx): Path<TorrentIdOrHash>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_reannounce(idx)
+                .await
+                .map(axum::Json)
+        }
+
+        async fn feeds_list(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_feed_list())
+        }
+
+        async fn feeds_post(
+            State(state): State<ApiState>,
+            axum::Json(subscription): axum::Json<FeedSubscription>,
+        ) -> Result<impl IntoResponse> {
+            state.api_feed_add(subscription).map(axum::Json)
+        }
+
+        async fn feed_delete(
+            State(state): State<ApiState>,
+            Path(id): Path<FeedId>,
+        ) -> Result<impl IntoResponse> {
+            state.api_feed_delete(id).map(axum::Json)
+        }
+
         async fn set_rust_log(
             State(state): State<ApiState>,
             new_value: String,
@@ -204,37 +487,109 @@ impl HttpApi {
             state.api_set_rust_log(new_value).map(axum::Json)
         }
 
-        async fn stream_logs(State(state): State<ApiState>) -> Result<impl IntoResponse> {
-            let s = state.api_log_lines_stream()?.map_err(|e| {
+        async fn stream_logs(
+            State(state): State<ApiState>,
+            Query(filter): Query<LogStreamFilter>,
+        ) -> Result<impl IntoResponse> {
+            let s = state.api_log_lines_stream(filter)?.map_err(|e| {
                 debug!(error=%e, "stream_logs");
                 e
             });
             Ok(axum::body::Body::from_stream(s))
         }
 
+        async fn metrics(State(state): State<ApiState>) -> impl IntoResponse {
+            (
+                [("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+                state.api_metrics(),
+            )
+        }
+
+        async fn events(State(state): State<ApiState>) -> impl IntoResponse {
+            let s = state.api_events_stream().map(|e| match e {
+                Ok(event) => sse::Event::default().json_data(event),
+                // The client fell behind, it should refetch the state it's interested in.
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    debug!(skipped, "events: lagged");
+                    Ok(sse::Event::default()
+                        .event("lagged")
+                        .data(skipped.to_string()))
+                }
+            });
+            Sse::new(s).keep_alive(KeepAlive::default())
+        }
+
         let mut app = Router::new()
             .route("/", get(api_root))
             .route("/stream_logs", get(stream_logs))
+            .route("/events", get(events))
+            .route("/metrics", get(metrics))
+            .route("/stats", get(session_stats))
+            .route("/stats/bandwidth", get(session_bandwidth_history))
             .route("/rust_log", post(set_rust_log))
             .route("/dht/stats", get(dht_stats))
             .route("/dht/table", get(dht_table))
+            .route("/dht/scrape/:info_hash", get(dht_scrape))
             .route("/torrents", get(torrents_list))
             .route("/torrents/:id", get(torrent_details))
             .route("/torrents/:id/haves", get(torrent_haves))
+            .route("/torrents/:id/piece_map", get(torrent_piece_map))
             .route("/torrents/:id/stats", get(torrent_stats_v0))
             .route("/torrents/:id/stats/v1", get(torrent_stats_v1))
-            .route("/torrents/:id/peer_stats", get(peer_stats));
+            .route(
+                "/torrents/:id/stats/bandwidth",
+                get(torrent_bandwidth_history),
+            )
+            .route("/torrents/:id/trackers", get(torrent_trackers))
+            .route("/torrents/:id/peer_stats", get(peer_stats))
+            .route("/feeds", get(feeds_list));
 
         if !self.opts.read_only {
             app = app
                 .route("/torrents", post(torrents_post))
                 .route("/torrents/:id/pause", post(torrent_action_pause))
                 .route("/torrents/:id/start", post(torrent_action_start))
+                .route("/torrents/:id/recheck", post(torrent_action_recheck))
+                .route(
+                    "/torrents/:id/pieces/:piece/verify",
+                    post(torrent_action_verify_piece),
+                )
                 .route("/torrents/:id/forget", post(torrent_action_forget))
                 .route("/torrents/:id/delete", post(torrent_action_delete))
                 .route(
                     "/torrents/:id/update_only_files",
                     post(torrent_action_update_only_files),
+                )
+                .route(
+                    "/torrents/:id/set_sequential",
+                    post(torrent_action_set_sequential),
+                )
+                .route(
+                    "/torrents/:id/file_priorities",
+                    post(torrent_action_set_file_priorities),
+                )
+                .route(
+                    "/torrents/:id/head_tail_files",
+                    post(torrent_action_set_head_tail_files),
+                )
+                .route(
+                    "/torrents/:id/move_storage",
+                    post(torrent_action_move_storage),
+                )
+                .route(
+                    "/torrents/:id/files/:file_id/rename",
+                    post(torrent_action_rename_file),
+                )
+                .route(
+                    "/torrents/:id/rename",
+                    post(torrent_action_rename_root_folder),
+                )
+                .route("/torrents/:id/reannounce", post(torrent_action_reannounce))
+                .route("/feeds", post(feeds_post))
+                .route("/feeds/:id/delete", post(feed_delete))
+                .route(
+                    "/torrents/:id/peers/:addr/rate_limits",
+                    post(peer_set_rate_limits),
                 );
         }
 
@@ -284,6 +639,8 @@ impl HttpApi {
         let cors_layer = {
             use tower_http::cors::{AllowHeaders, AllowOrigin};
 
+            let allowed_origins = self.opts.cors_allowed_origins;
+
             const ALLOWED_ORIGINS: [&[u8]; 4] = [
                 // Webui-dev
                 b"http://localhost:3031",
@@ -294,13 +651,27 @@ impl HttpApi {
                 b"tauri://localhost",
             ];
 
-            tower_http::cors::CorsLayer::default()
-                .allow_origin(AllowOrigin::predicate(|v, _| {
+            let allow_origin = if allowed_origins.iter().any(|o| o == "*") {
+                AllowOrigin::any()
+            } else {
+                AllowOrigin::predicate(move |v, _| {
                     ALLOWED_ORIGINS.contains(&v.as_bytes())
-                }))
+                        || allowed_origins.iter().any(|o| o.as_bytes() == v.as_bytes())
+                })
+            };
+
+            tower_http::cors::CorsLayer::default()
+                .allow_origin(allow_origin)
                 .allow_headers(AllowHeaders::any())
         };
 
+        if self.opts.auth.is_enabled() {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                Arc::new(self.opts.auth),
+                check_auth,
+            ));
+        }
+
         let app = app
             .layer(cors_layer)
             .layer(tower_http::trace::TraceLayer::new_for_http())
@@ -334,6 +705,15 @@ pub(crate) struct TorrentAddQueryParams {
     pub only_files: Option<OnlyFiles>,
     pub peer_connect_timeout: Option<u64>,
     pub peer_read_write_timeout: Option<u64>,
+    pub peer_idle_timeout: Option<u64>,
+    pub peer_inactivity_timeout: Option<u64>,
+    pub bind_interface: Option<String>,
+    pub fwmark: Option<u32>,
+    pub bind_addr: Option<IpAddr>,
+    pub upload_slots: Option<usize>,
+    pub upload_rate_limit: Option<NonZeroU32>,
+    pub sequential: Option<bool>,
+    pub category: Option<String>,
     pub initial_peers: Option<InitialPeers>,
     // Will force interpreting the content as a URL.
     pub is_url: Option<bool>,
@@ -420,8 +800,19 @@ impl TorrentAddQueryParams {
             peer_opts: Some(PeerConnectionOptions {
                 connect_timeout: self.peer_connect_timeout.map(Duration::from_secs),
                 read_write_timeout: self.peer_read_write_timeout.map(Duration::from_secs),
+                idle_timeout: self.peer_idle_timeout.map(Duration::from_secs),
+                inactivity_timeout: self.peer_inactivity_timeout.map(Duration::from_secs),
                 ..Default::default()
             }),
+            socket_binding: Some(PeerSocketBinding {
+                interface: self.bind_interface,
+                fwmark: self.fwmark,
+                local_addr: self.bind_addr,
+            }),
+            upload_slots: self.upload_slots,
+            upload_rate_limit: self.upload_rate_limit,
+            sequential: self.sequential.unwrap_or(false),
+            category: self.category,
             ..Default::default()
         }
     }
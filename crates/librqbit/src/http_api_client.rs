@@ -4,7 +4,7 @@ use serde::Deserialize;
 
 use crate::{
     api::ApiAddTorrentResponse,
-    http_api::TorrentAddQueryParams,
+    http_api::{HttpApiAuth, TorrentAddQueryParams},
     session::{AddTorrent, AddTorrentOptions},
 };
 
@@ -68,6 +68,21 @@ impl HttpApiClient {
         })
     }
 
+    /// Send these credentials with every request.
+    pub fn with_auth(mut self, auth: &HttpApiAuth) -> anyhow::Result<Self> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(value) = auth.authorization_values().into_iter().next() {
+            let mut value = reqwest::header::HeaderValue::from_str(&value)
+                .context("invalid characters in credentials")?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        self.client = reqwest::ClientBuilder::new()
+            .default_headers(headers)
+            .build()?;
+        Ok(self)
+    }
+
     pub fn base_url(&self) -> &reqwest::Url {
         &self.base_url
     }
@@ -92,6 +107,7 @@ impl HttpApiClient {
     ) -> BoxFuture<'a, anyhow::Result<ApiAddTorrentResponse>> {
         async move {
             let opts = opts.unwrap_or_default();
+            let socket_binding = opts.socket_binding.unwrap_or_default();
             let params = TorrentAddQueryParams {
                 overwrite: Some(opts.overwrite),
                 only_files_regex: opts.only_files_regex,
@@ -99,6 +115,13 @@ impl HttpApiClient {
                 output_folder: opts.output_folder,
                 sub_folder: opts.sub_folder,
                 list_only: Some(opts.list_only),
+                bind_interface: socket_binding.interface,
+                fwmark: socket_binding.fwmark,
+                bind_addr: socket_binding.local_addr,
+                upload_slots: opts.upload_slots,
+                upload_rate_limit: opts.upload_rate_limit,
+                sequential: Some(opts.sequential),
+                category: opts.category,
                 ..Default::default()
             };
             let qs = serde_urlencoded::to_string(&params).unwrap();
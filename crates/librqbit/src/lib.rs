@@ -25,33 +25,70 @@
 
 pub mod api;
 mod api_error;
+mod bandwidth_history;
 mod chunk_tracker;
 mod create_torrent_file;
 mod dht_utils;
+mod direct_io;
+mod disk_space;
+mod error;
+mod events;
+mod extensions;
+mod feeds;
+mod file_io;
 mod file_ops;
+#[cfg(feature = "grpc")]
+pub mod grpc_api;
+mod hooks;
 pub mod http_api;
 pub mod http_api_client;
+mod metrics;
+mod mmap;
 mod opened_file;
 mod peer_connection;
 mod peer_info_reader;
+mod piece_picker;
+mod preallocate;
+mod queue;
+mod rate_limit;
 mod read_buf;
 mod session;
+mod session_stats;
 mod spawn_utils;
 mod torrent_state;
 pub mod tracing_subscriber_config_utils;
+mod tracker_stats;
 mod type_aliases;
+mod webhooks;
 
 pub use api::Api;
 pub use api_error::ApiError;
+pub use bandwidth_history::{BandwidthHistorySnapshot, BandwidthSample};
+pub use chunk_tracker::{FilePriority, PartialPieces};
 pub use create_torrent_file::{create_torrent, CreateTorrentOptions};
 pub use dht;
-pub use peer_connection::PeerConnectionOptions;
+pub use error::Error;
+pub use events::Event;
+pub use extensions::{ExtensionHandler, ExtensionPeer, ExtensionRegistry};
+pub use feeds::{FeedId, FeedSubscription};
+pub use hooks::TorrentHooks;
+pub use peer_connection::{PeerConnectionOptions, PeerSocketBinding, PeerTransport};
+pub use piece_picker::{
+    LastPieceFirstPicker, PickerContext, PiecePicker, RandomFirstPiecesPicker, RarestFirstPicker,
+    SequentialPicker, StreamingPicker, DEFAULT_STREAMING_LOOKAHEAD_PIECES,
+};
+pub use preallocate::Preallocation;
 pub use session::{
     AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, SessionOptions,
-    SUPPORTED_SCHEMES,
+    TrackerAnnounceStatus, SUPPORTED_SCHEMES,
 };
+pub use session_stats::SessionStatsSnapshot;
 pub use spawn_utils::spawn as librqbit_spawn;
-pub use torrent_state::{ManagedTorrent, ManagedTorrentState, TorrentStats, TorrentStatsState};
+pub use torrent_state::{
+    streaming::FileStream, ManagedTorrent, ManagedTorrentState, TorrentStats, TorrentStatsState,
+};
+pub use tracker_stats::TrackerStats;
+pub use webhooks::{Webhook, WebhookEvent};
 
 pub use buffers::*;
 pub use clone_to_owned::CloneToOwned;
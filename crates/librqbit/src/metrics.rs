@@ -0,0 +1,232 @@
+// Session metrics in the Prometheus text exposition format.
+//
+// Per-torrent metrics are labelled with the torrent id and info hash. The counters of a torrent
+// restart from 0 when it's paused and resumed, which Prometheus handles as a counter reset.
+
+use std::fmt::Write;
+
+use crate::{session::Session, torrent_state::TorrentStatsState};
+
+#[derive(Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn metric(
+        &mut self,
+        name: &str,
+        kind: MetricType,
+        help: &str,
+        samples: impl IntoIterator<Item = (Labels, u64)>,
+    ) {
+        let kind = match kind {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        };
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            self.out.push_str(name);
+            if !labels.is_empty() {
+                self.out.push('{');
+                for (idx, (label, value)) in labels.iter().enumerate() {
+                    if idx > 0 {
+                        self.out.push(',');
+                    }
+                    let value = value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    let _ = write!(self.out, "{label}=\"{value}\"");
+                }
+                self.out.push('}');
+            }
+            let _ = writeln!(self.out, " {value}");
+        }
+    }
+}
+
+pub(crate) fn render(session: &Session) -> String {
+    use MetricType::*;
+
+    let torrents = session.with_torrents(|torrents| {
+        torrents
+            .map(|(id, t)| {
+                let labels = vec![
+                    ("id", id.to_string()),
+                    ("info_hash", t.info_hash().as_string()),
+                ];
+                (labels, t.stats())
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut w = MetricsWriter::default();
+
+    let states = [
+        TorrentStatsState::Initializing,
+        TorrentStatsState::Live,
+        TorrentStatsState::Paused,
+        TorrentStatsState::Error,
+    ];
+    w.metric(
+        "rqbit_torrents",
+        Gauge,
+        "Number of torrents by state.",
+        states.iter().map(|state| {
+            let count = torrents.iter().filter(|(_, s)| s.state == *state).count();
+            (vec![("state", state.to_string())], count as u64)
+        }),
+    );
+
+    w.metric(
+        "rqbit_torrent_total_bytes",
+        Gauge,
+        "Size of the selected files of the torrent.",
+        torrents.iter().map(|(l, s)| (l.clone(), s.total_bytes)),
+    );
+    w.metric(
+        "rqbit_torrent_progress_bytes",
+        Gauge,
+        "Bytes of the selected files that were downloaded and verified.",
+        torrents.iter().map(|(l, s)| (l.clone(), s.progress_bytes)),
+    );
+
+    let live = torrents
+        .iter()
+        .filter_map(|(l, s)| Some((l, &s.live.as_ref()?.snapshot)))
+        .collect::<Vec<_>>();
+    w.metric(
+        "rqbit_torrent_uploaded_bytes_total",
+        Counter,
+        "Bytes uploaded to peers.",
+        live.iter().map(|(l, s)| ((*l).clone(), s.uploaded_bytes)),
+    );
+    w.metric(
+        "rqbit_torrent_fetched_bytes_total",
+        Counter,
+        "Bytes downloaded from peers, including data that failed verification.",
+        live.iter().map(|(l, s)| ((*l).clone(), s.fetched_bytes)),
+    );
+    w.metric(
+        "rqbit_torrent_pieces_verified_total",
+        Counter,
+        "Pieces downloaded that passed the hash check.",
+        live.iter()
+            .map(|(l, s)| ((*l).clone(), s.downloaded_and_checked_pieces)),
+    );
+    w.metric(
+        "rqbit_torrent_pieces_hash_failed_total",
+        Counter,
+        "Pieces downloaded that failed the hash check.",
+        live.iter()
+            .map(|(l, s)| ((*l).clone(), s.hash_failed_pieces)),
+    );
+    w.metric(
+        "rqbit_torrent_peers",
+        Gauge,
+        "Number of peers of the torrent by state.",
+        live.iter().flat_map(|(l, s)| {
+            let p = &s.peer_stats;
+            [
+                ("queued", p.queued),
+                ("connecting", p.connecting),
+                ("live", p.live),
+                ("dead", p.dead),
+                ("not_needed", p.not_needed),
+                ("banned", p.banned),
+            ]
+            .into_iter()
+            .map(move |(state, count)| {
+                let mut labels = (*l).clone();
+                labels.push(("state", state.to_owned()));
+                (labels, count as u64)
+            })
+        }),
+    );
+    w.metric(
+        "rqbit_torrent_peers_seen",
+        Gauge,
+        "Number of distinct peer addresses the torrent knows about.",
+        live.iter()
+            .map(|(l, s)| ((*l).clone(), s.peer_stats.seen as u64)),
+    );
+
+    w.metric(
+        "rqbit_tracker_announce_errors_total",
+        Counter,
+        "Failed tracker announces.",
+        [(Vec::new(), session.tracker_announce_errors())],
+    );
+
+    if let Some(dht) = session.get_dht() {
+        let stats = dht.stats();
+        w.metric(
+            "rqbit_dht_routing_table_size",
+            Gauge,
+            "Number of nodes in the DHT routing table.",
+            [(Vec::new(), stats.routing_table_size as u64)],
+        );
+        w.metric(
+            "rqbit_dht_routing_table_size_v6",
+            Gauge,
+            "Number of nodes in the IPv6 DHT routing table.",
+            [(Vec::new(), stats.routing_table_size_v6 as u64)],
+        );
+        w.metric(
+            "rqbit_dht_outstanding_requests",
+            Gauge,
+            "Number of DHT requests waiting for a response.",
+            [(Vec::new(), stats.outstanding_requests as u64)],
+        );
+    }
+
+    w.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricType, MetricsWriter};
+
+    #[test]
+    fn test_metrics_writer() {
+        let mut w = MetricsWriter::default();
+        w.metric(
+            "rqbit_test",
+            MetricType::Counter,
+            "A test.",
+            [
+                (vec![("id", "0".to_owned())], 5),
+                (
+                    vec![("id", "1".to_owned()), ("name", "a \"b\"".to_owned())],
+                    6,
+                ),
+            ],
+        );
+        w.metric(
+            "rqbit_gauge",
+            MetricType::Gauge,
+            "Another test.",
+            [(vec![], 1)],
+        );
+        assert_eq!(
+            w.out,
+            "# HELP rqbit_test A test.\n\
+             # TYPE rqbit_test counter\n\
+             rqbit_test{id=\"0\"} 5\n\
+             rqbit_test{id=\"1\",name=\"a \\\"b\\\"\"} 6\n\
+             # HELP rqbit_gauge Another test.\n\
+             # TYPE rqbit_gauge gauge\n\
+             rqbit_gauge 1\n"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+// Memory-mapped access to torrent files.
+//
+// Reads and writes become plain memory copies, without a seek and a read/write syscall per
+// chunk. The OS writes dirty pages back in the background. This helps when the disk (or rather
+// the syscall overhead of small I/Os) is the bottleneck, e.g. on fast local networks.
+//
+// The mapping covers the file as it was when mapped, so a file is only mapped once it has its
+// full length. Accessing a mapping of a file that was truncated by someone else crashes the
+// process with SIGBUS, which is the price of not copying through the kernel.
+
+use std::fs::File;
+
+use anyhow::Context;
+use memmap2::{Mmap, MmapMut};
+
+#[derive(Debug)]
+pub(crate) enum FileMap {
+    ReadOnly(Mmap),
+    ReadWrite(MmapMut),
+}
+
+impl FileMap {
+    pub fn new(file: &File, read_only: bool) -> anyhow::Result<Self> {
+        // SAFETY: the files are owned by the torrent, and only resized through the handle that
+        // is mapped, before mapping it.
+        let map = unsafe {
+            if read_only {
+                Self::ReadOnly(Mmap::map(file).context("error mapping file read only")?)
+            } else {
+                Self::ReadWrite(MmapMut::map_mut(file).context("error mapping file")?)
+            }
+        };
+        Ok(map)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::ReadOnly(m) => m,
+            Self::ReadWrite(m) => m,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    // The mapped bytes themselves, e.g. to hash them without copying.
+    pub fn slice_at(&self, offset: u64, len: usize) -> anyhow::Result<&[u8]> {
+        let start = offset as usize;
+        self.as_slice().get(start..start + len).with_context(|| {
+            format!(
+                "reading {len} bytes at {offset} is out of mapped range of {} bytes",
+                self.len()
+            )
+        })
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        buf.copy_from_slice(self.slice_at(offset, buf.len())?);
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let len = self.len();
+        let map = match self {
+            Self::ReadWrite(m) => m,
+            Self::ReadOnly(_) => anyhow::bail!("bug: writing to a read only mapping"),
+        };
+        let start = offset as usize;
+        map.get_mut(start..start + data.len())
+            .with_context(|| {
+                format!(
+                    "writing {} bytes at {offset} is out of mapped range of {len} bytes",
+                    data.len()
+                )
+            })?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    // Write dirty pages back, waiting until it's done.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if let Self::ReadWrite(m) = self {
+            m.flush().context("error flushing mapped file")?;
+        }
+        Ok(())
+    }
+
+    // Start writing dirty pages back without waiting for it, e.g. before the mapping is dropped.
+    pub fn flush_async(&self) -> anyhow::Result<()> {
+        if let Self::ReadWrite(m) = self {
+            m.flush_async().context("error flushing mapped file")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::FileMap;
+
+    #[test]
+    fn test_read_write() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(&[0u8; 100]).unwrap();
+
+        let mut map = FileMap::new(f.as_file(), false).unwrap();
+        assert_eq!(map.len(), 100);
+        map.write_at(10, &[1, 2, 3]).unwrap();
+        assert!(map.write_at(98, &[1, 2, 3]).is_err());
+
+        let mut buf = [0u8; 5];
+        map.read_at(9, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+        assert!(map.read_at(96, &mut buf).is_err());
+
+        map.flush_async().unwrap();
+        drop(map);
+        assert_eq!(&std::fs::read(f.path()).unwrap()[9..14], &[0, 1, 2, 3, 0]);
+
+        let mut map = FileMap::new(f.as_file(), true).unwrap();
+        assert!(map.write_at(0, &[1]).is_err());
+    }
+}
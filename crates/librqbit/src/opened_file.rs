@@ -1,37 +1,154 @@
 use std::{
     fs::File,
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::Context;
 use librqbit_core::lengths::Lengths;
-use parking_lot::Mutex;
-use tracing::debug;
+use parking_lot::{
+    MappedRwLockReadGuard, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    direct_io,
+    mmap::FileMap,
+    preallocate::{self, Preallocation},
+};
+
+// Appended to the names of incomplete files when part files are enabled.
+pub(crate) const PART_SUFFIX: &str = ".part";
+
+pub(crate) fn part_filename(filename: &Path) -> PathBuf {
+    let mut s = filename.as_os_str().to_owned();
+    s.push(PART_SUFFIX);
+    PathBuf::from(s)
+}
+
+// How many files are open at once if not configured.
+pub(crate) const DEFAULT_MAX_OPEN_FILES: usize = 512;
+
+// A file handle that the pool may close while it's not locked. I/O only needs a read lock,
+// as it's all positional, see file_io.
+//
+// The direct I/O handle and the memory map are closed along with it, lock the file before
+// locking them.
+#[derive(Debug, Default)]
+struct FileSlot {
+    file: RwLock<Option<File>>,
+    direct: Mutex<Option<File>>,
+    mmap: Mutex<Option<FileMap>>,
+    last_used: AtomicU64,
+}
+
+impl FileSlot {
+    fn close_locked(&self, g: &mut Option<File>) {
+        if let Some(old) = self.mmap.lock().take() {
+            if let Err(e) = old.flush_async() {
+                warn!("error flushing memory map: {e:#}");
+            }
+        }
+        self.direct.lock().take();
+        *g = None;
+    }
+}
+
+// Limits how many files are open at the same time, so that torrents with many thousands of
+// files don't run out of file descriptors. The least recently used files are closed, and
+// reopened once they are needed again.
+//
+// Only the main handles are counted, with direct I/O files open for writing use one more.
+#[derive(Debug)]
+pub(crate) struct FilePool {
+    max_open: usize,
+    clock: AtomicU64,
+    open: Mutex<Vec<Arc<FileSlot>>>,
+}
+
+impl Default for FilePool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_OPEN_FILES)
+    }
+}
+
+impl FilePool {
+    pub fn new(max_open: usize) -> Self {
+        Self {
+            max_open: max_open.max(1),
+            clock: AtomicU64::new(0),
+            open: Default::default(),
+        }
+    }
+
+    #[cfg(test)]
+    fn open_files(&self) -> usize {
+        self.open.lock().len()
+    }
+
+    fn touch(&self, slot: &FileSlot) {
+        slot.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    // Called with the slot locked, before opening it. Files that are locked, i.e. in use,
+    // aren't closed, so the limit may be exceeded for a moment.
+    fn make_room(&self, slot: &Arc<FileSlot>) {
+        let mut open = self.open.lock();
+        // Also forget the files that were closed, e.g. to be deleted.
+        open.retain(|s| {
+            let closed = matches!(s.file.try_read().as_deref(), Some(None));
+            !Arc::ptr_eq(s, slot) && !closed
+        });
+        while open.len() >= self.max_open {
+            let lru = open
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| !s.file.is_locked())
+                .min_by_key(|(_, s)| s.last_used.load(Ordering::Relaxed))
+                .map(|(idx, _)| idx);
+            let idx = match lru {
+                Some(idx) => idx,
+                None => break,
+            };
+            match open[idx].file.try_write() {
+                Some(mut f) => open[idx].close_locked(&mut f),
+                // Locked just now, look for another one.
+                None => continue,
+            }
+            open.swap_remove(idx);
+        }
+        open.push(slot.clone());
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct OpenedFile {
-    pub file: Mutex<File>,
-    pub filename: PathBuf,
+    // Use lock_file() to access it. Shared with the pool, which closes it when there are too
+    // many open files.
+    slot: Arc<FileSlot>,
+    pool: Arc<FilePool>,
+    pub direct_io: bool,
+    pub use_mmap: bool,
+    pub preallocation: Preallocation,
+    // Changes when the file is moved. Lock the file before locking this.
+    filename: RwLock<PathBuf>,
+    read_only: AtomicBool,
+    // The file is incomplete and named with PART_SUFFIX.
+    part: AtomicBool,
     pub offset_in_torrent: u64,
     pub have: AtomicU64,
     pub piece_range: std::ops::Range<u32>,
     pub len: u64,
 }
 
-pub(crate) fn dummy_file() -> anyhow::Result<std::fs::File> {
-    #[cfg(target_os = "windows")]
-    const DEVNULL: &str = "NUL";
-    #[cfg(not(target_os = "windows"))]
-    const DEVNULL: &str = "/dev/null";
-
-    std::fs::OpenOptions::new()
-        .read(true)
-        .open(DEVNULL)
-        .with_context(|| format!("error opening {}", DEVNULL))
-}
-
 impl OpenedFile {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         f: File,
         filename: PathBuf,
@@ -39,17 +156,97 @@ impl OpenedFile {
         len: u64,
         offset_in_torrent: u64,
         piece_range: std::ops::Range<u32>,
+        direct_io: bool,
+        use_mmap: bool,
+        preallocation: Preallocation,
+        pool: Arc<FilePool>,
     ) -> Self {
+        let slot = Arc::new(FileSlot::default());
+        {
+            let mut g = slot.file.write();
+            pool.make_room(&slot);
+            pool.touch(&slot);
+            *g = Some(f);
+        }
         Self {
-            file: Mutex::new(f),
-            filename,
+            slot,
+            pool,
+            direct_io,
+            use_mmap,
+            preallocation,
+            filename: RwLock::new(filename),
+            read_only: AtomicBool::new(false),
+            part: AtomicBool::new(false),
             have: AtomicU64::new(have),
             len,
             offset_in_torrent,
             piece_range,
         }
     }
-    pub fn reopen(&self, read_only: bool) -> anyhow::Result<()> {
+
+    // Mark the file as named with PART_SUFFIX, see finalize().
+    pub fn with_part(self, part: bool) -> Self {
+        self.part.store(part, Ordering::Relaxed);
+        self
+    }
+
+    pub fn is_part(&self) -> bool {
+        self.part.load(Ordering::Relaxed)
+    }
+
+    // A second handle opened for direct I/O, used for writes when direct I/O is enabled
+    // and the file is open for writing. Lock the file before locking this.
+    pub fn direct(&self) -> MutexGuard<'_, Option<File>> {
+        self.slot.direct.lock()
+    }
+
+    // The file mapped into memory, used for reads and writes when mmap is enabled and the
+    // file has its full length. Lock the file before locking this.
+    pub fn mmap(&self) -> MutexGuard<'_, Option<FileMap>> {
+        self.slot.mmap.lock()
+    }
+
+    // Rename a complete part file to its final name.
+    pub fn finalize(&self) -> anyhow::Result<()> {
+        if !self.is_part() {
+            return Ok(());
+        }
+        let filename = self.filename();
+        let final_filename = filename
+            .to_str()
+            .and_then(|s| s.strip_suffix(PART_SUFFIX))
+            .map(PathBuf::from)
+            .with_context(|| format!("{filename:?} doesn't end with {PART_SUFFIX}"))?;
+        self.move_to(&final_filename)?;
+        self.part.store(false, Ordering::Relaxed);
+        debug!("finalized {final_filename:?}");
+        Ok(())
+    }
+
+    // Lock the file for I/O, opening it again, along with its direct I/O handle and memory map,
+    // if the pool closed it.
+    pub fn lock_file(&self) -> anyhow::Result<MappedRwLockReadGuard<'_, File>> {
+        self.pool.touch(&self.slot);
+        let g = self.slot.file.read();
+        let g = if g.is_none() {
+            drop(g);
+            let mut g = self.slot.file.write();
+            if g.is_none() {
+                self.reopen_locked(&mut g, self.read_only.load(Ordering::Relaxed))?;
+            }
+            RwLockWriteGuard::downgrade(g)
+        } else {
+            g
+        };
+        Ok(RwLockReadGuard::map(g, |f| {
+            f.as_ref().expect("the file was opened above")
+        }))
+    }
+
+    fn open_locked(&self, g: &mut Option<File>, read_only: bool) -> anyhow::Result<()> {
+        if g.is_none() {
+            self.pool.make_room(&self.slot);
+        }
         let log_suffix = if read_only { " read only" } else { "" };
 
         let mut open_opts = std::fs::OpenOptions::new();
@@ -58,26 +255,110 @@ impl OpenedFile {
             open_opts.write(true).create(false);
         }
 
-        let mut g = self.file.lock();
-        *g = open_opts
-            .open(&self.filename)
-            .with_context(|| format!("error re-opening {:?}{log_suffix}", self.filename))?;
-        debug!("reopened {:?}{log_suffix}", self.filename);
+        let filename = self.filename();
+        *g = Some(
+            open_opts
+                .open(&filename)
+                .with_context(|| format!("error opening {filename:?}{log_suffix}"))?,
+        );
+        self.read_only.store(read_only, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn reopen(&self, read_only: bool) -> anyhow::Result<()> {
+        let mut g = self.slot.file.write();
+        self.pool.touch(&self.slot);
+        self.reopen_locked(&mut g, read_only)
+    }
+
+    fn reopen_locked(&self, g: &mut Option<File>, read_only: bool) -> anyhow::Result<()> {
+        let log_suffix = if read_only { " read only" } else { "" };
+        self.open_locked(g, read_only)?;
+        let filename = self.filename();
+        debug!("reopened {filename:?}{log_suffix}");
+
+        let direct = if self.direct_io && !read_only {
+            match direct_io::open_direct(&filename) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    warn!("falling back to buffered writes: {e:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        *self.slot.direct.lock() = direct;
+        if let Some(f) = g.as_ref() {
+            self.remap(f, read_only);
+        }
+        Ok(())
+    }
+
+    // Map the file if mmap is enabled, replacing the previous mapping. Files that don't have
+    // their full length yet aren't mapped, and mapping errors fall back to regular I/O.
+    fn remap(&self, file: &File, read_only: bool) {
+        self.unmap();
+        if !self.use_mmap || self.len == 0 {
+            return;
+        }
+        match file.metadata() {
+            Ok(m) if m.len() == self.len => {}
+            _ => return,
+        }
+        match FileMap::new(file, read_only) {
+            Ok(m) => *self.slot.mmap.lock() = Some(m),
+            Err(e) => warn!("{:?}: falling back to regular I/O: {e:#}", self.filename()),
+        }
+    }
+
+    fn unmap(&self) {
+        if let Some(old) = self.slot.mmap.lock().take() {
+            if let Err(e) = old.flush_async() {
+                warn!("{:?}: {e:#}", self.filename());
+            }
+        }
+    }
+
+    // Extend the file to its full length if it's shorter, e.g. when it was just selected for
+    // download.
+    pub fn ensure_len(&self) -> anyhow::Result<()> {
+        let f = self.lock_file()?;
+        let filename = self.filename();
+        let current = f
+            .metadata()
+            .with_context(|| format!("error getting metadata of {filename:?}"))?
+            .len();
+        if current < self.len {
+            preallocate::set_file_len(&f, self.len, self.preallocation)
+                .with_context(|| format!("error setting length of {filename:?}"))?;
+            debug!("extended {filename:?} from {current} to {} bytes", self.len);
+            self.remap(&f, false);
+        }
         Ok(())
     }
 
-    pub fn take(&self) -> anyhow::Result<File> {
-        let mut f = self.file.lock();
-        let dummy = dummy_file()?;
-        let f = std::mem::replace(&mut *f, dummy);
-        Ok(f)
+    // Close the file, e.g. before deleting it. It's opened again if used.
+    pub fn close(&self) {
+        let mut g = self.slot.file.write();
+        self.unmap();
+        self.slot.close_locked(&mut g);
     }
 
+    // A copy for the next state of the torrent, e.g. when pausing. It uses the same file handle.
     pub fn take_clone(&self) -> anyhow::Result<Self> {
-        let f = self.take()?;
+        let _g = self.slot.file.write();
+        self.unmap();
+        self.slot.direct.lock().take();
         Ok(Self {
-            file: Mutex::new(f),
-            filename: self.filename.clone(),
+            slot: self.slot.clone(),
+            pool: self.pool.clone(),
+            direct_io: self.direct_io,
+            use_mmap: self.use_mmap,
+            preallocation: self.preallocation,
+            filename: RwLock::new(self.filename()),
+            read_only: AtomicBool::new(self.read_only.load(Ordering::Relaxed)),
+            part: AtomicBool::new(self.is_part()),
             offset_in_torrent: self.offset_in_torrent,
             have: AtomicU64::new(self.have.load(Ordering::Relaxed)),
             len: self.len,
@@ -85,6 +366,45 @@ impl OpenedFile {
         })
     }
 
+    pub fn filename(&self) -> PathBuf {
+        self.filename.read().clone()
+    }
+
+    // Flush written data to disk, waiting until it's done.
+    pub fn sync_all(&self) -> anyhow::Result<()> {
+        let f = self.lock_file()?;
+        if let Some(m) = self.mmap().as_ref() {
+            m.flush()?;
+        }
+        f.sync_all()
+            .with_context(|| format!("error syncing {:?}", self.filename()))
+    }
+
+    // Move the file to a new path, and reopen it there the same way it was open before. I/O on
+    // the file waits until the move is done.
+    pub fn move_to(&self, new_filename: &Path) -> anyhow::Result<()> {
+        let mut g = self.slot.file.write();
+        let old_filename = self.filename();
+        if old_filename == new_filename {
+            return Ok(());
+        }
+        let read_only = self.read_only.load(Ordering::Relaxed);
+
+        // Some platforms (Windows) can't move files that are open.
+        self.unmap();
+        self.slot.close_locked(&mut g);
+
+        let moved = move_file(&old_filename, new_filename);
+        if moved.is_ok() {
+            *self.filename.write() = new_filename.to_owned();
+        }
+        // Reopen the old file if the move failed, so that the torrent can keep going.
+        self.reopen_locked(&mut g, read_only)?;
+        moved.with_context(|| format!("error moving {old_filename:?} to {new_filename:?}"))?;
+        debug!("moved {old_filename:?} to {new_filename:?}");
+        Ok(())
+    }
+
     pub fn piece_range_usize(&self) -> std::ops::Range<usize> {
         self.piece_range.start as usize..self.piece_range.end as usize
     }
@@ -94,4 +414,171 @@ impl OpenedFile {
         self.have.fetch_add(size, Ordering::Relaxed);
         size
     }
+
+    pub fn update_have_on_piece_lost(&self, piece_id: u32, lengths: &Lengths) {
+        let size = lengths.size_of_piece_in_file(piece_id, self.offset_in_torrent, self.len);
+        self.have.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+// Rename the file, or copy and remove it if it's on another filesystem.
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("error creating directory {parent:?}"))?;
+    }
+    if to.exists() {
+        anyhow::bail!("{to:?} already exists");
+    }
+    if let Err(e) = std::fs::rename(from, to) {
+        debug!("error renaming {from:?} to {to:?}, will copy it instead: {e:#}");
+        std::fs::copy(from, to).with_context(|| format!("error copying {from:?} to {to:?}"))?;
+        std::fs::remove_file(from).with_context(|| format!("error removing {from:?}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{part_filename, FilePool, OpenedFile};
+    use crate::file_io::read_exact_at;
+    use crate::preallocate::Preallocation;
+
+    #[test]
+    fn test_move_to() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let old = dir.path().join("a");
+        let new = dir.path().join("b").join("c");
+        std::fs::write(&old, b"hello").unwrap();
+
+        let file = OpenedFile::new(
+            std::fs::File::open(&old).unwrap(),
+            old.clone(),
+            0,
+            5,
+            0,
+            0..1,
+            false,
+            false,
+            Preallocation::Sparse,
+            Default::default(),
+        );
+        file.reopen(true).unwrap();
+        file.move_to(&new).unwrap();
+        assert!(!old.exists());
+        assert_eq!(file.filename(), new);
+        assert_eq!(std::fs::read(&new).unwrap(), b"hello");
+
+        // The target exists, the file stays where it was.
+        std::fs::write(&old, b"other").unwrap();
+        assert!(file.move_to(&old).is_err());
+        assert_eq!(file.filename(), new);
+        assert_eq!(std::fs::read(&new).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_finalize() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let filename = dir.path().join("a.mkv");
+        let part = part_filename(&filename);
+        assert_eq!(part, dir.path().join("a.mkv.part"));
+        std::fs::write(&part, b"hello").unwrap();
+
+        let file = OpenedFile::new(
+            std::fs::File::open(&part).unwrap(),
+            part.clone(),
+            5,
+            5,
+            0,
+            0..1,
+            false,
+            false,
+            Preallocation::Sparse,
+            Default::default(),
+        )
+        .with_part(true);
+        file.reopen(true).unwrap();
+        file.finalize().unwrap();
+        assert!(!file.is_part());
+        assert!(!part.exists());
+        assert_eq!(file.filename(), filename);
+        assert_eq!(std::fs::read(&filename).unwrap(), b"hello");
+
+        // Finalizing again does nothing.
+        file.finalize().unwrap();
+        assert_eq!(file.filename(), filename);
+    }
+
+    #[test]
+    fn test_file_pool() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = std::sync::Arc::new(FilePool::new(2));
+        let files = (0..3u8)
+            .map(|i| {
+                let filename = dir.path().join(i.to_string());
+                std::fs::write(&filename, [i; 4]).unwrap();
+                let file = OpenedFile::new(
+                    std::fs::File::open(&filename).unwrap(),
+                    filename,
+                    0,
+                    4,
+                    0,
+                    0..1,
+                    false,
+                    false,
+                    Preallocation::Sparse,
+                    pool.clone(),
+                );
+                file.reopen(true).unwrap();
+                file
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pool.open_files(), 2);
+
+        // The least recently used file is closed, and reopened when it's needed.
+        for (i, file) in files.iter().enumerate().rev() {
+            let f = file.lock_file().unwrap();
+            let mut buf = [0u8; 4];
+            read_exact_at(&f, &mut buf, 0).unwrap();
+            assert_eq!(buf, [i as u8; 4]);
+            drop(f);
+            assert_eq!(pool.open_files(), 2);
+        }
+        assert!(files[2].slot.file.read().is_none());
+        assert!(files[0].slot.file.read().is_some());
+    }
+
+    #[test]
+    fn test_file_pool_unmaps_closed_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pool = std::sync::Arc::new(FilePool::new(1));
+        let files = (0..2u8)
+            .map(|i| {
+                let filename = dir.path().join(i.to_string());
+                std::fs::write(&filename, [i; 4]).unwrap();
+                let file = OpenedFile::new(
+                    std::fs::File::open(&filename).unwrap(),
+                    filename,
+                    4,
+                    4,
+                    0,
+                    0..1,
+                    false,
+                    true,
+                    Preallocation::Sparse,
+                    pool.clone(),
+                );
+                file.reopen(true).unwrap();
+                file
+            })
+            .collect::<Vec<_>>();
+        assert!(files[0].slot.file.read().is_none());
+        assert!(files[0].mmap().is_none());
+        assert!(files[1].mmap().is_some());
+
+        // Mapped again along with the file.
+        let _f = files[0].lock_file().unwrap();
+        assert!(files[0].mmap().is_some());
+        assert!(files[1].mmap().is_none());
+    }
 }
@@ -1,27 +1,51 @@
 use std::{
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
 use buffers::{ByteBuf, ByteBufOwned};
 use clone_to_owned::CloneToOwned;
-use librqbit_core::{hash_id::Id20, lengths::ChunkInfo, peer_id::try_decode_peer_id};
+use librqbit_core::{
+    hash_id::Id20, lengths::ChunkInfo, peer_id::try_decode_peer_id, socket_binding::SocketBinding,
+};
+use librqbit_utp::UtpSocket;
 use parking_lot::RwLock;
 use peer_binary_protocol::{
-    extended::{handshake::ExtendedHandshake, ExtendedMessage},
-    serialize_piece_preamble, Handshake, Message, MessageOwned, PIECE_MESSAGE_DEFAULT_LEN,
+    extended::{
+        handshake::{ExtendedHandshake, YourIP},
+        ExtendedMessage, PeerExtendedMessageIds,
+    },
+    serialize_piece_preamble, Handshake, Message, MessageOwned, Request, PIECE_MESSAGE_DEFAULT_LEN,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use tokio::time::timeout;
-use tracing::trace;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc::Sender, Semaphore},
+    time::timeout,
+};
+use tracing::{debug, trace};
 
-use crate::{read_buf::ReadBuf, spawn_utils::BlockingSpawner};
+use crate::{
+    extensions::{ExtensionPeer, ExtensionRegistry},
+    rate_limit::{PeerRateLimits, RateLimit},
+    read_buf::ReadBuf,
+    spawn_utils::BlockingSpawner,
+};
 
 pub trait PeerConnectionHandler {
     fn on_connected(&self, _connection_time: Duration) {}
     fn get_have_bytes(&self) -> u64;
+    // Whether we only upload, i.e. have everything we want. Advertised in the extended handshake.
+    fn is_upload_only(&self) -> bool {
+        false
+    }
+    // The port of our DHT, sent in a PORT message to peers that support the DHT (BEP 5).
+    fn dht_port(&self) -> Option<u16> {
+        None
+    }
     fn serialize_bitfield_message_to_buf(&self, buf: &mut Vec<u8>) -> anyhow::Result<usize>;
     fn on_handshake<B>(&self, handshake: Handshake<B>) -> anyhow::Result<()>;
     fn on_extended_handshake(
@@ -31,11 +55,21 @@ pub trait PeerConnectionHandler {
     fn on_received_message(&self, msg: Message<ByteBuf<'_>>) -> anyhow::Result<()>;
     fn on_uploaded_bytes(&self, bytes: u32);
     fn read_chunk(&self, chunk: &ChunkInfo, buf: &mut [u8]) -> anyhow::Result<()>;
+    // A ReadChunkRequest was taken off the write queue, whether it's served or not.
+    fn on_upload_request_dequeued(&self) {}
 }
 
+// How many chunk requests of a peer are queued for upload at most, advertised as our reqq.
+pub(crate) const PEER_UPLOAD_QUEUE_DEPTH: usize = 250;
+// The writer queue also holds other messages, e.g. haves, requests and cancels. A peer that
+// lets it fill up isn't reading what we send.
+pub(crate) const PEER_WRITE_QUEUE_LEN: usize = PEER_UPLOAD_QUEUE_DEPTH * 2;
+
 #[derive(Debug)]
 pub enum WriterRequest {
     Message(MessageOwned),
+    // Have messages for these pieces, sent in one write.
+    Haves(Vec<u32>),
     ReadChunkRequest(ChunkInfo),
     Disconnect,
 }
@@ -51,6 +85,157 @@ pub struct PeerConnectionOptions {
 
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
     pub keep_alive_interval: Option<Duration>,
+
+    /// Drop peers that send nothing, not even a keep-alive, for this long. Defaults to 3 minutes.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Drop peers that have pieces we need, but don't send us any of them for this long, to
+    /// make room for other peers. Defaults to 5 minutes.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    pub inactivity_timeout: Option<Duration>,
+
+    /// Which transport to dial peers with. Defaults to uTP with TCP fallback if the
+    /// session has uTP enabled, TCP otherwise.
+    pub transport: Option<PeerTransport>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerTransport {
+    Tcp,
+    Utp,
+    UtpThenTcp,
+}
+
+pub(crate) trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
+
+// A TCP or uTP connection to a peer.
+pub(crate) type BoxPeerStream = Box<dyn AsyncReadWrite>;
+
+/// How to bind outgoing peer sockets, e.g. on multi-homed hosts or to force a torrent's traffic
+/// through a VPN.
+///
+/// The interface and fwmark are only supported on Linux. Connecting fails if binding fails, so
+/// that traffic never silently leaks through the default route.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSocketBinding {
+    /// Network interface name to bind to (SO_BINDTODEVICE), e.g. "wg0".
+    pub interface: Option<String>,
+    /// Firewall mark to set on the socket (SO_MARK), to be matched by policy routing rules.
+    pub fwmark: Option<u32>,
+    /// Local IP address to bind to.
+    #[serde(default)]
+    pub local_addr: Option<IpAddr>,
+}
+
+impl PeerSocketBinding {
+    pub fn is_empty(&self) -> bool {
+        self.interface.is_none() && self.fwmark.is_none() && self.local_addr.is_none()
+    }
+
+    // The binding for tracker and DHT sockets. The fwmark only applies to peer connections.
+    pub(crate) fn socket_binding(&self) -> SocketBinding {
+        SocketBinding {
+            local_addr: self.local_addr,
+            interface: self.interface.clone(),
+        }
+    }
+
+    fn apply(&self, socket: &tokio::net::TcpSocket) -> anyhow::Result<()> {
+        if let Some(ip) = self.local_addr {
+            socket
+                .bind(SocketAddr::new(ip, 0))
+                .with_context(|| format!("error binding socket to {ip}"))?;
+        }
+        self.apply_os_specific(socket)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_os_specific(&self, socket: &tokio::net::TcpSocket) -> anyhow::Result<()> {
+        if let Some(interface) = &self.interface {
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .with_context(|| format!("error binding socket to interface {interface:?}"))?;
+        }
+        if let Some(mark) = self.fwmark {
+            use std::os::fd::AsRawFd;
+            // SAFETY: the fd is valid for the lifetime of the socket, and the option value
+            // is a properly sized integer.
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_MARK,
+                    &mark as *const u32 as *const libc::c_void,
+                    std::mem::size_of::<u32>() as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("error setting SO_MARK={mark} on socket"));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_os_specific(&self, _socket: &tokio::net::TcpSocket) -> anyhow::Result<()> {
+        if self.interface.is_some() || self.fwmark.is_some() {
+            bail!("binding peer sockets to an interface or fwmark is only supported on Linux")
+        }
+        Ok(())
+    }
+}
+
+async fn connect(
+    addr: SocketAddr,
+    binding: Option<&PeerSocketBinding>,
+) -> anyhow::Result<tokio::net::TcpStream> {
+    let binding = match binding {
+        Some(b) if !b.is_empty() => b,
+        _ => return Ok(tokio::net::TcpStream::connect(addr).await?),
+    };
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
+    }
+    .context("error creating socket")?;
+    binding.apply(&socket)?;
+    Ok(socket.connect(addr).await?)
+}
+
+// uTP and the TCP fallback share the timeout, so that a dead peer doesn't take twice as long:
+// with a fallback uTP gets at most half of it, and TCP whatever is left.
+async fn connect_with_transport(
+    addr: SocketAddr,
+    transport: PeerTransport,
+    utp_socket: Option<&UtpSocket>,
+    binding: Option<&PeerSocketBinding>,
+    connect_timeout: Duration,
+) -> anyhow::Result<BoxPeerStream> {
+    let deadline = Instant::now() + connect_timeout;
+    if let Some(utp_socket) = utp_socket {
+        let utp_timeout = match transport {
+            PeerTransport::Utp => connect_timeout,
+            _ => connect_timeout / 2,
+        };
+        match with_timeout(utp_timeout, utp_socket.connect(addr)).await {
+            Ok(stream) => {
+                trace!("connected over uTP");
+                return Ok(Box::new(stream));
+            }
+            Err(e) if transport == PeerTransport::Utp => {
+                return Err(e.context("error connecting over uTP"))
+            }
+            Err(e) => debug!("error connecting over uTP, falling back to TCP: {e:#}"),
+        }
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let stream = with_timeout(remaining, connect(addr, binding)).await?;
+    Ok(Box::new(stream))
 }
 
 pub(crate) struct PeerConnection<H> {
@@ -59,6 +244,13 @@ pub(crate) struct PeerConnection<H> {
     info_hash: Id20,
     peer_id: Id20,
     options: PeerConnectionOptions,
+    socket_binding: Option<PeerSocketBinding>,
+    utp_socket: Option<UtpSocket>,
+    upload_limits: Vec<Arc<RateLimit>>,
+    peer_limits: Option<Arc<PeerRateLimits>>,
+    half_open_limit: Option<Arc<Semaphore>>,
+    // Custom extensions, and the channel their handlers send messages to the peer through.
+    extensions: Option<(Arc<ExtensionRegistry>, Sender<WriterRequest>)>,
     spawner: BlockingSpawner,
 }
 
@@ -91,17 +283,121 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             peer_id,
             spawner,
             options: options.unwrap_or_default(),
+            socket_binding: None,
+            utp_socket: None,
+            upload_limits: Vec::new(),
+            peer_limits: None,
+            half_open_limit: None,
+            extensions: None,
         }
     }
 
+    pub fn with_socket_binding(mut self, binding: Option<PeerSocketBinding>) -> Self {
+        self.socket_binding = binding;
+        self
+    }
+
+    pub fn with_utp_socket(mut self, utp_socket: Option<UtpSocket>) -> Self {
+        self.utp_socket = utp_socket;
+        self
+    }
+
+    // Uploaded chunks have to pass through all of these limits.
+    pub fn with_upload_limits(mut self, upload_limits: Vec<Arc<RateLimit>>) -> Self {
+        self.upload_limits = upload_limits;
+        self
+    }
+
+    // Connection attempts in progress need a permit from it.
+    pub fn with_half_open_limit(mut self, half_open_limit: Option<Arc<Semaphore>>) -> Self {
+        self.half_open_limit = half_open_limit;
+        self
+    }
+
+    pub fn with_peer_limits(mut self, peer_limits: Arc<PeerRateLimits>) -> Self {
+        self.peer_limits = Some(peer_limits);
+        self
+    }
+
+    pub fn with_extensions(
+        mut self,
+        extensions: Option<Arc<ExtensionRegistry>>,
+        tx: Sender<WriterRequest>,
+    ) -> Self {
+        self.extensions = extensions.map(|e| (e, tx));
+        self
+    }
+
+    fn on_extensions_handshake<B: AsRef<[u8]> + Eq + std::hash::Hash>(
+        &self,
+        h: &ExtendedHandshake<B>,
+    ) -> anyhow::Result<()> {
+        let (registry, tx) = match &self.extensions {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        for (_, handler) in registry.iter() {
+            if let Some(msg_id) = h.get_msgid(handler.name().as_bytes()) {
+                let peer = ExtensionPeer::new(self.addr, self.info_hash, Some(msg_id), tx.clone());
+                handler
+                    .on_peer_handshake(&peer)
+                    .with_context(|| format!("error in {} extension handshake", handler.name()))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if the message isn't one of our custom extensions.
+    fn on_extension_message(
+        &self,
+        msg_id: u8,
+        payload: &[u8],
+        peer_handshake: Option<&ExtendedHandshake<ByteBufOwned>>,
+    ) -> anyhow::Result<bool> {
+        let (handler, tx) = match &self.extensions {
+            Some((registry, tx)) => match registry.get(msg_id) {
+                Some(handler) => (handler, tx),
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        let peer_msg_id = peer_handshake.and_then(|h| h.get_msgid(handler.name().as_bytes()));
+        let peer = ExtensionPeer::new(self.addr, self.info_hash, peer_msg_id, tx.clone());
+        handler
+            .on_message(&peer, payload)
+            .with_context(|| format!("error handling {} message", handler.name()))?;
+        Ok(true)
+    }
+
+    async fn connect(&self, connect_timeout: Duration) -> anyhow::Result<BoxPeerStream> {
+        let binding = self.socket_binding.as_ref().filter(|b| !b.is_empty());
+        let transport = self.options.transport.unwrap_or(match self.utp_socket {
+            Some(_) => PeerTransport::UtpThenTcp,
+            None => PeerTransport::Tcp,
+        });
+        let utp_socket = match (transport, &self.utp_socket) {
+            (PeerTransport::Tcp, _) => None,
+            // The uTP socket is shared by the session and can't be bound per torrent.
+            // Don't let the traffic leak through the default route.
+            (PeerTransport::UtpThenTcp, _) if binding.is_some() => None,
+            (PeerTransport::Utp, _) if binding.is_some() => {
+                bail!("uTP can't be used together with socket binding")
+            }
+            (PeerTransport::UtpThenTcp, None) => None,
+            (PeerTransport::Utp, None) => bail!("uTP is not enabled in the session"),
+            (_, Some(s)) => Some(s),
+        };
+        connect_with_transport(self.addr, transport, utp_socket, binding, connect_timeout).await
+    }
+
     // By the time this is called:
     // read_buf should start with valuable data. The handshake should be removed from it.
     pub async fn manage_peer_incoming(
         &self,
-        outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
+        outgoing_chan: tokio::sync::mpsc::Receiver<WriterRequest>,
         read_buf: ReadBuf,
         handshake: Handshake<ByteBufOwned>,
-        mut conn: tokio::net::TcpStream,
+        mut conn: BoxPeerStream,
     ) -> anyhow::Result<()> {
         use tokio::io::AsyncWriteExt;
 
@@ -124,19 +420,23 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         );
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
-        let handshake = Handshake::new(self.info_hash, self.peer_id);
-        handshake.serialize(&mut write_buf);
+        let my_handshake = Handshake::new(self.info_hash, self.peer_id);
+        my_handshake.serialize(&mut write_buf);
         with_timeout(rwtimeout, conn.write_all(&write_buf))
             .await
             .context("error writing handshake")?;
         write_buf.clear();
 
         let h_supports_extended = handshake.supports_extended();
+        let h_supports_fast = handshake.supports_fast();
+        let h_supports_dht = handshake.supports_dht();
 
         self.handler.on_handshake(handshake)?;
 
         self.manage_peer(
             h_supports_extended,
+            h_supports_fast,
+            h_supports_dht,
             read_buf,
             write_buf,
             conn,
@@ -147,7 +447,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
 
     pub async fn manage_peer_outgoing(
         &self,
-        outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
+        outgoing_chan: tokio::sync::mpsc::Receiver<WriterRequest>,
     ) -> anyhow::Result<()> {
         use tokio::io::AsyncWriteExt;
 
@@ -161,10 +461,16 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .connect_timeout
             .unwrap_or_else(|| Duration::from_secs(10));
 
+        let half_open_permit = match self.half_open_limit.as_ref() {
+            Some(sem) => Some(sem.acquire().await?),
+            None => None,
+        };
         let now = Instant::now();
-        let mut conn = with_timeout(connect_timeout, tokio::net::TcpStream::connect(self.addr))
+        let mut conn = self
+            .connect(connect_timeout)
             .await
             .context("error connecting")?;
+        drop(half_open_permit);
         self.handler.on_connected(now.elapsed());
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
@@ -181,6 +487,8 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .await
             .context("error reading handshake")?;
         let h_supports_extended = h.supports_extended();
+        let h_supports_fast = h.supports_fast();
+        let h_supports_dht = h.supports_dht();
         trace!(
             "connected: id={:?}",
             try_decode_peer_id(Id20::new(h.peer_id))
@@ -197,6 +505,8 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
 
         self.manage_peer(
             h_supports_extended,
+            h_supports_fast,
+            h_supports_dht,
             read_buf,
             write_buf,
             conn,
@@ -205,13 +515,16 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn manage_peer(
         &self,
         handshake_supports_extended: bool,
+        handshake_supports_fast: bool,
+        handshake_supports_dht: bool,
         mut read_buf: ReadBuf,
         mut write_buf: Vec<u8>,
-        mut conn: tokio::net::TcpStream,
-        mut outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
+        mut conn: BoxPeerStream,
+        mut outgoing_chan: tokio::sync::mpsc::Receiver<WriterRequest>,
     ) -> anyhow::Result<()> {
         use tokio::io::AsyncWriteExt;
 
@@ -220,15 +533,35 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .read_write_timeout
             .unwrap_or_else(|| Duration::from_secs(10));
 
+        // Longer than the keep-alive interval, so that quiet but healthy peers are kept.
+        let idle_timeout = self
+            .options
+            .idle_timeout
+            .unwrap_or_else(|| Duration::from_secs(180));
+
         let extended_handshake: RwLock<Option<ExtendedHandshake<ByteBufOwned>>> = RwLock::new(None);
         let extended_handshake_ref = &extended_handshake;
         let supports_extended = handshake_supports_extended;
 
         if supports_extended {
-            let my_extended =
-                Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
+            let mut my_extended = ExtendedHandshake {
+                yourip: Some(YourIP(self.addr.ip())),
+                upload_only: self.handler.is_upload_only().then_some(1),
+                reqq: Some(PEER_UPLOAD_QUEUE_DEPTH as u32),
+                ..ExtendedHandshake::new()
+            };
+            if let Some((registry, _)) = &self.extensions {
+                for (msg_id, handler) in registry.iter() {
+                    my_extended
+                        .m
+                        .insert(ByteBuf(handler.name().as_bytes()), msg_id);
+                }
+            }
+            let my_extended = Message::Extended(ExtendedMessage::Handshake(my_extended));
             trace!("sending extended handshake: {:?}", &my_extended);
-            my_extended.serialize(&mut write_buf, &|| None).unwrap();
+            my_extended
+                .serialize(&mut write_buf, &PeerExtendedMessageIds::default)
+                .unwrap();
             with_timeout(rwtimeout, conn.write_all(&write_buf))
                 .await
                 .context("error writing extended handshake")?;
@@ -251,8 +584,31 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                     .await
                     .context("error writing bitfield to peer")?;
                 trace!("sent bitfield");
+            } else if handshake_supports_fast {
+                // With the Fast Extension (BEP 6) a peer must send one of bitfield, have all or
+                // have none.
+                let len = MessageOwned::HaveNone
+                    .serialize(&mut write_buf, &PeerExtendedMessageIds::default)
+                    .unwrap();
+                with_timeout(rwtimeout, write_half.write_all(&write_buf[..len]))
+                    .await
+                    .context("error writing have none to peer")?;
+                trace!("sent have none");
+            }
+
+            if let Some(port) = self.handler.dht_port().filter(|_| handshake_supports_dht) {
+                let len = MessageOwned::Port(port)
+                    .serialize(&mut write_buf, &PeerExtendedMessageIds::default)
+                    .unwrap();
+                with_timeout(rwtimeout, write_half.write_all(&write_buf[..len]))
+                    .await
+                    .context("error writing port to peer")?;
+                trace!(port, "sent DHT port");
             }
 
+            // Chunk requests queued before we choked the peer aren't served anymore.
+            let mut choking = true;
+
             loop {
                 let req = match timeout(keep_alive_interval, outgoing_chan.recv()).await {
                     Ok(Some(msg)) => msg,
@@ -262,6 +618,35 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                     Err(_) => WriterRequest::Message(MessageOwned::KeepAlive),
                 };
 
+                if let WriterRequest::ReadChunkRequest(_) = &req {
+                    self.handler.on_upload_request_dequeued();
+                }
+
+                let req = match req {
+                    WriterRequest::Message(MessageOwned::Choke) => {
+                        choking = true;
+                        req
+                    }
+                    WriterRequest::Message(MessageOwned::Unchoke) => {
+                        choking = false;
+                        req
+                    }
+                    WriterRequest::ReadChunkRequest(chunk) if choking => {
+                        if !handshake_supports_fast {
+                            trace!("peer choked, dropping request for {:?}", chunk);
+                            continue;
+                        }
+                        // BEP 6: with the fast extension, choking doesn't discard the requests
+                        // implicitly, each of them has to be rejected.
+                        WriterRequest::Message(MessageOwned::RejectRequest(Request::new(
+                            chunk.piece_index.get(),
+                            chunk.offset,
+                            chunk.size,
+                        )))
+                    }
+                    req => req,
+                };
+
                 let mut uploaded_add = None;
 
                 let len = match &req {
@@ -269,8 +654,19 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                         extended_handshake_ref
                             .read()
                             .as_ref()
-                            .and_then(|e| e.ut_metadata())
+                            .map(|e| e.peer_extended_messages())
+                            .unwrap_or_default()
                     })?,
+                    WriterRequest::Haves(pieces) => {
+                        write_buf.clear();
+                        let mut msg_buf = Vec::new();
+                        for piece in pieces {
+                            let len = MessageOwned::Have(*piece)
+                                .serialize(&mut msg_buf, &PeerExtendedMessageIds::default)?;
+                            write_buf.extend_from_slice(&msg_buf[..len]);
+                        }
+                        write_buf.len()
+                    }
                     WriterRequest::ReadChunkRequest(chunk) => {
                         #[allow(unused_mut)]
                         let mut skip_reading_for_e2e_tests = false;
@@ -298,6 +694,11 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                             }
                         }
 
+                        let peer_limit = self.peer_limits.as_ref().and_then(|l| l.upload());
+                        for limit in self.upload_limits.iter().chain(peer_limit.as_ref()) {
+                            limit.acquire(chunk.size as usize).await;
+                        }
+
                         // this whole section is an optimization
                         write_buf.resize(PIECE_MESSAGE_DEFAULT_LEN, 0);
                         let preamble_len = serialize_piece_preamble(chunk, &mut write_buf);
@@ -340,14 +741,31 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
 
         let reader = async move {
             loop {
+                let mut piece_bytes = 0;
                 read_buf
-                    .read_message(&mut read_half, rwtimeout, |message| {
+                    .read_message(&mut read_half, rwtimeout, idle_timeout, |message| {
                         trace!("received: {:?}", &message);
 
+                        if let Message::Piece(piece) = &message {
+                            piece_bytes = piece.block.as_ref().len();
+                        }
+
                         if let Message::Extended(ExtendedMessage::Handshake(h)) = &message {
                             *extended_handshake_ref.write() = Some(h.clone_to_owned());
                             self.handler.on_extended_handshake(h)?;
                             trace!("remembered extended handshake for future serializing");
+                            self.on_extensions_handshake(h)?;
+                        } else if let Message::Extended(ExtendedMessage::Raw(msg_id, payload)) =
+                            &message
+                        {
+                            let handled = self.on_extension_message(
+                                *msg_id,
+                                payload.as_ref(),
+                                extended_handshake_ref.read().as_ref(),
+                            )?;
+                            if !handled {
+                                trace!(msg_id, "received message of unknown extension, ignoring");
+                            }
                         } else {
                             self.handler
                                 .on_received_message(message)
@@ -357,6 +775,13 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                     })
                     .await
                     .context("error reading message")?;
+
+                // Delaying the next read is enough to slow down the peer.
+                if piece_bytes > 0 {
+                    if let Some(limit) = self.peer_limits.as_ref().and_then(|l| l.download()) {
+                        limit.acquire(piece_bytes).await;
+                    }
+                }
             }
 
             // For type inference.
@@ -376,3 +801,30 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use librqbit_utp::UtpSocket;
+
+    use super::{connect_with_transport, PeerTransport};
+
+    #[tokio::test]
+    async fn test_utp_then_tcp_shares_the_timeout() {
+        // Swallows uTP SYNs, and there's no TCP listener on the same port.
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let utp = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let timeout = Duration::from_secs(2);
+        let start = Instant::now();
+        let res =
+            connect_with_transport(addr, PeerTransport::UtpThenTcp, Some(&utp), None, timeout)
+                .await;
+        assert!(res.is_err());
+        assert!(start.elapsed() < timeout, "took {:?}", start.elapsed());
+    }
+}
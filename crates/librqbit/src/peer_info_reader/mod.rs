@@ -14,12 +14,13 @@ use peer_binary_protocol::{
     Handshake, Message,
 };
 use sha1w::{ISha1, Sha1};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
 use tracing::trace;
 
 use crate::{
     peer_connection::{
         PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
+        PEER_WRITE_QUEUE_LEN,
     },
     spawn_utils::BlockingSpawner,
 };
@@ -33,7 +34,7 @@ pub(crate) async fn read_metainfo_from_peer(
 ) -> anyhow::Result<TorrentMetaV1Info<ByteBufOwned>> {
     let (result_tx, result_rx) =
         tokio::sync::oneshot::channel::<anyhow::Result<TorrentMetaV1Info<ByteBufOwned>>>();
-    let (writer_tx, writer_rx) = tokio::sync::mpsc::unbounded_channel::<WriterRequest>();
+    let (writer_tx, writer_rx) = tokio::sync::mpsc::channel::<WriterRequest>(PEER_WRITE_QUEUE_LEN);
     let handler = Handler {
         addr,
         info_hash,
@@ -130,7 +131,7 @@ impl HandlerLocked {
 struct Handler {
     addr: SocketAddr,
     info_hash: Id20,
-    writer_tx: UnboundedSender<WriterRequest>,
+    writer_tx: Sender<WriterRequest>,
     result_tx: Mutex<
         Option<tokio::sync::oneshot::Sender<anyhow::Result<TorrentMetaV1Info<ByteBufOwned>>>>,
     >,
@@ -204,9 +205,9 @@ impl PeerConnectionHandler for Handler {
         }
 
         self.writer_tx
-            .send(WriterRequest::Message(Message::Unchoke))?;
+            .try_send(WriterRequest::Message(Message::Unchoke))?;
         self.writer_tx
-            .send(WriterRequest::Message(Message::Interested))?;
+            .try_send(WriterRequest::Message(Message::Interested))?;
 
         let inner = HandlerLocked::new(metadata_size)?;
         let total_pieces = inner.total_pieces;
@@ -215,7 +216,7 @@ impl PeerConnectionHandler for Handler {
 
         for i in 0..total_pieces {
             self.writer_tx
-                .send(WriterRequest::Message(Message::Extended(
+                .try_send(WriterRequest::Message(Message::Extended(
                     ExtendedMessage::UtMetadata(UtMetadata::Request(i as u32)),
                 )))?;
         }
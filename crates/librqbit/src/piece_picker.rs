@@ -0,0 +1,338 @@
+// Strategies for choosing which of the needed pieces to download next.
+//
+// The chunk tracker knows which pieces are queued (needed and not being downloaded), and asks
+// the torrent's PiecePicker to order them, one file priority at a time from the highest. Peers
+// only take the pieces they have from that order.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use librqbit_core::compact_bitfield::CompactBitfield;
+use rand::seq::SliceRandom;
+
+use crate::type_aliases::BF;
+
+pub const DEFAULT_STREAMING_LOOKAHEAD_PIECES: usize = 16;
+
+// How many of the equally needed pieces a peer has to choose the rarest one from.
+const AVAILABILITY_PICK_WINDOW: usize = 8;
+
+// How many live peers have each piece.
+#[derive(Debug, Default)]
+pub(crate) struct PieceAvailability {
+    counts: Vec<AtomicU32>,
+}
+
+impl PieceAvailability {
+    pub fn new(total_pieces: u32) -> Self {
+        Self {
+            counts: (0..total_pieces).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> u32 {
+        self.counts
+            .get(index)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+
+    pub fn on_have(&self, index: u32) {
+        if let Some(c) = self.counts.get(index as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn on_donthave(&self, index: u32) {
+        if let Some(c) = self.counts.get(index as usize) {
+            let _ = c.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+        }
+    }
+
+    pub fn add_bitfield(&self, bitfield: &CompactBitfield) {
+        for index in bitfield.iter_ones() {
+            self.on_have(index);
+        }
+    }
+
+    pub fn remove_bitfield(&self, bitfield: &CompactBitfield) {
+        for index in bitfield.iter_ones() {
+            self.on_donthave(index);
+        }
+    }
+
+    // Copies of the rarest piece, and the "distributed copies": the number of full copies
+    // available, plus the fraction of pieces that have more copies than the rarest one. The
+    // pieces we have count as one more copy, so the torrent can complete if it's at least 1.
+    pub fn distributed_copies(&self, have: &BF) -> (u32, f64) {
+        let copies =
+            |idx: usize| self.get(idx) + have.get(idx).map(|b| *b as u32).unwrap_or_default();
+        let min = match (0..self.counts.len()).map(copies).min() {
+            Some(min) => min,
+            None => return (0, 0.),
+        };
+        let above_min = (0..self.counts.len())
+            .filter(|idx| copies(*idx) > min)
+            .count();
+        (
+            min,
+            min as f64 + above_min as f64 / self.counts.len() as f64,
+        )
+    }
+}
+
+// The first of the candidates, in picking order, that the fewest peers have. A peer that has
+// rare pieces is then used for those, and not for the ones that everyone else can serve too.
+pub(crate) fn pick_rarest(
+    candidates: impl IntoIterator<Item = usize>,
+    availability: &PieceAvailability,
+) -> Option<usize> {
+    candidates
+        .into_iter()
+        .take(AVAILABILITY_PICK_WINDOW)
+        .min_by_key(|id| availability.get(*id))
+}
+
+/// The state of the torrent a [PiecePicker] chooses from.
+#[derive(Clone, Copy)]
+pub struct PickerContext<'a> {
+    total_pieces: usize,
+    queued: &'a BF,
+    have: &'a BF,
+    selected: &'a BF,
+    availability: Option<&'a PieceAvailability>,
+}
+
+impl<'a> PickerContext<'a> {
+    pub(crate) fn new(
+        total_pieces: usize,
+        queued: &'a BF,
+        have: &'a BF,
+        selected: &'a BF,
+        availability: Option<&'a PieceAvailability>,
+    ) -> Self {
+        Self {
+            total_pieces,
+            queued,
+            have,
+            selected,
+            availability,
+        }
+    }
+
+    pub fn total_pieces(&self) -> usize {
+        self.total_pieces
+    }
+
+    /// Pieces that are needed and not being downloaded, in index order.
+    pub fn iter_queued(&self) -> impl Iterator<Item = usize> + 'a {
+        let total = self.total_pieces;
+        self.queued.iter_ones().take_while(move |id| *id < total)
+    }
+
+    pub fn is_queued(&self, index: usize) -> bool {
+        self.queued.get(index).map(|b| *b).unwrap_or(false)
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.have.get(index).map(|b| *b).unwrap_or(false)
+    }
+
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.selected.get(index).map(|b| *b).unwrap_or(false)
+    }
+
+    pub fn have_count(&self) -> usize {
+        self.have.count_ones()
+    }
+
+    /// How many connected peers have the piece. 0 if unknown.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.map(|a| a.get(index)).unwrap_or_default()
+    }
+}
+
+/// Decides in which order the queued pieces of a torrent are downloaded.
+pub trait PiecePicker: Send + Sync {
+    /// Queued pieces, the ones to download first go first. Pieces that aren't queued are
+    /// ignored.
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a>;
+}
+
+/// The last piece first, as players and archivers often need it early, then in order.
+#[derive(Default)]
+pub struct LastPieceFirstPicker;
+
+impl PiecePicker for LastPieceFirstPicker {
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+        let last = (0..ctx.total_pieces())
+            .rev()
+            .find(|id| ctx.is_selected(*id))
+            .filter(|id| ctx.is_queued(*id));
+        Box::new(
+            last.into_iter()
+                .chain(ctx.iter_queued().filter(move |id| Some(*id) != last)),
+        )
+    }
+}
+
+/// Strictly in order.
+#[derive(Default)]
+pub struct SequentialPicker;
+
+impl PiecePicker for SequentialPicker {
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+        Box::new(ctx.iter_queued())
+    }
+}
+
+/// In order, and only a few pieces past the first missing one, e.g. to play media while it's
+/// downloading.
+pub struct StreamingPicker {
+    pub lookahead: usize,
+}
+
+impl Default for StreamingPicker {
+    fn default() -> Self {
+        Self {
+            lookahead: DEFAULT_STREAMING_LOOKAHEAD_PIECES,
+        }
+    }
+}
+
+impl PiecePicker for StreamingPicker {
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+        let first_missing = (0..ctx.total_pieces())
+            .find(|id| ctx.is_selected(*id) && !ctx.has(*id))
+            .unwrap_or_default();
+        let window_end = first_missing + self.lookahead;
+        Box::new(ctx.iter_queued().take_while(move |id| *id < window_end))
+    }
+}
+
+/// The pieces the fewest connected peers have first, so that they don't disappear from the swarm.
+#[derive(Default)]
+pub struct RarestFirstPicker;
+
+impl PiecePicker for RarestFirstPicker {
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+        let mut pieces = ctx.iter_queued().collect::<Vec<_>>();
+        pieces.sort_by_key(|id| ctx.availability(*id));
+        Box::new(pieces.into_iter())
+    }
+}
+
+/// Random pieces until a few are downloaded, so that there's something to share with peers
+/// quickly, and then another strategy.
+pub struct RandomFirstPiecesPicker {
+    first_pieces: usize,
+    then: Arc<dyn PiecePicker>,
+}
+
+impl RandomFirstPiecesPicker {
+    pub fn new(first_pieces: usize, then: Arc<dyn PiecePicker>) -> Self {
+        Self { first_pieces, then }
+    }
+}
+
+impl PiecePicker for RandomFirstPiecesPicker {
+    fn pick<'a>(&'a self, ctx: PickerContext<'a>) -> Box<dyn Iterator<Item = usize> + 'a> {
+        if ctx.have_count() >= self.first_pieces {
+            return self.then.pick(ctx);
+        }
+        let mut pieces = ctx.iter_queued().collect::<Vec<_>>();
+        pieces.shuffle(&mut rand::thread_rng());
+        Box::new(pieces.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use librqbit_core::compact_bitfield::CompactBitfield;
+
+    use crate::type_aliases::BF;
+
+    use super::{
+        pick_rarest, LastPieceFirstPicker, PickerContext, PieceAvailability, PiecePicker,
+        RandomFirstPiecesPicker, RarestFirstPicker, StreamingPicker, AVAILABILITY_PICK_WINDOW,
+    };
+
+    fn bf(bits: &[bool]) -> BF {
+        let mut bf = BF::from_boxed_slice(vec![0u8; bits.len().div_ceil(8)].into_boxed_slice());
+        for (idx, bit) in bits.iter().enumerate() {
+            bf.set(idx, *bit);
+        }
+        bf
+    }
+
+    #[test]
+    fn test_pickers() {
+        let queued = bf(&[false, true, true, true, false, true]);
+        let have = bf(&[true, false, false, false, false, false]);
+        let selected = bf(&[true; 6]);
+        let availability = PieceAvailability::new(6);
+        let mut peer = CompactBitfield::have_none(6);
+        peer.set(1, true);
+        peer.set(2, true);
+        availability.add_bitfield(&peer);
+        availability.add_bitfield(&CompactBitfield::have_all(6));
+        peer.set(1, false);
+        availability.add_bitfield(&peer);
+        let ctx = PickerContext::new(6, &queued, &have, &selected, Some(&availability));
+
+        let pick = |p: &dyn PiecePicker| p.pick(ctx).collect::<Vec<_>>();
+        assert_eq!(pick(&LastPieceFirstPicker), vec![5, 1, 2, 3]);
+        assert_eq!(pick(&StreamingPicker { lookahead: 3 }), vec![1, 2, 3]);
+        assert_eq!(pick(&RarestFirstPicker), vec![3, 5, 1, 2]);
+
+        let random = RandomFirstPiecesPicker::new(1, Arc::new(RarestFirstPicker));
+        assert_eq!(pick(&random), vec![3, 5, 1, 2]);
+        let random = RandomFirstPiecesPicker::new(2, Arc::new(RarestFirstPicker));
+        let mut picked = pick(&random);
+        picked.sort();
+        assert_eq!(picked, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn test_pick_rarest() {
+        let total = AVAILABILITY_PICK_WINDOW as u32 + 2;
+        let availability = PieceAvailability::new(total);
+        assert_eq!(pick_rarest([], &availability), None);
+        // Ties keep the picking order.
+        assert_eq!(pick_rarest([3, 1, 2], &availability), Some(3));
+
+        availability.add_bitfield(&CompactBitfield::have_all(total));
+        availability.on_donthave(2);
+        assert_eq!(pick_rarest([3, 1, 2], &availability), Some(2));
+
+        // Only the first few candidates are looked at.
+        availability.on_have(2);
+        availability.on_donthave(total - 1);
+        assert_eq!(pick_rarest(0..total as usize, &availability), Some(0));
+    }
+
+    #[test]
+    fn test_distributed_copies() {
+        let availability = PieceAvailability::new(4);
+        assert_eq!(availability.distributed_copies(&bf(&[false; 4])), (0, 0.));
+
+        let mut peer = CompactBitfield::have_none(4);
+        peer.set(0, true);
+        peer.set(1, true);
+        availability.add_bitfield(&peer);
+        assert_eq!(availability.distributed_copies(&bf(&[false; 4])), (0, 0.5));
+        // Together with what we have, there's a full copy.
+        let have = bf(&[false, false, true, true]);
+        assert_eq!(availability.distributed_copies(&have), (1, 1.));
+
+        availability.add_bitfield(&CompactBitfield::have_all(4));
+        assert_eq!(availability.distributed_copies(&have), (2, 2.));
+        availability.remove_bitfield(&peer);
+        availability.on_have(3);
+        assert_eq!(availability.distributed_copies(&have), (1, 1.5));
+    }
+}
@@ -0,0 +1,124 @@
+// Setting torrent files to their full length when they are opened for download.
+//
+// Sparse files are instant to create, but the filesystem allocates blocks as pieces arrive in
+// random order, which fragments large files, and the disk can fill up in the middle of a
+// download. Full preallocation reserves all the blocks up front.
+
+use std::fs::File;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// How disk space is allocated for the files of a torrent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preallocation {
+    /// Only set the file length. Space is allocated as data is written.
+    #[default]
+    Sparse,
+    /// Allocate all the space of the file when it's opened for download.
+    Full,
+}
+
+// Zero-filling is used where the filesystem can't reserve space on its own.
+const ZERO_FILL_BLOCK: usize = 1024 * 1024;
+
+// Make the file exactly "len" bytes long, allocating the space if asked to.
+pub(crate) fn set_file_len(file: &File, len: u64, mode: Preallocation) -> anyhow::Result<()> {
+    if mode == Preallocation::Full {
+        allocate(file, len)?;
+    }
+    // Also truncates files that are too long, allocation never shrinks them.
+    file.set_len(len)
+        .with_context(|| format!("error setting file length to {len}"))
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // Mode 0 allocates the holes in the range and extends the file, keeping existing data.
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => {
+            tracing::debug!("fallocate not supported ({err}), zero-filling");
+            zero_fill(file, len)
+        }
+        _ => Err(err).with_context(|| format!("error allocating {len} bytes")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, len: u64) -> anyhow::Result<()> {
+    zero_fill(file, len)
+}
+
+// Write zeroes from the current end of file up to "len". Holes inside the existing length
+// can't be told apart from data here, so they stay sparse.
+fn zero_fill(file: &File, len: u64) -> anyhow::Result<()> {
+    let mut offset = file
+        .metadata()
+        .context("error getting file metadata")?
+        .len();
+    let zeroes = vec![0u8; ZERO_FILL_BLOCK];
+    while offset < len {
+        let n = std::cmp::min(len - offset, ZERO_FILL_BLOCK as u64) as usize;
+        write_all_at(file, &zeroes[..n], offset)
+            .with_context(|| format!("error writing zeroes at {offset}"))?;
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{set_file_len, zero_fill, Preallocation};
+
+    #[test]
+    fn test_set_file_len() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(b"hello").unwrap();
+
+        set_file_len(f.as_file(), 3_000_000, Preallocation::Full).unwrap();
+        let data = std::fs::read(f.path()).unwrap();
+        assert_eq!(data.len(), 3_000_000);
+        assert_eq!(&data[..5], b"hello");
+        assert!(data[5..].iter().all(|b| *b == 0));
+
+        set_file_len(f.as_file(), 3, Preallocation::Sparse).unwrap();
+        assert_eq!(std::fs::read(f.path()).unwrap(), b"hel");
+
+        zero_fill(f.as_file(), 1_500_000).unwrap();
+        let data = std::fs::read(f.path()).unwrap();
+        assert_eq!(data.len(), 1_500_000);
+        assert_eq!(&data[..3], b"hel");
+    }
+}
@@ -0,0 +1,112 @@
+// Limiting how many torrents are active at the same time.
+//
+// Torrents over the limits are queued: they are paused, and the session starts them in queue
+// order as slots free up, i.e. when other torrents finish downloading, error out, or get paused
+// or deleted. Downloading and seeding torrents have separate limits, so a torrent that finishes
+// moves from a download slot to a seeding slot, and gets queued if there are none left.
+
+use crate::session::TorrentId;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct QueueLimits {
+    pub max_active_downloads: Option<usize>,
+    pub max_active_seeds: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QueueAction {
+    Start(TorrentId),
+    // Pause the torrent and put it to the back of the queue.
+    Queue(TorrentId),
+}
+
+impl QueueLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_active_downloads.is_some() || self.max_active_seeds.is_some()
+    }
+
+    fn limit(&self, finished: bool) -> Option<usize> {
+        if finished {
+            self.max_active_seeds
+        } else {
+            self.max_active_downloads
+        }
+    }
+
+    // Decide which torrents to start and which to queue.
+    //
+    // "active" are the live torrents ordered by id, "queued" are the queued torrents that can be
+    // started (i.e. not initializing anymore) in queue order. Both with whether they have finished
+    // downloading.
+    //
+    // When over a limit, the torrents added last are queued first.
+    pub fn plan(
+        &self,
+        active: &[(TorrentId, bool)],
+        queued: &[(TorrentId, bool)],
+    ) -> Vec<QueueAction> {
+        let mut counts = [0usize; 2];
+        for (_, finished) in active {
+            counts[*finished as usize] += 1;
+        }
+
+        let mut actions = Vec::new();
+        for (id, finished) in active.iter().rev() {
+            let count = &mut counts[*finished as usize];
+            if matches!(self.limit(*finished), Some(limit) if *count > limit) {
+                *count -= 1;
+                actions.push(QueueAction::Queue(*id));
+            }
+        }
+        for (id, finished) in queued {
+            let count = &mut counts[*finished as usize];
+            if self.limit(*finished).is_none_or(|limit| *count < limit) {
+                *count += 1;
+                actions.push(QueueAction::Start(*id));
+            }
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueAction, QueueLimits};
+
+    #[test]
+    fn test_plan() {
+        let limits = QueueLimits {
+            max_active_downloads: Some(2),
+            max_active_seeds: Some(1),
+        };
+
+        // Slots are free.
+        assert_eq!(
+            limits.plan(&[(0, false)], &[(3, false), (1, true), (2, false)]),
+            vec![QueueAction::Start(3), QueueAction::Start(1)]
+        );
+
+        // Torrent 1 finished downloading, but there's already a seed. Its download slot goes to
+        // the first queued download.
+        assert_eq!(
+            limits.plan(
+                &[(0, true), (1, true), (2, false)],
+                &[(4, true), (3, false)]
+            ),
+            vec![QueueAction::Queue(1), QueueAction::Start(3)]
+        );
+
+        // Unlimited seeding.
+        let limits = QueueLimits {
+            max_active_downloads: Some(1),
+            max_active_seeds: None,
+        };
+        assert_eq!(
+            limits.plan(
+                &[(0, true), (1, false), (2, false)],
+                &[(3, true), (4, false)]
+            ),
+            vec![QueueAction::Queue(2), QueueAction::Start(3)]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+// Token-bucket limiting of peer traffic. One limiter can be shared by many peers, e.g. all peers
+// of the session.
+
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+
+use leaky_bucket::RateLimiter;
+use librqbit_core::constants::MAX_BLOCK_SIZE;
+use parking_lot::RwLock;
+
+// Refill often so that traffic is smooth rather than bursty.
+const REFILL_INTERVAL: Duration = Duration::from_millis(100);
+const REFILLS_PER_SECOND: usize = 10;
+
+// The bucket must fit at least one block, otherwise acquiring it would never complete.
+const MIN_BUCKET_SIZE: usize = MAX_BLOCK_SIZE as usize;
+
+pub(crate) struct RateLimit {
+    bytes_per_second: NonZeroU32,
+    limiter: RateLimiter,
+}
+
+impl std::fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RateLimit({} bytes/s)", self.bytes_per_second)
+    }
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: NonZeroU32) -> Self {
+        let bps = bytes_per_second.get() as usize;
+        let refill = (bps / REFILLS_PER_SECOND).max(1);
+        let limiter = RateLimiter::builder()
+            .initial(refill)
+            .max(bps.max(MIN_BUCKET_SIZE))
+            .interval(REFILL_INTERVAL)
+            .refill(refill)
+            .build();
+        Self {
+            bytes_per_second,
+            limiter,
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> NonZeroU32 {
+        self.bytes_per_second
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        self.limiter.acquire(bytes).await
+    }
+}
+
+/// Upload and download limits of a single peer. They can be changed while it's connected.
+#[derive(Default, Debug)]
+pub(crate) struct PeerRateLimits {
+    upload: RwLock<Option<Arc<RateLimit>>>,
+    download: RwLock<Option<Arc<RateLimit>>>,
+}
+
+impl PeerRateLimits {
+    pub fn set(&self, upload: Option<NonZeroU32>, download: Option<NonZeroU32>) {
+        *self.upload.write() = upload.map(|bps| Arc::new(RateLimit::new(bps)));
+        *self.download.write() = download.map(|bps| Arc::new(RateLimit::new(bps)));
+    }
+
+    pub fn upload(&self) -> Option<Arc<RateLimit>> {
+        self.upload.read().clone()
+    }
+
+    pub fn download(&self) -> Option<Arc<RateLimit>> {
+        self.download.read().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Instant};
+
+    use super::RateLimit;
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        // 1 MiB/s, the first 100ms worth of data is available immediately.
+        let limit = RateLimit::new(NonZeroU32::new(1024 * 1024).unwrap());
+        let start = Instant::now();
+        for _ in 0..32 {
+            limit.acquire(16384).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed.as_millis() >= 300, "{elapsed:?}");
+        assert!(elapsed.as_millis() < 2000, "{elapsed:?}");
+    }
+}
@@ -64,10 +64,14 @@ impl ReadBuf {
 
     // Read a message into the buffer, try to deserialize it and call the callback on it.
     // We can't return the message because of a borrow checker issue.
+    //
+    // Waiting for a message to start times out after idle_timeout, the rest of it has to
+    // arrive within timeout.
     pub async fn read_message(
         &mut self,
         mut conn: impl AsyncReadExt + Unpin,
         timeout: Duration,
+        idle_timeout: Duration,
         on_message: impl for<'a> FnOnce(MessageBorrowed<'a>) -> anyhow::Result<()>,
     ) -> anyhow::Result<()> {
         loop {
@@ -84,6 +88,11 @@ impl ReadBuf {
                     Err(e) => return Err(e.into()),
                 };
             self.prepare_for_read(need_additional_bytes);
+            let timeout = if self.filled == 0 {
+                idle_timeout
+            } else {
+                timeout
+            };
             let size = with_timeout(timeout, conn.read(&mut self.buf[self.filled..]))
                 .await
                 .context("error reading from peer")?;
@@ -1,23 +1,43 @@
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     io::{BufReader, BufWriter, Read},
-    net::SocketAddr,
-    path::PathBuf,
+    net::{Ipv6Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use crate::{
+    bandwidth_history::{BandwidthHistory, BandwidthHistorySnapshot, BandwidthSample},
+    chunk_tracker::{FilePriority, PartialPieces},
     dht_utils::{read_metainfo_from_peer_receiver, ReadMetainfoResult},
-    peer_connection::PeerConnectionOptions,
+    error::{Error, ErrorKind},
+    events::{Event, EventSender, EVENTS_CHANNEL_CAPACITY},
+    extensions::ExtensionRegistry,
+    feeds::{self, Feed, FeedFilter, FeedId, FeedSubscription},
+    hooks::{HookEvent, TorrentHooks},
+    opened_file::{FilePool, DEFAULT_MAX_OPEN_FILES},
+    peer_connection::{BoxPeerStream, PeerConnectionOptions, PeerSocketBinding},
+    piece_picker::PiecePicker,
+    preallocate::Preallocation,
+    queue::{QueueAction, QueueLimits},
+    rate_limit::RateLimit,
     read_buf::ReadBuf,
-    spawn_utils::BlockingSpawner,
+    session_stats::SessionStatsSnapshot,
+    spawn_utils::{BlockingSpawner, CpuPool},
     torrent_state::{
+        live::peers::stats::snapshot::AggregatePeerStats,
+        peer::{stats::snapshot::PeerStats, PeerOrigin},
         ManagedTorrentBuilder, ManagedTorrentHandle, ManagedTorrentState, TorrentStateLive,
     },
-    type_aliases::PeerStream,
+    type_aliases::{PeerHandle, PeerStream},
+    webhooks::{self, Webhook, WebhookEvent},
 };
 use anyhow::{bail, Context};
 use bencode::{bencode_serialize_to_writer, BencodeDeserializer};
@@ -32,25 +52,37 @@ use futures::{
 use itertools::Itertools;
 use librqbit_core::{
     directories::get_configuration_directory,
+    hash_id::Id32,
     magnet::Magnet,
     peer_id::generate_peer_id,
+    socket_binding::SocketBinding,
     spawn_utils::spawn_with_cancel,
     torrent_metainfo::{
-        torrent_from_bytes as bencode_torrent_from_bytes, TorrentMetaV1Info, TorrentMetaV1Owned,
+        torrent_from_bytes as bencode_torrent_from_bytes, PieceLayers, TorrentMetaV1Info,
+        TorrentMetaV1Owned,
     },
 };
+use librqbit_upnp::{UpnpPortForwarder, UpnpPortForwarderStatus};
+use librqbit_utp::UtpSocket;
 use parking_lot::RwLock;
 use peer_binary_protocol::Handshake;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::serde_as;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_stream::StreamExt;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast::error::RecvError, Semaphore},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 use tokio_util::sync::{CancellationToken, DropGuard};
 use tracing::{debug, error, error_span, info, trace, warn, Instrument};
 use tracker_comms::TrackerComms;
 
 pub const SUPPORTED_SCHEMES: [&str; 3] = ["http:", "https:", "magnet:"];
 
+const TRACKER_REANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+const DHT_REANNOUNCE_DURATION: Duration = Duration::from_secs(60);
+const QUEUE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub type TorrentId = usize;
 
 fn torrent_from_bytes(bytes: &[u8]) -> anyhow::Result<TorrentMetaV1Owned> {
@@ -65,6 +97,10 @@ fn torrent_from_bytes(bytes: &[u8]) -> anyhow::Result<TorrentMetaV1Owned> {
 pub struct SessionDatabase {
     next_id: TorrentId,
     torrents: HashMap<TorrentId, ManagedTorrentHandle>,
+    // Paused torrents waiting for a free slot, in the order they will be started.
+    queued: VecDeque<TorrentId>,
+    next_feed_id: FeedId,
+    feeds: HashMap<FeedId, Feed>,
 }
 
 impl SessionDatabase {
@@ -105,12 +141,45 @@ impl SessionDatabase {
                                 .iter()
                                 .map(|u| u.to_string())
                                 .collect(),
+                            tracker_tiers: torrent.info().tracker_tiers.clone(),
                             info_hash: torrent.info_hash().as_string(),
+                            info_hash_v2: torrent.info().info_hash_v2.map(|h| h.as_string()),
                             info: torrent.info().info.clone(),
+                            piece_layers: torrent.info().piece_layers.clone(),
                             only_files: torrent.only_files().clone(),
                             is_paused: torrent
                                 .with_state(|s| matches!(s, ManagedTorrentState::Paused(_))),
-                            output_folder: torrent.info().out_dir.clone(),
+                            is_queued: self.queued.contains(id),
+                            output_folder: torrent.info().out_dir(),
+                            socket_binding: torrent.info().options.socket_binding.clone(),
+                            upload_slots: torrent.info().options.upload_slots,
+                            upload_rate_limit: torrent
+                                .info()
+                                .options
+                                .upload_rate_limit
+                                .as_ref()
+                                .map(|l| l.bytes_per_second()),
+                            sequential: torrent.is_sequential(),
+                            file_priorities: torrent.file_priorities(),
+                            head_tail_files: torrent.head_tail_files(),
+                            hooks: torrent.hooks(),
+                            renamed_files: torrent.info().renamed_files(),
+                            webseeds: torrent.info().webseeds.clone(),
+                            category: torrent.info().category.clone(),
+                            partial_pieces: torrent.partial_pieces(),
+                        },
+                    )
+                })
+                .collect(),
+            feeds: self
+                .feeds
+                .iter()
+                .map(|(id, feed)| {
+                    (
+                        *id,
+                        SerializedFeed {
+                            subscription: feed.subscription.clone(),
+                            seen: feed.seen.clone(),
                         },
                     )
                 })
@@ -127,10 +196,51 @@ struct SerializedTorrent {
         deserialize_with = "deserialize_torrent"
     )]
     info: TorrentMetaV1Info<ByteBufOwned>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash_v2: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_piece_layers",
+        deserialize_with = "deserialize_piece_layers"
+    )]
+    piece_layers: Option<PieceLayers<ByteBufOwned>>,
     trackers: HashSet<String>,
+    // Older versions only stored the flat list of trackers above.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tracker_tiers: Vec<Vec<String>>,
     output_folder: PathBuf,
     only_files: Option<Vec<usize>>,
     is_paused: bool,
+    #[serde(default)]
+    is_queued: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    socket_binding: Option<PeerSocketBinding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_slots: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate_limit: Option<NonZeroU32>,
+    #[serde(default)]
+    sequential: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_priorities: Option<Vec<FilePriority>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    head_tail_files: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hooks: Option<TorrentHooks>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    renamed_files: HashMap<usize, PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    webseeds: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "PartialPieces::is_empty",
+        serialize_with = "serialize_partial_pieces",
+        deserialize_with = "deserialize_partial_pieces"
+    )]
+    partial_pieces: PartialPieces,
 }
 
 fn serialize_torrent<S>(
@@ -162,23 +272,124 @@ where
         .map_err(D::Error::custom)
 }
 
+// Bencoded and base64 encoded, like the info.
+fn serialize_piece_layers<S>(
+    l: &Option<PieceLayers<ByteBufOwned>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::ser::Error;
+    let mut writer = Vec::new();
+    bencode_serialize_to_writer(l, &mut writer).map_err(S::Error::custom)?;
+    general_purpose::STANDARD_NO_PAD
+        .encode(&writer)
+        .serialize(serializer)
+}
+
+fn deserialize_piece_layers<'de, D>(
+    deserializer: D,
+) -> Result<Option<PieceLayers<ByteBufOwned>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::de::Error;
+    let s = String::deserialize(deserializer)?;
+    let b = general_purpose::STANDARD_NO_PAD
+        .decode(s)
+        .map_err(D::Error::custom)?;
+    PieceLayers::<ByteBufOwned>::deserialize(&mut BencodeDeserializer::new_from_buf(&b))
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+// Chunk bits of each piece as base64, by piece index.
+fn serialize_partial_pieces<S>(p: &PartialPieces, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    serializer.collect_map(
+        p.iter()
+            .map(|(id, chunks)| (id, general_purpose::STANDARD_NO_PAD.encode(chunks))),
+    )
+}
+
+fn deserialize_partial_pieces<'de, D>(deserializer: D) -> Result<PartialPieces, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::de::Error;
+    HashMap::<u32, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(id, chunks)| {
+            let chunks = general_purpose::STANDARD_NO_PAD
+                .decode(chunks)
+                .map_err(D::Error::custom)?;
+            Ok((id, chunks))
+        })
+        .collect()
+}
+
 #[derive(Serialize, Deserialize)]
 struct SerializedSessionDatabase {
     torrents: HashMap<usize, SerializedTorrent>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    feeds: HashMap<FeedId, SerializedFeed>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedFeed {
+    #[serde(flatten)]
+    subscription: FeedSubscription,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    seen: HashSet<String>,
 }
 
 pub struct Session {
     peer_id: Id20,
     dht: Option<Dht>,
+    persistence: bool,
     persistence_filename: PathBuf,
     peer_opts: PeerConnectionOptions,
     spawner: BlockingSpawner,
+    cpu_pool: CpuPool,
+    file_pool: Arc<FilePool>,
     db: RwLock<SessionDatabase>,
     output_folder: PathBuf,
+    direct_io: bool,
+    mmap: bool,
+    preallocation: Preallocation,
+    part_files: bool,
+    write_cache_size: Option<usize>,
+    upload_rate_limit: Option<Arc<RateLimit>>,
+    queue_limits: QueueLimits,
+    peer_limit_per_torrent: Option<NonZeroUsize>,
+    peer_semaphore: Option<Arc<Semaphore>>,
+    half_open_semaphore: Option<Arc<Semaphore>>,
+    peer_request_queue_depth: Option<NonZeroUsize>,
+    stream_readahead_pieces: Option<NonZeroU32>,
+    max_request_block_size: Option<NonZeroU32>,
+    hooks: Option<Arc<TorrentHooks>>,
+    extensions: Option<Arc<ExtensionRegistry>>,
+    socket_binding: Option<PeerSocketBinding>,
+    upnp: Option<UpnpPortForwarderStatus>,
+    events: EventSender,
+    tracker_announce_errors: AtomicU64,
+    bandwidth_history: BandwidthHistory,
+    // Totals of all torrents since the session started.
+    fetched_bytes: AtomicU64,
+    uploaded_bytes: AtomicU64,
 
     tcp_listen_port: Option<u16>,
+    utp_socket: Option<UtpSocket>,
 
     cancellation_token: CancellationToken,
+    shutting_down: AtomicBool,
 
     // This is stored for all tasks to stop when session is dropped.
     _cancellation_token_drop_guard: DropGuard,
@@ -289,6 +500,29 @@ pub struct AddTorrentOptions {
     pub sub_folder: Option<String>,
     /// Peer connection options, timeouts etc. If not set, session's defaults will be used.
     pub peer_opts: Option<PeerConnectionOptions>,
+    /// Bind outgoing peer connections of this torrent to an interface or fwmark.
+    pub socket_binding: Option<PeerSocketBinding>,
+    /// How many peers to upload to at once, not counting the optimistic unchoke. Defaults to 4.
+    pub upload_slots: Option<usize>,
+    /// Limit uploads of this torrent to this many bytes per second. The session-wide limit
+    /// still applies.
+    pub upload_rate_limit: Option<NonZeroU32>,
+    /// Download pieces in order, e.g. to play media while it's downloading.
+    pub sequential: bool,
+    /// The priority of every file. Files with "skip" priority are not downloaded.
+    pub file_priorities: Option<Vec<FilePriority>>,
+    /// Files whose first and last pieces are downloaded before the rest, as media players need
+    /// them to probe the container.
+    pub head_tail_files: Option<Vec<usize>>,
+    /// Commands to run when something happens to this torrent, instead of the session's ones.
+    pub hooks: Option<TorrentHooks>,
+    /// New paths of files, relative to the output folder, by file id.
+    pub renamed_files: Option<HashMap<usize, PathBuf>>,
+    /// A label to group torrents by, e.g. all the torrents added from one feed.
+    pub category: Option<String>,
+    /// The order to download pieces in. Defaults to the last piece first, then in order.
+    #[serde(skip)]
+    pub piece_picker: Option<Arc<dyn PiecePicker>>,
 
     /// Force a refresh interval for polling trackers.
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
@@ -302,6 +536,11 @@ pub struct AddTorrentOptions {
     /// This is used to restore the session from serialized state.
     #[serde(skip)]
     pub preferred_id: Option<usize>,
+
+    /// Chunks of unfinished pieces that are already on disk. This is used to restore the
+    /// session from serialized state.
+    #[serde(skip)]
+    pub partial_pieces: Option<PartialPieces>,
 }
 
 pub struct ListOnlyResponse {
@@ -409,13 +648,106 @@ pub struct SessionOptions {
 
     pub listen_port_range: Option<std::ops::Range<u16>>,
     pub enable_upnp_port_forwarding: bool,
+
+    /// Write downloaded chunks with direct (unbuffered) I/O where supported (Linux, Windows),
+    /// so that large downloads don't evict the OS page cache.
+    pub direct_io: bool,
+
+    /// Read and write torrent files through memory maps instead of read/write syscalls.
+    /// Takes precedence over direct_io for files that are mapped.
+    pub mmap: bool,
+
+    /// How to allocate disk space for files when they are opened for download.
+    pub preallocation: Preallocation,
+
+    /// Download files under a temporary name with a ".part" suffix, and rename them into place
+    /// once all their pieces are verified, so that other programs never see half-written files.
+    pub part_files: bool,
+
+    /// Buffer downloaded chunks in memory, up to this many bytes per torrent, and write every
+    /// piece with one sequential write once it's complete. Helps disks that are slow at small
+    /// random writes. Disabled if None.
+    pub write_cache_size: Option<usize>,
+
+    /// The most files open at the same time, across all torrents. The least recently used
+    /// ones are closed, and opened again when needed. Defaults to 512.
+    pub max_open_files: Option<NonZeroUsize>,
+
+    /// Enable uTP (BEP 29) in addition to TCP. Peers are dialed over uTP first, and incoming
+    /// uTP connections are accepted on the same port as TCP.
+    pub enable_utp: bool,
+
+    /// Limit uploads of all torrents together to this many bytes per second.
+    pub upload_rate_limit: Option<NonZeroU32>,
+
+    /// The most torrents to download at the same time. Torrents over the limit are queued, and
+    /// started as others finish. Unlimited if None.
+    pub max_active_downloads: Option<usize>,
+    /// The most finished torrents to seed at the same time. Unlimited if None.
+    pub max_active_seeds: Option<usize>,
+
+    /// The most peers each torrent is connected to at the same time. 128 if None.
+    pub peer_limit_per_torrent: Option<NonZeroUsize>,
+    /// The most peers all torrents together are connected to at the same time. Unlimited if None.
+    pub peer_limit: Option<NonZeroUsize>,
+    /// The most outgoing peer connections being established (connecting, not yet handshaken)
+    /// at the same time, across all torrents. Unlimited if None.
+    pub half_open_limit: Option<NonZeroUsize>,
+    /// The most chunk requests in flight to one peer. If None, it's sized from each peer's
+    /// speed and round trip time, between 16 and what the peer allows in its extended
+    /// handshake (reqq), up to 250.
+    pub peer_request_queue_depth: Option<NonZeroUsize>,
+    /// How many pieces after the position a file stream reads at are downloaded before all
+    /// others. The rest of the file is downloaded after all others. Defaults to 8.
+    pub stream_readahead_pieces: Option<NonZeroU32>,
+    /// The largest block peers may request from us. Most clients request 16 KiB blocks, but some
+    /// request larger ones. Defaults to 256 KiB, which is also the most allowed.
+    pub max_request_block_size: Option<NonZeroU32>,
+    /// Commands to run when something happens to any torrent, e.g. when it finishes. Torrents
+    /// can override them.
+    pub hooks: TorrentHooks,
+    /// URLs to POST JSON to when torrents finish, fail or stall.
+    pub webhooks: Vec<Webhook>,
+
+    /// Bind outgoing peer connections, tracker requests and the DHT socket to this local
+    /// address and/or interface. Torrents with their own binding use it instead, except for
+    /// the DHT which is shared.
+    pub socket_binding: Option<PeerSocketBinding>,
+
+    /// Custom extension protocol messages to negotiate with peers and handle.
+    pub extensions: ExtensionRegistry,
+}
+
+// An IPv6 socket that also accepts IPv4 (as v4-mapped addresses), so that one listener
+// serves both families.
+fn bind_dual_stack(ty: socket2::Type, port: u16) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, ty, None)?;
+    socket.set_only_v6(false)?;
+    #[cfg(not(windows))]
+    if ty == socket2::Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket)
 }
 
 async fn create_tcp_listener(
     port_range: std::ops::Range<u16>,
 ) -> anyhow::Result<(TcpListener, u16)> {
     for port in port_range.clone() {
-        match TcpListener::bind(("0.0.0.0", port)).await {
+        let dual_stack = bind_dual_stack(socket2::Type::STREAM, port).and_then(|s| {
+            s.listen(1024)?;
+            TcpListener::from_std(s.into())
+        });
+        let listener = match dual_stack {
+            Ok(l) => Ok(l),
+            Err(e) => {
+                debug!("error listening on [::]:{port}, trying IPv4 only: {e:#}");
+                TcpListener::bind(("0.0.0.0", port)).await
+            }
+        };
+        match listener {
             Ok(l) => return Ok((l, port)),
             Err(e) => {
                 debug!("error listening on port {port}: {e:#}")
@@ -425,9 +757,21 @@ async fn create_tcp_listener(
     bail!("no free TCP ports in range {port_range:?}");
 }
 
+async fn create_utp_socket(port: u16) -> anyhow::Result<UtpSocket> {
+    let dual_stack = bind_dual_stack(socket2::Type::DGRAM, port)
+        .and_then(|s| tokio::net::UdpSocket::from_std(s.into()));
+    match dual_stack {
+        Ok(udp) => UtpSocket::from_udp(udp),
+        Err(e) => {
+            debug!("error binding UDP socket to [::]:{port}, trying IPv4 only: {e:#}");
+            UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await
+        }
+    }
+}
+
 pub(crate) struct CheckedIncomingConnection {
     pub addr: SocketAddr,
-    pub stream: tokio::net::TcpStream,
+    pub stream: BoxPeerStream,
     pub read_buf: ReadBuf,
     pub handshake: Handshake<ByteBufOwned>,
 }
@@ -435,7 +779,7 @@ pub(crate) struct CheckedIncomingConnection {
 impl Session {
     /// Create a new session. The passed in folder will be used as a default unless overriden per torrent.
     #[inline(never)]
-    pub fn new(output_folder: PathBuf) -> BoxFuture<'static, anyhow::Result<Arc<Self>>> {
+    pub fn new(output_folder: PathBuf) -> BoxFuture<'static, crate::error::Result<Arc<Self>>> {
         Self::new_with_opts(output_folder, SessionOptions::default())
     }
 
@@ -453,7 +797,7 @@ impl Session {
     pub fn new_with_opts(
         output_folder: PathBuf,
         mut opts: SessionOptions,
-    ) -> BoxFuture<'static, anyhow::Result<Arc<Self>>> {
+    ) -> BoxFuture<'static, crate::error::Result<Arc<Self>>> {
         async move {
             let peer_id = opts.peer_id.unwrap_or_else(generate_peer_id);
             let token = CancellationToken::new();
@@ -462,24 +806,58 @@ impl Session {
                 let (l, p) = create_tcp_listener(port_range)
                     .await
                     .context("error listening on TCP")?;
-                info!("Listening on 0.0.0.0:{p} for incoming peer connections");
+                info!(
+                    "Listening on {} for incoming peer connections",
+                    l.local_addr().context("error getting listener address")?
+                );
                 (Some(l), Some(p))
             } else {
                 (None, None)
             };
 
+            let utp_socket = if opts.enable_utp {
+                let port = tcp_listen_port.unwrap_or(0);
+                match create_utp_socket(port).await {
+                    Ok(s) => {
+                        info!(
+                            "Listening on {} for incoming uTP connections",
+                            s.local_addr()
+                        );
+                        Some(s)
+                    }
+                    Err(e) => {
+                        warn!("error enabling uTP, continuing with TCP only: {e:#}");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let socket_binding = opts.socket_binding.take().filter(|b| !b.is_empty());
+
             let dht = if opts.disable_dht {
                 None
             } else {
                 let dht = if opts.disable_dht_persistence {
+                    let pdht_config = opts.dht_config.as_ref();
                     DhtBuilder::with_config(DhtConfig {
                         cancellation_token: Some(token.child_token()),
+                        socket_binding: socket_binding.as_ref().map(|b| b.socket_binding()),
+                        rate_limits: pdht_config.map(|c| c.rate_limits).unwrap_or_default(),
+                        read_only: pdht_config.map(|c| c.read_only).unwrap_or_default(),
+                        bootstrap_addrs: pdht_config.and_then(|c| c.bootstrap_addrs.clone()),
+                        peer_id: pdht_config.and_then(|c| c.peer_id),
                         ..Default::default()
                     })
                     .await
                     .context("error initializing DHT")?
                 } else {
-                    let pdht_config = opts.dht_config.take().unwrap_or_default();
+                    let mut pdht_config = opts.dht_config.take().unwrap_or_default();
+                    if pdht_config.socket_binding.is_none() {
+                        pdht_config.socket_binding =
+                            socket_binding.as_ref().map(|b| b.socket_binding());
+                    }
                     PersistentDht::create(Some(pdht_config), Some(token.clone()))
                         .await
                         .context("error initializing persistent DHT")?
@@ -494,17 +872,62 @@ impl Session {
             };
             let spawner = BlockingSpawner::default();
 
+            let upnp_port_forwarder = match tcp_listen_port {
+                Some(port) if opts.enable_upnp_port_forwarding => {
+                    Some(UpnpPortForwarder::new(vec![port], None)?)
+                }
+                _ => None,
+            };
+
             let session = Arc::new(Self {
+                persistence: opts.persistence,
                 persistence_filename,
                 peer_id,
                 dht,
                 peer_opts,
                 spawner,
+                cpu_pool: CpuPool::default(),
+                file_pool: Arc::new(FilePool::new(
+                    opts.max_open_files
+                        .map_or(DEFAULT_MAX_OPEN_FILES, |n| n.get()),
+                )),
                 output_folder,
+                direct_io: opts.direct_io,
+                mmap: opts.mmap,
+                preallocation: opts.preallocation,
+                part_files: opts.part_files,
+                write_cache_size: opts.write_cache_size,
+                upload_rate_limit: opts
+                    .upload_rate_limit
+                    .map(|bps| Arc::new(RateLimit::new(bps))),
+                queue_limits: QueueLimits {
+                    max_active_downloads: opts.max_active_downloads,
+                    max_active_seeds: opts.max_active_seeds,
+                },
+                peer_limit_per_torrent: opts.peer_limit_per_torrent,
+                peer_semaphore: opts.peer_limit.map(|l| Arc::new(Semaphore::new(l.get()))),
+                half_open_semaphore: opts
+                    .half_open_limit
+                    .map(|l| Arc::new(Semaphore::new(l.get()))),
+                peer_request_queue_depth: opts.peer_request_queue_depth,
+                stream_readahead_pieces: opts.stream_readahead_pieces,
+                max_request_block_size: opts.max_request_block_size,
+                hooks: (!opts.hooks.is_empty()).then(|| Arc::new(std::mem::take(&mut opts.hooks))),
+                extensions: (!opts.extensions.is_empty())
+                    .then(|| Arc::new(std::mem::take(&mut opts.extensions))),
+                socket_binding,
+                upnp: upnp_port_forwarder.as_ref().map(|pf| pf.status()),
+                events: tokio::sync::broadcast::channel(EVENTS_CHANNEL_CAPACITY).0,
+                tracker_announce_errors: AtomicU64::new(0),
+                bandwidth_history: Default::default(),
+                fetched_bytes: AtomicU64::new(0),
+                uploaded_bytes: AtomicU64::new(0),
                 db: RwLock::new(Default::default()),
                 _cancellation_token_drop_guard: token.clone().drop_guard(),
                 cancellation_token: token,
+                shutting_down: AtomicBool::new(false),
                 tcp_listen_port,
+                utp_socket: utp_socket.clone(),
             });
 
             if let Some(tcp_listener) = tcp_listener {
@@ -514,13 +937,36 @@ impl Session {
                 );
             }
 
-            if let Some(listen_port) = tcp_listen_port {
-                if opts.enable_upnp_port_forwarding {
-                    session.spawn(
-                        error_span!("upnp_forward", port = listen_port),
-                        session.clone().task_upnp_port_forwarder(listen_port),
-                    );
-                }
+            if let Some(utp_socket) = utp_socket {
+                session.spawn(
+                    error_span!("utp_listen", addr = %utp_socket.local_addr()),
+                    session.clone().task_utp_listener(utp_socket),
+                );
+            }
+
+            if let Some(pf) = upnp_port_forwarder {
+                session.spawn(
+                    error_span!("upnp_forward", port = tcp_listen_port),
+                    Self::task_upnp_port_forwarder(pf),
+                );
+            }
+
+            session.spawn(
+                error_span!("session_stats"),
+                session.clone().task_session_stats(),
+            );
+
+            if !opts.webhooks.is_empty() {
+                session.spawn(
+                    error_span!("webhooks"),
+                    session
+                        .clone()
+                        .task_webhooks(std::mem::take(&mut opts.webhooks)),
+                );
+            }
+
+            if session.queue_limits.is_enabled() {
+                session.spawn(error_span!("queue"), session.clone().task_queue());
             }
 
             if opts.persistence {
@@ -529,16 +975,19 @@ impl Session {
                     session.persistence_filename
                 );
                 if let Some(parent) = session.persistence_filename.parent() {
-                    std::fs::create_dir_all(parent).with_context(|| {
-                        format!("couldn't create directory {:?} for session storage", parent)
-                    })?;
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| {
+                            format!("couldn't create directory {:?} for session storage", parent)
+                        })
+                        .context(ErrorKind::Disk)?;
                 }
                 let persistence_task = session.clone().task_persistence();
                 session.spawn(error_span!("session_persistence"), persistence_task);
             }
 
-            Ok(session)
+            Ok::<_, anyhow::Error>(session)
         }
+        .map(|r| r.map_err(Error::from))
         .boxed()
     }
 
@@ -557,6 +1006,10 @@ impl Session {
                 Some(s) => s,
                 None => break,
             };
+            // Shutdown saves the session itself, before pausing the torrents.
+            if session.is_shutting_down() {
+                break;
+            }
             if let Err(e) = session.dump_to_disk() {
                 error!("error dumping session to disk: {:?}", e);
             }
@@ -565,10 +1018,183 @@ impl Session {
         Ok(())
     }
 
+    // Every second, sum up the latest rates of all live torrents, and add what they transferred
+    // to the session totals.
+    async fn task_session_stats(self: Arc<Self>) -> anyhow::Result<()> {
+        let session = Arc::downgrade(&self);
+        drop(self);
+
+        // Fetched and uploaded bytes of each live torrent at the previous tick. Torrent counters
+        // start from 0 every time they go live.
+        let mut prev_totals: HashMap<TorrentId, (u64, u64)> = HashMap::new();
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let session = match session.upgrade() {
+                Some(session) => session,
+                None => return Ok(()),
+            };
+            let live = session.with_torrents(|torrents| {
+                torrents
+                    .filter_map(|(id, t)| Some((id, t.live()?)))
+                    .collect::<Vec<_>>()
+            });
+            let mut sample = BandwidthSample::default();
+            let mut totals = HashMap::with_capacity(live.len());
+            for (id, live) in live {
+                if let Some(s) = live.last_bandwidth_sample() {
+                    sample.download_bps += s.download_bps;
+                    sample.upload_bps += s.upload_bps;
+                }
+                let (fetched, uploaded) = (live.get_fetched_bytes(), live.get_uploaded_bytes());
+                let (prev_fetched, prev_uploaded) = match prev_totals.get(&id) {
+                    Some(&(f, u)) if f <= fetched && u <= uploaded => (f, u),
+                    _ => (0, 0),
+                };
+                session
+                    .fetched_bytes
+                    .fetch_add(fetched - prev_fetched, Ordering::Relaxed);
+                session
+                    .uploaded_bytes
+                    .fetch_add(uploaded - prev_uploaded, Ordering::Relaxed);
+                totals.insert(id, (fetched, uploaded));
+            }
+            prev_totals = totals;
+            session.bandwidth_history.add_sample(sample);
+        }
+    }
+
+    async fn task_queue(self: Arc<Self>) -> anyhow::Result<()> {
+        let session = Arc::downgrade(&self);
+        drop(self);
+
+        loop {
+            tokio::time::sleep(QUEUE_CHECK_INTERVAL).await;
+            match session.upgrade() {
+                Some(session) => session.check_queue(),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Send the events of torrents to the webhooks that want them.
+    async fn task_webhooks(self: Arc<Self>, webhooks: Vec<Webhook>) -> anyhow::Result<()> {
+        let mut events = self.events.subscribe();
+        let session = Arc::downgrade(&self);
+        drop(self);
+
+        let client = reqwest::Client::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "fell behind, some events weren't sent to webhooks");
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            let kind = match WebhookEvent::from_event(&event) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let session = match session.upgrade() {
+                Some(session) => session,
+                None => return Ok(()),
+            };
+            let name = session
+                .db
+                .read()
+                .torrents
+                .values()
+                .find(|t| t.info_hash() == event.info_hash())
+                .and_then(|t| t.info().info.name.as_ref().map(|n| n.to_string()));
+            let body = webhooks::payload(&event, name.as_deref())?;
+            for webhook in webhooks.iter().filter(|w| w.wants(kind)) {
+                let (client, webhook, body) = (client.clone(), webhook.clone(), body.clone());
+                session.spawn(
+                    error_span!("webhook", url = webhook.url.as_str()),
+                    async move { webhooks::deliver(&client, &webhook, body).await },
+                );
+            }
+        }
+    }
+
+    // Start the queued torrents that have a free slot, and queue the ones over the limits.
+    fn check_queue(self: &Arc<Self>) {
+        use crate::torrent_state::stats::TorrentStatsState as S;
+
+        if self.is_shutting_down() {
+            return;
+        }
+
+        let (active, queued) = {
+            let mut g = self.db.write();
+            let db = &mut *g;
+            // Forget torrents that were deleted or errored out while queued.
+            db.queued.retain(|id| {
+                db.torrents.get(id).is_some_and(|t| {
+                    t.with_state(|s| {
+                        matches!(
+                            s,
+                            ManagedTorrentState::Paused(_) | ManagedTorrentState::Initializing(_)
+                        )
+                    })
+                })
+            });
+
+            let mut active = Vec::new();
+            for (id, t) in db.torrents.iter() {
+                let stats = t.stats();
+                if matches!(stats.state, S::Live) {
+                    active.push((*id, stats.finished));
+                }
+            }
+            active.sort_unstable();
+
+            let mut queued = Vec::new();
+            for id in db.queued.iter() {
+                let stats = db.torrents[id].stats();
+                if matches!(stats.state, S::Paused) {
+                    queued.push((*id, stats.finished));
+                }
+            }
+            (active, queued)
+        };
+
+        for action in self.queue_limits.plan(&active, &queued) {
+            let result = match action {
+                QueueAction::Queue(id) => self.queue_torrent(id),
+                QueueAction::Start(id) => self.start_queued(id),
+            };
+            if let Err(e) = result {
+                warn!(?action, "error updating the queue: {e:#}");
+            }
+        }
+    }
+
+    fn queue_torrent(&self, id: TorrentId) -> anyhow::Result<()> {
+        let handle = self.get(id).context("torrent not found")?;
+        handle.pause()?;
+        debug!(id, "queued torrent");
+        self.db.write().queued.push_back(id);
+        Ok(())
+    }
+
+    fn start_queued(self: &Arc<Self>, id: TorrentId) -> anyhow::Result<()> {
+        self.db.write().queued.retain(|q| *q != id);
+        let handle = self.get(id).context("torrent not found")?;
+        debug!(id, "starting queued torrent");
+        self.resume(&handle)
+    }
+
+    /// The queued torrents, in the order they will be started.
+    pub fn queued(&self) -> Vec<TorrentId> {
+        self.db.read().queued.iter().copied().collect()
+    }
+
     async fn check_incoming_connection(
         &self,
         addr: SocketAddr,
-        mut stream: TcpStream,
+        mut stream: BoxPeerStream,
     ) -> anyhow::Result<(Arc<TorrentStateLive>, CheckedIncomingConnection)> {
         let rwtimeout = self
             .peer_opts
@@ -586,6 +1212,10 @@ impl Session {
             bail!("seems like we are connecting to ourselves, ignoring");
         }
 
+        if self.is_shutting_down() {
+            bail!("session is shutting down, ignoring connection");
+        }
+
         for (id, torrent) in self.db.read().torrents.iter() {
             if torrent.info_hash().0 != h.info_hash {
                 continue;
@@ -625,9 +1255,11 @@ impl Session {
                 r = l.accept() => {
                     match r {
                         Ok((stream, addr)) => {
+                            // IPv4 peers on the dual-stack listener come as v4-mapped addresses.
+                            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
                             trace!("accepted connection from {addr}");
                             futs.push(
-                                self.check_incoming_connection(addr, stream)
+                                self.check_incoming_connection(addr, Box::new(stream))
                                     .map_err(|e| {
                                         debug!("error checking incoming connection: {e:#}");
                                         e
@@ -650,11 +1282,74 @@ impl Session {
         }
     }
 
-    async fn task_upnp_port_forwarder(self: Arc<Self>, port: u16) -> anyhow::Result<()> {
-        let pf = librqbit_upnp::UpnpPortForwarder::new(vec![port], None)?;
+    async fn task_utp_listener(self: Arc<Self>, socket: UtpSocket) -> anyhow::Result<()> {
+        let mut futs = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                r = socket.accept() => {
+                    match r {
+                        Ok((stream, addr)) => {
+                            trace!("accepted uTP connection from {addr}");
+                            futs.push(
+                                self.check_incoming_connection(addr, Box::new(stream))
+                                    .map_err(|e| {
+                                        debug!("error checking incoming uTP connection: {e:#}");
+                                        e
+                                    })
+                                    .instrument(error_span!("incoming_utp", addr=%addr))
+                            );
+                        }
+                        Err(e) => {
+                            bail!("error accepting uTP connections: {e:#}");
+                        }
+                    }
+                },
+                Some(Ok((live, checked))) = futs.next(), if !futs.is_empty() => {
+                    if let Err(e) = live.add_incoming_peer(checked) {
+                        warn!("error handing over incoming connection: {e:#}");
+                    }
+                },
+            }
+        }
+    }
+
+    async fn task_upnp_port_forwarder(pf: UpnpPortForwarder) -> anyhow::Result<()> {
         pf.run_forever().await
     }
 
+    /// Session-wide stats, not tied to a torrent.
+    pub fn stats_snapshot(&self) -> SessionStatsSnapshot {
+        let torrents =
+            self.with_torrents(|torrents| torrents.map(|(_, t)| t.clone()).collect::<Vec<_>>());
+        let mut peers = AggregatePeerStats::default();
+        let mut live_torrents = 0;
+        let mut write_cache_bytes = 0u64;
+        for live in torrents.iter().filter_map(|t| t.live()) {
+            live_torrents += 1;
+            peers.add(&live.get_peer_stats());
+            write_cache_bytes += live.get_write_cache_bytes() as u64;
+        }
+        let rates = self.bandwidth_history.last().unwrap_or_default();
+        SessionStatsSnapshot {
+            upnp_port_mappings: self.upnp.as_ref().map(|u| u.mappings()).unwrap_or_default(),
+            torrents: torrents.len(),
+            live_torrents,
+            download_bps: rates.download_bps,
+            upload_bps: rates.upload_bps,
+            peers,
+            write_cache_bytes,
+            fetched_bytes: self.fetched_bytes.load(Ordering::Relaxed),
+            uploaded_bytes: self.uploaded_bytes.load(Ordering::Relaxed),
+            dht: self.dht.as_ref().map(|d| d.stats()),
+        }
+    }
+
+    /// Per-second download and upload rates of the whole session over the last few minutes.
+    pub fn bandwidth_history(&self) -> BandwidthHistorySnapshot {
+        self.bandwidth_history.snapshot()
+    }
+
     pub fn get_dht(&self) -> Option<&Dht> {
         self.dht.as_ref()
     }
@@ -672,6 +1367,11 @@ impl Session {
             keep_alive_interval: other
                 .keep_alive_interval
                 .or(self.peer_opts.keep_alive_interval),
+            idle_timeout: other.idle_timeout.or(self.peer_opts.idle_timeout),
+            inactivity_timeout: other
+                .inactivity_timeout
+                .or(self.peer_opts.inactivity_timeout),
+            transport: other.transport.or(self.peer_opts.transport),
         }
     }
 
@@ -716,16 +1416,33 @@ impl Session {
         };
         let db: SerializedSessionDatabase =
             serde_json::from_reader(&mut rdr).context("error deserializing session database")?;
+        for (id, feed) in db.feeds {
+            if let Err(e) = self.add_feed_inner(feed.subscription, feed.seen, Some(id)) {
+                error!("error adding feed from stored session: {:?}", e);
+            }
+        }
         let mut futures = Vec::new();
+        let mut queued = Vec::new();
         for (id, storrent) in db.torrents.into_iter() {
-            let trackers: Vec<ByteBufOwned> = storrent
-                .trackers
+            if storrent.is_queued {
+                queued.push(id);
+            }
+            let tiers = if storrent.tracker_tiers.is_empty() {
+                storrent.trackers.into_iter().map(|t| vec![t]).collect()
+            } else {
+                storrent.tracker_tiers
+            };
+            let announce_list: Vec<Vec<ByteBufOwned>> = tiers
                 .into_iter()
-                .map(|t| ByteBufOwned::from(t.into_bytes()))
+                .map(|tier| {
+                    tier.into_iter()
+                        .map(|t| ByteBufOwned::from(t.into_bytes()))
+                        .collect()
+                })
                 .collect();
             let info = TorrentMetaV1Owned {
-                announce: trackers.first().cloned(),
-                announce_list: vec![trackers],
+                announce: announce_list.iter().flatten().next().cloned(),
+                announce_list,
                 info: storrent.info,
                 comment: None,
                 created_by: None,
@@ -733,7 +1450,18 @@ impl Session {
                 publisher: None,
                 publisher_url: None,
                 creation_date: None,
+                url_list: storrent
+                    .webseeds
+                    .into_iter()
+                    .map(|u| ByteBufOwned::from(u.into_bytes()))
+                    .collect(),
+                piece_layers: storrent.piece_layers,
                 info_hash: Id20::from_str(&storrent.info_hash)?,
+                info_hash_v2: storrent
+                    .info_hash_v2
+                    .as_deref()
+                    .map(Id32::from_str)
+                    .transpose()?,
             };
             futures.push({
                 let session = self.clone();
@@ -742,7 +1470,7 @@ impl Session {
                         .add_torrent(
                             AddTorrent::TorrentInfo(Box::new(info)),
                             Some(AddTorrentOptions {
-                                paused: storrent.is_paused,
+                                paused: storrent.is_paused || storrent.is_queued,
                                 output_folder: Some(
                                     storrent
                                         .output_folder
@@ -751,8 +1479,18 @@ impl Session {
                                         .to_owned(),
                                 ),
                                 only_files: storrent.only_files,
+                                socket_binding: storrent.socket_binding,
+                                upload_slots: storrent.upload_slots,
+                                upload_rate_limit: storrent.upload_rate_limit,
+                                sequential: storrent.sequential,
+                                file_priorities: storrent.file_priorities,
+                                head_tail_files: Some(storrent.head_tail_files),
+                                hooks: storrent.hooks,
+                                renamed_files: Some(storrent.renamed_files),
+                                category: storrent.category,
                                 overwrite: true,
                                 preferred_id: Some(id),
+                                partial_pieces: Some(storrent.partial_pieces),
                                 ..Default::default()
                             }),
                         )
@@ -765,6 +1503,8 @@ impl Session {
             });
         }
         futures::future::join_all(futures).await;
+        queued.sort_unstable();
+        self.db.write().queued.extend(queued);
         Ok(())
     }
 
@@ -796,18 +1536,38 @@ impl Session {
         callback(&mut self.db.read().torrents.iter().map(|(id, t)| (*id, t)))
     }
 
+    /// Iterate over the peers of all live torrents in the session.
+    ///
+    /// The peers are snapshotted upfront, so no session or torrent locks are held
+    /// while the returned iterator is consumed.
+    pub fn iter_peers(&self) -> impl Iterator<Item = (TorrentId, PeerHandle, PeerStats)> {
+        let torrents = self.with_torrents(|torrents| {
+            torrents
+                .filter_map(|(id, t)| Some((id, t.live()?)))
+                .collect::<Vec<_>>()
+        });
+        torrents.into_iter().flat_map(|(id, live)| {
+            live.iter_peer_stats()
+                .map(move |(addr, stats)| (id, addr, stats))
+        })
+    }
+
     /// Add a torrent to the session.
     #[inline(never)]
     pub fn add_torrent<'a>(
         self: &'a Arc<Self>,
         add: AddTorrent<'a>,
         opts: Option<AddTorrentOptions>,
-    ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
+    ) -> BoxFuture<'a, crate::error::Result<AddTorrentResponse>> {
         async move {
             // Magnet links are different in that we first need to discover the metadata.
             let span = error_span!("add_torrent");
             let _ = span.enter();
 
+            if self.is_shutting_down() {
+                return Err(Error::ShuttingDown.into());
+            }
+
             let opts = opts.unwrap_or_default();
 
             let paused = opts.list_only || opts.paused;
@@ -818,19 +1578,38 @@ impl Session {
             // into a torrent file by connecting to peers that support extended handshakes.
             // So we must discover at least one peer and connect to it to be able to proceed further.
 
-            let (info_hash, info, trackers, peer_rx, initial_peers) = match add {
+            let (
+                info_hash,
+                info_hash_v2,
+                info,
+                piece_layers,
+                trackers,
+                webseeds,
+                peer_rx,
+                initial_peers,
+            ) = match add {
                 AddTorrent::Url(magnet) if magnet.starts_with("magnet:") => {
                     let magnet = Magnet::parse(&magnet)
-                        .context("provided path is not a valid magnet URL")?;
+                        .context("provided path is not a valid magnet URL")
+                        .context(ErrorKind::InvalidTorrent)?;
                     let info_hash = magnet
                         .as_id20()
-                        .context("magnet link didn't contain a BTv1 infohash")?;
+                        .context("magnet link didn't contain a BTv1 infohash")
+                        .context(ErrorKind::InvalidTorrent)?;
 
+                    // Magnet links have no tiers, announce to all the trackers.
+                    let trackers = magnet
+                        .trackers
+                        .into_iter()
+                        .unique()
+                        .map(|t| vec![t])
+                        .collect::<Vec<_>>();
                     let peer_rx = self.make_peer_rx(
                         info_hash,
-                        magnet.trackers.clone(),
+                        trackers.clone(),
                         announce_port,
                         opts.force_tracker_interval,
+                        opts.socket_binding.as_ref(),
                     )?;
                     let peer_rx = match peer_rx {
                         Some(peer_rx) => peer_rx,
@@ -849,14 +1628,20 @@ impl Session {
                     {
                         ReadMetainfoResult::Found { info, rx, seen } => (info, rx, seen),
                         ReadMetainfoResult::ChannelClosed { .. } => {
-                            bail!("DHT died, no way to discover torrent metainfo")
+                            return Err(anyhow::anyhow!(
+                                "DHT died, no way to discover torrent metainfo"
+                            )
+                            .context(ErrorKind::Protocol))
                         }
                     };
                     debug!(?info, "received result from DHT");
                     (
                         info_hash,
+                        None,
                         info,
-                        magnet.trackers.into_iter().unique().collect(),
+                        None,
+                        trackers,
+                        Vec::new(),
                         Some(peer_rx),
                         initial_peers,
                     )
@@ -869,24 +1654,44 @@ impl Session {
                             torrent_from_url(&url).await?
                         }
                         AddTorrent::Url(url) => {
-                            bail!(
+                            return Err(anyhow::anyhow!(
                                 "unsupported URL {:?}. Supporting magnet:, http:, and https",
                                 url
                             )
+                            .context(ErrorKind::InvalidTorrent))
                         }
-                        AddTorrent::TorrentFileBytes(bytes) => {
-                            torrent_from_bytes(&bytes).context("error decoding torrent")?
-                        }
+                        AddTorrent::TorrentFileBytes(bytes) => torrent_from_bytes(&bytes)
+                            .context("error decoding torrent")
+                            .context(ErrorKind::InvalidTorrent)?,
                         AddTorrent::TorrentInfo(t) => *t,
                     };
 
+                    let mut seen_trackers = HashSet::new();
                     let trackers = torrent
-                        .iter_announce()
+                        .iter_announce_tiers()
+                        .map(|tier| {
+                            tier.iter()
+                                .filter_map(|tracker| match std::str::from_utf8(tracker.as_ref()) {
+                                    Ok(url) => Some(url.to_owned()),
+                                    Err(_) => {
+                                        warn!("cannot parse tracker url as utf-8, ignoring");
+                                        None
+                                    }
+                                })
+                                .filter(|url| seen_trackers.insert(url.clone()))
+                                .collect::<Vec<_>>()
+                        })
+                        .filter(|tier| !tier.is_empty())
+                        .collect::<Vec<_>>();
+
+                    let webseeds = torrent
+                        .url_list
+                        .iter()
                         .unique()
-                        .filter_map(|tracker| match std::str::from_utf8(tracker.as_ref()) {
+                        .filter_map(|url| match std::str::from_utf8(url.as_ref()) {
                             Ok(url) => Some(url.to_owned()),
                             Err(_) => {
-                                warn!("cannot parse tracker url as utf-8, ignoring");
+                                warn!("cannot parse web seed url as utf-8, ignoring");
                                 None
                             }
                         })
@@ -900,18 +1705,23 @@ impl Session {
                             trackers.clone(),
                             announce_port,
                             opts.force_tracker_interval,
+                            opts.socket_binding.as_ref(),
                         )?
                     };
 
                     (
                         torrent.info_hash,
+                        torrent.info_hash_v2,
                         torrent.info,
+                        torrent.piece_layers,
                         trackers,
+                        webseeds,
                         peer_rx,
                         opts.initial_peers
                             .clone()
                             .unwrap_or_default()
                             .into_iter()
+                            .map(|addr| (addr, PeerOrigin::Manual))
                             .collect(),
                     )
                 }
@@ -919,14 +1729,18 @@ impl Session {
 
             self.main_torrent_info(
                 info_hash,
+                info_hash_v2,
                 info,
+                piece_layers,
                 trackers,
+                webseeds,
                 peer_rx,
                 initial_peers.into_iter().collect(),
                 opts,
             )
             .await
         }
+        .map(|r| r.map_err(Error::from))
         .boxed()
     }
 
@@ -957,13 +1771,17 @@ impl Session {
         Ok::<_, anyhow::Error>(Some(PathBuf::from(longest)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn main_torrent_info(
         &self,
         info_hash: Id20,
+        info_hash_v2: Option<Id32>,
         info: TorrentMetaV1Info<ByteBufOwned>,
-        trackers: Vec<String>,
+        piece_layers: Option<PieceLayers<ByteBufOwned>>,
+        trackers: Vec<Vec<String>>,
+        webseeds: Vec<String>,
         peer_rx: Option<PeerStream>,
-        initial_peers: Vec<SocketAddr>,
+        initial_peers: Vec<(SocketAddr, PeerOrigin)>,
         opts: AddTorrentOptions,
     ) -> anyhow::Result<AddTorrentResponse> {
         debug!("Torrent info: {:#?}", &info);
@@ -991,23 +1809,102 @@ impl Session {
                 info,
                 only_files,
                 output_folder,
-                seen_peers: initial_peers,
+                seen_peers: initial_peers.into_iter().map(|(addr, _)| addr).collect(),
             }));
         }
 
         let mut builder = ManagedTorrentBuilder::new(info, info_hash, output_folder.clone());
         builder
             .overwrite(opts.overwrite)
+            .direct_io(self.direct_io)
+            .mmap(self.mmap)
+            .preallocation(self.preallocation)
+            .part_files(self.part_files)
+            .write_cache_size(self.write_cache_size)
             .spawner(self.spawner)
+            .cpu_pool(self.cpu_pool.clone())
+            .file_pool(self.file_pool.clone())
             .trackers(trackers)
+            .webseeds(webseeds)
             .peer_id(self.peer_id);
 
+        if let Some(info_hash_v2) = info_hash_v2 {
+            builder.info_hash_v2(info_hash_v2);
+        }
+        if let Some(piece_layers) = piece_layers {
+            builder.piece_layers(piece_layers);
+        }
         if let Some(only_files) = only_files {
             builder.only_files(only_files);
         }
         if let Some(interval) = opts.force_tracker_interval {
             builder.force_tracker_interval(interval);
         }
+        if let Some(dht) = self.dht.clone() {
+            builder.dht(dht);
+        }
+        if let Some(binding) = opts.socket_binding.filter(|b| !b.is_empty()) {
+            builder.socket_binding(binding);
+        }
+        if let Some(binding) = self.socket_binding.clone() {
+            builder.session_socket_binding(binding);
+        }
+        builder.sequential(opts.sequential);
+        if let Some(priorities) = opts.file_priorities {
+            builder.file_priorities(priorities);
+        }
+        if let Some(files) = opts.head_tail_files {
+            builder.head_tail_files(files);
+        }
+        if let Some(renamed_files) = opts.renamed_files {
+            builder.renamed_files(renamed_files);
+        }
+        if let Some(category) = opts.category {
+            builder.category(category);
+        }
+        if let Some(picker) = opts.piece_picker {
+            builder.piece_picker(picker);
+        }
+        if let Some(partial_pieces) = opts.partial_pieces {
+            builder.partial_pieces(partial_pieces);
+        }
+        if let Some(upload_slots) = opts.upload_slots {
+            builder.upload_slots(upload_slots);
+        }
+        if let Some(bps) = opts.upload_rate_limit {
+            builder.upload_rate_limit(bps);
+        }
+        if let Some(limit) = self.upload_rate_limit.clone() {
+            builder.session_upload_rate_limit(limit);
+        }
+        if let Some(limit) = self.peer_limit_per_torrent {
+            builder.peer_limit(limit);
+        }
+        if let Some(sem) = self.peer_semaphore.clone() {
+            builder.session_peer_semaphore(sem);
+        }
+        if let Some(sem) = self.half_open_semaphore.clone() {
+            builder.half_open_semaphore(sem);
+        }
+        if let Some(depth) = self.peer_request_queue_depth {
+            builder.peer_request_queue_depth(depth);
+        }
+        if let Some(pieces) = self.stream_readahead_pieces {
+            builder.stream_readahead_pieces(pieces);
+        }
+        if let Some(size) = self.max_request_block_size {
+            builder.max_request_block_size(size);
+        }
+        if let Some(hooks) = self.hooks.clone() {
+            builder.session_hooks(hooks);
+        }
+        if let Some(hooks) = opts.hooks.filter(|h| !h.is_empty()) {
+            builder.hooks(hooks);
+        }
+        if let Some(extensions) = self.extensions.clone() {
+            builder.extensions(extensions);
+        }
+        builder.events(self.events.clone());
 
         let peer_opts = self.merge_peer_opts(opts.peer_opts);
 
@@ -1019,6 +1916,29 @@ impl Session {
             builder.peer_read_write_timeout(t);
         }
 
+        if let Some(t) = peer_opts.keep_alive_interval {
+            builder.peer_keep_alive_interval(t);
+        }
+
+        if let Some(t) = peer_opts.idle_timeout {
+            builder.peer_idle_timeout(t);
+        }
+
+        if let Some(t) = peer_opts.inactivity_timeout {
+            builder.peer_inactivity_timeout(t);
+        }
+
+        if let Some(t) = peer_opts.transport {
+            builder.peer_transport(t);
+        }
+
+        if let Some(utp_socket) = self.utp_socket.clone() {
+            builder.utp_socket(utp_socket);
+        }
+
+        // With queueing, the torrent is started once there's a free slot for it.
+        let queued = !opts.paused && self.queue_limits.is_enabled();
+
         let (managed_torrent, id) = {
             let mut g = self.db.write();
             if let Some((id, handle)) = g.torrents.iter().find(|(_, t)| t.info_hash() == info_hash)
@@ -1029,6 +1949,9 @@ impl Session {
             let managed_torrent =
                 builder.build(error_span!(parent: None, "torrent", id = next_id))?;
             let id = g.add_torrent(managed_torrent.clone(), opts.preferred_id);
+            if queued {
+                g.queued.push_back(id);
+            }
             (managed_torrent, id)
         };
 
@@ -1047,10 +1970,28 @@ impl Session {
             let _ = span.enter();
 
             managed_torrent
-                .start(peer_rx, opts.paused, self.cancellation_token.child_token())
+                .start(
+                    peer_rx,
+                    opts.paused || queued,
+                    self.cancellation_token.child_token(),
+                )
                 .context("error starting torrent")?;
         }
 
+        self.emit(Event::TorrentAdded {
+            info_hash,
+            name: managed_torrent
+                .info()
+                .info
+                .name
+                .as_ref()
+                .map(|n| n.to_string()),
+        });
+        // Torrents restored from the session come with their old id, don't run it for them again.
+        if opts.preferred_id.is_none() {
+            managed_torrent.info().run_hook(HookEvent::Added, None);
+        }
+
         Ok(AddTorrentResponse::Added(id, managed_torrent))
     }
 
@@ -1058,13 +1999,33 @@ impl Session {
         self.db.read().torrents.get(&id).cloned()
     }
 
-    pub fn delete(&self, id: TorrentId, delete_files: bool) -> anyhow::Result<()> {
-        let removed = self
-            .db
-            .write()
+    pub fn get_by_info_hash(&self, info_hash: Id20) -> Option<(TorrentId, ManagedTorrentHandle)> {
+        self.db
+            .read()
             .torrents
-            .remove(&id)
-            .with_context(|| format!("torrent with id {} did not exist", id))?;
+            .iter()
+            .find(|(_, t)| t.info_hash() == info_hash)
+            .map(|(id, t)| (*id, t.clone()))
+    }
+
+    /// A snapshot of the managed torrents, ordered by id.
+    pub fn torrents(&self) -> Vec<(TorrentId, ManagedTorrentHandle)> {
+        let mut torrents =
+            self.with_torrents(|it| it.map(|(id, t)| (id, t.clone())).collect::<Vec<_>>());
+        torrents.sort_unstable_by_key(|(id, _)| *id);
+        torrents
+    }
+
+    pub fn torrent_count(&self) -> usize {
+        self.db.read().torrents.len()
+    }
+
+    pub fn delete(&self, id: TorrentId, delete_files: bool) -> crate::error::Result<()> {
+        let removed = {
+            let mut g = self.db.write();
+            g.queued.retain(|q| *q != id);
+            g.torrents.remove(&id).ok_or(Error::TorrentNotFound(id))?
+        };
 
         let paused = removed
             .with_state_mut(|s| {
@@ -1078,15 +2039,20 @@ impl Session {
             .context("error pausing torrent");
 
         match (paused, delete_files) {
-            (Err(e), true) => return Err(e).context("torrent deleted, but could not delete files"),
+            (Err(e), true) => {
+                return Err(e
+                    .context("torrent deleted, but could not delete files")
+                    .into())
+            }
             (Err(e), false) => {
                 warn!(error=?e, "error deleting torrent cleanly");
             }
             (Ok(Some(paused)), true) => {
                 for file in paused.files.iter() {
-                    drop(file.take()?);
-                    if let Err(e) = std::fs::remove_file(&file.filename) {
-                        warn!(?file.filename, error=?e, "could not delete file");
+                    file.close();
+                    let filename = file.filename();
+                    if let Err(e) = std::fs::remove_file(&filename) {
+                        warn!(?filename, error=?e, "could not delete file");
                     }
                 }
             }
@@ -1099,16 +2065,18 @@ impl Session {
     fn make_peer_rx(
         self: &Arc<Self>,
         info_hash: Id20,
-        trackers: Vec<String>,
+        trackers: Vec<Vec<String>>,
         announce_port: Option<u16>,
         force_tracker_interval: Option<Duration>,
+        socket_binding: Option<&PeerSocketBinding>,
     ) -> anyhow::Result<Option<PeerStream>> {
         let announce_port = announce_port.or(self.tcp_listen_port);
         let dht_rx = self
             .dht
             .as_ref()
             .map(|dht| dht.get_peers(info_hash, announce_port))
-            .transpose()?;
+            .transpose()?
+            .map(|rx| rx.map(|addr| (addr, PeerOrigin::Dht)));
 
         let peer_rx_stats = PeerRxTorrentInfo {
             info_hash,
@@ -1121,17 +2089,92 @@ impl Session {
             Box::new(peer_rx_stats),
             force_tracker_interval,
             announce_port,
-        );
+            self.tracker_socket_binding(socket_binding),
+        )
+        .map(|rx| rx.map(|addr| (addr, PeerOrigin::Tracker)));
 
         Ok(merge_two_optional_streams(dht_rx, peer_rx))
     }
 
-    pub fn unpause(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> anyhow::Result<()> {
+    // The torrent's binding if it has one, the session's otherwise.
+    fn tracker_socket_binding(&self, torrent: Option<&PeerSocketBinding>) -> SocketBinding {
+        torrent
+            .filter(|b| !b.is_empty())
+            .or(self.socket_binding.as_ref())
+            .map(|b| b.socket_binding())
+            .unwrap_or_default()
+    }
+
+    pub fn pause(&self, handle: &ManagedTorrentHandle) -> crate::error::Result<()> {
+        // Queued torrents are already paused, only take them out of the queue.
+        if let Some(id) = self.id_of(handle) {
+            let mut g = self.db.write();
+            let len = g.queued.len();
+            g.queued.retain(|q| *q != id);
+            if g.queued.len() != len {
+                return Ok(());
+            }
+        }
+        Ok(handle.pause()?)
+    }
+
+    /// Start a paused torrent. With queueing enabled, it's put to the front of the queue
+    /// instead, and starts right away only if there's a free slot.
+    pub fn unpause(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> crate::error::Result<()> {
+        let is_paused = handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_)));
+        if !(self.queue_limits.is_enabled() && is_paused) {
+            return Ok(self.resume(handle)?);
+        }
+        let id = self
+            .id_of(handle)
+            .context("torrent not found in the session")?;
+        {
+            let mut g = self.db.write();
+            if g.queued.contains(&id) {
+                return Ok(());
+            }
+            g.queued.push_front(id);
+        }
+        self.check_queue();
+        Ok(())
+    }
+
+    /// Forget what was downloaded, and check all the files of the torrent again, e.g. after
+    /// they were changed on disk. A live torrent is paused for the check, and resumed after it.
+    pub fn recheck(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> crate::error::Result<()> {
+        let was_live = handle.live().is_some();
+        if was_live {
+            handle.pause()?;
+        }
+        handle.reset_for_recheck()?;
+        let peer_rx = if was_live {
+            self.make_peer_rx(
+                handle.info_hash(),
+                handle.info().tracker_tiers.clone(),
+                self.tcp_listen_port,
+                handle.info().options.force_tracker_interval,
+                handle.info().options.socket_binding.as_ref(),
+            )?
+        } else {
+            None
+        };
+        Ok(handle.start(peer_rx, !was_live, self.cancellation_token.child_token())?)
+    }
+
+    fn id_of(&self, handle: &ManagedTorrentHandle) -> Option<TorrentId> {
+        self.get_by_info_hash(handle.info_hash()).map(|(id, _)| id)
+    }
+
+    fn resume(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> anyhow::Result<()> {
+        if self.is_shutting_down() {
+            return Err(Error::ShuttingDown.into());
+        }
         let peer_rx = self.make_peer_rx(
             handle.info_hash(),
-            handle.info().trackers.clone().into_iter().collect(),
+            handle.info().tracker_tiers.clone(),
             self.tcp_listen_port,
             handle.info().options.force_tracker_interval,
+            handle.info().options.socket_binding.as_ref(),
         )?;
         handle.start(peer_rx, false, self.cancellation_token.child_token())?;
         Ok(())
@@ -1141,7 +2184,7 @@ impl Session {
         self: &Arc<Self>,
         handle: &ManagedTorrentHandle,
         only_files: &HashSet<usize>,
-    ) -> anyhow::Result<()> {
+    ) -> crate::error::Result<()> {
         handle.update_only_files(only_files)?;
         Ok(())
     }
@@ -1149,6 +2192,397 @@ impl Session {
     pub fn tcp_listen_port(&self) -> Option<u16> {
         self.tcp_listen_port
     }
+
+    /// The peer id the session uses for all its torrents.
+    pub fn peer_id(&self) -> Id20 {
+        self.peer_id
+    }
+
+    pub fn output_folder(&self) -> &Path {
+        &self.output_folder
+    }
+
+    /// Subscribe to an RSS or Atom feed. The feed is checked periodically, and new entries
+    /// matching the subscription's filters are added as torrents.
+    pub fn add_feed(
+        self: &Arc<Self>,
+        subscription: FeedSubscription,
+    ) -> crate::error::Result<FeedId> {
+        Ok(self.add_feed_inner(subscription, Default::default(), None)?)
+    }
+
+    fn add_feed_inner(
+        self: &Arc<Self>,
+        subscription: FeedSubscription,
+        seen: HashSet<String>,
+        preferred_id: Option<FeedId>,
+    ) -> anyhow::Result<FeedId> {
+        FeedFilter::new(&subscription)?;
+        let token = self.cancellation_token.child_token();
+        let id = {
+            let mut g = self.db.write();
+            let id = match preferred_id {
+                Some(id) if !g.feeds.contains_key(&id) => id,
+                _ => g.next_feed_id,
+            };
+            g.next_feed_id = g.next_feed_id.max(id + 1);
+            g.feeds.insert(
+                id,
+                Feed {
+                    subscription,
+                    seen,
+                    _cancel: token.clone().drop_guard(),
+                },
+            );
+            id
+        };
+        spawn_with_cancel(
+            error_span!(parent: None, "feed", id),
+            token,
+            self.clone().task_feed(id),
+        );
+        Ok(id)
+    }
+
+    pub fn remove_feed(&self, id: FeedId) -> crate::error::Result<()> {
+        self.db
+            .write()
+            .feeds
+            .remove(&id)
+            .with_context(|| format!("feed with id {id} did not exist"))?;
+        Ok(())
+    }
+
+    /// The subscribed feeds, ordered by id.
+    pub fn feeds(&self) -> Vec<(FeedId, FeedSubscription)> {
+        let mut feeds = self
+            .db
+            .read()
+            .feeds
+            .iter()
+            .map(|(id, f)| (*id, f.subscription.clone()))
+            .collect::<Vec<_>>();
+        feeds.sort_unstable_by_key(|(id, _)| *id);
+        feeds
+    }
+
+    async fn task_feed(self: Arc<Self>, id: FeedId) -> anyhow::Result<()> {
+        let session = Arc::downgrade(&self);
+        drop(self);
+
+        loop {
+            let session = match session.upgrade() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            let subscription = match session.db.read().feeds.get(&id) {
+                Some(feed) => feed.subscription.clone(),
+                None => return Ok(()),
+            };
+            if let Err(e) = session.poll_feed(id, &subscription).await {
+                warn!(url = %subscription.url, "error checking feed: {e:#}");
+            }
+            drop(session);
+            tokio::time::sleep(subscription.poll_interval()).await;
+        }
+    }
+
+    async fn poll_feed(
+        self: &Arc<Self>,
+        id: FeedId,
+        subscription: &FeedSubscription,
+    ) -> anyhow::Result<()> {
+        let filter = FeedFilter::new(subscription)?;
+        let response = reqwest::get(&subscription.url)
+            .await
+            .context("error fetching feed")?;
+        if !response.status().is_success() {
+            bail!("GET {} returned {}", subscription.url, response.status())
+        }
+        let body = response.bytes().await.context("error reading feed")?;
+        let entries = feeds::parse_feed(&body)?;
+
+        for entry in entries.iter() {
+            let seen = match self.db.read().feeds.get(&id) {
+                Some(feed) => feed.seen.contains(&entry.id),
+                None => return Ok(()),
+            };
+            if seen || !filter.matches(&entry.title) {
+                continue;
+            }
+            let opts = AddTorrentOptions {
+                output_folder: subscription.output_folder.clone(),
+                category: subscription.category.clone(),
+                ..Default::default()
+            };
+            match self
+                .add_torrent(AddTorrent::from_url(entry.url.as_str()), Some(opts))
+                .await
+            {
+                Ok(_) => info!(title = %entry.title, "added torrent from feed"),
+                // Not marked as seen, so it's tried again next time.
+                Err(e) => {
+                    warn!(title = %entry.title, "error adding torrent from feed: {e:#}");
+                    continue;
+                }
+            }
+            if let Some(feed) = self.db.write().feeds.get_mut(&id) {
+                feed.seen.insert(entry.id.clone());
+            }
+        }
+
+        // Entries that dropped out of the feed won't come back, no need to remember them.
+        if let Some(feed) = self.db.write().feeds.get_mut(&id) {
+            feed.seen
+                .retain(|seen| entries.iter().any(|entry| &entry.id == seen));
+        }
+        Ok(())
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Subscribe to the events of all the torrents in the session. Subscribers that fall behind
+    /// miss the oldest events, and get a "Lagged" error with how many they missed.
+    pub fn subscribe(&self) -> BroadcastStream<Event> {
+        BroadcastStream::new(self.events.subscribe())
+    }
+
+    pub(crate) fn tracker_announce_errors(&self) -> u64 {
+        self.tracker_announce_errors.load(Ordering::Relaxed)
+    }
+
+    fn emit(&self, event: Event) {
+        // Fails only if there are no subscribers.
+        let _ = self.events.send(event);
+    }
+
+    /// Stop the session gracefully. Incoming peers aren't accepted anymore, the session is
+    /// saved, and all live torrents are paused and announced as stopped to their trackers.
+    /// Waits up to "timeout" for all their peer tasks to exit, then flushes their files to disk.
+    ///
+    /// All the session's background tasks are stopped, so it can't be used afterwards.
+    pub async fn shutdown(self: &Arc<Self>, timeout: Duration) -> crate::error::Result<()> {
+        use tracker_comms::TorrentStatsProvider;
+
+        if self.shutting_down.swap(true, Ordering::Relaxed) {
+            return Err(Error::ShuttingDown);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        // Save before pausing, so that the torrents are restored in the state they were in.
+        if self.persistence {
+            if let Err(e) = self.dump_to_disk() {
+                error!("error dumping session to disk: {:?}", e);
+            }
+        }
+
+        let mut stopped = Vec::new();
+        for (id, handle) in self.torrents() {
+            let live = match handle.live() {
+                Some(live) => Arc::downgrade(&live),
+                None => continue,
+            };
+            let mut stats = PeerRxTorrentInfo {
+                info_hash: handle.info_hash(),
+                session: self.clone(),
+            }
+            .get();
+            stats.torrent_state = tracker_comms::TrackerCommsStatsState::Paused;
+            if let Err(e) = handle.pause() {
+                warn!(id, "error pausing torrent: {e:#}");
+                continue;
+            }
+            stopped.push((handle, live, stats));
+        }
+        info!(torrents = stopped.len(), "shutting down");
+
+        let announces = stopped.iter().flat_map(|(handle, _, stats)| {
+            handle
+                .info()
+                .trackers
+                .iter()
+                .map(move |tracker| async move {
+                    let socket_binding =
+                        self.tracker_socket_binding(handle.info().options.socket_binding.as_ref());
+                    let announce = TrackerComms::announce_once(
+                        handle.info_hash(),
+                        self.peer_id,
+                        tracker,
+                        stats,
+                        self.tcp_listen_port,
+                        &socket_binding,
+                    );
+                    match tokio::time::timeout_at(deadline, announce).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => debug!(tracker = %tracker, "error announcing stop: {e:#}"),
+                        Err(_) => debug!(tracker = %tracker, "timeout announcing stop"),
+                    }
+                })
+        });
+        let remove_port_mappings = async {
+            if let Some(upnp) = self.upnp.as_ref() {
+                if tokio::time::timeout_at(deadline, upnp.remove_all())
+                    .await
+                    .is_err()
+                {
+                    debug!("timeout removing UPnP port mappings");
+                }
+            }
+        };
+        futures::future::join(futures::future::join_all(announces), remove_port_mappings).await;
+
+        // Peer tasks hold the live state until they exit.
+        while stopped.iter().any(|(_, live, _)| live.strong_count() > 0) {
+            if tokio::time::Instant::now() >= deadline {
+                warn!("timed out waiting for torrent tasks to exit");
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let handles = stopped.into_iter().map(|(h, _, _)| h).collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || {
+            for handle in handles {
+                handle.with_state(|s| {
+                    if let ManagedTorrentState::Paused(p) = s {
+                        for file in p.files.iter() {
+                            if let Err(e) = file.sync_all() {
+                                warn!("{e:#}");
+                            }
+                        }
+                    }
+                });
+            }
+        })
+        .await
+        .context("error flushing files")?;
+
+        self.cancellation_token.cancel();
+        Ok(())
+    }
+
+    /// Announce the torrent to all its trackers and to the DHT right away, instead of waiting
+    /// for the next scheduled announce. The peers found are added to the torrent.
+    ///
+    /// Fails with [Error::Tracker] if there's no DHT and none of the trackers answered.
+    pub async fn reannounce(
+        self: &Arc<Self>,
+        handle: &ManagedTorrentHandle,
+    ) -> crate::error::Result<Vec<TrackerAnnounceStatus>> {
+        use tracker_comms::TorrentStatsProvider;
+
+        let live = handle.live().context("torrent is not live")?;
+        let info_hash = handle.info_hash();
+        let announce_port = self.tcp_listen_port;
+
+        if let Some(dht) = self.dht.as_ref() {
+            let mut dht_rx = dht.get_peers(info_hash, announce_port)?;
+            let weak = Arc::downgrade(&live);
+            // A new lookup announces to the closest nodes it finds. The torrent's own DHT stream
+            // keeps running, so this one only needs to live long enough to finish the lookup.
+            live.spawn(
+                error_span!(parent: handle.info().span.clone(), "dht_reannounce"),
+                async move {
+                    let deadline = tokio::time::sleep(DHT_REANNOUNCE_DURATION);
+                    tokio::pin!(deadline);
+                    loop {
+                        let peer = tokio::select! {
+                            _ = &mut deadline => return Ok(()),
+                            peer = dht_rx.next() => peer,
+                        };
+                        match (peer, weak.upgrade()) {
+                            (Some(peer), Some(live)) => {
+                                live.add_peer_if_not_seen(peer, PeerOrigin::Dht)
+                                    .context("torrent closed")?;
+                            }
+                            _ => return Ok(()),
+                        }
+                    }
+                },
+            );
+        }
+
+        let stats = PeerRxTorrentInfo {
+            info_hash,
+            session: self.clone(),
+        }
+        .get();
+        let trackers = handle.info().trackers.iter().cloned().collect::<Vec<_>>();
+        let socket_binding =
+            self.tracker_socket_binding(handle.info().options.socket_binding.as_ref());
+        let responses = futures::future::join_all(trackers.iter().map(|tracker| {
+            tokio::time::timeout(
+                TRACKER_REANNOUNCE_TIMEOUT,
+                TrackerComms::announce_once(
+                    info_hash,
+                    self.peer_id,
+                    tracker,
+                    &stats,
+                    announce_port,
+                    &socket_binding,
+                ),
+            )
+        }))
+        .await;
+
+        let mut results = Vec::with_capacity(trackers.len());
+        let mut last_error = None;
+        for (tracker, response) in trackers.into_iter().zip(responses) {
+            let response = response
+                .context("timeout")
+                .and_then(|r| r)
+                .with_context(|| format!("error announcing to {tracker}"));
+            results.push(match response {
+                Ok(response) => {
+                    handle.info().tracker_stats.on_announce(&tracker, &response);
+                    for peer in response.peers.iter().copied() {
+                        live.add_peer_if_not_seen(peer, PeerOrigin::Tracker)
+                            .context("torrent closed")?;
+                    }
+                    TrackerAnnounceStatus {
+                        tracker,
+                        peers: response.peers.len(),
+                        interval_secs: Some(response.interval.as_secs()),
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    debug!("{e:#}");
+                    handle.info().tracker_stats.on_error(&tracker, &e);
+                    let status = TrackerAnnounceStatus {
+                        tracker,
+                        peers: 0,
+                        interval_secs: None,
+                        error: Some(format!("{e:#}")),
+                    };
+                    last_error = Some(e);
+                    status
+                }
+            });
+        }
+        if let Some(e) = last_error {
+            if self.dht.is_none() && results.iter().all(|r| r.error.is_some()) {
+                return Err(e
+                    .context(ErrorKind::Tracker)
+                    .context("none of the trackers answered")
+                    .into());
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// The result of announcing a torrent to one of its trackers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackerAnnounceStatus {
+    pub tracker: String,
+    /// How many peers the tracker returned.
+    pub peers: usize,
+    /// How long the tracker asked to wait until the next announce.
+    pub interval_secs: Option<u64>,
+    pub error: Option<String>,
 }
 
 // Ad adapter for converting stats into the format that tracker_comms accepts.
@@ -1159,16 +2593,8 @@ struct PeerRxTorrentInfo {
 
 impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
     fn get(&self) -> tracker_comms::TrackerCommsStats {
-        let mt = self.session.with_torrents(|torrents| {
-            for (_, mt) in torrents {
-                if mt.info_hash() == self.info_hash {
-                    return Some(mt.clone());
-                }
-            }
-            None
-        });
-        let mt = match mt {
-            Some(mt) => mt,
+        let mt = match self.session.get_by_info_hash(self.info_hash) {
+            Some((_, mt)) => mt,
             None => {
                 warn!(info_hash=?self.info_hash, "can't find torrent in the session");
                 return Default::default();
@@ -1191,4 +2617,34 @@ impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
             },
         }
     }
+
+    fn on_tracker_announce(
+        &self,
+        tracker: &str,
+        response: &tracker_comms::TrackerAnnounceResponse,
+    ) {
+        if let Some((_, mt)) = self.session.get_by_info_hash(self.info_hash) {
+            mt.info().tracker_stats.on_announce(tracker, response);
+        }
+    }
+
+    fn on_next_announce(&self, tracker: &str, after: Duration) {
+        if let Some((_, mt)) = self.session.get_by_info_hash(self.info_hash) {
+            mt.info().tracker_stats.on_next_announce(tracker, after);
+        }
+    }
+
+    fn on_tracker_error(&self, tracker: &str, error: &anyhow::Error) {
+        if let Some((_, mt)) = self.session.get_by_info_hash(self.info_hash) {
+            mt.info().tracker_stats.on_error(tracker, error);
+        }
+        self.session
+            .tracker_announce_errors
+            .fetch_add(1, Ordering::Relaxed);
+        self.session.emit(Event::TrackerError {
+            info_hash: self.info_hash,
+            tracker: tracker.to_owned(),
+            error: format!("{error:#}"),
+        });
+    }
 }
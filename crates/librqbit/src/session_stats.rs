@@ -0,0 +1,24 @@
+use dht::DhtStats;
+use librqbit_upnp::PortMappingStatus;
+use serde::Serialize;
+
+use crate::torrent_state::live::peers::stats::snapshot::AggregatePeerStats;
+
+#[derive(Serialize)]
+pub struct SessionStatsSnapshot {
+    /// Port mappings on the gateways found with UPnP. Empty if port forwarding is disabled.
+    pub upnp_port_mappings: Vec<PortMappingStatus>,
+    pub torrents: usize,
+    pub live_torrents: usize,
+    /// Download and upload rates of all torrents, bytes per second.
+    pub download_bps: u64,
+    pub upload_bps: u64,
+    /// Peers of all live torrents by state.
+    pub peers: AggregatePeerStats,
+    /// Downloaded bytes buffered in memory and waiting to be written to disk.
+    pub write_cache_bytes: u64,
+    /// Transferred since the session started, including torrents that were removed since.
+    pub fetched_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub dht: Option<DhtStats>,
+}
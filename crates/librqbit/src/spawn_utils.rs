@@ -1,3 +1,8 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::sync::Semaphore;
+
 /// Spawn a future inside a tracing span, while logging it's start,
 /// finish and periodically logging if it's still alive.
 pub fn spawn(
@@ -38,3 +43,79 @@ impl Default for BlockingSpawner {
         Self::new(allow_block_in_place)
     }
 }
+
+/// Runs CPU heavy work, like checking piece hashes, on tokio's blocking threads,
+/// at most one job per CPU at a time. Unlike [`BlockingSpawner`], the caller's
+/// task isn't blocked while the job runs.
+#[derive(Clone, Debug)]
+pub(crate) struct CpuPool {
+    permits: Arc<Semaphore>,
+}
+
+impl CpuPool {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(threads.max(1))),
+        }
+    }
+
+    pub async fn run<F: FnOnce() -> R + Send + 'static, R: Send + 'static>(
+        &self,
+        f: F,
+    ) -> anyhow::Result<R> {
+        let _permit = self.permits.acquire().await?;
+        tokio::task::spawn_blocking(f)
+            .await
+            .context("CPU pool job panicked")
+    }
+}
+
+impl Default for CpuPool {
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::CpuPool;
+
+    #[tokio::test]
+    async fn test_cpu_pool_bounds_concurrency() {
+        let pool = CpuPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let jobs = (0..8usize).map(|i| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            })
+        });
+        let results = futures::future::try_join_all(jobs).await.unwrap();
+
+        assert_eq!(results, (0..8).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_cpu_pool_job_panic() {
+        let pool = CpuPool::new(1);
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+        // The permit of the panicked job is released.
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+    }
+}
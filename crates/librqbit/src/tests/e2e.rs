@@ -72,6 +72,26 @@ async fn test_e2e() {
                         peer_opts: None,
                         listen_port_range: Some(15100..17000),
                         enable_upnp_port_forwarding: false,
+                        direct_io: false,
+                        mmap: false,
+                        preallocation: Default::default(),
+                        part_files: false,
+                        write_cache_size: None,
+                        max_open_files: None,
+                        enable_utp: false,
+                        upload_rate_limit: None,
+                        max_active_downloads: None,
+                        max_active_seeds: None,
+                        peer_limit_per_torrent: None,
+                        peer_limit: None,
+                        half_open_limit: None,
+                        peer_request_queue_depth: None,
+                        stream_readahead_pieces: None,
+                        max_request_block_size: None,
+                        hooks: Default::default(),
+                        webhooks: Default::default(),
+                        socket_binding: None,
+                        extensions: Default::default(),
                     },
                 )
                 .await
@@ -149,6 +169,8 @@ async fn test_e2e() {
                 persistence_filename: None,
                 listen_port_range: None,
                 enable_upnp_port_forwarding: false,
+                direct_io: false,
+                enable_utp: false,
                 ..Default::default()
             },
         )
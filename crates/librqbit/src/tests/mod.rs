@@ -1,2 +1,4 @@
 mod e2e;
+mod persistence;
 pub mod test_util;
+mod write_cache;
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use buffers::ByteBufOwned;
+use librqbit_core::{
+    hash_id::{Id20, Id32},
+    merkle::{PieceHasherV2, MERKLE_BLOCK_SIZE},
+    torrent_metainfo::{
+        FileTreeEntry, FileTreeNode, PieceLayers, TorrentMetaV1Info, TorrentMetaV1Owned,
+    },
+};
+
+use crate::{AddTorrent, AddTorrentOptions, Session, SessionOptions};
+
+// A single-file v2-only torrent of 2 pieces.
+fn v2_only_torrent() -> TorrentMetaV1Owned {
+    let data = vec![42u8; MERKLE_BLOCK_SIZE as usize * 2];
+    let layer = data
+        .chunks(MERKLE_BLOCK_SIZE as usize)
+        .flat_map(|piece| {
+            let mut h = PieceHasherV2::new();
+            h.update(piece);
+            h.finish(1).0
+        })
+        .collect::<Vec<_>>();
+    let mut root = PieceHasherV2::new();
+    root.update(&data);
+    let root = root.finish(2);
+
+    let name = ByteBufOwned::from(b"file.bin".to_vec());
+    TorrentMetaV1Owned {
+        announce: None,
+        announce_list: Vec::new(),
+        info: TorrentMetaV1Info {
+            name: Some(name.clone()),
+            pieces: ByteBufOwned::from(Vec::new()),
+            piece_length: MERKLE_BLOCK_SIZE,
+            length: Some(data.len() as u64),
+            md5sum: None,
+            files: None,
+            private: None,
+            meta_version: Some(2),
+            file_tree: Some(FileTreeNode::Dir(vec![(
+                name,
+                FileTreeNode::File(FileTreeEntry {
+                    length: data.len() as u64,
+                    pieces_root: Some(ByteBufOwned::from(root.0.to_vec())),
+                }),
+            )])),
+        },
+        comment: None,
+        created_by: None,
+        encoding: None,
+        publisher: None,
+        publisher_url: None,
+        creation_date: None,
+        url_list: Vec::new(),
+        piece_layers: Some(PieceLayers(vec![(
+            ByteBufOwned::from(root.0.to_vec()),
+            ByteBufOwned::from(layer),
+        )])),
+        info_hash: Id20::new([1; 20]),
+        info_hash_v2: Some(Id32::new([1; 32])),
+    }
+}
+
+#[tokio::test]
+async fn test_reload_keeps_v2_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let opts = || SessionOptions {
+        disable_dht: true,
+        persistence: true,
+        persistence_filename: Some(dir.path().join("session.json")),
+        ..Default::default()
+    };
+    let torrent = v2_only_torrent();
+
+    let session = Session::new_with_opts(dir.path().to_owned(), opts())
+        .await
+        .unwrap();
+    session
+        .add_torrent(
+            AddTorrent::TorrentInfo(Box::new(torrent.clone())),
+            Some(AddTorrentOptions {
+                paused: true,
+                output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+    session.shutdown(Duration::from_secs(1)).await.unwrap();
+    drop(session);
+
+    let session = Session::new_with_opts(dir.path().to_owned(), opts())
+        .await
+        .unwrap();
+    let handle = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some((_, handle)) = session.get_by_info_hash(torrent.info_hash) {
+                return handle;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(handle.info().info_hash_v2, torrent.info_hash_v2);
+    assert_eq!(handle.info().piece_layers, torrent.piece_layers);
+    assert_eq!(
+        handle.info().v2_piece_hashes.as_ref().map(|h| h.len()),
+        Some(2)
+    );
+}
@@ -0,0 +1,127 @@
+use std::{
+    borrow::Cow,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use librqbit_core::Id20;
+use tokio::time::timeout;
+
+use crate::{
+    create_torrent,
+    tests::test_util::{create_new_file_with_random_content, TestPeerMetadata},
+    AddTorrent, AddTorrentOptions, CreateTorrentOptions, Session, SessionOptions,
+};
+
+// Odd server ids don't disconnect or send garbage, see TestPeerMetadata.
+fn peer_id(server_id: u8) -> Id20 {
+    TestPeerMetadata {
+        server_id: server_id * 2 + 1,
+        max_random_sleep_ms: 20,
+    }
+    .as_peer_id()
+}
+
+// A small torrent with many peers, so that pieces get shared between them and chunks of the same
+// buffered piece arrive from several peers at once while it's being completed.
+#[tokio::test(flavor = "multi_thread", worker_threads = 16)]
+async fn test_write_cache_concurrent_chunks() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let piece_length: u32 = 16384 * 4;
+    let dir = tempfile::TempDir::with_prefix("rqbit_write_cache").unwrap();
+    // Not a multiple of the piece length, so one piece spans both files.
+    create_new_file_with_random_content(&dir.path().join("0.data"), 600_000);
+    create_new_file_with_random_content(&dir.path().join("1.data"), 400_000);
+    let torrent_file = create_torrent(
+        dir.path(),
+        CreateTorrentOptions {
+            piece_length: Some(piece_length),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let torrent_file_bytes = torrent_file.as_bytes().unwrap();
+
+    let mut seeders = Vec::new();
+    let mut peers = Vec::new();
+    for i in 0..8 {
+        let session = Session::new_with_opts(
+            std::env::temp_dir().join("does_not_exist"),
+            SessionOptions {
+                disable_dht: true,
+                persistence: false,
+                peer_id: Some(peer_id(i)),
+                listen_port_range: Some(17100..17500),
+                enable_upnp_port_forwarding: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let handle = session
+            .add_torrent(
+                AddTorrent::TorrentFileBytes(Cow::Owned(torrent_file_bytes.clone())),
+                Some(AddTorrentOptions {
+                    overwrite: true,
+                    output_folder: Some(dir.path().to_str().unwrap().to_owned()),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap()
+            .into_handle()
+            .unwrap();
+        timeout(Duration::from_secs(30), handle.wait_until_completed())
+            .await
+            .unwrap()
+            .unwrap();
+        peers.push(SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            session.tcp_listen_port().unwrap(),
+        ));
+        seeders.push(session);
+    }
+
+    let outdir = tempfile::TempDir::with_prefix("rqbit_write_cache_client").unwrap();
+    let session = Session::new_with_opts(
+        outdir.path().to_owned(),
+        SessionOptions {
+            disable_dht: true,
+            persistence: false,
+            peer_id: Some(peer_id(100)),
+            enable_upnp_port_forwarding: false,
+            // Room for a few pieces only, the rest is written to disk chunk by chunk.
+            write_cache_size: Some(piece_length as usize * 4),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_file_bytes.clone())),
+            Some(AddTorrentOptions {
+                initial_peers: Some(peers),
+                output_folder: Some(outdir.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    timeout(Duration::from_secs(60), handle.wait_until_completed())
+        .await
+        .unwrap()
+        .unwrap();
+
+    for name in ["0.data", "1.data"] {
+        assert!(
+            std::fs::read(dir.path().join(name)).unwrap()
+                == std::fs::read(outdir.path().join(name)).unwrap(),
+            "{name} differs"
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use std::{
-    fs::{File, OpenOptions},
-    sync::{atomic::AtomicU64, Arc},
+    fs::OpenOptions,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Instant,
 };
 
@@ -10,20 +13,22 @@ use size_format::SizeFormatterBinary as SF;
 use tracing::{debug, info, warn};
 
 use crate::{
-    chunk_tracker::ChunkTracker, file_ops::FileOps, opened_file::OpenedFile,
+    chunk_tracker::ChunkTracker,
+    disk_space,
+    file_ops::FileOps,
+    opened_file::{part_filename, OpenedFile},
+    preallocate,
     type_aliases::OpenedFiles,
 };
 
 use super::{paused::TorrentStatePaused, ManagedTorrentInfo};
 
-fn ensure_file_length(file: &File, length: u64) -> anyhow::Result<()> {
-    Ok(file.set_len(length)?)
-}
-
 pub struct TorrentStateInitializing {
     pub(crate) meta: Arc<ManagedTorrentInfo>,
     pub(crate) only_files: Option<Vec<usize>>,
     pub(crate) checked_bytes: AtomicU64,
+    // The files are checked again, so they are expected to exist.
+    recheck: bool,
 }
 
 impl TorrentStateInitializing {
@@ -32,6 +37,14 @@ impl TorrentStateInitializing {
             meta,
             only_files,
             checked_bytes: AtomicU64::new(0),
+            recheck: false,
+        }
+    }
+
+    pub fn new_recheck(meta: Arc<ManagedTorrentInfo>, only_files: Option<Vec<usize>>) -> Self {
+        Self {
+            recheck: true,
+            ..Self::new(meta, only_files)
         }
     }
 
@@ -42,16 +55,29 @@ impl TorrentStateInitializing {
 
     pub async fn check(&self) -> anyhow::Result<TorrentStatePaused> {
         let mut files = OpenedFiles::new();
-        for file_details in self.meta.info.iter_file_details(&self.meta.lengths)? {
-            let mut full_path = self.meta.out_dir.clone();
-            let relative_path = file_details
-                .filename
-                .to_pathbuf()
-                .context("error converting file to path")?;
+        for (idx, file_details) in self
+            .meta
+            .info
+            .iter_file_details(&self.meta.lengths)?
+            .enumerate()
+        {
+            let mut full_path = self.meta.out_dir();
+            let relative_path = match self.meta.renamed_file(idx) {
+                Some(p) => p,
+                None => file_details
+                    .filename
+                    .to_pathbuf()
+                    .context("error converting file to path")?,
+            };
             full_path.push(relative_path);
+            // A file that was completed before is used as is.
+            let part = self.meta.options.part_files && !full_path.exists();
+            if part {
+                full_path = part_filename(&full_path);
+            }
 
             std::fs::create_dir_all(full_path.parent().context("bug: no parent")?)?;
-            let file = if self.meta.options.overwrite {
+            let file = if self.meta.options.overwrite || self.recheck {
                 OpenOptions::new()
                     .create(true)
                     .read(true)
@@ -67,21 +93,34 @@ impl TorrentStateInitializing {
                     .with_context(|| format!("error creating {:?}", &full_path))?;
                 OpenOptions::new().read(true).write(true).open(&full_path)?
             };
-            files.push(OpenedFile::new(
-                file,
-                full_path,
-                0,
-                file_details.len,
-                file_details.offset,
-                file_details.pieces,
-            ));
+            files.push(
+                OpenedFile::new(
+                    file,
+                    full_path,
+                    0,
+                    file_details.len,
+                    file_details.offset,
+                    file_details.pieces,
+                    self.meta.options.direct_io,
+                    self.meta.options.mmap,
+                    self.meta.options.preallocation,
+                    self.meta.file_pool.clone(),
+                )
+                .with_part(part),
+            );
         }
 
         debug!("computed lengths: {:?}", &self.meta.lengths);
 
         info!("Doing initial checksum validation, this might take a while...");
         let initial_check_results = self.meta.spawner.spawn_block_in_place(|| {
-            FileOps::new(&self.meta.info, &files, &self.meta.lengths).initial_check(
+            FileOps::new(
+                &self.meta.info,
+                self.meta.v2_piece_hashes.as_deref(),
+                &files,
+                &self.meta.lengths,
+            )
+            .initial_check(
                 self.only_files.as_deref(),
                 &files,
                 &self.meta.lengths,
@@ -96,6 +135,8 @@ impl TorrentStateInitializing {
             SF::new(initial_check_results.selected_bytes)
         );
 
+        self.check_disk_space(&files)?;
+
         // Ensure file lenghts are correct, and reopen read-only.
         self.meta.spawner.spawn_block_in_place(|| {
             for (idx, file) in files.iter().enumerate() {
@@ -106,15 +147,19 @@ impl TorrentStateInitializing {
                     .unwrap_or(true)
                 {
                     let now = Instant::now();
-                    if let Err(err) = ensure_file_length(&file.file.lock(), file.len) {
+                    if let Err(err) = file.lock_file().and_then(|f| {
+                        preallocate::set_file_len(&f, file.len, self.meta.options.preallocation)
+                    }) {
                         warn!(
                             "Error setting length for file {:?} to {}: {:#?}",
-                            file.filename, file.len, err
+                            file.filename(),
+                            file.len,
+                            err
                         );
                     } else {
                         debug!(
                             "Set length for file {:?} to {} in {:?}",
-                            file.filename,
+                            file.filename(),
                             SF::new(file.len),
                             now.elapsed()
                         );
@@ -122,6 +167,9 @@ impl TorrentStateInitializing {
                 }
 
                 file.reopen(true)?;
+                if file.have.load(Ordering::Relaxed) == file.len {
+                    file.finalize()?;
+                }
             }
             Ok::<_, anyhow::Error>(())
         })?;
@@ -137,7 +185,53 @@ impl TorrentStateInitializing {
             info: self.meta.clone(),
             files,
             chunk_tracker,
+            error: None,
         };
         Ok(paused)
     }
+
+    // Fail early if the selected files can't fit on the disk, instead of running out of space
+    // in the middle of the download.
+    fn check_disk_space(&self, files: &OpenedFiles) -> anyhow::Result<()> {
+        let out_dir = self.meta.out_dir();
+        let available = match disk_space::available_space(&out_dir) {
+            Ok(Some(available)) => available,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                warn!("error checking free disk space, skipping the check: {e:#}");
+                return Ok(());
+            }
+        };
+        let mut required = 0u64;
+        for (idx, file) in files.iter().enumerate() {
+            if !self
+                .only_files
+                .as_ref()
+                .map(|v| v.contains(&idx))
+                .unwrap_or(true)
+            {
+                continue;
+            }
+            // Space taken by already downloaded (or preallocated) data is reused.
+            let allocated =
+                disk_space::allocated_bytes(&*file.lock_file()?).with_context(|| {
+                    format!("error checking allocated size of {:?}", file.filename())
+                })?;
+            required += file.len.saturating_sub(allocated);
+        }
+        if required > available {
+            anyhow::bail!(
+                "not enough disk space in {:?}: {} more needed, but only {} available",
+                out_dir,
+                SF::new(required),
+                SF::new(available)
+            );
+        }
+        debug!(
+            "disk space check: need {}, available {}",
+            SF::new(required),
+            SF::new(available)
+        );
+        Ok(())
+    }
 }
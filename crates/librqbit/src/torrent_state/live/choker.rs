@@ -0,0 +1,171 @@
+// Deciding which peers we upload to (the "choking algorithm" from BEP 3).
+//
+// Every round the interested peers that gave us the most data recently (or that took the most
+// from us once we are seeding) get the regular upload slots. One extra "optimistic" slot goes to
+// a random other interested peer and is rotated every few rounds, so that new peers get a chance
+// to show how fast they are.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use peer_binary_protocol::MessageOwned;
+use rand::seq::SliceRandom;
+use tracing::{debug, trace};
+
+use crate::{peer_connection::WriterRequest, type_aliases::PeerHandle};
+
+use super::TorrentStateLive;
+
+pub(crate) const DEFAULT_UPLOAD_SLOTS: usize = 4;
+const CHOKE_ROUND_INTERVAL: Duration = Duration::from_secs(10);
+// Rotate the optimistic unchoke every 30 seconds.
+const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+// Split interested peers into the ones that deserve a regular slot and the rest, fastest first.
+fn rank_peers(
+    mut interested: Vec<(PeerHandle, u64)>,
+    slots: usize,
+) -> (Vec<PeerHandle>, Vec<PeerHandle>) {
+    interested.sort_by_key(|p| std::cmp::Reverse(p.1));
+    let mut regular = interested
+        .into_iter()
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>();
+    let rest = regular.split_off(slots.min(regular.len()));
+    (regular, rest)
+}
+
+impl TorrentStateLive {
+    pub(super) fn upload_slots(&self) -> usize {
+        self.meta
+            .options
+            .upload_slots
+            .unwrap_or(DEFAULT_UPLOAD_SLOTS)
+    }
+
+    // Send choke or unchoke to the peer if it's not in that state yet. Room in the write queue is
+    // reserved first, so that the state we keep only changes together with the message.
+    pub(super) async fn set_peer_choked(&self, addr: PeerHandle, choked: bool) {
+        let tx = match self.peers.with_live(addr, |live| {
+            (live.i_am_choking != choked).then(|| live.tx.clone())
+        }) {
+            Some(Some(tx)) => tx,
+            _ => return,
+        };
+        let permit = match tx.reserve().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        self.peers.with_live_mut(addr, "set_peer_choked", |live| {
+            if live.i_am_choking == choked {
+                return;
+            }
+            live.i_am_choking = choked;
+            let msg = if choked {
+                MessageOwned::Choke
+            } else {
+                MessageOwned::Unchoke
+            };
+            trace!(%addr, ?msg, "changing choke state");
+            permit.send(WriterRequest::Message(msg));
+        });
+    }
+
+    // A peer became interested. Don't make it wait for the next round if there's a free slot.
+    pub(super) async fn maybe_unchoke_interested_peer(&self, addr: PeerHandle) {
+        let unchoked = self
+            .peers
+            .states
+            .iter()
+            .filter(|e| {
+                e.value()
+                    .state
+                    .get_live()
+                    .map(|l| !l.i_am_choking)
+                    .unwrap_or_default()
+            })
+            .count();
+        // +1 for the optimistic unchoke.
+        if unchoked < self.upload_slots() + 1 {
+            self.set_peer_choked(addr, false).await;
+        }
+    }
+
+    pub(super) async fn task_choker(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut previous_totals: HashMap<PeerHandle, u64> = HashMap::new();
+        let mut optimistic: Option<PeerHandle> = None;
+        let mut round = 0u32;
+        loop {
+            tokio::time::sleep(CHOKE_ROUND_INTERVAL).await;
+
+            let seeding = self.is_finished();
+            let mut totals = HashMap::new();
+            let mut live_peers = Vec::new();
+            let mut interested = Vec::new();
+            for e in self.peers.states.iter() {
+                let live = match e.value().state.get_live() {
+                    Some(live) => live,
+                    None => continue,
+                };
+                let counters = &e.value().stats.counters;
+                let total = if seeding {
+                    counters.uploaded_bytes.load(Ordering::Relaxed)
+                } else {
+                    counters.fetched_bytes.load(Ordering::Relaxed)
+                };
+                let recent =
+                    total.saturating_sub(previous_totals.get(e.key()).copied().unwrap_or(0));
+                totals.insert(*e.key(), total);
+                live_peers.push(*e.key());
+                // Don't reward peers that stopped sending us data with upload slots, leave them
+                // to the other peers.
+                if live.peer_interested && (seeding || !live.snubbed) {
+                    interested.push((*e.key(), recent));
+                }
+            }
+            previous_totals = totals;
+
+            let (mut unchoked, rest) = rank_peers(interested, self.upload_slots());
+            let rotate = round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS);
+            if rotate || !optimistic.map(|o| rest.contains(&o)).unwrap_or_default() {
+                optimistic = rest.choose(&mut rand::thread_rng()).copied();
+            }
+            round = round.wrapping_add(1);
+            unchoked.extend(optimistic);
+            debug!(?unchoked, ?optimistic, seeding, "choker round");
+
+            let unchoked = unchoked.into_iter().collect::<HashSet<_>>();
+            futures::future::join_all(
+                live_peers
+                    .into_iter()
+                    .map(|addr| self.set_peer_choked(addr, !unchoked.contains(&addr))),
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::rank_peers;
+
+    #[test]
+    fn test_rank_peers() {
+        let addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        let (regular, rest) = rank_peers(
+            vec![(addr(1), 10), (addr(2), 500), (addr(3), 0), (addr(4), 100)],
+            2,
+        );
+        assert_eq!(regular, vec![addr(2), addr(4)]);
+        assert_eq!(rest, vec![addr(1), addr(3)]);
+
+        let (regular, rest) = rank_peers(vec![(addr(1), 10)], 4);
+        assert_eq!(regular, vec![addr(1)]);
+        assert!(rest.is_empty());
+    }
+}
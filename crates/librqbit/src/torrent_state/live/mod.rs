@@ -29,6 +29,7 @@
 // - connected -> live
 // - ANY STATE -> dead (on error)
 // - ANY STATE -> not_needed (when we don't need to talk to the peer anymore)
+// - ANY STATE -> banned (when it sent too many pieces that failed the hash check). It stays banned.
 //
 // When the peer dies, it's rescheduled with exponential backoff.
 //
@@ -39,15 +40,21 @@
 // > so don't lock them both at the same time at all, or at the worst lock them in the
 // > same order (peers one first, then the global one).
 
+mod choker;
 pub mod peer;
 pub mod peers;
+mod pipeline;
 pub mod stats;
+pub mod streaming;
+mod webseed;
+mod write_cache;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
     net::SocketAddr,
+    num::NonZeroU32,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -57,21 +64,31 @@ use anyhow::{bail, Context};
 use backoff::backoff::Backoff;
 use buffers::{ByteBuf, ByteBufOwned};
 use clone_to_owned::CloneToOwned;
-use futures::{stream::FuturesUnordered, StreamExt};
 use librqbit_core::{
+    compact_bitfield::CompactBitfield,
+    constants::{CHUNK_SIZE, MAX_BLOCK_SIZE},
     hash_id::Id20,
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
     spawn_utils::spawn_with_cancel,
     speed_estimator::SpeedEstimator,
     torrent_metainfo::TorrentMetaV1Info,
 };
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use peer_binary_protocol::{
-    extended::handshake::ExtendedHandshake, Handshake, Message, MessageOwned, Piece, Request,
+    extended::{
+        handshake::ExtendedHandshake,
+        ut_holepunch::{UtHolepunch, UtHolepunchError},
+        ut_pex::{UtPex, UT_PEX_MAX_PEERS},
+        ExtendedMessage, PeerExtendedMessageIds,
+    },
+    Handshake, Message, MessageOwned, Piece, Request,
 };
 use tokio::{
     sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        mpsc::{
+            channel, error::TrySendError, unbounded_channel, UnboundedReceiver, UnboundedSender,
+            WeakSender,
+        },
         Notify, OwnedSemaphorePermit, Semaphore,
     },
     time::timeout,
@@ -80,26 +97,36 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, error_span, info, trace, warn};
 
 use crate::{
-    chunk_tracker::{ChunkMarkingResult, ChunkTracker, HaveNeededSelected},
+    bandwidth_history::{BandwidthHistory, BandwidthHistorySnapshot, BandwidthSample},
+    chunk_tracker::{
+        ChunkMarkingResult, ChunkTracker, ChunkTrackerShared, HaveNeededSelected, PartialPieces,
+    },
+    disk_space,
+    events::Event,
     file_ops::FileOps,
     peer_connection::{
-        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
+        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, PeerTransport, WriterRequest,
+        PEER_UPLOAD_QUEUE_DEPTH, PEER_WRITE_QUEUE_LEN,
     },
+    piece_picker::pick_rarest,
     session::CheckedIncomingConnection,
     torrent_state::{peer::Peer, utils::atomic_inc},
-    type_aliases::{OpenedFiles, PeerHandle, BF},
+    type_aliases::{OpenedFiles, PeerHandle},
 };
 
 use self::{
     peer::{
         stats::{
             atomic::PeerCountersAtomic as AtomicPeerCounters,
-            snapshot::{PeerStatsFilter, PeerStatsSnapshot},
+            snapshot::{PeerStats, PeerStatsFilter, PeerStatsSnapshot},
         },
-        PeerRx, PeerState, PeerTx,
+        LivePeerState, PeerOrigin, PeerRx, PeerState, PeerTx,
     },
-    peers::PeerStates,
+    peers::{stats::snapshot::AggregatePeerStats, PeerStates},
+    pipeline::RequestPipeline,
     stats::{atomic::AtomicStats, snapshot::StatsSnapshot},
+    streaming::{TorrentStreams, DEFAULT_STREAM_READAHEAD_PIECES},
+    write_cache::WriteCache,
 };
 
 use super::{
@@ -108,13 +135,75 @@ use super::{
     ManagedTorrentInfo,
 };
 
+// BEP 11: don't send ut_pex messages more often than once a minute.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Peers that sent chunks of this many pieces that failed the hash check are banned. Not 1, as
+// the other peers that sent chunks of the same piece are blamed too.
+const PEER_BAN_HASH_FAILED_PIECES: u32 = 3;
+
+const DEFAULT_PEER_LIMIT: usize = 128;
+const DEFAULT_PEER_REQUEST_QUEUE_DEPTH: usize = 16;
+// Don't trust peers advertising huge reqq values.
+const MAX_PEER_REQUEST_QUEUE_DEPTH: usize = 250;
+// Peers that didn't send any chunk we requested for this long are considered snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// Requests that weren't answered for this long, while requests sent after them were, are
+// considered lost and requested again.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PEER_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(300);
+// How long to collect verified pieces for before telling peers about them.
+const HAVE_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+// An unfinished torrent that didn't download anything for this long is reported as stalled.
+const STALLED_AFTER: Duration = Duration::from_secs(300);
+// How many peers besides the one a piece was reserved for may share it.
+const SPLIT_MAX_EXTRA_PEERS: usize = 3;
+// How many peers besides the one a piece was reserved for may download it at once in endgame.
+const ENDGAME_MAX_EXTRA_PEERS: usize = 2;
+// Pieces this far along are left to their peer however slow it is, stealing them would waste
+// the requests already in flight for little gain.
+const STEAL_MAX_PIECE_PROGRESS: f64 = 0.8;
+// Only steal pieces from peers this many times slower than us.
+const STEAL_MIN_SPEEDUP: f64 = 2.;
+
+// Held while a peer is connected, the torrent's and the session's limit.
+struct PeerPermit {
+    _torrent: OwnedSemaphorePermit,
+    _session: Option<OwnedSemaphorePermit>,
+}
+
 struct InflightPiece {
     peer: PeerHandle,
     started: Instant,
+    // Other peers downloading the same piece in endgame mode.
+    endgame_peers: Vec<PeerHandle>,
+    // Other peers sharing the piece, see TorrentStateLocked::split_piece.
+    split_peers: Vec<PeerHandle>,
+    // Who requested each chunk, by chunk index, while the piece is shared.
+    claimed_chunks: HashMap<u32, PeerHandle>,
 }
 
-fn make_piece_bitfield(lengths: &Lengths) -> BF {
-    BF::from_boxed_slice(vec![0; lengths.piece_bitfield_bytes()].into_boxed_slice())
+impl InflightPiece {
+    // Everyone downloading the piece except "me".
+    fn other_peers(&self, me: PeerHandle) -> Vec<PeerHandle> {
+        std::iter::once(self.peer)
+            .chain(self.endgame_peers.iter().copied())
+            .chain(self.split_peers.iter().copied())
+            .filter(|p| *p != me)
+            .collect()
+    }
+
+    // Claim the chunk for the peer, so that nobody else sharing the piece requests it. False if
+    // someone else claimed it first.
+    fn claim_chunk(&mut self, chunk: &ChunkInfo, peer: PeerHandle) -> bool {
+        match self.claimed_chunks.entry(chunk.chunk_index) {
+            Entry::Occupied(e) => *e.get() == peer,
+            Entry::Vacant(e) => {
+                e.insert(peer);
+                true
+            }
+        }
+    }
 }
 
 pub(crate) struct TorrentStateLocked {
@@ -125,9 +214,6 @@ pub(crate) struct TorrentStateLocked {
     // At a moment in time, we are expecting a piece from only one peer.
     // inflight_pieces stores this information.
     inflight_pieces: HashMap<ValidPieceIndex, InflightPiece>,
-
-    // If this is None, then it was already used
-    fatal_errors_tx: Option<tokio::sync::oneshot::Sender<anyhow::Error>>,
 }
 
 impl TorrentStateLocked {
@@ -137,11 +223,161 @@ impl TorrentStateLocked {
             .context("chunk tracker empty, torrent was paused")
     }
 
-    fn get_chunks_mut(&mut self) -> anyhow::Result<&mut ChunkTracker> {
+    pub(crate) fn get_chunks_mut(&mut self) -> anyhow::Result<&mut ChunkTracker> {
         self.chunks
             .as_mut()
             .context("chunk tracker empty, torrent was paused")
     }
+
+    // Share the oldest in-flight piece that the peer has with it. Returns the piece and its
+    // chunks that nobody has requested yet, last first, so that they are claimed from the other
+    // end than the peer that reserved the piece does.
+    fn split_piece(
+        &mut self,
+        me: PeerHandle,
+        peer_bitfield: &CompactBitfield,
+    ) -> anyhow::Result<Option<(ValidPieceIndex, Vec<ChunkInfo>)>> {
+        let TorrentStateLocked {
+            chunks,
+            inflight_pieces,
+        } = self;
+        let chunks = chunks
+            .as_ref()
+            .context("chunk tracker empty, torrent was paused")?;
+
+        let mut candidates = inflight_pieces
+            .iter_mut()
+            .filter(|(index, piece)| {
+                piece.peer != me
+                    && !piece.split_peers.contains(&me)
+                    && !piece.endgame_peers.contains(&me)
+                    && piece.split_peers.len() < SPLIT_MAX_EXTRA_PEERS
+                    && peer_bitfield.get(index.get()) == Some(true)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(_, piece)| piece.started);
+
+        for (index, piece) in candidates {
+            let mut unclaimed = chunks
+                .get_lengths()
+                .iter_chunk_infos(*index)
+                .filter(|c| {
+                    !chunks.is_chunk_downloaded(c)
+                        && !piece.claimed_chunks.contains_key(&c.chunk_index)
+                })
+                .collect::<Vec<_>>();
+            if unclaimed.is_empty() {
+                continue;
+            }
+            unclaimed.reverse();
+            debug!(
+                "sharing piece={} with {}, {} chunks left to request",
+                index,
+                piece.peer,
+                unclaimed.len()
+            );
+            piece.split_peers.push(me);
+            return Ok(Some((*index, unclaimed)));
+        }
+        Ok(None)
+    }
+
+    // Once every needed piece is in flight, join downloading the oldest one that the peer has.
+    // Returns the piece and its chunks that are not downloaded yet.
+    fn join_endgame_piece(
+        &mut self,
+        me: PeerHandle,
+        peer_bitfield: &CompactBitfield,
+    ) -> anyhow::Result<Option<(ValidPieceIndex, Vec<ChunkInfo>)>> {
+        let TorrentStateLocked {
+            chunks,
+            inflight_pieces,
+        } = self;
+        let chunks = chunks
+            .as_ref()
+            .context("chunk tracker empty, torrent was paused")?;
+        if chunks.has_queued_pieces() {
+            return Ok(None);
+        }
+
+        let (index, piece) = match inflight_pieces
+            .iter_mut()
+            .filter(|(index, piece)| {
+                piece.peer != me
+                    && !piece.endgame_peers.contains(&me)
+                    && !piece.split_peers.contains(&me)
+                    && piece.endgame_peers.len() < ENDGAME_MAX_EXTRA_PEERS
+                    && peer_bitfield.get(index.get()) == Some(true)
+            })
+            .min_by_key(|(_, piece)| piece.started)
+        {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        let missing = chunks
+            .get_lengths()
+            .iter_chunk_infos(*index)
+            .filter(|c| !chunks.is_chunk_downloaded(c))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        debug!(
+            "endgame: also requesting piece={} from {}, reserved by {}",
+            index, me, piece.peer
+        );
+        piece.endgame_peers.push(me);
+        Ok(Some((*index, missing)))
+    }
+
+    // Pieces that "me" may take over, oldest first: their peer has been downloading them for
+    // "threshold" times longer than we take for a piece, and they aren't almost done yet.
+    fn steal_candidates(
+        &self,
+        me: PeerHandle,
+        my_avg_time: Duration,
+        threshold: f64,
+    ) -> anyhow::Result<Vec<(ValidPieceIndex, PeerHandle, Instant)>> {
+        let chunks = self.get_chunks()?;
+        let mut candidates = self
+            .inflight_pieces
+            .iter()
+            .filter(|(index, piece)| {
+                piece.peer != me
+                    && piece.started.elapsed().as_secs_f64() > my_avg_time.as_secs_f64() * threshold
+                    && chunks.get_piece_progress(**index) < STEAL_MAX_PIECE_PROGRESS
+            })
+            .map(|(index, piece)| (*index, piece.peer, piece.started))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(_, _, started)| *started);
+        Ok(candidates)
+    }
+
+    // Reserve the piece for "me" instead, unless it was finished or stolen meanwhile. The
+    // requests of the peer it's taken from are dropped.
+    fn take_over_piece(
+        &mut self,
+        index: ValidPieceIndex,
+        from: PeerHandle,
+        started: Instant,
+        me: PeerHandle,
+    ) -> bool {
+        let piece = match self
+            .inflight_pieces
+            .get_mut(&index)
+            .filter(|p| p.peer == from && p.started == started)
+        {
+            Some(piece) => piece,
+            None => return false,
+        };
+        piece.peer = me;
+        piece.started = Instant::now();
+        piece.endgame_peers.retain(|p| *p != me);
+        piece.split_peers.retain(|p| *p != me);
+        piece.claimed_chunks.retain(|_, p| *p != from);
+        true
+    }
 }
 
 #[derive(Default)]
@@ -155,7 +391,30 @@ pub struct TorrentStateLive {
     meta: Arc<ManagedTorrentInfo>,
     locked: RwLock<TorrentStateLocked>,
 
-    files: OpenedFiles,
+    // Read without taking "locked", e.g. when serving uploads or reporting stats.
+    chunks_shared: Arc<ChunkTrackerShared>,
+
+    // The peers that sent chunks of each piece being downloaded and how many bytes, to know
+    // whom to blame if it fails the hash check.
+    piece_contributors: Mutex<HashMap<ValidPieceIndex, HashMap<PeerHandle, u64>>>,
+
+    // Chunks of in-flight pieces that weren't written to disk yet. This and piece_contributors
+    // are locked while holding "locked" when chunks are marked downloaded, so that whoever
+    // completes a piece finds all of its chunks. Never lock "locked" while holding them.
+    write_cache: Mutex<WriteCache>,
+
+    // Verified pieces that peers weren't told about yet.
+    pending_haves: Mutex<Vec<ValidPieceIndex>>,
+    pending_haves_notify: Notify,
+
+    // Peers whose interest needs re-evaluating.
+    pending_interest: Mutex<HashSet<PeerHandle>>,
+    pending_interest_notify: Notify,
+
+    // If this is None, then it was already used
+    fatal_errors_tx: Mutex<Option<tokio::sync::oneshot::Sender<anyhow::Error>>>,
+
+    pub(crate) files: OpenedFiles,
 
     stats: AtomicStats,
     lengths: Lengths,
@@ -170,7 +429,11 @@ pub struct TorrentStateLive {
 
     down_speed_estimator: SpeedEstimator,
     up_speed_estimator: SpeedEstimator,
+    bandwidth_history: BandwidthHistory,
     cancellation_token: CancellationToken,
+
+    // Files being read while downloading, their pieces are downloaded first.
+    streams: TorrentStreams,
 }
 
 fn reopen_necessary_files_for_write(ct: &ChunkTracker, files: &OpenedFiles) -> anyhow::Result<()> {
@@ -194,6 +457,7 @@ fn reopen_necessary_files_for_write(ct: &ChunkTracker, files: &OpenedFiles) -> a
             .any(|(selected, have)| *selected && !*have);
         if need_write {
             opened_file.reopen(false)?;
+            opened_file.ensure_len()?;
         }
     }
     Ok(())
@@ -212,29 +476,53 @@ impl TorrentStateLive {
 
         let have_bytes = paused.chunk_tracker.get_hns().have_bytes;
         let lengths = *paused.chunk_tracker.get_lengths();
+        let chunks_shared = paused.chunk_tracker.shared().clone();
 
         reopen_necessary_files_for_write(&paused.chunk_tracker, &paused.files)?;
 
         let state = Arc::new(TorrentStateLive {
             meta: paused.info.clone(),
-            peers: Default::default(),
+            peers: PeerStates::new(lengths.total_pieces()),
             locked: RwLock::new(TorrentStateLocked {
                 chunks: Some(paused.chunk_tracker),
                 inflight_pieces: Default::default(),
-                fatal_errors_tx: Some(fatal_errors_tx),
             }),
+            chunks_shared,
+            piece_contributors: Default::default(),
+            write_cache: Mutex::new(WriteCache::new(
+                paused.info.options.write_cache_size.unwrap_or(0),
+            )),
+            pending_haves: Default::default(),
+            pending_haves_notify: Notify::new(),
+            pending_interest: Default::default(),
+            pending_interest_notify: Notify::new(),
+            fatal_errors_tx: Mutex::new(Some(fatal_errors_tx)),
             files: paused.files,
             stats: AtomicStats {
                 have_bytes: AtomicU64::new(have_bytes),
                 ..Default::default()
             },
             lengths,
-            peer_semaphore: Arc::new(Semaphore::new(128)),
+            peer_semaphore: Arc::new(Semaphore::new(
+                paused
+                    .info
+                    .options
+                    .peer_limit
+                    .map_or(DEFAULT_PEER_LIMIT, |l| l.get()),
+            )),
             peer_queue_tx,
             finished_notify: Notify::new(),
             down_speed_estimator,
             up_speed_estimator,
+            bandwidth_history: Default::default(),
             cancellation_token,
+            streams: TorrentStreams::new(
+                paused
+                    .info
+                    .options
+                    .stream_readahead_pieces
+                    .map_or(DEFAULT_STREAM_READAHEAD_PIECES, |n| n.get()),
+            ),
         });
 
         state.spawn(
@@ -242,6 +530,10 @@ impl TorrentStateLive {
             {
                 let state = Arc::downgrade(&state);
                 async move {
+                    // When the download last made progress, and whether it was reported as
+                    // stalled since.
+                    let mut last_progress = (0, Instant::now());
+                    let mut stalled = false;
                     loop {
                         let state = match state.upgrade() {
                             Some(state) => state,
@@ -250,13 +542,27 @@ impl TorrentStateLive {
                         let now = Instant::now();
                         let stats = state.stats_snapshot();
                         let fetched = stats.fetched_bytes;
-                        let remaining = state.locked.read().get_chunks()?.get_remaining_bytes();
+                        let remaining = state.get_hns().needed_bytes;
+                        if fetched != last_progress.0 || remaining == 0 {
+                            last_progress = (fetched, now);
+                            stalled = false;
+                        } else if !stalled && now - last_progress.1 >= STALLED_AFTER {
+                            stalled = true;
+                            state.meta.emit(Event::TorrentStalled {
+                                info_hash: state.meta.info_hash,
+                                stalled_for_secs: (now - last_progress.1).as_secs(),
+                            });
+                        }
                         state
                             .down_speed_estimator
                             .add_snapshot(fetched, Some(remaining), now);
                         state
                             .up_speed_estimator
                             .add_snapshot(stats.uploaded_bytes, None, now);
+                        state
+                            .bandwidth_history
+                            .add_totals(fetched, stats.uploaded_bytes, now);
+                        state.peers.update_speed_estimators(now);
                         tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
@@ -267,6 +573,28 @@ impl TorrentStateLive {
             error_span!(parent: state.meta.span.clone(), "peer_adder"),
             state.clone().task_peer_adder(peer_queue_rx),
         );
+
+        state.spawn(
+            error_span!(parent: state.meta.span.clone(), "choker"),
+            state.clone().task_choker(),
+        );
+
+        state.spawn(
+            error_span!(parent: state.meta.span.clone(), "transmit_haves"),
+            state.clone().task_transmit_haves(),
+        );
+
+        state.spawn(
+            error_span!(parent: state.meta.span.clone(), "update_interest"),
+            state.clone().task_update_interest(),
+        );
+
+        for url in state.meta.webseeds.iter() {
+            state.spawn(
+                error_span!(parent: state.meta.span.clone(), "webseed", url = url.as_str()),
+                state.clone().task_webseed(url.clone()),
+            );
+        }
         Ok(state)
     }
 
@@ -286,15 +614,24 @@ impl TorrentStateLive {
         &self.up_speed_estimator
     }
 
+    /// Per-second download and upload rates of the last few minutes.
+    pub fn bandwidth_history(&self) -> BandwidthHistorySnapshot {
+        self.bandwidth_history.snapshot()
+    }
+
+    pub(crate) fn last_bandwidth_sample(&self) -> Option<BandwidthSample> {
+        self.bandwidth_history.last()
+    }
+
     pub(crate) fn add_incoming_peer(
         self: &Arc<Self>,
         checked_peer: CheckedIncomingConnection,
     ) -> anyhow::Result<()> {
         use dashmap::mapref::entry::Entry;
-        let (tx, rx) = unbounded_channel();
-        let permit = match self.peer_semaphore.clone().try_acquire_owned() {
-            Ok(permit) => permit,
-            Err(_) => {
+        let (tx, rx) = channel(PEER_WRITE_QUEUE_LEN);
+        let permit = match self.try_acquire_peer_permit() {
+            Some(permit) => permit,
+            None => {
                 warn!("limit of live peers reached, dropping incoming peer");
                 self.peers.with_peer(checked_peer.addr, |p| {
                     atomic_inc(&p.stats.counters.incoming_connections);
@@ -347,15 +684,25 @@ impl TorrentStateLive {
         counters: Arc<AtomicPeerCounters>,
         tx: PeerTx,
         rx: PeerRx,
-        permit: OwnedSemaphorePermit,
+        permit: PeerPermit,
     ) -> anyhow::Result<()> {
         // TODO: bump counters for incoming
         let handler = PeerHandler {
             addr: checked_peer.addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                supports_fast: false,
+                allowed_fast: HashSet::new(),
+                peer_reqq: None,
+                pipeline: self.new_request_pipeline(),
+                rtt_probe: None,
+                last_answered_request: None,
+                timed_out_requests: HashSet::new(),
+            }),
             requests_sem: Semaphore::new(0),
+            queued_uploads: AtomicUsize::new(0),
             state: self.clone(),
             tx,
             counters,
@@ -363,6 +710,8 @@ impl TorrentStateLive {
         let options = PeerConnectionOptions {
             connect_timeout: self.meta.options.peer_connect_timeout,
             read_write_timeout: self.meta.options.peer_read_write_timeout,
+            keep_alive_interval: self.meta.options.peer_keep_alive_interval,
+            idle_timeout: self.meta.options.peer_idle_timeout,
             ..Default::default()
         };
         let peer_connection = PeerConnection::new(
@@ -372,11 +721,19 @@ impl TorrentStateLive {
             &handler,
             Some(options),
             self.meta.spawner,
-        );
+        )
+        .with_upload_limits(self.meta.options.upload_limits())
+        .with_peer_limits(
+            self.peers
+                .with_peer(checked_peer.addr, |p| p.limits.clone())
+                .unwrap_or_default(),
+        )
+        .with_extensions(self.meta.options.extensions.clone(), handler.tx.clone());
         let requester = handler.task_peer_chunk_requester();
 
         let res = tokio::select! {
             r = requester => {r}
+            r = handler.task_evict_if_inactive() => {r}
             r = peer_connection.manage_peer_incoming(
                 rx,
                 checked_peer.read_buf,
@@ -402,21 +759,31 @@ impl TorrentStateLive {
     async fn task_manage_outgoing_peer(
         self: Arc<Self>,
         addr: SocketAddr,
-        permit: OwnedSemaphorePermit,
+        permit: PeerPermit,
     ) -> anyhow::Result<()> {
         let state = self;
         let (rx, tx) = state.peers.mark_peer_connecting(addr)?;
-        let counters = state
+        let (counters, limits) = state
             .peers
-            .with_peer(addr, |p| p.stats.counters.clone())
+            .with_peer(addr, |p| (p.stats.counters.clone(), p.limits.clone()))
             .context("bug: peer not found")?;
 
         let handler = PeerHandler {
             addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                supports_fast: false,
+                allowed_fast: HashSet::new(),
+                peer_reqq: None,
+                pipeline: state.new_request_pipeline(),
+                rtt_probe: None,
+                last_answered_request: None,
+                timed_out_requests: HashSet::new(),
+            }),
             requests_sem: Semaphore::new(0),
+            queued_uploads: AtomicUsize::new(0),
             state: state.clone(),
             tx,
             counters,
@@ -424,6 +791,9 @@ impl TorrentStateLive {
         let options = PeerConnectionOptions {
             connect_timeout: state.meta.options.peer_connect_timeout,
             read_write_timeout: state.meta.options.peer_read_write_timeout,
+            keep_alive_interval: state.meta.options.peer_keep_alive_interval,
+            idle_timeout: state.meta.options.peer_idle_timeout,
+            transport: state.meta.options.peer_transport,
             ..Default::default()
         };
         let peer_connection = PeerConnection::new(
@@ -433,7 +803,13 @@ impl TorrentStateLive {
             &handler,
             Some(options),
             state.meta.spawner,
-        );
+        )
+        .with_socket_binding(state.meta.options.effective_socket_binding().cloned())
+        .with_utp_socket(state.meta.options.utp_socket.clone())
+        .with_half_open_limit(state.meta.options.half_open_semaphore.clone())
+        .with_upload_limits(state.meta.options.upload_limits())
+        .with_peer_limits(limits)
+        .with_extensions(state.meta.options.extensions.clone(), handler.tx.clone());
         let requester = handler.task_peer_chunk_requester();
 
         handler
@@ -442,6 +818,7 @@ impl TorrentStateLive {
             .fetch_add(1, Ordering::Relaxed);
         let res = tokio::select! {
             r = requester => {r}
+            r = handler.task_evict_if_inactive() => {r}
             r = peer_connection.manage_peer_outgoing(rx) => {r}
         };
 
@@ -459,6 +836,60 @@ impl TorrentStateLive {
         Ok::<_, anyhow::Error>(())
     }
 
+    fn try_acquire_peer_permit(&self) -> Option<PeerPermit> {
+        let torrent = self.peer_semaphore.clone().try_acquire_owned().ok()?;
+        let session = match self.meta.options.session_peer_semaphore.clone() {
+            Some(sem) => Some(sem.try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(PeerPermit {
+            _torrent: torrent,
+            _session: session,
+        })
+    }
+
+    async fn acquire_peer_permit(&self) -> anyhow::Result<PeerPermit> {
+        let torrent = self.peer_semaphore.clone().acquire_owned().await?;
+        let session = match self.meta.options.session_peer_semaphore.clone() {
+            Some(sem) => Some(sem.acquire_owned().await?),
+            None => None,
+        };
+        Ok(PeerPermit {
+            _torrent: torrent,
+            _session: session,
+        })
+    }
+
+    // How many chunk requests to keep in flight to a peer that allows peer_reqq of them.
+    // The range of each peer's request pipeline depth, see RequestPipeline.
+    fn peer_request_queue_limits(&self, peer_reqq: Option<u32>) -> (usize, usize) {
+        let peer_reqq = peer_reqq.map(|r| (r as usize).clamp(1, MAX_PEER_REQUEST_QUEUE_DEPTH));
+        match (self.meta.options.peer_request_queue_depth, peer_reqq) {
+            (Some(depth), Some(reqq)) => (depth.get().min(reqq), depth.get().min(reqq)),
+            (Some(depth), None) => (depth.get(), depth.get()),
+            (None, Some(reqq)) => (DEFAULT_PEER_REQUEST_QUEUE_DEPTH.min(reqq), reqq),
+            (None, None) => (
+                DEFAULT_PEER_REQUEST_QUEUE_DEPTH,
+                MAX_PEER_REQUEST_QUEUE_DEPTH,
+            ),
+        }
+    }
+
+    // The largest block peers may request from us.
+    fn max_request_block_size(&self) -> u32 {
+        self.meta
+            .options
+            .max_request_block_size
+            .map_or(MAX_BLOCK_SIZE, |s| {
+                s.get().clamp(CHUNK_SIZE, MAX_BLOCK_SIZE)
+            })
+    }
+
+    fn new_request_pipeline(&self) -> RequestPipeline {
+        let (min_depth, max_depth) = self.peer_request_queue_limits(None);
+        RequestPipeline::new(min_depth, max_depth)
+    }
+
     async fn task_peer_adder(
         self: Arc<Self>,
         mut peer_queue_rx: UnboundedReceiver<SocketAddr>,
@@ -472,7 +903,7 @@ impl TorrentStateLive {
                 continue;
             }
 
-            let permit = state.peer_semaphore.clone().acquire_owned().await?;
+            let permit = state.acquire_peer_permit().await?;
             state.spawn(
                 error_span!(parent: state.meta.span.clone(), "manage_peer", peer = addr.to_string()),
                 state.clone().task_manage_outgoing_peer(addr, permit),
@@ -494,7 +925,12 @@ impl TorrentStateLive {
         self.meta.peer_id
     }
     pub(crate) fn file_ops(&self) -> FileOps<'_> {
-        FileOps::new(&self.meta.info, &self.files, &self.lengths)
+        FileOps::new(
+            &self.meta.info,
+            self.meta.v2_piece_hashes.as_deref(),
+            &self.files,
+            &self.lengths,
+        )
     }
 
     pub(crate) fn lock_read(
@@ -515,11 +951,53 @@ impl TorrentStateLive {
             p.state
                 .connecting_to_live(Id20::new(h.peer_id), &self.peers.stats);
         });
+        self.meta.emit(Event::PeerConnected {
+            info_hash: self.meta.info_hash,
+            addr: handle,
+        });
     }
 
     pub fn get_uploaded_bytes(&self) -> u64 {
         self.stats.uploaded_bytes.load(Ordering::Relaxed)
     }
+    pub(crate) fn get_fetched_bytes(&self) -> u64 {
+        self.stats.fetched_bytes.load(Ordering::Relaxed)
+    }
+    pub(crate) fn get_peer_stats(&self) -> AggregatePeerStats {
+        self.peers.stats()
+    }
+    pub(crate) fn get_write_cache_bytes(&self) -> usize {
+        self.write_cache.lock().used()
+    }
+    pub(crate) fn get_partial_pieces(&self) -> anyhow::Result<PartialPieces> {
+        let g = self.lock_read("get_partial_pieces");
+        let mut partial = g.get_chunks()?.get_partial_pieces();
+        // Buffered chunks aren't on disk yet.
+        for index in self.write_cache.lock().pieces() {
+            partial.remove(&index.get());
+        }
+        Ok(partial)
+    }
+    // Forget the buffered chunks of a piece that isn't downloaded anymore, e.g. as its peer
+    // died, so that they don't take up the cache. The piece is downloaded again from scratch
+    // if it's needed. Called with "locked" held.
+    fn drop_buffered_piece(&self, chunks: &mut ChunkTracker, index: ValidPieceIndex) {
+        if self.discard_buffered_chunks(chunks, index) {
+            chunks.mark_piece_broken_if_not_have(index);
+        }
+    }
+
+    // Like drop_buffered_piece, but the piece stays reserved, for when it's downloaded again
+    // from the start. Returns whether anything was buffered.
+    fn discard_buffered_chunks(&self, chunks: &mut ChunkTracker, index: ValidPieceIndex) -> bool {
+        if !self.write_cache.lock().remove(index) {
+            return false;
+        }
+        self.piece_contributors.lock().remove(&index);
+        chunks.mark_piece_chunks_not_downloaded(index);
+        true
+    }
+
     pub fn get_downloaded_bytes(&self) -> u64 {
         self.stats
             .downloaded_and_checked_bytes
@@ -530,72 +1008,191 @@ impl TorrentStateLive {
         self.stats.have_bytes.load(Ordering::Relaxed)
     }
 
-    pub fn get_hns(&self) -> Option<HaveNeededSelected> {
-        self.lock_read("get_hns")
-            .get_chunks()
-            .ok()
-            .map(|c| *c.get_hns())
+    pub fn get_hns(&self) -> HaveNeededSelected {
+        self.chunks_shared.hns()
     }
 
     fn maybe_transmit_haves(&self, index: ValidPieceIndex) {
-        let mut futures = Vec::new();
+        self.pending_haves.lock().push(index);
+        self.pending_haves_notify.notify_one();
+    }
 
-        for pe in self.peers.states.iter() {
-            match &pe.value().state.get() {
-                PeerState::Live(live) => {
+    // Haves of the pieces verified within HAVE_BATCH_INTERVAL are sent together, in one write
+    // per peer, instead of a write and a wakeup per piece.
+    async fn task_transmit_haves(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            self.pending_haves_notify.notified().await;
+            tokio::time::sleep(HAVE_BATCH_INTERVAL).await;
+            let pieces = std::mem::take(&mut *self.pending_haves.lock());
+            if pieces.is_empty() {
+                continue;
+            }
+
+            let sends = self
+                .peers
+                .states
+                .iter()
+                .filter_map(|pe| {
+                    let live = pe.value().state.get_live()?;
                     if !live.peer_interested {
-                        continue;
+                        return None;
                     }
-
-                    if live
-                        .bitfield
-                        .get(index.get() as usize)
-                        .map(|v| *v)
-                        .unwrap_or(false)
-                    {
-                        continue;
+                    let haves = pieces
+                        .iter()
+                        .map(|p| p.get())
+                        .filter(|p| !live.bitfield.get(*p).unwrap_or(false))
+                        .collect::<Vec<_>>();
+                    if haves.is_empty() {
+                        return None;
                     }
+                    Some((live.tx.clone(), haves))
+                })
+                .collect::<Vec<_>>();
+            let peers = sends.len();
+            // Wait for room in the write queues of all peers at once, so that a slow one
+            // doesn't hold up the others.
+            futures::future::join_all(
+                sends
+                    .into_iter()
+                    .map(|(tx, haves)| async move { tx.send(WriterRequest::Haves(haves)).await }),
+            )
+            .await;
+            trace!(pieces = pieces.len(), peers, "transmitted haves");
+        }
+    }
 
-                    let tx = live.tx.downgrade();
-                    futures.push(async move {
-                        if let Some(tx) = tx.upgrade() {
-                            if tx
-                                .send(WriterRequest::Message(Message::Have(index.get())))
-                                .is_err()
-                            {
-                                // whatever
-                            }
-                        }
-                    });
-                }
-                _ => continue,
+    // Whether we want any of the peer's pieces, if that's not what we told it last.
+    fn interest_change(&self, live: &LivePeerState) -> Option<bool> {
+        let interested = self
+            .lock_read("update_interest")
+            .get_chunks()
+            .ok()?
+            .is_interested_in(&live.bitfield);
+        (interested != live.i_am_interested).then_some(interested)
+    }
+
+    // Tell the peer whether we want any of its pieces, if that changed. Room in the write queue
+    // is reserved first, so that the state we keep only changes together with the message.
+    async fn update_interest(&self, handle: PeerHandle) {
+        let tx = match self.peers.with_live(handle, |live| {
+            self.interest_change(live).map(|_| live.tx.clone())
+        }) {
+            Some(Some(tx)) => tx,
+            _ => return,
+        };
+        let permit = match tx.reserve().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        self.peers.with_live_mut(handle, "update_interest", |live| {
+            let interested = match self.interest_change(live) {
+                Some(interested) => interested,
+                None => return,
+            };
+            live.i_am_interested = interested;
+            if interested {
+                live.last_useful_data = Instant::now();
             }
-        }
+            let msg = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            permit.send(WriterRequest::Message(msg));
+        });
+    }
 
-        if futures.is_empty() {
-            trace!("no peers to transmit Have={} to, saving some work", index);
-            return;
+    // Interest changes where we can't wait for room in the peer's write queue, e.g. when
+    // handling its messages, so it's sent from task_update_interest.
+    fn update_interest_later(&self, handles: impl IntoIterator<Item = PeerHandle>) {
+        self.pending_interest.lock().extend(handles);
+        self.pending_interest_notify.notify_one();
+    }
+
+    async fn task_update_interest(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            self.pending_interest_notify.notified().await;
+            let handles = std::mem::take(&mut *self.pending_interest.lock());
+            for handle in handles {
+                let changed = self
+                    .peers
+                    .with_live(handle, |live| self.interest_change(live).is_some())
+                    .unwrap_or_default();
+                if !changed {
+                    continue;
+                }
+                // A peer that doesn't read what we send only holds up its own message.
+                let state = self.clone();
+                self.spawn(
+                    error_span!(parent: self.meta.span.clone(), "update_interest", peer = %handle),
+                    async move {
+                        state.update_interest(handle).await;
+                        Ok(())
+                    },
+                );
+            }
         }
+    }
 
-        let mut unordered: FuturesUnordered<_> = futures.into_iter().collect();
+    // Re-evaluate interest in all live peers, or only in the ones that have this piece.
+    fn update_interest_all(&self, having: Option<ValidPieceIndex>) {
+        let handles = self
+            .peers
+            .states
+            .iter()
+            .filter(|pe| match pe.value().state.get() {
+                PeerState::Live(live) => {
+                    having.is_none_or(|i| live.bitfield.get(i.get()) == Some(true))
+                }
+                _ => false,
+            })
+            .map(|pe| *pe.key())
+            .collect::<Vec<_>>();
+        self.update_interest_later(handles);
+    }
 
-        // We don't want to remember this task as there may be too many.
-        self.spawn(
-            error_span!(
-                parent: self.meta.span.clone(),
-                "transmit_haves",
-                piece = index.get(),
-                count = unordered.len()
-            ),
-            async move {
-                while unordered.next().await.is_some() {}
+    // Queue a message whose order relative to the others doesn't matter, without waiting. If
+    // the peer's write queue is full, it's sent from a task once there's room, not dropped.
+    fn send_without_waiting(&self, tx: &PeerTx, req: WriterRequest) -> anyhow::Result<()> {
+        match tx.try_send(req) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(req)) => {
+                let tx = tx.clone();
+                self.spawn(
+                    error_span!(parent: self.meta.span.clone(), "send_without_waiting"),
+                    async move {
+                        let _ = tx.send(req).await;
+                        Ok(())
+                    },
+                );
                 Ok(())
-            },
-        );
+            }
+            Err(TrySendError::Closed(_)) => anyhow::bail!("peer disconnected"),
+        }
+    }
+
+    fn maybe_transmit_donthaves(&self, index: ValidPieceIndex) {
+        for pe in self.peers.states.iter() {
+            if let PeerState::Live(live) = &pe.value().state.get() {
+                if !live.supports_donthave {
+                    continue;
+                }
+                let _ = self.send_without_waiting(
+                    &live.tx,
+                    WriterRequest::Message(Message::Extended(ExtendedMessage::LtDonthave(
+                        index.get(),
+                    ))),
+                );
+            }
+        }
     }
 
-    pub(crate) fn add_peer_if_not_seen(&self, addr: SocketAddr) -> anyhow::Result<bool> {
-        match self.peers.add_if_not_seen(addr) {
+    pub(crate) fn add_peer_if_not_seen(
+        &self,
+        addr: SocketAddr,
+        origin: PeerOrigin,
+    ) -> anyhow::Result<bool> {
+        match self.peers.add_if_not_seen(addr, origin) {
             Some(handle) => handle,
             None => return Ok(false),
         };
@@ -604,16 +1201,164 @@ impl TorrentStateLive {
         Ok(true)
     }
 
+    // Whether outgoing connections of this torrent can go over uTP, which hole punching needs.
+    fn can_holepunch(&self) -> bool {
+        let options = &self.meta.options;
+        options.utp_socket.is_some()
+            && options.peer_transport != Some(PeerTransport::Tcp)
+            && options.effective_socket_binding().is_none()
+    }
+
+    // Ask a peer we're connected to to relay a hole punch to a peer we couldn't connect to.
+    fn request_holepunch(&self, relay: PeerHandle, target: SocketAddr) {
+        if !self.can_holepunch() {
+            return;
+        }
+        let tx = match self.peers.with_live(relay, |live| {
+            live.supports_holepunch.then(|| live.tx.clone())
+        }) {
+            Some(Some(tx)) => tx,
+            _ => return,
+        };
+        let msg = WriterRequest::Message(Message::Extended(ExtendedMessage::UtHolepunch(
+            UtHolepunch::Rendezvous(target),
+        )));
+        if self.send_without_waiting(&tx, msg).is_ok() {
+            debug!(%relay, %target, "sent ut_holepunch rendezvous");
+        }
+    }
+
+    // The relay told the other side to connect to us too, so connect right away, even if the
+    // peer is waiting to be retried.
+    fn on_holepunch_connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        if !self.can_holepunch() {
+            trace!("can't connect over uTP, ignoring ut_holepunch connect to {addr}");
+            return Ok(());
+        }
+        if self.add_peer_if_not_seen(addr, PeerOrigin::Holepunch)? {
+            return Ok(());
+        }
+        let requeue = self
+            .peers
+            .with_peer_mut(addr, "holepunch_requeue", |p| match p.state.get() {
+                PeerState::Dead => {
+                    p.state.set(PeerState::Queued, &self.peers.stats);
+                    true
+                }
+                _ => false,
+            })
+            .unwrap_or(false);
+        if requeue {
+            self.peer_queue_tx.send(addr)?;
+        }
+        Ok(())
+    }
+
+    // Live peers we can tell other peers about. For incoming connections we don't know the
+    // port the peer listens on, so only the ones we connected to are shared.
+    fn pex_shareable_peers(&self, exclude: PeerHandle) -> HashSet<SocketAddr> {
+        self.peers
+            .states
+            .iter()
+            .filter(|e| {
+                *e.key() != exclude
+                    && e.value().state.get_live().is_some()
+                    && e.value()
+                        .stats
+                        .counters
+                        .outgoing_connections
+                        .load(Ordering::Relaxed)
+                        > 0
+            })
+            .map(|e| *e.key())
+            .collect()
+    }
+
+    async fn task_send_pex_to_peer(
+        self: Arc<Self>,
+        addr: PeerHandle,
+        tx: WeakSender<WriterRequest>,
+    ) -> anyhow::Result<()> {
+        let mut sent = HashSet::new();
+        loop {
+            // The peer is gone if its writer is gone.
+            let tx = match tx.upgrade() {
+                Some(tx) => tx,
+                None => return Ok(()),
+            };
+            let connected = self.pex_shareable_peers(addr);
+            let added = connected
+                .difference(&sent)
+                .take(UT_PEX_MAX_PEERS)
+                .copied()
+                .collect::<Vec<_>>();
+            let dropped = sent
+                .difference(&connected)
+                .take(UT_PEX_MAX_PEERS)
+                .copied()
+                .collect::<Vec<_>>();
+            sent.extend(added.iter().copied());
+            for d in dropped.iter() {
+                sent.remove(d);
+            }
+
+            let msg = UtPex::from_addrs(&added, &dropped);
+            if !msg.is_empty() {
+                trace!(
+                    added = added.len(),
+                    dropped = dropped.len(),
+                    "sending ut_pex"
+                );
+                if tx
+                    .send(WriterRequest::Message(Message::Extended(
+                        ExtendedMessage::UtPex(msg),
+                    )))
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+
+            drop(tx);
+            tokio::time::sleep(PEX_INTERVAL).await;
+        }
+    }
+
     pub fn stats_snapshot(&self) -> StatsSnapshot {
         use Ordering::*;
         let downloaded_bytes = self.stats.downloaded_and_checked_bytes.load(Relaxed);
+        let hns = self.get_hns();
+        let (min_piece_availability, distributed_copies) = self
+            .lock_read("stats_snapshot")
+            .get_chunks()
+            .map(|c| {
+                self.peers
+                    .stats
+                    .availability
+                    .distributed_copies(c.get_have_pieces())
+            })
+            .unwrap_or_default();
         StatsSnapshot {
+            have_bytes: hns.have_bytes,
+            needed_bytes: hns.needed_bytes,
+            selected_bytes: hns.selected_bytes,
             downloaded_and_checked_bytes: downloaded_bytes,
             downloaded_and_checked_pieces: self.stats.downloaded_and_checked_pieces.load(Relaxed),
+            hash_failed_pieces: self.stats.hash_failed_pieces.load(Relaxed),
             fetched_bytes: self.stats.fetched_bytes.load(Relaxed),
+            wasted_bytes: self.stats.wasted_bytes.load(Relaxed),
             uploaded_bytes: self.stats.uploaded_bytes.load(Relaxed),
             total_piece_download_ms: self.stats.total_piece_download_ms.load(Relaxed),
             peer_stats: self.peers.stats(),
+            min_piece_availability,
+            distributed_copies,
+            download_bps_smoothed: self.down_speed_estimator.smoothed_bps(),
+            upload_bps_smoothed: self.up_speed_estimator.smoothed_bps(),
+            eta_secs: self
+                .down_speed_estimator
+                .smoothed_time_remaining()
+                .map(|d| d.as_secs()),
         }
     }
 
@@ -624,11 +1369,35 @@ impl TorrentStateLive {
                 .states
                 .iter()
                 .filter(|e| filter.state.matches(e.value().state.get()))
+                .filter(|e| filter.origin.is_none_or(|o| o == e.value().origin))
                 .map(|e| (e.key().to_string(), e.value().into()))
                 .collect(),
         }
     }
 
+    /// Limit upload and download rates of a single peer, in bytes per second. None removes the
+    /// limit.
+    pub fn set_peer_rate_limits(
+        &self,
+        addr: PeerHandle,
+        upload: Option<NonZeroU32>,
+        download: Option<NonZeroU32>,
+    ) -> anyhow::Result<()> {
+        self.peers
+            .with_peer(addr, |p| p.limits.set(upload, download))
+            .context("peer not found")
+    }
+
+    /// Snapshot the state and counters of every known peer of this torrent.
+    pub fn iter_peer_stats(&self) -> impl Iterator<Item = (PeerHandle, PeerStats)> {
+        self.peers
+            .states
+            .iter()
+            .map(|e| (*e.key(), e.value().into()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
     pub async fn wait_until_completed(&self) {
         if self.is_finished() {
             return;
@@ -642,7 +1411,7 @@ impl TorrentStateLive {
         let mut g = self.locked.write();
 
         // It should be impossible to make a fatal error after pausing.
-        g.fatal_errors_tx.take();
+        self.fatal_errors_tx.lock().take();
 
         let files = self
             .files
@@ -656,7 +1425,12 @@ impl TorrentStateLive {
             .chunks
             .take()
             .context("bug: pausing already paused torrent")?;
+        // The chunks already written stay, only the rest is requested after resuming.
         for piece_id in g.inflight_pieces.keys().copied() {
+            chunk_tracker.mark_chunk_request_cancelled(piece_id, 0);
+        }
+        // Buffered chunks are lost, so they need to be downloaded again.
+        for piece_id in self.write_cache.lock().clear() {
             chunk_tracker.mark_piece_broken_if_not_have(piece_id);
         }
 
@@ -665,13 +1439,23 @@ impl TorrentStateLive {
             info: self.meta.clone(),
             files,
             chunk_tracker,
+            error: None,
         })
     }
 
     fn on_fatal_error(&self, e: anyhow::Error) -> anyhow::Result<()> {
-        let mut g = self.lock_write("fatal_error");
-        let tx = g
+        let e = if disk_space::is_out_of_space(&e) {
+            e.context("out of disk space, free some up and resume the torrent")
+        } else {
+            e
+        };
+        self.meta.emit(Event::DiskError {
+            info_hash: self.meta.info_hash,
+            error: format!("{e:#}"),
+        });
+        let tx = self
             .fatal_errors_tx
+            .lock()
             .take()
             .context("fatal_errors_tx already taken")?;
         let res = anyhow::anyhow!("fatal error: {:?}", e);
@@ -682,18 +1466,49 @@ impl TorrentStateLive {
     }
 
     pub(crate) fn update_only_files(&self, only_files: &HashSet<usize>) -> anyhow::Result<()> {
-        let mut g = self.lock_write("update_only_files");
-        let ct = g.get_chunks_mut()?;
-        let hns = ct.update_only_files(self.files.iter().map(|f| f.len), only_files)?;
-        reopen_necessary_files_for_write(ct, &self.files)?;
-        if !hns.finished() {
+        let hns = {
+            let mut g = self.lock_write("update_only_files");
+            let ct = g.get_chunks_mut()?;
+            let hns = ct.update_only_files(self.files.iter().map(|f| f.len), only_files)?;
+            reopen_necessary_files_for_write(ct, &self.files)?;
+
+            // Deselected pieces aren't downloaded anymore.
+            let deselected = {
+                let selected = g.get_chunks()?.get_selected_pieces();
+                let buffered = self.write_cache.lock().pieces().collect::<Vec<_>>();
+                g.inflight_pieces
+                    .keys()
+                    .copied()
+                    .chain(buffered)
+                    .filter(|index| !selected[index.get() as usize])
+                    .collect::<HashSet<_>>()
+            };
+            for index in deselected {
+                g.inflight_pieces.remove(&index);
+                self.drop_buffered_piece(g.get_chunks_mut()?, index);
+            }
+            hns
+        };
+        if hns.finished() {
+            // Deselecting the remaining files finishes the torrent.
+            self.finished_notify.notify_waiters();
+            self.meta.emit(Event::TorrentFinished {
+                info_hash: self.meta.info_hash,
+            });
+            self.disconnect_all_peers_that_have_full_torrent();
+        } else {
             self.reconnect_all_not_needed_peers();
         }
+        self.update_interest_all(None);
         Ok(())
     }
 
+    fn on_wasted_bytes(&self, bytes: u64) {
+        self.stats.wasted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
     pub(crate) fn is_finished(&self) -> bool {
-        self.get_hns().map(|h| h.finished()).unwrap_or_default()
+        self.get_hns().finished()
     }
 
     fn on_piece_completed(&self, id: ValidPieceIndex) -> anyhow::Result<()> {
@@ -721,12 +1536,16 @@ impl TorrentStateLive {
                 .all();
             if have_all {
                 opened_file.reopen(true)?;
+                opened_file.finalize()?;
             }
         }
 
         if self.is_finished() {
             info!("torrent finished downloading");
             self.finished_notify.notify_waiters();
+            self.meta.emit(Event::TorrentFinished {
+                info_hash: self.meta.info_hash,
+            });
 
             // There is not poing being connected to peers that have all the torrent, when
             // we don't need anything from them, and they don't need anything from us.
@@ -735,27 +1554,176 @@ impl TorrentStateLive {
         Ok(())
     }
 
-    fn disconnect_all_peers_that_have_full_torrent(&self) {
-        for mut pe in self.peers.states.iter_mut() {
-            if let PeerState::Live(l) = pe.value().state.get() {
-                if l.has_full_torrent(self.lengths.total_pieces() as usize) {
-                    let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
-                    let _ = prev
-                        .take_live_no_counters()
-                        .unwrap()
-                        .tx
-                        .send(WriterRequest::Disconnect);
-                }
-            }
+    // Called once a downloaded piece was written to disk and its hash checked.
+    fn on_piece_verified(
+        &self,
+        index: ValidPieceIndex,
+        download_time: Duration,
+    ) -> anyhow::Result<()> {
+        {
+            let mut g = self.lock_write("mark_piece_downloaded");
+            g.get_chunks_mut()?.mark_piece_downloaded(index);
         }
-    }
 
-    fn reconnect_all_not_needed_peers(&self) {
-        for pe in self.peers.states.iter() {
-            if let PeerState::NotNeeded = pe.value().state.get() {
-                if self.peer_queue_tx.send(*pe.key()).is_err() {
-                    return;
-                }
+        // Global piece counters.
+        let piece_len = self.lengths.piece_length(index) as u64;
+        self.stats
+            .downloaded_and_checked_bytes
+            // This counter is used to compute "is_finished", so using
+            // stronger ordering.
+            .fetch_add(piece_len, Ordering::Release);
+        self.stats
+            .downloaded_and_checked_pieces
+            // This counter is used to compute "is_finished", so using
+            // stronger ordering.
+            .fetch_add(1, Ordering::Release);
+        self.stats
+            .have_bytes
+            .fetch_add(piece_len, Ordering::Relaxed);
+        self.stats
+            .total_piece_download_ms
+            .fetch_add(download_time.as_millis() as u64, Ordering::Relaxed);
+
+        self.meta.emit(Event::PieceCompleted {
+            info_hash: self.meta.info_hash,
+            piece: index.get(),
+        });
+        self.on_piece_completed(index)?;
+        self.streams.on_piece_verified();
+
+        self.maybe_transmit_haves(index);
+        self.update_interest_all(Some(index));
+        Ok(())
+    }
+
+    /// Hash a piece on disk again, e.g. after repairing the disk. If we had it, but it doesn't
+    /// match anymore, it's downloaded again. Returns whether the piece is valid.
+    pub async fn verify_piece(self: &Arc<Self>, piece: u32) -> anyhow::Result<bool> {
+        let index = self
+            .lengths
+            .validate_piece_index(piece)
+            .with_context(|| format!("invalid piece {piece}"))?;
+        let first_chunk = self
+            .lengths
+            .iter_chunk_infos(index)
+            .next()
+            .context("bug: piece has no chunks")?;
+        let state = self.clone();
+        let valid = self
+            .meta
+            .cpu_pool
+            .run(move || {
+                state
+                    .file_ops()
+                    .check_piece("verify_piece", index, &first_chunk)
+                    .unwrap_or_else(|e| {
+                        warn!(piece, "error reading piece: {e:#}");
+                        false
+                    })
+            })
+            .await?;
+        if !valid {
+            self.on_piece_lost(index)?;
+        }
+        Ok(valid)
+    }
+
+    // Called when a piece we had no longer verifies on disk.
+    fn on_piece_lost(&self, index: ValidPieceIndex) -> anyhow::Result<()> {
+        {
+            let mut g = self.lock_write("mark_piece_lost");
+            let ct = g.get_chunks_mut()?;
+            if !ct.mark_piece_lost(index) {
+                return Ok(());
+            }
+            reopen_necessary_files_for_write(ct, &self.files)?;
+        }
+        warn!(
+            piece = index.get(),
+            "piece is corrupt on disk, will download it again"
+        );
+
+        let piece_len = self.lengths.piece_length(index) as u64;
+        self.stats
+            .have_bytes
+            .fetch_sub(piece_len, Ordering::Relaxed);
+        for opened_file in self
+            .files
+            .iter()
+            .filter(|f| f.piece_range.contains(&index.get()))
+        {
+            opened_file.update_have_on_piece_lost(index.get(), &self.lengths);
+        }
+
+        self.maybe_transmit_donthaves(index);
+        self.update_interest_all(Some(index));
+        // Peers we disconnected from as we didn't need them may have this piece.
+        self.reconnect_all_not_needed_peers();
+        Ok(())
+    }
+
+    fn on_piece_hash_failed(&self, contributors: &HashMap<PeerHandle, u64>) {
+        for (handle, bytes) in contributors.iter().map(|(h, b)| (*h, *b)) {
+            let hash_failed_pieces = self.peers.with_peer(handle, |p| {
+                let counters = &p.stats.counters;
+                counters
+                    .hash_failed_bytes
+                    .fetch_add(bytes, Ordering::Relaxed);
+                counters.wasted_bytes.fetch_add(bytes, Ordering::Relaxed);
+                counters.hash_failed_pieces.fetch_add(1, Ordering::Relaxed) + 1
+            });
+            if hash_failed_pieces >= Some(PEER_BAN_HASH_FAILED_PIECES) {
+                self.ban_peer(handle);
+            }
+        }
+    }
+
+    fn ban_peer(&self, handle: PeerHandle) {
+        let prev = self
+            .peers
+            .with_peer_mut(handle, "ban_peer", |peer| match peer.state.get() {
+                PeerState::Banned => None,
+                _ => Some(peer.state.set(PeerState::Banned, &self.peers.stats)),
+            });
+        let prev = match prev.flatten() {
+            Some(prev) => prev,
+            None => return,
+        };
+        warn!(peer = %handle, "banning peer, it sent too many pieces that failed the hash check");
+        if let PeerState::Live(live) = prev {
+            let mut g = self.lock_write("ban_peer");
+            if let Ok(chunks) = g.get_chunks_mut() {
+                for req in live.inflight_requests.keys() {
+                    chunks.mark_chunk_request_cancelled(req.piece_index, req.chunk_index);
+                }
+            }
+            drop(g);
+            let _ = self.send_without_waiting(&live.tx, WriterRequest::Disconnect);
+        }
+        self.meta.emit(Event::PeerBanned {
+            info_hash: self.meta.info_hash,
+            addr: handle,
+        });
+    }
+
+    fn disconnect_all_peers_that_have_full_torrent(&self) {
+        for mut pe in self.peers.states.iter_mut() {
+            if let PeerState::Live(l) = pe.value().state.get() {
+                if l.is_seed(self.lengths.total_pieces() as usize) {
+                    let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
+                    let live = prev.take_live_no_counters().unwrap();
+                    let _ = self.send_without_waiting(&live.tx, WriterRequest::Disconnect);
+                }
+            }
+        }
+    }
+
+    fn reconnect_all_not_needed_peers(&self) {
+        for pe in self.peers.states.iter() {
+            if let PeerState::NotNeeded = pe.value().state.get() {
+                if self.peer_queue_tx.send(*pe.key()).is_err() {
+                    return;
+                }
             }
         }
     }
@@ -770,6 +1738,21 @@ impl TorrentStateLive {
 
 struct PeerHandlerLocked {
     pub i_am_choked: bool,
+    // Whether the peer supports the Fast Extension (BEP 6).
+    pub supports_fast: bool,
+    // Pieces we may request even while choked.
+    pub allowed_fast: HashSet<ValidPieceIndex>,
+    // Max outstanding requests from the peer's extended handshake.
+    pub peer_reqq: Option<u32>,
+    // How many requests to keep in flight, the permits of requests_sem.
+    pub pipeline: RequestPipeline,
+    // A request sent while nothing else was in flight, and when, to measure the round trip time.
+    pub rtt_probe: Option<(ChunkInfo, Instant)>,
+    // When the latest request the peer answered was sent.
+    pub last_answered_request: Option<Instant>,
+    // Requests that timed out and were cancelled. Their permits were returned already, so if
+    // the chunk arrives anyway it doesn't get another one.
+    pub timed_out_requests: HashSet<ChunkInfo>,
 }
 
 // All peer state that would never be used by other actors should pe put here.
@@ -794,6 +1777,9 @@ struct PeerHandler {
     // This is used to limit the number of chunk requests we send to a peer at a time.
     requests_sem: Semaphore,
 
+    // Chunk requests of the peer waiting in the write queue, up to PEER_UPLOAD_QUEUE_DEPTH.
+    queued_uploads: AtomicUsize,
+
     addr: SocketAddr,
 
     tx: PeerTx,
@@ -817,7 +1803,7 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
             Message::Bitfield(b) => self
                 .on_bitfield(b.clone_to_owned())
                 .context("on_bitfield")?,
-            Message::Choke => self.on_i_am_choked(),
+            Message::Choke => self.on_i_am_choked().context("on_i_am_choked")?,
             Message::Unchoke => self.on_i_am_unchoked(),
             Message::Interested => self.on_peer_interested(),
             Message::Piece(piece) => self.on_received_piece(piece).context("on_received_piece")?,
@@ -825,12 +1811,23 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
                 trace!("keepalive received");
             }
             Message::Have(h) => self.on_have(h),
-            Message::NotInterested => {
-                trace!("received \"not interested\", but we don't process it yet")
-            }
+            Message::Port(port) => self.on_dht_port(port),
+            Message::NotInterested => self.on_peer_not_interested(),
             Message::Cancel(_) => {
                 trace!("received \"cancel\", but we don't process it yet")
             }
+            Message::HaveAll => self.on_have_all(),
+            Message::HaveNone => self.on_have_none(),
+            Message::RejectRequest(request) => self
+                .on_request_rejected(request)
+                .context("on_request_rejected")?,
+            Message::AllowedFast(index) => self.on_allowed_fast(index),
+            Message::SuggestPiece(index) => {
+                trace!("received suggest piece {index}, ignoring")
+            }
+            Message::Extended(ExtendedMessage::UtPex(pex)) => self.on_pex_message(pex),
+            Message::Extended(ExtendedMessage::UtHolepunch(msg)) => self.on_holepunch_message(msg),
+            Message::Extended(ExtendedMessage::LtDonthave(index)) => self.on_donthave(index),
             message => {
                 warn!("received unsupported message {:?}, ignoring", message);
             }
@@ -840,20 +1837,29 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
 
     fn serialize_bitfield_message_to_buf(&self, buf: &mut Vec<u8>) -> anyhow::Result<usize> {
         let g = self.state.lock_read("serialize_bitfield_message_to_buf");
-        let msg = Message::Bitfield(ByteBuf(g.get_chunks()?.get_have_pieces().as_raw_slice()));
-        let len = msg.serialize(buf, &|| None)?;
+        let have = g.get_chunks()?.get_have_pieces();
+        let msg = if self.locked.read().supports_fast
+            && have.count_ones() == self.state.lengths.total_pieces() as usize
+        {
+            Message::HaveAll
+        } else {
+            Message::Bitfield(ByteBuf(have.as_raw_slice()))
+        };
+        let len = msg.serialize(buf, &PeerExtendedMessageIds::default)?;
         trace!("sending: {:?}, length={}", &msg, len);
         Ok(len)
     }
 
     fn on_handshake<B>(&self, handshake: Handshake<B>) -> anyhow::Result<()> {
+        self.locked.write().supports_fast = handshake.supports_fast();
         self.state.set_peer_live(self.addr, handshake);
-        self.tx
-            .send(WriterRequest::Message(MessageOwned::Unchoke))?;
         Ok(())
     }
 
     fn on_uploaded_bytes(&self, bytes: u32) {
+        self.counters
+            .uploaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
         self.state
             .stats
             .uploaded_bytes
@@ -861,16 +1867,86 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
     }
 
     fn read_chunk(&self, chunk: &ChunkInfo, buf: &mut [u8]) -> anyhow::Result<()> {
-        self.state.file_ops().read_chunk(self.addr, chunk, buf)
+        let res = self.state.file_ops().read_chunk(self.addr, chunk, buf);
+        if res.is_err() {
+            // The data may have changed on disk under us. If the piece doesn't verify anymore,
+            // stop advertising it and download it again.
+            let valid = self
+                .state
+                .file_ops()
+                .check_piece(self.addr, chunk.piece_index, chunk)
+                .unwrap_or(false);
+            if !valid {
+                self.state.on_piece_lost(chunk.piece_index)?;
+            }
+        }
+        res
+    }
+
+    fn on_upload_request_dequeued(&self) {
+        self.queued_uploads.fetch_sub(1, Ordering::Relaxed);
     }
 
-    fn on_extended_handshake(&self, _: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+    fn on_extended_handshake(&self, h: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_extended_handshake", |live| {
+                live.supports_holepunch = h.ut_holepunch().is_some();
+                live.supports_donthave = h.lt_donthave().is_some();
+                live.upload_only = h.upload_only.unwrap_or_default() != 0;
+            });
+        if let Some(reqq) = h.reqq {
+            let mut g = self.locked.write();
+            g.peer_reqq = Some(reqq);
+            let (min_depth, max_depth) = self.state.peer_request_queue_limits(g.peer_reqq);
+            let permits = g.pipeline.set_limits(min_depth, max_depth);
+            // Permits were already handed out if we're unchoked, let the requester use a
+            // deeper pipeline right away.
+            if !g.i_am_choked {
+                self.requests_sem.add_permits(permits);
+            }
+        }
+        if h.upload_only.unwrap_or_default() != 0 && self.state.is_finished() {
+            debug!("both peer and us are only uploading, disconnecting");
+            self.state
+                .send_without_waiting(&self.tx, WriterRequest::Disconnect)?;
+            return Ok(());
+        }
+        if !self.state.meta.info.is_private() {
+            // The peer may also be reachable over the other address family.
+            for addr in h.advertised_addrs().filter(|a| a.ip() != self.addr.ip()) {
+                if let Err(e) = self.state.add_peer_if_not_seen(addr, PeerOrigin::Pex) {
+                    debug!("error adding peer {addr} from extended handshake: {e:#}");
+                }
+            }
+        }
+        if h.ut_pex().is_some() && !self.state.meta.info.is_private() {
+            self.state.spawn(
+                error_span!(parent: self.state.meta.span.clone(), "send_pex", addr = %self.addr),
+                self.state
+                    .clone()
+                    .task_send_pex_to_peer(self.addr, self.tx.downgrade()),
+            );
+        }
         Ok(())
     }
 
     fn get_have_bytes(&self) -> u64 {
         self.state.get_approx_have_bytes()
     }
+
+    fn is_upload_only(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    // Read-only nodes don't answer queries, so there's no point in peers adding us (BEP 43).
+    fn dht_port(&self) -> Option<u16> {
+        let dht = self.state.meta.options.dht.as_ref()?;
+        if dht.is_read_only() {
+            return None;
+        }
+        Some(dht.listen_addr().port())
+    }
 }
 
 impl PeerHandler {
@@ -886,12 +1962,13 @@ impl PeerHandler {
             }
         };
         let prev = pe.value_mut().state.take(pstats);
+        let never_connected = matches!(prev, PeerState::Connecting(_));
 
         match prev {
             PeerState::Connecting(_) => {}
             PeerState::Live(live) => {
                 let mut g = self.state.lock_write("mark_chunk_requests_canceled");
-                for req in live.inflight_requests {
+                for req in live.inflight_requests.into_keys() {
                     debug!(
                         "peer dead, marking chunk request cancelled, index={}, chunk={}",
                         req.piece_index.get(),
@@ -900,12 +1977,29 @@ impl PeerHandler {
                     g.get_chunks_mut()?
                         .mark_chunk_request_cancelled(req.piece_index, req.chunk_index);
                 }
+                // Nobody adds to the buffered chunks of the pieces it was downloading alone.
+                let abandoned = g
+                    .inflight_pieces
+                    .iter()
+                    .filter(|(_, p)| {
+                        p.peer == handle && p.endgame_peers.is_empty() && p.split_peers.is_empty()
+                    })
+                    .map(|(index, _)| *index)
+                    .collect::<Vec<_>>();
+                for index in abandoned {
+                    self.state.drop_buffered_piece(g.get_chunks_mut()?, index);
+                }
             }
             PeerState::NotNeeded => {
                 // Restore it as std::mem::take() replaced it above.
                 pe.value_mut().state.set(PeerState::NotNeeded, pstats);
                 return Ok(());
             }
+            PeerState::Banned => {
+                // Its requests were cancelled when it was banned.
+                pe.value_mut().state.set(PeerState::Banned, pstats);
+                return Ok(());
+            }
             s @ PeerState::Queued | s @ PeerState::Dead => {
                 warn!("bug: peer was in a wrong state {s:?}, ignoring it forever");
                 // Prevent deadlocks.
@@ -935,10 +2029,16 @@ impl PeerHandler {
         pe.value_mut().state.set(PeerState::Dead, pstats);
 
         let backoff = pe.value_mut().stats.backoff.next_backoff();
+        // Only ask for a hole punch once.
+        let holepunch_relay = pe.value_mut().holepunch_relay.take();
 
         // Prevent deadlocks.
         drop(pe);
 
+        if let (true, Some(relay)) = (never_connected, holepunch_relay) {
+            self.state.request_holepunch(relay, handle);
+        }
+
         if let Some(dur) = backoff {
             self.state.clone().spawn(
                 error_span!(
@@ -949,22 +2049,31 @@ impl PeerHandler {
                 ),
                 async move {
                     tokio::time::sleep(dur).await;
-                    self.state
+                    let requeue = self
+                        .state
                         .peers
                         .with_peer_mut(handle, "dead_to_queued", |peer| {
                             match peer.state.get() {
                                 PeerState::Dead => {
                                     peer.state.set(PeerState::Queued, &self.state.peers.stats)
                                 }
+                                // Banned while waiting.
+                                PeerState::Banned => return Ok(false),
+                                // Re-queued early for a hole punch.
+                                PeerState::Queued
+                                | PeerState::Connecting(_)
+                                | PeerState::Live(_) => return Ok(false),
                                 other => bail!(
                                     "peer is in unexpected state: {}. Expected dead",
                                     other.name()
                                 ),
                             };
-                            Ok(())
+                            Ok(true)
                         })
                         .context("bug: peer disappeared")??;
-                    self.state.peer_queue_tx.send(handle)?;
+                    if requeue {
+                        self.state.peer_queue_tx.send(handle)?;
+                    }
                     Ok::<_, anyhow::Error>(())
                 },
             );
@@ -976,29 +2085,76 @@ impl PeerHandler {
     }
 
     fn reserve_next_needed_piece(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        let stream_pieces = self.state.streams.priority_pieces();
+        let far_ahead = self.state.streams.far_ahead_pieces();
+        let is_far_ahead = |n: &usize| far_ahead.iter().any(|r| r.contains(&(*n as u32)));
+
         // TODO: locking one inside the other in different order results in deadlocks.
         self.state
             .peers
             .with_live_mut(self.addr, "reserve_next_needed_piece", |live| {
-                if self.locked.read().i_am_choked {
-                    debug!("we are choked, can't reserve next piece");
-                    return Ok(None);
-                }
+                let locked = self.locked.read();
+                // While choked, only the pieces the peer allowed us to get fast can be requested.
+                let allowed_fast = if locked.i_am_choked {
+                    if locked.allowed_fast.is_empty() {
+                        debug!("we are choked, can't reserve next piece");
+                        return Ok(None);
+                    }
+                    Some(&locked.allowed_fast)
+                } else {
+                    None
+                };
                 let mut g = self.state.lock_write("reserve_next_needed_piece");
 
                 let n = {
-                    let mut n_opt = None;
                     let bf = &live.bitfield;
-                    for n in g.get_chunks()?.iter_queued_pieces() {
-                        if bf.get(n).map(|v| *v) == Some(true) {
-                            n_opt = Some(n);
-                            break;
+                    let chunks = g.get_chunks()?;
+                    let availability = &self.state.peers.stats.availability;
+                    let peer_has = |n: &usize| {
+                        if let Some(allowed_fast) = allowed_fast {
+                            let allowed = self
+                                .state
+                                .lengths
+                                .validate_piece_index(*n as u32)
+                                .is_some_and(|p| allowed_fast.contains(&p));
+                            if !allowed {
+                                return false;
+                            }
                         }
-                    }
-
-                    let n_opt = match n_opt {
-                        Some(n_opt) => n_opt,
-                        None => return Ok(None),
+                        bf.get(*n as u32) == Some(true)
+                    };
+                    // Pieces that someone is waiting to read go first.
+                    let mut stream_pieces = stream_pieces
+                        .iter()
+                        .map(|p| *p as usize)
+                        .filter(|p| chunks.is_piece_queued(*p))
+                        .filter(peer_has);
+                    // And the ones far past what streams are reading go last.
+                    let mut candidates = chunks
+                        .iter_queued_pieces_with_availability(Some(availability))
+                        .filter(|n| !is_far_ahead(n))
+                        .chain(
+                            chunks
+                                .iter_queued_pieces_with_availability(Some(availability))
+                                .filter(is_far_ahead),
+                        )
+                        .filter(peer_has);
+
+                    let n_opt = match stream_pieces.next() {
+                        Some(n) => n,
+                        None => match candidates.next() {
+                            // Among the pieces that are needed as much as the first one, the
+                            // rarest.
+                            Some(first) => pick_rarest(
+                                std::iter::once(first).chain(candidates.take_while(|n| {
+                                    chunks.are_equally_needed(first, *n)
+                                        && is_far_ahead(n) == is_far_ahead(&first)
+                                })),
+                                availability,
+                            )
+                            .context("bug: no piece to pick")?,
+                            None => return Ok(None),
+                        },
                     };
 
                     self.state
@@ -1011,6 +2167,9 @@ impl PeerHandler {
                     InflightPiece {
                         peer: self.addr,
                         started: Instant::now(),
+                        endgame_peers: Vec::new(),
+                        split_peers: Vec::new(),
+                        claimed_chunks: HashMap::new(),
                     },
                 );
                 g.get_chunks_mut()?.reserve_needed_piece(n);
@@ -1020,40 +2179,44 @@ impl PeerHandler {
             .map(|r| r.flatten())
     }
 
-    /// Try to steal a piece from a slower peer. Threshold is
-    /// "how many times is my average download speed faster to be able to steal".
-    ///
-    /// If this returns, an existing in-flight piece was marked to be ours.
+    // A piece is reserved for one peer, and if that peer is very slow, the piece holds back the
+    // ones that depend on it, e.g. when streaming. Take over a piece that another peer has been
+    // downloading for "threshold" times longer than we take for a piece, if we'd be much faster
+    // at finishing it. The slow peer gets a Cancel for its requests.
     fn try_steal_old_slow_piece(&self, threshold: f64) -> Option<ValidPieceIndex> {
-        let my_avg_time = match self.counters.average_piece_download_time() {
-            Some(t) => t,
-            None => return None,
-        };
+        let my_avg_time = self.counters.average_piece_download_time()?;
+        let my_bps = self.state.lengths.default_piece_length() as f64 / my_avg_time.as_secs_f64();
 
-        let (stolen_idx, from_peer) = {
+        let candidates = self
+            .state
+            .lock_read("try_steal_old_slow_piece")
+            .steal_candidates(self.addr, my_avg_time, threshold)
+            .ok()?;
+        let (stolen_idx, from_peer, started) = candidates.into_iter().find(|(_, peer, _)| {
+            let their_bps = self
+                .state
+                .peers
+                .with_peer(*peer, |p| p.stats.down_speed.bps())
+                .unwrap_or_default();
+            their_bps as f64 * STEAL_MIN_SPEEDUP < my_bps
+        })?;
+
+        {
             let mut g = self.state.lock_write("try_steal_old_slow_piece");
-            let (idx, elapsed, piece_req) = g
-                .inflight_pieces
-                .iter_mut()
-                // don't steal from myself
-                .filter(|(_, r)| r.peer != self.addr)
-                .map(|(p, r)| (p, r.started.elapsed(), r))
-                .max_by_key(|(_, e, _)| *e)?;
-
-            // heuristic for "too slow peer"
-            if elapsed.as_secs_f64() > my_avg_time.as_secs_f64() * threshold {
-                debug!(
-                    "will steal piece {} from {}: elapsed time {:?}, my avg piece time: {:?}",
-                    idx, piece_req.peer, elapsed, my_avg_time
-                );
-                let old = piece_req.peer;
-                piece_req.peer = self.addr;
-                piece_req.started = Instant::now();
-                (*idx, old)
-            } else {
+            if !g.take_over_piece(stolen_idx, from_peer, started, self.addr) {
                 return None;
             }
-        };
+            debug!(
+                "stole piece {} from {}: elapsed time {:?}, my avg piece time: {:?}",
+                stolen_idx,
+                from_peer,
+                started.elapsed(),
+                my_avg_time
+            );
+            // Along with the chunks it sent, the piece is downloaded from the start.
+            self.state
+                .discard_buffered_chunks(g.get_chunks_mut().ok()?, stolen_idx);
+        }
 
         // Send cancellations to old peer and bump counters.
         self.state.peers.on_steal(from_peer, self.addr, stolen_idx);
@@ -1061,6 +2224,67 @@ impl PeerHandler {
         Some(stolen_idx)
     }
 
+    // Endgame mode: peers that have nothing else to do request the missing chunks of pieces
+    // in flight too. Whoever delivers a chunk first wins, and the others that were asked for it
+    // get a Cancel. Otherwise the last few pieces can take very long if they went to slow peers.
+    fn try_join_endgame_piece(&self) -> anyhow::Result<Option<(ValidPieceIndex, Vec<ChunkInfo>)>> {
+        self.state
+            .peers
+            .with_live(self.addr, |live| {
+                self.state
+                    .lock_write("try_join_endgame_piece")
+                    .join_endgame_piece(self.addr, &live.bitfield)
+            })
+            .transpose()
+            .map(|r| r.flatten())
+    }
+
+    // A piece is normally downloaded from the one peer that reserved it. When there are fewer
+    // pieces to reserve than peers that could download them, e.g. in small torrents or ones with
+    // large pieces, idle peers share the pieces in flight instead. Unlike in endgame mode, each
+    // chunk is requested from only one of them: whoever claims it first. If the piece fails the
+    // hash check, it counts against all of them (piece_contributors).
+    fn try_split_piece(&self) -> anyhow::Result<Option<(ValidPieceIndex, Vec<ChunkInfo>)>> {
+        self.state
+            .peers
+            .with_live(self.addr, |live| {
+                if self.locked.read().i_am_choked {
+                    return Ok(None);
+                }
+                self.state
+                    .lock_write("try_split_piece")
+                    .split_piece(self.addr, &live.bitfield)
+            })
+            .transpose()
+            .map(|r| r.flatten())
+    }
+
+    // False if the chunk was requested by another peer sharing the piece, or the piece isn't
+    // in flight anymore.
+    fn claim_chunk(&self, chunk: &ChunkInfo) -> bool {
+        self.state
+            .lock_write("claim_chunk")
+            .inflight_pieces
+            .get_mut(&chunk.piece_index)
+            .map(|p| p.claim_chunk(chunk, self.addr))
+            .unwrap_or(false)
+    }
+
+    // A chunk from this peer that someone else had already sent.
+    fn on_wasted_chunk(&self, chunk_info: &ChunkInfo) {
+        let bytes = chunk_info.size as u64;
+        self.state.on_wasted_bytes(bytes);
+        self.counters
+            .wasted_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // The piece isn't ours to download anymore, so the peer shouldn't waste its upload on
+    // the rest of it.
+    fn cancel_remaining_requests(&self, index: ValidPieceIndex) {
+        self.state.peers.cancel_requests(self.addr, index, None);
+    }
+
     fn on_download_request(&self, request: Request) -> anyhow::Result<()> {
         let piece_index = match self.state.lengths.validate_piece_index(request.index) {
             Some(p) => p,
@@ -1072,10 +2296,11 @@ impl PeerHandler {
             }
         };
 
-        let chunk_info = match self.state.lengths.chunk_info_from_received_data(
+        let chunk_info = match self.state.lengths.chunk_info_from_request(
             piece_index,
             request.begin,
             request.length,
+            self.state.max_request_block_size(),
         ) {
             Some(d) => d,
             None => {
@@ -1086,24 +2311,65 @@ impl PeerHandler {
             }
         };
 
-        if !self
+        if self
             .state
-            .lock_read("is_chunk_ready_to_upload")
-            .get_chunks()?
-            .is_chunk_ready_to_upload(&chunk_info)
+            .peers
+            .with_live(self.addr, |live| live.i_am_choking)
+            .unwrap_or(true)
         {
+            if self.locked.read().supports_fast {
+                self.reject_request(request)?;
+            } else {
+                trace!("ignoring request from a choked peer: {:?}", request);
+            }
+            return Ok(());
+        }
+
+        if !self.state.chunks_shared.have_piece(chunk_info.piece_index) {
+            if self.locked.read().supports_fast {
+                debug!(
+                    "rejecting request for a chunk we don't have: {:?}",
+                    &chunk_info
+                );
+                return self.reject_request(request);
+            }
             anyhow::bail!(
                 "got request for a chunk that is not ready to upload. chunk {:?}",
                 &chunk_info
             );
         }
 
+        // Don't let a peer that requests more than our reqq grow the write queue. The rest of
+        // it is left for our own messages.
+        let queued = self.queued_uploads.fetch_add(1, Ordering::Relaxed);
+        if queued >= PEER_UPLOAD_QUEUE_DEPTH {
+            self.queued_uploads.fetch_sub(1, Ordering::Relaxed);
+            if self.locked.read().supports_fast {
+                trace!(queued, "too many requests queued, rejecting {:?}", request);
+                self.reject_request(request)?;
+            } else {
+                trace!(queued, "too many requests queued, ignoring {:?}", request);
+            }
+            return Ok(());
+        }
+
         // TODO: this is not super efficient as it does copying multiple times.
         // Theoretically, this could be done in the sending code, so that it reads straight into
         // the send buffer.
         let request = WriterRequest::ReadChunkRequest(chunk_info);
         trace!("sending {:?}", &request);
-        Ok::<_, anyhow::Error>(self.tx.send(request)?)
+        if let Err(e) = self.state.send_without_waiting(&self.tx, request) {
+            self.queued_uploads.fetch_sub(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn reject_request(&self, request: Request) -> anyhow::Result<()> {
+        self.state.send_without_waiting(
+            &self.tx,
+            WriterRequest::Message(MessageOwned::RejectRequest(request)),
+        )
     }
 
     fn on_have(&self, have: u32) {
@@ -1113,20 +2379,209 @@ impl PeerHandler {
                 // If bitfield wasn't allocated yet, let's do it. Some clients start empty so they never
                 // send bitfields.
                 if live.bitfield.is_empty() {
-                    live.bitfield = make_piece_bitfield(&self.state.lengths);
+                    live.bitfield = CompactBitfield::have_none(self.state.lengths.total_pieces());
                 }
-                match live.bitfield.get_mut(have as usize) {
-                    Some(mut v) => *v = true,
+                match live.bitfield.get(have) {
+                    Some(false) => self.state.peers.stats.availability.on_have(have),
+                    Some(true) => {}
                     None => {
                         warn!("received have {} out of range", have);
                         return;
                     }
-                };
+                }
+                live.bitfield.set(have, true);
                 trace!("updated bitfield with have={}", have);
             });
+        self.state.update_interest_later([self.addr]);
+        self.on_bitfield_notify.notify_waiters();
+    }
+
+    fn on_donthave(&self, index: u32) {
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_donthave", |live| {
+                if live.bitfield.get(index) == Some(true) {
+                    live.bitfield.set(index, false);
+                    self.state.peers.stats.availability.on_donthave(index);
+                    trace!("updated bitfield with donthave={}", index);
+                }
+            });
+        self.state.update_interest_later([self.addr]);
+    }
+
+    fn on_pex_message(&self, pex: UtPex<ByteBuf<'_>>) {
+        if self.state.meta.info.is_private() {
+            trace!("ignoring ut_pex for a private torrent");
+            return;
+        }
+        let mut added = 0;
+        for addr in pex.added_peers().take(UT_PEX_MAX_PEERS) {
+            match self.state.add_peer_if_not_seen(addr, PeerOrigin::Pex) {
+                Ok(true) => {
+                    added += 1;
+                    self.state
+                        .peers
+                        .with_peer_mut(addr, "set_holepunch_relay", |p| {
+                            p.holepunch_relay = Some(self.addr)
+                        });
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    debug!("error adding peer {addr} from ut_pex: {e:#}");
+                    return;
+                }
+            }
+        }
+        trace!("added {added} new peers from ut_pex");
+    }
+
+    fn on_holepunch_message(&self, msg: UtHolepunch) {
+        if self.state.meta.info.is_private() {
+            trace!("ignoring ut_holepunch for a private torrent");
+            return;
+        }
+        let send = |tx: &PeerTx, msg: UtHolepunch| {
+            let _ = self.state.send_without_waiting(
+                tx,
+                WriterRequest::Message(Message::Extended(ExtendedMessage::UtHolepunch(msg))),
+            );
+        };
+        match msg {
+            // Relay: tell both peers to connect to each other at the same time.
+            UtHolepunch::Rendezvous(target) => {
+                let error = if target == self.addr {
+                    Some(UtHolepunchError::NoSelf)
+                } else {
+                    match self.state.peers.with_peer(target, |p| {
+                        p.state
+                            .get_live()
+                            .map(|live| live.supports_holepunch.then(|| live.tx.clone()))
+                    }) {
+                        None => Some(UtHolepunchError::NoSuchPeer),
+                        Some(None) => Some(UtHolepunchError::NotConnected),
+                        Some(Some(None)) => Some(UtHolepunchError::NoSupport),
+                        Some(Some(Some(target_tx))) => {
+                            trace!(%target, "relaying ut_holepunch rendezvous");
+                            send(&target_tx, UtHolepunch::Connect(self.addr));
+                            send(&self.tx, UtHolepunch::Connect(target));
+                            None
+                        }
+                    }
+                };
+                if let Some(error) = error {
+                    send(&self.tx, UtHolepunch::Error(target, error));
+                }
+            }
+            UtHolepunch::Connect(addr) => {
+                if let Err(e) = self.state.on_holepunch_connect(addr) {
+                    debug!("error connecting to {addr} for ut_holepunch: {e:#}");
+                }
+            }
+            UtHolepunch::Error(addr, error) => {
+                debug!("ut_holepunch with {addr} failed: {error:?}")
+            }
+        }
+    }
+
+    fn on_dht_port(&self, port: u16) {
+        let dht = match self.state.meta.options.dht.clone() {
+            Some(dht) => dht,
+            None => {
+                trace!("received DHT port {}, but DHT is disabled", port);
+                return;
+            }
+        };
+        if !self.state.peers.mark_dht_port_pinged(self.addr) {
+            trace!(
+                "already pinged the DHT port of this peer, ignoring {}",
+                port
+            );
+            return;
+        }
+        let addr = SocketAddr::new(self.addr.ip(), port);
+        self.state.spawn(
+            error_span!(parent: self.state.meta.span.clone(), "dht_ping", addr = %addr),
+            async move {
+                match dht.ping_and_add_node(addr).await {
+                    Ok(id) => debug!("added DHT node {:?} from PORT message", id),
+                    Err(e) => debug!("error pinging DHT node from PORT message: {:#}", e),
+                }
+                Ok(())
+            },
+        );
+    }
+
+    fn on_have_all(&self) {
+        let total_pieces = self.state.lengths.total_pieces();
+        self.state
+            .peers
+            .update_bitfield(self.addr, CompactBitfield::have_all(total_pieces));
+        self.state.update_interest_later([self.addr]);
+        self.on_bitfield_notify.notify_waiters();
+    }
+
+    fn on_have_none(&self) {
+        let total_pieces = self.state.lengths.total_pieces();
+        self.state
+            .peers
+            .update_bitfield(self.addr, CompactBitfield::have_none(total_pieces));
+        self.state.update_interest_later([self.addr]);
         self.on_bitfield_notify.notify_waiters();
     }
 
+    fn on_allowed_fast(&self, index: u32) {
+        let index = match self.state.lengths.validate_piece_index(index) {
+            Some(index) => index,
+            None => {
+                debug!("received allowed fast for invalid piece {index}, ignoring");
+                return;
+            }
+        };
+        let mut g = self.locked.write();
+        let was_empty = g.allowed_fast.is_empty();
+        g.allowed_fast.insert(index);
+        if was_empty && g.i_am_choked {
+            // Let the requester proceed with allowed pieces while we are choked.
+            self.requests_sem.add_permits(g.pipeline.depth());
+            self.unchoke_notify.notify_waiters();
+        }
+    }
+
+    fn on_request_rejected(&self, request: Request) -> anyhow::Result<()> {
+        let chunk_info = self
+            .state
+            .lengths
+            .validate_piece_index(request.index)
+            .and_then(|index| {
+                self.state.lengths.chunk_info_from_received_data(
+                    index,
+                    request.begin,
+                    request.length,
+                )
+            })
+            .with_context(|| format!("peer rejected an invalid request {:?}", request))?;
+
+        let was_inflight = self
+            .state
+            .peers
+            .with_live_mut(self.addr, "inflight_requests.remove", |live| {
+                live.inflight_requests.remove(&chunk_info).is_some()
+            })
+            .unwrap_or_default();
+        if !was_inflight {
+            debug!("peer rejected a request we didn't send: {:?}", request);
+            return Ok(());
+        }
+
+        debug!("peer rejected request, marking chunk request cancelled: {chunk_info:?}");
+        self.state
+            .lock_write("mark_chunk_request_cancelled")
+            .get_chunks_mut()?
+            .mark_chunk_request_cancelled(chunk_info.piece_index, chunk_info.chunk_index);
+        self.requests_sem.add_permits(1);
+        Ok(())
+    }
+
     fn on_bitfield(&self, bitfield: ByteBufOwned) -> anyhow::Result<()> {
         if bitfield.len() != self.state.lengths.piece_bitfield_bytes() {
             anyhow::bail!(
@@ -1135,9 +2590,9 @@ impl PeerHandler {
                 self.state.lengths.piece_bitfield_bytes(),
             );
         }
-        self.state
-            .peers
-            .update_bitfield_from_vec(self.addr, bitfield.0);
+        let bitfield = CompactBitfield::from_bytes(&bitfield, self.state.lengths.total_pieces());
+        self.state.peers.update_bitfield(self.addr, bitfield);
+        self.state.update_interest_later([self.addr]);
         self.on_bitfield_notify.notify_waiters();
         Ok(())
     }
@@ -1163,37 +2618,57 @@ impl PeerHandler {
     }
 
     async fn wait_for_unchoke(&self) {
-        self.wait_for_any_notify(&self.unchoke_notify, || !self.locked.read().i_am_choked)
-            .await;
+        self.wait_for_any_notify(&self.unchoke_notify, || {
+            let g = self.locked.read();
+            !g.i_am_choked || !g.allowed_fast.is_empty()
+        })
+        .await;
+    }
+
+    // Drop the peer if it has pieces we need, but sends none of them, so that its slot goes to
+    // another peer. It will be retried later.
+    async fn task_evict_if_inactive(&self) -> anyhow::Result<()> {
+        let inactivity_timeout = self
+            .state
+            .meta
+            .options
+            .peer_inactivity_timeout
+            .unwrap_or(DEFAULT_PEER_INACTIVITY_TIMEOUT);
+        loop {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            let inactive = self
+                .state
+                .peers
+                .with_live(self.addr, |live| {
+                    live.i_am_interested && live.last_useful_data.elapsed() > inactivity_timeout
+                })
+                .unwrap_or_default();
+            if inactive {
+                anyhow::bail!("no useful data from peer in {inactivity_timeout:?}, disconnecting");
+            }
+        }
     }
 
     async fn task_peer_chunk_requester(&self) -> anyhow::Result<()> {
         let handle = self.addr;
         self.wait_for_bitfield().await;
 
-        // TODO: this check needs to happen more often, we need to update our
-        // interested state with the other side, for now we send it only once.
-        if self.state.is_finished() {
-            self.tx
-                .send(WriterRequest::Message(MessageOwned::NotInterested))?;
+        self.state.update_interest(handle).await;
 
-            if self
+        if self.state.is_finished()
+            && self
                 .state
                 .peers
                 .with_live(self.addr, |l| {
-                    l.has_full_torrent(self.state.lengths.total_pieces() as usize)
+                    l.is_seed(self.state.lengths.total_pieces() as usize)
                 })
                 .unwrap_or_default()
-            {
-                debug!("both peer and us have full torrent, disconnecting");
-                self.tx.send(WriterRequest::Disconnect)?;
-                // Sleep a bit to ensure this gets written to the network by manage_peer
-                tokio::time::sleep(Duration::from_millis(100)).await;
-                return Ok(());
-            }
-        } else {
-            self.tx
-                .send(WriterRequest::Message(MessageOwned::Interested))?;
+        {
+            debug!("both peer and us have full torrent, disconnecting");
+            self.tx.send(WriterRequest::Disconnect).await?;
+            // Sleep a bit to ensure this gets written to the network by manage_peer
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            return Ok(());
         }
 
         loop {
@@ -1206,61 +2681,113 @@ impl PeerHandler {
                 }
             }
 
+            self.expire_lost_requests()?;
+
             // Try steal a pice from a very slow peer first. Otherwise we might wait too long
             // to download early pieces.
             // Then try get the next one in queue.
+            // If there's none, share a piece with the peer downloading it.
             // Afterwards means we are close to completion, try stealing more aggressively.
-            let next = match self
+            // If everything needed is already in flight, help downloading it (endgame).
+            let next = self
                 .try_steal_old_slow_piece(10.)
-                .map_or_else(|| self.reserve_next_needed_piece(), |v| Ok(Some(v)))?
-                .or_else(|| self.try_steal_old_slow_piece(3.))
-            {
-                Some(next) => next,
-                None => {
-                    debug!("no pieces to request");
-                    tokio::time::sleep(Duration::from_secs(10)).await;
+                .map_or_else(|| self.reserve_next_needed_piece(), |v| Ok(Some(v)))?;
+            let split = match next {
+                Some(_) => None,
+                None => self.try_split_piece()?,
+            };
+            // Endgame requests are meant to duplicate others, the rest need claiming chunks.
+            let mut claim = true;
+            let (next, chunks) = match split {
+                Some(split) => split,
+                None => match next.or_else(|| self.try_steal_old_slow_piece(3.)) {
+                    // Chunks that are already on disk, e.g. from before a restart, are skipped.
+                    Some(next) => {
+                        let g = self.state.lock_read("missing_chunks");
+                        let chunks = g.get_chunks()?;
+                        (
+                            next,
+                            self.state
+                                .lengths
+                                .iter_chunk_infos(next)
+                                .filter(|c| !chunks.is_chunk_downloaded(c))
+                                .collect::<Vec<_>>(),
+                        )
+                    }
+                    None => match self.try_join_endgame_piece()? {
+                        Some(next) => {
+                            claim = false;
+                            next
+                        }
+                        None => {
+                            debug!("no pieces to request");
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                            continue;
+                        }
+                    },
+                },
+            };
+
+            for chunk in chunks {
+                if claim && !self.claim_chunk(&chunk) {
                     continue;
                 }
-            };
 
-            for chunk in self.state.lengths.iter_chunk_infos(next) {
                 let request = Request {
                     index: next.get(),
                     begin: chunk.offset,
                     length: chunk.size,
                 };
 
-                match self
-                    .state
-                    .peers
-                    .with_live_mut(handle, "add chunk request", |live| {
-                        live.inflight_requests.insert(chunk)
-                    }) {
-                    Some(true) => {}
-                    Some(false) => {
-                        // This request was already in-flight for this peer for this chunk.
-                        // This might happen in theory, but not very likely.
-                        //
-                        // Example:
-                        // someone stole a piece from us, and then died, the piece became "needed" again, and we reserved it
-                        // all before the piece request was processed by us.
-                        warn!("we already requested {:?} previously", chunk);
-                        continue;
-                    }
-                    // peer died
-                    None => return Ok(()),
-                };
+                let was_idle =
+                    match self
+                        .state
+                        .peers
+                        .with_live_mut(handle, "add chunk request", |live| {
+                            if live.inflight_requests.contains_key(&chunk) {
+                                return None;
+                            }
+                            let was_idle = live.inflight_requests.is_empty();
+                            let now = Instant::now();
+                            if was_idle {
+                                live.last_request_progress = now;
+                            }
+                            live.inflight_requests.insert(chunk, now);
+                            Some(was_idle)
+                        }) {
+                        Some(Some(was_idle)) => was_idle,
+                        Some(None) => {
+                            // This request was already in-flight for this peer for this chunk.
+                            // This might happen in theory, but not very likely.
+                            //
+                            // Example:
+                            // someone stole a piece from us, and then died, the piece became "needed" again, and we reserved it
+                            // all before the piece request was processed by us.
+                            warn!("we already requested {:?} previously", chunk);
+                            continue;
+                        }
+                        // peer died
+                        None => return Ok(()),
+                    };
 
                 loop {
                     match timeout(Duration::from_secs(10), self.requests_sem.acquire()).await {
                         Ok(acq) => break acq?.forget(),
-                        Err(_) => continue,
+                        Err(_) => {
+                            self.maybe_mark_snubbed()?;
+                            self.expire_lost_requests()?;
+                        }
                     };
                 }
 
+                if was_idle {
+                    self.locked.write().rtt_probe = Some((chunk, Instant::now()));
+                }
+
                 if self
                     .tx
                     .send(WriterRequest::Message(MessageOwned::Request(request)))
+                    .await
                     .is_err()
                 {
                     return Ok(());
@@ -1269,20 +2796,159 @@ impl PeerHandler {
         }
     }
 
-    fn on_i_am_choked(&self) {
-        self.locked.write().i_am_choked = true;
+    // Cancel requests that the peer seems to have lost: it answered requests sent after them,
+    // but not these for a long time. They are put back into the queue to be requested again,
+    // from this peer or another one, instead of their pieces waiting for them forever.
+    fn expire_lost_requests(&self) -> anyhow::Result<()> {
+        let last_answered = match self.locked.read().last_answered_request {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let expired = self
+            .state
+            .peers
+            .with_live_mut(self.addr, "expire_lost_requests", |live| {
+                live.expire_lost_requests(last_answered, REQUEST_TIMEOUT)
+            })
+            .unwrap_or_default();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            requests = expired.len(),
+            "requests timed out, requesting them again"
+        );
+        self.locked
+            .write()
+            .timed_out_requests
+            .extend(expired.iter().copied());
+        let mut g = self.state.lock_write("mark_expired_requests_cancelled");
+        for req in expired.iter() {
+            g.get_chunks_mut()?
+                .mark_chunk_request_cancelled(req.piece_index, req.chunk_index);
+        }
+        // The pieces may go to other peers, don't keep what we buffered for them.
+        let abandoned = expired
+            .iter()
+            .map(|req| req.piece_index)
+            .filter(|index| {
+                g.inflight_pieces.get(index).is_some_and(|p| {
+                    p.peer == self.addr && p.endgame_peers.is_empty() && p.split_peers.is_empty()
+                })
+            })
+            .collect::<HashSet<_>>();
+        for index in abandoned {
+            self.state.drop_buffered_piece(g.get_chunks_mut()?, index);
+        }
+        drop(g);
+        self.requests_sem.add_permits(expired.len());
+        Ok(())
+    }
+
+    // Called while the request queue to the peer is full. If the peer hasn't delivered anything
+    // for a while, let other peers download what we asked it for, and keep only one request
+    // in flight to it until it delivers again.
+    fn maybe_mark_snubbed(&self) -> anyhow::Result<()> {
+        if self.locked.read().i_am_choked {
+            return Ok(());
+        }
+        let inflight = self
+            .state
+            .peers
+            .with_live_mut(self.addr, "maybe_mark_snubbed", |live| {
+                live.mark_snubbed(SNUB_TIMEOUT)
+            })
+            .unwrap_or_default();
+        if inflight.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            requests = inflight.len(),
+            "peer is snubbing us, letting others download its requests"
+        );
+        // Like timed out requests, so that late chunks are still accepted.
+        self.locked
+            .write()
+            .timed_out_requests
+            .extend(inflight.iter().copied());
+        let mut g = self.state.lock_write("mark_snubbed_requests_cancelled");
+        for req in inflight {
+            g.get_chunks_mut()?
+                .mark_chunk_request_cancelled(req.piece_index, req.chunk_index);
+        }
+        // The permits of the cancelled requests are given back once it delivers again.
+        self.requests_sem.add_permits(1);
+        Ok(())
+    }
+
+    fn on_i_am_choked(&self) -> anyhow::Result<()> {
+        let supports_fast = {
+            let mut g = self.locked.write();
+            g.i_am_choked = true;
+            g.supports_fast
+        };
+        // With the Fast Extension, the peer rejects pending requests explicitly. Otherwise
+        // they are dropped when choking, so put them back into the queue.
+        if supports_fast {
+            return Ok(());
+        }
+        let inflight = self
+            .state
+            .peers
+            .with_live_mut(self.addr, "on_i_am_choked", |live| {
+                std::mem::take(&mut live.inflight_requests)
+            })
+            .unwrap_or_default();
+        if inflight.is_empty() {
+            return Ok(());
+        }
+        let mut g = self.state.lock_write("mark_chunk_requests_canceled");
+        for req in inflight.into_keys() {
+            trace!(
+                "choked, marking chunk request cancelled, index={}, chunk={}",
+                req.piece_index.get(),
+                req.chunk_index
+            );
+            g.get_chunks_mut()?
+                .mark_chunk_request_cancelled(req.piece_index, req.chunk_index);
+        }
+        Ok(())
     }
 
     fn on_peer_interested(&self) {
         trace!("peer is interested");
         self.state.peers.mark_peer_interested(self.addr, true);
+        let (state, addr) = (self.state.clone(), self.addr);
+        self.state.spawn(
+            error_span!(parent: self.state.meta.span.clone(), "maybe_unchoke", peer = %addr),
+            async move {
+                state.maybe_unchoke_interested_peer(addr).await;
+                Ok(())
+            },
+        );
+    }
+
+    fn on_peer_not_interested(&self) {
+        trace!("peer is not interested");
+        self.state.peers.mark_peer_interested(self.addr, false);
     }
 
     fn on_i_am_unchoked(&self) {
         trace!("we are unchoked");
-        self.locked.write().i_am_choked = false;
+        let depth = {
+            let mut g = self.locked.write();
+            g.i_am_choked = false;
+            g.pipeline.depth()
+        };
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_i_am_unchoked", |live| {
+                live.last_request_progress = Instant::now();
+            });
         self.unchoke_notify.notify_waiters();
-        self.requests_sem.add_permits(16);
+        self.requests_sem.add_permits(depth);
     }
 
     fn on_received_piece(&self, piece: Piece<ByteBuf>) -> anyhow::Result<()> {
@@ -1302,7 +2968,30 @@ impl PeerHandler {
             }
         };
 
-        self.requests_sem.add_permits(1);
+        let bytes_per_second = self
+            .state
+            .peers
+            .with_peer(self.addr, |p| p.stats.down_speed.bps())
+            .unwrap_or_default();
+        let timed_out;
+        let permits = {
+            let mut g = self.locked.write();
+            if let Some((probe, sent)) = g.rtt_probe {
+                if probe == chunk_info {
+                    g.pipeline.on_rtt_sample(sent.elapsed());
+                    g.rtt_probe = None;
+                }
+            }
+            timed_out = g.timed_out_requests.remove(&chunk_info);
+            if timed_out {
+                g.pipeline
+                    .on_late_chunk_received(bytes_per_second, chunk_info.size)
+            } else {
+                g.pipeline
+                    .on_chunk_received(bytes_per_second, chunk_info.size)
+            }
+        };
+        self.requests_sem.add_permits(permits);
 
         // Peer chunk/byte counters.
         self.counters
@@ -1310,30 +2999,66 @@ impl PeerHandler {
             .fetch_add(piece.block.len() as u64, Ordering::Relaxed);
         self.counters.fetched_chunks.fetch_add(1, Ordering::Relaxed);
 
-        self.state
+        let (sent, was_snubbed, in_flight) = self
+            .state
             .peers
             .with_live_mut(self.addr, "inflight_requests.remove", |h| {
-                if !h.inflight_requests.remove(&chunk_info) {
-                    anyhow::bail!(
-                        "peer sent us a piece we did not ask. Requested pieces: {:?}. Got: {:?}",
-                        &h.inflight_requests,
-                        &piece,
-                    );
+                // Including this one, as its permit was just given back.
+                let in_flight = h.inflight_requests.len();
+                let sent = h.inflight_requests.remove(&chunk_info);
+                let mut was_snubbed = false;
+                if sent.is_some() {
+                    h.last_request_progress = Instant::now();
+                    h.last_useful_data = h.last_request_progress;
+                    was_snubbed = std::mem::take(&mut h.snubbed);
                 }
-                Ok(())
+                (sent, was_snubbed, in_flight)
             })
-            .context("peer not found")??;
+            .context("peer not found")?;
+        if let Some(sent) = sent {
+            let mut g = self.locked.write();
+            g.last_answered_request = Some(g.last_answered_request.map_or(sent, |t| t.max(sent)));
+        }
+        // A late answer to a request that timed out is still useful, unless the chunk was
+        // downloaded from someone else meanwhile.
+        let requested = sent.is_some() || timed_out;
+        if was_snubbed {
+            debug!("peer is no longer snubbing us");
+            let depth = self.locked.read().pipeline.depth();
+            self.requests_sem
+                .add_permits(depth.saturating_sub(in_flight));
+        }
+        if !requested {
+            // We might have cancelled it after getting it from someone else.
+            if self
+                .state
+                .lock_read("is_chunk_downloaded")
+                .get_chunks()?
+                .is_chunk_downloaded(&chunk_info)
+            {
+                debug!("got a chunk that was already downloaded, ignoring: {chunk_info:?}");
+                return Ok(());
+            }
+            anyhow::bail!("peer sent us a piece we did not ask. Got: {:?}", &piece);
+        }
 
-        let full_piece_download_time = {
+        let mut endgame_losers = Vec::new();
+        let (full_piece_download_time, buffered, buffered_piece, contributors) = {
             let mut g = self.state.lock_write("mark_chunk_downloaded");
 
             match g.inflight_pieces.get(&chunk_info.piece_index) {
                 Some(InflightPiece { peer, .. }) if *peer == self.addr => {}
+                Some(p)
+                    if p.endgame_peers.contains(&self.addr)
+                        || p.split_peers.contains(&self.addr) => {}
                 Some(InflightPiece { peer, .. }) => {
                     debug!(
                         "in-flight piece {} was stolen by {}, ignoring",
                         chunk_info.piece_index, peer
                     );
+                    drop(g);
+                    self.on_wasted_chunk(&chunk_info);
+                    self.cancel_remaining_requests(chunk_info.piece_index);
                     return Ok(());
                 }
                 None => {
@@ -1341,11 +3066,30 @@ impl PeerHandler {
                         "in-flight piece {} not found. it was probably completed by someone else",
                         chunk_info.piece_index
                     );
+                    drop(g);
+                    self.on_wasted_chunk(&chunk_info);
+                    self.cancel_remaining_requests(chunk_info.piece_index);
                     return Ok(());
                 }
             };
 
-            match g.get_chunks_mut()?.mark_chunk_downloaded(&piece) {
+            if g.get_chunks()?.is_chunk_downloaded(&chunk_info) {
+                trace!("chunk {chunk_info:?} was already downloaded from another peer");
+                drop(g);
+                self.on_wasted_chunk(&chunk_info);
+                return Ok(());
+            }
+            if let Some(p) = g.inflight_pieces.get(&chunk_info.piece_index) {
+                if !p.endgame_peers.is_empty() {
+                    endgame_losers = p.other_peers(self.addr);
+                }
+            }
+
+            let is_first_chunk = !g
+                .get_chunks()?
+                .has_downloaded_chunks(chunk_info.piece_index);
+
+            let full_piece_download_time = match g.get_chunks_mut()?.mark_chunk_downloaded(&piece) {
                 Some(ChunkMarkingResult::Completed) => {
                     trace!("piece={} done, will write and checksum", piece.index,);
                     // This will prevent others from stealing it.
@@ -1356,8 +3100,10 @@ impl PeerHandler {
                     .map(|t| t.started.elapsed())
                 }
                 Some(ChunkMarkingResult::PreviouslyCompleted) => {
-                    // TODO: we might need to send cancellations here.
                     debug!("piece={} was done by someone else, ignoring", piece.index,);
+                    drop(g);
+                    self.on_wasted_chunk(&chunk_info);
+                    self.cancel_remaining_requests(chunk_info.piece_index);
                     return Ok(());
                 }
                 Some(ChunkMarkingResult::NotCompleted) => None,
@@ -1367,30 +3113,77 @@ impl PeerHandler {
                         piece
                     );
                 }
-            }
+            };
+
+            // Taken before releasing "locked" so that whoever completes the piece sees this
+            // chunk in both.
+            let mut write_cache = self.state.write_cache.lock();
+            let mut piece_contributors = self.state.piece_contributors.lock();
+            drop(g);
+
+            *piece_contributors
+                .entry(chunk_info.piece_index)
+                .or_default()
+                .entry(self.addr)
+                .or_default() += chunk_info.size as u64;
+            let contributors = match full_piece_download_time {
+                Some(_) => piece_contributors
+                    .remove(&chunk_info.piece_index)
+                    .unwrap_or_default(),
+                None => Default::default(),
+            };
+            drop(piece_contributors);
+
+            let buffered = write_cache.put(
+                &self.state.lengths,
+                &chunk_info,
+                piece.block.as_ref(),
+                is_first_chunk,
+            );
+            let buffered_piece = match full_piece_download_time {
+                Some(_) if buffered => write_cache.take(chunk_info.piece_index),
+                _ => None,
+            };
+            (
+                full_piece_download_time,
+                buffered,
+                buffered_piece,
+                contributors,
+            )
         };
 
+        // Other peers asked for the same data in endgame mode won't need to send it. Once the
+        // piece is complete, none of its chunks are needed.
+        for loser in endgame_losers {
+            let chunk = match full_piece_download_time {
+                Some(_) => None,
+                None => Some(&chunk_info),
+            };
+            self.state
+                .peers
+                .cancel_requests(loser, chunk_info.piece_index, chunk);
+        }
+
         // By this time we reach here, no other peer can for this piece. All others, even if they steal pieces would
         // have fallen off above in one of the defensive checks.
 
         self.state
             .meta
             .spawner
-            .spawn_block_in_place(move || {
-                let index = piece.index;
-
-                // TODO: in theory we should unmark the piece as downloaded here. But if there was a disk error, what
-                // should we really do? If we unmark it, it will get requested forever...
-                //
-                // So let's just unwrap and abort.
-                match self
-                    .state
-                    .file_ops()
-                    .write_chunk(self.addr, &piece, &chunk_info)
-                {
-                    Ok(()) => {}
-                    Err(e) => {
+            .spawn_block_in_place(|| {
+                // On a disk error the piece is marked broken and the torrent gets paused with the error, so
+                // that it's downloaded again once the user fixes the disk and resumes.
+                if !buffered {
+                    if let Err(e) =
+                        self.state
+                            .file_ops()
+                            .write_chunk(self.addr, &piece, &chunk_info)
+                    {
                         error!("FATAL: error writing chunk to disk: {:?}", e);
+                        self.state
+                            .lock_write("write_chunk_failed")
+                            .get_chunks_mut()?
+                            .mark_piece_broken_if_not_have(chunk_info.piece_index);
                         return self.state.on_fatal_error(e);
                     }
                 }
@@ -1400,75 +3193,322 @@ impl PeerHandler {
                     .stats
                     .fetched_bytes
                     .fetch_add(piece.block.len() as u64, Ordering::Relaxed);
+                Ok::<_, anyhow::Error>(())
+            })
+            .with_context(|| format!("error processing received chunk {chunk_info:?}"))?;
 
-                let full_piece_download_time = match full_piece_download_time {
-                    Some(t) => t,
-                    None => return Ok(()),
-                };
+        let full_piece_download_time = match full_piece_download_time {
+            Some(t) => t,
+            None => return Ok(()),
+        };
 
-                match self
-                    .state
-                    .file_ops()
-                    .check_piece(self.addr, chunk_info.piece_index, &chunk_info)
-                    .with_context(|| format!("error checking piece={index}"))?
-                {
-                    true => {
-                        {
-                            let mut g = self.state.lock_write("mark_piece_downloaded");
-                            g.get_chunks_mut()?
-                                .mark_piece_downloaded(chunk_info.piece_index);
+        // Hashing happens on the CPU pool so that this peer keeps reading and writing
+        // meanwhile. No other peer can touch this piece until the result is known.
+        let state = self.state.clone();
+        let counters = self.counters.clone();
+        let addr = self.addr;
+        let tx = self.tx.clone();
+        self.state.spawn(
+            error_span!("verify_piece", piece = chunk_info.piece_index.get()),
+            async move {
+                let index = chunk_info.piece_index;
+                let verified = state
+                    .meta
+                    .cpu_pool
+                    .run({
+                        let state = state.clone();
+                        move || {
+                            let file_ops = state.file_ops();
+                            let verified = match &buffered_piece {
+                                Some(data) => file_ops.check_piece_data(index, data),
+                                None => file_ops.check_piece(addr, index, &chunk_info),
+                            }
+                            .with_context(|| format!("error checking piece={index}"))?;
+
+                            // A buffered piece is only written once it's known to be good.
+                            if let (true, Some(data)) = (verified, &buffered_piece) {
+                                if let Err(e) = file_ops.write_piece(index, data) {
+                                    error!("FATAL: error writing piece to disk: {:?}", e);
+                                    state
+                                        .lock_write("write_piece_failed")
+                                        .get_chunks_mut()?
+                                        .mark_piece_broken_if_not_have(index);
+                                    state.on_fatal_error(e)?;
+                                }
+                            }
+                            Ok::<_, anyhow::Error>(verified)
                         }
+                    })
+                    .await??;
+
+                if verified {
+                    // Per-peer piece counters.
+                    let piece_len = state.lengths.piece_length(index) as u64;
+                    counters.on_piece_downloaded(piece_len, full_piece_download_time);
+                    state.peers.reset_peer_backoff(addr);
+
+                    debug!("piece={} successfully downloaded and verified", index);
+
+                    state.on_piece_verified(index, full_piece_download_time)?;
+                } else {
+                    warn!(
+                        "checksum for piece={} did not validate. disconecting peer.",
+                        index
+                    );
+                    state
+                        .stats
+                        .hash_failed_pieces
+                        .fetch_add(1, Ordering::Relaxed);
+                    state.on_wasted_bytes(state.lengths.piece_length(index) as u64);
+                    state
+                        .lock_write("mark_piece_hash_failed")
+                        .get_chunks_mut()?
+                        .mark_piece_hash_failed(index);
+                    state.on_piece_hash_failed(&contributors);
+                    // The peer is probably bogus.
+                    let _ = tx.send(WriterRequest::Disconnect).await;
+                }
+                Ok(())
+            },
+        );
+        Ok(())
+    }
+}
 
-                        // Global piece counters.
-                        let piece_len =
-                            self.state.lengths.piece_length(chunk_info.piece_index) as u64;
-                        self.state
-                            .stats
-                            .downloaded_and_checked_bytes
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(piece_len, Ordering::Release);
-                        self.state
-                            .stats
-                            .downloaded_and_checked_pieces
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(1, Ordering::Release);
-                        self.state
-                            .stats
-                            .have_bytes
-                            .fetch_add(piece_len, Ordering::Relaxed);
-                        self.state.stats.total_piece_download_ms.fetch_add(
-                            full_piece_download_time.as_millis() as u64,
-                            Ordering::Relaxed,
-                        );
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
 
-                        // Per-peer piece counters.
-                        self.counters
-                            .on_piece_downloaded(piece_len, full_piece_download_time);
-                        self.state.peers.reset_peer_backoff(self.addr);
+    use librqbit_core::{
+        compact_bitfield::CompactBitfield,
+        lengths::{Lengths, ValidPieceIndex},
+    };
 
-                        debug!("piece={} successfully downloaded and verified", index);
+    use peer_binary_protocol::Piece;
 
-                        self.state.on_piece_completed(chunk_info.piece_index)?;
+    use crate::{chunk_tracker::ChunkTracker, type_aliases::BF};
 
-                        self.state.maybe_transmit_haves(chunk_info.piece_index);
-                    }
-                    false => {
-                        warn!(
-                            "checksum for piece={} did not validate. disconecting peer.",
-                            index
-                        );
-                        self.state
-                            .lock_write("mark_piece_broken")
-                            .get_chunks_mut()?
-                            .mark_piece_broken_if_not_have(chunk_info.piece_index);
-                        anyhow::bail!("i am probably a bogus peer. dying.")
-                    }
-                };
-                Ok::<_, anyhow::Error>(())
-            })
-            .with_context(|| format!("error processing received chunk {chunk_info:?}"))?;
-        Ok(())
+    use super::{
+        InflightPiece, PeerHandle, TorrentStateLocked, CHUNK_SIZE, ENDGAME_MAX_EXTRA_PEERS,
+        SPLIT_MAX_EXTRA_PEERS,
+    };
+
+    fn peer(port: u16) -> PeerHandle {
+        format!("127.0.0.1:{port}").parse().unwrap()
+    }
+
+    // 4 pieces of 4 chunks each, none of them downloaded. Pieces in "inflight" are reserved by
+    // the given peers, the first one for the longest.
+    fn new_state(inflight: &[(u32, PeerHandle)]) -> TorrentStateLocked {
+        let l = Lengths::new(CHUNK_SIZE as u64 * 16, CHUNK_SIZE * 4).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let have = BF::from_boxed_slice(vec![0; bf_len].into_boxed_slice());
+        let selected = BF::from_boxed_slice(vec![0b1111_0000; bf_len].into_boxed_slice());
+        let mut chunks = ChunkTracker::new(have, selected, l).unwrap();
+        let mut inflight_pieces = HashMap::new();
+        let now = Instant::now();
+        for (i, (index, peer)) in inflight.iter().enumerate() {
+            let index = l.validate_piece_index(*index).unwrap();
+            chunks.reserve_needed_piece(index);
+            inflight_pieces.insert(
+                index,
+                InflightPiece {
+                    peer: *peer,
+                    started: now - Duration::from_secs((inflight.len() - i) as u64),
+                    endgame_peers: Vec::new(),
+                    split_peers: Vec::new(),
+                    claimed_chunks: Default::default(),
+                },
+            );
+        }
+        TorrentStateLocked {
+            chunks: Some(chunks),
+            inflight_pieces,
+        }
+    }
+
+    fn piece(g: &TorrentStateLocked, index: u32) -> ValidPieceIndex {
+        g.get_chunks()
+            .unwrap()
+            .get_lengths()
+            .validate_piece_index(index)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_split_piece() {
+        let mut g = new_state(&[(2, peer(1)), (1, peer(2))]);
+        let (p1, p2) = (piece(&g, 1), piece(&g, 2));
+        let has_all = CompactBitfield::have_all(4);
+
+        // The oldest piece first, without the chunks its peer requested already.
+        let last_chunk = g
+            .get_chunks()
+            .unwrap()
+            .get_lengths()
+            .iter_chunk_infos(p2)
+            .last()
+            .unwrap();
+        let p2_inflight = g.inflight_pieces.get_mut(&p2).unwrap();
+        assert!(p2_inflight.claim_chunk(&last_chunk, peer(1)));
+        let (index, chunks) = g.split_piece(peer(3), &has_all).unwrap().unwrap();
+        assert_eq!(index, p2);
+        assert_eq!(
+            chunks.iter().map(|c| c.chunk_index).collect::<Vec<_>>(),
+            vec![2, 1, 0]
+        );
+        let p2_inflight = g.inflight_pieces.get_mut(&p2).unwrap();
+        assert!(p2_inflight.claim_chunk(&chunks[0], peer(3)));
+        assert!(!p2_inflight.claim_chunk(&chunks[0], peer(1)));
+
+        // Pieces are shared once per peer, and not with the peer they're reserved for.
+        let (index, _) = g.split_piece(peer(3), &has_all).unwrap().unwrap();
+        assert_eq!(index, p1);
+        assert!(g.split_piece(peer(3), &has_all).unwrap().is_none());
+        let (index, _) = g.split_piece(peer(1), &has_all).unwrap().unwrap();
+        assert_eq!(index, p1);
+
+        // Only pieces the peer has.
+        let mut has_p2 = CompactBitfield::have_none(4);
+        has_p2.set(2, true);
+        assert!(g
+            .split_piece(peer(4), &CompactBitfield::have_none(4))
+            .unwrap()
+            .is_none());
+
+        // Until enough peers share them.
+        for port in 4..3 + SPLIT_MAX_EXTRA_PEERS as u16 {
+            let (index, _) = g.split_piece(peer(port), &has_p2).unwrap().unwrap();
+            assert_eq!(index, p2);
+        }
+        assert_eq!(
+            g.inflight_pieces[&p2].split_peers.len(),
+            SPLIT_MAX_EXTRA_PEERS
+        );
+        assert!(g.split_piece(peer(10), &has_p2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_join_endgame_piece() {
+        let mut g = new_state(&[(3, peer(1)), (1, peer(2))]);
+        let (p1, p3) = (piece(&g, 1), piece(&g, 3));
+        let has_all = CompactBitfield::have_all(4);
+
+        // Not while there are pieces nobody downloads yet.
+        assert!(g.join_endgame_piece(peer(3), &has_all).unwrap().is_none());
+        for index in [0, 2] {
+            let index = piece(&g, index);
+            g.get_chunks_mut().unwrap().reserve_needed_piece(index);
+        }
+
+        // The oldest piece first, all of it, even if its chunks are requested already.
+        let p3_inflight = g.inflight_pieces.get_mut(&p3).unwrap();
+        p3_inflight.split_peers.push(peer(4));
+        let (index, chunks) = g.join_endgame_piece(peer(3), &has_all).unwrap().unwrap();
+        assert_eq!(index, p3);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(
+            g.inflight_pieces[&p3].other_peers(peer(3)),
+            vec![peer(1), peer(4)]
+        );
+
+        // Once per peer, and not by the peers that download it already.
+        let (index, _) = g.join_endgame_piece(peer(3), &has_all).unwrap().unwrap();
+        assert_eq!(index, p1);
+        assert!(g.join_endgame_piece(peer(3), &has_all).unwrap().is_none());
+        let (index, _) = g.join_endgame_piece(peer(4), &has_all).unwrap().unwrap();
+        assert_eq!(index, p1);
+        let (index, _) = g.join_endgame_piece(peer(2), &has_all).unwrap().unwrap();
+        assert_eq!(index, p3);
+        assert_eq!(
+            g.inflight_pieces[&p3].other_peers(peer(1)),
+            vec![peer(3), peer(2), peer(4)]
+        );
+
+        // Only pieces the peer has, until enough peers download them.
+        assert_eq!(
+            g.inflight_pieces[&p1].endgame_peers.len(),
+            ENDGAME_MAX_EXTRA_PEERS
+        );
+        let mut has_p1 = CompactBitfield::have_none(4);
+        has_p1.set(1, true);
+        assert!(g.join_endgame_piece(peer(5), &has_p1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_steal_piece() {
+        let mut g = new_state(&[(0, peer(1)), (1, peer(2)), (2, peer(3)), (3, peer(1))]);
+        let pieces = (0..4).map(|i| piece(&g, i)).collect::<Vec<_>>();
+        let now = Instant::now();
+        for (index, secs) in [(0, 3), (1, 1), (2, 4), (3, 4)] {
+            g.inflight_pieces.get_mut(&pieces[index]).unwrap().started =
+                now - Duration::from_secs(secs);
+        }
+        // Piece 3 is almost done.
+        for begin in (0..4).map(|c| c * CHUNK_SIZE) {
+            g.get_chunks_mut()
+                .unwrap()
+                .mark_chunk_downloaded(&Piece {
+                    index: 3,
+                    begin,
+                    block: &[0u8; CHUNK_SIZE as usize][..],
+                })
+                .unwrap();
+        }
+        let avg = Duration::from_millis(10);
+        let indexes = |candidates: Vec<(ValidPieceIndex, PeerHandle, Instant)>| {
+            candidates
+                .into_iter()
+                .map(|(index, _, _)| index.get())
+                .collect::<Vec<_>>()
+        };
+
+        // Oldest first, only the ones that are slow enough, and not our own.
+        assert_eq!(
+            indexes(g.steal_candidates(peer(3), avg, 50.).unwrap()),
+            vec![0, 1]
+        );
+        assert_eq!(
+            indexes(g.steal_candidates(peer(3), avg, 200.).unwrap()),
+            vec![0]
+        );
+        assert_eq!(
+            indexes(g.steal_candidates(peer(4), avg, 200.).unwrap()),
+            vec![2, 0]
+        );
+
+        let (index, from, started) = g.steal_candidates(peer(4), avg, 200.).unwrap()[0];
+        let stolen = g.inflight_pieces.get_mut(&index).unwrap();
+        stolen.endgame_peers.push(peer(4));
+        stolen.split_peers.push(peer(1));
+        let chunks = g
+            .get_chunks()
+            .unwrap()
+            .get_lengths()
+            .iter_chunk_infos(index)
+            .collect::<Vec<_>>();
+        let stolen = g.inflight_pieces.get_mut(&index).unwrap();
+        assert!(stolen.claim_chunk(&chunks[0], from));
+        assert!(stolen.claim_chunk(&chunks[1], peer(1)));
+        assert!(g.take_over_piece(index, from, started, peer(4)));
+        // Only once.
+        assert!(!g.take_over_piece(index, from, started, peer(5)));
+
+        let stolen = &g.inflight_pieces[&index];
+        assert_eq!(stolen.peer, peer(4));
+        assert!(stolen.endgame_peers.is_empty());
+        assert_eq!(stolen.split_peers, vec![peer(1)]);
+        // The chunks the slow peer requested are free to request again.
+        let stolen = g.inflight_pieces.get_mut(&index).unwrap();
+        assert!(stolen.claim_chunk(&chunks[0], peer(4)));
+        assert!(!stolen.claim_chunk(&chunks[1], peer(4)));
+        // It's started over, so it's not slow anymore.
+        assert_eq!(
+            indexes(g.steal_candidates(peer(5), avg, 200.).unwrap()),
+            vec![0]
+        );
     }
 }
@@ -1,25 +1,58 @@
 pub mod stats;
 
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use librqbit_core::compact_bitfield::CompactBitfield;
 use librqbit_core::hash_id::Id20;
 use librqbit_core::lengths::ChunkInfo;
 
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use peer_binary_protocol::{Message, Request};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::peer_connection::WriterRequest;
-use crate::type_aliases::BF;
+use crate::peer_connection::{WriterRequest, PEER_WRITE_QUEUE_LEN};
+use crate::rate_limit::PeerRateLimits;
+use crate::type_aliases::PeerHandle;
 
 use super::peers::stats::atomic::AggregatePeerStatsAtomic;
 
 pub(crate) type InflightRequest = ChunkInfo;
-pub(crate) type PeerRx = UnboundedReceiver<WriterRequest>;
-pub(crate) type PeerTx = UnboundedSender<WriterRequest>;
+pub(crate) type PeerRx = Receiver<WriterRequest>;
+pub(crate) type PeerTx = Sender<WriterRequest>;
+
+// How we got to know about the peer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerOrigin {
+    // Announced by a tracker.
+    Tracker,
+    // Found in the DHT.
+    Dht,
+    // Told about by another peer with ut_pex, or its extended handshake.
+    Pex,
+    // The peer connected to our listener.
+    Incoming,
+    // A peer relaying a NAT hole punch (ut_holepunch) told us to connect to it.
+    Holepunch,
+    // Given when adding the torrent, or re-added after a restart.
+    #[default]
+    Manual,
+}
 
 #[derive(Debug, Default)]
 pub(crate) struct Peer {
     pub state: PeerStateNoMut,
     pub stats: stats::atomic::PeerStats,
+    pub origin: PeerOrigin,
+    pub limits: Arc<PeerRateLimits>,
+    // The peer that told us about this one with ut_pex, to relay a hole punch if we can't
+    // connect to it directly.
+    pub holepunch_relay: Option<PeerHandle>,
+    // We pinged the DHT node from the peer's PORT message. Only done once, so that a peer can't
+    // make us ping arbitrary addresses over and over.
+    pub dht_port_pinged: bool,
 }
 
 impl Peer {
@@ -33,6 +66,10 @@ impl Peer {
         Self {
             state,
             stats: Default::default(),
+            origin: PeerOrigin::Incoming,
+            limits: Default::default(),
+            holepunch_relay: None,
+            dht_port_pinged: false,
         }
     }
 }
@@ -50,6 +87,8 @@ pub(crate) enum PeerState {
     // The peer has the full torrent, and we have the full torrent, so no need
     // to keep talking to it.
     NotNeeded,
+    // It sent data that failed the hash check too often. It's never connected to again.
+    Banned,
 }
 
 impl std::fmt::Display for PeerState {
@@ -66,6 +105,7 @@ impl PeerState {
             PeerState::Live(_) => "live",
             PeerState::Dead => "dead",
             PeerState::NotNeeded => "not needed",
+            PeerState::Banned => "banned",
         }
     }
 
@@ -114,7 +154,7 @@ impl PeerStateNoMut {
     ) -> Option<(PeerRx, PeerTx)> {
         match &self.0 {
             PeerState::Queued | PeerState::NotNeeded => {
-                let (tx, rx) = unbounded_channel();
+                let (tx, rx) = channel(PEER_WRITE_QUEUE_LEN);
                 let tx_2 = tx.clone();
                 self.set(PeerState::Connecting(tx), counters);
                 Some((rx, tx_2))
@@ -129,15 +169,12 @@ impl PeerStateNoMut {
         tx: PeerTx,
         counters: &AggregatePeerStatsAtomic,
     ) -> anyhow::Result<()> {
-        if matches!(&self.0, PeerState::Connecting(..) | PeerState::Live(..)) {
-            anyhow::bail!("peer already active");
-        }
-        match self.take(counters) {
-            PeerState::Queued | PeerState::Dead | PeerState::NotNeeded => {
-                self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
-            }
-            PeerState::Connecting(..) | PeerState::Live(..) => unreachable!(),
+        match &self.0 {
+            PeerState::Connecting(..) | PeerState::Live(..) => anyhow::bail!("peer already active"),
+            PeerState::Banned => anyhow::bail!("peer is banned"),
+            PeerState::Queued | PeerState::Dead | PeerState::NotNeeded => {}
         }
+        self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
         Ok(())
     }
 
@@ -159,6 +196,9 @@ impl PeerStateNoMut {
     }
 
     pub fn set_not_needed(&mut self, counters: &AggregatePeerStatsAtomic) -> PeerState {
+        if let PeerState::Banned = self.0 {
+            return PeerState::Banned;
+        }
         self.set(PeerState::NotNeeded, counters)
     }
 }
@@ -170,14 +210,40 @@ pub(crate) struct LivePeerState {
 
     pub peer_interested: bool,
 
-    // This is used to track the pieces the peer has.
-    pub bitfield: BF,
+    // Whether we are choking the peer, i.e. not uploading to it.
+    pub i_am_choking: bool,
+
+    // Whether we told the peer we want some of its pieces.
+    pub i_am_interested: bool,
 
-    // When the peer sends us data this is used to track if we asked for it.
-    pub inflight_requests: HashSet<InflightRequest>,
+    // This is used to track the pieces the peer has. Empty until the peer
+    // sends us either a bitfield or a "have".
+    pub bitfield: CompactBitfield,
+
+    // When the peer sends us data this is used to track if we asked for it, and when.
+    pub inflight_requests: HashMap<InflightRequest, Instant>,
+
+    // When the peer last sent us a chunk we asked for, or when we started waiting for one.
+    pub last_request_progress: Instant,
+
+    // When the peer last sent us a chunk we asked for, or when we became interested in it.
+    pub last_useful_data: Instant,
+
+    // The peer didn't deliver what we asked for in a long time. Only one request is kept in
+    // flight to it until it does.
+    pub snubbed: bool,
 
     // The main channel to send requests to peer.
     pub tx: PeerTx,
+
+    // Whether the peer can relay hole punches (ut_holepunch).
+    pub supports_holepunch: bool,
+
+    // Whether the peer wants to know about pieces we lost (lt_donthave).
+    pub supports_donthave: bool,
+
+    // The peer said in its extended handshake that it doesn't download anymore.
+    pub upload_only: bool,
 }
 
 impl LivePeerState {
@@ -185,15 +251,222 @@ impl LivePeerState {
         LivePeerState {
             peer_id,
             peer_interested: false,
-            bitfield: BF::default(),
+            i_am_choking: true,
+            i_am_interested: false,
+            bitfield: CompactBitfield::default(),
             inflight_requests: Default::default(),
+            last_request_progress: Instant::now(),
+            last_useful_data: Instant::now(),
+            snubbed: false,
             tx,
+            supports_holepunch: false,
+            supports_donthave: false,
+            upload_only: false,
         }
     }
 
     pub fn has_full_torrent(&self, total_pieces: usize) -> bool {
-        self.bitfield
-            .get(0..total_pieces)
-            .map_or(false, |s| s.all())
+        self.bitfield.len() as usize == total_pieces && self.bitfield.all()
+    }
+
+    // Upload-only peers won't want anything from us, even if their bitfield isn't full yet.
+    pub fn is_seed(&self, total_pieces: usize) -> bool {
+        self.upload_only || self.has_full_torrent(total_pieces)
+    }
+
+    // Marks the peer snubbed if it hasn't delivered anything we asked for in "timeout", and
+    // removes and cancels its requests. Returns them so that other peers can download them.
+    pub fn mark_snubbed(&mut self, timeout: Duration) -> Vec<InflightRequest> {
+        if self.snubbed
+            || self.inflight_requests.is_empty()
+            || self.last_request_progress.elapsed() < timeout
+        {
+            return Vec::new();
+        }
+        self.snubbed = true;
+        let inflight = self
+            .inflight_requests
+            .drain()
+            .map(|(req, _)| req)
+            .collect::<Vec<_>>();
+        for req in inflight.iter() {
+            self.send_cancel(req);
+        }
+        inflight
+    }
+
+    // Removes and cancels the requests sent before "last_answered", i.e. before a request the
+    // peer already answered, and at least "timeout" ago. Returns them to be requested again.
+    pub fn expire_lost_requests(
+        &mut self,
+        last_answered: Instant,
+        timeout: Duration,
+    ) -> Vec<InflightRequest> {
+        // Requests of snubbing peers were put back into the queue already.
+        if self.snubbed {
+            return Vec::new();
+        }
+        let expired = self
+            .inflight_requests
+            .iter()
+            .filter(|(_, sent)| **sent < last_answered && sent.elapsed() >= timeout)
+            .map(|(req, _)| *req)
+            .collect::<Vec<_>>();
+        for req in expired.iter() {
+            self.inflight_requests.remove(req);
+            self.send_cancel(req);
+        }
+        expired
+    }
+
+    // Tell the peer we don't need the chunk anymore. This doesn't wait for room in the write
+    // queue: callers hold the peer table entry, and in endgame or when stealing they cancel
+    // on behalf of another peer that shouldn't stall on this one. If the queue is full, the
+    // cancel is dropped. That is safe as a cancel is only a hint: if the chunk still arrives,
+    // it's either used or counted as wasted.
+    pub fn send_cancel(&self, req: &InflightRequest) {
+        let _ = self
+            .tx
+            .try_send(WriterRequest::Message(Message::Cancel(Request {
+                index: req.piece_index.get(),
+                begin: req.offset,
+                length: req.size,
+            })));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::Ordering,
+        time::{Duration, Instant},
+    };
+
+    use librqbit_core::{
+        compact_bitfield::CompactBitfield,
+        hash_id::Id20,
+        lengths::{ChunkInfo, Lengths},
+    };
+    use peer_binary_protocol::Message;
+    use tokio::sync::mpsc::channel;
+
+    use super::{LivePeerState, PeerState, PeerStateNoMut};
+    use crate::peer_connection::WriterRequest;
+    use crate::torrent_state::live::peers::stats::atomic::AggregatePeerStatsAtomic;
+
+    #[test]
+    fn test_banned_is_terminal() {
+        let counters = AggregatePeerStatsAtomic::default();
+        let mut state = PeerStateNoMut::default();
+        counters.inc(state.get());
+
+        state.set(PeerState::Banned, &counters);
+        state.set_not_needed(&counters);
+        assert!(matches!(state.get(), PeerState::Banned));
+        assert!(state.idle_to_connecting(&counters).is_none());
+        let (tx, _rx) = channel(1);
+        assert!(state
+            .incoming_connection(Id20::new([0; 20]), tx, &counters)
+            .is_err());
+
+        assert_eq!(counters.banned.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.queued.load(Ordering::Relaxed), 0);
+        assert_eq!(counters.not_needed.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_upload_only_is_seed() {
+        let (tx, _rx) = channel(1);
+        let mut live = LivePeerState::new(Id20::new([0; 20]), tx);
+        live.bitfield = CompactBitfield::have_none(4);
+        assert!(!live.is_seed(4));
+
+        live.upload_only = true;
+        assert!(live.is_seed(4));
+        assert!(!live.has_full_torrent(4));
+    }
+
+    // 4 pieces of 2 chunks.
+    fn chunk(piece: u32, chunk: usize) -> ChunkInfo {
+        let lengths = Lengths::new(4 * 32768, 32768).unwrap();
+        let index = lengths.validate_piece_index(piece).unwrap();
+        lengths.iter_chunk_infos(index).nth(chunk).unwrap()
+    }
+
+    #[test]
+    fn test_mark_snubbed() {
+        let timeout = Duration::from_secs(60);
+        let (tx, mut rx) = channel(16);
+        let mut live = LivePeerState::new(Id20::new([0; 20]), tx);
+        live.inflight_requests.insert(chunk(0, 1), Instant::now());
+
+        // It delivered recently.
+        assert!(live.mark_snubbed(timeout).is_empty());
+        assert!(!live.snubbed);
+
+        live.last_request_progress = Instant::now() - timeout;
+        assert_eq!(live.mark_snubbed(timeout), vec![chunk(0, 1)]);
+        assert!(live.snubbed);
+        assert!(live.inflight_requests.is_empty());
+        match rx.try_recv() {
+            Ok(WriterRequest::Message(Message::Cancel(r))) => {
+                assert_eq!((r.index, r.begin, r.length), (0, 16384, 16384))
+            }
+            _ => panic!("expected a cancel"),
+        }
+
+        // Its requests were given away already.
+        assert!(live.mark_snubbed(timeout).is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_expire_lost_requests() {
+        let timeout = Duration::from_secs(30);
+        let (tx, mut rx) = channel(16);
+        let mut live = LivePeerState::new(Id20::new([0; 20]), tx);
+        let now = Instant::now();
+        let old = now - timeout;
+        live.inflight_requests.insert(chunk(0, 0), old);
+        // Sent after the last answered one.
+        live.inflight_requests.insert(chunk(0, 1), now);
+        // Not for long enough.
+        live.inflight_requests
+            .insert(chunk(1, 0), now - timeout / 2);
+
+        assert_eq!(
+            live.expire_lost_requests(now - timeout / 4, timeout),
+            vec![chunk(0, 0)]
+        );
+        assert_eq!(live.inflight_requests.len(), 2);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WriterRequest::Message(Message::Cancel(_)))
+        ));
+        assert!(rx.try_recv().is_err());
+
+        // Nothing expires for snubbing peers.
+        live.snubbed = true;
+        live.inflight_requests.insert(chunk(2, 0), old);
+        assert!(live.expire_lost_requests(now, timeout).is_empty());
+        assert_eq!(live.inflight_requests.len(), 3);
+    }
+
+    #[test]
+    fn test_send_cancel_doesnt_block() {
+        let (tx, mut rx) = channel(1);
+        let live = LivePeerState::new(Id20::new([0; 20]), tx);
+
+        live.send_cancel(&chunk(1, 0));
+        // The write queue is full, the second cancel is dropped.
+        live.send_cancel(&chunk(1, 1));
+
+        match rx.try_recv() {
+            Ok(WriterRequest::Message(Message::Cancel(r))) => {
+                assert_eq!((r.index, r.begin, r.length), (1, 0, 16384))
+            }
+            _ => panic!("expected a cancel"),
+        }
+        assert!(rx.try_recv().is_err());
     }
 }
@@ -7,10 +7,12 @@ use std::{
 };
 
 use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use librqbit_core::speed_estimator::SpeedEstimator;
 
 #[derive(Default, Debug)]
 pub(crate) struct PeerCountersAtomic {
     pub fetched_bytes: AtomicU64,
+    pub uploaded_bytes: AtomicU64,
     pub total_time_connecting_ms: AtomicU64,
     pub incoming_connections: AtomicU32,
     pub outgoing_connection_attempts: AtomicU32,
@@ -22,6 +24,12 @@ pub(crate) struct PeerCountersAtomic {
     pub total_piece_download_ms: AtomicU64,
     pub times_stolen_from_me: AtomicU32,
     pub times_i_stole: AtomicU32,
+    // Pieces it sent chunks of that failed the hash check.
+    pub hash_failed_pieces: AtomicU32,
+    // Bytes it sent of the pieces that failed the hash check.
+    pub hash_failed_bytes: AtomicU64,
+    // Bytes it sent that were thrown away, including hash_failed_bytes.
+    pub wasted_bytes: AtomicU64,
 }
 
 impl PeerCountersAtomic {
@@ -51,6 +59,9 @@ impl PeerCountersAtomic {
 pub(crate) struct PeerStats {
     pub counters: Arc<PeerCountersAtomic>,
     pub backoff: ExponentialBackoff,
+    // Updated every second while the peer is live.
+    pub down_speed: SpeedEstimator,
+    pub up_speed: SpeedEstimator,
 }
 
 impl Default for PeerStats {
@@ -63,6 +74,8 @@ impl Default for PeerStats {
                 .with_max_interval(Duration::from_secs(3600))
                 .with_max_elapsed_time(Some(Duration::from_secs(86400)))
                 .build(),
+            down_speed: SpeedEstimator::new(5),
+            up_speed: SpeedEstimator::new(5),
         }
     }
 }
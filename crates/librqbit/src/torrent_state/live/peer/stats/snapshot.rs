@@ -2,12 +2,13 @@ use std::{collections::HashMap, sync::atomic::Ordering};
 
 use serde::{Deserialize, Serialize};
 
-use crate::torrent_state::live::peer::{Peer, PeerState};
+use crate::torrent_state::live::peer::{Peer, PeerOrigin, PeerState};
 
 #[derive(Serialize, Deserialize)]
 pub struct PeerCounters {
     pub incoming_connections: u32,
     pub fetched_bytes: u64,
+    pub uploaded_bytes: u64,
     pub total_time_connecting_ms: u64,
     pub connection_attempts: u32,
     pub connections: u32,
@@ -17,12 +18,28 @@ pub struct PeerCounters {
     pub total_piece_download_ms: u64,
     pub times_stolen_from_me: u32,
     pub times_i_stole: u32,
+    pub hash_failed_pieces: u32,
+    pub hash_failed_bytes: u64,
+    pub wasted_bytes: u64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PeerStats {
     pub counters: PeerCounters,
     pub state: &'static str,
+    pub origin: PeerOrigin,
+    /// Upload limit for this peer, bytes per second.
+    pub upload_limit: Option<u32>,
+    /// Download limit for this peer, bytes per second.
+    pub download_limit: Option<u32>,
+    /// Current download rate from this peer, bytes per second. 0 if it's not live.
+    pub download_bps: u64,
+    /// Current upload rate to this peer, bytes per second. 0 if it's not live.
+    pub upload_bps: u64,
+    /// Whether we want pieces the peer has. false if it's not live.
+    pub i_am_interested: bool,
+    /// Whether the peer stopped delivering the chunks we requested. false if it's not live.
+    pub snubbed: bool,
 }
 
 impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
@@ -30,6 +47,7 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
         Self {
             incoming_connections: counters.incoming_connections.load(Ordering::Relaxed),
             fetched_bytes: counters.fetched_bytes.load(Ordering::Relaxed),
+            uploaded_bytes: counters.uploaded_bytes.load(Ordering::Relaxed),
             total_time_connecting_ms: counters.total_time_connecting_ms.load(Ordering::Relaxed),
             connection_attempts: counters
                 .outgoing_connection_attempts
@@ -43,15 +61,40 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
             total_piece_download_ms: counters.total_piece_download_ms.load(Ordering::Relaxed),
             times_i_stole: counters.times_i_stole.load(Ordering::Relaxed),
             times_stolen_from_me: counters.times_stolen_from_me.load(Ordering::Relaxed),
+            hash_failed_pieces: counters.hash_failed_pieces.load(Ordering::Relaxed),
+            hash_failed_bytes: counters.hash_failed_bytes.load(Ordering::Relaxed),
+            wasted_bytes: counters.wasted_bytes.load(Ordering::Relaxed),
         }
     }
 }
 
 impl From<&Peer> for PeerStats {
     fn from(peer: &Peer) -> Self {
+        let is_live = matches!(peer.state.get(), PeerState::Live(_));
         Self {
             counters: peer.stats.counters.as_ref().into(),
             state: peer.state.get().name(),
+            origin: peer.origin,
+            upload_limit: peer.limits.upload().map(|l| l.bytes_per_second().get()),
+            download_limit: peer.limits.download().map(|l| l.bytes_per_second().get()),
+            download_bps: if is_live {
+                peer.stats.down_speed.bps()
+            } else {
+                0
+            },
+            upload_bps: if is_live {
+                peer.stats.up_speed.bps()
+            } else {
+                0
+            },
+            i_am_interested: match peer.state.get() {
+                PeerState::Live(live) => live.i_am_interested,
+                _ => false,
+            },
+            snubbed: match peer.state.get() {
+                PeerState::Live(live) => live.snubbed,
+                _ => false,
+            },
         }
     }
 }
@@ -77,4 +120,7 @@ impl PeerStatsFilterState {
 #[derive(Default, Deserialize)]
 pub struct PeerStatsFilter {
     pub state: PeerStatsFilterState,
+    /// Only the peers discovered this way.
+    #[serde(default)]
+    pub origin: Option<PeerOrigin>,
 }
@@ -1,20 +1,22 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Instant};
 
 use anyhow::Context;
 use backoff::backoff::Backoff;
 use dashmap::DashMap;
-use librqbit_core::lengths::ValidPieceIndex;
-use peer_binary_protocol::{Message, Request};
+use librqbit_core::{
+    compact_bitfield::CompactBitfield,
+    lengths::{ChunkInfo, ValidPieceIndex},
+};
 
 use crate::{
-    peer_connection::WriterRequest,
+    piece_picker::PieceAvailability,
     torrent_state::utils::{atomic_inc, TimedExistence},
-    type_aliases::{PeerHandle, BF},
+    type_aliases::PeerHandle,
 };
 
 use self::stats::{atomic::AggregatePeerStatsAtomic, snapshot::AggregatePeerStats};
 
-use super::peer::{LivePeerState, Peer, PeerRx, PeerState, PeerTx};
+use super::peer::{LivePeerState, Peer, PeerOrigin, PeerRx, PeerState, PeerTx};
 
 pub mod stats;
 
@@ -25,16 +27,29 @@ pub(crate) struct PeerStates {
 }
 
 impl PeerStates {
+    pub fn new(total_pieces: u32) -> Self {
+        Self {
+            stats: AggregatePeerStatsAtomic {
+                availability: PieceAvailability::new(total_pieces),
+                ..Default::default()
+            },
+            states: Default::default(),
+        }
+    }
+
     pub fn stats(&self) -> AggregatePeerStats {
         AggregatePeerStats::from(&self.stats)
     }
 
-    pub fn add_if_not_seen(&self, addr: SocketAddr) -> Option<PeerHandle> {
+    pub fn add_if_not_seen(&self, addr: SocketAddr, origin: PeerOrigin) -> Option<PeerHandle> {
         use dashmap::mapref::entry::Entry;
         match self.states.entry(addr) {
             Entry::Occupied(_) => None,
             Entry::Vacant(vac) => {
-                vac.insert(Default::default());
+                vac.insert(Peer {
+                    origin,
+                    ..Default::default()
+                });
                 atomic_inc(&self.stats.queued);
                 atomic_inc(&self.stats.seen);
                 Some(addr)
@@ -77,6 +92,14 @@ impl PeerStates {
         Some(p)
     }
 
+    // False if the DHT port of the peer was already pinged, or the peer is gone.
+    pub fn mark_dht_port_pinged(&self, handle: PeerHandle) -> bool {
+        self.with_peer_mut(handle, "mark_dht_port_pinged", |peer| {
+            !std::mem::replace(&mut peer.dht_port_pinged, true)
+        })
+        .unwrap_or(false)
+    }
+
     pub fn mark_peer_interested(&self, handle: PeerHandle, is_interested: bool) -> Option<bool> {
         self.with_live_mut(handle, "mark_peer_interested", |live| {
             let prev = live.peer_interested;
@@ -84,9 +107,11 @@ impl PeerStates {
             prev
         })
     }
-    pub fn update_bitfield_from_vec(&self, handle: PeerHandle, bitfield: Box<[u8]>) -> Option<()> {
-        self.with_live_mut(handle, "update_bitfield_from_vec", |live| {
-            live.bitfield = BF::from_boxed_slice(bitfield);
+    pub fn update_bitfield(&self, handle: PeerHandle, bitfield: CompactBitfield) -> Option<()> {
+        self.with_live_mut(handle, "update_bitfield", |live| {
+            self.stats.availability.remove_bitfield(&live.bitfield);
+            self.stats.availability.add_bitfield(&bitfield);
+            live.bitfield = bitfield;
         })
     }
     pub fn mark_peer_connecting(&self, h: PeerHandle) -> anyhow::Result<(PeerRx, PeerTx)> {
@@ -100,6 +125,26 @@ impl PeerStates {
         Ok(rx)
     }
 
+    pub fn update_speed_estimators(&self, now: Instant) {
+        for pe in self.states.iter() {
+            let peer = pe.value();
+            if !matches!(peer.state.get(), PeerState::Live(_)) {
+                continue;
+            }
+            let counters = &peer.stats.counters;
+            peer.stats.down_speed.add_snapshot(
+                counters.fetched_bytes.load(Ordering::Relaxed),
+                None,
+                now,
+            );
+            peer.stats.up_speed.add_snapshot(
+                counters.uploaded_bytes.load(Ordering::Relaxed),
+                None,
+                now,
+            );
+        }
+    }
+
     pub fn reset_peer_backoff(&self, handle: PeerHandle) {
         self.with_peer_mut(handle, "reset_peer_backoff", |p| {
             p.stats.backoff.reset();
@@ -127,23 +172,44 @@ impl PeerStates {
         });
         self.stats.inc_steals();
 
-        self.with_live_mut(from_peer, "send_cancellations", |live| {
+        self.cancel_requests(from_peer, stolen_idx, None);
+    }
+
+    // Send cancellations for in-flight requests of the piece, or only of one chunk of it.
+    pub(crate) fn cancel_requests(
+        &self,
+        peer: PeerHandle,
+        index: ValidPieceIndex,
+        chunk: Option<&ChunkInfo>,
+    ) {
+        self.with_live_mut(peer, "send_cancellations", |live| {
             let to_remove = live
                 .inflight_requests
-                .iter()
-                .filter(|r| r.piece_index == stolen_idx)
+                .keys()
+                .filter(|r| r.piece_index == index && chunk.is_none_or(|c| c == *r))
                 .copied()
                 .collect::<Vec<_>>();
             for req in to_remove {
-                let _ = live
-                    .tx
-                    .send(WriterRequest::Message(Message::Cancel(Request {
-                        index: stolen_idx.get(),
-                        begin: req.offset,
-                        length: req.size,
-                    })));
+                live.send_cancel(&req);
                 live.inflight_requests.remove(&req);
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PeerStates;
+    use crate::torrent_state::peer::PeerOrigin;
+
+    #[test]
+    fn test_dht_port_pinged_once() {
+        let peers = PeerStates::new(4);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        assert!(!peers.mark_dht_port_pinged(addr));
+
+        peers.add_if_not_seen(addr, PeerOrigin::Dht).unwrap();
+        assert!(peers.mark_dht_port_pinged(addr));
+        assert!(!peers.mark_dht_port_pinged(addr));
+    }
+}
@@ -2,9 +2,12 @@ use std::sync::atomic::AtomicU32;
 
 use serde::Serialize;
 
-use crate::torrent_state::{
-    live::peer::PeerState,
-    utils::{atomic_dec, atomic_inc},
+use crate::{
+    piece_picker::PieceAvailability,
+    torrent_state::{
+        live::peer::PeerState,
+        utils::{atomic_dec, atomic_inc},
+    },
 };
 
 #[derive(Debug, Default, Serialize)]
@@ -15,7 +18,11 @@ pub(crate) struct AggregatePeerStatsAtomic {
     pub seen: AtomicU32,
     pub dead: AtomicU32,
     pub not_needed: AtomicU32,
+    pub banned: AtomicU32,
     pub steals: AtomicU32,
+    // Follows the bitfields of live peers.
+    #[serde(skip)]
+    pub availability: PieceAvailability,
 }
 
 impl AggregatePeerStatsAtomic {
@@ -26,15 +33,22 @@ impl AggregatePeerStatsAtomic {
             PeerState::Queued => &self.queued,
             PeerState::Dead => &self.dead,
             PeerState::NotNeeded => &self.not_needed,
+            PeerState::Banned => &self.banned,
         }
     }
 
     pub fn inc(&self, state: &PeerState) {
         atomic_inc(self.counter(state));
+        if let PeerState::Live(live) = state {
+            self.availability.add_bitfield(&live.bitfield);
+        }
     }
 
     pub fn dec(&self, state: &PeerState) {
         atomic_dec(self.counter(state));
+        if let PeerState::Live(live) = state {
+            self.availability.remove_bitfield(&live.bitfield);
+        }
     }
 
     pub fn incdec(&self, old: &PeerState, new: &PeerState) {
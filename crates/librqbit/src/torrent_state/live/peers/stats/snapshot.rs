@@ -12,9 +12,23 @@ pub struct AggregatePeerStats {
     pub seen: usize,
     pub dead: usize,
     pub not_needed: usize,
+    pub banned: usize,
     pub steals: usize,
 }
 
+impl AggregatePeerStats {
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.queued += other.queued;
+        self.connecting += other.connecting;
+        self.live += other.live;
+        self.seen += other.seen;
+        self.dead += other.dead;
+        self.not_needed += other.not_needed;
+        self.banned += other.banned;
+        self.steals += other.steals;
+    }
+}
+
 impl<'a> From<&'a AggregatePeerStatsAtomic> for AggregatePeerStats {
     fn from(s: &'a AggregatePeerStatsAtomic) -> Self {
         let ordering = Ordering::Relaxed;
@@ -25,6 +39,7 @@ impl<'a> From<&'a AggregatePeerStatsAtomic> for AggregatePeerStats {
             seen: s.seen.load(ordering) as usize,
             dead: s.dead.load(ordering) as usize,
             not_needed: s.not_needed.load(ordering) as usize,
+            banned: s.banned.load(ordering) as usize,
             steals: s.steals.load(ordering) as usize,
         }
     }
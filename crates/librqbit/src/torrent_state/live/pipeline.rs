@@ -0,0 +1,131 @@
+// How many chunk requests to keep in flight to a peer.
+//
+// To keep a peer busy, the requests in flight must cover its bandwidth-delay product: what the
+// peer sends while our next request travels to it. We keep twice that, so that the pipeline
+// doesn't limit the speed, which lets it grow along with the measured speed.
+
+use std::time::Duration;
+
+pub(crate) struct RequestPipeline {
+    min_depth: usize,
+    max_depth: usize,
+    depth: usize,
+    // Permits to take back as chunks arrive, after the depth was lowered.
+    debt: usize,
+    // The fastest answer to a request sent while nothing else was in flight, so that it's not
+    // delayed by the peer's queue.
+    rtt: Option<Duration>,
+}
+
+impl RequestPipeline {
+    pub fn new(min_depth: usize, max_depth: usize) -> Self {
+        Self {
+            min_depth,
+            max_depth,
+            depth: min_depth,
+            debt: 0,
+            rtt: None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        self.rtt = Some(self.rtt.map_or(rtt, |r| r.min(rtt)));
+    }
+
+    // Returns how many permits to add.
+    pub fn set_limits(&mut self, min_depth: usize, max_depth: usize) -> usize {
+        self.min_depth = min_depth;
+        self.max_depth = max_depth;
+        self.resize(self.depth, 0)
+    }
+
+    // Returns how many permits to add: the received chunk's own, and more if the pipeline
+    // grew. Fewer if it shrank.
+    pub fn on_chunk_received(&mut self, bytes_per_second: u64, chunk_size: u32) -> usize {
+        let target = match self.rtt {
+            Some(rtt) if chunk_size > 0 => {
+                let bdp = bytes_per_second as f64 * rtt.as_secs_f64();
+                (2. * bdp / chunk_size as f64).ceil() as usize
+            }
+            _ => self.depth,
+        };
+        self.resize(target, 1)
+    }
+
+    // Like on_chunk_received, for a chunk whose permit was returned already when its request
+    // timed out.
+    pub fn on_late_chunk_received(&mut self, bytes_per_second: u64, chunk_size: u32) -> usize {
+        self.debt += 1;
+        self.on_chunk_received(bytes_per_second, chunk_size)
+    }
+
+    fn resize(&mut self, depth: usize, permits: usize) -> usize {
+        let depth = depth.clamp(self.min_depth, self.max_depth.max(self.min_depth));
+        let mut permits = permits;
+        if depth > self.depth {
+            permits += depth - self.depth;
+        } else {
+            self.debt += self.depth - depth;
+        }
+        self.depth = depth;
+        let repaid = permits.min(self.debt);
+        self.debt -= repaid;
+        permits - repaid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RequestPipeline;
+
+    const CHUNK: u32 = 16384;
+
+    #[test]
+    fn test_request_pipeline() {
+        let mut p = RequestPipeline::new(16, 250);
+
+        // Nothing is known about the round trip time yet.
+        assert_eq!(p.on_chunk_received(10_000_000, CHUNK), 1);
+        assert_eq!(p.depth(), 16);
+
+        // 100ms at ~2.5MiB/s is ~16 chunks, twice that is kept in flight.
+        p.on_rtt_sample(Duration::from_millis(200));
+        p.on_rtt_sample(Duration::from_millis(100));
+        p.on_rtt_sample(Duration::from_millis(300));
+        assert_eq!(p.on_chunk_received(16 * CHUNK as u64 * 10, CHUNK), 1 + 16);
+        assert_eq!(p.depth(), 32);
+
+        // Fast peers are limited by what they allow.
+        assert_eq!(p.on_chunk_received(1_000_000_000, CHUNK), 1 + 218);
+        assert_eq!(p.depth(), 250);
+
+        // Shrinking takes back the permits of the chunks that arrive.
+        assert_eq!(p.on_chunk_received(0, CHUNK), 0);
+        assert_eq!(p.depth(), 16);
+        for _ in 0..233 {
+            assert_eq!(p.on_chunk_received(0, CHUNK), 0);
+        }
+        assert_eq!(p.on_chunk_received(0, CHUNK), 1);
+
+        // The peer's reqq lowers the limits.
+        assert_eq!(p.set_limits(4, 8), 0);
+        assert_eq!(p.depth(), 8);
+        assert_eq!(p.on_chunk_received(0, CHUNK), 0);
+        assert_eq!(p.depth(), 4);
+        for _ in 0..11 {
+            assert_eq!(p.on_chunk_received(0, CHUNK), 0);
+        }
+        assert_eq!(p.on_chunk_received(0, CHUNK), 1);
+        assert_eq!(p.set_limits(16, 250), 12);
+
+        // The permit of a late chunk was returned already.
+        assert_eq!(p.on_late_chunk_received(0, CHUNK), 0);
+        assert_eq!(p.on_chunk_received(0, CHUNK), 1);
+    }
+}
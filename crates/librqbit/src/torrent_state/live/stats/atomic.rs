@@ -5,7 +5,10 @@ pub struct AtomicStats {
     pub have_bytes: AtomicU64,
     pub downloaded_and_checked_bytes: AtomicU64,
     pub downloaded_and_checked_pieces: AtomicU64,
+    pub hash_failed_pieces: AtomicU64,
     pub uploaded_bytes: AtomicU64,
     pub fetched_bytes: AtomicU64,
+    // Downloaded, but thrown away: failed the hash check, or arrived after someone else sent it.
+    pub wasted_bytes: AtomicU64,
     pub total_piece_download_ms: AtomicU64,
 }
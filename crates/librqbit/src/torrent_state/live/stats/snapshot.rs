@@ -8,12 +8,33 @@ use crate::torrent_state::live::peers::stats::snapshot::AggregatePeerStats;
 pub struct StatsSnapshot {
     pub downloaded_and_checked_bytes: u64,
 
+    // These follow the file selection.
+    pub have_bytes: u64,
+    pub needed_bytes: u64,
+    pub selected_bytes: u64,
+
     pub fetched_bytes: u64,
     pub uploaded_bytes: u64,
+    // Part of fetched_bytes that was discarded: pieces that failed the hash check, and chunks
+    // that someone else had already sent.
+    pub wasted_bytes: u64,
 
     pub downloaded_and_checked_pieces: u64,
+    pub hash_failed_pieces: u64,
     pub total_piece_download_ms: u64,
     pub peer_stats: AggregatePeerStats,
+
+    // Copies of the rarest piece among live peers and us.
+    pub min_piece_availability: u32,
+    // Full copies of the torrent among live peers and us, plus the fraction of pieces that
+    // have more copies than the rarest one. Below 1 the torrent can't complete.
+    pub distributed_copies: f64,
+
+    // Exponentially smoothed speeds in bytes per second, updated every second.
+    pub download_bps_smoothed: u64,
+    pub upload_bps_smoothed: u64,
+    // Time to download the needed bytes at the smoothed download speed.
+    pub eta_secs: Option<u64>,
 }
 
 impl StatsSnapshot {
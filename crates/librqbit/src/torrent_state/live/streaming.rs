@@ -0,0 +1,367 @@
+// Reading files of a torrent while it's still downloading, e.g. to play a video.
+//
+// Every open stream remembers the piece it's going to read next. The readahead window of pieces
+// starting from there is requested from peers before all others, and the pieces of the file past
+// the window after all others, so that after a seek the new position doesn't wait behind pieces
+// nobody needs yet. Reads wait until the pieces they need are downloaded and verified.
+
+use std::{
+    io::SeekFrom,
+    num::NonZeroU32,
+    ops::Range,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context};
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use librqbit_core::lengths::ValidPieceIndex;
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::Notify,
+};
+use tracing::trace;
+
+use super::TorrentStateLive;
+use crate::file_io::read_exact_at;
+
+/// How many pieces starting from the current position of a stream to download first.
+pub const DEFAULT_STREAM_READAHEAD_PIECES: u32 = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct StreamPosition {
+    // The piece the stream is going to read next.
+    piece: u32,
+    // The end of the piece range of the file being streamed.
+    end: u32,
+    // How many pieces from "piece" on to download first.
+    readahead: u32,
+}
+
+impl StreamPosition {
+    // The pieces of the file too far ahead to be needed soon.
+    fn far_ahead(&self) -> Range<u32> {
+        self.piece.saturating_add(self.readahead).min(self.end)..self.end
+    }
+}
+
+pub(crate) struct TorrentStreams {
+    next_id: AtomicUsize,
+    streams: DashMap<usize, StreamPosition>,
+    piece_verified: Notify,
+    default_readahead: u32,
+}
+
+impl TorrentStreams {
+    pub(crate) fn new(default_readahead: u32) -> Self {
+        Self {
+            next_id: AtomicUsize::new(0),
+            streams: DashMap::new(),
+            piece_verified: Notify::new(),
+            default_readahead,
+        }
+    }
+
+    fn register(&self, position: StreamPosition) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.insert(id, position);
+        id
+    }
+
+    fn set_piece(&self, id: usize, piece: u32) {
+        if let Some(mut s) = self.streams.get_mut(&id) {
+            s.piece = piece;
+        }
+    }
+
+    fn set_readahead(&self, id: usize, readahead: u32) {
+        if let Some(mut s) = self.streams.get_mut(&id) {
+            s.readahead = readahead;
+        }
+    }
+
+    fn unregister(&self, id: usize) {
+        self.streams.remove(&id);
+    }
+
+    // Pieces that streams are going to need soon, the most urgent ones first.
+    pub(crate) fn priority_pieces(&self) -> Vec<u32> {
+        let positions = self.streams.iter().map(|s| *s.value()).collect::<Vec<_>>();
+        priority_pieces(&positions)
+    }
+
+    // Pieces of streamed files past the readahead windows, to be downloaded after all others.
+    pub(crate) fn far_ahead_pieces(&self) -> Vec<Range<u32>> {
+        self.streams
+            .iter()
+            .map(|s| s.value().far_ahead())
+            .filter(|r| !r.is_empty())
+            .collect()
+    }
+
+    pub(crate) fn on_piece_verified(&self) {
+        self.piece_verified.notify_waiters();
+    }
+}
+
+// The n-th piece of every stream goes before the n+1-th piece of any stream.
+fn priority_pieces(positions: &[StreamPosition]) -> Vec<u32> {
+    let mut pieces = Vec::new();
+    let max_readahead = positions.iter().map(|p| p.readahead).max().unwrap_or(0);
+    for offset in 0..max_readahead {
+        for p in positions.iter().filter(|p| offset < p.readahead) {
+            let piece = p.piece + offset;
+            if piece < p.end && !pieces.contains(&piece) {
+                pieces.push(piece);
+            }
+        }
+    }
+    pieces
+}
+
+type ReadFuture = BoxFuture<'static, anyhow::Result<Vec<u8>>>;
+
+/// A file of a live torrent that can be read while the torrent is downloading.
+///
+/// Reads wait until the data they need is downloaded and verified, and the pieces
+/// around the current position are downloaded before everything else.
+pub struct FileStream {
+    torrent: Arc<TorrentStateLive>,
+    stream_id: usize,
+    file_id: usize,
+    file_len: u64,
+    // The position of the next byte returned to the reader.
+    position: u64,
+    // Data read from disk at "position" that wasn't returned to the reader yet.
+    buffered: Vec<u8>,
+    buffered_start: usize,
+    pending: Option<ReadFuture>,
+}
+
+impl FileStream {
+    pub fn len(&self) -> u64 {
+        self.file_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_len == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// How many pieces from the current position on to download before all others. The
+    /// default is set in [SessionOptions](crate::SessionOptions).
+    pub fn set_readahead_pieces(&mut self, pieces: NonZeroU32) {
+        self.torrent
+            .streams
+            .set_readahead(self.stream_id, pieces.get());
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.torrent.streams.unregister(self.stream_id);
+    }
+}
+
+impl TorrentStateLive {
+    /// Open a file of the torrent for reading while it's downloading.
+    pub fn stream_file(self: &Arc<Self>, file_id: usize) -> anyhow::Result<FileStream> {
+        let file = self
+            .files
+            .get(file_id)
+            .with_context(|| format!("invalid file id {file_id}"))?;
+        let stream_id = self.streams.register(StreamPosition {
+            piece: file.piece_range.start,
+            end: file.piece_range.end,
+            readahead: self.streams.default_readahead,
+        });
+        trace!(file_id, stream_id, "opened stream");
+        Ok(FileStream {
+            torrent: self.clone(),
+            stream_id,
+            file_id,
+            file_len: file.len,
+            position: 0,
+            buffered: Vec::new(),
+            buffered_start: 0,
+            pending: None,
+        })
+    }
+
+    async fn wait_for_piece(&self, index: ValidPieceIndex) -> anyhow::Result<()> {
+        loop {
+            let notified = self.streams.piece_verified.notified();
+            {
+                let g = self.lock_read("stream_wait_for_piece");
+                let chunks = g.get_chunks()?;
+                if chunks.get_have_pieces()[index.get() as usize] {
+                    return Ok(());
+                }
+                if !chunks.get_selected_pieces()[index.get() as usize] {
+                    bail!("piece={index} is not selected for download");
+                }
+            }
+            tokio::select! {
+                _ = notified => {},
+                _ = self.cancellation_token.cancelled() => bail!("torrent was paused"),
+            }
+        }
+    }
+
+    // Read the data at "position" in the file up to the end of the piece it's in.
+    async fn read_for_stream(
+        self: Arc<Self>,
+        stream_id: usize,
+        file_id: usize,
+        position: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let file = self.files.get(file_id).context("bug: invalid file id")?;
+        let absolute = file.offset_in_torrent + position;
+        let index = self
+            .lengths
+            .validate_piece_index((absolute / self.lengths.default_piece_length() as u64) as u32)
+            .context("bug: position out of torrent bounds")?;
+        let piece_end = self.lengths.piece_offset(index) + self.lengths.piece_length(index) as u64;
+        let len = piece_end.min(file.offset_in_torrent + file.len) - absolute;
+
+        self.streams.set_piece(stream_id, index.get());
+        self.wait_for_piece(index).await?;
+
+        self.meta.spawner.spawn_block_in_place(|| {
+            let mut buf = vec![0u8; len as usize];
+            let f = file.lock_file()?;
+            if let Some(map) = file.mmap().as_ref() {
+                map.read_at(position, &mut buf)
+                    .with_context(|| format!("error reading {:?}", file.filename()))?;
+                return Ok(buf);
+            }
+            read_exact_at(&f, &mut buf, position)
+                .with_context(|| format!("error reading at {position} in {:?}", file.filename()))?;
+            Ok(buf)
+        })
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let available = &this.buffered[this.buffered_start..];
+            if !available.is_empty() {
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.buffered_start += n;
+                this.position += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if this.position >= this.file_len {
+                return Poll::Ready(Ok(()));
+            }
+
+            let fut = this.pending.get_or_insert_with(|| {
+                Box::pin(this.torrent.clone().read_for_stream(
+                    this.stream_id,
+                    this.file_id,
+                    this.position,
+                ))
+            });
+            let result = futures::ready!(fut.as_mut().poll(cx));
+            this.pending = None;
+            this.buffered = result.map_err(|e| std::io::Error::other(format!("{e:#}")))?;
+            this.buffered_start = 0;
+        }
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let new_position = match position {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.file_len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.position.checked_add_signed(d),
+        };
+        let new_position = new_position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        if new_position == self.position {
+            return Ok(());
+        }
+        self.position = new_position;
+        self.buffered.clear();
+        self.buffered_start = 0;
+        self.pending = None;
+
+        // Start prioritizing the new position right away, not on the next read.
+        let file = &self.torrent.files[self.file_id];
+        let absolute = file.offset_in_torrent + new_position.min(file.len);
+        let piece = (absolute / self.torrent.lengths.default_piece_length() as u64) as u32;
+        self.torrent.streams.set_piece(self.stream_id, piece);
+        Ok(())
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{priority_pieces, StreamPosition};
+
+    fn pos(piece: u32, end: u32) -> StreamPosition {
+        StreamPosition {
+            piece,
+            end,
+            readahead: 8,
+        }
+    }
+
+    #[test]
+    fn test_priority_pieces() {
+        assert!(priority_pieces(&[]).is_empty());
+        assert_eq!(priority_pieces(&[pos(5, 8)]), vec![5, 6, 7]);
+        assert_eq!(
+            priority_pieces(&[pos(0, 100), pos(50, 52), pos(1, 100)])[..6],
+            [0, 50, 1, 51, 2, 3]
+        );
+        assert_eq!(
+            priority_pieces(&[
+                StreamPosition {
+                    readahead: 2,
+                    ..pos(0, 100)
+                },
+                pos(50, 100)
+            ])[..5],
+            [0, 50, 1, 51, 52]
+        );
+    }
+
+    #[test]
+    fn test_far_ahead() {
+        assert_eq!(pos(0, 100).far_ahead(), 8..100);
+        assert_eq!(pos(95, 100).far_ahead(), 100..100);
+        let p = StreamPosition {
+            readahead: u32::MAX,
+            ..pos(10, 100)
+        };
+        assert!(p.far_ahead().is_empty());
+    }
+}
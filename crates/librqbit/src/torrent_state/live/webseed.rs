@@ -0,0 +1,260 @@
+// Downloading pieces from HTTP web seeds (BEP 19).
+//
+// A web seed task reserves one queued piece at a time, fetches it with HTTP range requests
+// and then writes and verifies it the same way as pieces received from peers.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
+use buffers::ByteBuf;
+use librqbit_core::lengths::ValidPieceIndex;
+use peer_binary_protocol::Piece;
+use reqwest::StatusCode;
+use tracing::{debug, trace, warn};
+
+use super::TorrentStateLive;
+
+const WEBSEED_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The URL of a file in the torrent, as described in BEP 19.
+fn file_url(base: &str, name: Option<&str>, path: Option<&[&str]>) -> String {
+    let enc = |s: &str| urlencoding::encode(s).into_owned();
+    match (path, name) {
+        // Single-file torrent. If the URL is a directory, the file is named after the torrent.
+        (None, Some(name)) if base.ends_with('/') => format!("{base}{}", enc(name)),
+        (None, _) => base.to_owned(),
+        (Some(path), name) => {
+            let mut url = base.to_owned();
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            for component in name.into_iter().chain(path.iter().copied()) {
+                url.push_str(&enc(component));
+                url.push('/');
+            }
+            url.pop();
+            url
+        }
+    }
+}
+
+impl TorrentStateLive {
+    fn reserve_piece_for_webseed(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        let mut g = self.lock_write("reserve_piece_for_webseed");
+        let chunks = g.get_chunks_mut()?;
+        let index = match chunks.iter_queued_pieces().next() {
+            Some(index) => self
+                .lengths
+                .validate_piece_index(index as u32)
+                .context("bug: invalid piece")?,
+            None => return Ok(None),
+        };
+        chunks.reserve_needed_piece(index);
+        Ok(Some(index))
+    }
+
+    async fn fetch_piece_from_webseed(
+        &self,
+        client: &reqwest::Client,
+        base_url: &str,
+        index: ValidPieceIndex,
+    ) -> anyhow::Result<Vec<u8>> {
+        let info = &self.meta.info;
+        let piece_offset = self.lengths.piece_offset(index);
+        let piece_end = piece_offset + self.lengths.piece_length(index) as u64;
+        let name = info
+            .name
+            .as_ref()
+            .map(|n| std::str::from_utf8(n.as_ref()))
+            .transpose()
+            .context("torrent name is not valid UTF-8")?;
+
+        let mut data = Vec::with_capacity((piece_end - piece_offset) as usize);
+        for file in info.iter_file_details(&self.lengths)? {
+            let file_end = file.offset + file.len;
+            if file_end <= piece_offset || file.offset >= piece_end || file.len == 0 {
+                continue;
+            }
+            let start = piece_offset.max(file.offset) - file.offset;
+            let end = piece_end.min(file_end) - file.offset;
+
+            let url = if info.files.is_some() {
+                let path = file.filename.to_vec()?;
+                let path = path.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                file_url(base_url, name, Some(&path))
+            } else {
+                file_url(base_url, name, None)
+            };
+
+            trace!(%url, start, end, "requesting range");
+            let response = client
+                .get(&url)
+                .header(
+                    reqwest::header::RANGE,
+                    format!("bytes={}-{}", start, end - 1),
+                )
+                .send()
+                .await
+                .with_context(|| format!("error requesting {url}"))?
+                .error_for_status()
+                .with_context(|| format!("error requesting {url}"))?;
+            let whole_file = start == 0 && end == file.len;
+            if response.status() != StatusCode::PARTIAL_CONTENT && !whole_file {
+                bail!(
+                    "{url} doesn't support range requests, got {}",
+                    response.status()
+                );
+            }
+            let body = response
+                .bytes()
+                .await
+                .with_context(|| format!("error reading response body from {url}"))?;
+            if body.len() as u64 != end - start {
+                bail!(
+                    "expected {} bytes from {url}, got {}",
+                    end - start,
+                    body.len()
+                );
+            }
+            data.extend_from_slice(&body);
+        }
+        Ok(data)
+    }
+
+    // Write a piece received from a web seed and verify it. Returns false if the hash didn't
+    // match.
+    fn on_webseed_piece(
+        &self,
+        url: &str,
+        index: ValidPieceIndex,
+        data: &[u8],
+        download_time: Duration,
+    ) -> anyhow::Result<bool> {
+        let mut last_chunk = None;
+        for chunk in self.lengths.iter_chunk_infos(index) {
+            let begin = chunk.offset as usize;
+            let piece = Piece {
+                index: index.get(),
+                begin: chunk.offset,
+                block: ByteBuf(&data[begin..begin + chunk.size as usize]),
+            };
+            self.file_ops()
+                .write_chunk(url, &piece, &chunk)
+                .context("error writing chunk to disk")?;
+            self.lock_write("webseed_mark_chunk_downloaded")
+                .get_chunks_mut()?
+                .mark_chunk_downloaded(&piece);
+            last_chunk = Some(chunk);
+        }
+        self.stats
+            .fetched_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        let last_chunk = last_chunk.context("bug: piece has no chunks")?;
+        if !self
+            .file_ops()
+            .check_piece(url, index, &last_chunk)
+            .with_context(|| format!("error checking piece={index}"))?
+        {
+            self.on_wasted_bytes(data.len() as u64);
+            self.lock_write("webseed_mark_piece_hash_failed")
+                .get_chunks_mut()?
+                .mark_piece_hash_failed(index);
+            return Ok(false);
+        }
+
+        debug!("piece={} successfully downloaded and verified", index);
+        self.on_piece_verified(index, download_time)?;
+        Ok(true)
+    }
+
+    pub(super) async fn task_webseed(self: Arc<Self>, url: String) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBSEED_REQUEST_TIMEOUT)
+            .build()
+            .context("error building HTTP client")?;
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(10))
+            .with_max_interval(Duration::from_secs(600))
+            .with_max_elapsed_time(Some(Duration::from_secs(3600)))
+            .build();
+
+        loop {
+            let index = match self.reserve_piece_for_webseed()? {
+                Some(index) => index,
+                None => {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    continue;
+                }
+            };
+
+            let started = Instant::now();
+            let data = match self.fetch_piece_from_webseed(&client, &url, index).await {
+                Ok(data) => data,
+                Err(e) => {
+                    self.lock_write("webseed_piece_failed")
+                        .get_chunks_mut()?
+                        .mark_piece_broken_if_not_have(index);
+                    match backoff.next_backoff() {
+                        Some(dur) => {
+                            debug!("error downloading piece={index}, retrying in {dur:?}: {e:#}");
+                            tokio::time::sleep(dur).await;
+                            continue;
+                        }
+                        None => {
+                            warn!("giving up on web seed: {e:#}");
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+            backoff.reset();
+
+            let verified = self.meta.spawner.spawn_block_in_place(|| {
+                self.on_webseed_piece(&url, index, &data, started.elapsed())
+            });
+            match verified {
+                Ok(true) => {}
+                Ok(false) => {
+                    // Same as a peer sending bad data: stop using it.
+                    bail!("piece={index} from web seed failed the hash check, not using it anymore")
+                }
+                Err(e) => {
+                    self.lock_write("webseed_piece_failed")
+                        .get_chunks_mut()?
+                        .mark_piece_broken_if_not_have(index);
+                    return self.on_fatal_error(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::file_url;
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(
+            file_url("http://example.com/file.iso", Some("name.iso"), None),
+            "http://example.com/file.iso"
+        );
+        assert_eq!(
+            file_url("http://example.com/seeds/", Some("a b.iso"), None),
+            "http://example.com/seeds/a%20b.iso"
+        );
+        assert_eq!(
+            file_url(
+                "http://example.com/seeds",
+                Some("dir"),
+                Some(&["sub", "f#1"])
+            ),
+            "http://example.com/seeds/dir/sub/f%231"
+        );
+    }
+}
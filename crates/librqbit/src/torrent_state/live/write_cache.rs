@@ -0,0 +1,137 @@
+// Buffering downloaded pieces in memory.
+//
+// Without it every chunk is written to disk as soon as it arrives, in whatever order peers send
+// them. With it, chunks are collected in memory until the piece is complete, the piece is hashed
+// from memory and written with one large sequential write. Disks that are slow at small random
+// writes (spinning and SMR drives) benefit the most.
+//
+// The cache is part of the locked torrent state, so a piece can't be taken for writing while a
+// chunk of it from another peer is still being copied in.
+
+use std::collections::HashMap;
+
+use librqbit_core::lengths::{ChunkInfo, Lengths, ValidPieceIndex};
+
+#[derive(Default)]
+pub(crate) struct WriteCache {
+    // The most bytes to keep buffered. 0 disables the cache.
+    limit: usize,
+    used: usize,
+    pieces: HashMap<ValidPieceIndex, Box<[u8]>>,
+}
+
+impl WriteCache {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    // Bytes buffered and waiting to be written to disk.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Copy the chunk into its piece's buffer. A piece can only start being buffered with its first
+    // chunk ("is_first_chunk"), otherwise the chunks that were written to disk before would be
+    // missing from it.
+    //
+    // Returns false if the chunk wasn't buffered and needs to be written to disk.
+    pub fn put(
+        &mut self,
+        lengths: &Lengths,
+        chunk: &ChunkInfo,
+        data: &[u8],
+        is_first_chunk: bool,
+    ) -> bool {
+        if !self.pieces.contains_key(&chunk.piece_index) {
+            let piece_len = lengths.piece_length(chunk.piece_index) as usize;
+            if !is_first_chunk || self.used + piece_len > self.limit {
+                return false;
+            }
+            self.used += piece_len;
+            self.pieces
+                .insert(chunk.piece_index, vec![0u8; piece_len].into_boxed_slice());
+        }
+        let buf = match self.pieces.get_mut(&chunk.piece_index) {
+            Some(buf) => buf,
+            None => return false,
+        };
+        let start = chunk.offset as usize;
+        match buf.get_mut(start..start + data.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn take(&mut self, index: ValidPieceIndex) -> Option<Box<[u8]>> {
+        let buf = self.pieces.remove(&index)?;
+        self.used -= buf.len();
+        Some(buf)
+    }
+
+    // Forget the buffered chunks of a piece that isn't being downloaded anymore. Returns false if
+    // it wasn't buffered.
+    pub fn remove(&mut self, index: ValidPieceIndex) -> bool {
+        self.take(index).is_some()
+    }
+
+    // The pieces being buffered.
+    pub fn pieces(&self) -> impl Iterator<Item = ValidPieceIndex> + '_ {
+        self.pieces.keys().copied()
+    }
+
+    // Forget all buffered data, returning the pieces it belonged to.
+    pub fn clear(&mut self) -> Vec<ValidPieceIndex> {
+        self.used = 0;
+        self.pieces.drain().map(|(index, _)| index).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::Lengths;
+
+    use super::WriteCache;
+
+    #[test]
+    fn test_write_cache() {
+        // 3 pieces of 2 chunks each, the last one is a single short chunk.
+        let lengths = Lengths::new(16384 * 5 + 10, 32768).unwrap();
+        let chunks = lengths
+            .iter_piece_infos()
+            .flat_map(|p| lengths.iter_chunk_infos(p.piece_index))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 6);
+
+        let mut cache = WriteCache::new(65536);
+        assert!(!cache.put(&lengths, &chunks[1], &[1; 16384], false));
+        assert!(cache.put(&lengths, &chunks[1], &[1; 16384], true));
+        assert!(cache.put(&lengths, &chunks[0], &[2; 16384], false));
+        assert!(cache.put(&lengths, &chunks[2], &[3; 16384], true));
+        // Over the limit.
+        assert!(!cache.put(&lengths, &chunks[4], &[4; 16384], true));
+
+        let piece = cache.take(chunks[0].piece_index).unwrap();
+        assert_eq!(&piece[..16384], &[2; 16384]);
+        assert_eq!(&piece[16384..], &[1; 16384]);
+        assert!(cache.take(chunks[0].piece_index).is_none());
+
+        assert!(cache.put(&lengths, &chunks[5], &[5; 10], true));
+        assert_eq!(cache.take(chunks[5].piece_index).unwrap().len(), 16394);
+
+        // Removing frees up the space for other pieces.
+        assert!(cache.put(&lengths, &chunks[0], &[6; 16384], true));
+        assert_eq!(cache.used(), 65536);
+        assert!(cache.remove(chunks[0].piece_index));
+        assert!(!cache.remove(chunks[0].piece_index));
+        assert_eq!(cache.used(), 32768);
+
+        assert_eq!(cache.clear(), vec![chunks[2].piece_index]);
+        assert!(cache.put(&lengths, &chunks[4], &[4; 16384], true));
+    }
+}
@@ -4,7 +4,10 @@ pub mod paused;
 pub mod stats;
 pub mod utils;
 
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::num::NonZeroU32;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
@@ -14,31 +17,55 @@ use std::time::Duration;
 use anyhow::bail;
 use anyhow::Context;
 use buffers::ByteBufOwned;
+use dht::Dht;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use librqbit_core::hash_id::Id20;
+use librqbit_core::hash_id::Id32;
 use librqbit_core::lengths::Lengths;
+use librqbit_core::merkle::PieceHashV2;
 use librqbit_core::peer_id::generate_peer_id;
 
 use librqbit_core::spawn_utils::spawn_with_cancel;
+use librqbit_core::torrent_metainfo::PieceLayers;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
+use librqbit_utp::UtpSocket;
 pub use live::*;
+use parking_lot::Mutex;
 use parking_lot::RwLock;
 
+use tokio::sync::Semaphore;
 use tokio::time::timeout;
-use tokio_stream::StreamExt;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error_span;
+use tracing::info;
 use tracing::warn;
 
 use crate::chunk_tracker::ChunkTracker;
-use crate::spawn_utils::BlockingSpawner;
+use crate::chunk_tracker::FilePriority;
+use crate::chunk_tracker::PartialPieces;
+use crate::error::ErrorKind;
+use crate::events::{Event, EventSender, EVENTS_CHANNEL_CAPACITY};
+use crate::extensions::ExtensionRegistry;
+use crate::hooks::{spawn_hook, HookEnv, HookEvent, TorrentHooks};
+use crate::opened_file::{part_filename, FilePool};
+use crate::peer_connection::PeerSocketBinding;
+use crate::peer_connection::PeerTransport;
+use crate::piece_picker::PiecePicker;
+use crate::preallocate::Preallocation;
+use crate::rate_limit::RateLimit;
+use crate::spawn_utils::{BlockingSpawner, CpuPool};
 use crate::torrent_state::stats::LiveStats;
+use crate::tracker_stats::{TrackerStats, TrackersStats};
+use crate::type_aliases::OpenedFiles;
 use crate::type_aliases::PeerStream;
 
 use initializing::TorrentStateInitializing;
 
+use self::live::streaming::FileStream;
 use self::paused::TorrentStatePaused;
 pub use self::stats::{TorrentStats, TorrentStatsState};
 
@@ -68,6 +95,11 @@ impl ManagedTorrentState {
 pub(crate) struct ManagedTorrentLocked {
     pub state: ManagedTorrentState,
     pub(crate) only_files: Option<Vec<usize>>,
+    pub(crate) sequential: bool,
+    pub(crate) file_priorities: Option<Vec<FilePriority>>,
+    pub(crate) head_tail_files: Vec<usize>,
+    // Chunks on disk from before a restart, applied once the initial check is done.
+    pub(crate) partial_pieces: Option<PartialPieces>,
 }
 
 #[derive(Default)]
@@ -75,24 +107,142 @@ pub(crate) struct ManagedTorrentOptions {
     pub force_tracker_interval: Option<Duration>,
     pub peer_connect_timeout: Option<Duration>,
     pub peer_read_write_timeout: Option<Duration>,
+    pub peer_keep_alive_interval: Option<Duration>,
+    pub peer_idle_timeout: Option<Duration>,
+    pub peer_inactivity_timeout: Option<Duration>,
     pub overwrite: bool,
+    pub direct_io: bool,
+    pub mmap: bool,
+    pub preallocation: Preallocation,
+    pub part_files: bool,
+    pub write_cache_size: Option<usize>,
+    pub socket_binding: Option<PeerSocketBinding>,
+    pub session_socket_binding: Option<PeerSocketBinding>,
+    pub peer_transport: Option<PeerTransport>,
+    pub utp_socket: Option<UtpSocket>,
+    pub dht: Option<Dht>,
+    pub upload_slots: Option<usize>,
+    pub upload_rate_limit: Option<Arc<RateLimit>>,
+    pub session_upload_rate_limit: Option<Arc<RateLimit>>,
+    pub piece_picker: Option<Arc<dyn PiecePicker>>,
+    pub peer_limit: Option<NonZeroUsize>,
+    pub session_peer_semaphore: Option<Arc<Semaphore>>,
+    pub half_open_semaphore: Option<Arc<Semaphore>>,
+    pub peer_request_queue_depth: Option<NonZeroUsize>,
+    pub stream_readahead_pieces: Option<NonZeroU32>,
+    pub max_request_block_size: Option<NonZeroU32>,
+    pub hooks: Option<TorrentHooks>,
+    pub session_hooks: Option<Arc<TorrentHooks>>,
+    pub extensions: Option<Arc<ExtensionRegistry>>,
+}
+
+impl ManagedTorrentOptions {
+    pub(crate) fn upload_limits(&self) -> Vec<Arc<RateLimit>> {
+        self.upload_rate_limit
+            .iter()
+            .chain(self.session_upload_rate_limit.iter())
+            .cloned()
+            .collect()
+    }
+
+    // The torrent's own binding, or the session's one.
+    pub(crate) fn effective_socket_binding(&self) -> Option<&PeerSocketBinding> {
+        self.socket_binding
+            .as_ref()
+            .or(self.session_socket_binding.as_ref())
+    }
+
+    // The torrent's own hook for the event, or the session's one.
+    fn hook_command(&self, event: HookEvent) -> Option<&str> {
+        self.hooks
+            .as_ref()
+            .and_then(|h| h.command(event))
+            .or_else(|| self.session_hooks.as_ref().and_then(|h| h.command(event)))
+    }
 }
 
 pub struct ManagedTorrentInfo {
     pub info: TorrentMetaV1Info<ByteBufOwned>,
     pub info_hash: Id20,
-    pub out_dir: PathBuf,
+    // Set for v2 and hybrid torrents.
+    pub info_hash_v2: Option<Id32>,
+    // Kept to be able to restore v2 torrents, the hashes are in v2_piece_hashes.
+    pub piece_layers: Option<PieceLayers<ByteBufOwned>>,
+    // Changes when the storage is moved.
+    out_dir: RwLock<PathBuf>,
+    // Paths of renamed files, relative to out_dir.
+    renamed_files: RwLock<HashMap<usize, PathBuf>>,
     pub(crate) spawner: BlockingSpawner,
+    // Where downloaded pieces are hashed, shared by all torrents of the session.
+    pub(crate) cpu_pool: CpuPool,
+    // Limits the open files of all torrents of the session.
+    pub(crate) file_pool: Arc<FilePool>,
     pub trackers: HashSet<String>,
+    // The same trackers, grouped in tiers (BEP 12).
+    pub tracker_tiers: Vec<Vec<String>>,
+    pub webseeds: Vec<String>,
+    pub category: Option<String>,
     pub peer_id: Id20,
     pub lengths: Lengths,
+    // Expected piece hashes for v2-only torrents, validated against the file merkle roots.
+    pub(crate) v2_piece_hashes: Option<Vec<PieceHashV2>>,
     pub span: tracing::Span,
     pub(crate) options: ManagedTorrentOptions,
+    pub(crate) events: EventSender,
+    pub(crate) tracker_stats: TrackersStats,
+}
+
+impl ManagedTorrentInfo {
+    /// The directory the files of the torrent are in.
+    pub fn out_dir(&self) -> PathBuf {
+        self.out_dir.read().clone()
+    }
+
+    /// The new paths of the files that were renamed, relative to the output folder.
+    pub fn renamed_files(&self) -> HashMap<usize, PathBuf> {
+        self.renamed_files.read().clone()
+    }
+
+    pub(crate) fn renamed_file(&self, file_id: usize) -> Option<PathBuf> {
+        self.renamed_files.read().get(&file_id).cloned()
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        match &event {
+            Event::TorrentFinished { .. } => self.run_hook(HookEvent::Finished, None),
+            Event::DiskError { error, .. } | Event::TorrentError { error, .. } => {
+                self.run_hook(HookEvent::Error, Some(error.clone()))
+            }
+            _ => {}
+        }
+        // Fails only if there are no subscribers.
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn run_hook(&self, event: HookEvent, error: Option<String>) {
+        let command = match self.options.hook_command(event) {
+            Some(command) => command.to_owned(),
+            None => return,
+        };
+        let _e = self.span.enter();
+        spawn_hook(
+            command,
+            event,
+            HookEnv {
+                info_hash: self.info_hash,
+                name: self.info.name.as_ref().map(|n| n.to_string()),
+                path: self.out_dir(),
+                error,
+            },
+        );
+    }
 }
 
 pub struct ManagedTorrent {
     pub info: Arc<ManagedTorrentInfo>,
     locked: RwLock<ManagedTorrentLocked>,
+    // Held while files are being moved or renamed.
+    storage_lock: Mutex<()>,
 }
 
 impl ManagedTorrent {
@@ -108,10 +258,49 @@ impl ManagedTorrent {
         self.info.info_hash
     }
 
+    /// Subscribe to the events of this torrent. Subscribers that fall behind miss the oldest
+    /// events, and get a "Lagged" error with how many they missed.
+    pub fn subscribe(
+        &self,
+    ) -> impl Stream<Item = std::result::Result<Event, BroadcastStreamRecvError>> + Send + 'static
+    {
+        let info_hash = self.info_hash();
+        BroadcastStream::new(self.info.events.subscribe())
+            .filter(move |e| e.as_ref().map_or(true, |e| e.info_hash() == info_hash))
+    }
+
     pub fn only_files(&self) -> Option<Vec<usize>> {
         self.locked.read().only_files.clone()
     }
 
+    pub fn is_sequential(&self) -> bool {
+        self.locked.read().sequential
+    }
+
+    /// The torrent's own hooks, not including the session's ones.
+    pub fn hooks(&self) -> Option<TorrentHooks> {
+        self.info.options.hooks.clone()
+    }
+
+    pub fn file_priorities(&self) -> Option<Vec<FilePriority>> {
+        self.locked.read().file_priorities.clone()
+    }
+
+    pub fn head_tail_files(&self) -> Vec<usize> {
+        self.locked.read().head_tail_files.clone()
+    }
+
+    // The chunks of unfinished pieces that are on disk, to be persisted with the session.
+    pub(crate) fn partial_pieces(&self) -> PartialPieces {
+        let g = self.locked.read();
+        match &g.state {
+            ManagedTorrentState::Paused(p) => p.chunk_tracker.get_partial_pieces(),
+            ManagedTorrentState::Live(l) => l.get_partial_pieces().unwrap_or_default(),
+            // Not checked yet, keep what's going to be restored.
+            _ => g.partial_pieces.clone().unwrap_or_default(),
+        }
+    }
+
     pub fn with_state<R>(&self, f: impl FnOnce(&ManagedTorrentState) -> R) -> R {
         f(&self.locked.read().state)
     }
@@ -135,6 +324,18 @@ impl ManagedTorrent {
         }
     }
 
+    /// Bytes downloaded and verified of each file, in the order of the files in the torrent.
+    /// Fails if the torrent is neither paused nor live.
+    pub fn file_progress(&self) -> anyhow::Result<Vec<u64>> {
+        let file_lengths = self.info().info.iter_file_lengths()?.collect::<Vec<_>>();
+        self.with_chunk_tracker(|ct| ct.file_progress(file_lengths))
+    }
+
+    /// The announce status of each tracker of the torrent.
+    pub fn tracker_stats(&self) -> Vec<TrackerStats> {
+        self.info.tracker_stats.snapshot(&self.info.tracker_tiers)
+    }
+
     /// Get the live state if the torrent is live.
     pub fn live(&self) -> Option<Arc<TorrentStateLive>> {
         let g = self.locked.read();
@@ -144,18 +345,31 @@ impl ManagedTorrent {
         }
     }
 
+    /// Read a file while the torrent is downloading. The torrent has to be live.
+    pub fn stream_file(&self, file_id: usize) -> anyhow::Result<FileStream> {
+        self.live()
+            .context("torrent is not live")?
+            .stream_file(file_id)
+    }
+
     fn stop_with_error(&self, error: anyhow::Error) {
         let mut g = self.locked.write();
 
         match g.state.take() {
-            ManagedTorrentState::Live(live) => {
-                if let Err(err) = live.pause() {
+            // Keep the progress so the torrent can be resumed once the error is fixed.
+            ManagedTorrentState::Live(live) => match live.pause() {
+                Ok(mut paused) => {
+                    paused.error = Some(error);
+                    g.state = ManagedTorrentState::Paused(paused);
+                    return;
+                }
+                Err(err) => {
                     warn!(
                         "error pausing live torrent during fatal error handling: {:?}",
                         err
                     );
                 }
-            }
+            },
             ManagedTorrentState::Error(e) => {
                 warn!("bug: torrent already was in error state when trying to stop it. Previous error was: {:?}", e);
             }
@@ -220,12 +434,13 @@ impl ManagedTorrent {
 
                         loop {
                             match timeout(Duration::from_secs(5), peer_rx.next()).await {
-                                Ok(Some(peer)) => {
+                                Ok(Some((peer, origin))) => {
                                     let live = match live.upgrade() {
                                         Some(live) => live,
                                         None => return Ok(()),
                                     };
-                                    live.add_peer_if_not_seen(peer).context("torrent closed")?;
+                                    live.add_peer_if_not_seen(peer, origin)
+                                        .context("torrent closed")?;
                                 }
                                 Ok(None) => return Ok(()),
                                 // If timeout, check if the torrent is live.
@@ -253,13 +468,32 @@ impl ManagedTorrent {
                     token.clone(),
                     async move {
                         match init.check().await {
-                            Ok(paused) => {
+                            Ok(mut paused) => {
                                 let mut g = t.locked.write();
                                 if let ManagedTorrentState::Initializing(_) = &g.state {
                                 } else {
                                     debug!("no need to start torrent anymore, as it switched state from initilizing");
                                     return Ok(());
                                 }
+                                paused.chunk_tracker.set_sequential(g.sequential);
+                                if let Some(picker) = t.info().options.piece_picker.as_ref() {
+                                    paused.chunk_tracker.set_piece_picker(picker.clone());
+                                }
+                                if let Some(priorities) = g.file_priorities.as_ref() {
+                                    paused.chunk_tracker.set_file_priorities(
+                                        t.info().info.iter_file_lengths()?,
+                                        priorities,
+                                    );
+                                }
+                                if !g.head_tail_files.is_empty() {
+                                    paused.chunk_tracker.set_head_tail_files(
+                                        t.info().info.iter_file_lengths()?,
+                                        &g.head_tail_files,
+                                    );
+                                }
+                                if let Some(partial) = g.partial_pieces.take() {
+                                    paused.chunk_tracker.restore_partial_pieces(&partial);
+                                }
 
                                 if start_paused {
                                     g.state = ManagedTorrentState::Paused(paused);
@@ -278,6 +512,10 @@ impl ManagedTorrent {
                             }
                             Err(err) => {
                                 let result = anyhow::anyhow!("{:?}", err);
+                                t.info.emit(Event::TorrentError {
+                                    info_hash: t.info_hash(),
+                                    error: format!("{err:#}"),
+                                });
                                 t.locked.write().state = ManagedTorrentState::Error(err);
                                 Err(result)
                             }
@@ -310,6 +548,113 @@ impl ManagedTorrent {
         }
     }
 
+    /// Move the files of the torrent to another directory, keeping the layout inside it. Works
+    /// while the torrent is live: disk I/O on each file waits while it's being moved.
+    /// Files on another filesystem are copied, so this can take a while.
+    pub fn move_storage(&self, new_dir: &Path) -> crate::error::Result<()> {
+        let _storage_g = self.storage_lock.lock();
+        let g = self.locked.read();
+        let files = opened_files(&g.state)?;
+
+        let old_dir = self.info.out_dir();
+        if old_dir == new_dir {
+            return Ok(());
+        }
+        let old_filenames = files.iter().map(|f| f.filename()).collect::<Vec<_>>();
+        let new_filenames = old_filenames
+            .iter()
+            .map(|filename| {
+                let relative = filename
+                    .strip_prefix(&old_dir)
+                    .with_context(|| format!("{filename:?} is not in {old_dir:?}"))?;
+                Ok(new_dir.join(relative))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for (idx, file) in files.iter().enumerate() {
+            if let Err(e) = file.move_to(&new_filenames[idx]) {
+                // Put back what was moved, so that the files aren't split between two places.
+                for (file, old_filename) in files.iter().zip(old_filenames.iter()).take(idx) {
+                    if let Err(e) = file.move_to(old_filename) {
+                        warn!("error moving {:?} back: {e:#}", file.filename());
+                    }
+                }
+                return Err(e.context(ErrorKind::Disk).into());
+            }
+        }
+        for filename in old_filenames.iter() {
+            remove_empty_dirs(filename, &old_dir);
+        }
+
+        *self.info.out_dir.write() = new_dir.to_owned();
+        info!("moved storage from {old_dir:?} to {new_dir:?}");
+        Ok(())
+    }
+
+    /// Rename a file of the torrent. The new path is relative to the output folder, and may put
+    /// the file into another subfolder. Works while the torrent is live.
+    pub fn rename_file(&self, file_id: usize, new_path: &Path) -> crate::error::Result<()> {
+        validate_relative_path(new_path)?;
+        let _storage_g = self.storage_lock.lock();
+        let g = self.locked.read();
+        let files = opened_files(&g.state)?;
+        let file = files
+            .get(file_id)
+            .with_context(|| format!("invalid file id {file_id}"))?;
+
+        let out_dir = self.info.out_dir();
+        let old_filename = file.filename();
+        let mut new_filename = out_dir.join(new_path);
+        if file.is_part() {
+            new_filename = part_filename(&new_filename);
+        }
+        if files
+            .iter()
+            .enumerate()
+            .any(|(idx, f)| idx != file_id && f.filename() == new_filename)
+        {
+            return Err(
+                anyhow::anyhow!("another file of the torrent is already at {new_path:?}").into(),
+            );
+        }
+        file.move_to(&new_filename).context(ErrorKind::Disk)?;
+        remove_empty_dirs(&old_filename, &out_dir);
+
+        self.info
+            .renamed_files
+            .write()
+            .insert(file_id, new_path.to_owned());
+        info!("renamed {old_filename:?} to {new_filename:?}");
+        Ok(())
+    }
+
+    /// Rename the folder the files of a multi-file torrent are in, keeping it in the same parent
+    /// folder. Single-file torrents are stored right in the output folder, which isn't theirs to
+    /// rename.
+    pub fn rename_root_folder(&self, new_name: &str) -> crate::error::Result<()> {
+        let files = self.info.info.iter_filenames_and_lengths()?.count();
+        let new_dir = renamed_root_folder(&self.info.out_dir(), files, new_name)?;
+        self.move_storage(&new_dir)
+    }
+
+    // Replace the paused or errored state with one that checks all the files again when the
+    // torrent is started.
+    pub(crate) fn reset_for_recheck(&self) -> anyhow::Result<()> {
+        let mut g = self.locked.write();
+        match &g.state {
+            ManagedTorrentState::Paused(_) | ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::Initializing(_) => bail!("torrent is already being checked"),
+            ManagedTorrentState::Live(_) => bail!("torrent is live, pause it first"),
+            ManagedTorrentState::None => bail!("bug: torrent is in empty state"),
+        }
+        // Close the files before they are opened for the check.
+        drop(g.state.take());
+        g.state = ManagedTorrentState::Initializing(Arc::new(
+            TorrentStateInitializing::new_recheck(self.info.clone(), g.only_files.clone()),
+        ));
+        Ok(())
+    }
+
     /// Pause the torrent if it's live.
     pub fn pause(&self) -> anyhow::Result<()> {
         let mut g = self.locked.write();
@@ -354,6 +699,7 @@ impl ManagedTorrent {
                 }
                 ManagedTorrentState::Paused(p) => {
                     resp.state = S::Paused;
+                    resp.error = p.error.as_ref().map(|e| format!("{:?}", e));
                     let hns = p.hns();
                     resp.total_bytes = hns.total();
                     resp.progress_bytes = hns.progress();
@@ -367,7 +713,7 @@ impl ManagedTorrent {
                 ManagedTorrentState::Live(l) => {
                     resp.state = S::Live;
                     let live_stats = LiveStats::from(l.as_ref());
-                    let hns = l.get_hns().unwrap_or_default();
+                    let hns = l.get_hns();
                     resp.total_bytes = hns.total();
                     resp.progress_bytes = hns.progress();
                     resp.finished = hns.finished();
@@ -443,20 +789,139 @@ impl ManagedTorrent {
         g.only_files = Some(only_files.iter().copied().collect());
         Ok(())
     }
+
+    /// Download pieces in order. Can be changed at any time.
+    pub fn set_sequential(&self, sequential: bool) -> anyhow::Result<()> {
+        let mut g = self.locked.write();
+        match &mut g.state {
+            // Applied once initialization finishes.
+            ManagedTorrentState::Initializing(_) => {}
+            ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => {}
+            ManagedTorrentState::Paused(p) => p.chunk_tracker.set_sequential(sequential),
+            ManagedTorrentState::Live(l) => l
+                .lock_write("set_sequential")
+                .get_chunks_mut()?
+                .set_sequential(sequential),
+        };
+        g.sequential = sequential;
+        Ok(())
+    }
+
+    /// Set the priority of every file. Files with "skip" priority are deselected, and all the
+    /// others are selected.
+    pub fn set_file_priorities(&self, priorities: Vec<FilePriority>) -> anyhow::Result<()> {
+        let file_lengths = self.info().info.iter_file_lengths()?.collect::<Vec<_>>();
+        if priorities.len() != file_lengths.len() {
+            bail!(
+                "expected {} file priorities, got {}",
+                file_lengths.len(),
+                priorities.len()
+            );
+        }
+        let only_files = priorities
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p != FilePriority::Skip)
+            .map(|(idx, _)| idx)
+            .collect::<HashSet<_>>();
+        self.update_only_files(&only_files)?;
+
+        let mut g = self.locked.write();
+        match &mut g.state {
+            // Applied once initialization finishes.
+            ManagedTorrentState::Initializing(_) => {}
+            ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => {}
+            ManagedTorrentState::Paused(p) => p
+                .chunk_tracker
+                .set_file_priorities(file_lengths.iter().copied(), &priorities),
+            ManagedTorrentState::Live(l) => l
+                .lock_write("set_file_priorities")
+                .get_chunks_mut()?
+                .set_file_priorities(file_lengths.iter().copied(), &priorities),
+        };
+        g.file_priorities = Some(priorities);
+        Ok(())
+    }
+
+    /// Download the first and last pieces of these files before the rest, as players need them
+    /// to probe the container. Replaces the previous list.
+    pub fn set_head_tail_files(&self, files: Vec<usize>) -> anyhow::Result<()> {
+        let file_lengths = self.info().info.iter_file_lengths()?.collect::<Vec<_>>();
+        if let Some(idx) = files.iter().find(|idx| **idx >= file_lengths.len()) {
+            bail!("invalid file id {idx}");
+        }
+
+        let mut g = self.locked.write();
+        match &mut g.state {
+            // Applied once initialization finishes.
+            ManagedTorrentState::Initializing(_) => {}
+            ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => {}
+            ManagedTorrentState::Paused(p) => p
+                .chunk_tracker
+                .set_head_tail_files(file_lengths.iter().copied(), &files),
+            ManagedTorrentState::Live(l) => l
+                .lock_write("set_head_tail_files")
+                .get_chunks_mut()?
+                .set_head_tail_files(file_lengths.iter().copied(), &files),
+        };
+        g.head_tail_files = files;
+        Ok(())
+    }
 }
 
 pub struct ManagedTorrentBuilder {
     info: TorrentMetaV1Info<ByteBufOwned>,
     info_hash: Id20,
+    info_hash_v2: Option<Id32>,
+    piece_layers: Option<PieceLayers<ByteBufOwned>>,
     output_folder: PathBuf,
     force_tracker_interval: Option<Duration>,
     peer_connect_timeout: Option<Duration>,
     peer_read_write_timeout: Option<Duration>,
+    peer_keep_alive_interval: Option<Duration>,
+    peer_idle_timeout: Option<Duration>,
+    peer_inactivity_timeout: Option<Duration>,
     only_files: Option<Vec<usize>>,
-    trackers: Vec<String>,
+    trackers: Vec<Vec<String>>,
+    webseeds: Vec<String>,
+    category: Option<String>,
     peer_id: Option<Id20>,
     overwrite: bool,
+    direct_io: bool,
+    mmap: bool,
+    preallocation: Preallocation,
+    part_files: bool,
+    write_cache_size: Option<usize>,
+    socket_binding: Option<PeerSocketBinding>,
+    session_socket_binding: Option<PeerSocketBinding>,
+    peer_transport: Option<PeerTransport>,
+    utp_socket: Option<UtpSocket>,
     spawner: Option<BlockingSpawner>,
+    cpu_pool: Option<CpuPool>,
+    file_pool: Option<Arc<FilePool>>,
+    dht: Option<Dht>,
+    upload_slots: Option<usize>,
+    upload_rate_limit: Option<NonZeroU32>,
+    session_upload_rate_limit: Option<Arc<RateLimit>>,
+    sequential: bool,
+    file_priorities: Option<Vec<FilePriority>>,
+    head_tail_files: Vec<usize>,
+    partial_pieces: Option<PartialPieces>,
+    renamed_files: HashMap<usize, PathBuf>,
+    piece_picker: Option<Arc<dyn PiecePicker>>,
+    peer_limit: Option<NonZeroUsize>,
+    session_peer_semaphore: Option<Arc<Semaphore>>,
+    half_open_semaphore: Option<Arc<Semaphore>>,
+    peer_request_queue_depth: Option<NonZeroUsize>,
+    stream_readahead_pieces: Option<NonZeroU32>,
+    max_request_block_size: Option<NonZeroU32>,
+    hooks: Option<TorrentHooks>,
+    session_hooks: Option<Arc<TorrentHooks>>,
+    extensions: Option<Arc<ExtensionRegistry>>,
+    events: Option<EventSender>,
 }
 
 impl ManagedTorrentBuilder {
@@ -468,25 +933,117 @@ impl ManagedTorrentBuilder {
         Self {
             info,
             info_hash,
+            info_hash_v2: None,
+            piece_layers: None,
             output_folder: output_folder.as_ref().into(),
             spawner: None,
+            cpu_pool: None,
+            file_pool: None,
             force_tracker_interval: None,
             peer_connect_timeout: None,
             peer_read_write_timeout: None,
+            peer_keep_alive_interval: None,
+            peer_idle_timeout: None,
+            peer_inactivity_timeout: None,
             only_files: None,
             trackers: Default::default(),
+            webseeds: Default::default(),
+            category: None,
             peer_id: None,
             overwrite: false,
+            direct_io: false,
+            mmap: false,
+            preallocation: Preallocation::default(),
+            part_files: false,
+            write_cache_size: None,
+            socket_binding: None,
+            session_socket_binding: None,
+            peer_transport: None,
+            utp_socket: None,
+            dht: None,
+            upload_slots: None,
+            upload_rate_limit: None,
+            session_upload_rate_limit: None,
+            sequential: false,
+            file_priorities: None,
+            head_tail_files: Vec::new(),
+            partial_pieces: None,
+            renamed_files: Default::default(),
+            piece_picker: None,
+            peer_limit: None,
+            session_peer_semaphore: None,
+            half_open_semaphore: None,
+            peer_request_queue_depth: None,
+            stream_readahead_pieces: None,
+            max_request_block_size: None,
+            hooks: None,
+            session_hooks: None,
+            extensions: None,
+            events: None,
         }
     }
 
+    pub fn info_hash_v2(&mut self, info_hash_v2: Id32) -> &mut Self {
+        self.info_hash_v2 = Some(info_hash_v2);
+        self
+    }
+
+    /// The "piece layers" of a v2 torrent, needed to verify v2-only torrents.
+    pub fn piece_layers(&mut self, piece_layers: PieceLayers<ByteBufOwned>) -> &mut Self {
+        self.piece_layers = Some(piece_layers);
+        self
+    }
+
     pub fn only_files(&mut self, only_files: Vec<usize>) -> &mut Self {
         self.only_files = Some(only_files);
         self
     }
 
-    pub fn trackers(&mut self, trackers: Vec<String>) -> &mut Self {
-        self.trackers = trackers;
+    pub fn sequential(&mut self, sequential: bool) -> &mut Self {
+        self.sequential = sequential;
+        self
+    }
+
+    /// Files that were renamed, with their paths relative to the output folder.
+    pub fn renamed_files(&mut self, renamed_files: HashMap<usize, PathBuf>) -> &mut Self {
+        self.renamed_files = renamed_files;
+        self
+    }
+
+    pub fn file_priorities(&mut self, priorities: Vec<FilePriority>) -> &mut Self {
+        self.file_priorities = Some(priorities);
+        self
+    }
+
+    /// Files whose first and last pieces are downloaded before the rest.
+    pub fn head_tail_files(&mut self, files: Vec<usize>) -> &mut Self {
+        self.head_tail_files = files;
+        self
+    }
+
+    /// Chunks of unfinished pieces that are already on disk, e.g. from before a restart.
+    pub fn partial_pieces(&mut self, partial_pieces: PartialPieces) -> &mut Self {
+        self.partial_pieces = Some(partial_pieces);
+        self
+    }
+
+    pub fn piece_picker(&mut self, picker: Arc<dyn PiecePicker>) -> &mut Self {
+        self.piece_picker = Some(picker);
+        self
+    }
+
+    pub fn trackers(&mut self, tiers: Vec<Vec<String>>) -> &mut Self {
+        self.trackers = tiers;
+        self
+    }
+
+    pub fn webseeds(&mut self, webseeds: Vec<String>) -> &mut Self {
+        self.webseeds = webseeds;
+        self
+    }
+
+    pub fn category(&mut self, category: String) -> &mut Self {
+        self.category = Some(category);
         self
     }
 
@@ -495,6 +1052,128 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    pub fn preallocation(&mut self, preallocation: Preallocation) -> &mut Self {
+        self.preallocation = preallocation;
+        self
+    }
+
+    /// Download files with a ".part" suffix, and rename them once they are complete.
+    pub fn part_files(&mut self, part_files: bool) -> &mut Self {
+        self.part_files = part_files;
+        self
+    }
+
+    /// Buffer up to this many bytes of downloaded pieces in memory, and write each piece to disk
+    /// at once when it's complete and verified.
+    pub fn write_cache_size(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.write_cache_size = bytes;
+        self
+    }
+
+    pub fn socket_binding(&mut self, binding: PeerSocketBinding) -> &mut Self {
+        self.socket_binding = Some(binding);
+        self
+    }
+
+    pub(crate) fn session_socket_binding(&mut self, binding: PeerSocketBinding) -> &mut Self {
+        self.session_socket_binding = Some(binding);
+        self
+    }
+
+    pub fn upload_slots(&mut self, upload_slots: usize) -> &mut Self {
+        self.upload_slots = Some(upload_slots);
+        self
+    }
+
+    /// Limit uploads of this torrent to this many bytes per second.
+    pub fn upload_rate_limit(&mut self, bytes_per_second: NonZeroU32) -> &mut Self {
+        self.upload_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Connect to at most this many peers at the same time.
+    pub fn peer_limit(&mut self, limit: NonZeroUsize) -> &mut Self {
+        self.peer_limit = Some(limit);
+        self
+    }
+
+    /// Keep at most this many chunk requests in flight to each peer.
+    pub fn peer_request_queue_depth(&mut self, depth: NonZeroUsize) -> &mut Self {
+        self.peer_request_queue_depth = Some(depth);
+        self
+    }
+
+    /// How many pieces after the position streams read at to download before all others.
+    pub fn stream_readahead_pieces(&mut self, pieces: NonZeroU32) -> &mut Self {
+        self.stream_readahead_pieces = Some(pieces);
+        self
+    }
+
+    /// The largest block peers may request from this torrent.
+    pub fn max_request_block_size(&mut self, size: NonZeroU32) -> &mut Self {
+        self.max_request_block_size = Some(size);
+        self
+    }
+
+    /// Commands to run when something happens to the torrent, instead of the session's ones.
+    pub fn hooks(&mut self, hooks: TorrentHooks) -> &mut Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub(crate) fn session_hooks(&mut self, hooks: Arc<TorrentHooks>) -> &mut Self {
+        self.session_hooks = Some(hooks);
+        self
+    }
+
+    // Custom extensions registered with the session.
+    pub(crate) fn extensions(&mut self, extensions: Arc<ExtensionRegistry>) -> &mut Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    // Shared by all torrents of the session, a peer needs a permit from it too.
+    pub(crate) fn session_peer_semaphore(&mut self, sem: Arc<Semaphore>) -> &mut Self {
+        self.session_peer_semaphore = Some(sem);
+        self
+    }
+
+    // Shared by all torrents of the session, held while connecting to a peer.
+    pub(crate) fn half_open_semaphore(&mut self, sem: Arc<Semaphore>) -> &mut Self {
+        self.half_open_semaphore = Some(sem);
+        self
+    }
+
+    pub(crate) fn session_upload_rate_limit(&mut self, limit: Arc<RateLimit>) -> &mut Self {
+        self.session_upload_rate_limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn events(&mut self, events: EventSender) -> &mut Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn peer_transport(&mut self, transport: PeerTransport) -> &mut Self {
+        self.peer_transport = Some(transport);
+        self
+    }
+
+    pub(crate) fn utp_socket(&mut self, utp_socket: UtpSocket) -> &mut Self {
+        self.utp_socket = Some(utp_socket);
+        self
+    }
+
     pub fn force_tracker_interval(&mut self, force_tracker_interval: Duration) -> &mut Self {
         self.force_tracker_interval = Some(force_tracker_interval);
         self
@@ -505,6 +1184,21 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub(crate) fn cpu_pool(&mut self, cpu_pool: CpuPool) -> &mut Self {
+        self.cpu_pool = Some(cpu_pool);
+        self
+    }
+
+    pub(crate) fn file_pool(&mut self, file_pool: Arc<FilePool>) -> &mut Self {
+        self.file_pool = Some(file_pool);
+        self
+    }
+
+    pub(crate) fn dht(&mut self, dht: Dht) -> &mut Self {
+        self.dht = Some(dht);
+        self
+    }
+
     pub fn peer_id(&mut self, peer_id: Id20) -> &mut Self {
         self.peer_id = Some(peer_id);
         self
@@ -520,36 +1214,191 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub fn peer_keep_alive_interval(&mut self, interval: Duration) -> &mut Self {
+        self.peer_keep_alive_interval = Some(interval);
+        self
+    }
+
+    pub fn peer_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.peer_idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn peer_inactivity_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.peer_inactivity_timeout = Some(timeout);
+        self
+    }
+
     pub(crate) fn build(self, span: tracing::Span) -> anyhow::Result<ManagedTorrentHandle> {
         let lengths = Lengths::from_torrent(&self.info)?;
+        // Skipped files are never selected.
+        let only_files = match self.file_priorities.as_ref() {
+            Some(p) if p.contains(&FilePriority::Skip) => Some(
+                (0..p.len())
+                    .filter(|idx| p[*idx] != FilePriority::Skip)
+                    .filter(|idx| self.only_files.as_ref().is_none_or(|o| o.contains(idx)))
+                    .collect(),
+            ),
+            _ => self.only_files,
+        };
+        let v2_piece_hashes = self
+            .info
+            .v2_piece_hashes(self.piece_layers.as_ref())
+            .context("error validating v2 piece layers")?;
+        if let Some(hashes) = v2_piece_hashes.as_ref() {
+            if hashes.len() != lengths.total_pieces() as usize {
+                bail!(
+                    "expected {} v2 piece hashes, got {}",
+                    lengths.total_pieces(),
+                    hashes.len()
+                );
+            }
+        }
         let info = Arc::new(ManagedTorrentInfo {
             span,
             info: self.info,
             info_hash: self.info_hash,
-            out_dir: self.output_folder,
-            trackers: self.trackers.into_iter().collect(),
+            info_hash_v2: self.info_hash_v2,
+            piece_layers: self.piece_layers,
+            out_dir: RwLock::new(self.output_folder),
+            renamed_files: RwLock::new(self.renamed_files),
+            trackers: self.trackers.iter().flatten().cloned().collect(),
+            tracker_tiers: self.trackers,
+            webseeds: self.webseeds,
+            category: self.category,
             spawner: self.spawner.unwrap_or_default(),
+            cpu_pool: self.cpu_pool.unwrap_or_default(),
+            file_pool: self.file_pool.unwrap_or_default(),
             peer_id: self.peer_id.unwrap_or_else(generate_peer_id),
             lengths,
+            v2_piece_hashes,
+            tracker_stats: Default::default(),
             options: ManagedTorrentOptions {
                 force_tracker_interval: self.force_tracker_interval,
                 peer_connect_timeout: self.peer_connect_timeout,
                 peer_read_write_timeout: self.peer_read_write_timeout,
+                peer_keep_alive_interval: self.peer_keep_alive_interval,
+                peer_idle_timeout: self.peer_idle_timeout,
+                peer_inactivity_timeout: self.peer_inactivity_timeout,
                 overwrite: self.overwrite,
+                direct_io: self.direct_io,
+                mmap: self.mmap,
+                preallocation: self.preallocation,
+                part_files: self.part_files,
+                write_cache_size: self.write_cache_size,
+                socket_binding: self.socket_binding,
+                session_socket_binding: self.session_socket_binding,
+                peer_transport: self.peer_transport,
+                utp_socket: self.utp_socket,
+                dht: self.dht,
+                upload_slots: self.upload_slots,
+                upload_rate_limit: self
+                    .upload_rate_limit
+                    .map(|bps| Arc::new(RateLimit::new(bps))),
+                session_upload_rate_limit: self.session_upload_rate_limit,
+                piece_picker: self.piece_picker,
+                peer_limit: self.peer_limit,
+                session_peer_semaphore: self.session_peer_semaphore,
+                half_open_semaphore: self.half_open_semaphore,
+                peer_request_queue_depth: self.peer_request_queue_depth,
+                stream_readahead_pieces: self.stream_readahead_pieces,
+                max_request_block_size: self.max_request_block_size,
+                hooks: self.hooks,
+                session_hooks: self.session_hooks,
+                extensions: self.extensions,
             },
+            events: self
+                .events
+                .unwrap_or_else(|| tokio::sync::broadcast::channel(EVENTS_CHANNEL_CAPACITY).0),
         });
         let initializing = Arc::new(TorrentStateInitializing::new(
             info.clone(),
-            self.only_files.clone(),
+            only_files.clone(),
         ));
         Ok(Arc::new(ManagedTorrent {
             locked: RwLock::new(ManagedTorrentLocked {
                 state: ManagedTorrentState::Initializing(initializing),
-                only_files: self.only_files,
+                only_files,
+                sequential: self.sequential,
+                file_priorities: self.file_priorities,
+                head_tail_files: self.head_tail_files,
+                partial_pieces: self.partial_pieces,
             }),
             info,
+            storage_lock: Mutex::new(()),
         }))
     }
 }
 
 pub type ManagedTorrentHandle = Arc<ManagedTorrent>;
+
+// Remove the directories between "root" and "filename" that are empty, e.g. after the file was
+// moved. Errors are ignored, as the directories might be used by something else.
+fn remove_empty_dirs(filename: &Path, root: &Path) {
+    for dir in filename.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+fn opened_files(state: &ManagedTorrentState) -> anyhow::Result<&OpenedFiles> {
+    match state {
+        ManagedTorrentState::Live(live) => Ok(&live.files),
+        ManagedTorrentState::Paused(paused) => Ok(&paused.files),
+        ManagedTorrentState::Initializing(_) => {
+            bail!("torrent is initializing, its files can't be moved")
+        }
+        ManagedTorrentState::Error(_) => bail!("can't move files of a torrent in error state"),
+        ManagedTorrentState::None => bail!("bug: torrent is in empty state"),
+    }
+}
+
+// Where the folder of a torrent with this many files goes when it's renamed to "new_name".
+fn renamed_root_folder(out_dir: &Path, files: usize, new_name: &str) -> anyhow::Result<PathBuf> {
+    if files < 2 {
+        bail!("single-file torrents have no folder of their own, rename the file instead");
+    }
+    let new_name = Path::new(new_name);
+    validate_relative_path(new_name)?;
+    if new_name.components().count() != 1 {
+        bail!("{new_name:?} should be a folder name, not a path");
+    }
+    let parent = out_dir
+        .parent()
+        .with_context(|| format!("{out_dir:?} has no parent folder"))?;
+    Ok(parent.join(new_name))
+}
+
+// Paths given by users must stay inside the output folder.
+fn validate_relative_path(path: &Path) -> anyhow::Result<()> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        bail!("invalid path {path:?}, it must be relative and can't contain \"..\"");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::renamed_root_folder;
+
+    #[test]
+    fn test_renamed_root_folder() {
+        let out_dir = Path::new("/downloads/torrent");
+        assert_eq!(
+            renamed_root_folder(out_dir, 2, "renamed").unwrap(),
+            Path::new("/downloads/renamed")
+        );
+        // The output folder of a single file isn't the torrent's.
+        assert!(renamed_root_folder(Path::new("/downloads"), 1, "renamed").is_err());
+        assert!(renamed_root_folder(out_dir, 2, "a/b").is_err());
+        assert!(renamed_root_folder(out_dir, 2, "..").is_err());
+        assert!(renamed_root_folder(out_dir, 2, "").is_err());
+    }
+}
@@ -11,6 +11,8 @@ pub struct TorrentStatePaused {
     pub(crate) info: Arc<ManagedTorrentInfo>,
     pub(crate) files: OpenedFiles,
     pub(crate) chunk_tracker: ChunkTracker,
+    // Set if the torrent was paused because of a fatal (e.g. disk) error.
+    pub(crate) error: Option<anyhow::Error>,
 }
 
 impl TorrentStatePaused {
@@ -20,7 +22,7 @@ impl TorrentStatePaused {
         Ok(())
     }
 
-    pub(crate) fn hns(&self) -> &HaveNeededSelected {
+    pub(crate) fn hns(&self) -> HaveNeededSelected {
         self.chunk_tracker.get_hns()
     }
 }
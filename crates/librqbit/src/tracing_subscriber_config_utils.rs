@@ -1,25 +1,214 @@
-use std::io::LineWriter;
+use std::{io::LineWriter, sync::Arc};
 
 use anyhow::Context;
 use bytes::Bytes;
+use futures::{Stream, StreamExt};
 use librqbit_core::spawn_utils::spawn;
-use tracing::error_span;
-use tracing_subscriber::fmt::MakeWriter;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_with::serde_as;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{error_span, level_filters::LevelFilter, subscriber::Interest, Level, Metadata};
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    layer::{Context as LayerContext, Filter},
+    EnvFilter,
+};
+
+// What the log streams get unless they ask for something else.
+const DEFAULT_LOG_STREAM_FILTER: &str = "info,librqbit=debug";
+
+/// Which log lines an HTTP API log stream wants.
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LogStreamFilter {
+    /// The most verbose level to include, e.g. "trace". If not set, the stream
+    /// gets "info" and "debug" for librqbit.
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    pub level: Option<Level>,
+    /// Only include events of this target and its submodules, e.g.
+    /// "librqbit::peer_connection".
+    pub target: Option<String>,
+}
+
+impl LogStreamFilter {
+    fn matches(&self, level: &Level, target: &str) -> bool {
+        let max_level = match self.level {
+            Some(level) => level,
+            None if target_matches(target, "librqbit") => Level::DEBUG,
+            None => Level::INFO,
+        };
+        *level <= max_level
+            && self
+                .target
+                .as_deref()
+                .is_none_or(|t| target_matches(target, t))
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        LevelFilter::from_level(self.level.unwrap_or(Level::DEBUG))
+    }
+}
+
+fn target_matches(target: &str, prefix: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+#[derive(Clone)]
+struct LogLine {
+    level: Level,
+    target: Arc<str>,
+    line: Bytes,
+}
+
+type LogStreamFilters = Arc<RwLock<Vec<Arc<LogStreamFilter>>>>;
+
+/// Log lines formatted as JSON, broadcast to the HTTP API log streams.
+#[derive(Clone)]
+pub struct LineBroadcast {
+    tx: tokio::sync::broadcast::Sender<LogLine>,
+    // The filters of the open streams. Events they want are broadcast even if
+    // the default filter would drop them.
+    streams: LogStreamFilters,
+}
+
+impl LineBroadcast {
+    /// Subscribe to the log lines that match the filter, one JSON object per line.
+    pub fn subscribe(
+        &self,
+        filter: LogStreamFilter,
+    ) -> impl Stream<Item = std::result::Result<Bytes, BroadcastStreamRecvError>> + Send + Sync + 'static
+    {
+        let rx = BroadcastStream::new(self.tx.subscribe());
+        let registration = LogStreamRegistration::new(self.streams.clone(), filter);
+        rx.filter_map(move |line| {
+            let line = match line {
+                Ok(l) if registration.filter.matches(&l.level, &l.target) => Some(Ok(l.line)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            };
+            std::future::ready(line)
+        })
+    }
+}
+
+// Keeps the stream's filter registered while the stream is alive.
+struct LogStreamRegistration {
+    streams: LogStreamFilters,
+    filter: Arc<LogStreamFilter>,
+}
+
+impl LogStreamRegistration {
+    fn new(streams: LogStreamFilters, filter: LogStreamFilter) -> Self {
+        let filter = Arc::new(filter);
+        streams.write().push(filter.clone());
+        // Callsites that were disabled before may be enabled now.
+        tracing::callsite::rebuild_interest_cache();
+        Self { streams, filter }
+    }
+}
+
+impl Drop for LogStreamRegistration {
+    fn drop(&mut self) {
+        self.streams
+            .write()
+            .retain(|f| !Arc::ptr_eq(f, &self.filter));
+        tracing::callsite::rebuild_interest_cache();
+    }
+}
+
+// Lets through what the default filter does, and whatever an open stream wants.
+struct LineBroadcastFilter {
+    default: EnvFilter,
+    streams: LogStreamFilters,
+}
+
+impl LineBroadcastFilter {
+    fn any_stream_wants(&self, meta: &Metadata<'_>) -> bool {
+        self.streams
+            .read()
+            .iter()
+            .any(|f| f.matches(meta.level(), meta.target()))
+    }
+}
+
+impl<S> Filter<S> for LineBroadcastFilter {
+    fn enabled(&self, meta: &Metadata<'_>, cx: &LayerContext<'_, S>) -> bool {
+        <EnvFilter as Filter<S>>::enabled(&self.default, meta, cx) || self.any_stream_wants(meta)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.any_stream_wants(meta) {
+            return Interest::always();
+        }
+        <EnvFilter as Filter<S>>::callsite_enabled(&self.default, meta)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let default = <EnvFilter as Filter<S>>::max_level_hint(&self.default)?;
+        Some(
+            self.streams
+                .read()
+                .iter()
+                .map(|f| f.max_level())
+                .fold(default, std::cmp::max),
+        )
+    }
+
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: LayerContext<'_, S>,
+    ) {
+        <EnvFilter as Filter<S>>::on_new_span(&self.default, attrs, id, ctx)
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: LayerContext<'_, S>,
+    ) {
+        <EnvFilter as Filter<S>>::on_record(&self.default, id, values, ctx)
+    }
+
+    fn on_enter(&self, id: &tracing::span::Id, ctx: LayerContext<'_, S>) {
+        <EnvFilter as Filter<S>>::on_enter(&self.default, id, ctx)
+    }
+
+    fn on_exit(&self, id: &tracing::span::Id, ctx: LayerContext<'_, S>) {
+        <EnvFilter as Filter<S>>::on_exit(&self.default, id, ctx)
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: LayerContext<'_, S>) {
+        <EnvFilter as Filter<S>>::on_close(&self.default, id, ctx)
+    }
+}
 
 struct Subscriber {
-    tx: tokio::sync::broadcast::Sender<Bytes>,
+    tx: tokio::sync::broadcast::Sender<LogLine>,
 }
 
 struct Writer {
-    tx: tokio::sync::broadcast::Sender<Bytes>,
+    tx: tokio::sync::broadcast::Sender<LogLine>,
+    level: Level,
+    target: Arc<str>,
 }
 
-pub type LineBroadcast = tokio::sync::broadcast::Sender<Bytes>;
-
 impl Subscriber {
     pub fn new() -> (Self, LineBroadcast) {
         let (tx, _) = tokio::sync::broadcast::channel(100);
-        (Self { tx: tx.clone() }, tx)
+        (
+            Self { tx: tx.clone() },
+            LineBroadcast {
+                tx,
+                streams: Default::default(),
+            },
+        )
     }
 }
 
@@ -29,6 +218,16 @@ impl<'a> MakeWriter<'a> for Subscriber {
     fn make_writer(&self) -> Self::Writer {
         LineWriter::new(Writer {
             tx: self.tx.clone(),
+            level: Level::INFO,
+            target: "".into(),
+        })
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        LineWriter::new(Writer {
+            tx: self.tx.clone(),
+            level: *meta.level(),
+            target: meta.target().into(),
         })
     }
 }
@@ -39,8 +238,11 @@ impl std::io::Write for Writer {
         if self.tx.receiver_count() == 0 {
             return Ok(len);
         }
-        let arc = buf.to_vec().into();
-        let _ = self.tx.send(arc);
+        let _ = self.tx.send(LogLine {
+            level: self.level,
+            target: self.target.clone(),
+            line: buf.to_vec().into(),
+        });
         Ok(len)
     }
 
@@ -75,9 +277,15 @@ pub fn init_logging(opts: InitLoggingOptions) -> anyhow::Result<InitLoggingResul
     let (stderr_filter, reload_stderr_filter) =
         tracing_subscriber::reload::Layer::new(stderr_filter);
 
-    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+    use tracing_subscriber::{fmt, prelude::*};
 
     let (line_sub, line_broadcast) = Subscriber::new();
+    let line_filter = LineBroadcastFilter {
+        default: EnvFilter::builder()
+            .parse(DEFAULT_LOG_STREAM_FILTER)
+            .unwrap(),
+        streams: line_broadcast.streams.clone(),
+    };
 
     let layered = tracing_subscriber::registry()
         // Stderr logging layer.
@@ -89,7 +297,7 @@ pub fn init_logging(opts: InitLoggingOptions) -> anyhow::Result<InitLoggingResul
                 .fmt_fields(tracing_subscriber::fmt::format::JsonFields::new())
                 .event_format(fmt::format().with_ansi(false).json())
                 .with_writer(line_sub)
-                .with_filter(EnvFilter::builder().parse("info,librqbit=debug").unwrap()),
+                .with_filter(line_filter),
         );
     if let Some(log_file) = &opts.log_file {
         let log_file = log_file.to_string();
@@ -137,3 +345,27 @@ pub fn init_logging(opts: InitLoggingOptions) -> anyhow::Result<InitLoggingResul
         line_broadcast,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use tracing::Level;
+
+    use super::LogStreamFilter;
+
+    #[test]
+    fn test_log_stream_filter() {
+        let default = LogStreamFilter::default();
+        assert!(default.matches(&Level::DEBUG, "librqbit::session"));
+        assert!(!default.matches(&Level::TRACE, "librqbit::session"));
+        assert!(!default.matches(&Level::DEBUG, "hyper"));
+        assert!(default.matches(&Level::INFO, "hyper"));
+
+        let peers: LogStreamFilter =
+            serde_json::from_str(r#"{"level": "trace", "target": "librqbit::peer_connection"}"#)
+                .unwrap();
+        assert!(peers.matches(&Level::TRACE, "librqbit::peer_connection"));
+        assert!(peers.matches(&Level::DEBUG, "librqbit::peer_connection::inner"));
+        assert!(!peers.matches(&Level::TRACE, "librqbit::peer_connection_other"));
+        assert!(!peers.matches(&Level::ERROR, "librqbit::session"));
+    }
+}
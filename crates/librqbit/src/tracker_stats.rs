@@ -0,0 +1,178 @@
+// What's known about announcing a torrent to each of its trackers, for showing in UIs.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracker_comms::TrackerAnnounceResponse;
+
+/// The announce status of one tracker of a torrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerStats {
+    pub url: String,
+    /// Trackers are tried by tiers (BEP 12), lowest first.
+    pub tier: usize,
+    /// Whether the last announce succeeded. None if it wasn't announced to yet.
+    pub working: Option<bool>,
+    pub last_announce_secs_ago: Option<u64>,
+    pub next_announce_in_secs: Option<u64>,
+    pub last_error: Option<String>,
+    /// Reported by the tracker in the last successful announce.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Peers returned by the last successful announce, and in total.
+    pub last_peers: usize,
+    pub peers_received: u64,
+    pub announces: u64,
+    pub errors: u64,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    working: Option<bool>,
+    last_announce: Option<Instant>,
+    next_announce: Option<Instant>,
+    last_error: Option<String>,
+    seeders: Option<u64>,
+    leechers: Option<u64>,
+    last_peers: usize,
+    peers_received: u64,
+    announces: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct TrackersStats {
+    trackers: RwLock<HashMap<String, TrackerState>>,
+}
+
+// The announcer reports trackers by their parsed URL, which may not be spelled
+// exactly like in the torrent.
+fn key(tracker: &str) -> String {
+    url::Url::parse(tracker)
+        .map(String::from)
+        .unwrap_or_else(|_| tracker.to_owned())
+}
+
+impl TrackersStats {
+    pub fn on_announce(&self, tracker: &str, response: &TrackerAnnounceResponse) {
+        let mut g = self.trackers.write();
+        let t = g.entry(key(tracker)).or_default();
+        t.working = Some(true);
+        t.last_announce = Some(Instant::now());
+        t.seeders = response.seeders;
+        t.leechers = response.leechers;
+        t.last_peers = response.peers.len();
+        t.peers_received += response.peers.len() as u64;
+        t.announces += 1;
+    }
+
+    pub fn on_error(&self, tracker: &str, error: &anyhow::Error) {
+        let mut g = self.trackers.write();
+        let t = g.entry(key(tracker)).or_default();
+        t.working = Some(false);
+        t.last_announce = Some(Instant::now());
+        t.last_error = Some(format!("{error:#}"));
+        t.errors += 1;
+    }
+
+    pub fn on_next_announce(&self, tracker: &str, after: Duration) {
+        self.trackers
+            .write()
+            .entry(key(tracker))
+            .or_default()
+            .next_announce = Some(Instant::now() + after);
+    }
+
+    // The stats of all the trackers, by tier.
+    pub fn snapshot(&self, tiers: &[Vec<String>]) -> Vec<TrackerStats> {
+        let now = Instant::now();
+        let g = self.trackers.read();
+        tiers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, urls)| urls.iter().map(move |url| (tier, url)))
+            .map(|(tier, url)| {
+                let mut stats = TrackerStats {
+                    url: url.clone(),
+                    tier,
+                    ..Default::default()
+                };
+                if let Some(t) = g.get(&key(url)) {
+                    stats.working = t.working;
+                    stats.last_announce_secs_ago =
+                        t.last_announce.map(|i| now.duration_since(i).as_secs());
+                    stats.next_announce_in_secs = t
+                        .next_announce
+                        .map(|i| i.saturating_duration_since(now).as_secs());
+                    stats.last_error = t.last_error.clone();
+                    stats.seeders = t.seeders;
+                    stats.leechers = t.leechers;
+                    stats.last_peers = t.last_peers;
+                    stats.peers_received = t.peers_received;
+                    stats.announces = t.announces;
+                    stats.errors = t.errors;
+                }
+                stats
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracker_comms::TrackerAnnounceResponse;
+
+    use super::TrackersStats;
+
+    #[test]
+    fn test_trackers_stats() {
+        let stats = TrackersStats::default();
+        let a = "udp://a:1337/announce".to_owned();
+        let b = "http://B/announce".to_owned();
+        let c = "http://c/announce".to_owned();
+        let tiers = vec![vec![a.clone(), b.clone()], vec![c.clone()]];
+
+        stats.on_error(&a, &anyhow::anyhow!("timeout"));
+        let response = TrackerAnnounceResponse {
+            peers: vec!["1.2.3.4:6881".parse().unwrap()],
+            interval: Duration::from_secs(1800),
+            seeders: Some(10),
+            leechers: Some(2),
+            min_interval: None,
+            tracker_id: None,
+        };
+        // The announcer reports the parsed URL.
+        stats.on_announce("http://b/announce", &response);
+        stats.on_announce("http://b/announce", &response);
+        stats.on_next_announce(&a, Duration::from_secs(1800));
+        stats.on_next_announce("http://b/announce", Duration::from_secs(1800));
+
+        let snapshot = stats.snapshot(&tiers);
+        assert_eq!(snapshot.len(), 3);
+
+        assert_eq!(snapshot[0].url, a);
+        assert_eq!(snapshot[0].working, Some(false));
+        assert_eq!(snapshot[0].last_error.as_deref(), Some("timeout"));
+        assert_eq!(snapshot[0].errors, 1);
+        assert!(snapshot[0].next_announce_in_secs.unwrap() > 1700);
+
+        assert_eq!(snapshot[1].working, Some(true));
+        assert_eq!(snapshot[1].seeders, Some(10));
+        assert_eq!(snapshot[1].leechers, Some(2));
+        assert_eq!(snapshot[1].last_peers, 1);
+        assert_eq!(snapshot[1].peers_received, 2);
+        assert_eq!(snapshot[1].announces, 2);
+        assert_eq!(snapshot[1].last_announce_secs_ago, Some(0));
+
+        assert_eq!(snapshot[2].url, c);
+        assert_eq!(snapshot[2].tier, 1);
+        assert_eq!(snapshot[2].working, None);
+        assert_eq!(snapshot[2].last_announce_secs_ago, None);
+    }
+}
@@ -2,10 +2,10 @@ use std::net::SocketAddr;
 
 use futures::stream::BoxStream;
 
-use crate::opened_file::OpenedFile;
+use crate::{opened_file::OpenedFile, torrent_state::peer::PeerOrigin};
 
 pub type BF = bitvec::boxed::BitBox<u8, bitvec::order::Msb0>;
 
 pub type PeerHandle = SocketAddr;
-pub type PeerStream = BoxStream<'static, SocketAddr>;
+pub type PeerStream = BoxStream<'static, (SocketAddr, PeerOrigin)>;
 pub(crate) type OpenedFiles = Vec<OpenedFile>;
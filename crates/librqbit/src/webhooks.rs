@@ -0,0 +1,237 @@
+// Notifying other services about torrents over HTTP, e.g. to have a media server import a
+// download once it's finished, or to post to a chat.
+//
+// Every event is POSTed as JSON to the webhooks that want it, and failed deliveries are retried
+// with backoff. If the webhook has a secret, the body is signed with HMAC-SHA256, and the
+// signature sent in the X-Rqbit-Signature header as "sha256=<hex>", so that the receiver can check
+// that it came from us.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::events::Event;
+
+const WEBHOOK_ATTEMPTS: u32 = 5;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const WEBHOOK_SIGNATURE_HEADER: &str = "X-Rqbit-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// All the selected files were downloaded.
+    Finished,
+    /// The torrent couldn't be started, or failed writing to disk.
+    Error,
+    /// Nothing was downloaded for a while, although the torrent isn't finished.
+    Stalled,
+}
+
+impl WebhookEvent {
+    pub(crate) fn from_event(event: &Event) -> Option<Self> {
+        match event {
+            Event::TorrentFinished { .. } => Some(WebhookEvent::Finished),
+            Event::DiskError { .. } | Event::TorrentError { .. } => Some(WebhookEvent::Error),
+            Event::TorrentStalled { .. } => Some(WebhookEvent::Stalled),
+            _ => None,
+        }
+    }
+}
+
+/// A URL to POST events about torrents to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Sign the requests with this key.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// The events to send. All of them if empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Webhook {
+    pub(crate) fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+// The event as it's sent to the events endpoint, plus the name of the torrent.
+pub(crate) fn payload(event: &Event, name: Option<&str>) -> serde_json::Result<Vec<u8>> {
+    let mut value = serde_json::to_value(event)?;
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("name".into(), name.into());
+    }
+    serde_json::to_vec(&value)
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// POST the body to the webhook, retrying network errors, server errors and rate limiting.
+pub(crate) async fn deliver(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    body: Vec<u8>,
+) -> anyhow::Result<()> {
+    deliver_with_backoff(client, webhook, body, WEBHOOK_INITIAL_BACKOFF).await
+}
+
+async fn deliver_with_backoff(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    body: Vec<u8>,
+    mut backoff: Duration,
+) -> anyhow::Result<()> {
+    let mut attempt = 1;
+    loop {
+        let mut request = client
+            .post(&webhook.url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = webhook.secret.as_ref() {
+            request = request.header(WEBHOOK_SIGNATURE_HEADER, signature(secret, &body));
+        }
+        let error = match request.send().await {
+            Ok(r) if r.status().is_success() => return Ok(()),
+            // Sending the same request again won't help.
+            Ok(r)
+                if r.status().is_client_error() && r.status() != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                anyhow::bail!("{} responded with {}", webhook.url, r.status())
+            }
+            Ok(r) => anyhow::anyhow!("{} responded with {}", webhook.url, r.status()),
+            Err(e) => anyhow::Error::from(e).context(format!("error sending to {}", webhook.url)),
+        };
+        if attempt == WEBHOOK_ATTEMPTS {
+            return Err(error).with_context(|| format!("giving up after {attempt} attempts"));
+        }
+        debug!(
+            attempt,
+            "webhook failed, retrying in {backoff:?}: {error:#}"
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+        backoff *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Router};
+    use librqbit_core::hash_id::Id20;
+    use parking_lot::Mutex;
+
+    use super::{deliver_with_backoff, payload, signature, Webhook, WebhookEvent};
+    use crate::events::Event;
+
+    #[test]
+    fn test_signature() {
+        // Test vector from RFC 4231.
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    // Answers with the given statuses in order, the last one repeated, and returns when each
+    // request came.
+    async fn deliver_to(statuses: &[u16], backoff: Duration) -> (anyhow::Result<()>, Vec<Instant>) {
+        type Requests = Arc<Mutex<(Vec<u16>, Vec<Instant>)>>;
+        let requests: Requests = Arc::new(Mutex::new((statuses.to_vec(), Vec::new())));
+        let app = Router::new()
+            .route(
+                "/",
+                post(|State(requests): State<Requests>| async move {
+                    let mut g = requests.lock();
+                    g.1.push(Instant::now());
+                    let status = g.0[(g.1.len() - 1).min(g.0.len() - 1)];
+                    StatusCode::from_u16(status).unwrap()
+                }),
+            )
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = Webhook {
+            url,
+            secret: None,
+            events: Vec::new(),
+        };
+        let result =
+            deliver_with_backoff(&reqwest::Client::new(), &webhook, b"{}".to_vec(), backoff).await;
+        server.abort();
+        let times = requests.lock().1.clone();
+        (result, times)
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let backoff = Duration::from_millis(20);
+
+        // Rate limiting and server errors are retried.
+        let (result, times) = deliver_to(&[429, 503, 200], backoff).await;
+        result.unwrap();
+        assert_eq!(times.len(), 3);
+
+        // Other client errors aren't.
+        let (result, times) = deliver_to(&[404], backoff).await;
+        assert!(result.is_err());
+        assert_eq!(times.len(), 1);
+
+        // Giving up after all the attempts, waiting twice as long before each one.
+        let (result, times) = deliver_to(&[500], backoff).await;
+        assert!(result.is_err());
+        assert_eq!(times.len(), super::WEBHOOK_ATTEMPTS as usize);
+        for (i, w) in times.windows(2).enumerate() {
+            assert!(w[1] - w[0] >= backoff * 2u32.pow(i as u32));
+        }
+    }
+
+    #[test]
+    fn test_payload() {
+        let event = Event::TorrentStalled {
+            info_hash: Id20::new([1; 20]),
+            stalled_for_secs: 300,
+        };
+        assert_eq!(
+            WebhookEvent::from_event(&event),
+            Some(WebhookEvent::Stalled)
+        );
+        let body = payload(&event, Some("name")).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "type": "torrent_stalled",
+                "info_hash": "0101010101010101010101010101010101010101",
+                "stalled_for_secs": 300,
+                "name": "name",
+            })
+        );
+
+        let webhook = Webhook {
+            url: "http://localhost".into(),
+            secret: None,
+            events: vec![WebhookEvent::Finished],
+        };
+        assert!(webhook.wants(WebhookEvent::Finished));
+        assert!(!webhook.wants(WebhookEvent::Stalled));
+    }
+}
@@ -9,6 +9,7 @@ export interface TorrentFile {
   components: string[];
   length: number;
   included: boolean;
+  have_bytes?: number;
 }
 
 // Interface for the Torrent Details API response
@@ -38,10 +39,13 @@ export interface Speed {
 export interface LiveTorrentStats {
   snapshot: {
     have_bytes: number;
+    needed_bytes: number;
+    selected_bytes: number;
     downloaded_and_checked_bytes: number;
     downloaded_and_checked_pieces: number;
     fetched_bytes: number;
     uploaded_bytes: number;
+    wasted_bytes: number;
     initially_needed_bytes: number;
     remaining_bytes: number;
     total_bytes: number;
@@ -53,7 +57,13 @@ export interface LiveTorrentStats {
       seen: number;
       dead: number;
       not_needed: number;
+      banned: number;
     };
+    min_piece_availability: number;
+    distributed_copies: number;
+    download_bps_smoothed: number;
+    upload_bps_smoothed: number;
+    eta_secs: number | null;
   };
   average_piece_download_time: {
     secs: number;
@@ -97,12 +107,48 @@ export interface ErrorDetails {
   text: string;
 }
 
+export interface PeerCounters {
+  incoming_connections: number;
+  fetched_bytes: number;
+  uploaded_bytes: number;
+  total_time_connecting_ms: number;
+  connection_attempts: number;
+  connections: number;
+  errors: number;
+  fetched_chunks: number;
+  downloaded_and_checked_pieces: number;
+  total_piece_download_ms: number;
+  times_stolen_from_me: number;
+  times_i_stole: number;
+  hash_failed_pieces: number;
+  hash_failed_bytes: number;
+  wasted_bytes: number;
+}
+
+export interface PeerStats {
+  counters: PeerCounters;
+  state: string;
+  origin: "tracker" | "dht" | "pex" | "incoming" | "holepunch" | "manual";
+  upload_limit: number | null;
+  download_limit: number | null;
+  download_bps: number;
+  upload_bps: number;
+  i_am_interested: boolean;
+  snubbed: boolean;
+}
+
+export interface PeerStatsSnapshot {
+  peers: Record<string, PeerStats>;
+}
+
 export type Duration = number;
 
 export interface PeerConnectionOptions {
   connect_timeout?: Duration | null;
   read_write_timeout?: Duration | null;
   keep_alive_interval?: Duration | null;
+  idle_timeout?: Duration | null;
+  inactivity_timeout?: Duration | null;
 }
 
 export interface AddTorrentOptions {
@@ -166,6 +212,7 @@ export interface RqbitAPI {
   listTorrents: () => Promise<ListTorrentsResponse>;
   getTorrentDetails: (index: number) => Promise<TorrentDetails>;
   getTorrentStats: (index: number) => Promise<TorrentStats>;
+  getPeerStats: (index: number) => Promise<PeerStatsSnapshot>;
   uploadTorrent: (
     data: string | File,
     opts?: AddTorrentOptions,
@@ -3,7 +3,8 @@ import { TorrentStats } from "../../api-types";
 import { APIContext, RefreshTorrentStatsContext } from "../../context";
 import { IconButton } from "./IconButton";
 import { DeleteTorrentModal } from "../modal/DeleteTorrentModal";
-import { FaCog, FaPause, FaPlay, FaTrash } from "react-icons/fa";
+import { PeerStatsModal } from "../modal/PeerStatsModal";
+import { FaCog, FaPause, FaPlay, FaTrash, FaUsers } from "react-icons/fa";
 import { useErrorStore } from "../../stores/errorStore";
 
 export const TorrentActions: React.FC<{
@@ -16,12 +17,14 @@ export const TorrentActions: React.FC<{
 
   let [disabled, setDisabled] = useState<boolean>(false);
   let [deleting, setDeleting] = useState<boolean>(false);
+  let [showingPeers, setShowingPeers] = useState<boolean>(false);
 
   let refreshCtx = useContext(RefreshTorrentStatsContext);
 
   const canPause = state == "live";
   const canUnpause = state == "paused" || state == "error";
   const canConfigure = state == "paused" || state == "live";
+  const canShowPeers = state == "live";
 
   const setCloseableError = useErrorStore((state) => state.setCloseableError);
 
@@ -91,10 +94,20 @@ export const TorrentActions: React.FC<{
           <FaCog className="hover:text-green-600" />
         </IconButton>
       )}
+      {canShowPeers && (
+        <IconButton onClick={() => setShowingPeers(true)} disabled={disabled}>
+          <FaUsers className="hover:text-green-600" />
+        </IconButton>
+      )}
       <IconButton onClick={startDeleting} disabled={disabled}>
         <FaTrash className="hover:text-red-500" />
       </IconButton>
       <DeleteTorrentModal id={id} show={deleting} onHide={cancelDeleting} />
+      <PeerStatsModal
+        id={id}
+        show={showingPeers}
+        onClose={() => setShowingPeers(false)}
+      />
     </div>
   );
 };
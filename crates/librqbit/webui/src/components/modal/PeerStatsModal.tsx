@@ -0,0 +1,111 @@
+import { useContext, useEffect, useState } from "react";
+import sortBy from "lodash.sortby";
+import { PeerStatsSnapshot } from "../../api-types";
+import { APIContext } from "../../context";
+import { customSetInterval } from "../../helper/customSetInterval";
+import { formatBytes } from "../../helper/formatBytes";
+import { ErrorComponent } from "../ErrorComponent";
+import { Modal } from "./Modal";
+import { ModalBody } from "./ModalBody";
+import { ModalFooter } from "./ModalFooter";
+import { Button } from "../buttons/Button";
+
+interface Props {
+  id: number;
+  show: boolean;
+  onClose: () => void;
+}
+
+export const PeerStatsModal: React.FC<Props> = ({ id, show, onClose }) => {
+  const API = useContext(APIContext);
+  const [peerStats, setPeerStats] = useState<PeerStatsSnapshot | null>(null);
+  const [error, setError] = useState<any>(null);
+
+  // Refresh every second while shown.
+  useEffect(() => {
+    if (!show) {
+      return;
+    }
+    return customSetInterval(
+      () =>
+        API.getPeerStats(id).then(
+          (stats) => {
+            setPeerStats(stats);
+            setError(null);
+            return 1000;
+          },
+          (e) => {
+            setError(e);
+            return 5000;
+          },
+        ),
+      0,
+    );
+  }, [show, id]);
+
+  const peers = sortBy(
+    Object.entries(peerStats?.peers ?? {}),
+    [([_, p]) => -p.download_bps, ([_, p]) => -p.counters.fetched_bytes],
+  );
+
+  return (
+    <Modal
+      isOpen={show}
+      onClose={onClose}
+      title={`Peers of torrent ${id}`}
+      className="max-w-5xl"
+    >
+      <ModalBody>
+        {error && (
+          <ErrorComponent
+            error={{ text: "Error fetching peer stats", details: error }}
+          />
+        )}
+        {peerStats && peers.length == 0 && <p>No connected peers.</p>}
+        {peers.length > 0 && (
+          <table className="w-full text-sm text-left">
+            <thead>
+              <tr className="border-b dark:border-slate-600">
+                <th className="p-1">Address</th>
+                <th className="p-1">Origin</th>
+                <th className="p-1">Down speed</th>
+                <th className="p-1">Up speed</th>
+                <th className="p-1">Downloaded</th>
+                <th className="p-1">Uploaded</th>
+                <th className="p-1">Pieces</th>
+                <th className="p-1">Errors</th>
+                <th className="p-1">Failed pieces</th>
+              </tr>
+            </thead>
+            <tbody>
+              {peers.map(([addr, p]) => (
+                <tr key={addr} className="border-b dark:border-slate-700">
+                  <td className="p-1 font-mono">{addr}</td>
+                  <td className="p-1">{p.origin}</td>
+                  <td className="p-1">{formatBytes(p.download_bps)}/s</td>
+                  <td className="p-1">{formatBytes(p.upload_bps)}/s</td>
+                  <td className="p-1">
+                    {formatBytes(p.counters.fetched_bytes)}
+                  </td>
+                  <td className="p-1">
+                    {formatBytes(p.counters.uploaded_bytes)}
+                  </td>
+                  <td className="p-1">
+                    {p.counters.downloaded_and_checked_pieces}
+                  </td>
+                  <td className="p-1">{p.counters.errors}</td>
+                  <td className="p-1">{p.counters.hash_failed_pieces}</td>
+                </tr>
+              ))}
+            </tbody>
+          </table>
+        )}
+      </ModalBody>
+      <ModalFooter>
+        <Button variant="primary" onClick={onClose}>
+          Close
+        </Button>
+      </ModalFooter>
+    </Modal>
+  );
+};
@@ -2,6 +2,7 @@ import {
   AddTorrentResponse,
   ErrorDetails,
   ListTorrentsResponse,
+  PeerStatsSnapshot,
   RqbitAPI,
   TorrentDetails,
   TorrentStats,
@@ -82,6 +83,9 @@ export const API: RqbitAPI & { getVersion: () => Promise<string> } = {
   getTorrentStats: (index: number): Promise<TorrentStats> => {
     return makeRequest("GET", `/torrents/${index}/stats/v1`);
   },
+  getPeerStats: (index: number): Promise<PeerStatsSnapshot> => {
+    return makeRequest("GET", `/torrents/${index}/peer_stats`);
+  },
 
   uploadTorrent: (data, opts): Promise<AddTorrentResponse> => {
     let url = "/torrents?&overwrite=true";
@@ -97,6 +101,12 @@ export const API: RqbitAPI & { getVersion: () => Promise<string> } = {
     if (opts?.peer_opts?.read_write_timeout) {
       url += `&peer_read_write_timeout=${opts.peer_opts.read_write_timeout}`;
     }
+    if (opts?.peer_opts?.idle_timeout) {
+      url += `&peer_idle_timeout=${opts.peer_opts.idle_timeout}`;
+    }
+    if (opts?.peer_opts?.inactivity_timeout) {
+      url += `&peer_inactivity_timeout=${opts.peer_opts.inactivity_timeout}`;
+    }
     if (opts?.initial_peers) {
       url += `&initial_peers=${opts.initial_peers.join(",")}`;
     }
@@ -0,0 +1,91 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use librqbit_core::compact_bitfield::CompactBitfield;
+
+const PIECES: [u32; 3] = [1_000, 100_000, 1_000_000];
+
+fn bytes_with_ratio(len: u32, every_nth_missing: Option<u32>) -> Vec<u8> {
+    let mut bytes = vec![0u8; (len as usize).div_ceil(8)];
+    for idx in 0..len {
+        let set = match every_nth_missing {
+            Some(n) => idx % n != 0,
+            None => false,
+        };
+        if set {
+            bytes[idx as usize / 8] |= 0x80 >> (idx % 8);
+        }
+    }
+    bytes
+}
+
+fn bench_from_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact_bitfield/from_bytes");
+    for len in PIECES {
+        for (name, every_nth_missing) in
+            [("empty", None), ("half", Some(2)), ("seed", Some(u32::MAX))]
+        {
+            let bytes = bytes_with_ratio(len, every_nth_missing);
+            group.bench_with_input(BenchmarkId::new(name, len), &bytes, |b, bytes| {
+                b.iter(|| CompactBitfield::from_bytes(black_box(bytes), len))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_fill_with_haves(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact_bitfield/fill_with_haves");
+    for len in PIECES {
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter(|| {
+                let mut bf = CompactBitfield::have_none(len);
+                for idx in 0..len {
+                    bf.set(black_box((idx as u64 * 7919 % len as u64) as u32), true);
+                }
+                bf
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact_bitfield/get");
+    for len in PIECES {
+        for (name, every_nth_missing) in [("seed", Some(u32::MAX)), ("half", Some(2))] {
+            let bf = CompactBitfield::from_bytes(&bytes_with_ratio(len, every_nth_missing), len);
+            group.bench_with_input(BenchmarkId::new(name, len), &bf, |b, bf| {
+                b.iter(|| {
+                    (0..len)
+                        .step_by(97)
+                        .filter(|i| bf.get(*i) == Some(true))
+                        .count()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_iter_ones(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact_bitfield/iter_ones");
+    for len in PIECES {
+        for (name, every_nth_missing) in
+            [("empty", None), ("half", Some(2)), ("seed", Some(u32::MAX))]
+        {
+            let bf = CompactBitfield::from_bytes(&bytes_with_ratio(len, every_nth_missing), len);
+            group.bench_with_input(BenchmarkId::new(name, len), &bf, |b, bf| {
+                b.iter(|| bf.iter_ones().count())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_from_bytes,
+    bench_fill_with_haves,
+    bench_get,
+    bench_iter_ones
+);
+criterion_main!(benches);
@@ -0,0 +1,456 @@
+// A bitfield of pieces that stays small when it's mostly empty or mostly full.
+//
+// Most peers are either seeds or have just started downloading, so keeping one bit
+// per piece for each of them is wasteful for torrents with hundreds of thousands of pieces.
+// Depending on how many bits are set, this stores either nothing at all (for seeds and peers
+// that have nothing), the sorted indices of set bits, the sorted indices of unset bits, or
+// falls back to a dense bitfield.
+
+// How many bytes one entry of the sparse representations takes.
+const SPARSE_ENTRY_BYTES: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Default)]
+enum Repr {
+    // No bits are set. Nothing is allocated.
+    #[default]
+    HaveNone,
+    // All bits are set. Nothing is allocated.
+    HaveAll,
+    // Sorted indices of bits that are set.
+    Sparse(Vec<u32>),
+    // Sorted indices of bits that are NOT set.
+    Inverted(Vec<u32>),
+    // One bit per piece, most significant bit first, same as in the "bitfield" message.
+    Dense {
+        bytes: Box<[u8]>,
+        ones: u32,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactBitfield {
+    len: u32,
+    repr: Repr,
+}
+
+impl CompactBitfield {
+    // A bitfield of "len" bits, all of them unset.
+    pub const fn have_none(len: u32) -> Self {
+        Self {
+            len,
+            repr: Repr::HaveNone,
+        }
+    }
+
+    // A bitfield of "len" bits, all of them set.
+    pub const fn have_all(len: u32) -> Self {
+        Self {
+            len,
+            repr: Repr::HaveAll,
+        }
+    }
+
+    // Create from the wire format (MSB first). Missing trailing bytes are treated as zeroes,
+    // padding bits past "len" are ignored.
+    //
+    // Nothing is allocated if the peer has either all or none of the pieces.
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Self {
+        let masked_byte = |idx: usize| -> u8 {
+            let byte = bytes.get(idx).copied().unwrap_or(0);
+            if idx == dense_bytes(len) - 1 && !len.is_multiple_of(8) {
+                return byte & (0xffu8 << (8 - len % 8));
+            }
+            byte
+        };
+        let ones = (0..dense_bytes(len))
+            .map(|idx| masked_byte(idx).count_ones())
+            .sum::<u32>();
+        if ones == 0 {
+            return Self::have_none(len);
+        }
+        if ones == len {
+            return Self::have_all(len);
+        }
+        let dense = (0..dense_bytes(len)).map(masked_byte).collect();
+        let mut bf = Self {
+            len,
+            repr: Repr::Dense { bytes: dense, ones },
+        };
+        bf.rebuild();
+        bf
+    }
+
+    // Serialize to the wire format (MSB first).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.repr {
+            Repr::Dense { bytes, .. } => bytes.to_vec(),
+            _ => {
+                let mut bytes = vec![0u8; dense_bytes(self.len)];
+                for idx in self.iter_ones() {
+                    bytes[idx as usize / 8] |= 0x80 >> (idx % 8);
+                }
+                bytes
+            }
+        }
+    }
+
+    pub const fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn count_ones(&self) -> u32 {
+        match &self.repr {
+            Repr::HaveNone => 0,
+            Repr::HaveAll => self.len,
+            Repr::Sparse(ones) => ones.len() as u32,
+            Repr::Inverted(zeros) => self.len - zeros.len() as u32,
+            Repr::Dense { ones, .. } => *ones,
+        }
+    }
+
+    pub fn all(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    // None if out of range.
+    pub fn get(&self, idx: u32) -> Option<bool> {
+        if idx >= self.len {
+            return None;
+        }
+        let value = match &self.repr {
+            Repr::HaveNone => false,
+            Repr::HaveAll => true,
+            Repr::Sparse(ones) => ones.binary_search(&idx).is_ok(),
+            Repr::Inverted(zeros) => zeros.binary_search(&idx).is_err(),
+            Repr::Dense { bytes, .. } => bytes[idx as usize / 8] & (0x80 >> (idx % 8)) != 0,
+        };
+        Some(value)
+    }
+
+    // None if out of range.
+    pub fn set(&mut self, idx: u32, value: bool) -> Option<()> {
+        if idx >= self.len {
+            return None;
+        }
+        match &mut self.repr {
+            Repr::HaveNone if value => self.repr = Repr::Sparse(vec![idx]),
+            Repr::HaveAll if !value => self.repr = Repr::Inverted(vec![idx]),
+            Repr::HaveNone | Repr::HaveAll => return Some(()),
+            Repr::Sparse(ones) => set_in_sorted(ones, idx, value),
+            Repr::Inverted(zeros) => set_in_sorted(zeros, idx, !value),
+            Repr::Dense { bytes, ones } => {
+                let byte = &mut bytes[idx as usize / 8];
+                let mask = 0x80 >> (idx % 8);
+                let prev = *byte & mask != 0;
+                if prev != value {
+                    if value {
+                        *byte |= mask;
+                        *ones += 1;
+                    } else {
+                        *byte &= !mask;
+                        *ones -= 1;
+                    }
+                }
+            }
+        }
+        self.maybe_rebuild();
+        Some(())
+    }
+
+    // In order. Only walks what's stored: the indices of the sparse representations, and
+    // skips zero bytes of the dense one.
+    pub fn iter_ones(&self) -> impl Iterator<Item = u32> + '_ {
+        match &self.repr {
+            Repr::HaveNone => IterOnes::Range(0..0),
+            Repr::HaveAll => IterOnes::Range(0..self.len),
+            Repr::Sparse(ones) => IterOnes::Sparse(ones.iter()),
+            Repr::Inverted(zeros) => IterOnes::Inverted {
+                range: 0..self.len,
+                zeros: zeros.iter().peekable(),
+            },
+            Repr::Dense { bytes, .. } => IterOnes::Dense {
+                bytes: bytes.iter().enumerate(),
+                base: 0,
+                current: 0,
+            },
+        }
+    }
+
+    // How much heap memory this bitfield uses.
+    pub fn heap_size(&self) -> usize {
+        match &self.repr {
+            Repr::HaveNone | Repr::HaveAll => 0,
+            Repr::Sparse(v) | Repr::Inverted(v) => v.capacity() * SPARSE_ENTRY_BYTES,
+            Repr::Dense { bytes, .. } => bytes.len(),
+        }
+    }
+
+    // Switch the representation only when we are well past the break-even point,
+    // so that flipping a bit back and forth doesn't convert every time.
+    fn maybe_rebuild(&mut self) {
+        let dense = dense_bytes(self.len);
+        let needs_rebuild = match &self.repr {
+            Repr::HaveNone | Repr::HaveAll => false,
+            Repr::Sparse(v) | Repr::Inverted(v) => {
+                v.is_empty() || v.len() * SPARSE_ENTRY_BYTES > dense
+            }
+            Repr::Dense { ones, .. } => {
+                let minority = (*ones).min(self.len - *ones) as usize;
+                minority * SPARSE_ENTRY_BYTES <= dense / 2
+            }
+        };
+        if needs_rebuild {
+            self.rebuild();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let dense = dense_bytes(self.len);
+        let ones = self.count_ones() as usize;
+        let zeros = self.len as usize - ones;
+        let repr = if ones == 0 {
+            Repr::HaveNone
+        } else if zeros == 0 {
+            Repr::HaveAll
+        } else if ones * SPARSE_ENTRY_BYTES <= dense {
+            Repr::Sparse(self.iter_ones().collect())
+        } else if zeros * SPARSE_ENTRY_BYTES <= dense {
+            Repr::Inverted(
+                (0..self.len)
+                    .filter(|idx| self.get(*idx) == Some(false))
+                    .collect(),
+            )
+        } else if matches!(self.repr, Repr::Dense { .. }) {
+            return;
+        } else {
+            Repr::Dense {
+                bytes: self.to_bytes().into_boxed_slice(),
+                ones: ones as u32,
+            }
+        };
+        self.repr = repr;
+    }
+}
+
+enum IterOnes<'a> {
+    Range(std::ops::Range<u32>),
+    Sparse(std::slice::Iter<'a, u32>),
+    Inverted {
+        range: std::ops::Range<u32>,
+        zeros: std::iter::Peekable<std::slice::Iter<'a, u32>>,
+    },
+    Dense {
+        bytes: std::iter::Enumerate<std::slice::Iter<'a, u8>>,
+        // The index of the most significant bit of "current".
+        base: u32,
+        // What's left to yield of the current byte.
+        current: u8,
+    },
+}
+
+impl Iterator for IterOnes<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match self {
+            IterOnes::Range(range) => range.next(),
+            IterOnes::Sparse(ones) => ones.next().copied(),
+            IterOnes::Inverted { range, zeros } => loop {
+                let idx = range.next()?;
+                if zeros.next_if_eq(&&idx).is_none() {
+                    return Some(idx);
+                }
+            },
+            IterOnes::Dense {
+                bytes,
+                base,
+                current,
+            } => {
+                while *current == 0 {
+                    let (idx, byte) = bytes.find(|(_, b)| **b != 0)?;
+                    *base = idx as u32 * 8;
+                    *current = *byte;
+                }
+                let bit = current.leading_zeros();
+                *current &= !(0x80 >> bit);
+                Some(*base + bit)
+            }
+        }
+    }
+}
+
+fn dense_bytes(len: u32) -> usize {
+    (len as usize).div_ceil(8)
+}
+
+fn set_in_sorted(v: &mut Vec<u32>, idx: u32, present: bool) {
+    match (v.binary_search(&idx), present) {
+        (Ok(_), true) | (Err(_), false) => {}
+        (Err(pos), true) => v.insert(pos, idx),
+        (Ok(pos), false) => {
+            v.remove(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A trivial model to compare against.
+    fn check_same(bf: &CompactBitfield, model: &[bool]) {
+        assert_eq!(bf.len() as usize, model.len());
+        for (idx, expected) in model.iter().enumerate() {
+            assert_eq!(bf.get(idx as u32), Some(*expected), "idx={idx}");
+        }
+        assert_eq!(bf.get(model.len() as u32), None);
+        assert_eq!(
+            bf.count_ones() as usize,
+            model.iter().filter(|v| **v).count()
+        );
+        assert_eq!(bf.all(), model.iter().all(|v| *v));
+        assert_eq!(
+            bf.iter_ones().collect::<Vec<_>>(),
+            model
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, v)| if *v { Some(idx as u32) } else { None })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            CompactBitfield::from_bytes(&bf.to_bytes(), bf.len()).to_bytes(),
+            bf.to_bytes(),
+            "roundtrip through bytes"
+        );
+    }
+
+    #[test]
+    fn test_iter_ones_skips_zero_bytes() {
+        let len = 1_000_000;
+        let mut bytes = vec![0u8; dense_bytes(len)];
+        for idx in (0..len).step_by(3) {
+            bytes[idx as usize / 8] |= 0x80 >> (idx % 8);
+        }
+        // Zero out a stretch in the middle, so that it stays dense but has empty bytes.
+        bytes[1000..100_000].fill(0);
+        let bf = CompactBitfield::from_bytes(&bytes, len);
+        assert!(matches!(bf.repr, Repr::Dense { .. }));
+        assert_eq!(
+            bf.iter_ones().collect::<Vec<_>>(),
+            (0..len)
+                .step_by(3)
+                .filter(|idx| !(8000..800_000).contains(idx))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_default_is_empty() {
+        let bf = CompactBitfield::default();
+        assert!(bf.is_empty());
+        assert_eq!(bf.get(0), None);
+        assert_eq!(bf.heap_size(), 0);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let bf = CompactBitfield::from_bytes(&[0b1010_0000, 0b1111_1111], 11);
+        let mut model = vec![false; 11];
+        model[0] = true;
+        model[2] = true;
+        model[8] = true;
+        model[9] = true;
+        model[10] = true;
+        check_same(&bf, &model);
+
+        // Padding is ignored, missing bytes are zeroes.
+        let bf = CompactBitfield::from_bytes(&[0xff], 20);
+        let mut model = vec![false; 20];
+        model[..8].fill(true);
+        check_same(&bf, &model);
+    }
+
+    #[test]
+    fn test_fill_and_drain() {
+        let len = 10_000;
+        let mut bf = CompactBitfield::have_none(len);
+        let mut model = vec![false; len as usize];
+        assert_eq!(bf.set(len, true), None);
+
+        // A deterministic but scrambled order going through all the representations.
+        let order = (0..len).map(|i| (i * 7919) % len).collect::<Vec<_>>();
+        for (step, idx) in order.iter().copied().enumerate() {
+            bf.set(idx, true).unwrap();
+            model[idx as usize] = true;
+            if step % 500 == 0 {
+                check_same(&bf, &model);
+            }
+        }
+        check_same(&bf, &model);
+        assert!(matches!(bf.repr, Repr::HaveAll));
+
+        for (step, idx) in order.iter().copied().enumerate() {
+            bf.set(idx, false).unwrap();
+            model[idx as usize] = false;
+            if step % 500 == 0 {
+                check_same(&bf, &model);
+            }
+        }
+        check_same(&bf, &model);
+        assert!(matches!(bf.repr, Repr::HaveNone));
+    }
+
+    #[test]
+    fn test_compact_for_seeds_and_leechers() {
+        let len = 500_000;
+        let dense = dense_bytes(len);
+
+        let seed = CompactBitfield::from_bytes(&vec![0xff; dense], len);
+        assert!(seed.all());
+        assert_eq!(seed.heap_size(), 0);
+
+        let leecher = CompactBitfield::from_bytes(&vec![0; dense], len);
+        assert_eq!(leecher.count_ones(), 0);
+        assert_eq!(leecher.heap_size(), 0);
+
+        let mut almost_seed = CompactBitfield::from_bytes(&vec![0xff; dense], len);
+        almost_seed.set(42, false).unwrap();
+        assert_eq!(almost_seed.count_ones(), len - 1);
+        assert!(almost_seed.heap_size() < dense / 100);
+
+        let half = CompactBitfield::from_bytes(&vec![0b1010_1010; dense], len);
+        assert_eq!(half.count_ones(), len / 2);
+        assert_eq!(half.heap_size(), dense);
+    }
+
+    #[test]
+    fn test_sentinels() {
+        let len = 1000;
+
+        let mut none = CompactBitfield::have_none(len);
+        check_same(&none, &vec![false; len as usize]);
+        none.set(5, false).unwrap();
+        assert!(matches!(none.repr, Repr::HaveNone));
+        none.set(5, true).unwrap();
+        none.set(5, false).unwrap();
+        assert!(matches!(none.repr, Repr::HaveNone));
+
+        let mut all = CompactBitfield::have_all(len);
+        check_same(&all, &vec![true; len as usize]);
+        all.set(5, true).unwrap();
+        assert!(matches!(all.repr, Repr::HaveAll));
+        all.set(5, false).unwrap();
+        all.set(5, true).unwrap();
+        assert!(matches!(all.repr, Repr::HaveAll));
+
+        // Padding bits don't prevent detecting a seed.
+        let seed = CompactBitfield::from_bytes(&[0xff, 0b1110_0000], 11);
+        assert!(matches!(seed.repr, Repr::HaveAll));
+        let seed = CompactBitfield::from_bytes(&[0xff, 0xff], 11);
+        assert!(matches!(seed.repr, Repr::HaveAll));
+    }
+}
@@ -1 +1,5 @@
 pub const CHUNK_SIZE: u32 = 16384;
+
+// The largest block we serve to peers in one message. Most clients request CHUNK_SIZE blocks, but
+// some request larger ones.
+pub const MAX_BLOCK_SIZE: u32 = 256 * 1024;
@@ -74,20 +74,50 @@ impl Lengths {
         torrent: &TorrentMetaV1Info<ByteBuf>,
     ) -> anyhow::Result<Lengths> {
         let total_length = torrent.iter_file_lengths()?.sum();
-        Lengths::new(total_length, torrent.piece_length)
+        let lengths = Lengths::new(total_length, torrent.piece_length)?;
+        let expected_hashes_len = lengths.total_pieces() as usize * 20;
+        // v2-only torrents don't have SHA-1 piece hashes.
+        if !torrent.is_v2_only() && torrent.pieces.as_ref().len() != expected_hashes_len {
+            anyhow::bail!(
+                "expected {} bytes of piece hashes ({} pieces), got {}",
+                expected_hashes_len,
+                lengths.total_pieces(),
+                torrent.pieces.as_ref().len()
+            );
+        }
+        Ok(lengths)
     }
 
+    // Piece length doesn't have to be a power of two, or even a multiple of CHUNK_SIZE.
+    // The only requirement is that all the pieces and chunks are addressable with u32.
     pub fn new(total_length: u64, piece_length: u32) -> anyhow::Result<Self> {
         if total_length == 0 {
             anyhow::bail!("torrent with 0 length is useless")
         }
-        let total_pieces = total_length.div_ceil(piece_length as u64) as u32;
+        if piece_length == 0 {
+            anyhow::bail!("piece length can't be 0")
+        }
+        let total_pieces: u32 = total_length
+            .div_ceil(piece_length as u64)
+            .try_into()
+            .with_context(|| {
+                format!("too many pieces: total_length={total_length}, piece_length={piece_length}")
+            })?;
+        let chunks_per_piece = piece_length.div_ceil(CHUNK_SIZE);
+        let last_piece_length = last_element_size(total_length, piece_length as u64) as u32;
+        let total_chunks = (total_pieces as u64 - 1) * chunks_per_piece as u64
+            + last_piece_length.div_ceil(CHUNK_SIZE) as u64;
+        if total_chunks > u32::MAX as u64 {
+            anyhow::bail!(
+                "too many chunks: total_length={total_length}, piece_length={piece_length}"
+            );
+        }
         Ok(Self {
             piece_length,
             total_length,
-            chunks_per_piece: (piece_length as u64).div_ceil(CHUNK_SIZE as u64) as u32,
+            chunks_per_piece,
             last_piece_id: total_pieces - 1,
-            last_piece_length: last_element_size(total_length, piece_length as u64) as u32,
+            last_piece_length,
         })
     }
 
@@ -120,7 +150,6 @@ impl Lengths {
         self.chunks_per_piece
     }
     pub const fn total_chunks(&self) -> u32 {
-        // TODO: test
         self.last_piece_id * self.default_chunks_per_piece()
             + self.chunks_per_piece(self.last_piece_id())
     }
@@ -217,6 +246,34 @@ impl Lengths {
             absolute_index,
         })
     }
+
+    // Like chunk_info_from_received_data, but for a block a peer requests from us, which can span
+    // several chunks: up to max_block_size bytes, starting at a chunk boundary. The result
+    // describes the first chunk, with the size of the whole block.
+    pub fn chunk_info_from_request(
+        &self,
+        piece_index: ValidPieceIndex,
+        begin: u32,
+        size: u32,
+        max_block_size: u32,
+    ) -> Option<ChunkInfo> {
+        if size == 0 || size > max_block_size.max(CHUNK_SIZE) {
+            return None;
+        }
+        let index = begin / CHUNK_SIZE;
+        let offset = self.chunk_offset_in_piece(piece_index, index)?;
+        if offset != begin || begin.checked_add(size)? > self.piece_length(piece_index) {
+            return None;
+        }
+        Some(ChunkInfo {
+            piece_index,
+            chunk_index: index,
+            size,
+            offset,
+            absolute_index: self.chunks_per_piece * piece_index.get() + index,
+        })
+    }
+
     pub const fn chunk_range(&self, index: ValidPieceIndex) -> std::ops::Range<usize> {
         let start = index.0 * self.chunks_per_piece;
         let end = start + self.chunks_per_piece(index);
@@ -252,8 +309,12 @@ impl Lengths {
 
     // How many bytes out of the given piece are present in the given file (by offset and len).
     pub fn size_of_piece_in_file(&self, piece_id: u32, file_offset: u64, file_len: u64) -> u64 {
-        let piece_offset = piece_id as u64 * self.default_piece_length() as u64;
-        let piece_end = piece_offset + self.default_piece_length() as u64;
+        let piece = match self.validate_piece_index(piece_id) {
+            Some(p) => p,
+            None => return 0,
+        };
+        let piece_offset = self.piece_offset(piece);
+        let piece_end = piece_offset + self.piece_length(piece) as u64;
 
         let file_end = file_offset + file_len;
 
@@ -267,6 +328,7 @@ impl Lengths {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::constants::MAX_BLOCK_SIZE;
 
     fn make_lengths() -> Lengths {
         Lengths::new(1174243328, 262144).unwrap()
@@ -634,4 +696,181 @@ mod tests {
         assert_eq!(l.size_of_piece_in_file(0, 10, 0), 0);
         assert_eq!(l.size_of_piece_in_file(0, 10, 5), 0);
     }
+
+    #[test]
+    fn test_invalid_lengths() {
+        assert!(Lengths::new(0, 16384).is_err());
+        assert!(Lengths::new(16384, 0).is_err());
+        assert!(Lengths::new(u32::MAX as u64 + 1, 1).is_err());
+        assert!(Lengths::new(u32::MAX as u64, 1).is_ok());
+        // Every piece is 2 chunks, so there are more chunks than u32 can address.
+        assert!(Lengths::new(
+            (CHUNK_SIZE as u64 + 1) * (u32::MAX as u64 / 2 + 1),
+            CHUNK_SIZE + 1
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_arbitrary_piece_lengths_exhaustive() {
+        let piece_lengths = [
+            1,
+            2,
+            3,
+            7,
+            1000,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            CHUNK_SIZE * 2 - 1,
+            CHUNK_SIZE * 3 + 7,
+            100_000,
+            262_144 - 1,
+            262_144 + 3,
+            1_000_000,
+        ];
+        let total_lengths = [1u64, 2, 3, 999, 16384, 16385, 65537, 1_000_000, 3_333_333];
+
+        for piece_length in piece_lengths {
+            for total_length in total_lengths {
+                if total_length / piece_length as u64 > 100_000 {
+                    // Keep the test fast.
+                    continue;
+                }
+                check_lengths_invariants(Lengths::new(total_length, piece_length).unwrap());
+            }
+        }
+    }
+
+    fn check_lengths_invariants(l: Lengths) {
+        let ctx = format!(
+            "total_length={}, piece_length={}",
+            l.total_length(),
+            l.default_piece_length()
+        );
+
+        let pieces = l.iter_piece_infos().collect::<Vec<_>>();
+        assert_eq!(pieces.len() as u32, l.total_pieces(), "{ctx}");
+        assert_eq!(
+            pieces.iter().map(|p| p.len as u64).sum::<u64>(),
+            l.total_length(),
+            "{ctx}"
+        );
+        assert!(pieces.iter().all(|p| p.len > 0), "{ctx}");
+
+        let mut expected_absolute_index = 0u32;
+        let mut expected_piece_offset = 0u64;
+        for piece in pieces {
+            let idx = piece.piece_index;
+            assert_eq!(l.piece_length(idx), piece.len, "{ctx}");
+            assert_eq!(l.piece_offset(idx), expected_piece_offset, "{ctx}");
+            assert!(piece.len <= l.default_piece_length(), "{ctx}");
+
+            let range = l.chunk_range(idx);
+            assert_eq!(range.start, expected_absolute_index as usize, "{ctx}");
+            assert_eq!(range.len() as u32, l.chunks_per_piece(idx), "{ctx}");
+
+            let mut piece_bytes = 0u32;
+            for chunk in l.iter_chunk_infos(idx) {
+                assert_eq!(chunk.absolute_index, expected_absolute_index, "{ctx}");
+                assert_eq!(chunk.offset, piece_bytes, "{ctx}");
+                assert_eq!(
+                    l.chunk_size(idx, chunk.chunk_index),
+                    Some(chunk.size),
+                    "{ctx}"
+                );
+                assert_eq!(
+                    l.chunk_offset_in_piece(idx, chunk.chunk_index),
+                    Some(chunk.offset),
+                    "{ctx}"
+                );
+                assert_eq!(
+                    l.chunk_absolute_offset(&chunk),
+                    expected_piece_offset + chunk.offset as u64,
+                    "{ctx}"
+                );
+                assert_eq!(
+                    l.chunk_info_from_received_data(idx, chunk.offset, chunk.size),
+                    Some(chunk),
+                    "{ctx}"
+                );
+                // Wrong sizes and unaligned offsets must be rejected.
+                assert_eq!(
+                    l.chunk_info_from_received_data(idx, chunk.offset, chunk.size + 1),
+                    None,
+                    "{ctx}"
+                );
+                if chunk.size > 1 {
+                    assert_eq!(
+                        l.chunk_info_from_received_data(idx, chunk.offset + 1, chunk.size - 1),
+                        None,
+                        "{ctx}"
+                    );
+                }
+                assert_eq!(
+                    l.chunk_info_from_request(idx, chunk.offset, chunk.size, CHUNK_SIZE),
+                    Some(chunk),
+                    "{ctx}"
+                );
+                assert_eq!(
+                    l.chunk_info_from_request(idx, chunk.offset, piece.len + 1, u32::MAX),
+                    None,
+                    "{ctx}"
+                );
+                piece_bytes += chunk.size;
+                expected_absolute_index += 1;
+            }
+            assert_eq!(piece_bytes, piece.len, "{ctx}");
+            assert_eq!(l.chunk_size(idx, l.chunks_per_piece(idx)), None, "{ctx}");
+            // The whole piece as one block, if it's allowed to be that large.
+            let block = l.chunk_info_from_request(idx, 0, piece.len, MAX_BLOCK_SIZE);
+            assert_eq!(block.is_some(), piece.len <= MAX_BLOCK_SIZE, "{ctx}");
+            if let Some(block) = block {
+                assert_eq!(block.size, piece.len, "{ctx}");
+                assert_eq!(
+                    l.chunk_absolute_offset(&block),
+                    expected_piece_offset,
+                    "{ctx}"
+                );
+            }
+            assert_eq!(
+                l.chunk_info_from_request(idx, 0, 0, MAX_BLOCK_SIZE),
+                None,
+                "{ctx}"
+            );
+            if piece.len > 1 {
+                assert_eq!(
+                    l.chunk_info_from_request(idx, 1, 1, MAX_BLOCK_SIZE),
+                    None,
+                    "{ctx}"
+                );
+            }
+            assert_eq!(
+                l.chunk_info_from_received_data(idx, piece.len, 1),
+                None,
+                "{ctx}"
+            );
+
+            // The piece is fully contained in a "file" spanning the whole torrent.
+            assert_eq!(
+                l.size_of_piece_in_file(idx.get(), 0, l.total_length()),
+                piece.len as u64,
+                "{ctx}"
+            );
+            assert_eq!(
+                l.iter_pieces_within_offset(expected_piece_offset, piece.len as u64),
+                idx.get()..idx.get() + 1,
+                "{ctx}"
+            );
+
+            expected_piece_offset += piece.len as u64;
+        }
+        assert_eq!(expected_absolute_index, l.total_chunks(), "{ctx}");
+        assert_eq!(
+            l.chunk_bitfield_bytes(),
+            l.total_chunks().div_ceil(8) as usize,
+            "{ctx}"
+        );
+        assert_eq!(l.validate_piece_index(l.total_pieces()), None, "{ctx}");
+    }
 }
@@ -1,9 +1,12 @@
+pub mod compact_bitfield;
 pub mod constants;
 pub mod directories;
 pub mod hash_id;
 pub mod lengths;
 pub mod magnet;
+pub mod merkle;
 pub mod peer_id;
+pub mod socket_binding;
 pub mod spawn_utils;
 pub mod speed_estimator;
 pub mod torrent_metainfo;
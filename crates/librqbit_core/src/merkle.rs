@@ -0,0 +1,231 @@
+// BitTorrent v2 (BEP 52) merkle trees.
+//
+// Each file is hashed separately. The leaves are SHA-256 hashes of 16KiB blocks, and the
+// tree is padded to a power of two with zero hashes. The "piece layer" is the layer of the
+// tree where each node covers one piece.
+
+use anyhow::{bail, Context};
+use sha1w::{ISha256, Sha256};
+
+use crate::hash_id::Id32;
+
+pub const MERKLE_BLOCK_SIZE: u32 = 16384;
+
+fn hash_pair(left: &Id32, right: &Id32) -> Id32 {
+    let mut h = Sha256::new();
+    h.update(&left.0);
+    h.update(&right.0);
+    Id32::new(h.finish())
+}
+
+// The root of a tree of 2^height zero leaves.
+fn pad_hash(height: u32) -> Id32 {
+    let mut h = Id32::default();
+    for _ in 0..height {
+        h = hash_pair(&h, &h);
+    }
+    h
+}
+
+// Compute the root from one layer of the tree. Nodes at this layer are "height" levels
+// above the leaves, and the layer is padded to "width" nodes.
+fn root_from_layer(layer: &[Id32], height: u32, width: usize) -> Id32 {
+    debug_assert!(width.is_power_of_two() && layer.len() <= width);
+    let mut pad = pad_hash(height);
+    let mut layer = layer.to_vec();
+    let mut width = width;
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Computes the merkle hash of a piece (or of a whole file smaller than a piece).
+#[derive(Default)]
+pub struct PieceHasherV2 {
+    buf: Vec<u8>,
+    leaves: Vec<Id32>,
+}
+
+impl PieceHasherV2 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        let block_size = MERKLE_BLOCK_SIZE as usize;
+        while !data.is_empty() {
+            let take = (block_size - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buf.len() == block_size {
+                self.flush_block();
+            }
+        }
+    }
+
+    fn flush_block(&mut self) {
+        let mut h = Sha256::new();
+        h.update(&self.buf);
+        self.leaves.push(Id32::new(h.finish()));
+        self.buf.clear();
+    }
+
+    // "leaves" is how many leaves the piece's subtree has.
+    pub fn finish(mut self, leaves: u32) -> Id32 {
+        if !self.buf.is_empty() {
+            self.flush_block();
+        }
+        root_from_layer(&self.leaves, 0, leaves as usize)
+    }
+}
+
+/// The expected hash of one piece of a v2 torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceHashV2 {
+    pub hash: Id32,
+    // The number of leaves in the piece's subtree, to pad to.
+    pub leaves: u32,
+}
+
+/// Validate a file's piece layer against its "pieces root", and return expected hashes for
+/// each of the file's pieces. Files not larger than one piece don't have a piece layer.
+pub fn file_piece_hashes(
+    file_length: u64,
+    piece_length: u32,
+    pieces_root: Id32,
+    piece_layer: Option<&[u8]>,
+) -> anyhow::Result<Vec<PieceHashV2>> {
+    if piece_length < MERKLE_BLOCK_SIZE || !piece_length.is_power_of_two() {
+        bail!("piece length {piece_length} is not a power of two >= {MERKLE_BLOCK_SIZE}");
+    }
+    if file_length == 0 {
+        return Ok(Vec::new());
+    }
+    if file_length <= piece_length as u64 {
+        let blocks = file_length.div_ceil(MERKLE_BLOCK_SIZE as u64) as u32;
+        return Ok(vec![PieceHashV2 {
+            hash: pieces_root,
+            leaves: blocks.next_power_of_two(),
+        }]);
+    }
+
+    let layer = piece_layer.context("missing piece layer")?;
+    let pieces = file_length.div_ceil(piece_length as u64) as usize;
+    if layer.len() != pieces * 32 {
+        bail!(
+            "piece layer has {} bytes, expected {} for {pieces} pieces",
+            layer.len(),
+            pieces * 32
+        );
+    }
+    let hashes = layer
+        .chunks_exact(32)
+        .map(|c| Id32::new(c.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let leaves_per_piece = piece_length / MERKLE_BLOCK_SIZE;
+    let root = root_from_layer(
+        &hashes,
+        leaves_per_piece.trailing_zeros(),
+        pieces.next_power_of_two(),
+    );
+    if root != pieces_root {
+        bail!("piece layer doesn't match pieces root {pieces_root:?}");
+    }
+    Ok(hashes
+        .into_iter()
+        .map(|hash| PieceHashV2 {
+            hash,
+            leaves: leaves_per_piece,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(data: &[u8]) -> Id32 {
+        let mut h = Sha256::new();
+        h.update(data);
+        Id32::new(h.finish())
+    }
+
+    #[test]
+    fn test_small_file_root() {
+        // 2.5 blocks: 3 leaves, padded to 4.
+        let data = vec![1u8; MERKLE_BLOCK_SIZE as usize * 5 / 2];
+        let b = MERKLE_BLOCK_SIZE as usize;
+        let expected = hash_pair(
+            &hash_pair(&leaf(&data[..b]), &leaf(&data[b..2 * b])),
+            &hash_pair(&leaf(&data[2 * b..]), &Id32::default()),
+        );
+
+        let mut h = PieceHasherV2::new();
+        // Feed in uneven parts to exercise buffering.
+        for part in data.chunks(1000) {
+            h.update(part);
+        }
+        assert_eq!(h.finish(4), expected);
+
+        let hashes = file_piece_hashes(data.len() as u64, 65536, expected, None).unwrap();
+        assert_eq!(
+            hashes,
+            vec![PieceHashV2 {
+                hash: expected,
+                leaves: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn test_piece_layer_validation() {
+        let piece_length = MERKLE_BLOCK_SIZE * 2;
+        // 3 pieces, the last one is a single short block.
+        let data = (0..piece_length as usize * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let piece_hashes = data
+            .chunks(piece_length as usize)
+            .map(|piece| {
+                let mut h = PieceHasherV2::new();
+                h.update(piece);
+                h.finish(2)
+            })
+            .collect::<Vec<_>>();
+
+        // Pieces padded to 4 with the root of a 2-leaf zero subtree.
+        let pad = hash_pair(&Id32::default(), &Id32::default());
+        let root = hash_pair(
+            &hash_pair(&piece_hashes[0], &piece_hashes[1]),
+            &hash_pair(&piece_hashes[2], &pad),
+        );
+        let layer = piece_hashes.iter().flat_map(|h| h.0).collect::<Vec<_>>();
+
+        let hashes =
+            file_piece_hashes(data.len() as u64, piece_length, root, Some(&layer)).unwrap();
+        assert_eq!(
+            hashes.iter().map(|h| h.hash).collect::<Vec<_>>(),
+            piece_hashes
+        );
+        assert!(hashes.iter().all(|h| h.leaves == 2));
+
+        // Corrupted layer.
+        let mut bad = layer.clone();
+        bad[0] ^= 1;
+        assert!(file_piece_hashes(data.len() as u64, piece_length, root, Some(&bad)).is_err());
+        // Wrong length and missing layer.
+        assert!(
+            file_piece_hashes(data.len() as u64, piece_length, root, Some(&layer[32..])).is_err()
+        );
+        assert!(file_piece_hashes(data.len() as u64, piece_length, root, None).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The local address and interface to bind outgoing sockets to, for multi-homed hosts or to
+/// force traffic through a VPN.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketBinding {
+    /// Local IP address to bind to.
+    pub local_addr: Option<IpAddr>,
+    /// Network interface name to bind to (SO_BINDTODEVICE), e.g. "wg0". Linux only.
+    pub interface: Option<String>,
+}
+
+impl SocketBinding {
+    pub fn is_empty(&self) -> bool {
+        self.local_addr.is_none() && self.interface.is_none()
+    }
+
+    /// The address to bind to, the local address replacing the IP of `addr` if set.
+    pub fn bind_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match self.local_addr {
+            Some(ip) => SocketAddr::new(ip, addr.port()),
+            None => addr,
+        }
+    }
+
+    /// Bind a UDP socket to `addr`, or to the local address with the port of `addr`.
+    pub async fn bind_udp(&self, addr: SocketAddr) -> anyhow::Result<tokio::net::UdpSocket> {
+        let addr = self.bind_addr(addr);
+        let sock = tokio::net::UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("error binding UDP socket to {addr}"))?;
+        if let Some(interface) = &self.interface {
+            bind_device(&sock, interface)?;
+        }
+        Ok(sock)
+    }
+
+    /// Bind an IPv6-only UDP socket on `port`, so it can run alongside an IPv4 socket bound
+    /// to the same port.
+    pub fn bind_udp_v6_only(&self, port: u16) -> anyhow::Result<tokio::net::UdpSocket> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let addr = self.bind_addr(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)));
+        let sock = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+            .context("error creating UDP socket")?;
+        sock.set_only_v6(true)
+            .context("error setting IPV6_V6ONLY")?;
+        sock.set_nonblocking(true)
+            .context("error setting socket to non-blocking")?;
+        sock.bind(&addr.into())
+            .with_context(|| format!("error binding UDP socket to {addr}"))?;
+        let sock =
+            tokio::net::UdpSocket::from_std(sock.into()).context("error registering UDP socket")?;
+        if let Some(interface) = &self.interface {
+            bind_device(&sock, interface)?;
+        }
+        Ok(sock)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(sock: &tokio::net::UdpSocket, interface: &str) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the fd is valid for the lifetime of the socket, and the option value is a
+    // buffer of the given length.
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("error binding socket to interface {interface:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_sock: &tokio::net::UdpSocket, _interface: &str) -> anyhow::Result<()> {
+    anyhow::bail!("binding sockets to an interface is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_addr() {
+        let addr: SocketAddr = "0.0.0.0:6881".parse().unwrap();
+        assert_eq!(SocketBinding::default().bind_addr(addr), addr);
+
+        let binding = SocketBinding {
+            local_addr: Some("192.168.1.10".parse().unwrap()),
+            interface: None,
+        };
+        assert_eq!(
+            binding.bind_addr(addr),
+            "192.168.1.10:6881".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_local_addr() {
+        let binding = SocketBinding {
+            local_addr: Some("127.0.0.1".parse().unwrap()),
+            interface: None,
+        };
+        let sock = binding
+            .bind_udp("0.0.0.0:0".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            sock.local_addr().unwrap().ip(),
+            "127.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -6,17 +6,33 @@ use std::{
 
 use parking_lot::Mutex;
 
+// Weight of the newest sample in the exponentially smoothed speed. Snapshots are usually
+// taken once a second, so older samples fade out within about 10 seconds.
+const SMOOTHING_ALPHA: f64 = 0.2;
+
 #[derive(Clone, Copy)]
 struct ProgressSnapshot {
     progress_bytes: u64,
     instant: Instant,
 }
 
-/// Estimates download/upload speed in a sliding time window.
+/// Estimates download/upload speed in a sliding time window. Also keeps an exponentially
+/// smoothed speed, which reacts to changes slower, but doesn't jump around as much.
 pub struct SpeedEstimator {
     latest_per_second_snapshots: Mutex<VecDeque<ProgressSnapshot>>,
     bytes_per_second: AtomicU64,
     time_remaining_millis: AtomicU64,
+    // f64 bits.
+    smoothed_bytes_per_second: AtomicU64,
+    smoothed_time_remaining_millis: AtomicU64,
+}
+
+impl std::fmt::Debug for SpeedEstimator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpeedEstimator")
+            .field("bps", &self.bps())
+            .finish()
+    }
 }
 
 impl SpeedEstimator {
@@ -26,6 +42,8 @@ impl SpeedEstimator {
             latest_per_second_snapshots: Mutex::new(VecDeque::with_capacity(window_seconds)),
             bytes_per_second: Default::default(),
             time_remaining_millis: Default::default(),
+            smoothed_bytes_per_second: Default::default(),
+            smoothed_time_remaining_millis: Default::default(),
         }
     }
 
@@ -45,6 +63,48 @@ impl SpeedEstimator {
         self.bps() as f64 / 1024f64 / 1024f64
     }
 
+    pub fn smoothed_bps(&self) -> u64 {
+        f64::from_bits(self.smoothed_bytes_per_second.load(Ordering::Relaxed)) as u64
+    }
+
+    /// Time to download the remaining bytes at the smoothed speed.
+    pub fn smoothed_time_remaining(&self) -> Option<Duration> {
+        let tr = self.smoothed_time_remaining_millis.load(Ordering::Relaxed);
+        if tr == 0 {
+            return None;
+        }
+        Some(Duration::from_millis(tr))
+    }
+
+    fn update_smoothed(
+        &self,
+        previous: ProgressSnapshot,
+        current: ProgressSnapshot,
+        remaining_bytes: Option<u64>,
+    ) {
+        let elapsed = current.instant - previous.instant;
+        if elapsed.is_zero() {
+            return;
+        }
+        let sample = current
+            .progress_bytes
+            .saturating_sub(previous.progress_bytes) as f64
+            / elapsed.as_secs_f64();
+        let old = f64::from_bits(self.smoothed_bytes_per_second.load(Ordering::Relaxed));
+        let smoothed = SMOOTHING_ALPHA * sample + (1. - SMOOTHING_ALPHA) * old;
+        self.smoothed_bytes_per_second
+            .store(smoothed.to_bits(), Ordering::Relaxed);
+
+        let remaining = remaining_bytes.unwrap_or_default();
+        let time_remaining_millis = if remaining > 0 && smoothed >= 1. {
+            (remaining as f64 / smoothed * 1000f64) as u64
+        } else {
+            0
+        };
+        self.smoothed_time_remaining_millis
+            .store(time_remaining_millis, Ordering::Relaxed);
+    }
+
     pub fn add_snapshot(
         &self,
         progress_bytes: u64,
@@ -59,6 +119,10 @@ impl SpeedEstimator {
                 instant,
             };
 
+            if let Some(previous) = g.back().copied() {
+                self.update_smoothed(previous, current, remaining_bytes);
+            }
+
             if g.is_empty() {
                 g.push_back(current);
                 return;
@@ -87,3 +151,33 @@ impl SpeedEstimator {
         self.bytes_per_second.store(bps as u64, Ordering::Relaxed);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SpeedEstimator;
+
+    #[test]
+    fn test_smoothed_speed() {
+        let e = SpeedEstimator::new(5);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        e.add_snapshot(0, Some(10_000), at(0));
+        assert_eq!(e.smoothed_bps(), 0);
+        e.add_snapshot(1000, Some(9000), at(1));
+        assert_eq!(e.smoothed_bps(), 200);
+        assert_eq!(e.smoothed_time_remaining(), Some(Duration::from_secs(45)));
+
+        // A steady speed is approached gradually.
+        for secs in 2..50 {
+            e.add_snapshot(secs * 1000, Some(1000), at(secs));
+        }
+        assert!((990..=1000).contains(&e.smoothed_bps()));
+
+        // Nothing remaining, no ETA.
+        e.add_snapshot(50_000, Some(0), at(50));
+        assert_eq!(e.smoothed_time_remaining(), None);
+    }
+}
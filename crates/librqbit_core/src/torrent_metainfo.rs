@@ -1,4 +1,4 @@
-use std::{iter::once, path::PathBuf};
+use std::{iter::once, marker::PhantomData, path::PathBuf};
 
 use anyhow::Context;
 use bencode::BencodeDeserializer;
@@ -7,15 +7,20 @@ use clone_to_owned::CloneToOwned;
 use itertools::Either;
 use serde::{Deserialize, Serialize};
 
-use crate::{hash_id::Id20, lengths::Lengths};
+use crate::{
+    hash_id::{Id20, Id32},
+    lengths::Lengths,
+    merkle::{file_piece_hashes, PieceHashV2},
+};
 
 pub type TorrentMetaV1Borrowed<'a> = TorrentMetaV1<ByteBuf<'a>>;
 pub type TorrentMetaV1Owned = TorrentMetaV1<ByteBufOwned>;
 
 /// Parse torrent metainfo from bytes.
-pub fn torrent_from_bytes<'de, BufType: Deserialize<'de>>(
-    buf: &'de [u8],
-) -> anyhow::Result<TorrentMetaV1<BufType>> {
+pub fn torrent_from_bytes<'de, BufType>(buf: &'de [u8]) -> anyhow::Result<TorrentMetaV1<BufType>>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]> + Clone + Default,
+{
     let mut de = BencodeDeserializer::new_from_buf(buf);
     de.is_torrent_info = true;
     let mut t = TorrentMetaV1::deserialize(&mut de)?;
@@ -23,6 +28,19 @@ pub fn torrent_from_bytes<'de, BufType: Deserialize<'de>>(
         de.torrent_info_digest
             .ok_or_else(|| anyhow::anyhow!("programming error"))?,
     );
+
+    if t.info.file_tree.is_some() {
+        let digest = de
+            .torrent_info_digest_sha256
+            .ok_or_else(|| anyhow::anyhow!("programming error"))?;
+        t.info_hash_v2 = Some(Id32::new(digest));
+
+        if t.info.is_v2_only() {
+            t.info.fill_v1_files_from_file_tree()?;
+            // v2-only torrents use the truncated v2 info hash in the v1 protocol (BEP 52).
+            t.info_hash = Id20::new(digest[..20].try_into().unwrap());
+        }
+    }
     Ok(t)
 }
 
@@ -37,6 +55,7 @@ pub struct TorrentMetaV1<BufType> {
         skip_serializing_if = "Vec::is_empty"
     )]
     pub announce_list: Vec<Vec<BufType>>,
+    #[serde(bound(deserialize = "BufType: Deserialize<'de> + AsRef<[u8]> + Default"))]
     pub info: TorrentMetaV1Info<BufType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<BufType>,
@@ -50,9 +69,62 @@ pub struct TorrentMetaV1<BufType> {
     pub publisher_url: Option<BufType>,
     #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
     pub creation_date: Option<usize>,
+    // BEP 19 web seeds.
+    #[serde(
+        rename = "url-list",
+        default = "Vec::new",
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_url_list"
+    )]
+    pub url_list: Vec<BufType>,
+    // BEP 52. Lives next to "info", so it isn't covered by the info hash.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<PieceLayers<BufType>>,
 
     #[serde(skip)]
     pub info_hash: Id20,
+    // BEP 52, set for v2 and hybrid torrents.
+    #[serde(skip)]
+    pub info_hash_v2: Option<Id32>,
+}
+
+// "url-list" is either a single URL or a list of them.
+fn deserialize_url_list<'de, D, BufType>(deserializer: D) -> Result<Vec<BufType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    BufType: Deserialize<'de>,
+{
+    struct Visitor<BufType>(PhantomData<BufType>);
+
+    impl<'de, BufType: Deserialize<'de>> serde::de::Visitor<'de> for Visitor<BufType> {
+        type Value = Vec<BufType>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a URL or a list of URLs")
+        }
+
+        fn visit_borrowed_bytes<E: serde::de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+            let url = BufType::deserialize(serde::de::value::BorrowedBytesDeserializer::new(v))?;
+            Ok(vec![url])
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            let mut urls = Vec::new();
+            while let Some(url) = seq.next_element()? {
+                urls.push(url);
+            }
+            Ok(urls)
+        }
+    }
+
+    deserializer.deserialize_any(Visitor(PhantomData))
 }
 
 impl<BufType> TorrentMetaV1<BufType> {
@@ -62,6 +134,19 @@ impl<BufType> TorrentMetaV1<BufType> {
         }
         itertools::Either::Right(self.announce.iter())
     }
+
+    /// The tracker tiers from "announce-list" (BEP 12), or "announce" as the only tier.
+    pub fn iter_announce_tiers(&self) -> impl Iterator<Item = &[BufType]> {
+        if self.announce_list.iter().flatten().next().is_some() {
+            return itertools::Either::Left(
+                self.announce_list
+                    .iter()
+                    .map(|tier| tier.as_slice())
+                    .filter(|tier| !tier.is_empty()),
+            );
+        }
+        itertools::Either::Right(self.announce.as_ref().map(std::slice::from_ref).into_iter())
+    }
 }
 
 /// Main torrent information, shared by .torrent files and magnet link contents.
@@ -69,6 +154,8 @@ impl<BufType> TorrentMetaV1<BufType> {
 pub struct TorrentMetaV1Info<BufType> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<BufType>,
+    // Absent in v2-only torrents.
+    #[serde(default)]
     pub pieces: BufType,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
@@ -83,6 +170,25 @@ pub struct TorrentMetaV1Info<BufType> {
     // Multi-file mode
     #[serde(skip_serializing_if = "Option::is_none")]
     pub files: Option<Vec<TorrentMetaV1File<BufType>>>,
+
+    // BEP 27: if set to 1, peers may only be obtained from the trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
+    // BEP 52 (BitTorrent v2)
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u32>,
+    #[serde(
+        rename = "file tree",
+        default,
+        skip_serializing_if = "Option::is_none",
+        bound(deserialize = "BufType: Deserialize<'de> + AsRef<[u8]>")
+    )]
+    pub file_tree: Option<FileTreeNode<BufType>>,
 }
 
 #[derive(Clone, Copy)]
@@ -165,6 +271,55 @@ impl<'a, BufType> FileDetails<'a, BufType> {
 }
 
 impl<BufType: AsRef<[u8]>> TorrentMetaV1Info<BufType> {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_v2_only(&self) -> bool {
+        self.file_tree.is_some() && self.pieces.as_ref().is_empty()
+    }
+
+    /// Validates the piece layers of a v2-only torrent and returns the expected hash of each
+    /// piece. Returns None for v1 and hybrid torrents, which are verified with SHA-1.
+    pub fn v2_piece_hashes(
+        &self,
+        piece_layers: Option<&PieceLayers<BufType>>,
+    ) -> anyhow::Result<Option<Vec<PieceHashV2>>> {
+        if !self.is_v2_only() {
+            return Ok(None);
+        }
+        let files = self.file_tree.as_ref().unwrap().files();
+        let mut hashes = Vec::new();
+        for (idx, (_, entry)) in files.iter().enumerate() {
+            if idx + 1 < files.len() && entry.length % self.piece_length as u64 != 0 {
+                // In v2 each file starts at a piece boundary. Until pad files are supported, we
+                // can only map them onto our contiguous layout if they are already aligned.
+                anyhow::bail!(
+                    "v2 torrents with files not aligned to piece boundaries are not supported yet"
+                );
+            }
+            if entry.length == 0 {
+                continue;
+            }
+            let pieces_root = entry
+                .pieces_root
+                .as_ref()
+                .context("file is missing \"pieces root\"")?;
+            let pieces_root = Id32::new(
+                pieces_root
+                    .as_ref()
+                    .try_into()
+                    .context("\"pieces root\" must be 32 bytes")?,
+            );
+            let layer = piece_layers.and_then(|l| l.get(&pieces_root));
+            hashes.extend(
+                file_piece_hashes(entry.length, self.piece_length, pieces_root, layer)
+                    .with_context(|| format!("invalid piece layer for file {idx}"))?,
+            );
+        }
+        Ok(Some(hashes))
+    }
+
     pub fn get_hash(&self, piece: u32) -> Option<&[u8]> {
         let start = piece as usize * 20;
         let end = start + 20;
@@ -230,6 +385,37 @@ impl<BufType: AsRef<[u8]>> TorrentMetaV1Info<BufType> {
     }
 }
 
+impl<BufType: AsRef<[u8]> + Clone> TorrentMetaV1Info<BufType> {
+    // Lets the rest of the code treat v2-only torrents as if they had v1 "length" or "files".
+    fn fill_v1_files_from_file_tree(&mut self) -> anyhow::Result<()> {
+        let files = self
+            .file_tree
+            .as_ref()
+            .context("missing \"file tree\"")?
+            .files();
+        match files.as_slice() {
+            [] => anyhow::bail!("\"file tree\" is empty"),
+            [(path, entry)] if path.len() == 1 => {
+                self.length = Some(entry.length);
+                self.files = None;
+            }
+            _ => {
+                self.length = None;
+                self.files = Some(
+                    files
+                        .iter()
+                        .map(|(path, entry)| TorrentMetaV1File {
+                            length: entry.length,
+                            path: path.iter().map(|p| (*p).clone()).collect(),
+                        })
+                        .collect(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct TorrentMetaV1File<BufType> {
     pub length: u64,
@@ -263,6 +449,203 @@ where
     }
 }
 
+/// A file in a v2 "file tree".
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileTreeEntry<BufType> {
+    pub length: u64,
+    // Absent for empty files.
+    #[serde(rename = "pieces root", skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<BufType>,
+}
+
+/// A v2 "file tree" node. Files are dictionaries with a single empty key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileTreeNode<BufType> {
+    File(FileTreeEntry<BufType>),
+    Dir(Vec<(BufType, FileTreeNode<BufType>)>),
+}
+
+impl<BufType> FileTreeNode<BufType> {
+    /// All files with their paths, in the order of the tree.
+    pub fn files(&self) -> Vec<(Vec<&BufType>, &FileTreeEntry<BufType>)> {
+        fn walk<'a, BufType>(
+            node: &'a FileTreeNode<BufType>,
+            path: &mut Vec<&'a BufType>,
+            out: &mut Vec<(Vec<&'a BufType>, &'a FileTreeEntry<BufType>)>,
+        ) {
+            match node {
+                FileTreeNode::File(entry) => out.push((path.clone(), entry)),
+                FileTreeNode::Dir(children) => {
+                    for (name, child) in children {
+                        path.push(name);
+                        walk(child, path, out);
+                        path.pop();
+                    }
+                }
+            }
+        }
+        let mut out = Vec::new();
+        walk(self, &mut Vec::new(), &mut out);
+        out
+    }
+}
+
+impl<BufType: Serialize> Serialize for FileTreeNode<BufType> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        match self {
+            FileTreeNode::File(entry) => {
+                let mut m = serializer.serialize_map(Some(1))?;
+                m.serialize_entry(&ByteBuf(b""), entry)?;
+                m.end()
+            }
+            FileTreeNode::Dir(children) => {
+                let mut m = serializer.serialize_map(Some(children.len()))?;
+                for (name, child) in children {
+                    m.serialize_entry(name, child)?;
+                }
+                m.end()
+            }
+        }
+    }
+}
+
+impl<'de, BufType> Deserialize<'de> for FileTreeNode<BufType>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]>,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<BufType>(PhantomData<BufType>);
+
+        impl<'de, BufType> serde::de::Visitor<'de> for Visitor<BufType>
+        where
+            BufType: Deserialize<'de> + AsRef<[u8]>,
+        {
+            type Value = FileTreeNode<BufType>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a file tree dictionary")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                use serde::de::Error;
+                let mut file = None;
+                let mut children = Vec::new();
+                while let Some(key) = map.next_key::<BufType>()? {
+                    if key.as_ref().is_empty() {
+                        file = Some(map.next_value()?);
+                    } else {
+                        children.push((key, map.next_value()?));
+                    }
+                }
+                match file {
+                    Some(_) if !children.is_empty() => Err(A::Error::custom(
+                        "file tree node is both a file and a directory",
+                    )),
+                    Some(file) => Ok(FileTreeNode::File(file)),
+                    None => Ok(FileTreeNode::Dir(children)),
+                }
+            }
+        }
+
+        deserializer.deserialize_map(Visitor(PhantomData))
+    }
+}
+
+/// v2 "piece layers": the piece layer of each file's merkle tree, keyed by its "pieces root".
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PieceLayers<BufType>(pub Vec<(BufType, BufType)>);
+
+impl<BufType: AsRef<[u8]>> PieceLayers<BufType> {
+    pub fn get(&self, pieces_root: &Id32) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.as_ref() == pieces_root.0)
+            .map(|(_, v)| v.as_ref())
+    }
+}
+
+impl<BufType: Serialize> Serialize for PieceLayers<BufType> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut m = serializer.serialize_map(Some(self.0.len()))?;
+        for (k, v) in self.0.iter() {
+            m.serialize_entry(k, v)?;
+        }
+        m.end()
+    }
+}
+
+impl<'de, BufType: Deserialize<'de>> Deserialize<'de> for PieceLayers<BufType> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<BufType>(PhantomData<BufType>);
+
+        impl<'de, BufType: Deserialize<'de>> serde::de::Visitor<'de> for Visitor<BufType> {
+            type Value = PieceLayers<BufType>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a piece layers dictionary")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut layers = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    layers.push(entry);
+                }
+                Ok(PieceLayers(layers))
+            }
+        }
+
+        deserializer.deserialize_map(Visitor(PhantomData))
+    }
+}
+
+impl<BufType: CloneToOwned> CloneToOwned for FileTreeEntry<BufType> {
+    type Target = FileTreeEntry<<BufType as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        FileTreeEntry {
+            length: self.length,
+            pieces_root: self.pieces_root.clone_to_owned(),
+        }
+    }
+}
+
+impl<BufType: CloneToOwned> CloneToOwned for FileTreeNode<BufType> {
+    type Target = FileTreeNode<<BufType as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        match self {
+            FileTreeNode::File(entry) => FileTreeNode::File(entry.clone_to_owned()),
+            FileTreeNode::Dir(children) => FileTreeNode::Dir(
+                children
+                    .iter()
+                    .map(|(k, v)| (k.clone_to_owned(), v.clone_to_owned()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<BufType: CloneToOwned> CloneToOwned for PieceLayers<BufType> {
+    type Target = PieceLayers<<BufType as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        PieceLayers(
+            self.0
+                .iter()
+                .map(|(k, v)| (k.clone_to_owned(), v.clone_to_owned()))
+                .collect(),
+        )
+    }
+}
+
 impl<BufType> CloneToOwned for TorrentMetaV1Info<BufType>
 where
     BufType: CloneToOwned,
@@ -277,6 +660,9 @@ where
             length: self.length,
             md5sum: self.md5sum.clone_to_owned(),
             files: self.files.clone_to_owned(),
+            private: self.private,
+            meta_version: self.meta_version,
+            file_tree: self.file_tree.clone_to_owned(),
         }
     }
 }
@@ -298,7 +684,10 @@ where
             publisher: self.publisher.clone_to_owned(),
             publisher_url: self.publisher_url.clone_to_owned(),
             creation_date: self.creation_date,
+            url_list: self.url_list.clone_to_owned(),
+            piece_layers: self.piece_layers.clone_to_owned(),
             info_hash: self.info_hash,
+            info_hash_v2: self.info_hash_v2,
         }
     }
 }
@@ -350,6 +739,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_iter_announce_tiers() {
+        let mut buf = Vec::new();
+        std::fs::File::open(TORRENT_FILENAME)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+
+        let mut torrent: TorrentMetaV1Borrowed = torrent_from_bytes(&buf).unwrap();
+        let tiers = |t: &TorrentMetaV1Borrowed| {
+            t.iter_announce_tiers()
+                .map(|tier| {
+                    tier.iter()
+                        .map(|u| std::str::from_utf8(u.as_ref()).unwrap().to_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tiers(&torrent),
+            vec![
+                vec!["https://torrent.ubuntu.com/announce".to_owned()],
+                vec!["https://ipv6.torrent.ubuntu.com/announce".to_owned()]
+            ]
+        );
+
+        torrent.announce_list.clear();
+        assert_eq!(
+            tiers(&torrent),
+            vec![vec!["https://torrent.ubuntu.com/announce".to_owned()]]
+        );
+    }
+
     #[test]
     fn test_serialize_then_deserialize_bencode() {
         let mut buf = Vec::new();
@@ -368,4 +790,101 @@ mod tests {
 
         assert_eq!(torrent, deserialized);
     }
+
+    #[test]
+    fn test_deserialize_v2_only_torrent() {
+        use crate::merkle::{PieceHasherV2, MERKLE_BLOCK_SIZE};
+        use sha1w::{ISha256, Sha256};
+
+        let piece_length = MERKLE_BLOCK_SIZE;
+        let data = vec![42u8; piece_length as usize * 2 + 100];
+        let piece_hashes = data
+            .chunks(piece_length as usize)
+            .map(|piece| {
+                let mut h = PieceHasherV2::new();
+                h.update(piece);
+                h.finish(1)
+            })
+            .collect::<Vec<_>>();
+        let layer = piece_hashes.iter().flat_map(|h| h.0).collect::<Vec<_>>();
+        // 3 pieces, padded to 4.
+        let mut root = PieceHasherV2::new();
+        root.update(&data);
+        let root = root.finish(4);
+
+        let info = TorrentMetaV1Info::<ByteBuf> {
+            name: Some(ByteBuf(b"file.bin")),
+            pieces: ByteBuf(b""),
+            piece_length,
+            length: None,
+            md5sum: None,
+            files: None,
+            private: None,
+            meta_version: Some(2),
+            file_tree: Some(FileTreeNode::Dir(vec![(
+                ByteBuf(b"file.bin"),
+                FileTreeNode::File(FileTreeEntry {
+                    length: data.len() as u64,
+                    pieces_root: Some(ByteBuf(&root.0)),
+                }),
+            )])),
+        };
+        let mut info_bytes = Vec::new();
+        bencode::bencode_serialize_to_writer(&info, &mut info_bytes).unwrap();
+
+        let mut buf = b"d4:info".to_vec();
+        buf.extend_from_slice(&info_bytes);
+        buf.extend_from_slice(b"12:piece layersd32:");
+        buf.extend_from_slice(&root.0);
+        buf.extend_from_slice(format!("{}:", layer.len()).as_bytes());
+        buf.extend_from_slice(&layer);
+        buf.extend_from_slice(b"ee");
+
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(&buf).unwrap();
+        let mut h = Sha256::new();
+        h.update(&info_bytes);
+        let info_hash_v2 = Id32::new(h.finish());
+        assert_eq!(torrent.info_hash_v2, Some(info_hash_v2));
+        assert_eq!(torrent.info_hash.0[..], info_hash_v2.0[..20]);
+        assert!(torrent.info.is_v2_only());
+        assert_eq!(torrent.info.length, Some(data.len() as u64));
+
+        let hashes = torrent
+            .info
+            .v2_piece_hashes(torrent.piece_layers.as_ref())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            hashes.iter().map(|h| h.hash).collect::<Vec<_>>(),
+            piece_hashes
+        );
+        Lengths::from_torrent(&torrent.info).unwrap();
+    }
+
+    #[test]
+    fn test_deserialize_url_list() {
+        let info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let make = |url_list: &[u8]| {
+            let mut buf = b"d4:info".to_vec();
+            buf.extend_from_slice(info);
+            buf.extend_from_slice(b"8:url-list");
+            buf.extend_from_slice(url_list);
+            buf.push(b'e');
+            buf
+        };
+
+        let buf = make(b"18:http://example.com");
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(&buf).unwrap();
+        assert_eq!(torrent.url_list, vec![ByteBuf(b"http://example.com")]);
+
+        let buf = make(b"l13:http://a.com/13:http://b.com/e");
+        let torrent: TorrentMetaV1Owned = torrent_from_bytes(&buf).unwrap();
+        assert_eq!(
+            torrent.url_list,
+            vec![
+                ByteBufOwned::from(&b"http://a.com/"[..]),
+                ByteBufOwned::from(&b"http://b.com/"[..])
+            ]
+        );
+    }
 }
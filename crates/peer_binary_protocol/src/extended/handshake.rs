@@ -1,6 +1,6 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use buffers::ByteBuf;
@@ -9,7 +9,11 @@ use byteorder::BE;
 use clone_to_owned::CloneToOwned;
 use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::MY_EXTENDED_UT_METADATA;
+use crate::{
+    MY_EXTENDED_LT_DONTHAVE, MY_EXTENDED_UT_HOLEPUNCH, MY_EXTENDED_UT_METADATA, MY_EXTENDED_UT_PEX,
+};
+
+use super::PeerExtendedMessageIds;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct ExtendedHandshake<ByteBuf: Eq + std::hash::Hash> {
@@ -39,6 +43,9 @@ impl ExtendedHandshake<ByteBuf<'static>> {
     pub fn new() -> Self {
         let mut features = HashMap::new();
         features.insert(ByteBuf(b"ut_metadata"), MY_EXTENDED_UT_METADATA);
+        features.insert(ByteBuf(b"ut_pex"), MY_EXTENDED_UT_PEX);
+        features.insert(ByteBuf(b"ut_holepunch"), MY_EXTENDED_UT_HOLEPUNCH);
+        features.insert(ByteBuf(b"lt_donthave"), MY_EXTENDED_LT_DONTHAVE);
         Self {
             m: features,
             ..Default::default()
@@ -47,6 +54,31 @@ impl ExtendedHandshake<ByteBuf<'static>> {
 }
 
 impl<ByteBuf: Eq + std::hash::Hash> ExtendedHandshake<ByteBuf> {
+    /// The addresses the peer says it's also reachable at, from the "ipv4" and "ipv6"
+    /// fields and the listen port.
+    pub fn advertised_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        let port = self
+            .p
+            .and_then(|p| u16::try_from(p).ok())
+            .filter(|p| *p != 0);
+        let v4 = self
+            .ipv4
+            .as_ref()
+            .and_then(|b| <[u8; 4]>::try_from(b.as_ref()).ok())
+            .map(|o| IpAddr::from(Ipv4Addr::from(o)));
+        let v6 = self
+            .ipv6
+            .as_ref()
+            .and_then(|b| <[u8; 16]>::try_from(b.as_ref()).ok())
+            .map(|o| IpAddr::from(Ipv6Addr::from(o)));
+        v4.into_iter()
+            .chain(v6)
+            .filter_map(move |ip| Some(SocketAddr::new(ip, port?)))
+    }
+
     pub fn get_msgid(&self, msg_type: &[u8]) -> Option<u8>
     where
         ByteBuf: AsRef<[u8]>,
@@ -66,6 +98,39 @@ impl<ByteBuf: Eq + std::hash::Hash> ExtendedHandshake<ByteBuf> {
     {
         self.get_msgid(b"ut_metadata")
     }
+
+    pub fn ut_pex(&self) -> Option<u8>
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        self.get_msgid(b"ut_pex")
+    }
+
+    pub fn ut_holepunch(&self) -> Option<u8>
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        self.get_msgid(b"ut_holepunch")
+    }
+
+    pub fn lt_donthave(&self) -> Option<u8>
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        self.get_msgid(b"lt_donthave")
+    }
+
+    pub fn peer_extended_messages(&self) -> PeerExtendedMessageIds
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        PeerExtendedMessageIds {
+            ut_metadata: self.ut_metadata(),
+            ut_pex: self.ut_pex(),
+            ut_holepunch: self.ut_holepunch(),
+            lt_donthave: self.lt_donthave(),
+        }
+    }
 }
 
 impl<ByteBuf> CloneToOwned for ExtendedHandshake<ByteBuf>
@@ -104,7 +169,10 @@ impl Serialize for YourIP {
                 let buf = ipv4.octets();
                 serializer.serialize_bytes(&buf)
             }
-            IpAddr::V6(_) => todo!(),
+            IpAddr::V6(ipv6) => {
+                let buf = ipv6.octets();
+                serializer.serialize_bytes(&buf)
+            }
         }
     }
 }
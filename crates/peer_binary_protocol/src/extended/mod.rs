@@ -4,20 +4,44 @@ use bencode::BencodeValue;
 use clone_to_owned::CloneToOwned;
 use serde::{Deserialize, Serialize};
 
-use self::{handshake::ExtendedHandshake, ut_metadata::UtMetadata};
+use self::{
+    handshake::ExtendedHandshake, ut_holepunch::UtHolepunch, ut_metadata::UtMetadata, ut_pex::UtPex,
+};
 
 use super::MessageDeserializeError;
 
 pub mod handshake;
+pub mod ut_holepunch;
 pub mod ut_metadata;
+pub mod ut_pex;
 
-use super::MY_EXTENDED_UT_METADATA;
+use super::{
+    MY_EXTENDED_FIRST_CUSTOM, MY_EXTENDED_LT_DONTHAVE, MY_EXTENDED_UT_HOLEPUNCH,
+    MY_EXTENDED_UT_METADATA, MY_EXTENDED_UT_PEX,
+};
+
+// Message ids the peer asked us to use for its supported extensions, as received in its
+// extended handshake.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PeerExtendedMessageIds {
+    pub ut_metadata: Option<u8>,
+    pub ut_pex: Option<u8>,
+    pub ut_holepunch: Option<u8>,
+    pub lt_donthave: Option<u8>,
+}
 
 #[derive(Debug)]
 pub enum ExtendedMessage<ByteBuf: std::hash::Hash + Eq> {
     Handshake(ExtendedHandshake<ByteBuf>),
     UtMetadata(UtMetadata<ByteBuf>),
+    UtPex(UtPex<ByteBuf>),
+    UtHolepunch(UtHolepunch),
+    // The peer no longer has this piece.
+    LtDonthave(u32),
     Dyn(u8, BencodeValue<ByteBuf>),
+    // A message of a custom extension with its raw payload. The id is ours when received,
+    // and the peer's when sent.
+    Raw(u8, ByteBuf),
 }
 
 impl<ByteBuf> CloneToOwned for ExtendedMessage<ByteBuf>
@@ -32,6 +56,10 @@ where
             ExtendedMessage::Handshake(h) => ExtendedMessage::Handshake(h.clone_to_owned()),
             ExtendedMessage::Dyn(u, d) => ExtendedMessage::Dyn(*u, d.clone_to_owned()),
             ExtendedMessage::UtMetadata(m) => ExtendedMessage::UtMetadata(m.clone_to_owned()),
+            ExtendedMessage::UtPex(m) => ExtendedMessage::UtPex(m.clone_to_owned()),
+            ExtendedMessage::UtHolepunch(m) => ExtendedMessage::UtHolepunch(*m),
+            ExtendedMessage::LtDonthave(i) => ExtendedMessage::LtDonthave(*i),
+            ExtendedMessage::Raw(u, b) => ExtendedMessage::Raw(*u, b.clone_to_owned()),
         }
     }
 }
@@ -40,7 +68,7 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_messages: &dyn Fn() -> PeerExtendedMessageIds,
     ) -> anyhow::Result<()>
     where
         ByteBuf: AsRef<[u8]>,
@@ -55,12 +83,37 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
                 bencode_serialize_to_writer(h, out)?;
             }
             ExtendedMessage::UtMetadata(u) => {
-                let emsg_id = extended_handshake_ut_metadata().ok_or_else(|| {
+                let emsg_id = peer_extended_messages().ut_metadata.ok_or_else(|| {
                     anyhow::anyhow!("need peer's handshake to serialize ut_metadata")
                 })?;
                 out.push(emsg_id);
                 u.serialize(out);
             }
+            ExtendedMessage::UtPex(p) => {
+                let emsg_id = peer_extended_messages().ut_pex.ok_or_else(|| {
+                    anyhow::anyhow!("peer doesn't support ut_pex, can't serialize it")
+                })?;
+                out.push(emsg_id);
+                bencode_serialize_to_writer(p, out)?;
+            }
+            ExtendedMessage::UtHolepunch(h) => {
+                let emsg_id = peer_extended_messages().ut_holepunch.ok_or_else(|| {
+                    anyhow::anyhow!("peer doesn't support ut_holepunch, can't serialize it")
+                })?;
+                out.push(emsg_id);
+                h.serialize(out);
+            }
+            ExtendedMessage::LtDonthave(index) => {
+                let emsg_id = peer_extended_messages().lt_donthave.ok_or_else(|| {
+                    anyhow::anyhow!("peer doesn't support lt_donthave, can't serialize it")
+                })?;
+                out.push(emsg_id);
+                out.extend_from_slice(&index.to_be_bytes());
+            }
+            ExtendedMessage::Raw(msg_id, payload) => {
+                out.push(*msg_id);
+                out.extend_from_slice(payload.as_ref());
+            }
         }
         Ok(())
     }
@@ -86,6 +139,24 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
             MY_EXTENDED_UT_METADATA => {
                 Ok(ExtendedMessage::UtMetadata(UtMetadata::deserialize(buf)?))
             }
+            MY_EXTENDED_UT_PEX => Ok(ExtendedMessage::UtPex(from_bytes(buf)?)),
+            MY_EXTENDED_UT_HOLEPUNCH => {
+                Ok(ExtendedMessage::UtHolepunch(UtHolepunch::deserialize(buf)?))
+            }
+            MY_EXTENDED_LT_DONTHAVE => {
+                let index = buf
+                    .get(..4)
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_be_bytes)
+                    .ok_or_else(|| {
+                        MessageDeserializeError::Other(anyhow::anyhow!(
+                            "error deserializing lt_donthave: expected 4 bytes, got {}",
+                            buf.len()
+                        ))
+                    })?;
+                Ok(ExtendedMessage::LtDonthave(index))
+            }
+            id if id >= MY_EXTENDED_FIRST_CUSTOM => Ok(ExtendedMessage::Raw(id, buf.into())),
             _ => Ok(ExtendedMessage::Dyn(emsg_id, from_bytes(buf)?)),
         }
     }
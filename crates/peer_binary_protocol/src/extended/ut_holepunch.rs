@@ -0,0 +1,149 @@
+// NAT hole punching through a relay peer (BEP 55).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{ByteOrder, BE};
+
+use crate::MessageDeserializeError;
+
+const MSG_RENDEZVOUS: u8 = 0;
+const MSG_CONNECT: u8 = 1;
+const MSG_ERROR: u8 = 2;
+
+const ADDR_IPV4: u8 = 0;
+const ADDR_IPV6: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtHolepunchError {
+    NoSuchPeer,
+    NotConnected,
+    NoSupport,
+    NoSelf,
+    Other(u32),
+}
+
+impl UtHolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            UtHolepunchError::NoSuchPeer => 1,
+            UtHolepunchError::NotConnected => 2,
+            UtHolepunchError::NoSupport => 3,
+            UtHolepunchError::NoSelf => 4,
+            UtHolepunchError::Other(code) => code,
+        }
+    }
+
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => UtHolepunchError::NoSuchPeer,
+            2 => UtHolepunchError::NotConnected,
+            3 => UtHolepunchError::NoSupport,
+            4 => UtHolepunchError::NoSelf,
+            other => UtHolepunchError::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtHolepunch {
+    // Sent to the relay: please connect me with this peer.
+    Rendezvous(SocketAddr),
+    // Sent by the relay to both sides: connect to this peer now.
+    Connect(SocketAddr),
+    // Sent by the relay when it can't do the rendezvous with this peer.
+    Error(SocketAddr, UtHolepunchError),
+}
+
+impl UtHolepunch {
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        let (msg_type, addr, code) = match *self {
+            UtHolepunch::Rendezvous(addr) => (MSG_RENDEZVOUS, addr, 0),
+            UtHolepunch::Connect(addr) => (MSG_CONNECT, addr, 0),
+            UtHolepunch::Error(addr, e) => (MSG_ERROR, addr, e.code()),
+        };
+        out.push(msg_type);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                out.push(ADDR_IPV4);
+                out.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                out.push(ADDR_IPV6);
+                out.extend_from_slice(&ip.octets());
+            }
+        }
+        out.extend_from_slice(&addr.port().to_be_bytes());
+        out.extend_from_slice(&code.to_be_bytes());
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, MessageDeserializeError> {
+        let err = |msg: &str| {
+            MessageDeserializeError::Other(anyhow::anyhow!(
+                "error deserializing ut_holepunch: {msg}"
+            ))
+        };
+        let (msg_type, addr_type, rest) = match buf {
+            [msg_type, addr_type, rest @ ..] => (*msg_type, *addr_type, rest),
+            _ => return Err(err("message too short")),
+        };
+        let (ip, rest): (IpAddr, &[u8]) = match addr_type {
+            ADDR_IPV4 if rest.len() >= 4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&rest[..4]);
+                (Ipv4Addr::from(octets).into(), &rest[4..])
+            }
+            ADDR_IPV6 if rest.len() >= 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&rest[..16]);
+                (Ipv6Addr::from(octets).into(), &rest[16..])
+            }
+            ADDR_IPV4 | ADDR_IPV6 => return Err(err("message too short")),
+            other => return Err(err(&format!("unknown address type {other}"))),
+        };
+        if rest.len() != 6 {
+            return Err(err("wrong message length"));
+        }
+        let addr = SocketAddr::new(ip, BE::read_u16(&rest[..2]));
+        let code = BE::read_u32(&rest[2..]);
+        match msg_type {
+            MSG_RENDEZVOUS => Ok(UtHolepunch::Rendezvous(addr)),
+            MSG_CONNECT => Ok(UtHolepunch::Connect(addr)),
+            MSG_ERROR => Ok(UtHolepunch::Error(addr, UtHolepunchError::from_code(code))),
+            other => Err(err(&format!("unknown message type {other}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UtHolepunch, UtHolepunchError};
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let cases = [
+            (UtHolepunch::Rendezvous("1.2.3.4:6881".parse().unwrap()), 12),
+            (
+                UtHolepunch::Connect("[2001:db8::1]:51413".parse().unwrap()),
+                24,
+            ),
+            (
+                UtHolepunch::Error(
+                    "1.2.3.4:6881".parse().unwrap(),
+                    UtHolepunchError::NotConnected,
+                ),
+                12,
+            ),
+        ];
+        for (msg, len) in cases {
+            let mut buf = Vec::new();
+            msg.serialize(&mut buf);
+            assert_eq!(buf.len(), len);
+            assert_eq!(UtHolepunch::deserialize(&buf).unwrap(), msg);
+        }
+
+        let mut buf = Vec::new();
+        UtHolepunch::Connect("1.2.3.4:6881".parse().unwrap()).serialize(&mut buf);
+        assert_eq!(buf, [1, 0, 1, 2, 3, 4, 0x1a, 0xe1, 0, 0, 0, 0]);
+        assert!(UtHolepunch::deserialize(&buf[..8]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+// Peer Exchange (BEP 11).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use buffers::ByteBufOwned;
+use byteorder::{ByteOrder, BE};
+use clone_to_owned::CloneToOwned;
+use serde::{Deserialize, Serialize};
+
+// BEP 11 limits the number of added and dropped peers in one message.
+pub const UT_PEX_MAX_PEERS: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UtPex<ByteBuf> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added: Option<ByteBuf>,
+    #[serde(rename = "added.f", skip_serializing_if = "Option::is_none")]
+    pub added_f: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f", skip_serializing_if = "Option::is_none")]
+    pub added6_f: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped6: Option<ByteBuf>,
+}
+
+impl<ByteBuf: CloneToOwned> CloneToOwned for UtPex<ByteBuf> {
+    type Target = UtPex<<ByteBuf as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        UtPex {
+            added: self.added.clone_to_owned(),
+            added_f: self.added_f.clone_to_owned(),
+            added6: self.added6.clone_to_owned(),
+            added6_f: self.added6_f.clone_to_owned(),
+            dropped: self.dropped.clone_to_owned(),
+            dropped6: self.dropped6.clone_to_owned(),
+        }
+    }
+}
+
+fn iter_compact<'a>(
+    v4: Option<&'a [u8]>,
+    v6: Option<&'a [u8]>,
+) -> impl Iterator<Item = SocketAddr> + 'a {
+    let v4 = v4.unwrap_or_default().chunks_exact(6).map(|c| {
+        let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+        SocketAddr::new(IpAddr::V4(ip), BE::read_u16(&c[4..]))
+    });
+    let v6 = v6.unwrap_or_default().chunks_exact(18).map(|c| {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&c[..16]);
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), BE::read_u16(&c[16..]))
+    });
+    v4.chain(v6)
+}
+
+fn serialize_compact<'a>(
+    addrs: impl IntoIterator<Item = &'a SocketAddr>,
+) -> (Option<ByteBufOwned>, Option<ByteBufOwned>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for addr in addrs {
+        let port = addr.port().to_be_bytes();
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&port);
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&port);
+            }
+        }
+    }
+    let to_buf = |v: Vec<u8>| {
+        if v.is_empty() {
+            None
+        } else {
+            Some(ByteBufOwned::from(v))
+        }
+    };
+    (to_buf(v4), to_buf(v6))
+}
+
+impl<ByteBuf: AsRef<[u8]>> UtPex<ByteBuf> {
+    pub fn added_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        iter_compact(
+            self.added.as_ref().map(|b| b.as_ref()),
+            self.added6.as_ref().map(|b| b.as_ref()),
+        )
+    }
+
+    pub fn dropped_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        iter_compact(
+            self.dropped.as_ref().map(|b| b.as_ref()),
+            self.dropped6.as_ref().map(|b| b.as_ref()),
+        )
+    }
+}
+
+impl UtPex<ByteBufOwned> {
+    pub fn from_addrs<'a>(
+        added: impl IntoIterator<Item = &'a SocketAddr>,
+        dropped: impl IntoIterator<Item = &'a SocketAddr>,
+    ) -> Self {
+        let (added, added6) = serialize_compact(added);
+        let (dropped, dropped6) = serialize_compact(dropped);
+        // We don't know anything about the peers' flags, so "added.f" is all zeroes.
+        let flags = |b: &Option<ByteBufOwned>, len: usize| {
+            b.as_ref()
+                .map(|b| ByteBufOwned::from(vec![0u8; b.as_ref().len() / len]))
+        };
+        Self {
+            added_f: flags(&added, 6),
+            added6_f: flags(&added6, 18),
+            added,
+            added6,
+            dropped,
+            dropped6,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_none()
+            && self.added6.is_none()
+            && self.dropped.is_none()
+            && self.dropped6.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bencode::{bencode_serialize_to_writer, from_bytes};
+    use buffers::{ByteBuf, ByteBufOwned};
+
+    use super::UtPex;
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let added: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+            "10.0.0.1:1".parse().unwrap(),
+        ];
+        let dropped: Vec<SocketAddr> = vec!["5.6.7.8:80".parse().unwrap()];
+        let pex = UtPex::<ByteBufOwned>::from_addrs(&added, &dropped);
+        assert_eq!(pex.added_f.as_ref().unwrap().as_ref(), &[0, 0]);
+        assert_eq!(pex.added6_f.as_ref().unwrap().as_ref(), &[0]);
+        assert!(pex.dropped6.is_none());
+
+        let mut buf = Vec::new();
+        bencode_serialize_to_writer(&pex, &mut buf).unwrap();
+        let de: UtPex<ByteBuf> = from_bytes(&buf).unwrap();
+        assert_eq!(
+            de.added_peers().collect::<Vec<_>>(),
+            vec![added[0], added[2], added[1]]
+        );
+        assert_eq!(de.dropped_peers().collect::<Vec<_>>(), dropped);
+    }
+
+    #[test]
+    fn test_ignores_truncated_entries() {
+        let pex = UtPex {
+            added: Some(ByteBuf(&[1, 2, 3, 4, 0, 80, 9, 9])),
+            ..Default::default()
+        };
+        assert_eq!(
+            pex.added_peers().collect::<Vec<_>>(),
+            vec!["1.2.3.4:80".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(UtPex::<ByteBufOwned>::from_addrs(&[], &[]).is_empty());
+    }
+}
@@ -11,7 +11,7 @@ use clone_to_owned::CloneToOwned;
 use librqbit_core::{constants::CHUNK_SIZE, hash_id::Id20, lengths::ChunkInfo};
 use serde::{Deserialize, Serialize};
 
-use self::extended::ExtendedMessage;
+use self::extended::{ExtendedMessage, PeerExtendedMessageIds};
 
 const INTEGER_LEN: usize = 4;
 const MSGID_LEN: usize = 1;
@@ -31,6 +31,12 @@ const LEN_PREFIX_NOT_INTERESTED: u32 = 1;
 const LEN_PREFIX_HAVE: u32 = 5;
 const LEN_PREFIX_PIECE: u32 = 9;
 const LEN_PREFIX_REQUEST: u32 = 13;
+const LEN_PREFIX_PORT: u32 = 3;
+const LEN_PREFIX_SUGGEST_PIECE: u32 = 5;
+const LEN_PREFIX_HAVE_ALL: u32 = 1;
+const LEN_PREFIX_HAVE_NONE: u32 = 1;
+const LEN_PREFIX_REJECT_REQUEST: u32 = 13;
+const LEN_PREFIX_ALLOWED_FAST: u32 = 5;
 
 const MSGID_CHOKE: u8 = 0;
 const MSGID_UNCHOKE: u8 = 1;
@@ -41,9 +47,22 @@ const MSGID_BITFIELD: u8 = 5;
 const MSGID_REQUEST: u8 = 6;
 const MSGID_PIECE: u8 = 7;
 const MSGID_CANCEL: u8 = 8;
+const MSGID_PORT: u8 = 9;
+// Fast Extension (BEP 6)
+const MSGID_SUGGEST_PIECE: u8 = 0x0d;
+const MSGID_HAVE_ALL: u8 = 0x0e;
+const MSGID_HAVE_NONE: u8 = 0x0f;
+const MSGID_REJECT_REQUEST: u8 = 0x10;
+const MSGID_ALLOWED_FAST: u8 = 0x11;
 const MSGID_EXTENDED: u8 = 20;
 
 pub const MY_EXTENDED_UT_METADATA: u8 = 3;
+pub const MY_EXTENDED_UT_PEX: u8 = 1;
+pub const MY_EXTENDED_UT_HOLEPUNCH: u8 = 4;
+pub const MY_EXTENDED_LT_DONTHAVE: u8 = 5;
+// Extensions registered by library users get ids from this one up. Their payloads are passed
+// through as is.
+pub const MY_EXTENDED_FIRST_CUSTOM: u8 = 16;
 
 #[derive(Debug)]
 pub enum MessageDeserializeError {
@@ -179,6 +198,12 @@ pub enum Message<ByteBuf: std::hash::Hash + Eq> {
     Interested,
     NotInterested,
     Piece(Piece<ByteBuf>),
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(Request),
+    AllowedFast(u32),
     Extended(ExtendedMessage<ByteBuf>),
 }
 
@@ -215,6 +240,12 @@ where
             Message::KeepAlive => Message::KeepAlive,
             Message::Have(v) => Message::Have(*v),
             Message::NotInterested => Message::NotInterested,
+            Message::Port(p) => Message::Port(*p),
+            Message::SuggestPiece(v) => Message::SuggestPiece(*v),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::RejectRequest(req) => Message::RejectRequest(*req),
+            Message::AllowedFast(v) => Message::AllowedFast(*v),
             Message::Extended(e) => Message::Extended(e.clone_to_owned()),
         }
     }
@@ -256,13 +287,19 @@ where
             ),
             Message::KeepAlive => (LEN_PREFIX_KEEPALIVE, 0),
             Message::Have(_) => (LEN_PREFIX_HAVE, MSGID_HAVE),
+            Message::Port(_) => (LEN_PREFIX_PORT, MSGID_PORT),
+            Message::SuggestPiece(_) => (LEN_PREFIX_SUGGEST_PIECE, MSGID_SUGGEST_PIECE),
+            Message::HaveAll => (LEN_PREFIX_HAVE_ALL, MSGID_HAVE_ALL),
+            Message::HaveNone => (LEN_PREFIX_HAVE_NONE, MSGID_HAVE_NONE),
+            Message::RejectRequest(_) => (LEN_PREFIX_REJECT_REQUEST, MSGID_REJECT_REQUEST),
+            Message::AllowedFast(_) => (LEN_PREFIX_ALLOWED_FAST, MSGID_ALLOWED_FAST),
             Message::Extended(_) => (0, MSGID_EXTENDED),
         }
     }
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_messages: &dyn Fn() -> PeerExtendedMessageIds,
     ) -> anyhow::Result<usize> {
         let (lp, msg_id) = self.len_prefix_and_msg_id();
 
@@ -274,7 +311,9 @@ where
         let ser = bopts();
 
         match self {
-            Message::Request(request) | Message::Cancel(request) => {
+            Message::Request(request)
+            | Message::Cancel(request)
+            | Message::RejectRequest(request) => {
                 const MSG_LEN: usize = PREAMBLE_LEN + 12;
                 out.resize(MSG_LEN, 0);
                 debug_assert_eq!(out[PREAMBLE_LEN..].len(), 12);
@@ -289,9 +328,12 @@ where
                 out[PREAMBLE_LEN..PREAMBLE_LEN + block_len].copy_from_slice(b.as_ref());
                 Ok(msg_len)
             }
-            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => {
-                Ok(PREAMBLE_LEN)
-            }
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => Ok(PREAMBLE_LEN),
             Message::Piece(p) => {
                 let block_len = p.block.as_ref().len();
                 let payload_len = 8 + block_len;
@@ -305,14 +347,20 @@ where
                 // the len prefix was already written out to buf
                 Ok(4)
             }
-            Message::Have(v) => {
+            Message::Have(v) | Message::SuggestPiece(v) | Message::AllowedFast(v) => {
                 let msg_len = PREAMBLE_LEN + 4;
                 out.resize(msg_len, 0);
                 BE::write_u32(&mut out[PREAMBLE_LEN..], *v);
                 Ok(msg_len)
             }
+            Message::Port(port) => {
+                let msg_len = PREAMBLE_LEN + 2;
+                out.resize(msg_len, 0);
+                BE::write_u16(&mut out[PREAMBLE_LEN..], *port);
+                Ok(msg_len)
+            }
             Message::Extended(e) => {
-                e.serialize(out, extended_handshake_ut_metadata)?;
+                e.serialize(out, peer_extended_messages)?;
                 let msg_size = out.len();
                 // no fucking idea why +1, but I tweaked that for it all to match up
                 // with real messages.
@@ -385,16 +433,36 @@ where
                 }
                 Ok((Message::NotInterested, NO_PAYLOAD_MSG_LEN))
             }
-            MSGID_HAVE => {
+            MSGID_HAVE | MSGID_SUGGEST_PIECE | MSGID_ALLOWED_FAST => {
                 let expected_len = 4;
+                let (msg, name): (fn(u32) -> Message<ByteBuf>, _) = match msg_id {
+                    MSGID_HAVE => (Message::Have, "have"),
+                    MSGID_SUGGEST_PIECE => (Message::SuggestPiece, "suggest piece"),
+                    _ => (Message::AllowedFast, "allowed fast"),
+                };
                 match rest.get(..expected_len) {
-                    Some(h) => Ok((Message::Have(BE::read_u32(h)), PREAMBLE_LEN + expected_len)),
+                    Some(h) => Ok((msg(BE::read_u32(h)), PREAMBLE_LEN + expected_len)),
                     None => {
                         let missing = expected_len - rest.len();
-                        Err(MessageDeserializeError::NotEnoughData(missing, "have"))
+                        Err(MessageDeserializeError::NotEnoughData(missing, name))
                     }
                 }
             }
+            MSGID_HAVE_ALL | MSGID_HAVE_NONE => {
+                if len_prefix != LEN_PREFIX_HAVE_ALL {
+                    return Err(MessageDeserializeError::IncorrectLenPrefix {
+                        received: len_prefix,
+                        expected: LEN_PREFIX_HAVE_ALL,
+                        msg_id,
+                    });
+                }
+                let msg = if msg_id == MSGID_HAVE_ALL {
+                    Message::HaveAll
+                } else {
+                    Message::HaveNone
+                };
+                Ok((msg, NO_PAYLOAD_MSG_LEN))
+            }
             MSGID_BITFIELD => {
                 if len_prefix <= 1 {
                     return Err(MessageDeserializeError::IncorrectLenPrefix {
@@ -415,15 +483,15 @@ where
                     }
                 }
             }
-            MSGID_REQUEST | MSGID_CANCEL => {
+            MSGID_REQUEST | MSGID_CANCEL | MSGID_REJECT_REQUEST => {
                 let expected_len = 12;
                 match rest.get(..expected_len) {
                     Some(b) => {
                         let request = decoder_config.deserialize::<Request>(b).unwrap();
-                        let req = if msg_id == MSGID_REQUEST {
-                            Message::Request(request)
-                        } else {
-                            Message::Cancel(request)
+                        let req = match msg_id {
+                            MSGID_REQUEST => Message::Request(request),
+                            MSGID_CANCEL => Message::Cancel(request),
+                            _ => Message::RejectRequest(request),
                         };
                         Ok((req, PREAMBLE_LEN + expected_len))
                     }
@@ -431,10 +499,10 @@ where
                         let missing = expected_len - rest.len();
                         Err(MessageDeserializeError::NotEnoughData(
                             missing,
-                            if msg_id == MSGID_REQUEST {
-                                "request"
-                            } else {
-                                "cancel"
+                            match msg_id {
+                                MSGID_REQUEST => "request",
+                                MSGID_CANCEL => "cancel",
+                                _ => "reject request",
                             },
                         ))
                     }
@@ -461,10 +529,28 @@ where
                     )),
                 }
             }
+            MSGID_PORT => {
+                if len_prefix != LEN_PREFIX_PORT {
+                    return Err(MessageDeserializeError::IncorrectLenPrefix {
+                        received: len_prefix,
+                        expected: LEN_PREFIX_PORT,
+                        msg_id,
+                    });
+                }
+                let expected_len = 2;
+                match rest.get(..expected_len) {
+                    Some(p) => Ok((Message::Port(BE::read_u16(p)), PREAMBLE_LEN + expected_len)),
+                    None => {
+                        let missing = expected_len - rest.len();
+                        Err(MessageDeserializeError::NotEnoughData(missing, "port"))
+                    }
+                }
+            }
             MSGID_EXTENDED => {
-                if len_prefix <= 6 {
+                // The message id and the extended message id. lt_donthave is only 6 bytes long.
+                if len_prefix < 2 {
                     return Err(MessageDeserializeError::IncorrectLenPrefix {
-                        expected: 6,
+                        expected: 2,
                         received: len_prefix,
                         msg_id,
                     });
@@ -508,6 +594,10 @@ impl Handshake<ByteBuf<'static>> {
         let mut reserved: u64 = 0;
         // supports extended messaging
         reserved |= 1 << 20;
+        // supports DHT (BEP 5), so that peers tell us their DHT port
+        reserved |= 1;
+        // supports the Fast Extension (BEP 6)
+        reserved |= 1 << 2;
         let mut reserved_arr = [0u8; 8];
         BE::write_u64(&mut reserved_arr, reserved);
 
@@ -555,6 +645,12 @@ impl<B> Handshake<B> {
     pub fn supports_extended(&self) -> bool {
         self.reserved[5] & 0x10 > 0
     }
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 > 0
+    }
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 > 0
+    }
     fn bopts() -> impl bincode::Options {
         bincode::DefaultOptions::new()
     }
@@ -622,7 +718,8 @@ mod tests {
     fn test_extended_serialize() {
         let msg = Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
         let mut out = Vec::new();
-        msg.serialize(&mut out, &|| None).unwrap();
+        msg.serialize(&mut out, &PeerExtendedMessageIds::default)
+            .unwrap();
         dbg!(out);
     }
 
@@ -638,7 +735,8 @@ mod tests {
         let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
         assert_eq!(size, buf.len());
         let mut write_buf = Vec::new();
-        msg.serialize(&mut write_buf, &|| None).unwrap();
+        msg.serialize(&mut write_buf, &PeerExtendedMessageIds::default)
+            .unwrap();
         if buf != write_buf {
             {
                 use std::io::Write;
@@ -652,4 +750,153 @@ mod tests {
             panic!("resources/test/extended-handshake.bin did not serialize exactly the same. Dumped to /tmp/test_deserialize_serialize_extended_is_same, you can compare with resources/test/extended-handshake.bin")
         }
     }
+
+    #[test]
+    fn test_extended_handshake_ipv6() {
+        use crate::extended::handshake::YourIP;
+
+        let msg = Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake {
+            p: Some(6881),
+            yourip: Some(YourIP("2001:db8::2".parse().unwrap())),
+            ipv6: Some(ByteBuf(&[
+                0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+            ])),
+            ..ExtendedHandshake::new()
+        }));
+        let mut buf = Vec::new();
+        msg.serialize(&mut buf, &PeerExtendedMessageIds::default)
+            .unwrap();
+        let (de, _) = MessageBorrowed::deserialize(&buf).unwrap();
+        let h = match de {
+            Message::Extended(ExtendedMessage::Handshake(h)) => h,
+            other => panic!("unexpected message {other:?}"),
+        };
+        assert_eq!(
+            h.yourip.map(|ip| ip.0),
+            Some("2001:db8::2".parse().unwrap())
+        );
+        assert_eq!(
+            h.advertised_addrs().collect::<Vec<_>>(),
+            vec!["[2001:db8::1]:6881"
+                .parse::<std::net::SocketAddr>()
+                .unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_port_serialize_deserialize() {
+        let mut buf = Vec::new();
+        let len = MessageBorrowed::Port(6881)
+            .serialize(&mut buf, &PeerExtendedMessageIds::default)
+            .unwrap();
+        assert_eq!(&buf[..len], &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+
+        let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
+        assert_eq!(size, len);
+        assert!(matches!(msg, Message::Port(6881)));
+    }
+
+    #[test]
+    fn test_handshake_supports_dht() {
+        let h = Handshake::new(Id20::new([0; 20]), Id20::new([1; 20]));
+        assert!(h.supports_dht());
+        assert!(h.supports_extended());
+        assert!(h.supports_fast());
+    }
+
+    #[test]
+    fn test_fast_extension_serialize_deserialize() {
+        let reject = Request::new(1, 16384, 16384);
+        let cases: [(MessageBorrowed, &[u8]); 5] = [
+            (Message::HaveAll, &[0, 0, 0, 1, 0x0e]),
+            (Message::HaveNone, &[0, 0, 0, 1, 0x0f]),
+            (Message::SuggestPiece(3), &[0, 0, 0, 5, 0x0d, 0, 0, 0, 3]),
+            (Message::AllowedFast(7), &[0, 0, 0, 5, 0x11, 0, 0, 0, 7]),
+            (
+                Message::RejectRequest(reject),
+                &[0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+        ];
+        for (msg, expected) in cases {
+            let mut buf = Vec::new();
+            let len = msg
+                .serialize(&mut buf, &PeerExtendedMessageIds::default)
+                .unwrap();
+            assert_eq!(&buf[..len], expected, "{msg:?}");
+
+            let (de, size) = MessageBorrowed::deserialize(&buf).unwrap();
+            assert_eq!(size, len);
+            assert_eq!(format!("{de:?}"), format!("{msg:?}"));
+        }
+    }
+
+    #[test]
+    fn test_lt_donthave_serialize_deserialize() {
+        let mut buf = Vec::new();
+        let ids = || PeerExtendedMessageIds {
+            lt_donthave: Some(7),
+            ..Default::default()
+        };
+        let len = MessageBorrowed::Extended(ExtendedMessage::LtDonthave(42))
+            .serialize(&mut buf, &ids)
+            .unwrap();
+        assert_eq!(&buf[..len], &[0, 0, 0, 6, 20, 7, 0, 0, 0, 42]);
+
+        // What we receive carries our own id.
+        buf[5] = MY_EXTENDED_LT_DONTHAVE;
+        let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
+        assert_eq!(size, len);
+        assert!(matches!(
+            msg,
+            Message::Extended(ExtendedMessage::LtDonthave(42))
+        ));
+
+        assert!(MessageBorrowed::Extended(ExtendedMessage::LtDonthave(42))
+            .serialize(&mut Vec::new(), &PeerExtendedMessageIds::default)
+            .is_err());
+    }
+
+    #[test]
+    fn test_minimal_extended_message() {
+        // lt_donthave is the shortest extended message: the two ids and a piece index.
+        let buf = [0, 0, 0, 6, MSGID_EXTENDED, MY_EXTENDED_LT_DONTHAVE, 0, 0, 1, 0];
+        let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
+        assert_eq!(size, buf.len());
+        assert!(matches!(
+            msg,
+            Message::Extended(ExtendedMessage::LtDonthave(256))
+        ));
+
+        // Not even the extended message id.
+        assert!(matches!(
+            MessageBorrowed::deserialize(&[0, 0, 0, 1, MSGID_EXTENDED]),
+            Err(MessageDeserializeError::IncorrectLenPrefix { .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_extended_message_is_raw() {
+        let payload: &[u8] = b"not bencode";
+        let mut buf = Vec::new();
+        let len = MessageBorrowed::Extended(ExtendedMessage::Raw(
+            MY_EXTENDED_FIRST_CUSTOM,
+            ByteBuf(payload),
+        ))
+        .serialize(&mut buf, &PeerExtendedMessageIds::default)
+        .unwrap();
+        assert_eq!(
+            &buf[5..len],
+            &[&[MY_EXTENDED_FIRST_CUSTOM], payload].concat()[..]
+        );
+
+        let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
+        assert_eq!(size, len);
+        match msg {
+            Message::Extended(ExtendedMessage::Raw(id, b)) => {
+                assert_eq!(id, MY_EXTENDED_FIRST_CUSTOM);
+                assert_eq!(b.as_ref(), payload);
+            }
+            m => panic!("unexpected message {m:?}"),
+        }
+    }
 }
@@ -0,0 +1,339 @@
+// "rqbit bench": a self-contained benchmark that seeds a synthetic torrent from one
+// in-process session and downloads it with another over loopback.
+//
+// Useful for catching performance regressions without relying on the outside world.
+
+use std::{
+    borrow::Cow,
+    io::Write,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use librqbit::{
+    create_torrent, AddTorrent, AddTorrentOptions, CreateTorrentOptions, Session, SessionOptions,
+};
+use size_format::SizeFormatterBinary as SF;
+use tracing::info;
+
+#[derive(Parser)]
+pub struct BenchOpts {
+    /// The total size of the generated torrent in MiB.
+    #[arg(long = "size-mib", default_value = "1024")]
+    size_mib: u64,
+
+    /// How many files to split the generated torrent into.
+    #[arg(long = "files", default_value = "4")]
+    num_files: u64,
+
+    /// The piece length of the generated torrent in bytes.
+    #[arg(long = "piece-length")]
+    piece_length: Option<u32>,
+
+    /// Where to create the temporary directory. Defaults to the system temp dir.
+    /// Point this to the disk you want to benchmark.
+    #[arg(long = "tmp-dir")]
+    tmp_dir: Option<PathBuf>,
+
+    /// Make the downloading session write with direct I/O.
+    #[arg(long = "direct-io")]
+    direct_io: bool,
+
+    /// Make the downloading session read and write through memory-mapped files.
+    #[arg(long = "mmap")]
+    mmap: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ResourceUsage {
+    user: Duration,
+    system: Duration,
+    blocks_in: u64,
+    blocks_out: u64,
+}
+
+impl ResourceUsage {
+    #[cfg(unix)]
+    fn current() -> Option<Self> {
+        let mut ru = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+        // SAFETY: getrusage only writes into the provided struct.
+        let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, ru.as_mut_ptr()) };
+        if ret != 0 {
+            return None;
+        }
+        // SAFETY: getrusage succeeded, so the struct is initialized.
+        let ru = unsafe { ru.assume_init() };
+        let tv = |tv: libc::timeval| {
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+        };
+        Some(Self {
+            user: tv(ru.ru_utime),
+            system: tv(ru.ru_stime),
+            blocks_in: ru.ru_inblock as u64,
+            blocks_out: ru.ru_oublock as u64,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn current() -> Option<Self> {
+        None
+    }
+
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+            blocks_in: self.blocks_in.saturating_sub(earlier.blocks_in),
+            blocks_out: self.blocks_out.saturating_sub(earlier.blocks_out),
+        }
+    }
+}
+
+// Fill the files with fast pseudo-random data (xorshift), so that nothing along the way
+// can cheat by compressing or deduplicating it.
+fn generate_files(dir: &Path, num_files: u64, total_bytes: u64) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("error creating {dir:?}"))?;
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut buf = vec![0u8; 1024 * 1024];
+    let per_file = total_bytes / num_files;
+    for idx in 0..num_files {
+        let len = if idx == num_files - 1 {
+            total_bytes - per_file * (num_files - 1)
+        } else {
+            per_file
+        };
+        let path = dir.join(format!("file_{idx}.bin"));
+        let mut file =
+            std::fs::File::create(&path).with_context(|| format!("error creating {path:?}"))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            for chunk in buf.chunks_exact_mut(8) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                chunk.copy_from_slice(&state.to_le_bytes());
+            }
+            let n = remaining.min(buf.len() as u64) as usize;
+            file.write_all(&buf[..n])
+                .with_context(|| format!("error writing to {path:?}"))?;
+            remaining -= n as u64;
+        }
+    }
+    Ok(())
+}
+
+fn mibps(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / 1024f64 / 1024f64 / elapsed.as_secs_f64()
+}
+
+fn bench_session_options(listen: bool, direct_io: bool, mmap: bool) -> SessionOptions {
+    SessionOptions {
+        disable_dht: true,
+        disable_dht_persistence: true,
+        dht_config: None,
+        persistence: false,
+        persistence_filename: None,
+        peer_id: None,
+        peer_opts: None,
+        listen_port_range: if listen { Some(35100..35200) } else { None },
+        enable_upnp_port_forwarding: false,
+        direct_io,
+        mmap,
+        preallocation: Default::default(),
+        part_files: false,
+        write_cache_size: None,
+        max_open_files: None,
+        enable_utp: false,
+        upload_rate_limit: None,
+        max_active_downloads: None,
+        max_active_seeds: None,
+        peer_limit_per_torrent: None,
+        peer_limit: None,
+        half_open_limit: None,
+        peer_request_queue_depth: None,
+        stream_readahead_pieces: None,
+        max_request_block_size: None,
+        hooks: Default::default(),
+        webhooks: Default::default(),
+        socket_binding: None,
+        extensions: Default::default(),
+    }
+}
+
+pub async fn run_bench(opts: &BenchOpts) -> anyhow::Result<()> {
+    if opts.size_mib == 0 || opts.num_files == 0 {
+        anyhow::bail!("--size-mib and --files must be positive");
+    }
+    let total_bytes = opts.size_mib * 1024 * 1024;
+
+    let tmp = match &opts.tmp_dir {
+        Some(dir) => tempfile::TempDir::with_prefix_in("rqbit_bench", dir),
+        None => tempfile::TempDir::with_prefix("rqbit_bench"),
+    }
+    .context("error creating temporary directory")?;
+    let seed_dir = tmp.path().join("seed");
+    let leech_dir = tmp.path().join("leech");
+
+    info!(
+        "generating {} in {} files at {:?}",
+        SF::new(total_bytes),
+        opts.num_files,
+        seed_dir
+    );
+    let started = Instant::now();
+    {
+        let seed_dir = seed_dir.clone();
+        let num_files = opts.num_files;
+        tokio::task::spawn_blocking(move || generate_files(&seed_dir, num_files, total_bytes))
+            .await
+            .context("file generator panicked")??;
+    }
+    let generate_time = started.elapsed();
+
+    let started = Instant::now();
+    let torrent = create_torrent(
+        &seed_dir,
+        CreateTorrentOptions {
+            piece_length: opts.piece_length,
+            ..Default::default()
+        },
+    )
+    .await
+    .context("error creating torrent")?;
+    let create_torrent_time = started.elapsed();
+    let piece_length = torrent.as_info().info.piece_length;
+    let torrent_bytes = torrent.as_bytes()?;
+
+    let seeder = Session::new_with_opts(
+        tmp.path().to_owned(),
+        bench_session_options(true, false, false),
+    )
+    .await
+    .context("error starting seeder session")?;
+    let seeder_port = seeder
+        .tcp_listen_port()
+        .context("seeder session isn't listening on TCP")?;
+
+    let started = Instant::now();
+    let seeder_handle = seeder
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes.clone())),
+            Some(AddTorrentOptions {
+                overwrite: true,
+                output_folder: Some(
+                    seed_dir
+                        .to_str()
+                        .context("temporary directory is not valid UTF-8")?
+                        .to_owned(),
+                ),
+                ..Default::default()
+            }),
+        )
+        .await
+        .context("error adding torrent to seeder")?
+        .into_handle()
+        .context("seeder didn't add the torrent")?;
+    seeder_handle
+        .wait_until_completed()
+        .await
+        .context("error checking seeder files")?;
+    let initial_check_time = started.elapsed();
+
+    let leecher = Session::new_with_opts(
+        leech_dir,
+        bench_session_options(false, opts.direct_io, opts.mmap),
+    )
+    .await
+    .context("error starting leecher session")?;
+
+    let usage_before = ResourceUsage::current();
+    let started = Instant::now();
+    let handle = leecher
+        .add_torrent(
+            AddTorrent::TorrentFileBytes(Cow::Owned(torrent_bytes)),
+            Some(AddTorrentOptions {
+                initial_peers: Some(vec![SocketAddr::new(
+                    Ipv4Addr::LOCALHOST.into(),
+                    seeder_port,
+                )]),
+                disable_trackers: true,
+                ..Default::default()
+            }),
+        )
+        .await
+        .context("error adding torrent to leecher")?
+        .into_handle()
+        .context("leecher didn't add the torrent")?;
+
+    let progress_printer = async {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let stats = handle.stats();
+            info!(
+                "downloaded {} / {}",
+                SF::new(stats.progress_bytes),
+                SF::new(stats.total_bytes)
+            );
+        }
+    };
+    tokio::select! {
+        r = handle.wait_until_completed() => r.context("error downloading")?,
+        _ = progress_printer => {},
+    }
+    let download_time = started.elapsed();
+    let usage = ResourceUsage::current()
+        .zip(usage_before)
+        .map(|(after, before)| after.since(&before));
+
+    leecher.stop().await;
+    seeder.stop().await;
+
+    info!("benchmark results:");
+    info!(
+        "  torrent: {} in {} files, piece length {}",
+        SF::new(total_bytes),
+        opts.num_files,
+        SF::new(piece_length as u64)
+    );
+    info!(
+        "  generate data: {:.2?} ({:.2} MiB/s)",
+        generate_time,
+        mibps(total_bytes, generate_time)
+    );
+    info!(
+        "  create torrent: {:.2?} ({:.2} MiB/s)",
+        create_torrent_time,
+        mibps(total_bytes, create_torrent_time)
+    );
+    info!(
+        "  seeder initial check: {:.2?} ({:.2} MiB/s)",
+        initial_check_time,
+        mibps(total_bytes, initial_check_time)
+    );
+    info!(
+        "  download: {:.2?} ({:.2} MiB/s)",
+        download_time,
+        mibps(total_bytes, download_time)
+    );
+    match usage {
+        Some(usage) => {
+            let cpu = usage.user + usage.system;
+            info!(
+                "  CPU during download (seeder and leecher): user {:.2?}, system {:.2?}, {:.0}% of one core",
+                usage.user,
+                usage.system,
+                cpu.as_secs_f64() / download_time.as_secs_f64() * 100f64
+            );
+            info!(
+                "  disk during download: {} block reads, {} block writes",
+                usage.blocks_in, usage.blocks_out
+            );
+        }
+        None => info!("  CPU and disk usage are not available on this platform"),
+    }
+    Ok(())
+}
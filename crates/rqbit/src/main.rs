@@ -1,19 +1,33 @@
-use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
 use clap::{CommandFactory, Parser, ValueEnum};
 use clap_complete::Shell;
 use librqbit::{
     api::ApiAddTorrentResponse,
-    http_api::{HttpApi, HttpApiOptions},
+    dht::{DhtRateLimits, Id20, PersistentDhtConfig},
+    http_api::{HttpApi, HttpApiAuth, HttpApiOptions},
     http_api_client, librqbit_spawn,
     tracing_subscriber_config_utils::{init_logging, InitLoggingOptions},
     AddTorrent, AddTorrentOptions, AddTorrentResponse, Api, ListOnlyResponse,
-    PeerConnectionOptions, Session, SessionOptions, TorrentStatsState,
+    PeerConnectionOptions, PeerSocketBinding, Preallocation, Session, SessionOptions, TorrentHooks,
+    TorrentStatsState, Webhook,
 };
 use size_format::SizeFormatterBinary as SF;
 use tracing::{error, error_span, info, trace_span, warn};
 
+mod bench;
+
+// How long to wait for torrents to stop cleanly on Ctrl-C.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum LogLevel {
     Trace,
@@ -48,6 +62,25 @@ struct Opts {
     #[arg(long = "http-api-listen-addr", default_value = "127.0.0.1:3030")]
     http_api_listen_addr: SocketAddr,
 
+    /// Require HTTP basic auth for the HTTP API, in the form "username:password"
+    #[arg(long = "http-api-basic-auth", env = "RQBIT_HTTP_API_BASIC_AUTH", value_parser = parse_basic_auth)]
+    http_api_basic_auth: Option<(String, String)>,
+
+    /// Require this token for the HTTP API, sent as "Authorization: Bearer <token>"
+    #[arg(long = "http-api-token", env = "RQBIT_HTTP_API_TOKEN")]
+    http_api_token: Option<String>,
+
+    /// Allow browsers to call the HTTP API from this origin, e.g. "https://example.com".
+    /// Can be repeated, "*" allows any origin.
+    #[arg(long = "http-api-allowed-origin")]
+    http_api_allowed_origins: Vec<String>,
+
+    /// The listen address for the gRPC API. It uses the same credentials as the HTTP API.
+    /// Not started if not set.
+    #[cfg(feature = "grpc")]
+    #[arg(long = "grpc-listen-addr")]
+    grpc_listen_addr: Option<SocketAddr>,
+
     /// Set this flag if you want to use tokio's single threaded runtime.
     /// It MAY perform better, but the main purpose is easier debugging, as time
     /// profilers work better with this one.
@@ -63,6 +96,32 @@ struct Opts {
     #[arg(long = "disable-dht-persistence")]
     disable_dht_persistence: bool,
 
+    /// Limit outgoing DHT packets per second. Lower this if your router struggles
+    /// with many UDP connections. Defaults to 250.
+    #[arg(long = "dht-max-packets-per-second")]
+    dht_max_packets_per_second: Option<NonZeroU32>,
+
+    /// Limit outgoing DHT traffic to this many bytes per second.
+    #[arg(long = "dht-max-bytes-per-second")]
+    dht_max_bytes_per_second: Option<NonZeroU32>,
+
+    /// Only query the DHT, without answering other nodes or storing peers for them (BEP 43).
+    #[arg(long = "dht-read-only")]
+    dht_read_only: bool,
+
+    /// Bootstrap the DHT from this node ("host:port") instead of the built-in ones.
+    /// Can be repeated.
+    #[arg(long = "dht-bootstrap-node")]
+    dht_bootstrap_nodes: Vec<String>,
+
+    /// Don't bootstrap the DHT, e.g. for isolated networks.
+    #[arg(long = "dht-no-bootstrap", conflicts_with = "dht_bootstrap_nodes")]
+    dht_no_bootstrap: bool,
+
+    /// Use this DHT node id (40 hex characters) instead of the persisted or a random one.
+    #[arg(long = "dht-node-id")]
+    dht_node_id: Option<Id20>,
+
     /// The connect timeout, e.g. 1s, 1.5s, 100ms etc.
     #[arg(long = "peer-connect-timeout", value_parser = parse_duration::parse, default_value="2s")]
     peer_connect_timeout: Duration,
@@ -71,6 +130,14 @@ struct Opts {
     #[arg(long = "peer-read-write-timeout" , value_parser = parse_duration::parse, default_value="10s")]
     peer_read_write_timeout: Duration,
 
+    /// Drop peers that send nothing, not even keep-alives, for this long, e.g. 3m, 180s.
+    #[arg(long = "peer-idle-timeout", value_parser = parse_duration::parse, default_value="180s")]
+    peer_idle_timeout: Duration,
+
+    /// Drop peers that have pieces we need, but send none of them for this long, e.g. 5m, 300s.
+    #[arg(long = "peer-inactivity-timeout", value_parser = parse_duration::parse, default_value="300s")]
+    peer_inactivity_timeout: Duration,
+
     /// How many threads to spawn for the executor.
     #[arg(short = 't', long)]
     worker_threads: Option<usize>,
@@ -91,6 +158,113 @@ struct Opts {
     #[arg(long = "disable-upnp")]
     disable_upnp: bool,
 
+    /// Write downloaded data with direct I/O (O_DIRECT on Linux, unbuffered on Windows)
+    /// to avoid evicting the OS page cache. Ignored on other platforms.
+    #[arg(long = "direct-io")]
+    direct_io: bool,
+
+    /// Read and write downloaded data through memory-mapped files. Fewer syscalls and copies,
+    /// useful on fast networks where disk I/O is the bottleneck.
+    #[arg(long = "mmap")]
+    mmap: bool,
+
+    /// Allocate all the disk space of files when they are opened for download, instead of
+    /// creating sparse files. Avoids fragmentation and running out of space mid-download.
+    #[arg(long = "preallocate-full")]
+    preallocate_full: bool,
+
+    /// Download files with a ".part" suffix, and rename them once all their pieces are
+    /// verified, so that other programs never see incomplete files.
+    #[arg(long = "part-files")]
+    part_files: bool,
+
+    /// Keep up to this many MiB of downloaded data per torrent in memory, and write each piece
+    /// to disk at once. Speeds up disks that are slow at small random writes.
+    #[arg(long = "write-cache-mib")]
+    write_cache_mib: Option<usize>,
+
+    /// The most files to keep open at the same time, across all torrents. The least recently
+    /// used ones are closed and reopened when needed [default: 512]
+    #[arg(long = "max-open-files")]
+    max_open_files: Option<NonZeroUsize>,
+
+    /// Enable uTP (BEP 29). Peers are dialed over uTP first with TCP fallback, and incoming
+    /// uTP connections are accepted on the TCP listen port.
+    #[arg(long = "enable-utp")]
+    enable_utp: bool,
+
+    /// Limit uploads of all torrents together to this many bytes per second.
+    #[arg(long = "upload-rate-limit")]
+    upload_rate_limit: Option<NonZeroU32>,
+
+    /// Download at most this many torrents at the same time, queueing the rest.
+    #[arg(long = "max-active-downloads")]
+    max_active_downloads: Option<usize>,
+
+    /// Seed at most this many finished torrents at the same time, queueing the rest.
+    #[arg(long = "max-active-seeds")]
+    max_active_seeds: Option<usize>,
+
+    /// The most peers each torrent is connected to at the same time [default: 128]
+    #[arg(long = "peer-limit-per-torrent")]
+    peer_limit_per_torrent: Option<NonZeroUsize>,
+
+    /// The most peers all torrents together are connected to at the same time.
+    #[arg(long = "peer-limit")]
+    peer_limit: Option<NonZeroUsize>,
+
+    /// The most outgoing peer connections being established at the same time.
+    #[arg(long = "half-open-limit")]
+    half_open_limit: Option<NonZeroUsize>,
+
+    /// The most chunk requests in flight to one peer [default: sized from the peer's speed and
+    /// round trip time, between 16 and what the peer allows, up to 250]
+    #[arg(long = "peer-request-queue-depth")]
+    peer_request_queue_depth: Option<NonZeroUsize>,
+
+    /// How many pieces after the position a stream reads at to download before all others
+    /// [default: 8]
+    #[arg(long = "stream-readahead-pieces")]
+    stream_readahead_pieces: Option<NonZeroU32>,
+
+    /// The largest block peers may request from us, in bytes [default: 262144, the most allowed]
+    #[arg(long = "max-request-block-size")]
+    max_request_block_size: Option<NonZeroU32>,
+
+    /// A shell command to run when a torrent is added. It gets the torrent's details in
+    /// RQBIT_EVENT, RQBIT_INFO_HASH, RQBIT_TORRENT_NAME and RQBIT_TORRENT_PATH.
+    #[arg(long = "on-torrent-added")]
+    on_torrent_added: Option<String>,
+
+    /// A shell command to run when a torrent finishes downloading. It gets the same variables
+    /// as --on-torrent-added.
+    #[arg(long = "on-torrent-finished")]
+    on_torrent_finished: Option<String>,
+
+    /// A shell command to run when a torrent stops with an error. It gets the same variables as
+    /// --on-torrent-added, and the error in RQBIT_ERROR.
+    #[arg(long = "on-torrent-error")]
+    on_torrent_error: Option<String>,
+
+    /// A URL to POST JSON to when a torrent finishes, fails or stalls. Can be given several
+    /// times.
+    #[arg(long = "webhook-url")]
+    webhook_urls: Vec<String>,
+
+    /// Sign webhook requests with HMAC-SHA256 using this key. The signature is sent in the
+    /// X-Rqbit-Signature header as "sha256=<hex>".
+    #[arg(long = "webhook-secret", env = "RQBIT_WEBHOOK_SECRET")]
+    webhook_secret: Option<String>,
+
+    /// Bind outgoing peer connections, tracker requests and the DHT socket to this local IP.
+    #[arg(long = "outgoing-addr")]
+    outgoing_addr: Option<IpAddr>,
+
+    /// Bind outgoing peer connections, tracker requests and the DHT socket to this network
+    /// interface (Linux only).
+    #[arg(long = "outgoing-interface")]
+    outgoing_interface: Option<String>,
+
     #[command(subcommand)]
     subcommand: SubCommand,
 }
@@ -158,11 +332,60 @@ struct DownloadOpts {
 
     #[arg(long = "initial-peers")]
     initial_peers: Option<InitialPeers>,
+
+    /// Bind outgoing peer connections to this network interface, e.g. a VPN one (Linux only).
+    #[arg(long = "bind-interface")]
+    bind_interface: Option<String>,
+
+    /// Set this firewall mark (SO_MARK) on outgoing peer connections (Linux only).
+    #[arg(long = "fwmark")]
+    fwmark: Option<u32>,
+
+    /// Bind outgoing peer connections and tracker requests to this local IP.
+    #[arg(long = "bind-addr")]
+    bind_addr: Option<IpAddr>,
+
+    /// Download pieces in order, e.g. to play media while it's downloading.
+    #[arg(long)]
+    sequential: bool,
 }
 
 #[derive(Clone)]
 struct InitialPeers(Vec<SocketAddr>);
 
+fn parse_basic_auth(s: &str) -> anyhow::Result<(String, String)> {
+    let (user, pass) = s
+        .split_once(':')
+        .context("expected the form \"username:password\"")?;
+    Ok((user.to_owned(), pass.to_owned()))
+}
+
+impl Opts {
+    fn http_api_auth(&self) -> HttpApiAuth {
+        HttpApiAuth {
+            basic: self.http_api_basic_auth.clone(),
+            bearer_token: self.http_api_token.clone(),
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    fn spawn_grpc_api(&self, api: &Api, read_only: bool) {
+        use librqbit::grpc_api::{GrpcApi, GrpcApiOptions};
+
+        let Some(addr) = self.grpc_listen_addr else {
+            return;
+        };
+        let grpc_api = GrpcApi::new(
+            api.clone(),
+            Some(GrpcApiOptions {
+                read_only,
+                auth: self.http_api_auth(),
+            }),
+        );
+        librqbit_spawn("grpc_api", error_span!("grpc_api"), grpc_api.run(addr));
+    }
+}
+
 impl From<&str> for InitialPeers {
     fn from(s: &str) -> Self {
         let mut v = Vec::new();
@@ -187,6 +410,9 @@ enum SubCommand {
     Server(ServerOpts),
     Download(DownloadOpts),
     Completions(CompletionsOpts),
+    /// Seed a generated torrent and download it over loopback within this process,
+    /// reporting throughput, CPU and disk usage.
+    Bench(bench::BenchOpts),
 }
 
 fn _start_deadlock_detector_thread() {
@@ -262,7 +488,22 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
     let mut sopts = SessionOptions {
         disable_dht: opts.disable_dht,
         disable_dht_persistence: opts.disable_dht_persistence,
-        dht_config: None,
+        dht_config: Some(PersistentDhtConfig {
+            rate_limits: DhtRateLimits {
+                packets_per_second: opts.dht_max_packets_per_second,
+                bytes_per_second: opts.dht_max_bytes_per_second,
+            },
+            read_only: opts.dht_read_only,
+            bootstrap_addrs: if opts.dht_no_bootstrap {
+                Some(Vec::new())
+            } else if !opts.dht_bootstrap_nodes.is_empty() {
+                Some(opts.dht_bootstrap_nodes.clone())
+            } else {
+                None
+            },
+            peer_id: opts.dht_node_id,
+            ..Default::default()
+        }),
         // This will be overriden by "server start" below if needed.
         persistence: false,
         persistence_filename: None,
@@ -270,6 +511,8 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
         peer_opts: Some(PeerConnectionOptions {
             connect_timeout: Some(opts.peer_connect_timeout),
             read_write_timeout: Some(opts.peer_read_write_timeout),
+            idle_timeout: Some(opts.peer_idle_timeout),
+            inactivity_timeout: Some(opts.peer_inactivity_timeout),
             ..Default::default()
         }),
         listen_port_range: if !opts.disable_tcp_listen {
@@ -278,6 +521,46 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
             None
         },
         enable_upnp_port_forwarding: !opts.disable_upnp,
+        direct_io: opts.direct_io,
+        mmap: opts.mmap,
+        preallocation: if opts.preallocate_full {
+            Preallocation::Full
+        } else {
+            Preallocation::Sparse
+        },
+        part_files: opts.part_files,
+        write_cache_size: opts.write_cache_mib.map(|mib| mib * 1024 * 1024),
+        max_open_files: opts.max_open_files,
+        enable_utp: opts.enable_utp,
+        upload_rate_limit: opts.upload_rate_limit,
+        max_active_downloads: opts.max_active_downloads,
+        max_active_seeds: opts.max_active_seeds,
+        peer_limit_per_torrent: opts.peer_limit_per_torrent,
+        peer_limit: opts.peer_limit,
+        half_open_limit: opts.half_open_limit,
+        peer_request_queue_depth: opts.peer_request_queue_depth,
+        stream_readahead_pieces: opts.stream_readahead_pieces,
+        max_request_block_size: opts.max_request_block_size,
+        hooks: TorrentHooks {
+            on_added: opts.on_torrent_added.clone(),
+            on_finished: opts.on_torrent_finished.clone(),
+            on_error: opts.on_torrent_error.clone(),
+        },
+        webhooks: opts
+            .webhook_urls
+            .iter()
+            .map(|url| Webhook {
+                url: url.clone(),
+                secret: opts.webhook_secret.clone(),
+                events: Vec::new(),
+            })
+            .collect(),
+        socket_binding: Some(PeerSocketBinding {
+            interface: opts.outgoing_interface.clone(),
+            fwmark: None,
+            local_addr: opts.outgoing_addr,
+        }),
+        extensions: Default::default(),
     };
 
     let stats_printer = |session: Arc<Session>| async move {
@@ -348,16 +631,30 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     stats_printer(session.clone()),
                 );
                 let api = Api::new(
-                    session,
+                    session.clone(),
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: false }));
+                #[cfg(feature = "grpc")]
+                opts.spawn_grpc_api(&api, false);
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: false,
+                        auth: opts.http_api_auth(),
+                        cors_allowed_origins: opts.http_api_allowed_origins.clone(),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
-                http_api
-                    .make_http_api_and_run(http_api_listen_addr)
-                    .await
-                    .context("error running HTTP API")
+                tokio::select! {
+                    r = http_api.make_http_api_and_run(http_api_listen_addr) => {
+                        r.context("error running HTTP API")
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        info!("shutting down");
+                        Ok(session.shutdown(SHUTDOWN_TIMEOUT).await?)
+                    }
+                }
             }
         },
         SubCommand::Download(download_opts) => {
@@ -365,7 +662,8 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 anyhow::bail!("you must provide at least one URL to download")
             }
             let http_api_url = format!("http://{}", opts.http_api_listen_addr);
-            let client = http_api_client::HttpApiClient::new(&http_api_url)?;
+            let client = http_api_client::HttpApiClient::new(&http_api_url)?
+                .with_auth(&opts.http_api_auth())?;
             let torrent_opts = AddTorrentOptions {
                 only_files_regex: download_opts.only_files_matching_regex.clone(),
                 overwrite: download_opts.overwrite,
@@ -375,6 +673,12 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 sub_folder: download_opts.sub_folder.clone(),
                 initial_peers: download_opts.initial_peers.clone().map(|p| p.0),
                 disable_trackers: download_opts.disable_trackers,
+                socket_binding: Some(PeerSocketBinding {
+                    interface: download_opts.bind_interface.clone(),
+                    fwmark: download_opts.fwmark,
+                    local_addr: download_opts.bind_addr,
+                }),
+                sequential: download_opts.sequential,
                 ..Default::default()
             };
             let connect_to_existing = match client.validate_rqbit_server().await {
@@ -437,7 +741,16 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: true }));
+                #[cfg(feature = "grpc")]
+                opts.spawn_grpc_api(&api, true);
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: true,
+                        auth: opts.http_api_auth(),
+                        cors_allowed_origins: opts.http_api_allowed_origins.clone(),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
                 librqbit_spawn(
                     "http_api",
@@ -463,7 +776,7 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                                     "torrent {:?} is already managed, id={}, downloaded to {:?}",
                                     handle.info_hash(),
                                     id,
-                                    handle.info().out_dir
+                                    handle.info().out_dir()
                                 );
                                 continue;
                             }
@@ -517,16 +830,18 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                         info!("All downloads completed, exiting");
                         Ok(())
                     } else {
-                        // Sleep forever.
-                        loop {
-                            tokio::time::sleep(Duration::from_secs(60)).await;
-                        }
+                        tokio::signal::ctrl_c()
+                            .await
+                            .context("error waiting for Ctrl-C")?;
+                        info!("shutting down");
+                        Ok(session.shutdown(SHUTDOWN_TIMEOUT).await?)
                     }
                 } else {
                     anyhow::bail!("no torrents were added")
                 }
             }
         }
+        SubCommand::Bench(bench_opts) => bench::run_bench(bench_opts).await,
         SubCommand::Completions(completions_opts) => {
             clap_complete::generate(
                 completions_opts.shell,
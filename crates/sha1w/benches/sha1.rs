@@ -0,0 +1,26 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use librqbit_sha1_wrapper::{ISha1, Sha1System};
+
+// Typical piece sizes.
+const PIECE_LENGTHS: [usize; 3] = [256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+
+fn bench_backend<H: ISha1>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("sha1");
+    for len in PIECE_LENGTHS {
+        let data = vec![0xabu8; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new(name, len), &data, |b, data| {
+            b.iter(|| H::digest(black_box(data)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_sha1(c: &mut Criterion) {
+    bench_backend::<Sha1System>(c, "system");
+    #[cfg(feature = "sha1-rust")]
+    bench_backend::<librqbit_sha1_wrapper::Sha1Rust>(c, "rust");
+}
+
+criterion_group!(benches, bench_sha1);
+criterion_main!(benches);
@@ -4,13 +4,27 @@
 // Sha1 computation is the majority of CPU usage of librqbit.
 // openssl is 2-3x faster than rust's sha1.
 // system library is the best choice probably (it's the default anyway).
+// On CPUs with SHA extensions (SHA-NI, ARMv8 crypto) the "sha1-rust" feature may be
+// faster still, run "cargo bench" to compare on the target machine.
 
+#[cfg(not(feature = "sha1-rust"))]
 pub type Sha1 = Sha1System;
+#[cfg(feature = "sha1-rust")]
+pub type Sha1 = Sha1Rust;
 
 pub trait ISha1 {
     fn new() -> Self;
     fn update(&mut self, buf: &[u8]);
     fn finish(self) -> [u8; 20];
+
+    fn digest(buf: &[u8]) -> [u8; 20]
+    where
+        Self: Sized,
+    {
+        let mut h = Self::new();
+        h.update(buf);
+        h.finish()
+    }
 }
 
 pub struct Sha1System {
@@ -37,3 +51,94 @@ impl ISha1 for Sha1System {
         result_arr
     }
 }
+
+// RustCrypto's implementation. Picks the hardware accelerated code at runtime if the
+// CPU supports it.
+#[cfg(feature = "sha1-rust")]
+pub struct Sha1Rust {
+    inner: sha1::Sha1,
+}
+
+#[cfg(feature = "sha1-rust")]
+impl ISha1 for Sha1Rust {
+    fn new() -> Self {
+        Self {
+            inner: sha1::Digest::new(),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        sha1::Digest::update(&mut self.inner, buf);
+    }
+
+    fn finish(self) -> [u8; 20] {
+        sha1::Digest::finalize(self.inner).into()
+    }
+}
+
+// SHA-256, used by BitTorrent v2 (BEP 52) for info hashes and piece merkle trees.
+pub type Sha256 = Sha256System;
+
+pub trait ISha256 {
+    fn new() -> Self;
+    fn update(&mut self, buf: &[u8]);
+    fn finish(self) -> [u8; 32];
+}
+
+pub struct Sha256System {
+    inner: crypto_hash::Hasher,
+}
+
+impl ISha256 for Sha256System {
+    fn new() -> Self {
+        Self {
+            inner: crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        use std::io::Write;
+        self.inner.write_all(buf).unwrap();
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let result = self.inner.finish();
+        debug_assert_eq!(result.len(), 32);
+        let mut result_arr = [0u8; 32];
+        result_arr.copy_from_slice(&result);
+        result_arr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_sha1<H: ISha1>() {
+        assert_eq!(
+            H::digest(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut h = H::new();
+        for chunk in data.chunks(777) {
+            h.update(chunk);
+        }
+        assert_eq!(h.finish(), Sha1System::digest(&data));
+    }
+
+    #[test]
+    fn test_sha1_system() {
+        check_sha1::<Sha1System>();
+    }
+
+    #[cfg(feature = "sha1-rust")]
+    #[test]
+    fn test_sha1_rust() {
+        check_sha1::<Sha1Rust>();
+    }
+}
@@ -1,23 +1,27 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
-use futures::future::Either;
 use futures::stream::BoxStream;
 use futures::stream::FuturesUnordered;
-use futures::FutureExt;
 use futures::StreamExt;
+use rand::seq::SliceRandom;
 use tracing::debug;
+use tracing::debug_span;
 use tracing::error_span;
 use tracing::trace;
+use tracing::warn;
 use tracing::Instrument;
 use url::Url;
 
 use crate::tracker_comms_http;
 use crate::tracker_comms_udp;
 use librqbit_core::hash_id::Id20;
+use librqbit_core::socket_binding::SocketBinding;
 
 pub struct TrackerComms {
     info_hash: Id20,
@@ -26,6 +30,8 @@ pub struct TrackerComms {
     force_tracker_interval: Option<Duration>,
     tx: Sender,
     tcp_listen_port: Option<u16>,
+    socket_binding: SocketBinding,
+    http_client: reqwest::Client,
 }
 
 #[derive(Default)]
@@ -62,6 +68,15 @@ impl TrackerCommsStats {
 
 pub trait TorrentStatsProvider: Send + Sync {
     fn get(&self) -> TrackerCommsStats;
+
+    // Called when an announce to one of the trackers succeeds.
+    fn on_tracker_announce(&self, _tracker: &str, _response: &TrackerAnnounceResponse) {}
+
+    // Called when an announce to one of the trackers fails.
+    fn on_tracker_error(&self, _tracker: &str, _error: &anyhow::Error) {}
+
+    // Called with how long until a tracker that was just tried is announced to again.
+    fn on_next_announce(&self, _tracker: &str, _after: Duration) {}
 }
 
 impl TorrentStatsProvider for () {
@@ -77,35 +92,78 @@ enum SupportedTracker {
     Http(Url),
 }
 
+impl SupportedTracker {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let parsed = Url::parse(url).with_context(|| format!("error parsing tracker URL {url}"))?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(SupportedTracker::Http(parsed)),
+            "udp" => Ok(SupportedTracker::Udp(parsed)),
+            _ => bail!("unsupported tracker URL: {url}"),
+        }
+    }
+
+    fn url(&self) -> &Url {
+        match self {
+            SupportedTracker::Udp(url) | SupportedTracker::Http(url) => url,
+        }
+    }
+}
+
+/// The response of a single announce to a tracker.
+#[derive(Debug, Clone)]
+pub struct TrackerAnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    /// How long the tracker asked to wait until the next announce.
+    pub interval: Duration,
+    /// Seeders and leechers of the torrent, if the tracker reported them.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Announcing more often than this is not allowed by the tracker.
+    pub min_interval: Option<Duration>,
+    /// Sent back to the tracker on the next announce.
+    pub tracker_id: Option<String>,
+}
+
 impl TrackerComms {
+    /// Announce to the trackers of each tier (BEP 12). Within a tier, the trackers are tried
+    /// in order until one works, which is then moved to the front of the tier.
     pub fn start(
         info_hash: Id20,
         peer_id: Id20,
-        trackers: Vec<String>,
+        tiers: Vec<Vec<String>>,
         stats: Box<dyn TorrentStatsProvider>,
         force_interval: Option<Duration>,
         tcp_listen_port: Option<u16>,
+        socket_binding: SocketBinding,
     ) -> Option<BoxStream<'static, SocketAddr>> {
-        let trackers = trackers
+        let tiers = tiers
             .into_iter()
-            .filter_map(|t| match Url::parse(&t) {
-                Ok(parsed) => match parsed.scheme() {
-                    "http" | "https" => Some(SupportedTracker::Http(parsed)),
-                    "udp" => Some(SupportedTracker::Udp(parsed)),
-                    _ => {
-                        debug!("unsuppoted tracker URL: {}", t);
-                        None
-                    }
-                },
-                Err(e) => {
-                    debug!("error parsing tracker URL {}: {}", t, e);
-                    None
-                }
+            .map(|tier| {
+                let mut tier = tier
+                    .into_iter()
+                    .filter_map(|t| match SupportedTracker::parse(&t) {
+                        Ok(t) => Some(t),
+                        Err(e) => {
+                            debug!("{e:#}");
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                tier.shuffle(&mut rand::thread_rng());
+                tier
             })
+            .filter(|tier| !tier.is_empty())
             .collect::<Vec<_>>();
-        if trackers.is_empty() {
+        if tiers.is_empty() {
             return None;
         }
+        let http_client = match http_client(&socket_binding, None) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("not announcing to trackers: {e:#}");
+                return None;
+            }
+        };
 
         let (tx, mut rx) = tokio::sync::mpsc::channel::<SocketAddr>(16);
 
@@ -118,10 +176,13 @@ impl TrackerComms {
                 force_tracker_interval: force_interval,
                 tx,
                 tcp_listen_port,
+                socket_binding,
+                http_client,
             });
             let mut futures = FuturesUnordered::new();
-            for tracker in trackers {
-                futures.push(comms.add_tracker(tracker))
+            for (idx, tier) in tiers.into_iter().enumerate() {
+                let span = error_span!(parent: None, "tracker_tier", tier = idx, info_hash = ?info_hash);
+                futures.push(comms.task_tier(tier).instrument(span))
             }
             while !(futures.is_empty()) {
                 tokio::select! {
@@ -142,166 +203,380 @@ impl TrackerComms {
         Some(s.boxed())
     }
 
-    fn add_tracker(
-        &self,
-        url: SupportedTracker,
-    ) -> Either<
-        impl std::future::Future<Output = anyhow::Result<()>> + '_ + Send,
-        impl std::future::Future<Output = anyhow::Result<()>> + '_ + Send,
-    > {
-        let info_hash = self.info_hash;
-        match url {
-            SupportedTracker::Udp(url) => {
-                let span = error_span!(parent: None, "udp_tracker", tracker = %url, info_hash = ?info_hash);
-                self.task_single_tracker_monitor_udp(url)
+    async fn task_tier(&self, mut tier: Vec<SupportedTracker>) -> anyhow::Result<()> {
+        // The trackers that were sent the "started" event already.
+        let mut started = HashSet::new();
+        let mut tracker_ids = HashMap::new();
+        loop {
+            let stats = self.stats.get();
+            let mut interval = None;
+            let mut min_interval = None;
+            let mut tried = Vec::new();
+            for idx in 0..tier.len() {
+                let url = tier[idx].url().clone();
+                let announce = Announce {
+                    info_hash: self.info_hash,
+                    peer_id: self.peer_id,
+                    stats: &stats,
+                    event: if started.contains(&url) {
+                        None
+                    } else {
+                        Some(tracker_comms_http::TrackerRequestEvent::Started)
+                    },
+                    tcp_listen_port: self.tcp_listen_port,
+                    socket_binding: &self.socket_binding,
+                    tracker_id: tracker_ids.get(&url).map(String::as_str),
+                };
+                tried.push(url.clone());
+                let span = debug_span!("announce", tracker = %url);
+                match announce
+                    .run(&tier[idx], &self.http_client)
                     .instrument(span)
-                    .right_future()
+                    .await
+                {
+                    Ok(response) => {
+                        trace!(tracker = %url, peers = response.peers.len(), "announced");
+                        self.stats.on_tracker_announce(url.as_str(), &response);
+                        for peer in response.peers {
+                            self.tx.send(peer).await.context("rx closed")?;
+                        }
+                        if let Some(id) = response.tracker_id {
+                            tracker_ids.insert(url.clone(), id);
+                        }
+                        started.insert(url);
+                        tier[..=idx].rotate_right(1);
+                        interval = Some(response.interval);
+                        min_interval = response.min_interval;
+                        break;
+                    }
+                    Err(e) => {
+                        debug!(tracker = %url, "error announcing: {e:#}");
+                        self.stats.on_tracker_error(url.as_str(), &e);
+                    }
+                }
+            }
+            // If all the trackers of the tier failed, retry in a minute. A forced interval
+            // still doesn't go below the tracker's minimum.
+            let interval = match self.force_tracker_interval {
+                Some(forced) => forced.max(min_interval.unwrap_or_default()),
+                None => interval.unwrap_or(Duration::from_secs(60)),
+            };
+            for url in tried {
+                self.stats.on_next_announce(url.as_str(), interval);
             }
+            trace!(?interval, "sleeping");
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Announce to one tracker once, outside of the regular announce schedule.
+    /// Announcing a paused torrent sends the "stopped" event.
+    pub async fn announce_once(
+        info_hash: Id20,
+        peer_id: Id20,
+        tracker: &str,
+        stats: &TrackerCommsStats,
+        tcp_listen_port: Option<u16>,
+        socket_binding: &SocketBinding,
+    ) -> anyhow::Result<TrackerAnnounceResponse> {
+        let tracker = SupportedTracker::parse(tracker)?;
+        let announce = Announce {
+            info_hash,
+            peer_id,
+            stats,
+            event: match stats.torrent_state {
+                TrackerCommsStatsState::Paused => {
+                    Some(tracker_comms_http::TrackerRequestEvent::Stopped)
+                }
+                _ => None,
+            },
+            tcp_listen_port,
+            socket_binding,
+            tracker_id: None,
+        };
+        announce
+            .run(&tracker, &http_client(socket_binding, None)?)
+            .await
+    }
+}
+
+// The parameters of one announce.
+struct Announce<'a> {
+    info_hash: Id20,
+    peer_id: Id20,
+    stats: &'a TrackerCommsStats,
+    // UDP trackers get the event from the stats instead.
+    event: Option<tracker_comms_http::TrackerRequestEvent>,
+    tcp_listen_port: Option<u16>,
+    socket_binding: &'a SocketBinding,
+    // What the tracker returned as "tracker id" last time, HTTP only.
+    tracker_id: Option<&'a str>,
+}
+
+impl Announce<'_> {
+    // Trackers resolving to both address families are announced to over each, so that they
+    // return both IPv4 and IPv6 peers.
+    async fn run(
+        &self,
+        tracker: &SupportedTracker,
+        client: &reqwest::Client,
+    ) -> anyhow::Result<TrackerAnnounceResponse> {
+        match tracker {
             SupportedTracker::Http(url) => {
-                let span = error_span!(
-                    parent: None,
-                    "http_tracker",
-                    tracker = %url,
-                    info_hash = ?info_hash
-                );
-                self.task_single_tracker_monitor_http(url)
-                    .instrument(span)
-                    .left_future()
+                let mut url = url.clone();
+                url.set_query(Some(&self.http_request().as_querystring()));
+                let addrs = match (url.host_str(), url.port_or_known_default()) {
+                    (Some(host), Some(port)) => resolve_tracker(host, port, self.socket_binding)
+                        .await
+                        .map_err(|e| debug!("{e:#}"))
+                        .unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+                if addrs.len() < 2 {
+                    // Let the HTTP client resolve and report errors when it can't.
+                    return http_announce(client, url).await;
+                }
+                let host = url.host_str().unwrap_or_default();
+                let responses = futures::future::join_all(addrs.into_iter().map(|addr| {
+                    let url = url.clone();
+                    async move {
+                        // Pin the client to this address.
+                        let pinned = http_client(self.socket_binding, Some((host, addr)))?;
+                        http_announce(&pinned, url).await
+                    }
+                }))
+                .await;
+                merge_responses(responses)
+            }
+            SupportedTracker::Udp(url) => {
+                let addrs = resolve_tracker(
+                    url.host_str().context("missing host")?,
+                    url.port().context("missing port")?,
+                    self.socket_binding,
+                )
+                .await?;
+                let responses =
+                    futures::future::join_all(addrs.into_iter().map(|addr| self.udp(addr))).await;
+                merge_responses(responses)
             }
         }
     }
 
-    async fn task_single_tracker_monitor_http(&self, mut tracker_url: Url) -> anyhow::Result<()> {
-        let mut event = Some(tracker_comms_http::TrackerRequestEvent::Started);
-        loop {
-            let stats = self.stats.get();
-            let request = tracker_comms_http::TrackerRequest {
+    fn http_request(&self) -> tracker_comms_http::TrackerRequest {
+        tracker_comms_http::TrackerRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.tcp_listen_port.unwrap_or(0),
+            uploaded: self.stats.uploaded_bytes,
+            downloaded: self.stats.downloaded_bytes,
+            left: self.stats.get_left_to_download_bytes(),
+            compact: true,
+            no_peer_id: false,
+            event: self.event,
+            ip: None,
+            numwant: None,
+            key: None,
+            trackerid: self.tracker_id.map(str::to_owned),
+        }
+    }
+
+    async fn udp(&self, addr: SocketAddr) -> anyhow::Result<TrackerAnnounceResponse> {
+        use tracker_comms_udp::*;
+
+        let mut requester = UdpTrackerRequester::new(addr, self.socket_binding)
+            .await
+            .with_context(|| format!("error creating UDP tracker requester for {addr}"))?;
+        let response = requester
+            .announce(AnnounceFields {
                 info_hash: self.info_hash,
                 peer_id: self.peer_id,
+                downloaded: self.stats.downloaded_bytes,
+                left: self.stats.get_left_to_download_bytes(),
+                uploaded: self.stats.uploaded_bytes,
+                event: udp_event(self.stats),
+                key: 0,
                 port: self.tcp_listen_port.unwrap_or(0),
-                uploaded: stats.uploaded_bytes,
-                downloaded: stats.downloaded_bytes,
-                left: stats.get_left_to_download_bytes(),
-                compact: true,
-                no_peer_id: false,
-                event,
-                ip: None,
-                numwant: None,
-                key: None,
-                trackerid: None,
-            };
+            })
+            .await?;
+        Ok(TrackerAnnounceResponse {
+            peers: response.addrs,
+            interval: Duration::from_secs(response.interval.max(5) as u64),
+            seeders: Some(response.seeders as u64),
+            leechers: Some(response.leechers as u64),
+            min_interval: None,
+            tracker_id: None,
+        })
+    }
+}
 
-            let request_query = request.as_querystring();
-            tracker_url.set_query(Some(&request_query));
-
-            match self.tracker_one_request_http(tracker_url.clone()).await {
-                Ok(interval) => {
-                    event = None;
-                    let interval = self
-                        .force_tracker_interval
-                        .unwrap_or_else(|| Duration::from_secs(interval));
-                    debug!(
-                        "sleeping for {:?} after calling tracker {}",
-                        interval,
-                        tracker_url.host().unwrap()
-                    );
-                    tokio::time::sleep(interval).await;
-                }
-                Err(e) => {
-                    debug!("error calling the tracker {}: {:#}", tracker_url, e);
-                    tokio::time::sleep(Duration::from_secs(60)).await;
+// Succeeds if announcing over any address family did, with the peers from all of them.
+fn merge_responses(
+    responses: Vec<anyhow::Result<TrackerAnnounceResponse>>,
+) -> anyhow::Result<TrackerAnnounceResponse> {
+    let mut merged: Option<TrackerAnnounceResponse> = None;
+    let mut first_error = None;
+    for response in responses {
+        match (response, &mut merged) {
+            (Ok(r), Some(merged)) => {
+                merged.peers.extend(r.peers);
+                // Each address family may have its own swarm.
+                merged.seeders = merged.seeders.max(r.seeders);
+                merged.leechers = merged.leechers.max(r.leechers);
+                merged.min_interval = merged.min_interval.max(r.min_interval);
+                if merged.tracker_id.is_none() {
+                    merged.tracker_id = r.tracker_id;
                 }
-            };
+            }
+            (Ok(r), None) => merged = Some(r),
+            (Err(e), _) => {
+                first_error.get_or_insert(e);
+            }
         }
     }
+    match (merged, first_error) {
+        (Some(merged), _) => Ok(merged),
+        (None, Some(e)) => Err(e),
+        (None, None) => bail!("no addresses to announce to"),
+    }
+}
 
-    async fn tracker_one_request_http(&self, tracker_url: Url) -> anyhow::Result<u64> {
-        let response: reqwest::Response = reqwest::get(tracker_url).await?;
-        if !response.status().is_success() {
-            anyhow::bail!("tracker responded with {:?}", response.status());
+// The addresses to announce to, at most one per address family, so that the tracker returns
+// both IPv4 and IPv6 peers.
+async fn resolve_tracker(
+    host: &str,
+    port: u16,
+    binding: &SocketBinding,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    // URLs have IPv6 literals in brackets.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut v4 = None;
+    let mut v6 = None;
+    for addr in tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("error resolving {host}"))?
+    {
+        // A socket bound to a local address can only reach its own family.
+        if matches!(binding.local_addr, Some(ip) if ip.is_ipv4() != addr.is_ipv4()) {
+            continue;
         }
-        let bytes = response.bytes().await?;
-        if let Ok(error) = bencode::from_bytes::<tracker_comms_http::TrackerError>(&bytes) {
-            anyhow::bail!(
-                "tracker returned failure. Failure reason: {}",
-                error.failure_reason
-            )
+        match addr {
+            SocketAddr::V4(_) => v4.get_or_insert(addr),
+            SocketAddr::V6(_) => v6.get_or_insert(addr),
         };
-        let response = bencode::from_bytes::<tracker_comms_http::TrackerResponse>(&bytes)?;
+    }
+    let addrs = v4.into_iter().chain(v6).collect::<Vec<_>>();
+    if addrs.is_empty() {
+        bail!("{host} has no addresses usable with the socket binding");
+    }
+    Ok(addrs)
+}
 
-        for peer in response.peers.iter_sockaddrs() {
-            self.tx.send(peer).await?;
-        }
-        Ok(response.interval)
+// With "resolve" set, requests to the host only go to the given address.
+fn http_client(
+    binding: &SocketBinding,
+    resolve: Option<(&str, SocketAddr)>,
+) -> anyhow::Result<reqwest::Client> {
+    #[allow(unused_mut)]
+    let mut builder = reqwest::Client::builder().local_address(binding.local_addr);
+    if let Some((host, addr)) = resolve {
+        builder = builder.resolve(host, addr);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &binding.interface {
+        builder = builder.interface(interface);
     }
+    #[cfg(not(target_os = "linux"))]
+    if binding.interface.is_some() {
+        bail!("binding sockets to an interface is only supported on Linux");
+    }
+    builder.build().context("error building HTTP client")
+}
 
-    async fn task_single_tracker_monitor_udp(&self, url: Url) -> anyhow::Result<()> {
-        use tracker_comms_udp::*;
+async fn http_announce(
+    client: &reqwest::Client,
+    tracker_url: Url,
+) -> anyhow::Result<TrackerAnnounceResponse> {
+    let response: reqwest::Response = client.get(tracker_url).send().await?;
+    if !response.status().is_success() {
+        anyhow::bail!("tracker responded with {:?}", response.status());
+    }
+    let bytes = response.bytes().await?;
+    if let Ok(error) = bencode::from_bytes::<tracker_comms_http::TrackerError>(&bytes) {
+        anyhow::bail!(
+            "tracker returned failure. Failure reason: {}",
+            error.failure_reason
+        )
+    };
+    let response = bencode::from_bytes::<tracker_comms_http::TrackerResponse>(&bytes)?;
+    if let Some(message) = &response.warning_message {
+        warn!("tracker returned a warning: {message}");
+    }
+    Ok(TrackerAnnounceResponse {
+        peers: response
+            .peers
+            .iter_sockaddrs()
+            .chain(response.peers6.iter_sockaddrs())
+            .collect(),
+        interval: Duration::from_secs(response.interval),
+        seeders: Some(response.complete),
+        leechers: Some(response.incomplete),
+        min_interval: response.min_interval.map(Duration::from_secs),
+        tracker_id: response
+            .tracker_id
+            .map(|id| String::from_utf8_lossy(&id).into_owned()),
+    })
+}
 
-        if url.scheme() != "udp" {
-            bail!("expected UDP scheme in {}", url);
-        }
-        let hp: (&str, u16) = (
-            url.host_str().context("missing host")?,
-            url.port().context("missing port")?,
-        );
-        let mut requester = UdpTrackerRequester::new(hp)
-            .await
-            .context("error creating UDP tracker requester")?;
+fn udp_event(stats: &TrackerCommsStats) -> u32 {
+    use tracker_comms_udp::*;
 
-        let mut sleep_interval: Option<Duration> = None;
-        loop {
-            if let Some(i) = sleep_interval {
-                trace!(interval=?sleep_interval, "sleeping");
-                tokio::time::sleep(i).await;
+    match stats.torrent_state {
+        TrackerCommsStatsState::None => EVENT_NONE,
+        TrackerCommsStatsState::Initializing => EVENT_STARTED,
+        TrackerCommsStatsState::Paused => EVENT_STOPPED,
+        TrackerCommsStatsState::Live => {
+            if stats.is_completed() {
+                EVENT_COMPLETED
+            } else {
+                EVENT_STARTED
             }
+        }
+    }
+}
 
-            let stats = self.stats.get();
-            let request = AnnounceFields {
-                info_hash: self.info_hash,
-                peer_id: self.peer_id,
-                downloaded: stats.downloaded_bytes,
-                left: stats.get_left_to_download_bytes(),
-                uploaded: stats.uploaded_bytes,
-                event: match stats.torrent_state {
-                    TrackerCommsStatsState::None => EVENT_NONE,
-                    TrackerCommsStatsState::Initializing => EVENT_STARTED,
-                    TrackerCommsStatsState::Paused => EVENT_STOPPED,
-                    TrackerCommsStatsState::Live => {
-                        if stats.is_completed() {
-                            EVENT_COMPLETED
-                        } else {
-                            EVENT_STARTED
-                        }
-                    }
-                },
-                key: 0, // whatever that is?
-                port: self.tcp_listen_port.unwrap_or(0),
-            };
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-            match requester.announce(request).await {
-                Ok(response) => {
-                    trace!(len = response.addrs.len(), "received announce response");
-                    for addr in response.addrs {
-                        self.tx
-                            .send(SocketAddr::V4(addr))
-                            .await
-                            .context("rx closed")?;
-                    }
-                    let new_interval = response.interval.max(5);
-                    let new_interval = Duration::from_secs(new_interval as u64);
-                    sleep_interval = Some(self.force_tracker_interval.unwrap_or(new_interval));
-                }
-                Err(e) => {
-                    debug!(url = ?url, "error reading announce response: {e:#}");
-                    if sleep_interval.is_none() {
-                        sleep_interval = Some(
-                            self.force_tracker_interval
-                                .unwrap_or(Duration::from_secs(60)),
-                        );
-                    }
-                }
-            }
-        }
+    use super::{merge_responses, TrackerAnnounceResponse};
+
+    #[test]
+    fn test_merge_responses() {
+        let response = |peer: &str, seeders| TrackerAnnounceResponse {
+            peers: vec![peer.parse().unwrap()],
+            interval: Duration::from_secs(1800),
+            seeders: Some(seeders),
+            leechers: None,
+            min_interval: None,
+            tracker_id: None,
+        };
+
+        let merged = merge_responses(vec![
+            Err(anyhow::anyhow!("unreachable")),
+            Ok(response("1.2.3.4:6881", 3)),
+            Ok(response("[2001:db8::1]:6881", 5)),
+        ])
+        .unwrap();
+        assert_eq!(merged.peers.len(), 2);
+        assert_eq!(merged.interval, Duration::from_secs(1800));
+        assert_eq!(merged.seeders, Some(5));
+        assert_eq!(merged.leechers, None);
+
+        let err = merge_responses(vec![
+            Err(anyhow::anyhow!("first")),
+            Err(anyhow::anyhow!("second")),
+        ])
+        .unwrap_err();
+        assert_eq!(err.to_string(), "first");
     }
 }
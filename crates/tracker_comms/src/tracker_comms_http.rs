@@ -4,7 +4,7 @@ use serde::{Deserialize, Deserializer};
 use std::{
     fmt::Write,
     marker::PhantomData,
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     str::FromStr,
 };
 
@@ -13,7 +13,6 @@ use librqbit_core::hash_id::Id20;
 #[derive(Clone, Copy)]
 pub enum TrackerRequestEvent {
     Started,
-    #[allow(dead_code)]
     Stopped,
     #[allow(dead_code)]
     Completed,
@@ -58,7 +57,7 @@ impl<'a> DictPeer<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Peers {
     addrs: Vec<SocketAddr>,
 }
@@ -147,6 +146,58 @@ fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
     ips
 }
 
+fn parse_compact_peers6(b: &[u8]) -> Vec<SocketAddrV6> {
+    let mut ips = Vec::new();
+    for chunk in b.chunks_exact(18) {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&chunk[..16]);
+        let port = byteorder::BigEndian::read_u16(&chunk[16..18]);
+        ips.push(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0));
+    }
+    ips
+}
+
+/// IPv6 peers in compact format (BEP 7).
+#[derive(Debug, Default)]
+pub struct Peers6 {
+    addrs: Vec<SocketAddr>,
+}
+
+impl Peers6 {
+    pub fn iter_sockaddrs(&self) -> impl Iterator<Item = std::net::SocketAddr> + '_ {
+        self.addrs.iter().copied()
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Peers6;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a list of IPv6 peers in compact format")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Peers6 {
+                    addrs: parse_compact_peers6(v)
+                        .into_iter()
+                        .map(|v| v.into())
+                        .collect(),
+                })
+            }
+        }
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TrackerResponse<'a> {
     #[serde(rename = "warning message", borrow)]
@@ -155,9 +206,14 @@ pub struct TrackerResponse<'a> {
     pub interval: u64,
     #[serde(rename = "min interval")]
     pub min_interval: Option<u64>,
+    #[serde(rename = "tracker id", borrow)]
     pub tracker_id: Option<ByteBuf<'a>>,
     pub incomplete: u64,
+    // A tracker that only has IPv6 peers for us may omit "peers".
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default)]
+    pub peers6: Peers6,
 }
 
 impl TrackerRequest {
@@ -196,7 +252,8 @@ impl TrackerRequest {
             write!(s, "&key={key}").unwrap();
         }
         if let Some(trackerid) = &self.trackerid {
-            write!(s, "&trackerid={trackerid}").unwrap();
+            s.push_str("&trackerid=");
+            s.push_str(u::encode(trackerid).as_ref());
         }
         s
     }
@@ -230,4 +287,32 @@ mod tests {
         };
         dbg!(request.as_querystring());
     }
+
+    #[test]
+    fn test_parse_peers6() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"d8:completei1e10:incompletei2e8:intervali1800e5:peers6:");
+        buf.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        buf.extend_from_slice(b"6:peers618:");
+        buf.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        buf.extend_from_slice(&[0x1a, 0xe2]);
+        buf.extend_from_slice(b"e");
+
+        let response = bencode::from_bytes::<TrackerResponse>(&buf).unwrap();
+        assert_eq!(
+            response.peers.iter_sockaddrs().collect::<Vec<_>>(),
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            response.peers6.iter_sockaddrs().collect::<Vec<_>>(),
+            vec!["[::1]:6882".parse::<SocketAddr>().unwrap()]
+        );
+    }
+    #[test]
+    fn test_parse_tracker_id() {
+        let buf = b"d8:completei1e10:incompletei2e8:intervali1800e12:min intervali900e10:tracker id5:a b/ce";
+        let response = bencode::from_bytes::<TrackerResponse>(buf).unwrap();
+        assert_eq!(response.min_interval, Some(900));
+        assert_eq!(response.tracker_id.as_deref(), Some(&b"a b/c"[..]));
+    }
 }
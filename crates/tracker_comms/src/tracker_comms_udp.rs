@@ -1,9 +1,8 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::{bail, Context};
-use librqbit_core::hash_id::Id20;
+use librqbit_core::{hash_id::Id20, socket_binding::SocketBinding};
 use rand::Rng;
-use tokio::net::ToSocketAddrs;
 use tracing::trace;
 
 const ACTION_CONNECT: u32 = 0;
@@ -77,7 +76,7 @@ pub struct AnnounceResponse {
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
-    pub addrs: Vec<SocketAddrV4>,
+    pub addrs: Vec<SocketAddr>,
 }
 
 #[derive(Debug)]
@@ -124,7 +123,8 @@ parse_impl!(i64, 8);
 parse_impl!(i16, 2);
 
 impl Response {
-    pub fn parse(buf: &[u8]) -> anyhow::Result<(TransactionId, Self)> {
+    // Announce responses received over IPv6 list IPv6 peers (BEP 15).
+    pub fn parse(buf: &[u8], ipv6: bool) -> anyhow::Result<(TransactionId, Self)> {
         let (action, buf) = u32::parse_num(buf).context("can't parse action")?;
         let (tid, mut buf) = u32::parse_num(buf).context("can't parse transaction id")?;
         let response = match action {
@@ -140,13 +140,19 @@ impl Response {
                 let (seeders, mut b) = u32::parse_num(b).context("can't parse seeders")?;
                 let mut addrs = Vec::new();
                 while !b.is_empty() {
-                    let (ip, b2) = u32::parse_num(b)?;
-                    let ip = Ipv4Addr::from(ip);
-                    b = b2;
+                    let ip = if ipv6 {
+                        let (ip, b2) = split_slice(b, 16).context("can't parse IPv6 address")?;
+                        b = b2;
+                        Ipv6Addr::from(s_to_arr::<16>(ip)).into()
+                    } else {
+                        let (ip, b2) = u32::parse_num(b)?;
+                        b = b2;
+                        Ipv4Addr::from(ip).into()
+                    };
 
                     let (port, b2) = u16::parse_num(b)?;
                     b = b2;
-                    addrs.push(SocketAddrV4::new(ip, port));
+                    addrs.push(SocketAddr::new(ip, port));
                 }
                 buf = b;
                 Response::Announce(AnnounceResponse {
@@ -172,17 +178,19 @@ impl Response {
 
 pub struct UdpTrackerRequester {
     sock: tokio::net::UdpSocket,
+    ipv6: bool,
     connection_id: ConnectionId,
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
 }
 
 impl UdpTrackerRequester {
-    // Addr is "host:port"
-    pub async fn new(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
-        let sock = tokio::net::UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("error binding UDP socket")?;
+    pub async fn new(addr: SocketAddr, binding: &SocketBinding) -> anyhow::Result<Self> {
+        let bind_addr = match addr {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let sock = binding.bind_udp(bind_addr).await?;
         sock.connect(addr)
             .await
             .context("error connecting UDP socket")?;
@@ -204,7 +212,7 @@ impl UdpTrackerRequester {
             .context("error receiving from socket")?;
 
         let (rtid, response) =
-            Response::parse(&read_buf[..size]).context("error parsing response")?;
+            Response::parse(&read_buf[..size], addr.is_ipv6()).context("error parsing response")?;
         if tid != rtid {
             bail!("expected transaction id {} == {}", tid, rtid);
         }
@@ -219,6 +227,7 @@ impl UdpTrackerRequester {
 
         Ok(Self {
             sock,
+            ipv6: addr.is_ipv6(),
             connection_id,
             read_buf,
             write_buf,
@@ -245,7 +254,7 @@ impl UdpTrackerRequester {
             .context("error sending")?;
         let size = self.sock.recv(&mut self.read_buf).await.unwrap();
 
-        let (rtid, response) = Response::parse(&self.read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&self.read_buf[..size], self.ipv6).unwrap();
         trace!("received response");
         if tid != rtid {
             bail!("unexpected transaction id");
@@ -267,10 +276,32 @@ mod tests {
     #[test]
     fn test_parse_announce() {
         let b = include_bytes!("../resources/test/udp-tracker-announce-response.bin");
-        let (tid, response) = Response::parse(b).unwrap();
+        let (tid, response) = Response::parse(b, false).unwrap();
         dbg!(tid, response);
     }
 
+    #[test]
+    fn test_parse_announce_ipv6() {
+        let mut b = Vec::new();
+        b.extend_from_slice(&1u32.to_be_bytes()); // action announce
+        b.extend_from_slice(&42u32.to_be_bytes()); // transaction id
+        b.extend_from_slice(&1800u32.to_be_bytes());
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&2u32.to_be_bytes());
+        b.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        b.extend_from_slice(&6881u16.to_be_bytes());
+
+        let (tid, response) = Response::parse(&b, true).unwrap();
+        assert_eq!(tid, 42);
+        match response {
+            Response::Announce(r) => assert_eq!(
+                r.addrs,
+                vec!["[::1]:6881".parse::<std::net::SocketAddr>().unwrap()]
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_announce() {
@@ -287,7 +318,7 @@ mod tests {
 
         let size = sock.recv(&mut read_buf).await.unwrap();
 
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         let connection_id = match response {
             Response::Connect(connection_id) => {
@@ -329,7 +360,7 @@ mod tests {
         }
 
         dbg!(&read_buf[..size]);
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         match response {
             Response::Announce(r) => {
@@ -2,11 +2,15 @@ use anyhow::{bail, Context};
 use futures::{stream::FuturesUnordered, StreamExt, TryFutureExt};
 use network_interface::NetworkInterfaceConfig;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_xml_rs::from_str;
 use std::{
     collections::{HashMap, HashSet},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
@@ -56,42 +60,43 @@ fn get_local_ip_relative_to(local_dest: Ipv4Addr) -> anyhow::Result<Ipv4Addr> {
     bail!("couldn't find a local ip address")
 }
 
-async fn forward_port(
-    control_url: Url,
-    local_ip: Ipv4Addr,
-    port: u16,
-    lease_duration: Duration,
-) -> anyhow::Result<()> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+async fn soap_request(control_url: Url, action: &str, args: &str) -> anyhow::Result<()> {
     let request_body = format!(
         r#"
         <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"
             s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
             <s:Body>
-                <u:AddPortMapping xmlns:u="{SERVICE_TYPE_WAN_IP_CONNECTION}">
-                    <NewRemoteHost></NewRemoteHost>
-                    <NewExternalPort>{port}</NewExternalPort>
-                    <NewProtocol>TCP</NewProtocol>
-                    <NewInternalPort>{port}</NewInternalPort>
-                    <NewInternalClient>{local_ip}</NewInternalClient>
-                    <NewEnabled>1</NewEnabled>
-                    <NewPortMappingDescription>rust UPnP</NewPortMappingDescription>
-                    <NewLeaseDuration>{}</NewLeaseDuration>
-                </u:AddPortMapping>
+                <u:{action} xmlns:u="{SERVICE_TYPE_WAN_IP_CONNECTION}">
+                    {args}
+                </u:{action}>
             </s:Body>
         </s:Envelope>
-    "#,
-        lease_duration.as_secs()
+    "#
     );
 
-    let url = control_url;
-
     let client = reqwest::Client::new();
     let response = client
-        .post(url.clone())
+        .post(control_url)
         .header("Content-Type", "text/xml")
         .header(
             "SOAPAction",
-            format!("\"{}#AddPortMapping\"", SERVICE_TYPE_WAN_IP_CONNECTION),
+            format!("\"{}#{action}\"", SERVICE_TYPE_WAN_IP_CONNECTION),
         )
         .body(request_body)
         .send()
@@ -105,15 +110,153 @@ async fn forward_port(
         .await
         .context("error reading response text")?;
 
-    trace!(status = %status, text=response_text, "AddPortMapping response");
+    trace!(status = %status, text=response_text, "{action} response");
     if !status.is_success() {
-        bail!("failed port forwarding: {}", status);
-    } else {
-        debug!(%local_ip, port, "successfully port forwarded");
+        bail!("{action} failed: {}", status);
     }
     Ok(())
 }
 
+async fn forward_port(
+    control_url: Url,
+    local_ip: Ipv4Addr,
+    port: u16,
+    protocol: Protocol,
+    lease_duration: Duration,
+) -> anyhow::Result<()> {
+    let args = format!(
+        r#"
+        <NewRemoteHost></NewRemoteHost>
+        <NewExternalPort>{port}</NewExternalPort>
+        <NewProtocol>{}</NewProtocol>
+        <NewInternalPort>{port}</NewInternalPort>
+        <NewInternalClient>{local_ip}</NewInternalClient>
+        <NewEnabled>1</NewEnabled>
+        <NewPortMappingDescription>rust UPnP</NewPortMappingDescription>
+        <NewLeaseDuration>{}</NewLeaseDuration>
+    "#,
+        protocol.as_str(),
+        lease_duration.as_secs()
+    );
+    soap_request(control_url, "AddPortMapping", &args).await?;
+    debug!(%local_ip, port, ?protocol, "successfully port forwarded");
+    Ok(())
+}
+
+async fn delete_port_mapping(
+    control_url: Url,
+    port: u16,
+    protocol: Protocol,
+) -> anyhow::Result<()> {
+    let args = format!(
+        r#"
+        <NewRemoteHost></NewRemoteHost>
+        <NewExternalPort>{port}</NewExternalPort>
+        <NewProtocol>{}</NewProtocol>
+    "#,
+        protocol.as_str()
+    );
+    soap_request(control_url, "DeletePortMapping", &args).await?;
+    debug!(port, ?protocol, "removed port mapping");
+    Ok(())
+}
+
+/// The state of one port mapping on one gateway.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortMappingStatus {
+    /// The control URL of the gateway's WANIPConnection service.
+    pub gateway: String,
+    pub local_ip: Ipv4Addr,
+    pub port: u16,
+    pub protocol: Protocol,
+    /// If the last attempt to add or renew the mapping succeeded.
+    pub mapped: bool,
+    pub last_error: Option<String>,
+}
+
+// Mappings by gateway control URL, port and protocol.
+type MappingsByKey = HashMap<(Url, u16, Protocol), PortMappingStatus>;
+
+/// Status of all the mappings of a port forwarder. Clones share the same state, so it can be
+/// read while the forwarder runs.
+#[derive(Clone, Default)]
+pub struct UpnpPortForwarderStatus {
+    mappings: Arc<Mutex<MappingsByKey>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl UpnpPortForwarderStatus {
+    pub fn mappings(&self) -> Vec<PortMappingStatus> {
+        let mut mappings = self
+            .mappings
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        mappings.sort_by(|a, b| {
+            (&a.gateway, a.port, a.protocol.as_str()).cmp(&(
+                &b.gateway,
+                b.port,
+                b.protocol.as_str(),
+            ))
+        });
+        mappings
+    }
+
+    fn update(
+        &self,
+        control_url: &Url,
+        local_ip: Ipv4Addr,
+        port: u16,
+        protocol: Protocol,
+        result: &anyhow::Result<()>,
+    ) {
+        self.mappings.lock().unwrap().insert(
+            (control_url.clone(), port, protocol),
+            PortMappingStatus {
+                gateway: control_url.to_string(),
+                local_ip,
+                port,
+                protocol,
+                mapped: result.is_ok(),
+                last_error: result.as_ref().err().map(|e| format!("{e:#}")),
+            },
+        );
+    }
+
+    /// Stop renewing the leases, and remove all the mappings that were added.
+    pub async fn remove_all(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let mapped = self
+            .mappings
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.mapped)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        let removals = mapped
+            .into_iter()
+            .map(|(control_url, port, protocol)| async move {
+                let result = delete_port_mapping(control_url.clone(), port, protocol).await;
+                if let Err(e) = &result {
+                    warn!(port, ?protocol, "failed to remove port mapping: {e:#}");
+                }
+                if let Some(s) =
+                    self.mappings
+                        .lock()
+                        .unwrap()
+                        .get_mut(&(control_url, port, protocol))
+                {
+                    s.mapped = false;
+                    s.last_error = result.err().map(|e| format!("{e:#}"));
+                }
+            });
+        futures::future::join_all(removals).await;
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct RootDesc {
     #[serde(rename = "device")]
@@ -302,6 +445,7 @@ impl Default for UpnpPortForwarderOptions {
 pub struct UpnpPortForwarder {
     ports: Vec<u16>,
     opts: UpnpPortForwarderOptions,
+    status: UpnpPortForwarderStatus,
 }
 
 impl UpnpPortForwarder {
@@ -312,9 +456,14 @@ impl UpnpPortForwarder {
         Ok(Self {
             ports,
             opts: opts.unwrap_or_default(),
+            status: Default::default(),
         })
     }
 
+    pub fn status(&self) -> UpnpPortForwarderStatus {
+        self.status.clone()
+    }
+
     async fn parse_endpoint(
         &self,
         discover_response: UpnpDiscoverResponse,
@@ -386,22 +535,43 @@ impl UpnpPortForwarder {
         }
     }
 
-    async fn manage_port(&self, control_url: Url, local_ip: Ipv4Addr, port: u16) -> ! {
+    async fn manage_port(
+        &self,
+        control_url: Url,
+        local_ip: Ipv4Addr,
+        port: u16,
+        protocol: Protocol,
+    ) -> ! {
         let lease_duration = self.opts.lease_duration;
         let mut interval = tokio::time::interval(lease_duration / 2);
         loop {
             interval.tick().await;
-            if let Err(e) = forward_port(control_url.clone(), local_ip, port, lease_duration).await
-            {
+            // The mappings are being removed, don't renew them.
+            if self.status.stopped.load(Ordering::Relaxed) {
+                continue;
+            }
+            let result = forward_port(
+                control_url.clone(),
+                local_ip,
+                port,
+                protocol,
+                lease_duration,
+            )
+            .await;
+            if let Err(e) = &result {
                 warn!("failed to forward port: {e:#}");
             }
+            self.status
+                .update(&control_url, local_ip, port, protocol, &result);
         }
     }
 
     async fn manage_service(&self, control_url: Url, local_ip: Ipv4Addr) -> anyhow::Result<()> {
-        futures::future::join_all(self.ports.iter().cloned().map(|port| {
-            self.manage_port(control_url.clone(), local_ip, port)
-                .instrument(error_span!("manage_port", port = port))
+        futures::future::join_all(self.ports.iter().cloned().flat_map(|port| {
+            [Protocol::Tcp, Protocol::Udp].map(|protocol| {
+                self.manage_port(control_url.clone(), local_ip, port, protocol)
+                    .instrument(error_span!("manage_port", port = port, ?protocol))
+            })
         }))
         .await;
         Ok(())
@@ -467,10 +637,28 @@ impl UpnpPortForwarder {
 mod tests {
     use serde_xml_rs::from_str;
 
-    use crate::RootDesc;
+    use crate::{Protocol, RootDesc, UpnpPortForwarderStatus};
 
     #[test]
     fn test_parse() {
         dbg!(from_str::<RootDesc>(include_str!("resources/test/devices-0.xml")).unwrap());
     }
+
+    #[test]
+    fn test_status_update() {
+        let status = UpnpPortForwarderStatus::default();
+        let url = url::Url::parse("http://192.168.1.1:5000/ctl/IPConn").unwrap();
+        let ip = "192.168.1.10".parse().unwrap();
+        status.update(&url, ip, 6881, Protocol::Tcp, &Ok(()));
+        status.update(&url, ip, 6881, Protocol::Udp, &Err(anyhow::anyhow!("nope")));
+        status.update(&url, ip, 6881, Protocol::Tcp, &Ok(()));
+
+        let mappings = status.mappings();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].protocol, Protocol::Tcp);
+        assert!(mappings[0].mapped);
+        assert_eq!(mappings[1].protocol, Protocol::Udp);
+        assert!(!mappings[1].mapped);
+        assert_eq!(mappings[1].last_error.as_deref(), Some("nope"));
+    }
 }
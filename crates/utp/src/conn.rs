@@ -0,0 +1,651 @@
+// The state machine of one uTP connection. It runs as its own task, talks to the user
+// through the shared state of UtpStream, and receives packets from the socket dispatcher.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, trace};
+
+use crate::{
+    ledbat::Ledbat,
+    packet::{seq_less_or_equal, seq_less_than, Header, Packet, PacketType, HEADER_LEN},
+    socket::Registry,
+    stream::{Shared, RECV_BUFFER},
+    MAX_PAYLOAD,
+};
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// Consecutive retransmission timeouts before giving up on the connection.
+const MAX_TIMEOUTS: u32 = 6;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+// Once we sent FIN, don't linger long waiting for it to be acknowledged.
+const MAX_FIN_TIMEOUTS: u32 = 2;
+
+// How far ahead of the next expected packet we buffer out-of-order packets.
+const MAX_REORDER_DISTANCE: u16 = 1024;
+// The selective ack we send covers this many packets after the next expected one.
+const MAX_SELECTIVE_ACK_BYTES: usize = 32;
+
+pub(crate) struct OwnedPacket {
+    pub header: Header,
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl From<&Packet<'_>> for OwnedPacket {
+    fn from(p: &Packet<'_>) -> Self {
+        Self {
+            header: p.header,
+            selective_ack: p.selective_ack.map(|s| s.to_vec()),
+            payload: p.payload.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    seq: u16,
+    packet_type: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    // Considered lost, waiting to be sent again. Not counted as in flight.
+    need_resend: bool,
+}
+
+pub(crate) struct Connection {
+    addr: SocketAddr,
+    send_addr: SocketAddr,
+    udp: Arc<UdpSocket>,
+    shared: Arc<Shared>,
+    rx: mpsc::Receiver<OwnedPacket>,
+    registry: Arc<Registry>,
+
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    epoch: Instant,
+
+    // The sequence number of the next packet we send.
+    seq_nr: u16,
+    // The last packet we received in order.
+    ack_nr: u16,
+
+    inflight: VecDeque<SentPacket>,
+    bytes_in_flight: usize,
+    peer_wnd: usize,
+
+    // Packets received after a gap, by sequence number.
+    reorder: HashMap<u16, Vec<u8>>,
+    reorder_bytes: usize,
+
+    peer_fin: Option<u16>,
+    our_fin: Option<u16>,
+    our_fin_acked: bool,
+
+    last_ack_received: u16,
+    duplicate_acks: u32,
+
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    timeouts: u32,
+
+    ledbat: Ledbat,
+    // The one-way delay of the last received packet, echoed back to the remote.
+    last_recv_delay: u32,
+    ack_pending: bool,
+
+    connected_tx: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+impl Connection {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        addr: SocketAddr,
+        udp: Arc<UdpSocket>,
+        shared: Arc<Shared>,
+        rx: mpsc::Receiver<OwnedPacket>,
+        registry: Arc<Registry>,
+        recv_id: u16,
+        send_id: u16,
+        state: State,
+    ) -> Self {
+        let now = Instant::now();
+        let send_addr = match udp.local_addr() {
+            Ok(local_addr) => crate::socket::send_addr(local_addr, addr),
+            Err(_) => addr,
+        };
+        Self {
+            addr,
+            send_addr,
+            udp,
+            shared,
+            rx,
+            registry,
+            recv_id,
+            send_id,
+            state,
+            epoch: now,
+            seq_nr: 1,
+            ack_nr: 0,
+            inflight: Default::default(),
+            bytes_in_flight: 0,
+            peer_wnd: MAX_PAYLOAD,
+            reorder: Default::default(),
+            reorder_bytes: 0,
+            peer_fin: None,
+            our_fin: None,
+            our_fin_acked: false,
+            last_ack_received: 0,
+            duplicate_acks: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            timeouts: 0,
+            ledbat: Ledbat::new(now),
+            last_recv_delay: 0,
+            ack_pending: false,
+            connected_tx: None,
+        }
+    }
+
+    pub fn new_outgoing(
+        addr: SocketAddr,
+        udp: Arc<UdpSocket>,
+        shared: Arc<Shared>,
+        rx: mpsc::Receiver<OwnedPacket>,
+        registry: Arc<Registry>,
+        recv_id: u16,
+        connected_tx: oneshot::Sender<io::Result<()>>,
+    ) -> Self {
+        let mut conn = Self::new(
+            addr,
+            udp,
+            shared,
+            rx,
+            registry,
+            recv_id,
+            recv_id.wrapping_add(1),
+            State::SynSent,
+        );
+        conn.connected_tx = Some(connected_tx);
+        conn
+    }
+
+    pub fn new_incoming(
+        syn: &Header,
+        addr: SocketAddr,
+        udp: Arc<UdpSocket>,
+        shared: Arc<Shared>,
+        rx: mpsc::Receiver<OwnedPacket>,
+        registry: Arc<Registry>,
+    ) -> Self {
+        let mut conn = Self::new(
+            addr,
+            udp,
+            shared,
+            rx,
+            registry,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            State::Connected,
+        );
+        conn.seq_nr = rand::random();
+        conn.ack_nr = syn.seq_nr;
+        conn.last_ack_received = conn.seq_nr.wrapping_sub(1);
+        conn.peer_wnd = syn.wnd_size as usize;
+        conn.last_recv_delay = conn.now_us().wrapping_sub(syn.timestamp_us);
+        // Reply with ST_STATE.
+        conn.ack_pending = true;
+        conn
+    }
+
+    fn now_us(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    pub async fn run(mut self) {
+        let result = self.run_inner().await;
+        if let Err(e) = &result {
+            debug!(addr=%self.addr, "uTP connection closed: {e:#}");
+        }
+        self.registry.lock().remove(&(self.addr, self.recv_id));
+
+        let mut g = self.shared.state.lock();
+        g.closed = true;
+        if let Err(e) = &result {
+            g.error = Some((e.kind(), e.to_string()));
+        }
+        g.wake_all();
+        drop(g);
+
+        if let Some(tx) = self.connected_tx.take() {
+            let _ = tx.send(Err(result.err().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "connect cancelled")
+            })));
+        }
+    }
+
+    async fn run_inner(&mut self) -> io::Result<()> {
+        if self.state == State::SynSent {
+            self.send_new(PacketType::Syn, Vec::new()).await;
+        }
+        let shared = self.shared.clone();
+        loop {
+            self.flush().await;
+            if self.is_done() {
+                return Ok(());
+            }
+
+            let deadline = self
+                .inflight
+                .iter()
+                .filter(|p| !p.need_resend)
+                .map(|p| p.sent_at)
+                .min()
+                .map(|t| t + self.rto);
+            let timeout = async {
+                match deadline {
+                    Some(d) => tokio::time::sleep_until(d.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                packet = self.rx.recv() => match packet {
+                    Some(packet) => self.on_packet(packet)?,
+                    None => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "uTP socket closed")),
+                },
+                _ = shared.notify.notified() => {},
+                _ = timeout => self.on_timeout()?,
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        let g = self.shared.state.lock();
+        match self.state {
+            State::SynSent => g.dropped,
+            State::Connected => self.our_fin_acked && (g.recv_eof || g.dropped),
+        }
+    }
+
+    fn window_allows(&self, len: usize) -> bool {
+        // Always allow one packet, otherwise a zero window would never be probed.
+        self.bytes_in_flight == 0
+            || self.bytes_in_flight + len <= self.ledbat.cwnd().min(self.peer_wnd)
+    }
+
+    fn recv_window(&self) -> usize {
+        let mut g = self.shared.state.lock();
+        let window = RECV_BUFFER.saturating_sub(g.recv_buf.len() + self.reorder_bytes);
+        if window < MAX_PAYLOAD {
+            g.wants_window_update = true;
+        }
+        window
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.reorder.is_empty() {
+            return None;
+        }
+        let mut mask = [0u8; MAX_SELECTIVE_ACK_BYTES];
+        let mut max_byte = None;
+        for seq in self.reorder.keys() {
+            let d = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if d < MAX_SELECTIVE_ACK_BYTES * 8 {
+                mask[d / 8] |= 1 << (d % 8);
+                max_byte = max_byte.max(Some(d / 8));
+            }
+        }
+        // The length must be a multiple of 4.
+        max_byte.map(|b| mask[..(b / 4 + 1) * 4].to_vec())
+    }
+
+    async fn transmit(&mut self, packet_type: PacketType, seq_nr: u16, payload: &[u8]) {
+        let header = Header {
+            packet_type,
+            connection_id: match packet_type {
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp_us: self.now_us(),
+            timestamp_diff_us: self.last_recv_delay,
+            wnd_size: self.recv_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        let selective_ack = self.selective_ack();
+        let mut buf = Vec::with_capacity(HEADER_LEN + 2 + MAX_SELECTIVE_ACK_BYTES + payload.len());
+        Packet {
+            header,
+            selective_ack: selective_ack.as_deref(),
+            payload,
+        }
+        .serialize(&mut buf);
+        // UDP send errors are transient, lost packets are retransmitted anyway.
+        if let Err(e) = self.udp.send_to(&buf, self.send_addr).await {
+            trace!(addr=%self.addr, "error sending uTP packet: {e:#}");
+        }
+        // Every packet carries our ack_nr.
+        self.ack_pending = false;
+    }
+
+    async fn send_new(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let seq = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(packet_type, seq, &payload).await;
+        self.bytes_in_flight += payload.len();
+        self.inflight.push_back(SentPacket {
+            seq,
+            packet_type,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 1,
+            need_resend: false,
+        });
+    }
+
+    async fn flush(&mut self) {
+        // Retransmit lost packets first.
+        for idx in 0..self.inflight.len() {
+            if !self.inflight[idx].need_resend {
+                continue;
+            }
+            if !self.window_allows(self.inflight[idx].payload.len()) {
+                break;
+            }
+            let p = &mut self.inflight[idx];
+            p.need_resend = false;
+            p.transmissions += 1;
+            p.sent_at = Instant::now();
+            let (packet_type, seq, payload) =
+                (p.packet_type, p.seq, std::mem::take(&mut p.payload));
+            self.bytes_in_flight += payload.len();
+            self.transmit(packet_type, seq, &payload).await;
+            self.inflight[idx].payload = payload;
+        }
+
+        if self.state == State::Connected {
+            while self.our_fin.is_none() {
+                let chunk = {
+                    let mut g = self.shared.state.lock();
+                    let len = g.send_buf.len().min(MAX_PAYLOAD);
+                    if len == 0 || !self.window_allows(len) {
+                        break;
+                    }
+                    let chunk = g.send_buf.drain(..len).collect::<Vec<u8>>();
+                    if let Some(w) = g.writer_waker.take() {
+                        w.wake();
+                    }
+                    chunk
+                };
+                self.send_new(PacketType::Data, chunk).await;
+            }
+
+            let send_fin = {
+                let g = self.shared.state.lock();
+                self.our_fin.is_none() && (g.write_shutdown || g.dropped) && g.send_buf.is_empty()
+            };
+            if send_fin {
+                self.our_fin = Some(self.seq_nr);
+                self.send_new(PacketType::Fin, Vec::new()).await;
+            }
+        }
+
+        if self.ack_pending {
+            self.transmit(PacketType::State, self.seq_nr, &[]).await;
+        }
+    }
+
+    fn on_packet(&mut self, packet: OwnedPacket) -> io::Result<()> {
+        let now = Instant::now();
+        let h = packet.header;
+        self.last_recv_delay = self.now_us().wrapping_sub(h.timestamp_us);
+        self.peer_wnd = h.wnd_size as usize;
+
+        match h.packet_type {
+            PacketType::Reset => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "uTP connection reset by peer",
+                ))
+            }
+            PacketType::Syn => {
+                // Our reply to SYN was lost, send it again.
+                if self.state == State::Connected {
+                    self.ack_pending = true;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            if h.packet_type != PacketType::State {
+                return Ok(());
+            }
+            self.state = State::Connected;
+            // ST_STATE doesn't consume a sequence number, so the first data packet
+            // from the remote will have this number.
+            self.ack_nr = h.seq_nr.wrapping_sub(1);
+            if let Some(tx) = self.connected_tx.take() {
+                let _ = tx.send(Ok(()));
+            }
+        }
+
+        self.on_ack(
+            now,
+            h.packet_type,
+            h.ack_nr,
+            packet.selective_ack.as_deref(),
+            h.timestamp_diff_us,
+        );
+
+        match h.packet_type {
+            PacketType::Data => self.on_data(h.seq_nr, packet.payload),
+            PacketType::Fin => {
+                self.peer_fin = Some(h.seq_nr);
+                self.on_data(h.seq_nr, Vec::new());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_packet_acked(&mut self, p: &SentPacket, now: Instant, rtt_sample: &mut Option<Duration>) {
+        if !p.need_resend {
+            self.bytes_in_flight -= p.payload.len();
+        }
+        // Karn's algorithm: only measure RTT on packets that weren't retransmitted.
+        if p.transmissions == 1 {
+            *rtt_sample = Some(now - p.sent_at);
+        }
+        if p.packet_type == PacketType::Fin {
+            self.our_fin_acked = true;
+        }
+    }
+
+    fn mark_for_resend(&mut self, idx: usize) {
+        let p = &mut self.inflight[idx];
+        if !p.need_resend {
+            p.need_resend = true;
+            self.bytes_in_flight -= p.payload.len();
+        }
+    }
+
+    fn fast_retransmit(&mut self) {
+        if matches!(self.inflight.front(), Some(p) if p.transmissions == 1 && !p.need_resend) {
+            trace!(addr=%self.addr, "fast retransmit of seq={}", self.inflight[0].seq);
+            self.mark_for_resend(0);
+            self.ledbat.on_loss();
+        }
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        packet_type: PacketType,
+        ack_nr: u16,
+        selective_ack: Option<&[u8]>,
+        delay_us: u32,
+    ) {
+        let mut acked_any = false;
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+
+        while matches!(self.inflight.front(), Some(p) if seq_less_or_equal(p.seq, ack_nr)) {
+            let p = self.inflight.pop_front().unwrap();
+            self.on_packet_acked(&p, now, &mut rtt_sample);
+            acked_any = true;
+            acked_bytes += p.payload.len();
+        }
+
+        if let Some(mask) = selective_ack {
+            let mut idx = 0;
+            while idx < self.inflight.len() {
+                let d = self.inflight[idx].seq.wrapping_sub(ack_nr).wrapping_sub(2) as usize;
+                if d < mask.len() * 8 && mask[d / 8] & (1 << (d % 8)) != 0 {
+                    let p = self.inflight.remove(idx).unwrap();
+                    self.on_packet_acked(&p, now, &mut rtt_sample);
+                    acked_any = true;
+                    acked_bytes += p.payload.len();
+                } else {
+                    idx += 1;
+                }
+            }
+            // Several packets after the first unacked one arrived, so it was most likely lost.
+            let received_after = mask.iter().map(|b| b.count_ones()).sum::<u32>();
+            if received_after >= 3 {
+                self.fast_retransmit();
+            }
+        }
+
+        if packet_type == PacketType::State
+            && !acked_any
+            && !self.inflight.is_empty()
+            && ack_nr == self.last_ack_received
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                self.fast_retransmit();
+            }
+        } else if acked_any {
+            self.duplicate_acks = 0;
+        }
+        self.last_ack_received = ack_nr;
+
+        if acked_any {
+            self.timeouts = 0;
+            self.ledbat.on_ack(now, acked_bytes, delay_us);
+        }
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                let rtt_var = self.rtt_var.as_secs_f64()
+                    + (delta.as_secs_f64() - self.rtt_var.as_secs_f64()) / 4.;
+                let rtt = rtt.as_secs_f64() + (sample.as_secs_f64() - rtt.as_secs_f64()) / 8.;
+                self.rtt_var = Duration::from_secs_f64(rtt_var.max(0.));
+                self.rtt = Some(Duration::from_secs_f64(rtt.max(0.)));
+            }
+        }
+        self.rto = (self.rtt.unwrap_or_default() + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn on_timeout(&mut self) -> io::Result<()> {
+        self.timeouts += 1;
+        let max_timeouts = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            State::Connected if self.our_fin.is_some() => MAX_FIN_TIMEOUTS,
+            State::Connected => MAX_TIMEOUTS,
+        };
+        if self.timeouts > max_timeouts {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("uTP connection timed out after {} retries", max_timeouts),
+            ));
+        }
+        trace!(addr=%self.addr, rto=?self.rto, "timeout, resending {} packets", self.inflight.len());
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.ledbat.on_timeout();
+        for idx in 0..self.inflight.len() {
+            self.mark_for_resend(idx);
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, seq: u16, payload: Vec<u8>) {
+        // Ack everything we receive, including duplicates: our previous ack might have been lost.
+        self.ack_pending = true;
+
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq_less_than(seq, expected) {
+            return;
+        }
+        if matches!(self.peer_fin, Some(fin) if seq_less_than(fin, seq)) {
+            return;
+        }
+        if seq.wrapping_sub(expected) > MAX_REORDER_DISTANCE {
+            return;
+        }
+        let buffered = self.shared.state.lock().recv_buf.len() + self.reorder_bytes;
+        if buffered + payload.len() > RECV_BUFFER {
+            // No space. The remote will resend once we advertise a larger window.
+            return;
+        }
+
+        if seq != expected {
+            if !self.reorder.contains_key(&seq) {
+                self.reorder_bytes += payload.len();
+                self.reorder.insert(seq, payload);
+            }
+            return;
+        }
+
+        let mut g = self.shared.state.lock();
+        let mut payload = Some(payload);
+        while let Some(p) = payload {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            g.recv_buf.extend(p);
+            if self.peer_fin == Some(self.ack_nr) {
+                g.recv_eof = true;
+                break;
+            }
+            payload = self.reorder.remove(&self.ack_nr.wrapping_add(1));
+            if let Some(p) = &payload {
+                self.reorder_bytes -= p.len();
+            }
+        }
+        if let Some(w) = g.reader_waker.take() {
+            w.wake();
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// LEDBAT congestion control (RFC 6817), as used by uTP.
+//
+// The window grows while the measured one-way queuing delay is below the target, and
+// shrinks when it's above, so uTP yields to TCP and interactive traffic on the same link.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::MAX_PAYLOAD;
+
+const TARGET_DELAY_US: u32 = 100_000;
+const MAX_CWND_INCREASE_BYTES_PER_RTT: f64 = 3000.;
+// Keep the minimal delay of each of the last BASE_HISTORY minutes.
+const BASE_HISTORY: usize = 10;
+const BASE_HISTORY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const MIN_CWND: usize = MAX_PAYLOAD;
+const INITIAL_CWND: usize = MAX_PAYLOAD * 3;
+const MAX_CWND: usize = 8 * 1024 * 1024;
+
+pub struct Ledbat {
+    cwnd: usize,
+    slow_start: bool,
+    base_delays: VecDeque<u32>,
+    last_rollover: Instant,
+}
+
+// Timestamps wrap around every ~71 minutes, so compare them by distance.
+fn delay_less_than(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Ledbat {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            slow_start: true,
+            base_delays: VecDeque::new(),
+            last_rollover: now,
+        }
+    }
+
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn update_base_delay(&mut self, now: Instant, delay: u32) -> u32 {
+        match self.base_delays.back_mut() {
+            Some(last) if now - self.last_rollover < BASE_HISTORY_INTERVAL => {
+                if delay_less_than(delay, *last) {
+                    *last = delay;
+                }
+            }
+            _ => {
+                self.last_rollover = now;
+                self.base_delays.push_back(delay);
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.base_delays
+            .iter()
+            .copied()
+            .reduce(|a, b| if delay_less_than(b, a) { b } else { a })
+            .unwrap_or(delay)
+    }
+
+    // "delay_us" is the one-way delay the remote measured for our packets, or 0 if it
+    // didn't measure any yet.
+    pub fn on_ack(&mut self, now: Instant, bytes_acked: usize, delay_us: u32) {
+        let our_delay = if delay_us == 0 {
+            None
+        } else {
+            Some(delay_us.wrapping_sub(self.update_base_delay(now, delay_us)))
+        };
+
+        if self.slow_start {
+            match our_delay {
+                Some(d) if d > TARGET_DELAY_US / 10 * 9 => self.slow_start = false,
+                _ => {
+                    self.cwnd = (self.cwnd + bytes_acked).min(MAX_CWND);
+                    return;
+                }
+            }
+        }
+
+        let our_delay = match our_delay {
+            Some(d) => d,
+            None => return,
+        };
+        let off_target =
+            ((TARGET_DELAY_US as f64 - our_delay as f64) / TARGET_DELAY_US as f64).max(-1.);
+        let gain =
+            MAX_CWND_INCREASE_BYTES_PER_RTT * off_target * bytes_acked as f64 / self.cwnd as f64;
+        self.cwnd = (self.cwnd as f64 + gain).clamp(MIN_CWND as f64, MAX_CWND as f64) as usize;
+    }
+
+    pub fn on_loss(&mut self) {
+        self.slow_start = false;
+        self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.slow_start = false;
+        self.cwnd = MIN_CWND;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Ledbat, INITIAL_CWND, MIN_CWND, TARGET_DELAY_US};
+    use crate::MAX_PAYLOAD;
+
+    #[test]
+    fn test_slow_start_then_delay_based() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(now);
+        assert_eq!(l.cwnd(), INITIAL_CWND);
+
+        // No queuing delay: slow start grows by the number of acked bytes.
+        l.on_ack(now, MAX_PAYLOAD, 5000);
+        assert_eq!(l.cwnd(), INITIAL_CWND + MAX_PAYLOAD);
+
+        // Queuing delay above target ends slow start and shrinks the window.
+        let before = l.cwnd();
+        l.on_ack(now, MAX_PAYLOAD, 5000 + TARGET_DELAY_US * 2);
+        assert!(!l.slow_start);
+        assert!(l.cwnd() < before, "{} >= {}", l.cwnd(), before);
+
+        // Back to the base delay: grows again, but slowly.
+        let before = l.cwnd();
+        l.on_ack(now, MAX_PAYLOAD, 5000);
+        assert!(l.cwnd() > before);
+        assert!(l.cwnd() < before + MAX_PAYLOAD);
+    }
+
+    #[test]
+    fn test_loss_and_timeout() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(now);
+        for _ in 0..100 {
+            l.on_ack(now, MAX_PAYLOAD, 1000);
+        }
+        let before = l.cwnd();
+        l.on_loss();
+        assert_eq!(l.cwnd(), before / 2);
+
+        l.on_timeout();
+        assert_eq!(l.cwnd(), MIN_CWND);
+        l.on_loss();
+        assert_eq!(l.cwnd(), MIN_CWND);
+    }
+
+    #[test]
+    fn test_base_delay_expires() {
+        let start = Instant::now();
+        let mut l = Ledbat::new(start);
+        assert_eq!(l.update_base_delay(start, 1000), 1000);
+        assert_eq!(l.update_base_delay(start, 2000), 1000);
+
+        // The route changed and the base delay went up. It's picked up once the old
+        // minimum leaves the history.
+        let mut now = start;
+        for _ in 0..9 {
+            now += Duration::from_secs(61);
+            assert_eq!(l.update_base_delay(now, 50_000), 1000);
+        }
+        now += Duration::from_secs(61);
+        assert_eq!(l.update_base_delay(now, 50_000), 50_000);
+    }
+
+    #[test]
+    fn test_delay_wraps() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(now);
+        assert_eq!(l.update_base_delay(now, u32::MAX - 10), u32::MAX - 10);
+        assert_eq!(l.update_base_delay(now, 5), u32::MAX - 10);
+    }
+}
@@ -0,0 +1,17 @@
+// uTP (BEP 29): a reliable, ordered byte stream over UDP, with LEDBAT congestion control
+// so that it backs off in favor of other traffic.
+//
+// A UtpSocket owns one UDP socket and multiplexes all connections over it. Each connection
+// is driven by its own task, and UtpStream implements AsyncRead + AsyncWrite on top.
+
+mod conn;
+mod ledbat;
+mod packet;
+mod socket;
+mod stream;
+
+pub use socket::UtpSocket;
+pub use stream::UtpStream;
+
+// Payload bytes per packet. Small enough to avoid IP fragmentation on most paths.
+pub(crate) const MAX_PAYLOAD: usize = 1200;
@@ -0,0 +1,255 @@
+// uTP packet header and extensions, as described in BEP 29.
+//
+// 0       4       8               16              24              32
+// +-------+-------+---------------+---------------+---------------+
+// | type  | ver   | extension     | connection_id                 |
+// +-------+-------+---------------+---------------+---------------+
+// | timestamp_microseconds                                        |
+// +---------------+---------------+---------------+---------------+
+// | timestamp_difference_microseconds                             |
+// +---------------+---------------+---------------+---------------+
+// | wnd_size                                                      |
+// +---------------+---------------+---------------+---------------+
+// | seq_nr                        | ack_nr                        |
+// +---------------+---------------+---------------+---------------+
+
+use anyhow::{bail, Context};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Data,
+            1 => Self::Fin,
+            2 => Self::State,
+            3 => Self::Reset,
+            4 => Self::Syn,
+            _ => return None,
+        })
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Fin => 1,
+            Self::State => 2,
+            Self::Reset => 3,
+            Self::Syn => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp_us: u32,
+    pub timestamp_diff_us: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub header: Header,
+    // Bit i set means packet "ack_nr + 2 + i" was received. Least significant bit
+    // of each byte comes first.
+    pub selective_ack: Option<&'a [u8]>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn deserialize(buf: &'a [u8]) -> anyhow::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            bail!("packet too short: {} bytes", buf.len());
+        }
+        let version = buf[0] & 0x0f;
+        if version != VERSION {
+            bail!("unsupported version {version}");
+        }
+        let packet_type = PacketType::from_u8(buf[0] >> 4)
+            .with_context(|| format!("bad type {}", buf[0] >> 4))?;
+        let u16_at = |pos: usize| u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+        let u32_at =
+            |pos: usize| u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+        let header = Header {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp_us: u32_at(4),
+            timestamp_diff_us: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+
+        let mut selective_ack = None;
+        let mut extension = buf[1];
+        let mut rest = &buf[HEADER_LEN..];
+        while extension != EXTENSION_NONE {
+            if rest.len() < 2 {
+                bail!("truncated extension header");
+            }
+            let (next, len) = (rest[0], rest[1] as usize);
+            let data = rest
+                .get(2..2 + len)
+                .context("extension longer than the packet")?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                if len == 0 || len % 4 != 0 {
+                    bail!("bad selective ack length {len}");
+                }
+                selective_ack = Some(data);
+            }
+            // Unknown extensions are skipped.
+            extension = next;
+            rest = &rest[2 + len..];
+        }
+
+        Ok(Self {
+            header,
+            selective_ack,
+            payload: rest,
+        })
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        let h = &self.header;
+        out.push((h.packet_type.to_u8() << 4) | VERSION);
+        out.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        });
+        out.extend_from_slice(&h.connection_id.to_be_bytes());
+        out.extend_from_slice(&h.timestamp_us.to_be_bytes());
+        out.extend_from_slice(&h.timestamp_diff_us.to_be_bytes());
+        out.extend_from_slice(&h.wnd_size.to_be_bytes());
+        out.extend_from_slice(&h.seq_nr.to_be_bytes());
+        out.extend_from_slice(&h.ack_nr.to_be_bytes());
+        if let Some(sack) = self.selective_ack {
+            out.push(EXTENSION_NONE);
+            out.push(sack.len() as u8);
+            out.extend_from_slice(sack);
+        }
+        out.extend_from_slice(self.payload);
+    }
+}
+
+// Sequence numbers wrap around, so compare them by distance.
+pub fn seq_less_than(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+pub fn seq_less_or_equal(a: u16, b: u16) -> bool {
+    a == b || seq_less_than(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(packet_type: PacketType) -> Header {
+        Header {
+            packet_type,
+            connection_id: 0xabcd,
+            timestamp_us: 123456789,
+            timestamp_diff_us: 42,
+            wnd_size: 1 << 20,
+            seq_nr: 65535,
+            ack_nr: 7,
+        }
+    }
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let sack = [0b0000_0101, 0, 0, 0x80];
+        for (packet_type, selective_ack, payload) in [
+            (PacketType::Syn, None, &b""[..]),
+            (PacketType::Data, None, &b"hello"[..]),
+            (PacketType::State, Some(&sack[..]), &b""[..]),
+            (PacketType::Data, Some(&sack[..]), &b"world"[..]),
+            (PacketType::Fin, None, &b""[..]),
+            (PacketType::Reset, None, &b""[..]),
+        ] {
+            let packet = Packet {
+                header: header(packet_type),
+                selective_ack,
+                payload,
+            };
+            let mut buf = Vec::new();
+            packet.serialize(&mut buf);
+            assert_eq!(
+                buf.len(),
+                HEADER_LEN + selective_ack.map(|s| s.len() + 2).unwrap_or(0) + payload.len()
+            );
+            assert_eq!(Packet::deserialize(&buf).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_skips_unknown_extensions() {
+        let mut buf = Vec::new();
+        Packet {
+            header: header(PacketType::Data),
+            selective_ack: None,
+            payload: b"",
+        }
+        .serialize(&mut buf);
+        // Extension 2 (close reasons), followed by a selective ack.
+        buf[1] = 2;
+        buf.extend_from_slice(&[EXTENSION_SELECTIVE_ACK, 3, 9, 9, 9]);
+        buf.extend_from_slice(&[EXTENSION_NONE, 4, 1, 2, 3, 4]);
+        buf.extend_from_slice(b"payload");
+
+        let p = Packet::deserialize(&buf).unwrap();
+        assert_eq!(p.selective_ack, Some(&[1, 2, 3, 4][..]));
+        assert_eq!(p.payload, b"payload");
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut buf = Vec::new();
+        Packet {
+            header: header(PacketType::State),
+            selective_ack: Some(&[0; 4]),
+            payload: b"",
+        }
+        .serialize(&mut buf);
+
+        assert!(Packet::deserialize(&buf[..HEADER_LEN - 1]).is_err());
+        // Truncated extension.
+        assert!(Packet::deserialize(&buf[..buf.len() - 1]).is_err());
+
+        let mut bad_version = buf.clone();
+        bad_version[0] = (bad_version[0] & 0xf0) | 2;
+        assert!(Packet::deserialize(&bad_version).is_err());
+
+        let mut bad_type = buf.clone();
+        bad_type[0] = (5 << 4) | VERSION;
+        assert!(Packet::deserialize(&bad_type).is_err());
+    }
+
+    #[test]
+    fn test_seq_compare_wraps() {
+        assert!(seq_less_than(1, 2));
+        assert!(!seq_less_than(2, 2));
+        assert!(seq_less_than(65535, 0));
+        assert!(seq_less_than(65000, 100));
+        assert!(!seq_less_than(100, 65000));
+        assert!(seq_less_or_equal(5, 5));
+        assert!(seq_less_or_equal(65535, 3));
+    }
+}
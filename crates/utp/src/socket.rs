@@ -0,0 +1,306 @@
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use librqbit_core::spawn_utils::spawn;
+use parking_lot::Mutex;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, debug_span, trace};
+
+use crate::{
+    conn::{Connection, OwnedPacket},
+    packet::{Packet, PacketType},
+    stream::{Shared, UtpStream},
+};
+
+// Connections by remote address and the connection id the remote sends to us.
+pub(crate) type Registry = Mutex<HashMap<(SocketAddr, u16), mpsc::Sender<OwnedPacket>>>;
+
+// Incoming packets queued per connection. If the connection task falls behind,
+// packets are dropped like the network would.
+const PACKET_QUEUE: usize = 1024;
+const ACCEPT_QUEUE: usize = 32;
+
+struct Inner {
+    udp: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    registry: Arc<Registry>,
+    accept_rx: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    dispatcher: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+/// A UDP socket that multiplexes uTP connections, both outgoing and incoming.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let udp = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("error binding UDP socket at {addr}"))?;
+        Self::from_udp(udp)
+    }
+
+    /// Use an already bound socket, e.g. a dual-stack IPv6 one that also serves IPv4 peers.
+    pub fn from_udp(udp: UdpSocket) -> anyhow::Result<Self> {
+        let local_addr = udp.local_addr().context("error getting local address")?;
+        let udp = Arc::new(udp);
+        let registry = Arc::new(Registry::default());
+        let (accept_tx, accept_rx) = mpsc::channel(ACCEPT_QUEUE);
+        let dispatcher = spawn(
+            debug_span!("utp", addr = %local_addr),
+            dispatch(udp.clone(), registry.clone(), accept_tx),
+        );
+        Ok(Self {
+            inner: Arc::new(Inner {
+                udp,
+                local_addr,
+                registry,
+                accept_rx: tokio::sync::Mutex::new(accept_rx),
+                dispatcher,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+        let recv_id = {
+            let mut registry = self.inner.registry.lock();
+            loop {
+                let id: u16 = rand::random();
+                // The remote will use "id + 1" for incoming connections, avoid both.
+                if !registry.contains_key(&(addr, id))
+                    && !registry.contains_key(&(addr, id.wrapping_add(1)))
+                {
+                    registry.insert((addr, id), tx);
+                    break id;
+                }
+            }
+        };
+        let shared = Arc::new(Shared::default());
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let conn = Connection::new_outgoing(
+            addr,
+            self.inner.udp.clone(),
+            shared.clone(),
+            rx,
+            self.inner.registry.clone(),
+            recv_id,
+            connected_tx,
+        );
+        spawn(debug_span!("utp_conn", addr = %addr), async move {
+            conn.run().await;
+            Ok(())
+        });
+
+        // If this future is dropped, so is the stream, which stops the connection task.
+        let stream = UtpStream::new(shared, addr);
+        match connected_rx.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "uTP connection task exited",
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        self.inner
+            .accept_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "uTP socket closed"))
+    }
+}
+
+// A dual-stack IPv6 socket can only send to IPv4 peers through their v4-mapped addresses.
+pub(crate) fn send_addr(local_addr: SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local_addr, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+async fn dispatch(
+    udp: Arc<UdpSocket>,
+    registry: Arc<Registry>,
+    accept_tx: mpsc::Sender<(UtpStream, SocketAddr)>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, addr) = match udp.recv_from(&mut buf).await {
+            // IPv4 peers on a dual-stack socket show up as v4-mapped IPv6 addresses.
+            Ok((len, addr)) => (len, SocketAddr::new(addr.ip().to_canonical(), addr.port())),
+            Err(e) => {
+                // E.g. ICMP port unreachable reported by Windows, not fatal.
+                debug!("error receiving: {e:#}");
+                continue;
+            }
+        };
+        let packet = match Packet::deserialize(&buf[..len]) {
+            Ok(p) => p,
+            Err(e) => {
+                trace!(%addr, "ignoring invalid packet: {e:#}");
+                continue;
+            }
+        };
+        let header = packet.header;
+
+        if header.packet_type == PacketType::Syn {
+            let key = (addr, header.connection_id.wrapping_add(1));
+            if let Some(tx) = registry.lock().get(&key) {
+                // A retransmitted SYN, the connection will re-ack it.
+                let _ = tx.try_send(OwnedPacket::from(&packet));
+                continue;
+            }
+            if accept_tx.capacity() == 0 {
+                debug!(%addr, "accept queue full, ignoring SYN");
+                continue;
+            }
+            let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+            registry.lock().insert(key, tx);
+            let shared = Arc::new(Shared::default());
+            let conn = Connection::new_incoming(
+                &header,
+                addr,
+                udp.clone(),
+                shared.clone(),
+                rx,
+                registry.clone(),
+            );
+            spawn(debug_span!("utp_conn", addr = %addr), async move {
+                conn.run().await;
+                Ok(())
+            });
+            let _ = accept_tx.try_send((UtpStream::new(shared, addr), addr));
+            continue;
+        }
+
+        let tx = registry.lock().get(&(addr, header.connection_id)).cloned();
+        match tx {
+            Some(tx) => {
+                if tx.try_send(OwnedPacket::from(&packet)).is_err() {
+                    trace!(%addr, "connection queue full, dropping packet");
+                }
+            }
+            None => trace!(%addr, "packet for unknown connection {}", header.connection_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{send_addr, UtpSocket};
+
+    #[tokio::test]
+    async fn test_transfer_both_ways() {
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = server.local_addr();
+        let client_addr = client.local_addr();
+
+        // Larger than the send and receive buffers.
+        let data = (0..3 * 1024 * 1024u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let test = async {
+            let server_task = {
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let (mut stream, addr) = server.accept().await.unwrap();
+                    assert_eq!(addr, client_addr);
+                    let mut received = Vec::new();
+                    stream.read_to_end(&mut received).await.unwrap();
+                    assert!(received == data, "received data doesn't match");
+                    stream.write_all(b"thanks").await.unwrap();
+                    stream.shutdown().await.unwrap();
+                    // Keep the socket alive until the client has read everything.
+                    let mut buf = [0u8; 1];
+                    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                })
+            };
+
+            let mut stream = client.connect(server_addr).await.unwrap();
+            assert_eq!(stream.peer_addr(), server_addr);
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"thanks");
+            server_task.await.unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(30), test)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_send_addr_dual_stack() {
+        let v4 = "1.2.3.4:6881".parse().unwrap();
+        let v6 = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(send_addr("0.0.0.0:1".parse().unwrap(), v4), v4);
+        assert_eq!(
+            send_addr("[::]:1".parse().unwrap(), v4),
+            "[::ffff:1.2.3.4]:6881".parse().unwrap()
+        );
+        assert_eq!(send_addr("[::]:1".parse().unwrap(), v6), v6);
+    }
+
+    #[tokio::test]
+    async fn test_write_after_shutdown_fails() {
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = server.local_addr();
+
+        let test = async {
+            let accept = tokio::spawn(async move {
+                let (mut stream, _) = server.accept().await.unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+            let mut stream = client.connect(server_addr).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+            assert!(stream.write_all(b"more").await.is_err());
+            assert_eq!(accept.await.unwrap(), b"hello");
+        };
+        tokio::time::timeout(Duration::from_secs(30), test)
+            .await
+            .unwrap();
+    }
+}
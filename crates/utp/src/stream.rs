@@ -0,0 +1,158 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+// How many bytes the user can write before the writes start blocking.
+pub(crate) const SEND_BUFFER: usize = 1024 * 1024;
+// How many received bytes can be buffered before we stop accepting more.
+pub(crate) const RECV_BUFFER: usize = 1024 * 1024;
+
+#[derive(Default)]
+pub(crate) struct SharedState {
+    // Received in-order data, not yet read by the user.
+    pub recv_buf: VecDeque<u8>,
+    // The remote sent FIN, and all data before it was received.
+    pub recv_eof: bool,
+    pub reader_waker: Option<Waker>,
+    // The connection task wants to know when the receive window opens up again.
+    pub wants_window_update: bool,
+
+    // Data written by the user, not yet put into packets.
+    pub send_buf: VecDeque<u8>,
+    pub writer_waker: Option<Waker>,
+    // The user called shutdown(). FIN is sent after the send buffer is flushed.
+    pub write_shutdown: bool,
+
+    // The user dropped the stream.
+    pub dropped: bool,
+    // The connection is gone. If it was not closed gracefully, this has the reason.
+    pub closed: bool,
+    pub error: Option<(io::ErrorKind, String)>,
+}
+
+impl SharedState {
+    pub fn wake_all(&mut self) {
+        if let Some(w) = self.reader_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.writer_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn error(&self) -> Option<io::Error> {
+        self.error
+            .as_ref()
+            .map(|(kind, msg)| io::Error::new(*kind, msg.clone()))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Shared {
+    pub state: Mutex<SharedState>,
+    // Wakes up the connection task.
+    pub notify: Notify,
+}
+
+/// A uTP connection. Use it like a TCP stream.
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub(crate) fn new(shared: Arc<Shared>, peer_addr: SocketAddr) -> Self {
+        Self { shared, peer_addr }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut g = self.shared.state.lock();
+        if !g.recv_buf.is_empty() {
+            let len = buf.remaining().min(g.recv_buf.len());
+            let (a, b) = g.recv_buf.as_slices();
+            let from_a = len.min(a.len());
+            buf.put_slice(&a[..from_a]);
+            buf.put_slice(&b[..len - from_a]);
+            g.recv_buf.drain(..len);
+            if g.wants_window_update {
+                g.wants_window_update = false;
+                self.shared.notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if g.recv_eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(e) = g.error() {
+            return Poll::Ready(Err(e));
+        }
+        if g.closed {
+            return Poll::Ready(Ok(()));
+        }
+        g.reader_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut g = self.shared.state.lock();
+        if let Some(e) = g.error() {
+            return Poll::Ready(Err(e));
+        }
+        if g.closed || g.write_shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(SEND_BUFFER - g.send_buf.len());
+        if len == 0 && !buf.is_empty() {
+            g.writer_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        g.send_buf.extend(&buf[..len]);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Written data is already queued for sending.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.state.lock().write_shutdown = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shared.state.lock().dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
@@ -15,8 +15,8 @@ use config::RqbitDesktopConfig;
 use http::StatusCode;
 use librqbit::{
     api::{
-        ApiAddTorrentResponse, EmptyJsonResponse, TorrentDetailsResponse, TorrentListResponse,
-        TorrentStats,
+        ApiAddTorrentResponse, EmptyJsonResponse, PeerStatsSnapshot, TorrentDetailsResponse,
+        TorrentListResponse, TorrentStats,
     },
     dht::PersistentDhtConfig,
     tracing_subscriber_config_utils::{init_logging, InitLoggingOptions, InitLoggingResult},
@@ -104,6 +104,7 @@ async fn api_from_config(
             api.clone(),
             Some(librqbit::http_api::HttpApiOptions {
                 read_only: config.http_api.read_only,
+                ..Default::default()
             }),
         )
         .make_http_api_and_run(config.http_api.listen_addr);
@@ -250,7 +251,7 @@ async fn torrent_details(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<TorrentDetailsResponse, ApiError> {
-    state.api()?.api_torrent_details(id)
+    state.api()?.api_torrent_details(id.into())
 }
 
 #[tauri::command]
@@ -258,7 +259,15 @@ async fn torrent_stats(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<TorrentStats, ApiError> {
-    state.api()?.api_stats_v1(id)
+    state.api()?.api_stats_v1(id.into())
+}
+
+#[tauri::command]
+async fn torrent_peer_stats(
+    state: tauri::State<'_, State>,
+    id: usize,
+) -> Result<PeerStatsSnapshot, ApiError> {
+    state.api()?.api_peer_stats(id.into(), Default::default())
 }
 
 #[tauri::command]
@@ -266,7 +275,7 @@ async fn torrent_action_delete(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<EmptyJsonResponse, ApiError> {
-    state.api()?.api_torrent_action_delete(id)
+    state.api()?.api_torrent_action_delete(id.into())
 }
 
 #[tauri::command]
@@ -274,7 +283,7 @@ async fn torrent_action_pause(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<EmptyJsonResponse, ApiError> {
-    state.api()?.api_torrent_action_pause(id)
+    state.api()?.api_torrent_action_pause(id.into())
 }
 
 #[tauri::command]
@@ -282,7 +291,7 @@ async fn torrent_action_forget(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<EmptyJsonResponse, ApiError> {
-    state.api()?.api_torrent_action_forget(id)
+    state.api()?.api_torrent_action_forget(id.into())
 }
 
 #[tauri::command]
@@ -290,7 +299,7 @@ async fn torrent_action_start(
     state: tauri::State<'_, State>,
     id: usize,
 ) -> Result<EmptyJsonResponse, ApiError> {
-    state.api()?.api_torrent_action_start(id)
+    state.api()?.api_torrent_action_start(id.into())
 }
 
 #[tauri::command]
@@ -301,7 +310,7 @@ async fn torrent_action_configure(
 ) -> Result<EmptyJsonResponse, ApiError> {
     state
         .api()?
-        .api_torrent_action_update_only_files(id, &only_files.into_iter().collect())
+        .api_torrent_action_update_only_files(id.into(), &only_files.into_iter().collect())
 }
 
 #[tauri::command]
@@ -331,6 +340,7 @@ async fn start() {
             torrents_list,
             torrent_details,
             torrent_stats,
+            torrent_peer_stats,
             torrent_create_from_url,
             torrent_action_delete,
             torrent_action_pause,
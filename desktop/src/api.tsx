@@ -2,6 +2,7 @@ import { RqbitDesktopConfig } from "./configuration";
 import {
   AddTorrentResponse,
   ListTorrentsResponse,
+  PeerStatsSnapshot,
   RqbitAPI,
   TorrentDetails,
   TorrentStats,
@@ -88,6 +89,9 @@ export const makeAPI = (configuration: RqbitDesktopConfig): RqbitAPI => {
     getTorrentStats: async function (id: number): Promise<TorrentStats> {
       return await invokeAPI<TorrentStats>("torrent_stats", { id });
     },
+    getPeerStats: async function (id: number): Promise<PeerStatsSnapshot> {
+      return await invokeAPI<PeerStatsSnapshot>("torrent_peer_stats", { id });
+    },
     uploadTorrent: async function (data, opts): Promise<AddTorrentResponse> {
       if (data instanceof File) {
         let contents = await readFileAsBase64(data);